@@ -0,0 +1,154 @@
+// Benchmarks for the TreeKEM operations, from the individual direct path operations up to creating
+// and processing whole Add and Update handshakes. Every benchmark runs at each of the group sizes
+// in GROUP_SIZES. The fixtures are built inside the benchmark closures, so filtering by group size
+// also skips building the big ones.
+//
+// Usage: cargo bench --features bench [-- FILTER]
+
+use molasses::{
+    bench_utils::{GroupFixture, TreeFixture, BENCH_CIPHER_SUITE},
+    credential::RosterIndex,
+    ratchet_tree::PathSecret,
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::SeedableRng;
+
+const GROUP_SIZES: &[usize] = &[2, 10, 100, 1000, 10_000, 50_000, 100_000];
+
+// The big groups take seconds per iteration, so we take the fewest samples criterion allows
+const SAMPLE_SIZE: usize = 10;
+
+fn make_rng() -> rand::rngs::StdRng {
+    rand::rngs::StdRng::seed_from_u64(0)
+}
+
+fn direct_path_benches(c: &mut Criterion) {
+    let mut group = c.benchmark_group("direct_path");
+    group.sample_size(SAMPLE_SIZE);
+
+    for &num_leaves in GROUP_SIZES {
+        group.bench_with_input(
+            BenchmarkId::new("propagate_new_path_secret", num_leaves),
+            &num_leaves,
+            |b, &num_leaves| {
+                let mut rng = make_rng();
+                let mut fixture = TreeFixture::new(num_leaves, &mut rng).unwrap();
+                let path_secret = PathSecret::new_from_random(BENCH_CIPHER_SUITE, &mut rng);
+                b.iter(|| fixture.propagate_new_path_secret(path_secret.clone()).unwrap())
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("encrypt_direct_path_secrets", num_leaves),
+            &num_leaves,
+            |b, &num_leaves| {
+                let mut rng = make_rng();
+                let fixture = TreeFixture::new(num_leaves, &mut rng).unwrap();
+                let path_secret = PathSecret::new_from_random(BENCH_CIPHER_SUITE, &mut rng);
+                b.iter(|| {
+                    fixture.encrypt_direct_path_secrets(path_secret.clone(), &mut rng).unwrap()
+                })
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("decrypt_direct_path_message", num_leaves),
+            &num_leaves,
+            |b, &num_leaves| {
+                let mut rng = make_rng();
+                let fixture = TreeFixture::new(num_leaves, &mut rng).unwrap();
+                let path_secret = PathSecret::new_from_random(BENCH_CIPHER_SUITE, &mut rng);
+                let msg = fixture.encrypt_direct_path_secrets(path_secret, &mut rng).unwrap();
+                b.iter(|| fixture.decrypt_direct_path_message(&msg).unwrap())
+            },
+        );
+    }
+
+    group.finish();
+}
+
+fn handshake_benches(c: &mut Criterion) {
+    let mut group = c.benchmark_group("handshake");
+    group.sample_size(SAMPLE_SIZE);
+
+    for &num_members in GROUP_SIZES {
+        group.bench_with_input(
+            BenchmarkId::new("create_update", num_members),
+            &num_members,
+            |b, &num_members| {
+                let mut rng = make_rng();
+                let fixture = GroupFixture::new(num_members, &mut rng).unwrap();
+                let path_secret = PathSecret::new_from_random(BENCH_CIPHER_SUITE, &mut rng);
+                b.iter_with_large_drop(|| {
+                    fixture
+                        .sender
+                        .create_and_apply_update_handshake(path_secret.clone(), &mut rng)
+                        .unwrap()
+                })
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("process_update", num_members),
+            &num_members,
+            |b, &num_members| {
+                let mut rng = make_rng();
+                let fixture = GroupFixture::new(num_members, &mut rng).unwrap();
+                let path_secret = PathSecret::new_from_random(BENCH_CIPHER_SUITE, &mut rng);
+                let (handshake, _, _) = fixture
+                    .sender
+                    .create_and_apply_update_handshake(path_secret, &mut rng)
+                    .unwrap();
+                b.iter_with_large_drop(|| fixture.receiver.process_handshake(&handshake).unwrap())
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("create_add", num_members),
+            &num_members,
+            |b, &num_members| {
+                let mut rng = make_rng();
+                let fixture = GroupFixture::new(num_members, &mut rng).unwrap();
+                let init_key = fixture.new_user_init_key(&mut rng).unwrap();
+                let welcome_info_hash = fixture.welcome_info_hash().unwrap();
+                let new_roster_index = RosterIndex(num_members as u32);
+                b.iter_with_large_drop(|| {
+                    fixture
+                        .sender
+                        .create_and_apply_add_handshake(
+                            new_roster_index,
+                            init_key.clone(),
+                            &welcome_info_hash,
+                        )
+                        .unwrap()
+                })
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("process_add", num_members),
+            &num_members,
+            |b, &num_members| {
+                let mut rng = make_rng();
+                let fixture = GroupFixture::new(num_members, &mut rng).unwrap();
+                let init_key = fixture.new_user_init_key(&mut rng).unwrap();
+                let welcome_info_hash = fixture.welcome_info_hash().unwrap();
+                let (handshake, _, _) = fixture
+                    .sender
+                    .create_and_apply_add_handshake(
+                        RosterIndex(num_members as u32),
+                        init_key,
+                        &welcome_info_hash,
+                    )
+                    .unwrap();
+                b.iter_with_large_drop(|| fixture.receiver.process_handshake(&handshake).unwrap())
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, direct_path_benches, handshake_benches);
+criterion_main!(benches);
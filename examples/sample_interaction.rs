@@ -12,28 +12,26 @@
 
 use molasses::{
     application::{decrypt_application_message, encrypt_application_message, ApplicationMessage},
-    credential::{BasicCredential, Credential, Identity},
+    credential::{BasicCredential, Credential, Identity, RosterIndex},
     crypto::{
         ciphersuite::{CipherSuite, X25519_SHA256_AES128GCM},
         sig::{SigPublicKey, SigSecretKey, SignatureScheme, ED25519_IMPL},
     },
     group_state::{GroupState, Welcome},
     handshake::{Handshake, ProtocolVersion, UserInitKey, MLS_DUMMY_VERSION},
-    tls_de::TlsDeserializer,
     tls_ser::TlsSerializer,
-    upcast::{CryptoCtx, CryptoUpcast},
+    upcast::{self, CryptoCtx, CryptoUpcast},
 };
 
 use std::thread;
 
 use crossbeam::channel;
-use rand;
 use rot13::rot13;
 use serde::de::Deserialize;
 use serde::ser::Serialize;
 
-const COMMON_CIPHER_SUITE: &'static CipherSuite = &X25519_SHA256_AES128GCM;
-const COMMON_SIG_SCHEME: &'static SignatureScheme = &ED25519_IMPL;
+const COMMON_CIPHER_SUITE: &CipherSuite = &X25519_SHA256_AES128GCM;
+const COMMON_SIG_SCHEME: &SignatureScheme = &ED25519_IMPL;
 const COMMON_PROTOCOL_VERSION: ProtocolVersion = MLS_DUMMY_VERSION;
 
 // Pauses the main thread until the user presses Enter
@@ -43,21 +41,14 @@ fn pause_for_effect() {
 }
 
 // Deserializes and upcasts MLS data structures
-pub fn deserialize<'de, T: Deserialize<'de> + CryptoUpcast>(bytes: &[u8]) -> T {
-    // Deserialize
-    let mut cursor = bytes;
-    let mut deserializer = TlsDeserializer::from_reader(&mut cursor);
-    let mut val = <T as Deserialize>::deserialize(&mut deserializer).unwrap();
-
+pub fn deserialize<T: for<'de> Deserialize<'de> + CryptoUpcast>(bytes: &[u8]) -> T {
     // Punt on negotiating ciphersuites and signature schemes. I don't wanna deal with that
     let ctx = CryptoCtx::new()
         .set_cipher_suite(COMMON_CIPHER_SUITE)
         .set_signature_scheme(COMMON_SIG_SCHEME);
 
-    // Make everything nice and typesafe
-    val.upcast_crypto_values(&ctx).unwrap();
-
-    val
+    // Deserialize and make everything nice and typesafe
+    upcast::deserialize_and_upcast(bytes, &ctx).unwrap()
 }
 
 // Serializes MLS data structures
@@ -200,7 +191,7 @@ fn alice(tx: channel::Sender<Vec<u8>>, rx: channel::Receiver<Vec<u8>>) {
 
     // Then make an Add Handshake, letting the resulting group state be the new group state.
     // Bob will have roster index 1. Recall Alice is at roster index 0.
-    let bob_roster_idx = 1;
+    let bob_roster_idx = RosterIndex(1);
     let (add_handshake, group_state, mut app_key_chain) = group_state
         .create_and_apply_add_handshake(bob_roster_idx, bob_user_init_key, &welcome_info_hash)
         .unwrap();
@@ -216,7 +207,8 @@ fn alice(tx: channel::Sender<Vec<u8>>, rx: channel::Receiver<Vec<u8>>) {
 
     // Receive Bob's response
     let app_msg: ApplicationMessage = deserialize(&rx.recv().unwrap());
-    let plaintext = decrypt_application_message(app_msg, &group_state, &mut app_key_chain).unwrap();
+    let plaintext =
+        decrypt_application_message(app_msg, &group_state, &mut app_key_chain).unwrap().plaintext;
     println!(r#"ALICE RECV ApplicationMessage "{}""#, bytes_to_str(&plaintext));
 
     // Alice's response
@@ -239,7 +231,7 @@ fn alice(tx: channel::Sender<Vec<u8>>, rx: channel::Receiver<Vec<u8>>) {
     println!("ALICE SEND Welcome");
 
     // Then make an Add Handshake, letting the resulting group state be the new group state.
-    let carol_roster_idx = 2;
+    let carol_roster_idx = RosterIndex(2);
     let (add_handshake, group_state, mut app_key_chain) = group_state
         .create_and_apply_add_handshake(carol_roster_idx, carol_user_init_key, &welcome_info_hash)
         .unwrap();
@@ -248,7 +240,8 @@ fn alice(tx: channel::Sender<Vec<u8>>, rx: channel::Receiver<Vec<u8>>) {
 
     // Receive Carol's message
     let app_msg: ApplicationMessage = deserialize(&rx.recv().unwrap());
-    let plaintext = decrypt_application_message(app_msg, &group_state, &mut app_key_chain).unwrap();
+    let plaintext =
+        decrypt_application_message(app_msg, &group_state, &mut app_key_chain).unwrap().plaintext;
     println!(r#"ALICE RECV ApplicationMessage "{}""#, bytes_to_str(&plaintext));
 }
 
@@ -298,7 +291,8 @@ fn bob(tx: channel::Sender<Vec<u8>>, rx: channel::Receiver<Vec<u8>>) {
 
     // Time to receive the first ApplicationMessage
     let app_msg: ApplicationMessage = deserialize(&rx.recv().unwrap());
-    let plaintext = decrypt_application_message(app_msg, &group_state, &mut app_key_chain).unwrap();
+    let plaintext =
+        decrypt_application_message(app_msg, &group_state, &mut app_key_chain).unwrap().plaintext;
     println!(r#"BOB   RECV ApplicationMessage "{}""#, bytes_to_str(&plaintext));
 
     // Respond
@@ -310,7 +304,8 @@ fn bob(tx: channel::Sender<Vec<u8>>, rx: channel::Receiver<Vec<u8>>) {
 
     // Get rebuked by Alice
     let app_msg: ApplicationMessage = deserialize(&rx.recv().unwrap());
-    let plaintext = decrypt_application_message(app_msg, &group_state, &mut app_key_chain).unwrap();
+    let plaintext =
+        decrypt_application_message(app_msg, &group_state, &mut app_key_chain).unwrap().plaintext;
     println!(r#"BOB   RECV ApplicationMessage "{}""#, bytes_to_str(&plaintext));
 
     // Silently ignore Carol's UserInitKey
@@ -325,7 +320,8 @@ fn bob(tx: channel::Sender<Vec<u8>>, rx: channel::Receiver<Vec<u8>>) {
 
     // Get Carol's first message
     let app_msg: ApplicationMessage = deserialize(&rx.recv().unwrap());
-    let plaintext = decrypt_application_message(app_msg, &group_state, &mut app_key_chain).unwrap();
+    let plaintext =
+        decrypt_application_message(app_msg, &group_state, &mut app_key_chain).unwrap().plaintext;
     println!(r#"BOB   RECV ApplicationMessage "{}""#, bytes_to_str(&plaintext));
 }
 
@@ -0,0 +1,10 @@
+#![no_main]
+
+use molasses::application::ApplicationMessage;
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let ctx = molasses_fuzz::group_ctx();
+    let _ = molasses_fuzz::deserialize_and_upcast::<ApplicationMessage>(data, &ctx);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+
+use molasses::handshake::Handshake;
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = molasses_fuzz::deserialize_and_upcast::<Handshake>(data, &molasses_fuzz::group_ctx());
+});
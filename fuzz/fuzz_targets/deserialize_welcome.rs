@@ -0,0 +1,9 @@
+#![no_main]
+
+use molasses::{group_state::Welcome, upcast::CryptoCtx};
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = molasses_fuzz::deserialize_and_upcast::<Welcome>(data, &CryptoCtx::new());
+});
@@ -0,0 +1,25 @@
+#![no_main]
+
+use molasses::{group_state::GroupState, handshake::Handshake};
+
+use libfuzzer_sys::fuzz_target;
+
+thread_local! {
+    // Making a group is expensive relative to a fuzz iteration, so only do it once
+    static GROUPS: (GroupState, GroupState) = molasses_fuzz::two_member_group();
+}
+
+// Feeds arbitrary Handshakes to both members of a group. Since the sender index is attacker
+// controlled, this exercises the Add, Update, and Remove processing paths from both sides.
+fuzz_target!(|data: &[u8]| {
+    let handshake: Handshake =
+        match molasses_fuzz::deserialize_and_upcast(data, &molasses_fuzz::group_ctx()) {
+            Ok(h) => h,
+            Err(_) => return,
+        };
+
+    GROUPS.with(|(alice_group, bob_group)| {
+        let _ = alice_group.process_handshake(&handshake);
+        let _ = bob_group.process_handshake(&handshake);
+    });
+});
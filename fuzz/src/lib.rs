@@ -0,0 +1,78 @@
+//! Shared helpers for the molasses fuzz targets. Every target should only ever see `Err`s from
+//! molasses, never panics, no matter what bytes it's fed.
+
+use molasses::{
+    credential::{BasicCredential, Credential, Identity, RosterIndex},
+    crypto::{
+        ciphersuite::{CipherSuite, X25519_SHA256_AES128GCM},
+        sig::{SigPublicKey, SigSecretKey, SignatureScheme, ED25519_IMPL},
+    },
+    group_state::{GroupState, Welcome},
+    handshake::{UserInitKey, MLS_DUMMY_VERSION},
+    upcast::CryptoCtx,
+};
+
+use rand::SeedableRng;
+
+pub const CIPHER_SUITE: &'static CipherSuite = &X25519_SHA256_AES128GCM;
+pub const SIG_SCHEME: &'static SignatureScheme = &ED25519_IMPL;
+
+pub use molasses::upcast::deserialize_and_upcast;
+
+/// The context that messages within a group made by `two_member_group` are upcast with
+pub fn group_ctx() -> CryptoCtx {
+    CryptoCtx::new().set_cipher_suite(CIPHER_SUITE).set_signature_scheme(SIG_SCHEME)
+}
+
+// Makes a credential with the given name and a deterministic identity key
+fn credential(name: &[u8], seed: u8) -> (Credential, SigSecretKey) {
+    let identity_key = SigSecretKey::new_from_bytes(SIG_SCHEME, &[seed; 32]).unwrap();
+    let public_key = SigPublicKey::new_from_secret_key(SIG_SCHEME, &identity_key);
+    let cred = Credential::Basic(BasicCredential::new(
+        Identity::from_bytes(name.to_vec()),
+        SIG_SCHEME,
+        public_key,
+    ));
+
+    (cred, identity_key)
+}
+
+/// Deterministically makes a two-member group. Returns the `GroupState`s of the creator (roster
+/// index 0) and the added member (roster index 1), in that order.
+pub fn two_member_group() -> (GroupState, GroupState) {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+
+    let (alice_cred, alice_key) = credential(b"alice", 0xa1);
+    let (bob_cred, bob_key) = credential(b"bob", 0xb0);
+
+    let alice_group = GroupState::new_singleton_group(
+        CIPHER_SUITE,
+        MLS_DUMMY_VERSION,
+        alice_key,
+        b"fuzz".to_vec(),
+        alice_cred,
+        &mut rng,
+    )
+    .unwrap();
+
+    let bob_init_key = UserInitKey::new_from_random(
+        &bob_key,
+        b"bob_uik".to_vec(),
+        bob_cred,
+        vec![CIPHER_SUITE],
+        vec![MLS_DUMMY_VERSION],
+        &mut rng,
+    )
+    .unwrap();
+
+    let (welcome, welcome_info_hash) =
+        Welcome::from_group_state(&alice_group, &bob_init_key, &mut rng).unwrap();
+    let (add, alice_group, _) = alice_group
+        .create_and_apply_add_handshake(RosterIndex(1), bob_init_key.clone(), &welcome_info_hash)
+        .unwrap();
+
+    let bob_group = GroupState::from_welcome(welcome, bob_key, bob_init_key).unwrap();
+    let (bob_group, _) = bob_group.process_handshake(&add).unwrap();
+
+    (alice_group, bob_group)
+}
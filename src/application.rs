@@ -2,22 +2,27 @@
 //! messages
 
 use crate::{
+    credential::{Credential, RosterIndex},
     crypto::{
         aead::{AeadKey, AeadNonce},
         ciphersuite::CipherSuite,
         hkdf,
         hmac::HmacKey,
+        secret::SavedSecret,
         sig::Signature,
     },
     error::Error,
-    group_state::{ApplicationSecret, GroupState},
+    extensions::{self, ExtensionType, KnownExtension},
+    group_state::{ApplicationSecret, GroupContext, GroupState},
+    metrics,
     tls_de::TlsDeserializer,
-    tls_ser,
+    tls_ser, utils,
 };
 
 use core::convert::TryFrom;
 
 use serde::de::Deserialize;
+use subtle::ConstantTimeEq;
 
 /// Contains a secret that is unique to a member of the group. This is part of the application key
 /// schedule defined in the "Encryption Keys" section of the spec.
@@ -31,6 +36,37 @@ impl From<WriteSecret> for HmacKey {
     }
 }
 
+/// The key and nonce of a generation that was skipped over when ratcheting a sender's write secret
+/// forward. These are kept around, within a window, so that the message with that generation can
+/// still be decrypted if it arrives late. They are erased as soon as they're used.
+struct SkippedKey {
+    generation: u32,
+    key_bytes: Vec<u8>,
+    nonce_bytes: Vec<u8>,
+}
+
+// Erase skipped keys when they're used or fall out of the window
+impl Drop for SkippedKey {
+    fn drop(&mut self) {
+        utils::zeroize(&mut self.key_bytes);
+        utils::zeroize(&mut self.nonce_bytes);
+    }
+}
+
+/// Says where the key for a received message came from, so that the key chain can be updated
+/// accordingly once the message is authenticated
+enum ReceiveKeySource {
+    /// The key is the kept key at this position in the sender's list of skipped keys
+    Skipped(usize),
+    /// The key was found by ratcheting the sender's write secret forward. This holds the keys of
+    /// the generations that were jumped over, and the write secret for the generation after the
+    /// message's.
+    Ratcheted {
+        skipped: Vec<SkippedKey>,
+        next_write_secret: WriteSecret,
+    },
+}
+
 /// Contains the secrets for every member of the group. These are called "application_secrets" in
 /// the spec, but that's kinda confusing since "application_secret" is also something that the
 /// `GroupState` creates and uses to seed this struct.
@@ -38,34 +74,51 @@ impl From<WriteSecret> for HmacKey {
 /// This is intended to be used with the `encrypt_application_message` and
 /// `decrypt_application_message` functions.
 pub struct ApplicationKeyChain {
-    /// Contains write secrets and their respective generations, starting at 0
-    write_secrets_and_gens: Vec<(WriteSecret, u32)>,
+    /// Contains write secrets and their respective generations, starting at 0. An entry is `None`
+    /// once its sender has been forgotten with `ApplicationKeyChain::forget_sender`.
+    write_secrets_and_gens: Vec<Option<(WriteSecret, u32)>>,
+
+    /// For every roster entry, the unused keys of generations that were skipped over. Every key
+    /// here is for a generation before the sender's current one.
+    skipped_keys: Vec<Vec<SkippedKey>>,
+
+    /// How many generations behind the latest received one a sender's skipped keys are kept for.
+    /// See `ApplicationKeyChain::set_skipped_key_window`.
+    skipped_key_window: u32,
 
     /// The creating group's ciphersuite
     group_cs: &'static CipherSuite,
 
-    /// The creating group's ID
-    group_id: Vec<u8>,
+    /// The creating group's `GroupContext` at the time of creation. Its epoch is what makes the
+    /// `ApplicationKeyChain` work independently from the creating `GroupState`. The whole context
+    /// is the associated data of every message encrypted under this key chain, and is covered by
+    /// their signatures.
+    group_context: GroupContext,
 
-    /// The creating group's epoch at the time of creation. This is important for making the
-    /// `ApplicationKeyChain` work independently from the creating `GroupState`.
-    group_epoch_at_creation: u32,
+    /// Whether this key chain belongs to a past epoch. Past epochs' key chains are only kept
+    /// around to decrypt late messages, so they refuse to encrypt.
+    receive_only: bool,
 }
 
 impl ApplicationKeyChain {
     /// Creates an `ApplicationKeyChain` object from the given application secret and size of the
     /// current group (really, the size of the current roster, including blanks)
+    ///
+    /// Returns: `Ok(app_key_chain)` on success. Returns an `Error::SerdeError` if the group's
+    /// `GroupContext` can't be computed.
     pub(crate) fn from_application_secret(
         group_state: &GroupState,
         app_secret: ApplicationSecret,
-    ) -> ApplicationKeyChain {
+    ) -> Result<ApplicationKeyChain, Error> {
         // Remember that roster indices are u32. This fact matters when we serialize it in the
         // calculation of write_secret_[sender].
         let roster_len =
             u32::try_from(group_state.roster.len()).expect("roster length exceeds u32::MAX");
 
-        // The application secret is secretly an HMAC key
+        // The application secret is secretly an HMAC key. Every write secret is expanded from it,
+        // so set it up as one once.
         let prk: HmacKey = app_secret.into();
+        let expander = hkdf::LabelExpander::new(group_state.cs.hash_impl, &prk);
 
         // Make a write secret for every roster entry, and let its generation be 0
         let write_secrets_and_gens = (0u32..roster_len)
@@ -75,44 +128,74 @@ impl ApplicationKeyChain {
                 //  where sender is serialized as usual as a u32
                 let mut write_secret_buf = vec![0u8; group_state.cs.hash_impl.digest_size()];
                 let serialized_roster_idx = tls_ser::serialize_to_bytes(&roster_idx).unwrap();
-                hkdf::expand_label(
-                    group_state.cs.hash_impl,
-                    &prk,
+                expander.expand_label(
                     b"app sender",
                     &serialized_roster_idx,
                     write_secret_buf.as_mut_slice(),
                 );
                 let write_secret = WriteSecret(HmacKey::new_from_bytes(&write_secret_buf));
+                utils::zeroize(&mut write_secret_buf);
 
                 // (write_secret, generation=0)
-                (write_secret, 0)
+                Some((write_secret, 0))
             })
             .collect();
+        let skipped_keys = (0..roster_len).map(|_| Vec::new()).collect();
 
-        ApplicationKeyChain {
+        Ok(ApplicationKeyChain {
             write_secrets_and_gens,
+            skipped_keys,
+            skipped_key_window: 0,
             group_cs: group_state.cs,
-            group_id: group_state.group_id.clone(),
-            group_epoch_at_creation: group_state.epoch,
+            group_context: group_state.group_context()?,
+            receive_only: false,
+        })
+    }
+
+    /// Marks this key chain as belonging to a past epoch. After this, it can still decrypt
+    /// messages, but it can no longer encrypt them.
+    pub(crate) fn make_receive_only(&mut self) {
+        self.receive_only = true;
+    }
+
+    /// Erases the write secret and every kept key of the member at the given roster index. Nothing
+    /// they sent under this key chain can be decrypted afterwards. This is for members who've left
+    /// the group, so that their keys don't outlive their membership. An out-of-bounds index is
+    /// ignored.
+    pub(crate) fn forget_sender(&mut self, roster_idx: usize) {
+        // Write secrets and skipped keys both zero themselves when they're dropped
+        if let Some(entry) = self.write_secrets_and_gens.get_mut(roster_idx) {
+            *entry = None;
+        }
+        if let Some(skipped) = self.skipped_keys.get_mut(roster_idx) {
+            skipped.clear();
         }
     }
 
-    /// Retrieves `write_secrets_[roster_idx]` and derives a key and nonce from it, as per section
-    /// 9.1 of the MLS spec
+    /// Returns the current write secret and generation of the member at the given roster index
     ///
-    /// Returns: `Ok((gen, write_key_[roster_idx]_[gen], write_nonce_[roster_idx]_[gen]))` on
-    /// sucess, where `gen` is the current generation of the `WriteSecret` of the member indexed by
-    /// `roster_idx`. Returns an `Error` if `roster_idx` is out of bounds or something goes wrong
-    /// in the creation of the key/nonce from bytes.
-    fn get_key_nonce_gen(&self, roster_idx: usize) -> Result<(AeadKey, AeadNonce, u32), Error> {
-        // Get a reference to the write secret and current generation. We update these in-place at
-        // the end.
-        let (write_secret, generation) = self
-            .write_secrets_and_gens
+    /// Returns: `Ok(&(write_secret, generation))` on success. Returns an `Error::ValidationError`
+    /// if `roster_idx` is out of bounds or the member has been forgotten.
+    fn get_write_secret_and_gen(&self, roster_idx: usize) -> Result<&(WriteSecret, u32), Error> {
+        self.write_secrets_and_gens
             .get(roster_idx)
-            .ok_or(Error::ValidationError("Roster index out of bounds of application key chain"))?;
+            .ok_or(Error::ValidationError("Roster index out of bounds of application key chain"))?
+            .as_ref()
+            .ok_or(Error::ValidationError("Application key chain has forgotten this sender"))
+    }
 
-        // Derive the key and nonce
+    /// Returns the current generation of the member at the given roster index, i.e., how many
+    /// messages they've sent in this epoch
+    ///
+    /// Returns: `Ok(generation)` on success. Returns an `Error::ValidationError` if `roster_idx`
+    /// is out of bounds or the member has been forgotten.
+    pub(crate) fn get_generation(&self, roster_idx: usize) -> Result<u32, Error> {
+        self.get_write_secret_and_gen(roster_idx).map(|(_, generation)| *generation)
+    }
+
+    /// Derives the key and nonce bytes for the generation of the given write secret, as per
+    /// section 9.1 of the MLS spec
+    fn derive_key_nonce_bytes(&self, write_secret: &WriteSecret) -> (Vec<u8>, Vec<u8>) {
         let mut key_buf = vec![0u8; self.group_cs.aead_impl.key_size()];
         let mut nonce_buf = vec![0u8; self.group_cs.aead_impl.nonce_size()];
         hkdf::expand_label(
@@ -130,17 +213,11 @@ impl ApplicationKeyChain {
             nonce_buf.as_mut_slice(),
         );
 
-        let key = AeadKey::new_from_bytes(self.group_cs.aead_impl, &key_buf)?;
-        let nonce = AeadNonce::new_from_bytes(self.group_cs.aead_impl, &nonce_buf)?;
-        Ok((key, nonce, *generation))
+        (key_buf, nonce_buf)
     }
 
-    /// Ratchets `write_secrets_[roster_idx]` forward, as per section 9.1 of the MLS spec
-    ///
-    /// Returns: `Ok(())` on success. If the write secret is out of bounds, returns an
-    /// `Error::ValidationError`. If the write secret's generation is `u32::MAX`, returns an
-    /// `Error::KdfError`.
-    fn ratchet(&mut self, roster_idx: usize) -> Result<(), Error> {
+    /// Derives the write secret that comes after the given one, as per section 9.1 of the MLS spec
+    fn next_write_secret(&self, write_secret: &WriteSecret, roster_idx: u32) -> WriteSecret {
         // We rename application_secret_[sender] to write_secret_[sender] for disambiguation's
         // sake. From the spec, we derive the new keys as follows:
         //     application_secret_[sender]_[N-1]
@@ -155,43 +232,222 @@ impl ApplicationKeyChain {
         //               |
         //               V
         //     application_secret_[sender]_[N]
-
-        // Get the current write secret and generation
-        let (write_secret, generation) = self
-            .write_secrets_and_gens
-            .get_mut(roster_idx)
-            .ok_or(Error::ValidationError("Roster index out of bounds of application key chain"))?;
-        let current_secret = write_secret.clone();
-
-        // Ratchet the write secret, using its current value as a key
-        // write_secret_[sender]_[n] =
-        //     HKDF-Expand-Label(write_secret_[sender]_[n-1], "app sender", sender, Hash.length)
-        let roster_idx = u32::try_from(roster_idx)
-            .map_err(|_| Error::ValidationError("Roster index exceeds u32::MAX"))?;
         let serialized_roster_idx = tls_ser::serialize_to_bytes(&roster_idx).unwrap();
-        let prk: HmacKey = current_secret.into();
+        let mut new_secret_buf = vec![0u8; self.group_cs.hash_impl.digest_size()];
         hkdf::expand_label(
             self.group_cs.hash_impl,
-            &prk,
+            &write_secret.0,
             b"app sender",
             &serialized_roster_idx,
-            (write_secret.0).0.as_mut_slice(), // Overwrite the undelrying HmacKey
+            new_secret_buf.as_mut_slice(),
         );
 
-        // Increment the generation
-        *generation = generation
+        let new_secret = WriteSecret(HmacKey::new_from_bytes(&new_secret_buf));
+        utils::zeroize(&mut new_secret_buf);
+        new_secret
+    }
+
+    /// Retrieves `write_secrets_[roster_idx]` and derives a key and nonce from it, as per section
+    /// 9.1 of the MLS spec
+    ///
+    /// Returns: `Ok((gen, write_key_[roster_idx]_[gen], write_nonce_[roster_idx]_[gen]))` on
+    /// sucess, where `gen` is the current generation of the `WriteSecret` of the member indexed by
+    /// `roster_idx`. Returns an `Error` if `roster_idx` is out of bounds or something goes wrong
+    /// in the creation of the key/nonce from bytes.
+    fn get_key_nonce_gen(&self, roster_idx: usize) -> Result<(AeadKey, AeadNonce, u32), Error> {
+        let (write_secret, generation) = self.get_write_secret_and_gen(roster_idx)?;
+
+        // Wrapping these in a SkippedKey means they get erased when we're done with them
+        let message_key = {
+            let (key_bytes, nonce_bytes) = self.derive_key_nonce_bytes(write_secret);
+            SkippedKey {
+                generation: *generation,
+                key_bytes,
+                nonce_bytes,
+            }
+        };
+        let key = AeadKey::new_from_bytes(self.group_cs.aead_impl, &message_key.key_bytes)?;
+        let nonce = AeadNonce::new_from_bytes(self.group_cs.aead_impl, &message_key.nonce_bytes)?;
+        Ok((key, nonce, *generation))
+    }
+
+    /// Ratchets `write_secrets_[roster_idx]` forward, as per section 9.1 of the MLS spec. The old
+    /// write secret is overwritten.
+    ///
+    /// Returns: `Ok(())` on success. If the write secret is out of bounds, returns an
+    /// `Error::ValidationError`. If the write secret's generation is `u32::MAX`, returns an
+    /// `Error::KdfError`.
+    fn ratchet(&mut self, roster_idx: usize) -> Result<(), Error> {
+        let roster_idx_u32 = u32::try_from(roster_idx)
+            .map_err(|_| Error::ValidationError("Roster index exceeds u32::MAX"))?;
+        let (write_secret, generation) = self.get_write_secret_and_gen(roster_idx)?;
+
+        // write_secret_[sender]_[n] =
+        //     HKDF-Expand-Label(write_secret_[sender]_[n-1], "app sender", sender, Hash.length)
+        let new_write_secret = self.next_write_secret(write_secret, roster_idx_u32);
+        let new_generation = generation
             .checked_add(1)
             .ok_or(Error::KdfError("Write secret's generation has hit its max"))?;
 
+        self.write_secrets_and_gens[roster_idx] = Some((new_write_secret, new_generation));
+        Ok(())
+    }
+
+    /// Sets how many generations behind the latest one we've received from a sender we keep
+    /// unused keys for. These keys let us decrypt messages that arrive out of order. A message can
+    /// also be at most this many generations ahead of the one we expect. The default is 0, meaning
+    /// that every sender's messages must arrive in order. Shrinking the window erases any kept keys
+    /// that fall outside of it.
+    pub fn set_skipped_key_window(&mut self, window: u32) {
+        self.skipped_key_window = window;
+        for (skipped, entry) in self.skipped_keys.iter_mut().zip(self.write_secrets_and_gens.iter())
+        {
+            // Forgotten senders have no kept keys
+            if let Some((_, generation)) = entry {
+                // Kept keys are always for generations before the current one, so this can't
+                // underflow
+                skipped.retain(|k| generation - 1 - k.generation <= window);
+            }
+        }
+    }
+
+    /// Finds the key and nonce for a received message from the given sender with the given
+    /// generation. This does not modify the key chain. Once the message has been authenticated,
+    /// the returned `ReceiveKeySource` must be passed to `commit_receive_key`.
+    ///
+    /// Returns: `Ok((key, nonce, source))` on success. Returns an `Error::ReplayedMessage` if the
+    /// generation is older than the sender's current one and we have no key for it, either
+    /// because it was already used or because it fell out of the window. Returns an
+    /// `Error::ValidationError` if the index is out of bounds, the sender has been forgotten, or
+    /// the generation is too far ahead.
+    fn get_receive_key(
+        &self,
+        roster_idx: usize,
+        generation: u32,
+    ) -> Result<(AeadKey, AeadNonce, ReceiveKeySource), Error> {
+        let aead_impl = self.group_cs.aead_impl;
+        let (write_secret, current_generation) = self.get_write_secret_and_gen(roster_idx)?;
+
+        // Generations before the current one are only decryptable if we kept their keys. Every
+        // key is removed once it's used, so anything we don't have has either been seen already
+        // or has expired.
+        if generation < *current_generation {
+            let pos = self.skipped_keys[roster_idx]
+                .iter()
+                .position(|k| k.generation == generation)
+                .ok_or(Error::ReplayedMessage)?;
+            let skipped = &self.skipped_keys[roster_idx][pos];
+            let key = AeadKey::new_from_bytes(aead_impl, &skipped.key_bytes)?;
+            let nonce = AeadNonce::new_from_bytes(aead_impl, &skipped.nonce_bytes)?;
+            return Ok((key, nonce, ReceiveKeySource::Skipped(pos)));
+        }
+
+        if generation - current_generation > self.skipped_key_window {
+            return Err(Error::ValidationError(
+                "Application message's generation is too far ahead of the write secret's",
+            ));
+        }
+
+        // Ratchet a copy of the write secret forward to the message's generation, keeping the
+        // keys of every generation we jump over
+        let roster_idx_u32 = u32::try_from(roster_idx)
+            .map_err(|_| Error::ValidationError("Roster index exceeds u32::MAX"))?;
+        let mut write_secret = write_secret.clone();
+        let mut skipped = Vec::new();
+        for skipped_generation in *current_generation..generation {
+            let (key_bytes, nonce_bytes) = self.derive_key_nonce_bytes(&write_secret);
+            skipped.push(SkippedKey {
+                generation: skipped_generation,
+                key_bytes,
+                nonce_bytes,
+            });
+            write_secret = self.next_write_secret(&write_secret, roster_idx_u32);
+        }
+
+        // Wrapping these in a SkippedKey means they get erased when we're done with them
+        let message_key = {
+            let (key_bytes, nonce_bytes) = self.derive_key_nonce_bytes(&write_secret);
+            SkippedKey {
+                generation,
+                key_bytes,
+                nonce_bytes,
+            }
+        };
+        let key = AeadKey::new_from_bytes(aead_impl, &message_key.key_bytes)?;
+        let nonce = AeadNonce::new_from_bytes(aead_impl, &message_key.nonce_bytes)?;
+        let next_write_secret = self.next_write_secret(&write_secret, roster_idx_u32);
+
+        Ok((
+            key,
+            nonce,
+            ReceiveKeySource::Ratcheted {
+                skipped,
+                next_write_secret,
+            },
+        ))
+    }
+
+    /// Updates the key chain after a message from the given sender with the given generation was
+    /// successfully decrypted and authenticated. The key that was used is erased, and so is every
+    /// kept key that's now outside the window.
+    ///
+    /// Returns: `Ok(())` on success. If the new generation would overflow, returns an
+    /// `Error::KdfError`.
+    fn commit_receive_key(
+        &mut self,
+        roster_idx: usize,
+        generation: u32,
+        source: ReceiveKeySource,
+    ) -> Result<(), Error> {
+        match source {
+            ReceiveKeySource::Skipped(pos) => {
+                self.skipped_keys[roster_idx].remove(pos);
+            }
+            ReceiveKeySource::Ratcheted {
+                skipped,
+                next_write_secret,
+            } => {
+                let new_generation = generation
+                    .checked_add(1)
+                    .ok_or(Error::KdfError("Write secret's generation has hit its max"))?;
+                self.write_secrets_and_gens[roster_idx] = Some((next_write_secret, new_generation));
+                self.skipped_keys[roster_idx].extend(skipped);
+
+                // Only keep keys that are within the window of the generation we just received
+                let window = self.skipped_key_window;
+                self.skipped_keys[roster_idx].retain(|k| generation - k.generation <= window);
+            }
+        }
+
         Ok(())
     }
 
+    /// Returns the epoch this key chain belongs to
+    pub(crate) fn get_epoch(&self) -> u32 {
+        self.group_context.epoch
+    }
+
+    /// Returns the current write secret and generation of every sender that hasn't been forgotten,
+    /// along with their roster index. Exporting these is what `archive` is for, and nothing else
+    /// should need them.
+    pub(crate) fn current_write_secrets(
+        &self,
+    ) -> impl Iterator<Item = (RosterIndex, &HmacKey, u32)> {
+        self.write_secrets_and_gens.iter().enumerate().filter_map(|(roster_idx, entry)| {
+            entry.as_ref().map(|(write_secret, generation)| {
+                (RosterIndex(roster_idx as u32), &write_secret.0, *generation)
+            })
+        })
+    }
+
     /// Validates that this `ApplicationKeyChain` is created from the given `GroupState` and has
     /// sane values
-    #[must_use]
-    fn validate_against_group_state(&self, group_state: &GroupState) -> Result<(), Error> {
+    pub(crate) fn validate_against_group_state(
+        &self,
+        group_state: &GroupState,
+    ) -> Result<(), Error> {
         // Check ownership
-        if group_state.group_id != self.group_id {
+        if group_state.group_id != self.group_context.group_id {
             return Err(Error::ValidationError("Key chain does not belong to this group state"));
         }
         // This shouldn't happen. The key chain should inherit the ciphersuite it was created from
@@ -203,14 +459,197 @@ impl ApplicationKeyChain {
 
         Ok(())
     }
+
+    /// Packs up the secrets of this key chain, to be written out by `MlsClient::save`. The
+    /// `GroupContext` isn't saved, since it's the context of the `GroupState` saved with it.
+    pub(crate) fn to_saved(&self) -> SavedKeyChain {
+        let senders = self
+            .write_secrets_and_gens
+            .iter()
+            .zip(self.skipped_keys.iter())
+            .map(|(entry, skipped_keys)| {
+                entry.as_ref().map(|(write_secret, generation)| SavedSender {
+                    write_secret: SavedSecret::new_from_vec(write_secret.0.as_bytes().to_vec()),
+                    generation: *generation,
+                    skipped_keys: skipped_keys
+                        .iter()
+                        .map(|skipped_key| SavedSkippedKey {
+                            generation: skipped_key.generation,
+                            key: SavedSecret::new_from_vec(skipped_key.key_bytes.clone()),
+                            nonce: SavedSecret::new_from_vec(skipped_key.nonce_bytes.clone()),
+                        })
+                        .collect(),
+                })
+            })
+            .collect();
+
+        SavedKeyChain {
+            senders,
+            skipped_key_window: self.skipped_key_window,
+        }
+    }
+
+    /// Makes the current epoch's key chain of `group_state` from what `to_saved` packed up
+    ///
+    /// Returns: `Ok(app_key_chain)` on success. Returns an `Error::ValidationError` if there isn't
+    /// one sender per roster entry or a secret is the wrong size, and an `Error::SerdeError` if
+    /// the group's `GroupContext` can't be computed.
+    pub(crate) fn from_saved(
+        group_state: &GroupState,
+        saved: SavedKeyChain,
+    ) -> Result<ApplicationKeyChain, Error> {
+        let cs = group_state.cs;
+        if saved.senders.len() != group_state.roster.len() {
+            return Err(Error::ValidationError("Saved key chain doesn't match the roster"));
+        }
+
+        let mut write_secrets_and_gens = Vec::with_capacity(saved.senders.len());
+        let mut skipped_keys = Vec::with_capacity(saved.senders.len());
+        for sender in saved.senders {
+            let sender = match sender {
+                Some(sender) => sender,
+                None => {
+                    write_secrets_and_gens.push(None);
+                    skipped_keys.push(Vec::new());
+                    continue;
+                }
+            };
+            if sender.write_secret.as_bytes().len() != cs.hash_impl.digest_size() {
+                return Err(Error::ValidationError("Saved write secret is the wrong size"));
+            }
+            let write_secret = WriteSecret(HmacKey::new_from_bytes(sender.write_secret.as_bytes()));
+            write_secrets_and_gens.push(Some((write_secret, sender.generation)));
+
+            let mut sender_skipped_keys = Vec::with_capacity(sender.skipped_keys.len());
+            for skipped_key in sender.skipped_keys.iter() {
+                if skipped_key.key.as_bytes().len() != cs.aead_impl.key_size()
+                    || skipped_key.nonce.as_bytes().len() != cs.aead_impl.nonce_size()
+                {
+                    return Err(Error::ValidationError("Saved skipped key is the wrong size"));
+                }
+                sender_skipped_keys.push(SkippedKey {
+                    generation: skipped_key.generation,
+                    key_bytes: skipped_key.key.as_bytes().to_vec(),
+                    nonce_bytes: skipped_key.nonce.as_bytes().to_vec(),
+                });
+            }
+            skipped_keys.push(sender_skipped_keys);
+        }
+
+        Ok(ApplicationKeyChain {
+            write_secrets_and_gens,
+            skipped_keys,
+            skipped_key_window: saved.skipped_key_window,
+            group_cs: cs,
+            group_context: group_state.group_context()?,
+            receive_only: false,
+        })
+    }
+}
+
+// struct {
+//     optional<SavedSender> senders<0..2^32-1>;
+//     uint32 skipped_key_window;
+// } SavedKeyChain;
+/// The secrets of an `ApplicationKeyChain`, as written by `MlsClient::save`. See
+/// `ApplicationKeyChain::to_saved`.
+#[derive(Deserialize, Serialize)]
+pub(crate) struct SavedKeyChain {
+    // One per roster entry. Forgotten senders are None.
+    #[serde(rename = "senders__bound_u32")]
+    senders: Vec<Option<SavedSender>>,
+    skipped_key_window: u32,
+}
+
+// struct {
+//     SavedSecret write_secret;
+//     uint32 generation;
+//     SavedSkippedKey skipped_keys<0..2^32-1>;
+// } SavedSender;
+#[derive(Deserialize, Serialize)]
+struct SavedSender {
+    write_secret: SavedSecret,
+    generation: u32,
+    #[serde(rename = "skipped_keys__bound_u32")]
+    skipped_keys: Vec<SavedSkippedKey>,
+}
+
+// struct {
+//     uint32 generation;
+//     SavedSecret key;
+//     SavedSecret nonce;
+// } SavedSkippedKey;
+#[derive(Deserialize, Serialize)]
+struct SavedSkippedKey {
+    generation: u32,
+    key: SavedSecret,
+    nonce: SavedSecret,
 }
 
 //
 // Everything after this (not including tests) is non-standard
 //
 
-/// A signed payload of an application message. This can be padded at the end by an arbitrary
-/// number of zeros. This property is checked in constant time upon deserialization
+/// How a group pads its application messages before encrypting them, in order to hide their
+/// lengths. The group's scheme is stored as a group extension, so every member pads and checks
+/// padding the same way. Groups without this extension use `PaddingScheme::None`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename = "PaddingScheme__enum_u8")]
+pub enum PaddingScheme {
+    /// Messages are not padded
+    #[default]
+    None,
+    /// Messages are padded up to the next multiple of the given block size. A block size of 0 is
+    /// treated as `PaddingScheme::None`.
+    Block(u16),
+    /// Messages are padded according to the Padmé scheme from "Reducing Metadata Leakage from
+    /// Encrypted Files and Communication with PURBs" (Nikitin et al.). This leaks at most
+    /// O(log log L) bits of the length L, with at most 12% overhead.
+    Padme,
+}
+
+impl KnownExtension for PaddingScheme {
+    const EXTENSION_TYPE: ExtensionType = extensions::PADDING_SCHEME;
+}
+
+impl PaddingScheme {
+    /// Returns the length that a message of length `len` is padded to under this scheme. This is
+    /// always at least `len`.
+    pub fn padded_len(&self, len: usize) -> usize {
+        match *self {
+            PaddingScheme::None | PaddingScheme::Block(0) => len,
+            PaddingScheme::Block(block_size) => {
+                let block_size = block_size as usize;
+                // Round up to the next multiple of block_size
+                match len % block_size {
+                    0 => len,
+                    rem => len + (block_size - rem),
+                }
+            }
+            PaddingScheme::Padme => {
+                // Padmé on lengths this small is a no-op anyway, and log2(0) is undefined
+                if len < 2 {
+                    return len;
+                }
+                // E = floor(log2(L)), S = floor(log2(E)) + 1. The low E - S bits of the padded
+                // length are zero.
+                let e = floor_log2(len);
+                let s = floor_log2(e) + 1;
+                let last_bits = e - s;
+                let bit_mask = (1usize << last_bits) - 1;
+                (len + bit_mask) & !bit_mask
+            }
+        }
+    }
+}
+
+/// Returns floor(log2(n)). `n` must be nonzero.
+fn floor_log2(n: usize) -> usize {
+    (core::mem::size_of::<usize>() * 8) - 1 - (n.leading_zeros() as usize)
+}
+
+/// A signed payload of an application message. This can be padded at the end by zeros, according
+/// to the group's `PaddingScheme`. The padding is checked in constant time upon decryption.
 #[derive(Deserialize, Serialize)]
 #[serde(rename = "ApplicationMessageContent__zero_padded")]
 struct ApplicationMessageContent {
@@ -233,22 +672,83 @@ pub struct ApplicationMessage {
     group_id: Vec<u8>,
     epoch: u32,
     generation: u32,
-    sender: u32,
+    sender: RosterIndex,
+    #[serde(rename = "authenticated_data__bound_u32")]
+    authenticated_data: Vec<u8>,
     #[serde(rename = "encrypted_content__bound_u32")]
     encrypted_content: Vec<u8>,
 }
 
-#[derive(Deserialize, Serialize)]
+impl ApplicationMessage {
+    /// Returns the ID of the group this message was sent in
+    pub fn get_group_id(&self) -> &[u8] {
+        &self.group_id
+    }
+
+    /// Returns the epoch this message was sent in
+    pub fn get_epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// Returns the caller-supplied authenticated data this message was sent with. This is sent in
+    /// the clear, so it can be used for routing. It is NOT authenticated until the message is
+    /// decrypted, at which point it's returned in the `DecryptedMessage`.
+    pub fn get_authenticated_data(&self) -> &[u8] {
+        &self.authenticated_data
+    }
+}
+
+/// An application message that's been decrypted and authenticated, along with who sent it and
+/// when. Everything in here is covered by the sender's signature.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DecryptedMessage {
+    /// The message itself
+    pub plaintext: Vec<u8>,
+    /// The caller-supplied data that was sent in the clear alongside the message
+    pub authenticated_data: Vec<u8>,
+    /// The sender's roster index
+    pub sender: RosterIndex,
+    /// The sender's credential, as of the epoch the message was sent in
+    pub sender_credential: Credential,
+    /// The epoch the message was sent in
+    pub epoch: u32,
+    /// The message's generation, i.e., how many messages the sender had sent in this epoch before
+    /// this one
+    pub generation: u32,
+}
+
+// struct {
+//     GroupContext group_context;
+//     uint32 generation;
+//     uint32 sender;
+//     opaque authenticated_data<0..2^32-1>;
+//     opaque content<0..2^32-1>;
+// } SignatureContent;
+/// What an application message's signature is computed over. The `GroupContext` holds the
+/// group ID and epoch.
+#[derive(Serialize)]
 struct SignatureContent<'a> {
-    #[serde(rename = "group_id__bound_u8")]
-    group_id: &'a [u8],
-    epoch: u32,
+    group_context: &'a GroupContext,
     generation: u32,
-    sender: u32,
+    sender: RosterIndex,
+    #[serde(rename = "authenticated_data__bound_u32")]
+    authenticated_data: &'a [u8],
     #[serde(rename = "content__bound_u32")]
     content: &'a [u8],
 }
 
+// struct {
+//     GroupContext group_context;
+//     opaque authenticated_data<0..2^32-1>;
+// } ContentAad;
+/// The associated data of an application message's AEAD
+#[derive(Serialize)]
+struct ContentAad<'a> {
+    group_context: &'a GroupContext,
+    #[serde(rename = "authenticated_data__bound_u32")]
+    authenticated_data: &'a [u8],
+}
+
 /// Encrypts the given plaintext with the appropriate key and nonce derived from the sender's
 /// current `WriteSecret` in this application key chain
 ///
@@ -261,60 +761,98 @@ pub fn encrypt_application_message(
     plaintext: Vec<u8>,
     group_state: &GroupState,
     app_key_chain: &mut ApplicationKeyChain,
+) -> Result<ApplicationMessage, Error> {
+    encrypt_application_message_with_aad(plaintext, Vec::new(), group_state, app_key_chain)
+}
+
+/// Encrypts the given plaintext like `encrypt_application_message`, and attaches the given
+/// `authenticated_data` to it. The authenticated data is sent unencrypted, but is covered by the
+/// AEAD and the sender's signature. This is the place for metadata like message types or thread
+/// IDs, which a higher-level protocol needs to route on without decrypting.
+///
+/// Returns: `Ok(app_message)` on success. Otherwise, if one of myriad things goes wrong, returns
+/// some sort of `Error`.
+pub fn encrypt_application_message_with_aad(
+    plaintext: Vec<u8>,
+    authenticated_data: Vec<u8>,
+    group_state: &GroupState,
+    app_key_chain: &mut ApplicationKeyChain,
 ) -> Result<ApplicationMessage, Error> {
     // Check that this key chain really does belong to this group_state
     app_key_chain.validate_against_group_state(group_state)?;
 
+    // Nobody should be sending messages in an epoch that's already over
+    if app_key_chain.receive_only {
+        return Err(Error::ValidationError("Cannot encrypt with a past epoch's key chain"));
+    }
+
     // The validation above ensures these values are the same for the key chain as for the group
     let group_id = &group_state.group_id;
     let cs = group_state.cs;
 
-    // Get the signature scheme from this member of the group_state
-    let ss = group_state.get_signature_scheme();
-
     // This really really shouldn't be able to happen. A preliminary GroupState couldn't even
     // produce an ApplicationSecret to make this key chain in the first place.
     let my_roster_idx = group_state
         .roster_index
         .ok_or(Error::ValidationError("Cannot encrypt a message with a preliminary GroupState"))?;
-    let (key, nonce, generation) = app_key_chain.get_key_nonce_gen(my_roster_idx as usize)?;
 
-    // Sign the message. The epoch we use is the one that was current at the time of the creation of
-    // the key chain. This way, we could have multiple key chains in use at the same time and still
-    // be able to update the GroupState
+    // Refuse to go past this member's send limit. Generations start at 0 every epoch, so our
+    // generation is the number of messages we've sent in this one.
+    if let Some(max_messages) = group_state.config.max_messages_per_epoch {
+        if app_key_chain.get_generation(my_roster_idx.0 as usize)? >= max_messages {
+            return Err(Error::UpdateRequired);
+        }
+    }
+    let (key, nonce, generation) = app_key_chain.get_key_nonce_gen(my_roster_idx.0 as usize)?;
+
+    // Sign the message. The context we use is the one that was current at the time of the creation
+    // of the key chain. This way, we could have multiple key chains in use at the same time and
+    // still be able to update the GroupState
+    let group_context = &app_key_chain.group_context;
+    let epoch = group_context.epoch;
     let signature_content = SignatureContent {
-        group_id: &group_id,
-        epoch: app_key_chain.group_epoch_at_creation,
+        group_context,
         generation,
         sender: my_roster_idx,
+        authenticated_data: &authenticated_data,
         content: &plaintext,
     };
     let hashed_signature_content = cs.hash_impl.hash_serializable(&signature_content)?;
-    let sig = ss.sign(&group_state.identity_key, hashed_signature_content.as_bytes());
+    let sig = group_state.sign_with_identity_key(hashed_signature_content.as_bytes())?;
 
     // Pack the plaintext and signature together and encrypt it
+    let plaintext_len = plaintext.len();
     let message_content = ApplicationMessageContent {
         content: plaintext,
         signature: sig.as_bytes(),
     };
+    let padding_scheme = group_state.get_padding_scheme()?;
     let encrypted_content = {
-        // Serialize the ApplicationMessageContent and make room for the tag
+        // Serialize the ApplicationMessageContent, pad it, and make room for the tag
         let mut serialized_message_content = tls_ser::serialize_to_bytes(&message_content)?;
+        let padded_len = padding_scheme.padded_len(serialized_message_content.len());
+        serialized_message_content.resize(padded_len, 0u8);
         serialized_message_content.extend(vec![0u8; cs.aead_impl.tag_size()]);
 
-        // Encrypt it
-        cs.aead_impl.seal(&key, nonce, &mut serialized_message_content)?;
+        // Encrypt it, with the group context and authenticated data as associated data
+        let aad = tls_ser::serialize_to_bytes(&ContentAad {
+            group_context,
+            authenticated_data: &authenticated_data,
+        })?;
+        cs.aead_impl.seal(&key, nonce, &aad, &mut serialized_message_content)?;
         serialized_message_content
     };
 
     // All good. Now ratchet the write secret forward
-    app_key_chain.ratchet(my_roster_idx as usize)?;
+    app_key_chain.ratchet(my_roster_idx.0 as usize)?;
+    metrics::report(|m| m.message_encrypted(group_id, plaintext_len, encrypted_content.len()));
 
     Ok(ApplicationMessage {
         group_id: group_state.group_id.clone(),
-        epoch: app_key_chain.group_epoch_at_creation,
+        epoch,
         generation,
         sender: my_roster_idx,
+        authenticated_data,
         encrypted_content,
     })
 }
@@ -322,15 +860,15 @@ pub fn encrypt_application_message(
 /// Decrypts the given application message with the appropriate key and nonce derived from the
 /// sender's current `WriteSecret` in this application key chain
 ///
-/// Returns: `Ok(plaintext)` on success. Otherwise, if one of myriad things goes wrong, returns some
-/// sort of `Error`.
+/// Returns: `Ok(decrypted_message)` on success, where `decrypted_message` has the plaintext and
+/// who sent it. Otherwise, if one of myriad things goes wrong, returns some sort of `Error`.
 // Note that this still has to take in a `GroupState` because the group's roster is liable to change
 // over time, and the roster is necessary to verify message signatures.
 pub fn decrypt_application_message(
     mut app_message: ApplicationMessage,
     group_state: &GroupState,
     app_key_chain: &mut ApplicationKeyChain,
-) -> Result<Vec<u8>, Error> {
+) -> Result<DecryptedMessage, Error> {
     // Check that this key chain really does belong to this group_state
     app_key_chain.validate_against_group_state(group_state)?;
 
@@ -348,21 +886,19 @@ pub fn decrypt_application_message(
     // Again, the reason we use the current epoch at the time of the creation of this key chain is
     // so we could have multiple key chains in use at the same time and be able to update the
     // GroupState independently
-    if app_message.epoch != app_key_chain.group_epoch_at_creation {
+    if app_message.epoch != app_key_chain.group_context.epoch {
         return Err(Error::ValidationError(
             "Application message's epoch differs from the key chain's",
         ));
     }
 
-    // Get the secrets necessary to decrypt it
-    let (key, nonce, generation) = app_key_chain.get_key_nonce_gen(app_message.sender as usize)?;
-
-    // The WriteSecret generations need to match up
-    if app_message.generation != generation {
-        return Err(Error::ValidationError(
-            "Application message's generation differs from the write secret's",
-        ));
-    }
+    // Get the secrets necessary to decrypt it. Nothing in the key chain changes until the message
+    // is authenticated. Every key is erased once it's used, so a generation we have no key for has
+    // either been decrypted already or has fallen out of the skipped key window. Either way, this
+    // returns an Error::ReplayedMessage.
+    let generation = app_message.generation;
+    let (key, nonce, key_source) =
+        app_key_chain.get_receive_key(app_message.sender.0 as usize, generation)?;
 
     // Get the sender's public key and preferred signature scheme from the roster. There are two
     // things that can go wrong here: either the sender index is bad, or the index is good but the
@@ -370,54 +906,88 @@ pub fn decrypt_application_message(
     let sender_credential = group_state
         .roster
         .0
-        .get(app_message.sender as usize)
+        .get(app_message.sender.0 as usize)
         .ok_or(Error::ValidationError("Application message's sender index is out of bounds"))?
         .as_ref()
         .ok_or(Error::ValidationError("Application message's sender credential is empty"))?;
-    let sender_pubkey = sender_credential.get_public_key();
     let sender_ss = sender_credential.get_signature_scheme();
 
     // Reconstruct the content of the message as well as its signature
+    let padding_scheme = group_state.get_padding_scheme()?;
+    let aad = tls_ser::serialize_to_bytes(&ContentAad {
+        group_context: &app_key_chain.group_context,
+        authenticated_data: &app_message.authenticated_data,
+    })?;
     let serialized_message_content =
-        cs.aead_impl.open(&key, nonce, &mut app_message.encrypted_content)?;
+        cs.aead_impl.open(&key, nonce, &aad, &mut app_message.encrypted_content)?;
     let message_content = {
         let mut cursor: &[u8] = serialized_message_content;
-        let mut deserializer = TlsDeserializer::from_reader(&mut cursor);
-        ApplicationMessageContent::deserialize(&mut deserializer)?
+        let message_content = {
+            let mut deserializer = TlsDeserializer::from_reader(&mut cursor);
+            ApplicationMessageContent::deserialize(&mut deserializer)?
+        };
+
+        // Whatever is left over is padding. It has to be all zeros, and there has to be exactly
+        // as much of it as the group's padding scheme says. Check the zeros in constant time.
+        let unpadded_len = serialized_message_content.len() - cursor.len();
+        let padding_is_zero: bool = cursor.iter().fold(0u8, |acc, b| acc | b).ct_eq(&0u8).into();
+        if !padding_is_zero {
+            return Err(Error::ValidationError("Application message padding isn't all zeros"));
+        }
+        if padding_scheme.padded_len(unpadded_len) != serialized_message_content.len() {
+            return Err(Error::ValidationError(
+                "Application message padding doesn't match the group's padding scheme",
+            ));
+        }
+
+        message_content
     };
     let plaintext = message_content.content;
     let signature = Signature::new_from_bytes(sender_ss, &message_content.signature)?;
 
     // Create the stuff that the signature is over, then verify the signature. See above for why we
-    // use group_epoch_at_creation
+    // use the key chain's group context
     let signature_content = SignatureContent {
-        group_id,
-        epoch: app_key_chain.group_epoch_at_creation,
+        group_context: &app_key_chain.group_context,
         generation,
         sender: app_message.sender,
+        authenticated_data: &app_message.authenticated_data,
         content: &plaintext,
     };
     let hashed_signature_content = cs.hash_impl.hash_serializable(&signature_content)?;
-    sender_ss.verify(sender_pubkey, hashed_signature_content.as_bytes(), &signature)?;
+    sender_credential.verify(hashed_signature_content.as_bytes(), &signature)?;
 
-    // All good. Now ratchet the write secret forward
-    app_key_chain.ratchet(app_message.sender as usize)?;
+    // All good. Now erase the key we used and ratchet the write secret forward if need be
+    app_key_chain.commit_receive_key(app_message.sender.0 as usize, generation, key_source)?;
+    metrics::report(|m| {
+        m.message_decrypted(group_id, plaintext.len(), app_message.encrypted_content.len())
+    });
 
-    Ok(plaintext)
+    Ok(DecryptedMessage {
+        plaintext,
+        authenticated_data: app_message.authenticated_data,
+        sender: app_message.sender,
+        sender_credential: sender_credential.clone(),
+        epoch: app_message.epoch,
+        generation,
+    })
 }
 
 #[cfg(test)]
 mod test {
     use crate::{
         application::{
-            decrypt_application_message, encrypt_application_message, ApplicationKeyChain,
+            decrypt_application_message, encrypt_application_message,
+            encrypt_application_message_with_aad, ApplicationKeyChain, PaddingScheme,
         },
         crypto::{
             aead::{AeadKey, AeadNonce},
             ciphersuite::X25519_SHA256_AES128GCM,
+            hash::Digest,
             hmac::HmacKey,
             rng::CryptoRng,
         },
+        error::Error,
         group_state::GroupState,
         ratchet_tree::PathSecret,
         test_utils,
@@ -464,7 +1034,7 @@ mod test {
         // but with a different roster index
         let index2 = test_utils::random_roster_index_with_exceptions(
             group_state1.roster.len(),
-            &[group_state1.roster_index.unwrap() as usize],
+            &[group_state1.roster_index.unwrap().0 as usize],
             &mut rng,
         );
         let mut group_state2 = test_utils::change_self_index(&group_state1, &identity_keys, index2);
@@ -481,21 +1051,187 @@ mod test {
             let mut plaintext = orig_msg.to_vec();
             plaintext.extend(vec![0u8; group_state1.cs.aead_impl.tag_size()]);
 
-            let (key, nonce, _) = app_key_chain1.get_key_nonce_gen(index1 as usize).unwrap();
-            group_state1.cs.aead_impl.seal(&key, nonce, &mut plaintext).unwrap();
+            let (key, nonce, _) = app_key_chain1.get_key_nonce_gen(index1.0 as usize).unwrap();
+            group_state1.cs.aead_impl.seal(&key, nonce, b"", &mut plaintext).unwrap();
             plaintext
         };
 
         // Group 2 will decrypt it
         let plaintext = {
-            let (key, nonce, _) = app_key_chain2.get_key_nonce_gen(index1 as usize).unwrap();
-            group_state2.cs.aead_impl.open(&key, nonce, &mut ciphertext).unwrap()
+            let (key, nonce, _) = app_key_chain2.get_key_nonce_gen(index1.0 as usize).unwrap();
+            group_state2.cs.aead_impl.open(&key, nonce, b"", &mut ciphertext).unwrap()
         };
 
         // Make sure they agree
         assert_eq!(plaintext, orig_msg);
     }
 
+    // Check that decrypting the same message twice fails with a ReplayedMessage the second time
+    #[quickcheck]
+    fn replay_rejection(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+
+        // Make a group of at least 2 people from two perspectives, and get key chains for both
+        let (mut group_state1, identity_keys) = test_utils::random_full_group_state(2, &mut rng);
+        let index2 = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[group_state1.roster_index.unwrap().0 as usize],
+            &mut rng,
+        );
+        let mut group_state2 = test_utils::change_self_index(&group_state1, &identity_keys, index2);
+        let (mut app_key_chain1, mut app_key_chain2) =
+            do_update_op(&mut group_state1, &mut group_state2, &mut rng);
+
+        // Send two messages and have the delivery service replay the first one at the end
+        let msg1 = encrypt_application_message(b"one".to_vec(), &group_state1, &mut app_key_chain1)
+            .unwrap();
+        let msg2 = encrypt_application_message(b"two".to_vec(), &group_state1, &mut app_key_chain1)
+            .unwrap();
+        let replayed_msg1 = msg1.clone();
+
+        decrypt_application_message(msg1, &group_state2, &mut app_key_chain2).unwrap();
+        decrypt_application_message(msg2, &group_state2, &mut app_key_chain2).unwrap();
+        match decrypt_application_message(replayed_msg1, &group_state2, &mut app_key_chain2) {
+            Err(Error::ReplayedMessage) => (),
+            Err(e) => panic!("replayed message gave the wrong error: {:?}", e),
+            Ok(_) => panic!("replayed message was accepted"),
+        }
+    }
+
+    // Checks that messages can arrive out of order within the skipped key window, that every key
+    // works exactly once, and that keys outside the window are gone
+    #[quickcheck]
+    fn out_of_order_decryption(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+
+        let (mut group_state1, identity_keys) = test_utils::random_full_group_state(2, &mut rng);
+        let index2 = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[group_state1.roster_index.unwrap().0 as usize],
+            &mut rng,
+        );
+        let mut group_state2 = test_utils::change_self_index(&group_state1, &identity_keys, index2);
+        let (mut app_key_chain1, mut app_key_chain2) =
+            do_update_op(&mut group_state1, &mut group_state2, &mut rng);
+
+        // Send generations 0 through 8
+        let msgs: Vec<_> = (0u8..9)
+            .map(|i| {
+                encrypt_application_message(vec![i], &group_state1, &mut app_key_chain1).unwrap()
+            })
+            .collect();
+        let decrypt = |i: usize, app_key_chain: &mut ApplicationKeyChain| {
+            decrypt_application_message(msgs[i].clone(), &group_state2, app_key_chain)
+                .map(|m| m.plaintext)
+        };
+        let assert_err = |res: Result<Vec<u8>, Error>, replay: bool| match (res, replay) {
+            (Err(Error::ReplayedMessage), true) | (Err(Error::ValidationError(_)), false) => (),
+            (Err(e), _) => panic!("out-of-order message gave the wrong error: {:?}", e),
+            (Ok(_), _) => panic!("out-of-order message was accepted"),
+        };
+
+        // By default, messages have to come in order
+        assert_err(decrypt(1, &mut app_key_chain2), false);
+
+        // With a window, they can come in any order, but only once
+        app_key_chain2.set_skipped_key_window(3);
+        assert_eq!(decrypt(2, &mut app_key_chain2).unwrap(), vec![2]);
+        assert_eq!(decrypt(0, &mut app_key_chain2).unwrap(), vec![0]);
+        assert_err(decrypt(0, &mut app_key_chain2), true);
+        assert_eq!(decrypt(1, &mut app_key_chain2).unwrap(), vec![1]);
+        assert_eq!(decrypt(3, &mut app_key_chain2).unwrap(), vec![3]);
+
+        // Generation 8 is too far ahead of 4, but 7 isn't
+        assert_err(decrypt(8, &mut app_key_chain2), false);
+        assert_eq!(decrypt(7, &mut app_key_chain2).unwrap(), vec![7]);
+
+        // Shrinking the window to 1 erases the keys for generations 4 and 5
+        app_key_chain2.set_skipped_key_window(1);
+        assert_err(decrypt(4, &mut app_key_chain2), true);
+        assert_err(decrypt(5, &mut app_key_chain2), true);
+        assert_eq!(decrypt(6, &mut app_key_chain2).unwrap(), vec![6]);
+        assert_eq!(decrypt(8, &mut app_key_chain2).unwrap(), vec![8]);
+    }
+
+    // Checks that padded lengths are never shorter than the original, that block padding lands on
+    // a block boundary, and that Padmé agrees with some hand-computed values
+    #[quickcheck]
+    fn padding_lengths(len: u32, block_size: u16) {
+        let len = len as usize;
+
+        assert_eq!(PaddingScheme::None.padded_len(len), len);
+        assert_eq!(PaddingScheme::Block(0).padded_len(len), len);
+
+        let block_padded = PaddingScheme::Block(block_size).padded_len(len);
+        if block_size != 0 {
+            assert_eq!(block_padded % block_size as usize, 0);
+            assert!(block_padded >= len && block_padded < len + block_size as usize);
+        }
+
+        // Padmé overhead is at most 12%
+        let padme_padded = PaddingScheme::Padme.padded_len(len);
+        assert!(padme_padded >= len);
+        assert!(padme_padded - len <= len / 8);
+
+        assert_eq!(PaddingScheme::Padme.padded_len(100), 104);
+        assert_eq!(PaddingScheme::Padme.padded_len(1000), 1024);
+        assert_eq!(PaddingScheme::Padme.padded_len(1024), 1024);
+    }
+
+    // Checks that messages in a group with a padding scheme have their lengths hidden, still
+    // decrypt correctly, and are rejected by a member who expects a different scheme
+    #[quickcheck]
+    fn padded_message_correctness(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+
+        // Make a group that pads to 256-byte blocks, from two perspectives
+        let (mut group_state1, identity_keys) = test_utils::random_full_group_state(2, &mut rng);
+        group_state1.extensions.insert(&PaddingScheme::Block(256)).unwrap();
+        let index2 = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[group_state1.roster_index.unwrap().0 as usize],
+            &mut rng,
+        );
+        let mut group_state2 = test_utils::change_self_index(&group_state1, &identity_keys, index2);
+        assert_eq!(group_state2.get_padding_scheme().unwrap(), PaddingScheme::Block(256));
+        let (mut app_key_chain1, mut app_key_chain2) =
+            do_update_op(&mut group_state1, &mut group_state2, &mut rng);
+
+        // A short and a slightly less short message should be indistinguishable by length
+        let msg1 = encrypt_application_message(b"hi".to_vec(), &group_state1, &mut app_key_chain1)
+            .unwrap();
+        let msg2 = encrypt_application_message(
+            b"hello there".to_vec(),
+            &group_state1,
+            &mut app_key_chain1,
+        )
+        .unwrap();
+        assert_eq!(msg1.encrypted_content.len(), msg2.encrypted_content.len());
+
+        assert_eq!(
+            decrypt_application_message(msg1, &group_state2, &mut app_key_chain2)
+                .unwrap()
+                .plaintext,
+            b"hi"
+        );
+        assert_eq!(
+            decrypt_application_message(msg2, &group_state2, &mut app_key_chain2)
+                .unwrap()
+                .plaintext,
+            b"hello there"
+        );
+
+        // If the receiver thinks there's no padding, the message is rejected
+        let msg3 = encrypt_application_message(b"bye".to_vec(), &group_state1, &mut app_key_chain1)
+            .unwrap();
+        group_state2.extensions.insert(&PaddingScheme::None).unwrap();
+        match decrypt_application_message(msg3, &group_state2, &mut app_key_chain2) {
+            Err(Error::ValidationError(_)) => (),
+            Err(e) => panic!("mismatched padding gave the wrong error: {:?}", e),
+            Ok(_) => panic!("mismatched padding was accepted"),
+        }
+    }
+
     // The following test vector is from
     // https://github.com/mlswg/mls-implementations/tree/68d1cf562d6e489c3025a4b6d0e4e18725674349/test_vectors
     //
@@ -542,6 +1278,8 @@ mod test {
 
     #[derive(Debug, Deserialize)]
     struct AppKeyStep {
+        // Application secrets aren't exposed, so only the keys and nonces get checked
+        #[allow(dead_code)]
         #[serde(rename = "secret__bound_u8")]
         secret: Vec<u8>,
         #[serde(rename = "key__bound_u8")]
@@ -564,6 +1302,8 @@ mod test {
         num_generations: u32,
         #[serde(rename = "application_secret__bound_u8")]
         application_secret: Vec<u8>,
+        // We don't have P-256 DH, so this case is parsed but not checked
+        #[allow(dead_code)]
         case_p256: AppKeyScheduleCase,
         case_x25519: AppKeyScheduleCase,
     }
@@ -599,7 +1339,7 @@ mod test {
         // Finally make the application key chain with the given application secret and correct
         // number of members
         let mut app_key_chain =
-            ApplicationKeyChain::from_application_secret(&dummy_group_state, app_secret);
+            ApplicationKeyChain::from_application_secret(&dummy_group_state, app_secret).unwrap();
 
         // The element at index i of this vector is a sequence of write_secrets belonging to member
         // i of the group. The sequence goes in generational order, starting at 0.
@@ -633,7 +1373,7 @@ mod test {
                     plaintext.extend(vec![0u8; cs.aead_impl.tag_size()]);
 
                     // Encrypt the thing in-place and return the mutated plaintext
-                    cs.aead_impl.seal(&given_key, given_nonce, &mut plaintext).unwrap();
+                    cs.aead_impl.seal(&given_key, given_nonce, b"", &mut plaintext).unwrap();
                     plaintext
                 };
 
@@ -642,7 +1382,7 @@ mod test {
                 let plaintext = {
                     let (derived_key, derived_nonce, _) =
                         app_key_chain.get_key_nonce_gen(roster_idx).unwrap();
-                    cs.aead_impl.open(&derived_key, derived_nonce, &mut ciphertext).unwrap()
+                    cs.aead_impl.open(&derived_key, derived_nonce, b"", &mut ciphertext).unwrap()
                 };
 
                 // Make sure the decrypted ciphertext is equal to the original message
@@ -671,12 +1411,21 @@ mod test {
             let app_message =
                 encrypt_application_message(orig_msg.to_vec(), group1, app_key_chain1).unwrap();
 
+            let (epoch, generation) = (app_message.epoch, app_message.generation);
+
             // Group 2 will decrypt it
-            let plaintext =
+            let decrypted =
                 decrypt_application_message(app_message, group2, app_key_chain2).unwrap();
 
-            // Make sure it's the same after a round trip
-            assert_eq!(plaintext.as_slice(), orig_msg);
+            // Make sure it's the same after a round trip, and that it's attributed to Group 1
+            let sender = group1.roster_index.unwrap();
+            assert_eq!(decrypted.plaintext.as_slice(), orig_msg);
+            assert_eq!(decrypted.sender, sender);
+            assert_eq!(
+                Some(&decrypted.sender_credential),
+                group2.roster.0[sender.0 as usize].as_ref()
+            );
+            assert_eq!((decrypted.epoch, decrypted.generation), (epoch, generation));
         }
 
         let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
@@ -688,7 +1437,7 @@ mod test {
         // but with a different roster index
         let new_roster_idx = test_utils::random_roster_index_with_exceptions(
             group_state1.roster.len(),
-            &[group_state1.roster_index.unwrap() as usize],
+            &[group_state1.roster_index.unwrap().0 as usize],
             &mut rng,
         );
         let mut group_state2 =
@@ -775,6 +1524,56 @@ mod test {
 
     // A cursory test that our validation checks and ratcheting mechanism is working sufficiently
     // well to prevent misuse
+    // Tests that caller-supplied authenticated data makes it through unencrypted, and that it can't
+    // be tampered with
+    #[quickcheck]
+    fn authenticated_data_correctness(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+
+        let (mut group_state1, identity_keys) = test_utils::random_full_group_state(2, &mut rng);
+        let new_roster_idx = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[group_state1.roster_index.unwrap().0 as usize],
+            &mut rng,
+        );
+        let mut group_state2 =
+            test_utils::change_self_index(&group_state1, &identity_keys, new_roster_idx);
+        let (mut app_key_chain1, mut app_key_chain2) =
+            do_update_op(&mut group_state1, &mut group_state2, &mut rng);
+
+        // Group 1 sends a message with some routing metadata. The metadata is readable before
+        // decryption.
+        let orig_msg = b"see you in the thread".to_vec();
+        let aad = b"thread:42".to_vec();
+        let app_message = encrypt_application_message_with_aad(
+            orig_msg.clone(),
+            aad.clone(),
+            &group_state1,
+            &mut app_key_chain1,
+        )
+        .unwrap();
+        assert_eq!(app_message.get_authenticated_data(), aad.as_slice());
+
+        // Changing the authenticated data in transit should make decryption fail. This doesn't
+        // touch the key chain, so the untampered message can be decrypted afterwards.
+        let mut tampered = app_message.clone();
+        tampered.authenticated_data = b"thread:43".to_vec();
+        assert!(decrypt_application_message(tampered, &group_state2, &mut app_key_chain2).is_err());
+
+        // Group 2 decrypts the real thing and gets back both the plaintext and the metadata
+        let decrypted =
+            decrypt_application_message(app_message, &group_state2, &mut app_key_chain2).unwrap();
+        assert_eq!(decrypted.plaintext, orig_msg);
+        assert_eq!(decrypted.authenticated_data, aad);
+
+        // Messages without authenticated data come back with an empty one
+        let app_message =
+            encrypt_application_message(orig_msg, &group_state1, &mut app_key_chain1).unwrap();
+        let decrypted =
+            decrypt_application_message(app_message, &group_state2, &mut app_key_chain2).unwrap();
+        assert!(decrypted.authenticated_data.is_empty());
+    }
+
     #[quickcheck]
     fn application_message_soundness(rng_seed: u64) {
         let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
@@ -784,7 +1583,7 @@ mod test {
         // The second perspective cannot be the same as the first
         let new_roster_idx = test_utils::random_roster_index_with_exceptions(
             group_state1.roster.len(),
-            &[group_state1.roster_index.unwrap() as usize],
+            &[group_state1.roster_index.unwrap().0 as usize],
             &mut rng,
         );
         let mut group_state2 =
@@ -826,5 +1625,25 @@ mod test {
             &mut app_key_chain2
         )
         .is_err());
+
+        // Group 2 tries to decrypt it with a key chain whose group context has a different tree
+        // hash. The secrets are all the same, but the AEAD's associated data isn't, so this should
+        // error. The key chain is untouched by the failure.
+        let real_tree_hash = app_key_chain2.group_context.tree_hash.clone();
+        let digest_len = real_tree_hash.as_bytes().len();
+        app_key_chain2.group_context.tree_hash =
+            Digest::new_from_bytes(group_state2.cs.hash_impl, &vec![0xff; digest_len]).unwrap();
+        assert!(decrypt_application_message(
+            app_message.clone(),
+            &group_state2,
+            &mut app_key_chain2
+        )
+        .is_err());
+
+        // With the real group context, it decrypts fine
+        app_key_chain2.group_context.tree_hash = real_tree_hash;
+        assert!(
+            decrypt_application_message(app_message, &group_state2, &mut app_key_chain2).is_ok()
+        );
     }
 }
@@ -0,0 +1,451 @@
+//! Exporting a group's final state for record-keeping. This is for organizations that have to
+//! retain what happened in a group after they're done with it. `seal_group_archive` packs up the
+//! group's final roster, epoch, and transcript hash, the handshakes that got it there (if the
+//! `Session` kept a `HistoryLog`), and, only if explicitly asked for, the decryption secrets of
+//! the final epoch. The result is signed by the member who made it and encrypted to an archivist's
+//! `UserInitKey`, the same way a `Welcome` is.
+//!
+//! Nothing in a running group uses this module, and nothing here is needed to take part in one.
+//! Archives are only ever handed out sealed.
+
+use crate::{
+    application::ApplicationKeyChain,
+    credential::{Roster, RosterIndex},
+    crypto::{
+        ciphersuite::CipherSuite,
+        ecies::{self, EciesCiphertext},
+        rng::CryptoRng,
+        sig::Signature,
+    },
+    error::Error,
+    group_state::GroupState,
+    handshake::UserInitKey,
+    history::{HistoryEntry, HistoryLog},
+    tls_ser,
+    upcast::{self, CryptoCtx},
+    utils,
+};
+
+/// Whether a `GroupArchive` carries any decryption secrets
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ArchiveSecrets {
+    /// Leave every secret out. The archive says who was in the group and what happened to it, but
+    /// can't decrypt anything.
+    Omit,
+    /// Include the current write secret and generation of every sender in the group's final
+    /// epoch. Whoever opens the archive can decrypt every application message sent in that epoch
+    /// from those generations on. Nothing from earlier epochs can be, since those secrets are
+    /// gone, and nothing from later ones, since the init secret is never archived.
+    IncludeFinalWriteSecrets,
+}
+
+// struct {
+//     uint32 roster_index;
+//     uint32 generation;
+//     opaque secret<0..255>;
+// } ArchivedWriteSecret;
+/// A sender's write secret in the final epoch of an archived group. Its memory is wiped when it's
+/// dropped, and it prints redacted.
+#[derive(Deserialize, Serialize)]
+pub struct ArchivedWriteSecret {
+    /// The roster index of the sender this secret belongs to
+    pub roster_index: RosterIndex,
+    /// The generation the secret is at. Messages of earlier generations can't be decrypted.
+    pub generation: u32,
+    #[serde(rename = "secret__bound_u8")]
+    secret: Vec<u8>,
+}
+
+impl ArchivedWriteSecret {
+    /// Returns the bytes of the write secret
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.secret
+    }
+}
+
+impl Drop for ArchivedWriteSecret {
+    fn drop(&mut self) {
+        utils::zeroize(&mut self.secret);
+    }
+}
+
+// Ensure that the secret value isn't accidentally logged
+impl core::fmt::Debug for ArchivedWriteSecret {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("ArchivedWriteSecret")
+            .field("roster_index", &self.roster_index)
+            .field("generation", &self.generation)
+            .field("secret", &format_args!("[REDACTED; {}]", self.secret.len()))
+            .finish()
+    }
+}
+
+// struct {
+//     opaque group_id<1..255>;
+//     CipherSuite cipher_suite;
+//     uint32 epoch;
+//     opaque transcript_hash<0..255>;
+//     optional<Credential> roster<1..2^32-1>;
+//     HistoryEntry history<0..2^32-1>;
+//     ArchivedWriteSecret write_secrets<0..2^32-1>;
+//     uint32 signer_index;
+//     opaque signature<0..2^16-1>;
+// } GroupArchive;
+/// The final state of a group, as recorded by one of its members. This is what's inside a
+/// `SealedGroupArchive`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GroupArchive {
+    #[serde(rename = "group_id__nonempty__bound_u8")]
+    group_id: Vec<u8>,
+    pub(crate) cipher_suite: &'static CipherSuite,
+    epoch: u32,
+    #[serde(rename = "transcript_hash__bound_u8")]
+    transcript_hash: Vec<u8>,
+    #[serde(rename = "roster__bound_u32")]
+    pub(crate) roster: Roster,
+    #[serde(rename = "history__bound_u32")]
+    history: Vec<HistoryEntry>,
+    #[serde(rename = "write_secrets__bound_u32")]
+    write_secrets: Vec<ArchivedWriteSecret>,
+    signer_index: RosterIndex,
+    #[serde(rename = "signature__bound_u16")]
+    signature: Vec<u8>,
+}
+
+// The signed part of a GroupArchive, i.e., everything but the signature
+#[derive(Serialize)]
+struct GroupArchiveContent<'a> {
+    #[serde(rename = "group_id__bound_u8")]
+    group_id: &'a [u8],
+    cipher_suite: &'static CipherSuite,
+    epoch: u32,
+    #[serde(rename = "transcript_hash__bound_u8")]
+    transcript_hash: &'a [u8],
+    #[serde(rename = "roster__bound_u32")]
+    roster: &'a Roster,
+    #[serde(rename = "history__bound_u32")]
+    history: &'a [HistoryEntry],
+    #[serde(rename = "write_secrets__bound_u32")]
+    write_secrets: &'a [ArchivedWriteSecret],
+    signer_index: RosterIndex,
+}
+
+impl GroupArchive {
+    /// Returns the ID of the archived group
+    pub fn get_group_id(&self) -> &[u8] {
+        &self.group_id
+    }
+
+    /// Returns the archived group's ciphersuite
+    pub fn get_cipher_suite(&self) -> &'static CipherSuite {
+        self.cipher_suite
+    }
+
+    /// Returns the epoch the group was in when it was archived
+    pub fn get_epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// Returns the group's transcript hash when it was archived
+    pub fn get_transcript_hash(&self) -> &[u8] {
+        &self.transcript_hash
+    }
+
+    /// Returns the group's roster when it was archived
+    pub fn get_roster(&self) -> &Roster {
+        &self.roster
+    }
+
+    /// Returns the handshakes that led up to the archived state, oldest first. This is empty if
+    /// the archiving member didn't keep a `HistoryLog`. The history can be checked and replayed
+    /// with `history::replay`, given a checkpoint from before it.
+    pub fn history(&self) -> &[HistoryEntry] {
+        &self.history
+    }
+
+    /// Returns the write secrets of the group's final epoch. This is empty unless the archive was
+    /// made with `ArchiveSecrets::IncludeFinalWriteSecrets`.
+    pub fn write_secrets(&self) -> &[ArchivedWriteSecret] {
+        &self.write_secrets
+    }
+
+    /// Returns the roster index of the member who made this archive
+    pub fn get_signer_index(&self) -> RosterIndex {
+        self.signer_index
+    }
+
+    // Serializes everything the signature covers
+    fn signature_content(&self) -> Result<Vec<u8>, Error> {
+        tls_ser::serialize_to_bytes(&GroupArchiveContent {
+            group_id: &self.group_id,
+            cipher_suite: self.cipher_suite,
+            epoch: self.epoch,
+            transcript_hash: &self.transcript_hash,
+            roster: &self.roster,
+            history: &self.history,
+            write_secrets: &self.write_secrets,
+            signer_index: self.signer_index,
+        })
+    }
+
+    /// Checks that this archive is signed by the member at its signer index in its own roster, and
+    /// that its history is in consecutive epochs and ends where the archive does. It's up to the
+    /// caller to decide whether they trust that member's credential.
+    ///
+    /// Returns: `Ok(())` on success. Returns an `Error::ValidationError` if the signer isn't in
+    /// the roster or the history doesn't line up, and an `Error::SignatureError` if the signature
+    /// doesn't verify.
+    pub fn verify(&self) -> Result<(), Error> {
+        for pair in self.history.windows(2) {
+            if pair[1].prior_epoch != pair[0].prior_epoch.wrapping_add(1) {
+                return Err(Error::ValidationError("Archived history isn't in consecutive epochs"));
+            }
+        }
+        if let Some(last) = self.history.last() {
+            if last.prior_epoch.wrapping_add(1) != self.epoch
+                || last.transcript_hash != self.transcript_hash
+            {
+                return Err(Error::ValidationError("Archived history doesn't end at the archive"));
+            }
+        }
+
+        let signer_credential = self
+            .roster
+            .0
+            .get(self.signer_index.0 as usize)
+            .and_then(Option::as_ref)
+            .ok_or(Error::ValidationError("Archive's signer isn't in the archived roster"))?;
+        let sig_data = self.signature_content()?;
+        let ss = signer_credential.get_signature_scheme();
+        let signature = Signature::new_from_bytes(ss, &self.signature)?;
+        signer_credential.verify(&sig_data, &signature)
+    }
+}
+
+/// A `GroupArchive`, encrypted to the `UserInitKey` of whoever keeps the group's records
+#[derive(Deserialize, Serialize)]
+#[cfg_attr(test, derive(Debug))]
+pub struct SealedGroupArchive {
+    // opaque user_init_key_id<0..255>;
+    #[serde(rename = "user_init_key_id__nonempty__bound_u8")]
+    user_init_key_id: Vec<u8>,
+    pub(crate) cipher_suite: &'static CipherSuite,
+    pub(crate) encrypted_archive: EciesCiphertext,
+}
+
+/// Archives the final state of the group that `group_state` is in, as seen by this member, and
+/// seals it to `archivist_key`. `app_key_chain` is the group's current application key chain,
+/// and `history` is the `HistoryLog` to include, if any. Decryption secrets are only included if
+/// `secrets` says so.
+///
+/// Returns: `Ok(sealed_archive)` on success. Returns an `Error::ValidationError` if
+/// `app_key_chain` or `history` is for another group or epoch, or if `group_state` is
+/// preliminary. Returns some other `Error` if `archivist_key` doesn't support the group's
+/// ciphersuite or something can't be serialized or encrypted.
+pub fn seal_group_archive<R>(
+    group_state: &GroupState,
+    app_key_chain: &ApplicationKeyChain,
+    history: Option<&HistoryLog>,
+    secrets: ArchiveSecrets,
+    archivist_key: &UserInitKey,
+    csprng: &mut R,
+) -> Result<SealedGroupArchive, Error>
+where
+    R: CryptoRng,
+{
+    app_key_chain.validate_against_group_state(group_state)?;
+    if app_key_chain.get_epoch() != group_state.epoch {
+        return Err(Error::ValidationError("Key chain is for a different epoch than the group"));
+    }
+    let signer_index = group_state
+        .roster_index
+        .ok_or(Error::ValidationError("Cannot archive a preliminary GroupState"))?;
+
+    let history = match history {
+        Some(log) if log.get_group_id() != group_state.group_id.as_slice() => {
+            return Err(Error::ValidationError("HistoryLog is for a different group"));
+        }
+        Some(log) => log.entries().to_vec(),
+        None => Vec::new(),
+    };
+    let write_secrets = match secrets {
+        ArchiveSecrets::Omit => Vec::new(),
+        ArchiveSecrets::IncludeFinalWriteSecrets => app_key_chain
+            .current_write_secrets()
+            .map(|(roster_index, write_secret, generation)| ArchivedWriteSecret {
+                roster_index,
+                generation,
+                secret: write_secret.as_bytes().to_vec(),
+            })
+            .collect(),
+    };
+
+    let mut archive = GroupArchive {
+        group_id: group_state.group_id.clone(),
+        cipher_suite: group_state.cs,
+        epoch: group_state.epoch,
+        transcript_hash: group_state.transcript_hash.as_bytes().to_vec(),
+        roster: (*group_state.roster).clone(),
+        history,
+        write_secrets,
+        signer_index,
+        signature: Vec::new(),
+    };
+    let sig_data = archive.signature_content()?;
+    archive.signature = group_state.sign_with_identity_key(&sig_data)?.as_bytes();
+
+    // Sealing encrypts the serialized archive in place, so no plaintext copy of the secrets is
+    // left behind
+    let cs = group_state.cs;
+    let public_key = archivist_key.get_compatible_public_key(cs, group_state.protocol_version)?;
+    let serialized_archive = tls_ser::serialize_to_bytes(&archive)?;
+    let encrypted_archive = ecies::encrypt(cs, public_key, serialized_archive, csprng)?;
+
+    Ok(SealedGroupArchive {
+        user_init_key_id: archivist_key.user_init_key_id.clone(),
+        cipher_suite: cs,
+        encrypted_archive,
+    })
+}
+
+impl SealedGroupArchive {
+    /// Decrypts this archive with the archivist's `UserInitKey`, the one it was sealed to, and
+    /// checks it with `GroupArchive::verify`
+    ///
+    /// Returns: `Ok(archive)` on success. Returns an `Error::ValidationError` if `archivist_key`
+    /// isn't the one this was sealed to or has no private keys. Otherwise returns whatever
+    /// decrypting, parsing, or verifying the archive returns.
+    pub fn open(self, archivist_key: &UserInitKey) -> Result<GroupArchive, Error> {
+        if self.user_init_key_id != archivist_key.user_init_key_id
+            || archivist_key.private_keys.is_none()
+        {
+            return Err(Error::ValidationError("Archive isn't sealed to this UserInitKey"));
+        }
+        let cs = self.cipher_suite;
+        let dh_private_key = archivist_key.get_private_key(cs)?.ok_or(Error::ValidationError(
+            "UserInitKey doesn't support the archive's ciphersuite",
+        ))?;
+
+        let mut archive_bytes = ecies::decrypt(cs, dh_private_key, self.encrypted_archive)?;
+        let ctx = CryptoCtx::new().set_cipher_suite(cs);
+        let archive: Result<GroupArchive, Error> =
+            upcast::deserialize_and_upcast(&archive_bytes, &ctx);
+        utils::zeroize(&mut archive_bytes);
+        let archive = archive?;
+        if archive.cipher_suite != cs {
+            return Err(Error::ValidationError("Archive is sealed under a different ciphersuite"));
+        }
+
+        archive.verify()?;
+        Ok(archive)
+    }
+
+    /// Returns the ID of the `UserInitKey` this archive is sealed to
+    pub fn get_user_init_key_id(&self) -> &[u8] {
+        &self.user_init_key_id
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ratchet_tree::PathSecret, test_utils};
+
+    use quickcheck_macros::quickcheck;
+    use rand::SeedableRng;
+
+    // Archives a group after a few Updates, with and without secrets, and checks that only the
+    // archivist can open it, that it says what the member saw, and that it's tamper-evident
+    #[quickcheck]
+    fn archive_correctness(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state1, identity_keys) = test_utils::random_full_group_state(2, &mut rng);
+        let cs = group_state1.cs;
+        let other_index = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[group_state1.roster_index.unwrap().0 as usize],
+            &mut rng,
+        );
+        let group_state2 =
+            test_utils::change_self_index(&group_state1, &identity_keys, other_index);
+
+        // Member 2 follows a few Updates from member 1, logging them as it goes
+        let mut group_state1 = group_state1;
+        let mut group_state2 = group_state2;
+        let mut app_key_chain = None;
+        let mut history = HistoryLog::new(group_state2.group_id.clone());
+        for _ in 0..2 {
+            let new_path_secret = PathSecret::new_from_random(cs, &mut rng);
+            let (handshake, new_group_state1, _) =
+                group_state1.create_and_apply_update_handshake(new_path_secret, &mut rng).unwrap();
+            history.record(&group_state2, core::slice::from_ref(&handshake)).unwrap();
+            let (new_group_state2, new_app_key_chain) =
+                group_state2.process_handshake(&handshake).unwrap();
+            group_state1 = new_group_state1;
+            group_state2 = new_group_state2;
+            app_key_chain = Some(new_app_key_chain);
+        }
+        let group_state = &group_state2;
+        let app_key_chain = app_key_chain.as_ref().unwrap();
+
+        let (archivist_key, _) = test_utils::random_init_key(b"archivist", cs, &mut rng);
+        let (someone_else_key, _) = test_utils::random_init_key(b"archivist", cs, &mut rng);
+
+        // Without secrets
+        let sealed = seal_group_archive(
+            group_state,
+            app_key_chain,
+            Some(&history),
+            ArchiveSecrets::Omit,
+            &archivist_key,
+            &mut rng,
+        )
+        .unwrap();
+        let archive = sealed.open(&archivist_key).unwrap();
+        assert_eq!(archive.get_epoch(), group_state.epoch);
+        assert_eq!(archive.get_transcript_hash(), group_state.transcript_hash.as_bytes());
+        assert_eq!(archive.get_roster(), &*group_state.roster);
+        assert_eq!(archive.get_signer_index(), other_index);
+        assert_eq!(archive.history(), history.entries());
+        assert!(archive.write_secrets().is_empty());
+
+        // With secrets, which come out the same as the key chain's
+        let sealed = seal_group_archive(
+            group_state,
+            app_key_chain,
+            None,
+            ArchiveSecrets::IncludeFinalWriteSecrets,
+            &archivist_key,
+            &mut rng,
+        )
+        .unwrap();
+        let sealed_bytes = tls_ser::serialize_to_bytes(&sealed).unwrap();
+        let archive = sealed.open(&archivist_key).unwrap();
+        assert!(archive.history().is_empty());
+        let expected: Vec<(RosterIndex, Vec<u8>, u32)> = app_key_chain
+            .current_write_secrets()
+            .map(|(idx, secret, generation)| (idx, secret.as_bytes().to_vec(), generation))
+            .collect();
+        let archived: Vec<(RosterIndex, Vec<u8>, u32)> = archive
+            .write_secrets()
+            .iter()
+            .map(|ws| (ws.roster_index, ws.as_bytes().to_vec(), ws.generation))
+            .collect();
+        assert_eq!(archived, expected);
+        assert!(!format!("{:?}", archive).contains(&format!("{:?}", expected[0].1)));
+
+        // Nobody else can open it
+        let ctx = CryptoCtx::new();
+        let sealed: SealedGroupArchive =
+            upcast::deserialize_and_upcast(&sealed_bytes, &ctx).unwrap();
+        assert!(sealed.open(&someone_else_key).is_err());
+
+        // And changing what's in it breaks the signature
+        let mut tampered = archive;
+        tampered.epoch += 1;
+        match tampered.verify() {
+            Err(Error::SignatureError(_)) => (),
+            _ => panic!("tampered archive verified"),
+        }
+    }
+}
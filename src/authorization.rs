@@ -0,0 +1,268 @@
+//! Defines `AuthorizationPolicy`, which decides which members may make which `Handshake`s, and
+//! `RosterRoles`, the group extension that gives members roles for a policy to go by. A policy is
+//! checked before this member makes a `Handshake` and before any `Handshake` is applied, so a group
+//! can have admin-style rules without changing how handshakes are processed. See
+//! `GroupConfig::set_authorization_policy`.
+
+use crate::{
+    credential::{Credential, RosterIndex},
+    extensions::{self, ExtensionType, KnownExtension},
+    group_policy::GroupPolicy,
+    group_state::GroupState,
+};
+
+/// Decides whether a member may make an operation. Every method allows everything by default, so
+/// implementors only need to override the ones they care about. Members are referred to by roster
+/// index, and `group_state` is the state the operation would be applied to.
+///
+/// Every member of a group has to use the same policy. A `Handshake` that one member's policy
+/// refuses and another's allows splits the group in two.
+pub trait AuthorizationPolicy: Send + Sync + core::fmt::Debug {
+    /// Returns whether the member at `adder` may add `credential` at `roster_index`
+    fn may_add(
+        &self,
+        _group_state: &GroupState,
+        _adder: RosterIndex,
+        _roster_index: RosterIndex,
+        _credential: &Credential,
+    ) -> bool {
+        true
+    }
+
+    /// Returns whether the member at `remover` may remove the member at `removed`
+    fn may_remove(
+        &self,
+        _group_state: &GroupState,
+        _remover: RosterIndex,
+        _removed: RosterIndex,
+    ) -> bool {
+        true
+    }
+
+    /// Returns whether the member at `updater` may update their leaf
+    fn may_update(&self, _group_state: &GroupState, _updater: RosterIndex) -> bool {
+        true
+    }
+
+    /// Returns whether the member at `roster_index` may replace their credential with
+    /// `new_credential`
+    fn may_change_credential(
+        &self,
+        _group_state: &GroupState,
+        _roster_index: RosterIndex,
+        _new_credential: &Credential,
+    ) -> bool {
+        true
+    }
+
+    /// Returns whether the member at `roster_index` may replace the group's `GroupPolicy` with
+    /// `new_policy`. This is on top of the `GroupPolicy`'s own rule that only its admins may
+    /// replace it.
+    fn may_change_policy(
+        &self,
+        _group_state: &GroupState,
+        _roster_index: RosterIndex,
+        _new_policy: &GroupPolicy,
+    ) -> bool {
+        true
+    }
+}
+
+/// The policy that allows everything. This is the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AllowAll;
+
+impl AuthorizationPolicy for AllowAll {}
+
+/// A policy where only admins may add and remove members, according to the group's `RosterRoles`.
+/// Anyone may update their own leaf and credential. A malformed `RosterRoles` makes nobody an
+/// admin.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AdminsOnly;
+
+impl AuthorizationPolicy for AdminsOnly {
+    fn may_add(
+        &self,
+        group_state: &GroupState,
+        adder: RosterIndex,
+        _roster_index: RosterIndex,
+        _credential: &Credential,
+    ) -> bool {
+        matches!(group_state.get_role(adder), Ok(Role::Admin))
+    }
+
+    fn may_remove(
+        &self,
+        group_state: &GroupState,
+        remover: RosterIndex,
+        _removed: RosterIndex,
+    ) -> bool {
+        matches!(group_state.get_role(remover), Ok(Role::Admin))
+    }
+}
+
+/// What a member is allowed to do, as far as an `AuthorizationPolicy` is concerned
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename = "Role__enum_u8")]
+pub enum Role {
+    /// An ordinary member
+    #[default]
+    Member,
+    /// A member who administers the group
+    Admin,
+}
+
+/// The roles of a group's members, by roster index. This is a group extension, so every member
+/// agrees on it. Members past the end of the list, like anyone added after the group was made,
+/// are `Role::Member`s.
+// struct {
+//     Role roles<0..2^32-1>;
+// } RosterRoles;
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct RosterRoles {
+    #[serde(rename = "roles__bound_u32")]
+    roles: Vec<Role>,
+}
+
+impl KnownExtension for RosterRoles {
+    const EXTENSION_TYPE: ExtensionType = extensions::ROSTER_ROLES;
+}
+
+impl RosterRoles {
+    /// Makes a list of roles where everyone is a `Role::Member`
+    pub fn new() -> RosterRoles {
+        RosterRoles::default()
+    }
+
+    /// Returns these roles with the member at `roster_index` given the role `role`
+    pub fn set_role(mut self, roster_index: RosterIndex, role: Role) -> RosterRoles {
+        let idx = roster_index.0 as usize;
+        if self.roles.len() <= idx {
+            self.roles.resize(idx + 1, Role::Member);
+        }
+        self.roles[idx] = role;
+        self
+    }
+
+    /// Returns the role of the member at `roster_index`
+    pub fn get_role(&self, roster_index: RosterIndex) -> Role {
+        self.roles.get(roster_index.0 as usize).cloned().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        config::GroupConfig,
+        crypto::ciphersuite::X25519_SHA256_AES128GCM,
+        error::{Error, OperationError},
+        extensions::ExtensionList,
+        ratchet_tree::PathSecret,
+        test_utils,
+    };
+
+    use quickcheck_macros::quickcheck;
+    use rand::SeedableRng;
+    use std::sync::Arc;
+
+    // Makes a group where the creator is an admin and adds a plain member, then checks that only
+    // the admin can add and remove people, on both the creating and the processing side
+    #[quickcheck]
+    fn admins_only(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let cs = &X25519_SHA256_AES128GCM;
+
+        let mut extensions = ExtensionList::new();
+        extensions.insert(&RosterRoles::new().set_role(RosterIndex(0), Role::Admin)).unwrap();
+        let config = GroupConfig::new(cs)
+            .set_extensions(extensions)
+            .set_authorization_policy(Arc::new(AdminsOnly));
+        let (credential, identity_key) = test_utils::random_basic_credential(&mut rng);
+        let admin_state = GroupState::new_singleton_group_with_config(
+            identity_key,
+            b"admins only".to_vec(),
+            credential,
+            config,
+            &mut rng,
+        )
+        .unwrap();
+        assert_eq!(admin_state.get_role(RosterIndex(0)).unwrap(), Role::Admin);
+
+        // The admin adds a member, who joins with the same policy
+        let (init_key, member_identity_key) =
+            test_utils::random_init_key(b"authorization test", cs, &mut rng);
+        let (welcome, welcome_info_hash) =
+            crate::group_state::Welcome::from_group_state(&admin_state, &init_key, &mut rng)
+                .unwrap();
+        let (add, admin_state, _) = admin_state
+            .create_and_apply_add_handshake(RosterIndex(1), init_key.clone(), &welcome_info_hash)
+            .unwrap();
+        let mut member_state =
+            GroupState::from_welcome(welcome, member_identity_key, init_key).unwrap();
+        member_state.set_authorization_policy(Arc::new(AdminsOnly));
+        let (member_state, _) = member_state.process_handshake(&add).unwrap();
+        assert_eq!(member_state.get_role(RosterIndex(1)).unwrap(), Role::Member);
+
+        // The member can't add anyone, or remove the admin
+        let (init_key, _) = test_utils::random_init_key(b"authorization test", cs, &mut rng);
+        let (_, welcome_info_hash) =
+            crate::group_state::Welcome::from_group_state(&member_state, &init_key, &mut rng)
+                .unwrap();
+        match member_state.create_and_apply_add_handshake(
+            RosterIndex(2),
+            init_key,
+            &welcome_info_hash,
+        ) {
+            Err(Error::InvalidOperation(OperationError::NotAuthorized)) => (),
+            _ => panic!("a member who isn't an admin added someone"),
+        }
+        let path_secret = PathSecret::new_from_random(cs, &mut rng);
+        match member_state.create_and_apply_remove_handshake(RosterIndex(0), path_secret, &mut rng)
+        {
+            Err(Error::InvalidOperation(OperationError::NotAuthorized)) => (),
+            _ => panic!("a member who isn't an admin removed someone"),
+        }
+
+        // The member can update, and the admin accepts it
+        let path_secret = PathSecret::new_from_random(cs, &mut rng);
+        let (update, member_state, _) =
+            member_state.create_and_apply_update_handshake(path_secret, &mut rng).unwrap();
+        let (admin_state, _) = admin_state.process_handshake(&update).unwrap();
+
+        // If the member's policy were laxer, the admin would still refuse their Remove
+        let mut lax_member_state = member_state.clone();
+        lax_member_state.set_authorization_policy(Arc::new(AllowAll));
+        let path_secret = PathSecret::new_from_random(cs, &mut rng);
+        let (remove, _, _) = lax_member_state
+            .create_and_apply_remove_handshake(RosterIndex(0), path_secret, &mut rng)
+            .unwrap();
+        match admin_state.process_handshake(&remove) {
+            Err(Error::InvalidOperation(OperationError::NotAuthorized)) => (),
+            _ => panic!("accepted a Remove from a member who isn't an admin"),
+        }
+
+        // The admin can remove the member
+        let path_secret = PathSecret::new_from_random(cs, &mut rng);
+        let (remove, _, _) = admin_state
+            .create_and_apply_remove_handshake(RosterIndex(1), path_secret, &mut rng)
+            .unwrap();
+        match member_state.process_handshake(&remove) {
+            Err(Error::IAmRemoved) => (),
+            _ => panic!("the admin couldn't remove a member"),
+        }
+    }
+
+    // Checks that roles default to Member and survive a round trip through the extension
+    #[test]
+    fn roster_roles_extension() {
+        let roles = RosterRoles::new().set_role(RosterIndex(2), Role::Admin);
+        assert_eq!(roles.get_role(RosterIndex(0)), Role::Member);
+        assert_eq!(roles.get_role(RosterIndex(2)), Role::Admin);
+        assert_eq!(roles.get_role(RosterIndex(1000)), Role::Member);
+
+        let mut extensions = ExtensionList::new();
+        extensions.insert(&roles).unwrap();
+        assert_eq!(extensions.get::<RosterRoles>().unwrap(), Some(roles));
+    }
+}
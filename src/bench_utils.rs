@@ -0,0 +1,221 @@
+//! Fixtures for the benchmarks in `benches/`. Benchmarks are compiled as a separate crate, so they
+//! can only see our public API. This module wraps the crate-private tree operations we want to
+//! measure, and builds large groups directly instead of by running thousands of `Add`s.
+//!
+//! This module is only available with the `bench` feature enabled. It is not meant for use outside
+//! of benchmarking.
+
+use crate::{
+    config::GroupConfig,
+    credential::{BasicCredential, Credential, Identity, MemberIndex, Roster, RosterIndex},
+    crypto::{
+        ciphersuite::{CipherSuite, X25519_SHA256_AES128GCM},
+        dh::DhPrivateKey,
+        hash::Digest,
+        hmac::HmacKey,
+        rng::CryptoRng,
+        sig::{SigPublicKey, SigSecretKey, ED25519_IMPL},
+    },
+    error::Error,
+    extensions::ExtensionList,
+    group_state::{GroupState, WelcomeInfoHash},
+    handshake::{DirectPathMessage, UserInitKey, MLS_DUMMY_VERSION},
+    keystore::IdentityKey,
+    ratchet_tree::{PathSecret, RatchetTree, RatchetTreeNode},
+    tree_math::{self, LeafIndex, NodeIndex},
+};
+
+use std::sync::Arc;
+
+/// The ciphersuite every fixture uses
+pub const BENCH_CIPHER_SUITE: &CipherSuite = &X25519_SHA256_AES128GCM;
+
+// Makes a tree with `num_leaves` leaves where every node is filled with its own random keypair.
+// Deriving the keys from path secrets would be more realistic, but it costs a factor of log(n)
+// more key generations, which is far too slow for the biggest groups. The operations we measure
+// don't care how the keys were made.
+fn full_tree<R: CryptoRng>(num_leaves: usize, csprng: &mut R) -> Result<RatchetTree, Error> {
+    let cs = BENCH_CIPHER_SUITE;
+    let num_nodes = tree_math::num_nodes_in_tree(num_leaves);
+
+    let mut nodes = Vec::with_capacity(num_nodes);
+    for _ in 0..num_nodes {
+        let private_key = DhPrivateKey::new_from_random(cs.dh_impl, csprng)?;
+        nodes.push(RatchetTreeNode::new_from_private_key(cs, private_key));
+    }
+
+    Ok(RatchetTree::new(nodes))
+}
+
+// Makes a BasicCredential with a random 16 byte identity, along with its identity key
+fn random_credential<R: CryptoRng>(csprng: &mut R) -> Result<(Credential, SigSecretKey), Error> {
+    let mut identity = [0u8; 16];
+    csprng.fill_bytes(&mut identity);
+
+    let identity_key = SigSecretKey::new_from_random(&ED25519_IMPL, csprng)?;
+    let public_key = SigPublicKey::new_from_secret_key(&ED25519_IMPL, &identity_key);
+    let cred = Credential::Basic(BasicCredential {
+        identity: Identity(identity.to_vec()),
+        signature_scheme: &ED25519_IMPL,
+        public_key,
+    });
+
+    Ok((cred, identity_key))
+}
+
+/// An opaque `DirectPathMessage`, as produced by `TreeFixture::encrypt_direct_path_secrets`
+pub struct EncryptedPath(DirectPathMessage);
+
+/// A full ratchet tree, along with a sender leaf and a receiver leaf that are as far apart as
+/// possible. This is what the direct path benchmarks run on.
+pub struct TreeFixture {
+    tree: RatchetTree,
+    sender: NodeIndex,
+    receiver: NodeIndex,
+}
+
+impl TreeFixture {
+    /// Makes a fixture whose tree has `num_leaves` leaves, all filled with known private keys.
+    /// The sender is the first leaf and the receiver is the last.
+    ///
+    /// Requires: `num_leaves >= 2`
+    ///
+    /// Returns: `Ok(fixture)` on success. Returns an `Error::ValidationError` if `num_leaves < 2`.
+    pub fn new<R: CryptoRng>(num_leaves: usize, csprng: &mut R) -> Result<TreeFixture, Error> {
+        if num_leaves < 2 {
+            return Err(Error::ValidationError("Tree fixtures need at least two leaves"));
+        }
+
+        Ok(TreeFixture {
+            tree: full_tree(num_leaves, csprng)?,
+            sender: LeafIndex(0).node_index(),
+            receiver: LeafIndex(num_leaves - 1).node_index(),
+        })
+    }
+
+    /// Runs `propagate_new_path_secret` from the sender's leaf
+    pub fn propagate_new_path_secret(&mut self, path_secret: PathSecret) -> Result<(), Error> {
+        self.tree.propagate_new_path_secret(BENCH_CIPHER_SUITE, path_secret, self.sender)?;
+        Ok(())
+    }
+
+    /// Runs `encrypt_direct_path_secrets` from the sender's leaf
+    pub fn encrypt_direct_path_secrets<R: CryptoRng>(
+        &self,
+        path_secret: PathSecret,
+        csprng: &mut R,
+    ) -> Result<EncryptedPath, Error> {
+        let direct_path_msg = self.tree.encrypt_direct_path_secrets(
+            BENCH_CIPHER_SUITE,
+            self.sender,
+            path_secret,
+            None,
+            csprng,
+        )?;
+        Ok(EncryptedPath(direct_path_msg))
+    }
+
+    /// Runs `decrypt_direct_path_message` on a message from the sender, as the receiver
+    pub fn decrypt_direct_path_message(&self, msg: &EncryptedPath) -> Result<PathSecret, Error> {
+        let (path_secret, _) = self.tree.decrypt_direct_path_message(
+            BENCH_CIPHER_SUITE,
+            &msg.0,
+            self.sender,
+            self.receiver,
+            None,
+        )?;
+        Ok(path_secret)
+    }
+}
+
+/// Two views of the same full group: one from the first member's perspective and one from the
+/// last member's. This is what the `Add` and `Update` benchmarks run on.
+pub struct GroupFixture {
+    /// The first member of the group. This is the one who creates handshakes.
+    pub sender: GroupState,
+    /// The last member of the group. This is the one who processes handshakes.
+    pub receiver: GroupState,
+}
+
+impl GroupFixture {
+    /// Makes a fixture with `num_members` members, whose roster and tree are completely full and
+    /// whose tree secrets are all known to both members
+    ///
+    /// Requires: `2 <= num_members <= tree_math::MAX_LEAVES`
+    ///
+    /// Returns: `Ok(fixture)` on success. Returns an `Error::ValidationError` if the above
+    /// condition is not met.
+    pub fn new<R: CryptoRng>(num_members: usize, csprng: &mut R) -> Result<GroupFixture, Error> {
+        if !(2..=tree_math::MAX_LEAVES).contains(&num_members) {
+            return Err(Error::ValidationError(
+                "Group fixtures need between 2 and MAX_LEAVES members",
+            ));
+        }
+        let cs = BENCH_CIPHER_SUITE;
+
+        let mut roster = Roster(Vec::with_capacity(num_members));
+        let mut identity_keys = Vec::with_capacity(num_members);
+        for _ in 0..num_members {
+            let (cred, identity_key) = random_credential(csprng)?;
+            roster.0.push(Some(cred));
+            identity_keys.push(identity_key);
+        }
+
+        let mut group_id = [0u8; 16];
+        csprng.fill_bytes(&mut group_id);
+        let member_index = Arc::new(MemberIndex::from_roster(&roster));
+
+        let mut sender = GroupState {
+            cs,
+            protocol_version: MLS_DUMMY_VERSION,
+            identity_key: IdentityKey::Local(identity_keys[0].clone()),
+            group_id: group_id.to_vec(),
+            epoch: 0,
+            roster: Arc::new(roster),
+            tree: full_tree(num_members, csprng)?,
+            transcript_hash: Digest::new_from_zeros(cs.hash_impl),
+            extensions: ExtensionList::new(),
+            roster_index: Some(RosterIndex(0)),
+            initializing_user_init_key: None,
+            init_secret: HmacKey::new_from_random(cs.hash_impl, csprng),
+            retired_credentials: Vec::new(),
+            member_index,
+            config: GroupConfig::new(cs),
+            welcome_cache: Vec::new(),
+            cached_context: None,
+            #[cfg(feature = "dangerous-debug")]
+            recorded_secrets: Default::default(),
+        };
+
+        // Members cache the context of the epoch they're in, as well as the tree's node hashes
+        sender.refresh_group_context()?;
+
+        // The receiver is the same group from the other end of the roster
+        let mut receiver = sender.clone();
+        receiver.roster_index = Some(RosterIndex((num_members - 1) as u32));
+        receiver.identity_key = IdentityKey::Local(identity_keys[num_members - 1].clone());
+
+        Ok(GroupFixture {
+            sender,
+            receiver,
+        })
+    }
+
+    /// Makes a `UserInitKey` for a fresh member, ready to be added to this group
+    pub fn new_user_init_key<R: CryptoRng>(&self, csprng: &mut R) -> Result<UserInitKey, Error> {
+        let (cred, identity_key) = random_credential(csprng)?;
+        UserInitKey::new_from_random(
+            &identity_key,
+            b"bench".to_vec(),
+            cred,
+            vec![BENCH_CIPHER_SUITE],
+            vec![MLS_DUMMY_VERSION],
+            csprng,
+        )
+    }
+
+    /// Returns the hash of the group's current `WelcomeInfo`, which creating an `Add` requires
+    pub fn welcome_info_hash(&self) -> Result<WelcomeInfoHash, Error> {
+        self.sender.welcome_info_hash()
+    }
+}
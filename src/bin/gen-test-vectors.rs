@@ -0,0 +1,54 @@
+// Generates resolution.bin, key_schedule.bin, and messages.bin in the official MLS test vector
+// formats, along with tree.bin, and writes them to the given directory.
+//
+// Usage: gen-test-vectors [OUT_DIR] [SEED]
+//
+// OUT_DIR defaults to the current directory. SEED is a u64 that seeds the RNG, so that the same
+// seed always produces the same vectors. It defaults to 0.
+
+use molasses::test_vectors::{
+    key_schedule_test_vectors, messages_test_vectors, resolution_test_vectors, tree_test_vectors,
+};
+
+use std::path::PathBuf;
+
+use rand::SeedableRng;
+
+// These match the parameters of the official vectors
+const RESOLUTION_NUM_LEAVES: u32 = 7;
+const KEY_SCHEDULE_NUM_EPOCHS: u32 = 100;
+const KEY_SCHEDULE_GROUP_SIZE: u32 = 50;
+// Enough leaves that the tree isn't full
+const TREE_NUM_LEAVES: u32 = 5;
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let out_dir = PathBuf::from(args.next().unwrap_or_else(|| String::from(".")));
+    let seed: u64 = match args.next() {
+        Some(s) => s.parse().expect("SEED must be a u64"),
+        None => 0,
+    };
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+
+    std::fs::create_dir_all(&out_dir).expect("couldn't create output directory");
+
+    let vectors = [
+        ("resolution.bin", resolution_test_vectors(RESOLUTION_NUM_LEAVES)),
+        (
+            "key_schedule.bin",
+            key_schedule_test_vectors(KEY_SCHEDULE_NUM_EPOCHS, KEY_SCHEDULE_GROUP_SIZE, &mut rng),
+        ),
+        ("messages.bin", messages_test_vectors(&mut rng)),
+        ("tree.bin", tree_test_vectors(TREE_NUM_LEAVES, &mut rng)),
+    ];
+
+    for (filename, bytes) in vectors.iter() {
+        let bytes = match bytes {
+            Ok(b) => b,
+            Err(e) => panic!("failed to generate {}: {:?}", filename, e),
+        };
+        let path = out_dir.join(filename);
+        std::fs::write(&path, bytes).expect("couldn't write test vector file");
+        println!("Wrote {} ({} bytes)", path.display(), bytes.len());
+    }
+}
@@ -0,0 +1,821 @@
+//! Defines `MlsClient`, a batteries-included way of using this crate. A client holds one user's
+//! identity, the `UserInitKey`s they've published, and every group they're in, and routes incoming
+//! messages to the right group. Applications that want more control over any of these should use
+//! `Session` directly.
+
+use crate::{
+    application::{ApplicationKeyChain, ApplicationMessage, DecryptedMessage, SavedKeyChain},
+    credential::{Credential, Identity, RosterIndex},
+    crypto::{
+        ciphersuite::{CipherSuite, X25519_SHA256_AES128GCM},
+        rng::CryptoRng,
+        secret::SavedSecret,
+        sig::{SigSecretKey, ED25519_IMPL},
+    },
+    delivery::DeliveryService,
+    directory::UserInitKeyDirectory,
+    error::{Error, WelcomeError},
+    group_state::{GroupState, SavedGroupState, Welcome},
+    handshake::{Handshake, ProtocolVersion, SavedUserInitKey, UserInitKey, MLS_DUMMY_VERSION},
+    ratchet_tree::PathSecret,
+    session::Session,
+    tls_ser,
+    upcast::{self, CryptoCtx},
+};
+
+use std::collections::BTreeMap;
+
+// struct {
+//     Credential credential;
+//     SavedSecret identity_key;
+//     CipherSuite cipher_suites<1..255>;
+//     ProtocolVersion protocol_version;
+//     SavedUserInitKey init_keys<0..2^32-1>;
+//     uint64 next_init_key_id;
+//     SavedGroup groups<0..2^32-1>;
+// } SavedClient;
+/// An `MlsClient` as written by `MlsClient::save`
+#[derive(Deserialize, Serialize)]
+pub(crate) struct SavedClient {
+    pub(crate) credential: Credential,
+    identity_key: SavedSecret,
+    #[serde(rename = "cipher_suites__bound_u8")]
+    cipher_suites: Vec<&'static CipherSuite>,
+    protocol_version: ProtocolVersion,
+    #[serde(rename = "init_keys__bound_u32")]
+    pub(crate) init_keys: Vec<SavedUserInitKey>,
+    next_init_key_id: u64,
+    #[serde(rename = "groups__bound_u32")]
+    pub(crate) groups: Vec<SavedGroup>,
+}
+
+// struct {
+//     SavedGroupState group_state;
+//     optional<SavedKeyChain> app_key_chain;
+// } SavedGroup;
+/// A group's `Session` as written by `MlsClient::save`
+#[derive(Deserialize, Serialize)]
+pub(crate) struct SavedGroup {
+    pub(crate) group_state: SavedGroupState,
+    app_key_chain: Option<SavedKeyChain>,
+}
+
+/// A message from the delivery service, as far as an `MlsClient` is concerned
+// These are handled as soon as they arrive, so the size difference doesn't matter
+#[allow(clippy::large_enum_variant)]
+pub enum MlsMessage {
+    /// An invitation to a group
+    Welcome(Welcome),
+    /// A change to a group
+    Handshake(Handshake),
+    /// An encrypted message from a group member
+    Application(ApplicationMessage),
+}
+
+/// What came of an `MlsClient` receiving an `MlsMessage`
+// Same as for MlsMessage
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Eq, PartialEq)]
+pub enum Received {
+    /// We've joined the group with this ID. We're not a full member until we've received the Add
+    /// that goes with the `Welcome`.
+    Joined(Vec<u8>),
+    /// The given number of handshakes were applied to the group with this ID. This is 0 if the
+    /// handshake arrived early and was buffered.
+    Handshakes(Vec<u8>, usize),
+    /// A member of the group with this ID sent us this message
+    Application(Vec<u8>, DecryptedMessage),
+    /// An application message for the group with this ID is from an epoch we haven't reached yet,
+    /// so it was buffered. See `MlsClient::take_retried_messages`.
+    Buffered(Vec<u8>),
+    /// We were removed from the group with this ID, and the client has forgotten it
+    Removed(Vec<u8>),
+    /// Our state for the group with this ID can't be used anymore, even though we're still in its
+    /// roster. The client keeps the group until another member re-adds us with `MlsClient::readd`
+    /// and the `Welcome` for that arrives. See `GroupState::check_own_state`.
+    StateLost(Vec<u8>),
+}
+
+/// One user's view of all the groups they're in. Every group is a `Session`, with the default
+/// buffering and retention settings.
+///
+/// Everything is kept in memory. `MlsClient::save` writes it all out, secrets included, and
+/// `MlsClient::restore` reads it back. An application that wants to keep a group somewhere other
+/// than the client can move it out with `MlsClient::take_group` and back in with
+/// `MlsClient::insert_group`.
+pub struct MlsClient {
+    credential: Credential,
+    identity_key: SigSecretKey,
+    // In order of preference, most preferred first. This is never empty.
+    cipher_suites: Vec<&'static CipherSuite>,
+    protocol_version: ProtocolVersion,
+    // Published UserInitKeys that haven't been used yet, with their private keys, by ID
+    init_keys: BTreeMap<Vec<u8>, UserInitKey>,
+    // Used to make init key IDs, which only have to be unique among the ones we make
+    next_init_key_id: u64,
+    groups: BTreeMap<Vec<u8>, Session>,
+}
+
+impl MlsClient {
+    /// Makes a client for the user with the given credential and identity key. Groups and
+    /// `UserInitKey`s it makes use the ciphersuite `cs`, until that's changed with
+    /// `MlsClient::set_cipher_suite_preferences`.
+    pub fn new(
+        credential: Credential,
+        identity_key: SigSecretKey,
+        cs: &'static CipherSuite,
+    ) -> MlsClient {
+        MlsClient {
+            credential,
+            identity_key,
+            cipher_suites: vec![cs],
+            protocol_version: MLS_DUMMY_VERSION,
+            init_keys: BTreeMap::new(),
+            next_init_key_id: 0,
+            groups: BTreeMap::new(),
+        }
+    }
+
+    /// Makes a client for the user with the given identity, with a fresh Ed25519 identity key and
+    /// the ciphersuite `X25519_SHA256_AES128GCM`
+    ///
+    /// Returns: `Ok(client)` on success. Otherwise returns whatever
+    /// `Credential::new_basic_from_random` returns.
+    pub fn new_from_random<R: CryptoRng>(
+        identity: Identity,
+        csprng: &mut R,
+    ) -> Result<MlsClient, Error> {
+        let (credential, identity_key) =
+            Credential::new_basic_from_random(identity, &ED25519_IMPL, csprng)?;
+        Ok(MlsClient::new(credential, identity_key, &X25519_SHA256_AES128GCM))
+    }
+
+    /// Returns this client's credential
+    pub fn get_credential(&self) -> &Credential {
+        &self.credential
+    }
+
+    /// Sets the ciphersuites this client uses, most preferred first. `UserInitKey`s made from
+    /// then on offer all of them, in this order, and new groups use the first one. Keys that were
+    /// already published and groups that already exist are left alone.
+    ///
+    /// Returns: `Ok(())` on success. Returns an `Error::ValidationError` if `cipher_suites` is
+    /// empty or has duplicates.
+    pub fn set_cipher_suite_preferences(
+        &mut self,
+        cipher_suites: Vec<&'static CipherSuite>,
+    ) -> Result<(), Error> {
+        if cipher_suites.is_empty() {
+            return Err(Error::ValidationError("Ciphersuite preference list is empty"));
+        }
+        for (i, cs) in cipher_suites.iter().enumerate() {
+            if cipher_suites[..i].contains(cs) {
+                return Err(Error::ValidationError("Ciphersuite preference list has duplicates"));
+            }
+        }
+
+        self.cipher_suites = cipher_suites;
+        Ok(())
+    }
+
+    /// Returns the ciphersuites this client uses, most preferred first
+    pub fn get_cipher_suite_preferences(&self) -> &[&'static CipherSuite] {
+        &self.cipher_suites
+    }
+
+    /// Makes a new `UserInitKey` for this client. The returned key is the public half, which is
+    /// what the application should publish, e.g., to a `UserInitKeyDirectory`. The client keeps
+    /// the private half until it's used to join a group.
+    ///
+    /// Returns: `Ok(init_key)` on success. Otherwise returns whatever
+    /// `UserInitKey::new_from_random` returns.
+    pub fn publish_init_key<R: CryptoRng>(&mut self, csprng: &mut R) -> Result<UserInitKey, Error> {
+        let user_init_key_id = self.next_init_key_id.to_be_bytes().to_vec();
+        let init_key = UserInitKey::new_from_random(
+            &self.identity_key,
+            user_init_key_id.clone(),
+            self.credential.clone(),
+            self.cipher_suites.clone(),
+            // Every ciphersuite is offered with the same protocol version
+            vec![self.protocol_version; self.cipher_suites.len()],
+            csprng,
+        )?;
+        self.next_init_key_id += 1;
+
+        let mut public_init_key = init_key.clone();
+        public_init_key.private_keys = None;
+        self.init_keys.insert(user_init_key_id, init_key);
+
+        Ok(public_init_key)
+    }
+
+    /// Makes a new group with the given ID, with this client as its only member. The group uses
+    /// this client's most preferred ciphersuite.
+    ///
+    /// Returns: `Ok(())` on success. Returns an `Error::ValidationError` if this client is already
+    /// in a group with this ID. Otherwise returns whatever `GroupState::new_singleton_group`
+    /// returns.
+    pub fn create_group<R: CryptoRng>(
+        &mut self,
+        group_id: Vec<u8>,
+        csprng: &mut R,
+    ) -> Result<(), Error> {
+        if self.groups.contains_key(&group_id) {
+            return Err(Error::ValidationError("Already in a group with this ID"));
+        }
+
+        let group_state = GroupState::new_singleton_group(
+            self.cipher_suites[0],
+            self.protocol_version,
+            self.identity_key.clone(),
+            group_id.clone(),
+            self.credential.clone(),
+            csprng,
+        )?;
+        self.groups.insert(group_id, Session::new(group_state, None));
+
+        Ok(())
+    }
+
+    /// Adds the user with the given identity to a group, using the `UserInitKey` they published to
+    /// `directory`. The `Welcome` goes to the new member, and the `Handshake` goes to everyone,
+    /// including the new member.
+    ///
+    /// Returns: `Ok((welcome, handshake))` on success. Returns an `Error::ValidationError` if this
+    /// client isn't in a group with this ID. Otherwise returns whatever
+    /// `Session::add_member_by_identity` returns.
+    pub fn invite<D, R>(
+        &mut self,
+        group_id: &[u8],
+        directory: &D,
+        identity: &Identity,
+        csprng: &mut R,
+    ) -> Result<(Welcome, Handshake), Error>
+    where
+        D: UserInitKeyDirectory + ?Sized,
+        R: CryptoRng,
+    {
+        self.group_mut(group_id)?.add_member_by_identity(directory, identity, csprng)
+    }
+
+    /// Adds the member with the given identity back to a group in their roster entry at
+    /// `roster_index`, using a fresh `UserInitKey` they published to `directory`. This is for a
+    /// member who lost their state for the group, e.g., whose client said `Received::StateLost`.
+    /// It's a `Replace` of the member by themselves, so the group needs no empty roster entry and
+    /// no allowance for duplicate identities. The `Welcome` goes to the member, and the
+    /// `Handshake` goes to everyone, including the member. The roster index has to be given
+    /// explicitly, since someone with several devices in the group has several entries.
+    ///
+    /// Returns: `Ok((welcome, handshake))` on success. Returns an `Error::ValidationError` if this
+    /// client isn't in a group with this ID, if the entry at `roster_index` is empty or isn't
+    /// this identity's, or if the directory's key is for someone else. Otherwise returns whatever
+    /// the directory or `Session::create_and_apply_replace_handshake` returns.
+    pub fn readd<D, R>(
+        &mut self,
+        group_id: &[u8],
+        directory: &D,
+        identity: &Identity,
+        roster_index: RosterIndex,
+        csprng: &mut R,
+    ) -> Result<(Welcome, Handshake), Error>
+    where
+        D: UserInitKeyDirectory + ?Sized,
+        R: CryptoRng,
+    {
+        let session = self.group_mut(group_id)?;
+        let group_state = session.group_state();
+        let member_credential = group_state
+            .roster
+            .get(roster_index)
+            .ok_or(Error::ValidationError("Nobody is at this roster index"))?;
+        if member_credential.get_identity() != identity {
+            return Err(Error::ValidationError("Roster entry belongs to someone else"));
+        }
+
+        // The Replace checks the key like an Add would, except for who it's for
+        let init_key = directory.fetch_init_key(identity)?;
+        if init_key.credential.get_identity() != identity {
+            return Err(Error::ValidationError(
+                "Directory returned a UserInitKey for someone else",
+            ));
+        }
+
+        let path_secret = PathSecret::new_from_random(group_state.get_cipher_suite(), csprng);
+        session.create_and_apply_replace_handshake(roster_index, init_key, path_secret, csprng)
+    }
+
+    /// Encrypts a message to everyone in a group
+    ///
+    /// Returns: `Ok(app_message)` on success. Returns an `Error::ValidationError` if this client
+    /// isn't in a group with this ID. Otherwise returns whatever
+    /// `Session::encrypt_application_message` returns.
+    pub fn send(
+        &mut self,
+        group_id: &[u8],
+        plaintext: Vec<u8>,
+    ) -> Result<ApplicationMessage, Error> {
+        self.group_mut(group_id)?.encrypt_application_message(plaintext)
+    }
+
+    /// Handles a message from the delivery service. A `Welcome` makes this client join a group,
+    /// using up the `UserInitKey` it was for. Anything else goes to the group it was sent in. If a
+    /// `Handshake` removes this client from a group, the group is forgotten. If a `Handshake` shows
+    /// that our state for a group is lost, we say so, and a `Welcome` to that group is then taken
+    /// in place of the lost state. An application message from a future epoch is buffered until
+    /// its group gets there.
+    ///
+    /// Returns: `Ok(received)` on success. Returns an `Error::InvalidWelcome` if a `Welcome` is for
+    /// an unknown `UserInitKey`, and an `Error::ValidationError` if it's for a group we're already
+    /// in with intact state, or if a group message is for a group we're not in. Otherwise returns
+    /// whatever `GroupState::from_welcome`, `Session::handle_handshake`, or
+    /// `Session::handle_application_message` returns.
+    pub fn receive(&mut self, message: MlsMessage) -> Result<Received, Error> {
+        self.receive_inner(message, None)
+    }
+
+    /// Like `MlsClient::receive`, but if a `Handshake` is from a future epoch of its group, the
+    /// handshakes this client missed in between are fetched from `delivery_service` and applied
+    /// first, instead of waiting for them to arrive. See `Session::handle_handshake_with_recovery`.
+    ///
+    /// Returns: The same as `MlsClient::receive`, except that the number of handshakes applied
+    /// includes the fetched ones, and whatever `Session::handle_handshake_with_recovery` returns
+    /// takes the place of what `Session::handle_handshake` returns
+    pub fn receive_with_recovery(
+        &mut self,
+        message: MlsMessage,
+        delivery_service: &dyn DeliveryService,
+    ) -> Result<Received, Error> {
+        self.receive_inner(message, Some(delivery_service))
+    }
+
+    // Handles a message, recovering missed handshakes iff there's a delivery service to get them
+    // from
+    fn receive_inner(
+        &mut self,
+        message: MlsMessage,
+        delivery_service: Option<&dyn DeliveryService>,
+    ) -> Result<Received, Error> {
+        match message {
+            MlsMessage::Welcome(welcome) => self.join(welcome),
+            MlsMessage::Handshake(handshake) => {
+                let group_id = handshake.group_id.clone();
+                let session = self.group_mut(&group_id)?;
+                let res = match delivery_service {
+                    Some(delivery_service) => {
+                        session.handle_handshake_with_recovery(handshake, delivery_service)
+                    }
+                    None => session.handle_handshake(handshake),
+                };
+                match res {
+                    Ok(num_applied) => Ok(Received::Handshakes(group_id, num_applied)),
+                    Err(Error::IAmRemoved) => {
+                        self.groups.remove(&group_id);
+                        Ok(Received::Removed(group_id))
+                    }
+                    Err(Error::StateLost) => Ok(Received::StateLost(group_id)),
+                    Err(e) => Err(e),
+                }
+            }
+            MlsMessage::Application(app_message) => {
+                let group_id = app_message.get_group_id().to_vec();
+                match self.group_mut(&group_id)?.handle_application_message(app_message)? {
+                    Some(decrypted) => Ok(Received::Application(group_id, decrypted)),
+                    None => Ok(Received::Buffered(group_id)),
+                }
+            }
+        }
+    }
+
+    // Joins a group with one of our unused UserInitKeys
+    fn join(&mut self, welcome: Welcome) -> Result<Received, Error> {
+        let user_init_key_id = welcome.get_user_init_key_id().to_vec();
+        let init_key =
+            self.init_keys.get(&user_init_key_id).ok_or(WelcomeError::UnknownInitKey)?.clone();
+        let group_state = GroupState::from_welcome(welcome, self.identity_key.clone(), init_key)?;
+        let group_id = group_state.get_group_id().to_vec();
+        // We can only be welcomed back into a group we're in if our state for it is lost. See
+        // MlsClient::readd.
+        if let Some(session) = self.groups.get(&group_id) {
+            if session.group_state().check_own_state().is_ok() {
+                return Err(Error::ValidationError("Already in a group with this ID"));
+            }
+        }
+
+        // A UserInitKey is only good for one group. Only forget it once it's worked, so that a bad
+        // Welcome can't make us throw away a good key.
+        self.init_keys.remove(&user_init_key_id);
+        self.groups.insert(group_id.clone(), Session::new(group_state, None));
+
+        Ok(Received::Joined(group_id))
+    }
+
+    /// Returns what became of the buffered application messages of the group with the given ID
+    /// since the last call. See `Session::take_retried_messages`.
+    ///
+    /// Returns: `Ok(retried)` on success. Returns an `Error::ValidationError` if this client isn't
+    /// in a group with this ID.
+    pub fn take_retried_messages(
+        &mut self,
+        group_id: &[u8],
+    ) -> Result<Vec<Result<DecryptedMessage, Error>>, Error> {
+        Ok(self.group_mut(group_id)?.take_retried_messages())
+    }
+
+    /// Returns the session for the group with the given ID, or `None` if this client isn't in it
+    pub fn group(&self, group_id: &[u8]) -> Option<&Session> {
+        self.groups.get(group_id)
+    }
+
+    /// Returns the IDs of all the groups this client is in
+    pub fn group_ids(&self) -> impl Iterator<Item = &[u8]> {
+        self.groups.keys().map(Vec::as_slice)
+    }
+
+    /// Removes the group with the given ID from this client and returns it, or returns `None` if
+    /// this client isn't in it
+    pub fn take_group(&mut self, group_id: &[u8]) -> Option<Session> {
+        self.groups.remove(group_id)
+    }
+
+    /// Puts a group into this client, e.g., one that was taken out with `MlsClient::take_group`.
+    /// If the client already has a group with the same ID, that one is replaced and returned.
+    pub fn insert_group(&mut self, session: Session) -> Option<Session> {
+        let group_id = session.group_state().get_group_id().to_vec();
+        self.groups.insert(group_id, session)
+    }
+
+    /// Writes out this client's identity key, unused `UserInitKey`s with their private keys, and
+    /// every group's current epoch, so that `MlsClient::restore` can pick up where this left off.
+    /// Whatever a group's `Session` has buffered or retained is left out, i.e., early handshakes
+    /// and messages, past epochs' keys, a handshake waiting for its echo, and the history log. So
+    /// are the settings of each `Session` and `GroupConfig` that aren't group extensions. The
+    /// restored groups use the defaults, like groups that were just joined.
+    ///
+    /// The output holds every secret this client has, unencrypted. It MUST be encrypted before it
+    /// goes anywhere, and the caller is responsible for zeroing it.
+    ///
+    /// Returns: `Ok(bytes)` on success. Returns an `Error::ValidationError` if a group's identity
+    /// key is in a `KeyStore`, and an `Error::SerdeError` if something can't be serialized.
+    pub fn save(&self) -> Result<Vec<u8>, Error> {
+        let init_keys = self
+            .init_keys
+            .values()
+            .map(UserInitKey::to_saved)
+            .collect::<Result<Vec<_>, Error>>()?;
+        let groups = self
+            .groups
+            .values()
+            .map(|session| {
+                Ok(SavedGroup {
+                    group_state: session.group_state().to_saved()?,
+                    app_key_chain: session.app_key_chain().map(ApplicationKeyChain::to_saved),
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let saved = SavedClient {
+            credential: self.credential.clone(),
+            identity_key: SavedSecret::new_from_vec(self.identity_key.to_bytes()),
+            cipher_suites: self.cipher_suites.clone(),
+            protocol_version: self.protocol_version,
+            init_keys,
+            next_init_key_id: self.next_init_key_id,
+            groups,
+        };
+        tls_ser::serialize_to_bytes(&saved)
+    }
+
+    /// Makes a client out of the output of `MlsClient::save`
+    ///
+    /// Returns: `Ok(client)` on success. Returns an `Error::SerdeError` if `bytes` can't be
+    /// parsed, an `Error::SignatureError` if the identity key doesn't belong to the credential, and
+    /// an `Error::ValidationError` if anything else is inconsistent. Otherwise returns whatever
+    /// restoring a `UserInitKey`, `GroupState`, or `ApplicationKeyChain` returns.
+    pub fn restore(bytes: &[u8]) -> Result<MlsClient, Error> {
+        let saved: SavedClient = upcast::deserialize_and_upcast(bytes, &CryptoCtx::new())?;
+
+        let ss = saved.credential.get_signature_scheme();
+        let identity_key = SigSecretKey::new_from_bytes(ss, saved.identity_key.as_bytes())?;
+        saved.credential.check_identity_key(&identity_key)?;
+        let cs = *saved
+            .cipher_suites
+            .first()
+            .ok_or(Error::ValidationError("Saved client has no ciphersuites"))?;
+        let mut client = MlsClient::new(saved.credential, identity_key, cs);
+        client.set_cipher_suite_preferences(saved.cipher_suites)?;
+        client.protocol_version = saved.protocol_version;
+        client.next_init_key_id = saved.next_init_key_id;
+
+        for saved_init_key in saved.init_keys {
+            let init_key = saved_init_key.into_user_init_key()?;
+            client.init_keys.insert(init_key.user_init_key_id.clone(), init_key);
+        }
+        for saved_group in saved.groups {
+            let group_state = GroupState::from_saved(saved_group.group_state)?;
+            let app_key_chain = match saved_group.app_key_chain {
+                Some(saved_key_chain) => {
+                    Some(ApplicationKeyChain::from_saved(&group_state, saved_key_chain)?)
+                }
+                None => None,
+            };
+            if client.insert_group(Session::new(group_state, app_key_chain)).is_some() {
+                return Err(Error::ValidationError("Saved client has two groups with the same ID"));
+            }
+        }
+
+        Ok(client)
+    }
+
+    // Looks up a group we're in
+    fn group_mut(&mut self, group_id: &[u8]) -> Result<&mut Session, Error> {
+        self.groups.get_mut(group_id).ok_or(Error::ValidationError("Not in a group with this ID"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ratchet_tree::RatchetTreeNode;
+
+    use quickcheck_macros::quickcheck;
+    use rand::SeedableRng;
+
+    // A directory that's just a list of published keys
+    struct ListDirectory(Vec<(Identity, UserInitKey)>);
+
+    impl UserInitKeyDirectory for ListDirectory {
+        fn fetch_init_key(&self, identity: &Identity) -> Result<UserInitKey, Error> {
+            self.0
+                .iter()
+                .find(|(id, _)| id == identity)
+                .map(|(_, init_key)| init_key.clone())
+                .ok_or(Error::ValidationError("No such user in directory"))
+        }
+    }
+
+    // Has Alice make a group and invite Bob through a directory, then has them talk to each other
+    #[quickcheck]
+    fn client_correctness(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let group_id = b"client group".to_vec();
+        let alice_id = Identity::from("alice@example.com");
+        let bob_id = Identity::from("bob@example.com");
+        let mut alice = MlsClient::new_from_random(alice_id, &mut rng).unwrap();
+        let mut bob = MlsClient::new_from_random(bob_id.clone(), &mut rng).unwrap();
+
+        // Bob publishes a key. The published copy has no private keys in it, and offers his
+        // ciphersuites in order of preference.
+        let bob_init_key = bob.publish_init_key(&mut rng).unwrap();
+        assert!(bob_init_key.private_keys.is_none());
+        assert_eq!(bob_init_key.cipher_suites.as_slice(), bob.get_cipher_suite_preferences());
+        let directory = ListDirectory(vec![(bob_id.clone(), bob_init_key)]);
+
+        alice.create_group(group_id.clone(), &mut rng).unwrap();
+        assert!(alice.create_group(group_id.clone(), &mut rng).is_err());
+        let (welcome, handshake) = alice.invite(&group_id, &directory, &bob_id, &mut rng).unwrap();
+
+        // Bob joins. His init key is used up after that.
+        assert_eq!(
+            bob.receive(MlsMessage::Welcome(welcome)).unwrap(),
+            Received::Joined(group_id.clone())
+        );
+        assert!(bob.init_keys.is_empty());
+
+        // Alice's first message gets to Bob before the Add does. He holds onto it until then.
+        let app_message = alice.send(&group_id, b"welcome bob".to_vec()).unwrap();
+        assert_eq!(
+            bob.receive(MlsMessage::Application(app_message)).unwrap(),
+            Received::Buffered(group_id.clone())
+        );
+        assert_eq!(
+            bob.receive(MlsMessage::Handshake(handshake)).unwrap(),
+            Received::Handshakes(group_id.clone(), 1)
+        );
+        let retried = bob.take_retried_messages(&group_id).unwrap();
+        assert_eq!(retried.len(), 1);
+        assert_eq!(retried[0].as_ref().unwrap().plaintext, b"welcome bob");
+        assert_serialized_eq!(
+            *alice.group(&group_id).unwrap().group_state(),
+            *bob.group(&group_id).unwrap().group_state()
+        );
+
+        // They can talk both ways, and each knows who the other's messages are from
+        let app_message = alice.send(&group_id, b"hi bob".to_vec()).unwrap();
+        match bob.receive(MlsMessage::Application(app_message)).unwrap() {
+            Received::Application(id, decrypted) => {
+                assert_eq!(id, group_id);
+                assert_eq!(decrypted.plaintext, b"hi bob");
+                assert_eq!(&decrypted.sender_credential, alice.get_credential());
+            }
+            other => panic!("expected an application message, got {:?}", other),
+        }
+        let app_message = bob.send(&group_id, b"hi alice".to_vec()).unwrap();
+        match alice.receive(MlsMessage::Application(app_message)).unwrap() {
+            Received::Application(id, decrypted) => {
+                assert_eq!(id, group_id);
+                assert_eq!(decrypted.plaintext, b"hi alice");
+                assert_eq!(&decrypted.sender_credential, bob.get_credential());
+            }
+            other => panic!("expected an application message, got {:?}", other),
+        }
+
+        // Messages for groups the client isn't in are refused
+        assert!(bob.send(b"some other group", b"hello?".to_vec()).is_err());
+
+        // A group can be taken out and put back
+        let session = alice.take_group(&group_id).unwrap();
+        assert_eq!(alice.group_ids().count(), 0);
+        assert!(alice.insert_group(session).is_none());
+        assert_eq!(alice.group_ids().collect::<Vec<_>>(), vec![group_id.as_slice()]);
+
+        // Ciphersuite preferences have to make sense, and new groups follow them
+        assert!(alice.set_cipher_suite_preferences(Vec::new()).is_err());
+        let x25519 = &X25519_SHA256_AES128GCM;
+        assert!(alice.set_cipher_suite_preferences(vec![x25519, x25519]).is_err());
+        alice.set_cipher_suite_preferences(vec![x25519]).unwrap();
+        alice.create_group(b"another group".to_vec(), &mut rng).unwrap();
+        let new_group = alice.group(b"another group").unwrap().group_state();
+        assert_eq!(new_group.cs, alice.get_cipher_suite_preferences()[0]);
+    }
+
+    // Saves Bob's client in the middle of a conversation, with a group he's in, a group he's been
+    // welcomed to but not added to yet, and an unused UserInitKey, and checks that the restored
+    // client carries on in all three
+    #[quickcheck]
+    fn save_and_restore(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let group_id = b"saved group".to_vec();
+        let pending_group_id = b"pending group".to_vec();
+        let later_group_id = b"later group".to_vec();
+        let alice_id = Identity::from("alice@example.com");
+        let bob_id = Identity::from("bob@example.com");
+        let mut alice = MlsClient::new_from_random(alice_id, &mut rng).unwrap();
+        let mut bob = MlsClient::new_from_random(bob_id.clone(), &mut rng).unwrap();
+
+        // Bob is in one group and has talked in it
+        let bob_init_key = bob.publish_init_key(&mut rng).unwrap();
+        let directory = ListDirectory(vec![(bob_id.clone(), bob_init_key)]);
+        alice.create_group(group_id.clone(), &mut rng).unwrap();
+        let (welcome, handshake) = alice.invite(&group_id, &directory, &bob_id, &mut rng).unwrap();
+        bob.receive(MlsMessage::Welcome(welcome)).unwrap();
+        bob.receive(MlsMessage::Handshake(handshake)).unwrap();
+        let app_message = bob.send(&group_id, b"before".to_vec()).unwrap();
+        alice.receive(MlsMessage::Application(app_message)).unwrap();
+
+        // He's been welcomed to another, but hasn't seen the Add yet
+        let bob_init_key = bob.publish_init_key(&mut rng).unwrap();
+        let directory = ListDirectory(vec![(bob_id.clone(), bob_init_key)]);
+        alice.create_group(pending_group_id.clone(), &mut rng).unwrap();
+        let (welcome, pending_add) =
+            alice.invite(&pending_group_id, &directory, &bob_id, &mut rng).unwrap();
+        bob.receive(MlsMessage::Welcome(welcome)).unwrap();
+
+        // And he has a key out that nobody has used
+        let bob_init_key = bob.publish_init_key(&mut rng).unwrap();
+        let later_directory = ListDirectory(vec![(bob_id.clone(), bob_init_key)]);
+
+        // Saving and restoring changes nothing, not even the saved bytes
+        let saved = bob.save().unwrap();
+        let mut bob = MlsClient::restore(&saved).unwrap();
+        assert_eq!(bob.save().unwrap(), saved);
+        assert_eq!(bob.init_keys.len(), 1);
+        assert_eq!(bob.group_ids().count(), 2);
+        assert_serialized_eq!(
+            *alice.group(&group_id).unwrap().group_state(),
+            *bob.group(&group_id).unwrap().group_state()
+        );
+
+        // The restored key chain picks up where it left off in both directions
+        let app_message = bob.send(&group_id, b"after".to_vec()).unwrap();
+        match alice.receive(MlsMessage::Application(app_message)).unwrap() {
+            Received::Application(_, decrypted) => assert_eq!(decrypted.plaintext, b"after"),
+            other => panic!("expected an application message, got {:?}", other),
+        }
+        let app_message = alice.send(&group_id, b"welcome back".to_vec()).unwrap();
+        match bob.receive(MlsMessage::Application(app_message)).unwrap() {
+            Received::Application(_, decrypted) => {
+                assert_eq!(decrypted.plaintext, b"welcome back")
+            }
+            other => panic!("expected an application message, got {:?}", other),
+        }
+
+        // The restored tree has Bob's private keys, so he can follow an Update
+        let path_secret = PathSecret::new_from_random(&X25519_SHA256_AES128GCM, &mut rng);
+        let update = alice
+            .group_mut(&group_id)
+            .unwrap()
+            .create_and_apply_update_handshake(path_secret, &mut rng)
+            .unwrap();
+        bob.receive(MlsMessage::Handshake(update)).unwrap();
+        assert_serialized_eq!(
+            *alice.group(&group_id).unwrap().group_state(),
+            *bob.group(&group_id).unwrap().group_state()
+        );
+
+        // The pending group still takes its Add
+        bob.receive(MlsMessage::Handshake(pending_add)).unwrap();
+        assert_serialized_eq!(
+            *alice.group(&pending_group_id).unwrap().group_state(),
+            *bob.group(&pending_group_id).unwrap().group_state()
+        );
+
+        // The unused key still works
+        alice.create_group(later_group_id.clone(), &mut rng).unwrap();
+        let (welcome, _) =
+            alice.invite(&later_group_id, &later_directory, &bob_id, &mut rng).unwrap();
+        assert_eq!(
+            bob.receive(MlsMessage::Welcome(welcome)).unwrap(),
+            Received::Joined(later_group_id)
+        );
+
+        // A damaged save doesn't restore
+        assert!(MlsClient::restore(&saved[..saved.len() / 2]).is_err());
+    }
+
+    // Has Bob lose his leaf's private key, find out from the next Handshake, and get back in with
+    // the Welcome from Alice re-adding him in the roster entry he already has
+    #[quickcheck]
+    fn rejoin_after_state_loss(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let group_id = b"rejoin group".to_vec();
+        let alice_id = Identity::from("alice@example.com");
+        let bob_id = Identity::from("bob@example.com");
+        let mut alice = MlsClient::new_from_random(alice_id, &mut rng).unwrap();
+        let mut bob = MlsClient::new_from_random(bob_id.clone(), &mut rng).unwrap();
+
+        let bob_init_key = bob.publish_init_key(&mut rng).unwrap();
+        let directory = ListDirectory(vec![(bob_id.clone(), bob_init_key)]);
+        alice.create_group(group_id.clone(), &mut rng).unwrap();
+        let (welcome, handshake) = alice.invite(&group_id, &directory, &bob_id, &mut rng).unwrap();
+        bob.receive(MlsMessage::Welcome(welcome)).unwrap();
+        bob.receive(MlsMessage::Handshake(handshake)).unwrap();
+        let bob_roster_index = bob.group(&group_id).unwrap().group_state().roster_index.unwrap();
+
+        // Bob's state comes back from a backup without his leaf's private key
+        let mut lost_state = bob.take_group(&group_id).unwrap().group_state().clone();
+        let leaf_idx = bob_roster_index.node_index().unwrap();
+        let leaf = lost_state.tree.get_mut(leaf_idx).unwrap();
+        let public_key = leaf.get_public_key().unwrap().clone();
+        *leaf = RatchetTreeNode::Filled {
+            public_key,
+            private_key: None,
+        };
+        match lost_state.check_own_state() {
+            Err(Error::StateLost) => (),
+            _ => panic!("missing leaf private key wasn't noticed"),
+        }
+        bob.insert_group(Session::new(lost_state, None));
+
+        // He can't follow Alice's next Update, and his client says why
+        let path_secret = PathSecret::new_from_random(&X25519_SHA256_AES128GCM, &mut rng);
+        let update = alice
+            .group_mut(&group_id)
+            .unwrap()
+            .create_and_apply_update_handshake(path_secret, &mut rng)
+            .unwrap();
+        assert_eq!(
+            bob.receive(MlsMessage::Handshake(update)).unwrap(),
+            Received::StateLost(group_id.clone())
+        );
+
+        // Alice re-adds him where he is now, and the Welcome takes the place of the lost state
+        let bob_init_key = bob.publish_init_key(&mut rng).unwrap();
+        let directory = ListDirectory(vec![(bob_id.clone(), bob_init_key)]);
+        let (welcome, handshake) =
+            alice.readd(&group_id, &directory, &bob_id, bob_roster_index, &mut rng).unwrap();
+        assert_eq!(
+            bob.receive(MlsMessage::Welcome(welcome)).unwrap(),
+            Received::Joined(group_id.clone())
+        );
+        assert_eq!(
+            bob.receive(MlsMessage::Handshake(handshake)).unwrap(),
+            Received::Handshakes(group_id.clone(), 1)
+        );
+        let bob_state = bob.group(&group_id).unwrap().group_state();
+        assert_eq!(bob_state.roster_index, Some(bob_roster_index));
+        bob_state.check_own_state().unwrap();
+        assert_serialized_eq!(*alice.group(&group_id).unwrap().group_state(), *bob_state);
+
+        // They can talk again
+        let app_message = bob.send(&group_id, b"i'm back".to_vec()).unwrap();
+        match alice.receive(MlsMessage::Application(app_message)).unwrap() {
+            Received::Application(_, decrypted) => assert_eq!(decrypted.plaintext, b"i'm back"),
+            other => panic!("expected an application message, got {:?}", other),
+        }
+
+        // Nobody else can be re-added, nobody can be re-added over someone else's entry, and a
+        // Welcome can't replace a healthy state
+        let carol_id = Identity::from("carol@example.com");
+        assert!(alice.readd(&group_id, &directory, &carol_id, bob_roster_index, &mut rng).is_err());
+        let alice_roster_index =
+            alice.group(&group_id).unwrap().group_state().roster_index.unwrap();
+        assert!(alice.readd(&group_id, &directory, &bob_id, alice_roster_index, &mut rng).is_err());
+        let empty_index = RosterIndex(bob_roster_index.0 + 1);
+        assert!(alice.readd(&group_id, &directory, &bob_id, empty_index, &mut rng).is_err());
+        let bob_init_key = bob.publish_init_key(&mut rng).unwrap();
+        let directory = ListDirectory(vec![(bob_id.clone(), bob_init_key)]);
+        let (welcome, _) =
+            alice.readd(&group_id, &directory, &bob_id, bob_roster_index, &mut rng).unwrap();
+        assert!(bob.receive(MlsMessage::Welcome(welcome)).is_err());
+    }
+}
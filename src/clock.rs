@@ -0,0 +1,170 @@
+//! Defines the `Clock` trait, which is where this crate gets the time from whenever something
+//! depends on it, like whether a `UserInitKey` has expired. Times are whole seconds since the Unix
+//! epoch. Nothing in the trait needs `std`, so platforms without a system clock can bring their own.
+
+use crate::extensions::{self, ExtensionType, KnownExtension};
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// A source of the current time
+pub trait Clock: Send + Sync + core::fmt::Debug {
+    /// Returns the current time in seconds since the Unix epoch
+    fn now(&self) -> u64;
+}
+
+/// The system's wall clock. This is the default `Clock`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        // A system clock set to before 1970 is treated as being at 1970
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// A clock that only moves when it's told to. This is meant for tests of time-dependent behavior.
+#[derive(Debug, Default)]
+pub struct MockClock(AtomicU64);
+
+impl MockClock {
+    /// Makes a clock that's stopped at the given time
+    pub fn new(now: u64) -> MockClock {
+        MockClock(AtomicU64::new(now))
+    }
+
+    /// Sets the clock to the given time
+    pub fn set(&self, now: u64) {
+        self.0.store(now, Ordering::SeqCst);
+    }
+
+    /// Moves the clock forward by the given number of seconds, saturating at `u64::MAX`
+    pub fn advance(&self, secs: u64) {
+        let now = self.now();
+        self.set(now.saturating_add(secs));
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// A `UserInitKey` extension that limits when the key can be used to add its owner to a group.
+/// Both ends are inclusive, and are in seconds since the Unix epoch. See
+/// `UserInitKey::check_lifetime`.
+// struct {
+//     uint64 not_before;
+//     uint64 not_after;
+// } Lifetime;
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Lifetime {
+    /// The earliest time the key can be used
+    pub not_before: u64,
+    /// The latest time the key can be used
+    pub not_after: u64,
+}
+
+impl KnownExtension for Lifetime {
+    const EXTENSION_TYPE: ExtensionType = extensions::LIFETIME;
+}
+
+impl Lifetime {
+    /// Makes a lifetime that starts now, according to `clock`, and lasts `secs` seconds
+    pub fn starting_now(clock: &dyn Clock, secs: u64) -> Lifetime {
+        let now = clock.now();
+        Lifetime {
+            not_before: now,
+            not_after: now.saturating_add(secs),
+        }
+    }
+
+    /// Returns whether `time` is within this lifetime
+    pub fn contains(&self, time: u64) -> bool {
+        self.not_before <= time && time <= self.not_after
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        config::GroupConfig,
+        credential::RosterIndex,
+        crypto::ciphersuite::X25519_SHA256_AES128GCM,
+        error::Error,
+        extensions::ExtensionList,
+        group_state::GroupState,
+        handshake::{UserInitKey, MLS_DUMMY_VERSION},
+        test_utils,
+    };
+
+    use std::sync::Arc;
+
+    use quickcheck_macros::quickcheck;
+    use rand::SeedableRng;
+
+    // Makes a UserInitKey that's good for an hour from the start time, and checks that it can only
+    // be used to add someone during that hour
+    #[quickcheck]
+    fn init_key_lifetime(rng_seed: u64, start: u32) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let cs = &X25519_SHA256_AES128GCM;
+        let start = u64::from(start) + 1;
+        let clock = Arc::new(MockClock::new(start));
+
+        let (credential, identity_key) = test_utils::random_basic_credential(&mut rng);
+        let mut extensions = ExtensionList::new();
+        extensions.insert(&Lifetime::starting_now(clock.as_ref(), 3600)).unwrap();
+        let init_key = UserInitKey::new_from_random_with_extensions(
+            &identity_key,
+            b"lifetime test".to_vec(),
+            credential,
+            vec![cs],
+            vec![MLS_DUMMY_VERSION],
+            extensions,
+            &mut rng,
+        )
+        .unwrap();
+
+        // The key is good at both ends of its lifetime, and nowhere outside it
+        init_key.check_lifetime(clock.as_ref()).unwrap();
+        clock.advance(3600);
+        init_key.check_lifetime(clock.as_ref()).unwrap();
+        clock.advance(1);
+        assert!(init_key.check_lifetime(clock.as_ref()).is_err());
+        clock.set(start - 1);
+        assert!(init_key.check_lifetime(clock.as_ref()).is_err());
+
+        // A group using this clock refuses to add the owner of an expired key
+        let (credential, identity_key) = test_utils::random_basic_credential(&mut rng);
+        let config = GroupConfig::new(cs).set_clock(clock.clone());
+        let group_state = GroupState::new_singleton_group_with_config(
+            identity_key,
+            b"lifetime group".to_vec(),
+            credential,
+            config,
+            &mut rng,
+        )
+        .unwrap();
+        clock.advance(3602);
+        let welcome_info_hash = group_state.welcome_info_hash().unwrap();
+        match group_state.create_and_apply_add_handshake(
+            RosterIndex(1),
+            init_key.clone(),
+            &welcome_info_hash,
+        ) {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("added someone with an expired UserInitKey"),
+        }
+        // But it's fine while the key is still good
+        clock.set(start + 1800);
+        group_state
+            .create_and_apply_add_handshake(RosterIndex(1), init_key, &welcome_info_hash)
+            .unwrap();
+    }
+}
@@ -5,7 +5,7 @@ use crate::crypto::{
     dh::{DhPublicKey, DhPublicKeyRaw},
     sig::{
         SigPublicKey, SigPublicKeyRaw, Signature, SignatureRaw, SignatureScheme, ECDSA_P256_IMPL,
-        ED25519_IMPL,
+        ECDSA_P384_IMPL, ED25519_IMPL,
     },
 };
 
@@ -20,6 +20,7 @@ const CIPHERSUITE_NAME_IDS: &[(&CipherSuite, &str, u16)] = &[
 ];
 const SIGSCHEME_NAME_IDS: &[(&SignatureScheme, &str, u16)] = &[
     (&ECDSA_P256_IMPL, "dummy_ecdsa_secp256r1_sha256", 0x0403),
+    (&ECDSA_P384_IMPL, "ecdsa_secp384r1_sha384", 0x0503),
     (&ED25519_IMPL, "ed25519", 0x0807),
 ];
 
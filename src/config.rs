@@ -0,0 +1,383 @@
+//! Defines `GroupConfig`, which collects the choices made when a group is created. Some of these
+//! are agreed on by the whole group, and are handed to new members in their `WelcomeInfo` as
+//! group extensions. The rest only affect the member who set them.
+
+use crate::{
+    application::PaddingScheme,
+    authorization::{AllowAll, AuthorizationPolicy},
+    clock::{Clock, SystemClock},
+    crypto::ciphersuite::CipherSuite,
+    error::Error,
+    extensions::{self, ExtensionList, ExtensionType, KnownExtension},
+    handshake::{ProtocolVersion, MLS_DUMMY_VERSION},
+    session::DEFAULT_EPOCH_RETENTION,
+    tls_de::ParseMode,
+};
+
+use std::sync::Arc;
+
+/// How often a member should refresh their own leaf. An Update or a Remove that they make both
+/// count, since both replace their leaf secret. See `Session::is_update_due`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum UpdatePolicy {
+    /// Updating is entirely up to the application
+    #[default]
+    Manual,
+    /// An update is due once the group has moved this many epochs past this member's last one
+    EveryNEpochs(u32),
+}
+
+/// What a member does about the other members who haven't refreshed their leaf in a while. A leaf
+/// secret that's never replaced weakens everyone's post-compromise security, since whoever steals
+/// it can keep using it. An Update or a Remove that a member makes both count as a refresh, as does
+/// being added. See `Session::stale_members`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum StaleMemberPolicy {
+    /// Nobody is ever considered stale
+    #[default]
+    Ignore,
+    /// A member is flagged as stale once the group has moved this many epochs past their last
+    /// refresh. It's up to the application to get them to update.
+    Flag(u32),
+    /// Like `Flag`, and `Session::remove_stale_members` removes whoever is flagged
+    Remove(u32),
+}
+
+/// Whether one identity can be in a group more than once, e.g., one person on several devices.
+/// The group's policy is stored as a group extension, so every member accepts and refuses the same
+/// `Add`s. Groups without this extension use `DuplicateIdentityPolicy::Reject`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename = "DuplicateIdentityPolicy__enum_u8")]
+pub enum DuplicateIdentityPolicy {
+    /// An `Add` for an identity that's already in the roster is refused
+    #[default]
+    Reject,
+    /// An identity can be in the roster several times, but only with a different credential each
+    /// time. An `Add` for a credential that's already in the roster is still refused.
+    Allow,
+}
+
+impl KnownExtension for DuplicateIdentityPolicy {
+    const EXTENSION_TYPE: ExtensionType = extensions::DUPLICATE_IDENTITY_POLICY;
+}
+
+/// How the path secrets in a `DirectPathMessage` are encrypted. The group's choice is stored as a
+/// group extension, since whoever decrypts a path has to know how it was encrypted. Groups without
+/// this extension use `PathEncryption::Anonymous`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename = "PathEncryption__enum_u8")]
+pub enum PathEncryption {
+    /// Path secrets are encrypted with plain ECIES, like draft 4 says. Only the signature on the
+    /// `Handshake` says who they came from.
+    #[default]
+    Anonymous,
+    /// Path secrets are encrypted with the sender's leaf key mixed in, so a path secret only
+    /// decrypts if it was encrypted by whoever held the sender's leaf key before the operation.
+    SenderAuthenticated,
+}
+
+impl KnownExtension for PathEncryption {
+    const EXTENSION_TYPE: ExtensionType = extensions::PATH_ENCRYPTION;
+}
+
+/// The settings a group is created with. This is made with `GroupConfig::new` and then adjusted
+/// with the `set_*` methods, each of which returns the adjusted config. See
+/// `GroupState::new_singleton_group_with_config`.
+///
+/// The ciphersuite, protocol version, padding scheme, duplicate identity policy, path encryption,
+/// and extensions are part of the group, so every member sees them. The authorization policy has to
+/// be the same for every member, but each member sets it themselves. The rest are this member's own
+/// policy: the maximum group size only limits the `Add`s this member makes, the send limit only
+/// limits the application messages this member sends, the clock is only used to check the
+/// `UserInitKey`s of members this member adds, the parse mode only affects how this member parses
+/// `Handshake`s, the epoch retention only affects how long this member keeps old keys and
+/// `Welcome`s around, and the update policy and stale member policy only matter to a `Session`
+/// holding the group. Members who join from a `Welcome` get the defaults for their own policy.
+#[derive(Clone, Debug)]
+pub struct GroupConfig {
+    pub(crate) cs: &'static CipherSuite,
+    pub(crate) protocol_version: ProtocolVersion,
+    pub(crate) max_group_size: Option<usize>,
+    pub(crate) max_messages_per_epoch: Option<u32>,
+    pub(crate) padding_scheme: PaddingScheme,
+    pub(crate) epoch_retention: usize,
+    pub(crate) extensions: ExtensionList,
+    pub(crate) update_policy: UpdatePolicy,
+    pub(crate) stale_member_policy: StaleMemberPolicy,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) parse_mode: ParseMode,
+    pub(crate) duplicate_identity_policy: DuplicateIdentityPolicy,
+    pub(crate) path_encryption: PathEncryption,
+    pub(crate) authorization_policy: Arc<dyn AuthorizationPolicy>,
+}
+
+impl GroupConfig {
+    /// Makes the default config for a group with the given ciphersuite. This uses the protocol
+    /// version `MLS_DUMMY_VERSION`, no size limit, no send limit, `PaddingScheme::None`, no
+    /// extensions, `DEFAULT_EPOCH_RETENTION`, `UpdatePolicy::Manual`, `StaleMemberPolicy::Ignore`,
+    /// the `SystemClock`, `ParseMode::Lenient`, `DuplicateIdentityPolicy::Reject`,
+    /// `PathEncryption::Anonymous`, and the `AllowAll` authorization policy.
+    pub fn new(cs: &'static CipherSuite) -> GroupConfig {
+        GroupConfig {
+            cs,
+            protocol_version: MLS_DUMMY_VERSION,
+            max_group_size: None,
+            max_messages_per_epoch: None,
+            padding_scheme: PaddingScheme::None,
+            epoch_retention: DEFAULT_EPOCH_RETENTION,
+            extensions: ExtensionList::new(),
+            update_policy: UpdatePolicy::Manual,
+            stale_member_policy: StaleMemberPolicy::Ignore,
+            clock: Arc::new(SystemClock),
+            parse_mode: ParseMode::Lenient,
+            duplicate_identity_policy: DuplicateIdentityPolicy::Reject,
+            path_encryption: PathEncryption::Anonymous,
+            authorization_policy: Arc::new(AllowAll),
+        }
+    }
+
+    /// Returns this config with the given protocol version
+    pub fn set_protocol_version(mut self, protocol_version: ProtocolVersion) -> GroupConfig {
+        self.protocol_version = protocol_version;
+        self
+    }
+
+    /// Returns this config with the given maximum number of members. `None` means no limit.
+    pub fn set_max_group_size(mut self, max_group_size: Option<usize>) -> GroupConfig {
+        self.max_group_size = max_group_size;
+        self
+    }
+
+    /// Returns this config with the given maximum number of application messages this member can
+    /// send in one epoch. Once it's reached, encrypting returns `Error::UpdateRequired` until the
+    /// group moves to a new epoch, e.g., by this member making an Update. This bounds how much
+    /// traffic a single compromised epoch key exposes. `None` means no limit.
+    pub fn set_max_messages_per_epoch(mut self, max_messages: Option<u32>) -> GroupConfig {
+        self.max_messages_per_epoch = max_messages;
+        self
+    }
+
+    /// Returns this config with the given padding scheme for application messages
+    pub fn set_padding_scheme(mut self, padding_scheme: PaddingScheme) -> GroupConfig {
+        self.padding_scheme = padding_scheme;
+        self
+    }
+
+    /// Returns this config with the given number of epochs, counting the current one, to keep
+    /// application keys for. See `Session::set_epoch_retention`. This is also how many epochs this
+    /// member can re-issue the `Welcome`s of members they added for. See
+    /// `GroupState::reissue_welcome`.
+    pub fn set_epoch_retention(mut self, num_epochs: usize) -> GroupConfig {
+        self.epoch_retention = core::cmp::max(num_epochs, 1);
+        self
+    }
+
+    /// Returns this config with the given group-wide extensions. A `PaddingScheme` extension in
+    /// here is overridden by `GroupConfig::set_padding_scheme`, unless the padding scheme is
+    /// `PaddingScheme::None`.
+    pub fn set_extensions(mut self, extensions: ExtensionList) -> GroupConfig {
+        self.extensions = extensions;
+        self
+    }
+
+    /// Returns this config with the given update policy
+    pub fn set_update_policy(mut self, update_policy: UpdatePolicy) -> GroupConfig {
+        self.update_policy = update_policy;
+        self
+    }
+
+    /// Returns this config with the given policy on other members who haven't refreshed their leaf
+    /// in a while
+    pub fn set_stale_member_policy(mut self, policy: StaleMemberPolicy) -> GroupConfig {
+        self.stale_member_policy = policy;
+        self
+    }
+
+    /// Returns this config with the given clock. This is what this member checks the lifetimes
+    /// of `UserInitKey`s against before adding anyone.
+    pub fn set_clock(mut self, clock: Arc<dyn Clock>) -> GroupConfig {
+        self.clock = clock;
+        self
+    }
+
+    /// Returns this config with the given parse mode. This is what
+    /// `GroupState::deserialize_handshake` parses in.
+    pub fn set_parse_mode(mut self, parse_mode: ParseMode) -> GroupConfig {
+        self.parse_mode = parse_mode;
+        self
+    }
+
+    /// Returns this config with the given policy on identities being in the group more than once.
+    /// A `DuplicateIdentityPolicy` extension in here is overridden by this, unless the policy is
+    /// `DuplicateIdentityPolicy::Reject`.
+    pub fn set_duplicate_identity_policy(mut self, policy: DuplicateIdentityPolicy) -> GroupConfig {
+        self.duplicate_identity_policy = policy;
+        self
+    }
+
+    /// Returns this config with the given way of encrypting path secrets. A `PathEncryption`
+    /// extension in here is overridden by this, unless it's `PathEncryption::Anonymous`.
+    pub fn set_path_encryption(mut self, path_encryption: PathEncryption) -> GroupConfig {
+        self.path_encryption = path_encryption;
+        self
+    }
+
+    /// Returns this config with the given policy on who may make which operations. Every member
+    /// of the group has to use the same one. See `GroupState::set_authorization_policy`.
+    pub fn set_authorization_policy(mut self, policy: Arc<dyn AuthorizationPolicy>) -> GroupConfig {
+        self.authorization_policy = policy;
+        self
+    }
+
+    /// Returns the ciphersuite
+    pub fn get_cipher_suite(&self) -> &'static CipherSuite {
+        self.cs
+    }
+
+    /// Returns the protocol version
+    pub fn get_protocol_version(&self) -> ProtocolVersion {
+        self.protocol_version
+    }
+
+    /// Returns the maximum number of members, or `None` if there's no limit
+    pub fn get_max_group_size(&self) -> Option<usize> {
+        self.max_group_size
+    }
+
+    /// Returns the maximum number of application messages this member can send in one epoch, or
+    /// `None` if there's no limit
+    pub fn get_max_messages_per_epoch(&self) -> Option<u32> {
+        self.max_messages_per_epoch
+    }
+
+    /// Returns the padding scheme for application messages
+    pub fn get_padding_scheme(&self) -> PaddingScheme {
+        self.padding_scheme
+    }
+
+    /// Returns the number of epochs, counting the current one, to keep application keys for
+    pub fn get_epoch_retention(&self) -> usize {
+        self.epoch_retention
+    }
+
+    /// Returns the update policy
+    pub fn get_update_policy(&self) -> UpdatePolicy {
+        self.update_policy
+    }
+
+    /// Returns the policy on other members who haven't refreshed their leaf in a while
+    pub fn get_stale_member_policy(&self) -> StaleMemberPolicy {
+        self.stale_member_policy
+    }
+
+    /// Returns the clock
+    pub fn get_clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    /// Returns the parse mode
+    pub fn get_parse_mode(&self) -> ParseMode {
+        self.parse_mode
+    }
+
+    /// Returns the policy on identities being in the group more than once
+    pub fn get_duplicate_identity_policy(&self) -> DuplicateIdentityPolicy {
+        self.duplicate_identity_policy
+    }
+
+    /// Returns the way path secrets are encrypted
+    pub fn get_path_encryption(&self) -> PathEncryption {
+        self.path_encryption
+    }
+
+    /// Returns the policy on who may make which operations
+    pub fn get_authorization_policy(&self) -> &dyn AuthorizationPolicy {
+        self.authorization_policy.as_ref()
+    }
+
+    /// Returns the extensions a group made with this config starts out with. This is the
+    /// configured extensions plus the padding scheme, if there is one, and the duplicate identity
+    /// policy and path encryption, if they aren't the defaults.
+    ///
+    /// Returns: `Ok(extensions)` on success. Returns an `Error::ValidationError` if the
+    /// configured extensions have duplicate types, and an `Error::SerdeError` if the padding
+    /// scheme can't be serialized.
+    pub(crate) fn group_extensions(&self) -> Result<ExtensionList, Error> {
+        self.extensions.validate()?;
+        let mut extensions = self.extensions.clone();
+        if self.padding_scheme != PaddingScheme::None {
+            extensions.insert(&self.padding_scheme)?;
+        }
+        if self.duplicate_identity_policy != DuplicateIdentityPolicy::Reject {
+            extensions.insert(&self.duplicate_identity_policy)?;
+        }
+        if self.path_encryption != PathEncryption::Anonymous {
+            extensions.insert(&self.path_encryption)?;
+        }
+
+        Ok(extensions)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        credential::RosterIndex, crypto::ciphersuite::X25519_SHA256_AES128GCM,
+        group_state::GroupState, ratchet_tree::PathSecret, session::Session, test_utils,
+    };
+
+    use quickcheck_macros::quickcheck;
+    use rand::SeedableRng;
+
+    // Makes a group from a config and checks that every setting took effect
+    #[quickcheck]
+    fn config_correctness(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let cs = &X25519_SHA256_AES128GCM;
+        let config = GroupConfig::new(cs)
+            .set_max_group_size(Some(2))
+            .set_padding_scheme(PaddingScheme::Block(64))
+            .set_epoch_retention(3)
+            .set_update_policy(UpdatePolicy::EveryNEpochs(1))
+            .set_parse_mode(ParseMode::Strict)
+            .set_path_encryption(PathEncryption::SenderAuthenticated);
+
+        let (credential, identity_key) = test_utils::random_basic_credential(&mut rng);
+        let group_state = GroupState::new_singleton_group_with_config(
+            identity_key,
+            b"configured".to_vec(),
+            credential,
+            config,
+            &mut rng,
+        )
+        .unwrap();
+        // The padding scheme and path encryption are group-wide, so they're in the extensions
+        assert_eq!(group_state.get_padding_scheme().unwrap(), PaddingScheme::Block(64));
+        assert_eq!(group_state.get_path_encryption().unwrap(), PathEncryption::SenderAuthenticated);
+        assert_eq!(group_state.get_config().get_max_group_size(), Some(2));
+        assert_eq!(group_state.get_config().get_parse_mode(), ParseMode::Strict);
+
+        let mut session = Session::new(group_state, None);
+        assert!(!session.is_update_due());
+
+        // The group can grow to 2 members and no further
+        let (init_key, _) = test_utils::random_init_key(b"config test", cs, &mut rng);
+        session.create_and_apply_add_handshake(RosterIndex(1), init_key, &mut rng).unwrap();
+        let (init_key, _) = test_utils::random_init_key(b"config test", cs, &mut rng);
+        match session.create_and_apply_add_handshake(RosterIndex(2), init_key, &mut rng) {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("added a member past the maximum group size"),
+        }
+
+        // An epoch has passed since we last touched our leaf, so we're due for an Update. Making
+        // one resets the count.
+        assert!(session.is_update_due());
+        let path_secret = PathSecret::new_from_random(cs, &mut rng);
+        session.create_and_apply_update_handshake(path_secret, &mut rng).unwrap();
+        assert!(!session.is_update_due());
+
+        // The session keeps the previous epoch's keys, which the default config wouldn't
+        assert_eq!(session.num_retained_past_epochs(), 1);
+    }
+}
@@ -1,12 +1,63 @@
 //! Defines the data structures that relate to user identity and long-term keys
 
-use crate::crypto::sig::{SigPublicKey, SignatureScheme};
+use crate::crypto::{
+    rng::CryptoRng,
+    sig::{SigPublicKey, SigSecretKey, Signature, SignatureScheme},
+};
 use crate::error::Error;
+use crate::tree_math::{self, LeafIndex, NodeIndex};
+
+use core::convert::TryFrom;
+use std::collections::BTreeMap;
+
+/// The position of an entry in a `Roster`. A member's roster index is the same number as their
+/// leaf index, but it's a `u32` on the wire and it's never a node index. Going between the two is
+/// always explicit, so that roster positions and tree positions can't be mixed up.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct RosterIndex(pub u32);
+
+impl RosterIndex {
+    /// Returns the leaf index of the member at this roster index
+    ///
+    /// Returns: `Ok(leaf_idx)` on success. Returns an `Error::ValidationError` if no tree has a
+    /// leaf there, which is only possible on platforms where a `u32` can hold more than
+    /// `MAX_LEAVES`.
+    pub fn leaf_index(self) -> Result<LeafIndex, Error> {
+        let leaf_idx = LeafIndex(self.0 as usize);
+        if leaf_idx.0 >= tree_math::MAX_LEAVES {
+            Err(Error::ValidationError("roster/tree size invariant violated"))
+        } else {
+            Ok(leaf_idx)
+        }
+    }
+
+    /// Returns the node index of the leaf of the member at this roster index
+    ///
+    /// Returns: `Ok(node_idx)` on success. Returns an `Error::ValidationError` if no tree has a
+    /// leaf there. See `RosterIndex::leaf_index`.
+    pub fn node_index(self) -> Result<NodeIndex, Error> {
+        self.leaf_index().map(LeafIndex::node_index)
+    }
+}
+
+// LeafIndex --> RosterIndex when the leaf index fits in a u32
+impl TryFrom<LeafIndex> for RosterIndex {
+    type Error = Error;
+
+    fn try_from(leaf_idx: LeafIndex) -> Result<RosterIndex, Error> {
+        u32::try_from(leaf_idx.0)
+            .map(RosterIndex)
+            .map_err(|_| Error::ValidationError("Leaf index doesn't fit in a roster index"))
+    }
+}
 
 // TODO: Decide whether we check the size on the lower end while (de)serializing
 
 /// A `Roster`, as it appears in a `GroupState`, is a list of optional `Credential`s
 // Invariant: Rosters can never be empty
+// Invariant: Every credential in a roster passes Credential::validate. Outside of tests, entries
+// are only ever filled, replaced, or emptied through add_at, replace_at, and remove_at, which
+// check this, so that swapping out a member's key can't happen by accident.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct Roster(pub(crate) Vec<Option<Credential>>);
 
@@ -16,7 +67,6 @@ impl Roster {
     /// Requires: That there is at least one nonblank entry in the roster
     ///
     /// Returns: `Ok(())` on success. If the above requirement is not met, returns an `Error`.
-    #[must_use]
     pub(crate) fn truncate_to_last_nonblank(&mut self) -> Result<(), Error> {
         // Find the last non-None credential
         let mut last_nonempty_roster_entry = None;
@@ -43,13 +93,159 @@ impl Roster {
         }
     }
 
-    pub(crate) fn len(&self) -> usize {
+    /// Puts the given credential at the given index. The index must either be that of an empty
+    /// entry, or equal to the length of the roster, in which case the roster is extended by one.
+    ///
+    /// Returns: `Ok(())` on success. Otherwise returns an `Error::ValidationError` and leaves the
+    /// roster untouched.
+    pub(crate) fn add_at(
+        &mut self,
+        index: RosterIndex,
+        credential: Credential,
+    ) -> Result<(), Error> {
+        credential.validate()?;
+        if index.0 as usize == self.0.len() {
+            self.0.push(Some(credential));
+            return Ok(());
+        }
+
+        let entry = self
+            .0
+            .get_mut(index.0 as usize)
+            .ok_or(Error::ValidationError("Roster index is past the end of the roster"))?;
+        if entry.is_some() {
+            return Err(Error::ValidationError("Cannot overwrite a non-empty roster entry"));
+        }
+
+        *entry = Some(credential);
+        Ok(())
+    }
+
+    /// Replaces the credential at the given index, which must be non-empty, with `credential`.
+    /// This is only for a member's own properly signed `GroupCredentialUpdate`. It's up to the
+    /// caller to check that signature.
+    ///
+    /// Returns: `Ok(old_credential)` on success. Otherwise returns an `Error::ValidationError` and
+    /// leaves the roster untouched.
+    pub(crate) fn replace_at(
+        &mut self,
+        index: RosterIndex,
+        credential: Credential,
+    ) -> Result<Credential, Error> {
+        credential.validate()?;
+        let entry = self
+            .0
+            .get_mut(index.0 as usize)
+            .ok_or(Error::ValidationError("Roster index is past the end of the roster"))?
+            .as_mut()
+            .ok_or(Error::ValidationError("Cannot replace an empty roster entry"))?;
+
+        Ok(core::mem::replace(entry, credential))
+    }
+
+    /// Empties the entry at the given index
+    ///
+    /// Returns: `Ok(old_entry)` on success, where `old_entry` is what used to be at that index.
+    /// Returns an `Error::ValidationError` if the index is past the end of the roster.
+    pub(crate) fn remove_at(&mut self, index: RosterIndex) -> Result<Option<Credential>, Error> {
+        let entry = self
+            .0
+            .get_mut(index.0 as usize)
+            .ok_or(Error::ValidationError("Roster index is past the end of the roster"))?;
+        Ok(entry.take())
+    }
+
+    /// Checks that every credential in the roster is well-formed. This is for rosters that came
+    /// from someone else, like the one in a `WelcomeInfo`.
+    ///
+    /// Returns: `Ok(())` on success. Otherwise returns an `Error::ValidationError`.
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        self.credential_iter().try_for_each(Credential::validate)
+    }
+
+    /// Returns the number of entries in the roster, including empty ones. This is also the roster
+    /// index at which an appending `Add` puts its new member.
+    pub fn len(&self) -> usize {
         self.0.len()
     }
 
+    /// Returns whether the roster has no entries at all, empty or not
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns `Some(credential)` if the entry at the given index is non-empty. Otherwise, if it's
+    /// empty or past the end of the roster, returns `None`.
+    pub fn get(&self, index: RosterIndex) -> Option<&Credential> {
+        self.0.get(index.0 as usize).and_then(Option::as_ref)
+    }
+
     /// Returns an iterator of the non-empty entries in the roster
     pub fn credential_iter(&self) -> impl Iterator<Item = &Credential> {
-        self.0.iter().filter(|x| x.is_some()).map(|x| x.as_ref().unwrap())
+        self.0.iter().filter_map(|x| x.as_ref())
+    }
+
+    /// Returns the roster index that an `Add` should fill: the first empty entry if there is one,
+    /// otherwise the index right past the end
+    ///
+    /// Returns: `Ok(roster_index)` on success, and an `Error::ValidationError` if the roster is full
+    /// and the next index doesn't fit in a `u32`
+    pub(crate) fn next_add_index(&self) -> Result<RosterIndex, Error> {
+        let idx = self.0.iter().position(|entry| entry.is_none()).unwrap_or(self.0.len());
+        u32::try_from(idx)
+            .map(RosterIndex)
+            .map_err(|_| Error::ValidationError("Group is too big to add anyone else to"))
+    }
+}
+
+/// A lookup table from member identities to roster indices, so that finding a member doesn't mean
+/// scanning the roster. This is derived entirely from a `Roster`, and has to be updated alongside
+/// it whenever an entry is filled or emptied.
+// Two roster entries can have the same identity if the group's DuplicateIdentityPolicy allows it,
+// e.g., one person on two devices, so every identity maps to all of its indices, in ascending order
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct MemberIndex(BTreeMap<Vec<u8>, Vec<RosterIndex>>);
+
+impl MemberIndex {
+    /// Builds the index of the given roster
+    pub(crate) fn from_roster(roster: &Roster) -> MemberIndex {
+        let mut index = MemberIndex::default();
+        for (i, entry) in roster.0.iter().enumerate() {
+            if let Some(cred) = entry {
+                // Roster indices fit in a u32. See RosterIndex::leaf_index.
+                index.insert(cred.get_identity(), RosterIndex(i as u32));
+            }
+        }
+
+        index
+    }
+
+    /// Records that the member at `roster_index` has the given identity
+    pub(crate) fn insert(&mut self, identity: &Identity, roster_index: RosterIndex) {
+        let indices = self.0.entry(identity.0.clone()).or_default();
+        if let Err(pos) = indices.binary_search(&roster_index) {
+            indices.insert(pos, roster_index);
+        }
+    }
+
+    /// Records that the member at `roster_index`, who had the given identity, is gone
+    pub(crate) fn remove(&mut self, identity: &Identity, roster_index: RosterIndex) {
+        if let Some(indices) = self.0.get_mut(identity.0.as_slice()) {
+            indices.retain(|&idx| idx != roster_index);
+            if indices.is_empty() {
+                self.0.remove(identity.0.as_slice());
+            }
+        }
+    }
+
+    /// Returns the lowest roster index of a member with the given identity, if there is one
+    pub(crate) fn get(&self, identity: &[u8]) -> Option<RosterIndex> {
+        self.0.get(identity).and_then(|indices| indices.first().cloned())
+    }
+
+    /// Returns the roster indices of every member with the given identity, in ascending order
+    pub(crate) fn get_all(&self, identity: &[u8]) -> &[RosterIndex] {
+        self.0.get(identity).map(Vec::as_slice).unwrap_or(&[])
     }
 }
 
@@ -59,12 +255,12 @@ impl Roster {
 /// NOTE: This currently doesn't do anything.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(rename = "X509CertData__bound_u24")]
-pub struct X509CertData(Vec<u8>);
+pub struct X509CertData(pub(crate) Vec<u8>);
 
 // opaque identity<0..2^16-1>;
 /// A bytestring that should uniquely identify the user in the Group
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
-#[serde(rename = "Identity__bound_u16")]
+#[serde(rename = "Identity__nonempty__bound_u16")]
 pub struct Identity(pub(crate) Vec<u8>);
 
 impl Identity {
@@ -79,6 +275,14 @@ impl Identity {
     }
 }
 
+// Usernames, email addresses, etc. are the most common identities
+/// Makes an `Identity` out of the UTF-8 bytes of the given string
+impl<'a> From<&'a str> for Identity {
+    fn from(s: &'a str) -> Identity {
+        Identity(s.as_bytes().to_vec())
+    }
+}
+
 /// A user credential without respect to any standard credential format
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct BasicCredential {
@@ -107,8 +311,18 @@ impl BasicCredential {
     }
 }
 
+/// A `BasicCredential` is the simplest kind of `Credential`
+impl From<BasicCredential> for Credential {
+    fn from(basic: BasicCredential) -> Credential {
+        Credential::Basic(basic)
+    }
+}
+
 /// A user credential specifies the member's identity, public signing key, and signature scheme the
 /// member will use to sign messages
+// Most credentials are basic ones, and they're kept in a roster, not passed around by value, so the
+// size difference doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(rename = "Credential__enum_u8")]
 pub enum Credential {
@@ -117,6 +331,92 @@ pub enum Credential {
 }
 
 impl Credential {
+    /// Generates a fresh identity key under the given signature scheme and makes a
+    /// `BasicCredential` binding it to `identity`. This is all that's needed to start making and
+    /// joining groups.
+    ///
+    /// Returns: `Ok((credential, identity_key))` on success, where `identity_key` is the secret
+    /// key corresponding to the public key in `credential`. On error, returns
+    /// `Error::SignatureError` or `Error::OutOfEntropy`.
+    pub fn new_basic_from_random<R>(
+        identity: Identity,
+        ss: &'static SignatureScheme,
+        csprng: &mut R,
+    ) -> Result<(Credential, SigSecretKey), Error>
+    where
+        R: CryptoRng,
+    {
+        let identity_key = SigSecretKey::new_from_random(ss, csprng)?;
+        let public_key = SigPublicKey::new_from_secret_key(ss, &identity_key);
+        let credential = Credential::from(BasicCredential::new(identity, ss, public_key));
+
+        Ok((credential, identity_key))
+    }
+
+    /// Makes a copy of this credential with the public key replaced by `public_key`. The identity
+    /// and signature scheme stay the same.
+    ///
+    /// Returns: `Ok(credential)` on success. Returns an `Error::ValidationError` if this is an
+    /// X.509 credential, since those can't be rewritten without the issuer.
+    pub(crate) fn with_public_key(&self, public_key: SigPublicKey) -> Result<Credential, Error> {
+        match self {
+            Credential::Basic(ref basic) => Ok(Credential::from(BasicCredential::new(
+                basic.identity.clone(),
+                basic.signature_scheme,
+                public_key,
+            ))),
+            Credential::X509(_) => {
+                Err(Error::ValidationError("Cannot change the public key of an X.509 credential"))
+            }
+        }
+    }
+
+    /// Checks that this credential's public key is actually a key for its signature scheme. A
+    /// credential that fails this can't verify anything. Every credential that makes it into a
+    /// `Roster` has passed this.
+    ///
+    /// Returns: `Ok(())` on success. Otherwise returns an `Error::ValidationError`.
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        match self {
+            Credential::Basic(ref basic) => {
+                if basic.signature_scheme.is_public_key(&basic.public_key) {
+                    Ok(())
+                } else {
+                    Err(Error::ValidationError(
+                        "Credential's public key isn't for its signature scheme",
+                    ))
+                }
+            }
+            Credential::X509(_) => {
+                Err(Error::ValidationError("X.509 credentials aren't supported yet"))
+            }
+        }
+    }
+
+    /// Checks that `identity_key` is the secret key corresponding to this credential's public key,
+    /// i.e., that whatever it signs will verify under this credential
+    ///
+    /// Returns: `Ok(())` on success. Returns an `Error::ValidationError` if this credential is
+    /// malformed, and an `Error::SignatureError` if `identity_key` is the wrong key.
+    pub fn check_identity_key(&self, identity_key: &SigSecretKey) -> Result<(), Error> {
+        self.validate()?;
+        if self.get_signature_scheme().is_key_pair(identity_key, self.get_public_key()) {
+            Ok(())
+        } else {
+            Err(Error::SignatureError("Identity key doesn't match the credential"))
+        }
+    }
+
+    /// Verifies a signature of the given message under this credential's public key and signature
+    /// scheme. Every signature a member makes in a group is checked through here.
+    ///
+    /// Returns: `Ok(())` on success. Returns an `Error::ValidationError` if this credential is
+    /// malformed, and an `Error::SignatureError` if the signature doesn't verify.
+    pub(crate) fn verify(&self, msg: &[u8], sig: &Signature) -> Result<(), Error> {
+        self.validate()?;
+        self.get_signature_scheme().verify(self.get_public_key(), msg, sig)
+    }
+
     pub(crate) fn get_public_key(&self) -> &SigPublicKey {
         match self {
             Credential::Basic(ref basic) => &basic.public_key,
@@ -138,3 +438,109 @@ impl Credential {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        credential::{BasicCredential, Credential, Identity, Roster, RosterIndex},
+        crypto::{
+            ciphersuite::X25519_SHA256_AES128GCM,
+            sig::{SigPublicKey, ECDSA_P384_IMPL, ED25519_IMPL},
+        },
+        error::Error,
+        group_state::GroupState,
+        handshake::MLS_DUMMY_VERSION,
+        ratchet_tree::PathSecret,
+        test_utils,
+        tree_math::LeafIndex,
+    };
+
+    use core::convert::TryFrom;
+    use quickcheck_macros::quickcheck;
+    use rand::SeedableRng;
+
+    // Checks that a roster index goes to the same leaf and back, that its leaf is where the tree
+    // math puts it, and that a leaf index too big for the wire isn't a roster index
+    #[quickcheck]
+    fn roster_index_conversion(roster_index: u32) {
+        let roster_index = RosterIndex(roster_index);
+        let leaf_idx = roster_index.leaf_index().unwrap();
+        assert_eq!(leaf_idx, LeafIndex(roster_index.0 as usize));
+        assert_eq!(roster_index.node_index().unwrap(), leaf_idx.node_index());
+        assert_eq!(RosterIndex::try_from(leaf_idx).unwrap(), roster_index);
+
+        let too_big = LeafIndex(u32::MAX as usize + 1);
+        assert!(RosterIndex::try_from(too_big).is_err());
+    }
+
+    // Checks that a freshly generated credential can verify signatures made with its identity key
+    #[quickcheck]
+    fn basic_credential_from_random(rng_seed: u64, username: String) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let ss = &ED25519_IMPL;
+        let (credential, identity_key) =
+            Credential::new_basic_from_random(Identity::from(username.as_str()), ss, &mut rng)
+                .unwrap();
+
+        assert_eq!(credential.get_identity().as_bytes(), username.as_bytes());
+        assert_eq!(credential.get_signature_scheme(), ss);
+
+        let msg = b"hello";
+        let sig = ss.sign(&identity_key, msg);
+        ss.verify(credential.get_public_key(), msg, &sig).unwrap();
+    }
+
+    // Checks that a credential whose key isn't for its signature scheme can't get into a roster,
+    // and that nobody can start a group or rotate their credential with an identity key that
+    // doesn't belong to the credential
+    #[quickcheck]
+    fn credential_key_binding(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+
+        // An Ed25519 public key claiming to be a P-384 one
+        let (ed_credential, ed_identity_key) =
+            Credential::new_basic_from_random("ed".into(), &ED25519_IMPL, &mut rng).unwrap();
+        let ed_public_key = SigPublicKey::new_from_secret_key(&ED25519_IMPL, &ed_identity_key);
+        let mislabeled =
+            Credential::from(BasicCredential::new("ed".into(), &ECDSA_P384_IMPL, ed_public_key));
+        assert!(mislabeled.validate().is_err());
+        let mut roster = Roster(vec![Some(ed_credential.clone())]);
+        assert!(roster.add_at(RosterIndex(1), mislabeled.clone()).is_err());
+        assert!(roster.replace_at(RosterIndex(0), mislabeled).is_err());
+        assert_eq!(roster, Roster(vec![Some(ed_credential.clone())]));
+
+        // Someone else's key, of either scheme, isn't this credential's key
+        let (_, other_identity_key) = test_utils::random_basic_credential(&mut rng);
+        ed_credential.check_identity_key(&ed_identity_key).unwrap();
+        match ed_credential.check_identity_key(&other_identity_key) {
+            Err(Error::SignatureError(_)) => (),
+            _ => panic!("identity key matched someone else's credential"),
+        }
+        match GroupState::new_singleton_group(
+            &X25519_SHA256_AES128GCM,
+            MLS_DUMMY_VERSION,
+            other_identity_key.clone(),
+            b"mismatched".to_vec(),
+            ed_credential,
+            &mut rng,
+        ) {
+            Err(Error::SignatureError(_)) => (),
+            _ => panic!("made a group with an identity key that isn't the credential's"),
+        }
+
+        // A CredentialUpdate has to be signed by the new credential's key
+        let (group_state, _) = test_utils::random_full_group_state(2, &mut rng);
+        let my_credential =
+            group_state.roster.0[group_state.roster_index.unwrap().0 as usize].clone().unwrap();
+        let (_, new_identity_key) = test_utils::random_basic_credential(&mut rng);
+        let path_secret = PathSecret::new_from_random(group_state.cs, &mut rng);
+        assert!(group_state
+            .create_and_apply_credential_update_handshake(
+                my_credential,
+                new_identity_key,
+                path_secret,
+                &mut rng,
+            )
+            .is_err());
+    }
+}
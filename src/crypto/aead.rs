@@ -1,4 +1,4 @@
-use crate::error::Error;
+use crate::{crypto::backend, error::Error};
 
 /// A singleton object representing the AES-128-GCM AEAD scheme
 pub(crate) const AES128GCM_IMPL: AeadScheme = AeadScheme(&Aes128Gcm);
@@ -13,7 +13,7 @@ const AES_128_GCM_NONCE_SIZE: usize = 96 / 8;
 /// An enum of possible types for an AEAD key, depending on the underlying algorithm
 pub(crate) enum AeadKey {
     /// An opening / sealing key in AES-128-GCM
-    Aes128GcmKey(Aes128GcmKey),
+    Aes128GcmKey(backend::Aes128GcmKey),
 }
 
 impl AeadKey {
@@ -38,7 +38,7 @@ impl core::fmt::Debug for AeadKey {
 /// An enum of possible types for an AEAD nonce, depending on the underlying algorithm
 pub(crate) enum AeadNonce {
     /// A nonce in AES-128-GCM
-    Aes128GcmNonce(ring::aead::Nonce),
+    Aes128GcmNonce([u8; AES_128_GCM_NONCE_SIZE]),
 }
 
 impl AeadNonce {
@@ -91,15 +91,16 @@ impl AeadScheme {
     /// Returns: `Ok(plaintext)` on sucess, where `plaintext` is the decrypted form of the
     /// ciphertext, with no tags or garbage bytes (in particular, it's the same buffer as the input
     /// bytes, but without the last `self.tag_size()` bytes). If there is an error in any part of
-    /// this process, it will be returned as an `Error::CryptoError` with description
-    /// "Unspecified".
+    /// this process, including `aad` not being what the ciphertext was sealed with, it will be
+    /// returned as an `Error::CryptoError` with description "Unspecified".
     pub(crate) fn open<'a>(
         &self,
         key: &AeadKey,
         nonce: AeadNonce,
+        aad: &[u8],
         ciphertext_and_tag_modified_in_place: &'a mut [u8],
     ) -> Result<&'a mut [u8], Error> {
-        self.0.open(key, nonce, ciphertext_and_tag_modified_in_place)
+        self.0.open(key, nonce, aad, ciphertext_and_tag_modified_in_place)
     }
 
     // This just passes through to AeadSchemeInterface::seal
@@ -118,18 +119,19 @@ impl AeadScheme {
         &self,
         key: &AeadKey,
         nonce: AeadNonce,
+        aad: &[u8],
         plaintext: &mut [u8],
     ) -> Result<(), Error> {
-        self.0.seal(key, nonce, plaintext)
+        self.0.seal(key, nonce, aad, plaintext)
     }
 }
 
-/// A trait representing an authenticated encryption algorithm. Note that this makes no mention of
-/// associated data, since it is not used anywhere in MLS.
+/// A trait representing an authenticated encryption algorithm with associated data. ECIES passes
+/// empty associated data. Application messages pass their epoch's `GroupContext`.
 // ring does algorithm specification at runtime, but I'd rather encode these things in the type
-// system. So, similar to the Digest trait, we're making an AuthenticatedEncryption trait. I don't
-// think we'll need associated data in this crate, so we leave it out for simplicity
-trait AeadSchemeInterface {
+// system. So, similar to the Digest trait, we're making an AuthenticatedEncryption trait.
+// This is Sync so that &'static AeadSchemes, and everything holding them, are Send + Sync.
+trait AeadSchemeInterface: Sync {
     // Recall we can't have const trait methods if we want this to be a trait object
     fn key_size(&self) -> usize;
     fn nonce_size(&self) -> usize;
@@ -143,25 +145,23 @@ trait AeadSchemeInterface {
         &self,
         key: &AeadKey,
         nonce: AeadNonce,
+        aad: &[u8],
         ciphertext_and_tag: &'a mut [u8],
     ) -> Result<&'a mut [u8], Error>;
 
-    fn seal(&self, key: &AeadKey, nonce: AeadNonce, plaintext: &mut [u8]) -> Result<(), Error>;
+    fn seal(
+        &self,
+        key: &AeadKey,
+        nonce: AeadNonce,
+        aad: &[u8],
+        plaintext: &mut [u8],
+    ) -> Result<(), Error>;
 }
 
 /// This represents the AES-128-GCM authenticated encryption algorithm. Notably, it implements
 /// `AuthenticatedEncryption`.
 pub(crate) struct Aes128Gcm;
 
-/// An opening / sealing key for use with the `Aes128Gcm` algorithm
-// These will just be two copies of the same thing. They're different types because ring requires
-// an OpeningKey for opening and a SealingKey for sealing. This incurs some 64 bytes of storage
-// overhead, but I frankly don't care.
-pub(crate) struct Aes128GcmKey {
-    opening_key: ring::aead::OpeningKey,
-    sealing_key: ring::aead::SealingKey,
-}
-
 impl AeadSchemeInterface for Aes128Gcm {
     /// Returns `AES_128_GCM_KEY_SIZE`
     fn key_size(&self) -> usize {
@@ -189,16 +189,7 @@ impl AeadSchemeInterface for Aes128Gcm {
             return Err(Error::EncryptionError("AES-GCM-128 requires 128-bit keys"));
         }
 
-        // Again, the opening and sealing keys for AES-GCM are the same.
-        let opening_key = ring::aead::OpeningKey::new(&ring::aead::AES_128_GCM, key_bytes)
-            .map_err(|_| Error::EncryptionError("Unspecified"))?;
-        let sealing_key = ring::aead::SealingKey::new(&ring::aead::AES_128_GCM, key_bytes)
-            .map_err(|_| Error::EncryptionError("Unspecified"))?;
-
-        let key = Aes128GcmKey {
-            opening_key,
-            sealing_key,
-        };
+        let key = backend::Aes128GcmKey::new(key_bytes)?;
         Ok(AeadKey::Aes128GcmKey(key))
     }
 
@@ -215,7 +206,7 @@ impl AeadSchemeInterface for Aes128Gcm {
 
         let mut nonce = [0u8; AES_128_GCM_NONCE_SIZE];
         nonce.copy_from_slice(nonce_bytes);
-        Ok(AeadNonce::Aes128GcmNonce(ring::aead::Nonce::assume_unique_for_key(nonce)))
+        Ok(AeadNonce::Aes128GcmNonce(nonce))
     }
 
     /// Does an in-place authenticated decryption of the given ciphertext and tag. The input should
@@ -232,24 +223,15 @@ impl AeadSchemeInterface for Aes128Gcm {
         &self,
         key: &AeadKey,
         nonce: AeadNonce,
+        aad: &[u8],
         ciphertext_and_tag_modified_in_place: &'a mut [u8],
     ) -> Result<&'a mut [u8], Error> {
         let key = enum_variant!(key, AeadKey::Aes128GcmKey);
         let nonce = enum_variant!(nonce, AeadNonce::Aes128GcmNonce);
 
-        // We use the standard decryption function with no associated data, and no "prefix bytes".
-        // The length of the buffer is checked by the ring library. The function returns a
-        // plaintext = ciphertext_and_tag[..plaintext.len()] For more details on this function, see
-        // docs on ring::aead::open_in_place at
-        // https://briansmith.org/rustdoc/ring/aead/fn.open_in_place.html
-        ring::aead::open_in_place(
-            &key.opening_key,
-            nonce,
-            ring::aead::Aad::empty(),
-            0,
-            ciphertext_and_tag_modified_in_place,
-        )
-        .map_err(|_| Error::EncryptionError("Unspecified"))
+        // The length of the buffer is checked by the backend. The function returns a
+        // plaintext = ciphertext_and_tag[..plaintext.len()]
+        key.open_in_place(nonce, aad, ciphertext_and_tag_modified_in_place)
     }
 
     /// Does an in-place authenticated encryption of the given plaintext. The input MUST look like
@@ -262,27 +244,18 @@ impl AeadSchemeInterface for Aes128Gcm {
     /// Returns: `Ok(())` on sucess, indicating that the inputted buffer contains the tagged
     /// ciphertext. If there is an error in any part of this process, it will be returned as an
     /// `Error::CryptoError` with description "Unspecified".
-    fn seal(&self, key: &AeadKey, nonce: AeadNonce, plaintext: &mut [u8]) -> Result<(), Error> {
+    fn seal(
+        &self,
+        key: &AeadKey,
+        nonce: AeadNonce,
+        aad: &[u8],
+        plaintext: &mut [u8],
+    ) -> Result<(), Error> {
         let key = enum_variant!(key, AeadKey::Aes128GcmKey);
         let nonce = enum_variant!(nonce, AeadNonce::Aes128GcmNonce);
 
-        // We use the standard encryption function with no associated data. The length of the
-        // buffer is checked by the ring library.
-        // For more details on this function, see docs on ring::aead::seal_in_place at
-        // https://briansmith.org/rustdoc/ring/aead/fn.seal_in_place.html
-        let res = ring::aead::seal_in_place(
-            &key.sealing_key,
-            nonce,
-            ring::aead::Aad::empty(),
-            plaintext,
-            AES_128_GCM_TAG_SIZE,
-        );
-
-        if res.is_ok() {
-            Ok(())
-        } else {
-            Err(Error::EncryptionError("Unspecified"))
-        }
+        // The length of the buffer is checked by the backend
+        key.seal_in_place(nonce, aad, plaintext, AES_128_GCM_TAG_SIZE)
     }
 }
 
@@ -294,7 +267,62 @@ mod test {
     use quickcheck_macros::quickcheck;
     use rand::{RngCore, SeedableRng};
 
-    // TODO: AES-GCM KAT
+    // Checks seal and open against test case 2 of the GCM spec (McGrew and Viega, "The
+    // Galois/Counter Mode of Operation"): an all-zero key, nonce, and 16-byte plaintext. This runs
+    // under whichever crypto backend is selected.
+    #[test]
+    fn aes_gcm_kat() {
+        let scheme = &AES128GCM_IMPL;
+        let key = AeadKey::new_from_bytes(scheme, &[0u8; 16]).unwrap();
+        let nonce = || AeadNonce::new_from_bytes(scheme, &[0u8; 12]).unwrap();
+
+        // 16 bytes of plaintext, followed by room for the tag
+        let mut buf = vec![0u8; 32];
+        scheme.seal(&key, nonce(), b"", &mut buf).unwrap();
+        assert_eq!(
+            hex::encode(&buf),
+            "0388dace60b6a392f328c2b971b2fe78ab6e47d42cec13bdf53a67b21257bddf"
+        );
+
+        let plaintext = scheme.open(&key, nonce(), b"", &mut buf).unwrap();
+        assert_eq!(plaintext, &[0u8; 16][..]);
+    }
+
+    // Checks associated data against test case 4 of the GCM spec, and makes sure the ciphertext
+    // doesn't open under any other associated data
+    #[test]
+    fn aes_gcm_aad_kat() {
+        let scheme = &AES128GCM_IMPL;
+        let key = AeadKey::new_from_bytes(
+            scheme,
+            &hex::decode("feffe9928665731c6d6a8f9467308308").unwrap(),
+        )
+        .unwrap();
+        let nonce =
+            || AeadNonce::new_from_bytes(scheme, &hex::decode("cafebabefacedbaddecaf888").unwrap());
+        let aad = hex::decode("feedfacedeadbeeffeedfacedeadbeefabaddad2").unwrap();
+        let plaintext = hex::decode(
+            "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72\
+             1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39",
+        )
+        .unwrap();
+
+        let mut buf = plaintext.clone();
+        buf.extend(vec![0u8; scheme.tag_size()]);
+        scheme.seal(&key, nonce().unwrap(), &aad, &mut buf).unwrap();
+        assert_eq!(
+            hex::encode(&buf),
+            "42831ec2217774244b7221b784d0d49ce3aa212f2c02a4e035c17e2329aca12e\
+             21d514b25466931c7d8f6a5aac84aa051ba30b396a0aac973d58e0915bc94fbc\
+             3221a5db94fae95ae7121a47"
+        );
+
+        let mut wrong_aad = aad.clone();
+        wrong_aad[0] ^= 1;
+        assert!(scheme.open(&key, nonce().unwrap(), &wrong_aad, &mut buf.clone()).is_err());
+        assert!(scheme.open(&key, nonce().unwrap(), b"", &mut buf.clone()).is_err());
+        assert_eq!(scheme.open(&key, nonce().unwrap(), &aad, &mut buf).unwrap(), &plaintext[..]);
+    }
 
     // Returns a pair of identical nonces. For testing purposes only
     fn gen_nonce_pair<T: RngCore>(scheme: &AeadScheme, rng: &mut T) -> (AeadNonce, AeadNonce) {
@@ -339,13 +367,15 @@ mod test {
         };
 
         // Encrypt
-        scheme.seal(&key, nonce1, extended_plaintext.as_mut_slice()).expect("failed to encrypt");
+        scheme
+            .seal(&key, nonce1, b"", extended_plaintext.as_mut_slice())
+            .expect("failed to encrypt");
 
         // Rename for clarity, since plaintext was modified in-place
         let auth_ciphertext = extended_plaintext.as_mut_slice();
 
         let recovered_plaintext =
-            scheme.open(&key, nonce2, auth_ciphertext).expect("failed to decrypt");
+            scheme.open(&key, nonce2, b"", auth_ciphertext).expect("failed to decrypt");
 
         // Make sure we get out what we put in
         assert_eq!(plaintext, recovered_plaintext);
@@ -368,7 +398,7 @@ mod test {
         plaintext.extend(vec![0u8; scheme.tag_size()]);
 
         // Encrypt
-        scheme.seal(&key, nonce1, plaintext.as_mut_slice()).expect("failed to encrypt");
+        scheme.seal(&key, nonce1, b"", plaintext.as_mut_slice()).expect("failed to encrypt");
 
         // Rename for clarity, since plaintext was modified in-place
         let auth_ciphertext = plaintext.as_mut_slice();
@@ -384,7 +414,7 @@ mod test {
         }
 
         // Make sure this fails to open
-        let res = scheme.open(&key, nonce2, auth_ciphertext);
+        let res = scheme.open(&key, nonce2, b"", auth_ciphertext);
         assert!(res.is_err());
     }
 
@@ -395,7 +425,7 @@ mod test {
         // This is only interesting if plaintext != "". Since XORing anything into the empty string
         // is a noop, the open() operation below will actually succeed. This property is checked in
         // aes_gcm_correctness.
-        if plaintext.len() == 0 {
+        if plaintext.is_empty() {
             return;
         }
         // We're only working with AES-128 GCM
@@ -411,7 +441,7 @@ mod test {
         plaintext.extend(vec![0u8; scheme.tag_size()]);
 
         // Encrypt
-        scheme.seal(&key, nonce1, plaintext.as_mut_slice()).expect("failed to encrypt");
+        scheme.seal(&key, nonce1, b"", plaintext.as_mut_slice()).expect("failed to encrypt");
 
         // Rename for clarity, since plaintext was modified in-place
         let auth_ciphertext = plaintext.as_mut_slice();
@@ -427,7 +457,7 @@ mod test {
         }
 
         // Make sure this fails to open
-        let res = scheme.open(&key, nonce2, auth_ciphertext);
+        let res = scheme.open(&key, nonce2, b"", auth_ciphertext);
         assert!(res.is_err());
     }
 }
@@ -0,0 +1,42 @@
+//! Picks the library that the hash, HMAC, HKDF, and AEAD primitives come from. The default is ring,
+//! selected by the `ring-backend` feature. The `rustcrypto-backend` feature swaps in the RustCrypto
+//! crates instead, for platforms that ring doesn't build on and for anyone who'd rather audit pure
+//! Rust. If both are enabled, RustCrypto wins, so that turning it on doesn't require also turning
+//! off the default features.
+//!
+//! Every backend exports the same set of items: `HashContext`, `HmacContext`, `hmac_verify`,
+//! `HkdfPrk`, `hkdf_expand`, and `Aes128GcmKey`. Nothing outside of `crypto` should touch these
+//! directly.
+//! DH and signatures don't go through here. Those come from their own crates either way.
+
+#[cfg(not(any(feature = "ring-backend", feature = "rustcrypto-backend")))]
+compile_error!(
+    "molasses needs a crypto backend. Enable either ring-backend or rustcrypto-backend."
+);
+
+#[cfg(all(feature = "ring-backend", not(feature = "rustcrypto-backend")))]
+mod ring_backend;
+#[cfg(all(feature = "ring-backend", not(feature = "rustcrypto-backend")))]
+pub(crate) use ring_backend::*;
+
+#[cfg(feature = "rustcrypto-backend")]
+mod rustcrypto_backend;
+#[cfg(feature = "rustcrypto-backend")]
+pub(crate) use rustcrypto_backend::*;
+
+/// The hash functions that a backend has to provide
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum HashAlgorithm {
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    /// Returns the size of this hash function's digests, in bytes
+    pub(crate) fn output_len(self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 => 32,
+            HashAlgorithm::Sha512 => 64,
+        }
+    }
+}
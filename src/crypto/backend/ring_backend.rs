@@ -0,0 +1,154 @@
+//! The ring backend. This is the default.
+
+use super::HashAlgorithm;
+use crate::error::Error;
+
+fn ring_alg(alg: HashAlgorithm) -> &'static ring::digest::Algorithm {
+    match alg {
+        HashAlgorithm::Sha256 => &ring::digest::SHA256,
+        HashAlgorithm::Sha512 => &ring::digest::SHA512,
+    }
+}
+
+/// An incremental hash computation
+pub(crate) struct HashContext(ring::digest::Context);
+
+impl HashContext {
+    pub(crate) fn new(alg: HashAlgorithm) -> HashContext {
+        HashContext(ring::digest::Context::new(ring_alg(alg)))
+    }
+
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    pub(crate) fn finish(self) -> Vec<u8> {
+        self.0.finish().as_ref().to_vec()
+    }
+}
+
+/// An incremental HMAC computation
+pub(crate) struct HmacContext(ring::hmac::SigningContext);
+
+impl HmacContext {
+    pub(crate) fn new(alg: HashAlgorithm, key: &[u8]) -> HmacContext {
+        let signing_key = ring::hmac::SigningKey::new(ring_alg(alg), key);
+        HmacContext(ring::hmac::SigningContext::with_key(&signing_key))
+    }
+
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    pub(crate) fn finish(self) -> Vec<u8> {
+        self.0.sign().as_ref().to_vec()
+    }
+}
+
+/// Checks that `tag` is the HMAC of `msg` under `key`. This runs in constant time.
+///
+/// Returns: `Ok(())` on success. Otherwise returns an `Error::SignatureError`.
+pub(crate) fn hmac_verify(
+    alg: HashAlgorithm,
+    key: &[u8],
+    msg: &[u8],
+    tag: &[u8],
+) -> Result<(), Error> {
+    let verification_key = ring::hmac::VerificationKey::new(ring_alg(alg), key);
+    ring::hmac::verify(&verification_key, msg, tag)
+        .map_err(|_| Error::SignatureError("MAC verification failed"))
+}
+
+/// An HKDF PRK that's been made into an HMAC key, so it can be expanded any number of times
+/// without setting up the key again
+pub(crate) struct HkdfPrk(ring::hmac::SigningKey);
+
+impl HkdfPrk {
+    pub(crate) fn new(alg: HashAlgorithm, prk: &[u8]) -> HkdfPrk {
+        HkdfPrk(ring::hmac::SigningKey::new(ring_alg(alg), prk))
+    }
+
+    /// Fills `out_buf` with `HKDF-Expand(prk, info, out_buf.len())`
+    ///
+    /// Panics: If `out_buf` is longer than 255 times the digest size of the hash algorithm
+    pub(crate) fn expand(&self, info: &[u8], out_buf: &mut [u8]) {
+        ring::hkdf::expand(&self.0, info, out_buf);
+    }
+}
+
+/// Fills `out_buf` with `HKDF-Expand(prk, info, out_buf.len())`
+///
+/// Panics: If `out_buf` is longer than 255 times the digest size of `alg`
+pub(crate) fn hkdf_expand(alg: HashAlgorithm, prk: &[u8], info: &[u8], out_buf: &mut [u8]) {
+    HkdfPrk::new(alg, prk).expand(info, out_buf);
+}
+
+/// An opening / sealing key for AES-128-GCM
+// These are two copies of the same thing. They're different types because ring requires an
+// OpeningKey for opening and a SealingKey for sealing.
+pub(crate) struct Aes128GcmKey {
+    opening_key: ring::aead::OpeningKey,
+    sealing_key: ring::aead::SealingKey,
+}
+
+impl Aes128GcmKey {
+    /// Makes a key out of the given bytes
+    ///
+    /// Returns: `Ok(key)` on success. Returns an `Error::EncryptionError` if `key_bytes` is the
+    /// wrong length.
+    pub(crate) fn new(key_bytes: &[u8]) -> Result<Aes128GcmKey, Error> {
+        let opening_key = ring::aead::OpeningKey::new(&ring::aead::AES_128_GCM, key_bytes)
+            .map_err(|_| Error::EncryptionError("Unspecified"))?;
+        let sealing_key = ring::aead::SealingKey::new(&ring::aead::AES_128_GCM, key_bytes)
+            .map_err(|_| Error::EncryptionError("Unspecified"))?;
+
+        Ok(Aes128GcmKey {
+            opening_key,
+            sealing_key,
+        })
+    }
+
+    /// Decrypts `ciphertext || tag` in place with the given associated data
+    ///
+    /// Returns: `Ok(plaintext)` on success, where `plaintext` is the front of the input buffer.
+    /// Otherwise returns an `Error::EncryptionError`.
+    pub(crate) fn open_in_place<'a>(
+        &self,
+        nonce: [u8; 12],
+        aad: &[u8],
+        ciphertext_and_tag: &'a mut [u8],
+    ) -> Result<&'a mut [u8], Error> {
+        // No "prefix bytes". ring checks the length of the buffer.
+        ring::aead::open_in_place(
+            &self.opening_key,
+            ring::aead::Nonce::assume_unique_for_key(nonce),
+            ring::aead::Aad::from(aad),
+            0,
+            ciphertext_and_tag,
+        )
+        .map_err(|_| Error::EncryptionError("Unspecified"))
+    }
+
+    /// Encrypts `plaintext || extra` in place with the given associated data, where `extra` is
+    /// `tag_size` bytes that get overwritten with the tag
+    ///
+    /// Returns: `Ok(())` on success. Otherwise returns an `Error::EncryptionError`.
+    pub(crate) fn seal_in_place(
+        &self,
+        nonce: [u8; 12],
+        aad: &[u8],
+        plaintext_and_extra: &mut [u8],
+        tag_size: usize,
+    ) -> Result<(), Error> {
+        // ring checks the length of the buffer
+        ring::aead::seal_in_place(
+            &self.sealing_key,
+            ring::aead::Nonce::assume_unique_for_key(nonce),
+            ring::aead::Aad::from(aad),
+            plaintext_and_extra,
+            tag_size,
+        )
+        .map(|_| ())
+        .map_err(|_| Error::EncryptionError("Unspecified"))
+    }
+}
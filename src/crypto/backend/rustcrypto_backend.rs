@@ -0,0 +1,204 @@
+//! The RustCrypto backend. This is built on the sha2, hmac, and aes-gcm crates.
+
+use super::HashAlgorithm;
+use crate::error::Error;
+
+use ::hmac::{Hmac, Mac, NewMac};
+use aes_gcm::aead::{AeadInPlace, NewAead};
+use digest::Digest;
+use sha2::{Sha256, Sha512};
+
+/// An incremental hash computation
+pub(crate) enum HashContext {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl HashContext {
+    pub(crate) fn new(alg: HashAlgorithm) -> HashContext {
+        match alg {
+            HashAlgorithm::Sha256 => HashContext::Sha256(Sha256::new()),
+            HashAlgorithm::Sha512 => HashContext::Sha512(Sha512::new()),
+        }
+    }
+
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        match self {
+            HashContext::Sha256(h) => h.update(bytes),
+            HashContext::Sha512(h) => h.update(bytes),
+        }
+    }
+
+    pub(crate) fn finish(self) -> Vec<u8> {
+        match self {
+            HashContext::Sha256(h) => h.finalize().to_vec(),
+            HashContext::Sha512(h) => h.finalize().to_vec(),
+        }
+    }
+}
+
+/// An incremental HMAC computation
+// These are short-lived and never stored in anything, so the size difference doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone)]
+pub(crate) enum HmacContext {
+    Sha256(Hmac<Sha256>),
+    Sha512(Hmac<Sha512>),
+}
+
+impl HmacContext {
+    pub(crate) fn new(alg: HashAlgorithm, key: &[u8]) -> HmacContext {
+        // HMAC takes keys of any length, so new_varkey can't fail
+        match alg {
+            HashAlgorithm::Sha256 => HmacContext::Sha256(Hmac::new_varkey(key).unwrap()),
+            HashAlgorithm::Sha512 => HmacContext::Sha512(Hmac::new_varkey(key).unwrap()),
+        }
+    }
+
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        match self {
+            HmacContext::Sha256(m) => m.update(bytes),
+            HmacContext::Sha512(m) => m.update(bytes),
+        }
+    }
+
+    pub(crate) fn finish(self) -> Vec<u8> {
+        match self {
+            HmacContext::Sha256(m) => m.finalize().into_bytes().to_vec(),
+            HmacContext::Sha512(m) => m.finalize().into_bytes().to_vec(),
+        }
+    }
+}
+
+/// Checks that `tag` is the HMAC of `msg` under `key`. This runs in constant time.
+///
+/// Returns: `Ok(())` on success. Otherwise returns an `Error::SignatureError`.
+pub(crate) fn hmac_verify(
+    alg: HashAlgorithm,
+    key: &[u8],
+    msg: &[u8],
+    tag: &[u8],
+) -> Result<(), Error> {
+    let mut ctx = HmacContext::new(alg, key);
+    ctx.update(msg);
+
+    // Mac::verify compares in constant time, and refuses tags of the wrong length
+    let res = match ctx {
+        HmacContext::Sha256(m) => m.verify(tag),
+        HmacContext::Sha512(m) => m.verify(tag),
+    };
+    res.map_err(|_| Error::SignatureError("MAC verification failed"))
+}
+
+/// An HKDF PRK that's been made into an HMAC key, so it can be expanded any number of times
+/// without setting up the key again. The setup costs about as much as hashing two blocks.
+pub(crate) struct HkdfPrk {
+    alg: HashAlgorithm,
+    keyed_ctx: HmacContext,
+}
+
+impl HkdfPrk {
+    pub(crate) fn new(alg: HashAlgorithm, prk: &[u8]) -> HkdfPrk {
+        HkdfPrk {
+            alg,
+            keyed_ctx: HmacContext::new(alg, prk),
+        }
+    }
+
+    /// Fills `out_buf` with `HKDF-Expand(prk, info, out_buf.len())`
+    ///
+    /// Panics: If `out_buf` is longer than 255 times the digest size of the hash algorithm
+    // This isn't the hkdf crate because that refuses PRKs shorter than a digest, where ring (and
+    // RFC 5869's definition of HMAC) doesn't. Prk::from_bytes lets callers pass in whatever they
+    // want, so both backends have to agree on those.
+    pub(crate) fn expand(&self, info: &[u8], out_buf: &mut [u8]) {
+        assert!(out_buf.len() <= 255 * self.alg.output_len());
+
+        // T(0) = empty string
+        // T(i) = HMAC-Hash(PRK, T(i-1) || info || i)
+        // OKM = first L octets of T(1) || T(2) || ...
+        let mut prev_block = Vec::new();
+        for (i, chunk) in out_buf.chunks_mut(self.alg.output_len()).enumerate() {
+            let mut ctx = self.keyed_ctx.clone();
+            ctx.update(&prev_block);
+            ctx.update(info);
+            ctx.update(&[(i + 1) as u8]);
+
+            crate::utils::zeroize(&mut prev_block);
+            prev_block = ctx.finish();
+            chunk.copy_from_slice(&prev_block[..chunk.len()]);
+        }
+        crate::utils::zeroize(&mut prev_block);
+    }
+}
+
+/// Fills `out_buf` with `HKDF-Expand(prk, info, out_buf.len())`
+///
+/// Panics: If `out_buf` is longer than 255 times the digest size of `alg`
+pub(crate) fn hkdf_expand(alg: HashAlgorithm, prk: &[u8], info: &[u8], out_buf: &mut [u8]) {
+    HkdfPrk::new(alg, prk).expand(info, out_buf);
+}
+
+/// An opening / sealing key for AES-128-GCM
+pub(crate) struct Aes128GcmKey(aes_gcm::Aes128Gcm);
+
+impl Aes128GcmKey {
+    /// Makes a key out of the given bytes
+    ///
+    /// Returns: `Ok(key)` on success. Returns an `Error::EncryptionError` if `key_bytes` is the
+    /// wrong length.
+    pub(crate) fn new(key_bytes: &[u8]) -> Result<Aes128GcmKey, Error> {
+        aes_gcm::Aes128Gcm::new_varkey(key_bytes)
+            .map(Aes128GcmKey)
+            .map_err(|_| Error::EncryptionError("Unspecified"))
+    }
+
+    /// Decrypts `ciphertext || tag` in place with the given associated data
+    ///
+    /// Returns: `Ok(plaintext)` on success, where `plaintext` is the front of the input buffer.
+    /// Otherwise returns an `Error::EncryptionError`.
+    pub(crate) fn open_in_place<'a>(
+        &self,
+        nonce: [u8; 12],
+        aad: &[u8],
+        ciphertext_and_tag: &'a mut [u8],
+    ) -> Result<&'a mut [u8], Error> {
+        let mut tag = [0u8; 16];
+        if ciphertext_and_tag.len() < tag.len() {
+            return Err(Error::EncryptionError("Unspecified"));
+        }
+        let ct_len = ciphertext_and_tag.len() - tag.len();
+        let (ciphertext, tag_bytes) = ciphertext_and_tag.split_at_mut(ct_len);
+        tag.copy_from_slice(tag_bytes);
+
+        self.0
+            .decrypt_in_place_detached(&nonce.into(), aad, ciphertext, &tag.into())
+            .map_err(|_| Error::EncryptionError("Unspecified"))?;
+        Ok(ciphertext)
+    }
+
+    /// Encrypts `plaintext || extra` in place with the given associated data, where `extra` is
+    /// `tag_size` bytes that get overwritten with the tag
+    ///
+    /// Returns: `Ok(())` on success. Otherwise returns an `Error::EncryptionError`.
+    pub(crate) fn seal_in_place(
+        &self,
+        nonce: [u8; 12],
+        aad: &[u8],
+        plaintext_and_extra: &mut [u8],
+        tag_size: usize,
+    ) -> Result<(), Error> {
+        if tag_size != 16 || plaintext_and_extra.len() < tag_size {
+            return Err(Error::EncryptionError("Unspecified"));
+        }
+        let pt_len = plaintext_and_extra.len() - tag_size;
+        let (plaintext, extra) = plaintext_and_extra.split_at_mut(pt_len);
+
+        let tag = self
+            .0
+            .encrypt_in_place_detached(&nonce.into(), aad, plaintext)
+            .map_err(|_| Error::EncryptionError("Unspecified"))?;
+        extra.copy_from_slice(&tag);
+        Ok(())
+    }
+}
@@ -50,14 +50,20 @@ impl PartialEq for CipherSuite {
 }
 
 impl CipherSuite {
-    /// Given an arbitrary number of bytes, derives a Diffie-Hellman keypair. For this ciphersuite,
-    /// the function is simply `scalar: [u8; 32] = SHA256(bytes)`.
+    /// Returns the name of this ciphersuite, as per the MLS spec
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Given an arbitrary number of bytes, derives a Diffie-Hellman keypair. The private key is the
+    /// hash of `bytes`, truncated to the size of a private key if the hash is longer. For
+    /// X25519_SHA256_AES128GCM, this is simply `scalar: [u8; 32] = SHA256(bytes)`.
     ///
     /// Requires: `bytes.len() == self.hash_impl.digest_size()`
     ///
     /// Returns: `Ok((pubkey, privkey))` on success. If the above condition is not met, returns an
-    /// `Error::ValidationError`. If something goes wrong in key derivation, returns an
-    /// `Error::CryptoError`.
+    /// `Error::ValidationError`. If the hash is shorter than a private key, or something else goes
+    /// wrong in key derivation, returns an `Error::DhError`.
     pub(crate) fn derive_key_pair(
         &self,
         bytes: &[u8],
@@ -69,9 +75,14 @@ impl CipherSuite {
         //    return Err(Error::ValidationError("Derive-Key-Pair input length != Hash.length"));
         //}
 
-        // Hash the input and use the digest as a private key
+        // Hash the input and use the digest as a private key. A suite can pair a long hash like
+        // SHA-512 with a short scalar, in which case only the front of the digest is used.
         let digest = self.hash_impl.hash_bytes(bytes);
-        let privkey = DhPrivateKey::new_from_bytes(self.dh_impl, digest.as_bytes())?;
+        let privkey_bytes = digest
+            .as_bytes()
+            .get(..self.dh_impl.private_key_size())
+            .ok_or(Error::DhError("Digest is shorter than a DH private key"))?;
+        let privkey = DhPrivateKey::new_from_bytes(self.dh_impl, privkey_bytes)?;
         // Derive the pubkey
         let pubkey = DhPublicKey::new_from_private_key(self.dh_impl, &privkey);
 
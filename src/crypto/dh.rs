@@ -1,4 +1,4 @@
-use crate::crypto::rng::CryptoRng;
+use crate::crypto::{pkcs8, rng::CryptoRng, secure_mem::SecretBox};
 use crate::error::Error;
 
 /// A type representing the X25519 DH scheme
@@ -14,8 +14,8 @@ const X25519_SCALAR_SIZE: usize = 32;
 /// exponent.
 #[derive(Clone)]
 pub(crate) enum DhPrivateKey {
-    /// A scalar value in Curve25519
-    X25519PrivateKey(x25519_dalek::StaticSecret),
+    /// A scalar value in Curve25519. This is boxed so that it has a fixed place in memory to lock.
+    X25519PrivateKey(SecretBox<x25519_dalek::StaticSecret>),
 }
 
 impl DhPrivateKey {
@@ -30,11 +30,13 @@ impl DhPrivateKey {
         scheme.0.private_key_from_bytes(bytes)
     }
 
-    // This just passes through to DhSchemeInterface::private_key_from_random
     /// Generates a random private key
     ///
     /// Returns: `Ok(private_key)` on success. Otherwise, if something goes wrong with the RNG, it
     /// returns `Error::OutOfEntropy`.
+    // The randomness is drawn here rather than in DhSchemeInterface, since a trait object can't
+    // have a generic method. This way, the RNG is never a trait object, which matters because this
+    // is called once per node when encrypting a direct path.
     pub(crate) fn new_from_random<R>(
         scheme: &DhScheme,
         csprng: &mut R,
@@ -42,7 +44,49 @@ impl DhPrivateKey {
     where
         R: CryptoRng,
     {
-        scheme.0.private_key_from_random(csprng)
+        let mut key_bytes = vec![0u8; scheme.0.private_key_size()];
+        let private_key = csprng
+            .try_fill_bytes(&mut key_bytes)
+            .map_err(|_| Error::OutOfEntropy)
+            .and_then(|_| scheme.0.private_key_from_bytes(&key_bytes));
+        crate::utils::zeroize(&mut key_bytes);
+
+        private_key
+    }
+
+    /// Makes a `DhPrivateKey` from a DER-encoded PKCS#8 `PrivateKeyInfo`, as described in RFC 8410
+    ///
+    /// Returns: `Ok(private_key)` on success. If the encoding is malformed, is for a different
+    /// algorithm, or holds a key of the wrong size, returns `Error::DhError`.
+    pub(crate) fn new_from_pkcs8(scheme: &DhScheme, der: &[u8]) -> Result<DhPrivateKey, Error> {
+        let bytes = pkcs8::decode_private_key(scheme.0.oid(), der).map_err(Error::DhError)?;
+        scheme.0.private_key_from_bytes(bytes)
+    }
+
+    /// Overwrites this private key with zeros. The key is useless afterwards, so this is only for
+    /// keys that are about to be dropped.
+    pub(crate) fn zeroize(&mut self) {
+        match self {
+            DhPrivateKey::X25519PrivateKey(s) => zeroize::Zeroize::zeroize(&mut **s),
+        }
+    }
+
+    /// Returns the raw bytes of this private key. This is the inverse of
+    /// `DhPrivateKey::new_from_bytes`. The caller is responsible for zeroing the returned bytes.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            DhPrivateKey::X25519PrivateKey(s) => s.to_bytes().to_vec(),
+        }
+    }
+
+    /// Returns the DER encoding of this private key as a PKCS#8 `PrivateKeyInfo`, as described in
+    /// RFC 8410. The caller is responsible for zeroing the returned bytes.
+    pub(crate) fn to_pkcs8(&self, scheme: &DhScheme) -> Vec<u8> {
+        let mut bytes = self.to_bytes();
+        let der = pkcs8::encode_private_key(scheme.0.oid(), &bytes);
+        crate::utils::zeroize(&mut bytes);
+
+        der
     }
 }
 
@@ -105,7 +149,7 @@ impl DhPublicKey {
     // This just passes through to DhSchemeInterface::public_key_from_bytes
     /// Makes a `DhPublicKey` from the given bytes
     ///
-    /// Requires: `bytes.len() == scheme.public_key_size()`
+    /// Requires: `bytes` is the size of one of the scheme's public keys
     ///
     /// Returns: `Ok(public_key)` on success. Otherwise, if the above requirement is not
     /// met,returns `Error::DhError`.
@@ -152,13 +196,21 @@ impl DhScheme {
     ) -> Result<DhSharedSecret, Error> {
         self.0.diffie_hellman(privkey, pubkey)
     }
+
+    // This just passes through to DhSchemeInterface::private_key_size
+    /// Returns the size of a private key, in bytes
+    pub(crate) fn private_key_size(&self) -> usize {
+        self.0.private_key_size()
+    }
 }
 
 /// A trait representing any DH-like key-agreement algorithm. The notation it uses in documentation
 /// is that of elliptic curves, but these concepts should generalize to finite-fields, SIDH, CSIDH,
 /// etc.
-trait DhSchemeInterface {
-    fn public_key_size(&self) -> usize;
+// This is Sync so that &'static DhSchemes, and everything holding them, are Send + Sync
+trait DhSchemeInterface: Sync {
+    // The object identifier that PKCS#8 uses for this algorithm
+    fn oid(&self) -> &'static [u8];
 
     fn private_key_size(&self) -> usize;
 
@@ -166,13 +218,10 @@ trait DhSchemeInterface {
 
     fn public_key_from_private_key(&self, scalar: &DhPrivateKey) -> DhPublicKey;
 
+    // Every uniformly random string of private_key_size() bytes must make a valid private key.
+    // This is what DhPrivateKey::new_from_random relies on.
     fn private_key_from_bytes(&self, bytes: &[u8]) -> Result<DhPrivateKey, Error>;
 
-    // This has to take a dyn CryptoRng because DiffieHellman is itself a trait object inside a
-    // CipherSuite. Trait objects can't have associated types, associated constants, or generic
-    // methods.
-    fn private_key_from_random(&self, csprng: &mut dyn CryptoRng) -> Result<DhPrivateKey, Error>;
-
     fn diffie_hellman(
         &self,
         privkey: &DhPrivateKey,
@@ -185,9 +234,9 @@ trait DhSchemeInterface {
 pub(crate) struct X25519;
 
 impl DhSchemeInterface for X25519 {
-    /// Returns the size of a point
-    fn public_key_size(&self) -> usize {
-        X25519_POINT_SIZE
+    /// Returns the object identifier of X25519, as per RFC 8410
+    fn oid(&self) -> &'static [u8] {
+        pkcs8::OID_X25519
     }
 
     /// Returns the size of a scalar
@@ -219,7 +268,7 @@ impl DhSchemeInterface for X25519 {
     /// for creating public keys for DHE.
     fn public_key_from_private_key(&self, scalar: &DhPrivateKey) -> DhPublicKey {
         let scalar = enum_variant!(scalar, DhPrivateKey::X25519PrivateKey);
-        let public_key: x25519_dalek::PublicKey = scalar.into();
+        let public_key = x25519_dalek::PublicKey::from(&**scalar);
         DhPublicKey::X25519PublicKey(public_key)
     }
 
@@ -235,19 +284,12 @@ impl DhSchemeInterface for X25519 {
         } else {
             let mut buf = [0u8; X25519_SCALAR_SIZE];
             buf.copy_from_slice(bytes);
-            Ok(DhPrivateKey::X25519PrivateKey(buf.into()))
+            let scalar = SecretBox::new(buf.into());
+            crate::utils::zeroize(&mut buf);
+            Ok(DhPrivateKey::X25519PrivateKey(scalar))
         }
     }
 
-    /// Generates a random private key
-    ///
-    /// Returns: `Ok(private_key)` on success. Otherwise, if something goes wrong with the RNG, it
-    /// returns `Error::OutOfEntropy`.
-    fn private_key_from_random(&self, csprng: &mut dyn CryptoRng) -> Result<DhPrivateKey, Error> {
-        let mut box_rng = Box::new(csprng);
-        Ok(DhPrivateKey::X25519PrivateKey(x25519_dalek::StaticSecret::new(&mut box_rng)))
-    }
-
     /// Computes `privkey * Pubkey` where `privkey` is your local secret (a scalar) and `Pubkey` is
     /// someone's public key (a curve point)
     ///
@@ -261,7 +303,7 @@ impl DhSchemeInterface for X25519 {
         let privkey = enum_variant!(privkey, DhPrivateKey::X25519PrivateKey);
         let pubkey = enum_variant!(pubkey, DhPublicKey::X25519PublicKey);
 
-        let ss = privkey.diffie_hellman(&pubkey);
+        let ss = privkey.diffie_hellman(pubkey);
 
         // Make sure we don't get all zeros
         if ss.as_bytes() == &[0u8; 32] {
@@ -276,8 +318,8 @@ impl DhSchemeInterface for X25519 {
 pub(crate) struct DummyP256;
 
 impl DhSchemeInterface for DummyP256 {
-    fn public_key_size(&self) -> usize {
-        65
+    fn oid(&self) -> &'static [u8] {
+        unimplemented!()
     }
 
     fn private_key_size(&self) -> usize {
@@ -301,13 +343,6 @@ impl DhSchemeInterface for DummyP256 {
         unimplemented!()
     }
 
-    // This has to take a dyn CryptoRng because DhSchemeInterface is used as a trait object inside
-    // DhScheme. Trait objects can't have associated types, associated constants, or generic
-    // methods.
-    fn private_key_from_random(&self, _csprng: &mut dyn CryptoRng) -> Result<DhPrivateKey, Error> {
-        unimplemented!()
-    }
-
     fn diffie_hellman(
         &self,
         _privkey: &DhPrivateKey,
@@ -399,6 +434,18 @@ mod test {
         assert_eq!(shared1.as_bytes(), shared2.as_bytes());
     }
 
+    // Checks that a private key survives a trip through PKCS#8
+    #[quickcheck]
+    fn x25519_pkcs8_round_trip(rng_seed: u64) {
+        let scheme: &'static DhScheme = &X25519_IMPL;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+
+        let scalar = DhPrivateKey::new_from_random(scheme, &mut rng).unwrap();
+        let imported_scalar =
+            DhPrivateKey::new_from_pkcs8(scheme, &scalar.to_pkcs8(scheme)).unwrap();
+        assert_eq!(imported_scalar.to_bytes(), scalar.to_bytes());
+    }
+
     // This comes from
     // https://github.com/mlswg/mls-implementations/blob/master/test_vectors/treesnodes.md
     #[test]
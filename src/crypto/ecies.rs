@@ -1,3 +1,7 @@
+//! Defines ECIES encryption to a DH public key. Most of this is internal. The public part,
+//! `EciesStreamSealer` and `EciesStreamOpener`, is the chunked construction that large `Welcome`s
+//! use, for applications that want to encrypt big payloads of their own the same way.
+
 use crate::crypto::{
     aead::{AeadKey, AeadNonce},
     ciphersuite::CipherSuite,
@@ -35,7 +39,7 @@ pub(crate) struct EciesCiphertext {
     /// The payload
     // opaque ciphertext<0..2^32-1>;
     #[serde(rename = "ciphertext__bound_u32")]
-    ciphertext: Vec<u8>,
+    pub(crate) ciphertext: Vec<u8>,
 }
 
 /// Performs an ECIES encryption of a given plaintext under a given DH public key and a randomly
@@ -69,28 +73,18 @@ where
 pub(crate) fn encrypt_with_scalar(
     cs: &CipherSuite,
     others_public_key: &DhPublicKey,
-    mut plaintext: Vec<u8>,
+    plaintext: Vec<u8>,
     my_ephemeral_secret: DhPrivateKey,
 ) -> Result<EciesCiphertext, Error> {
-    // Make room for the tag and fill it with zeros
-    let tagged_plaintext_size = plaintext
-        .len()
-        .checked_add(cs.aead_impl.tag_size())
-        .expect("plaintext is too large to be encrypted");
-    plaintext.resize(tagged_plaintext_size, 0u8);
-
     // If my_ephermeral_secret is `a`, let this be `aP`
     let my_ephemeral_public_key =
         DhPublicKey::new_from_private_key(cs.dh_impl, &my_ephemeral_secret);
 
     // This is `abP` where `bP` is the other person's public key is `bP`
-    let shared_secret = cs.dh_impl.diffie_hellman(&my_ephemeral_secret, &others_public_key)?;
+    let shared_secret = cs.dh_impl.diffie_hellman(&my_ephemeral_secret, others_public_key)?;
 
     let (key, nonce) = derive_ecies_key_nonce(cs, shared_secret.as_bytes());
-
-    cs.aead_impl.seal(&key, nonce, plaintext.as_mut_slice())?;
-    // Rename for clarity
-    let ciphertext = plaintext;
+    let ciphertext = seal(cs, &key, nonce, plaintext)?;
 
     let ret = EciesCiphertext {
         ephemeral_public_key: my_ephemeral_public_key,
@@ -111,16 +105,147 @@ pub(crate) fn decrypt(
 ) -> Result<Vec<u8>, Error> {
     let EciesCiphertext {
         ephemeral_public_key,
-        mut ciphertext,
+        ciphertext,
     } = ciphertext;
     // This is `abP` where `bP` is the other person's public key is `bP` and my secret key is `a`
-    let shared_secret = cs.dh_impl.diffie_hellman(&my_secret_key, &ephemeral_public_key)?;
+    let shared_secret = cs.dh_impl.diffie_hellman(my_secret_key, &ephemeral_public_key)?;
 
-    // Derive the key and nonce, then open the ciphertext. The length of the subslice it gives is
-    // the length we'll truncate the plaintext to. Recall this happens because there was a MAC at
-    // the end of the ciphertext.
     let (key, nonce) = derive_ecies_key_nonce(cs, shared_secret.as_bytes());
-    let plaintext_len = cs.aead_impl.open(&key, nonce, ciphertext.as_mut_slice())?.len();
+    open(cs, &key, nonce, ciphertext)
+}
+
+// Draft 4 doesn't encrypt this way. Groups opt into it for their DirectPathMessages with the
+// PathEncryption extension.
+/// Performs an authenticated ECIES encryption of a given plaintext under a given DH public key. This
+/// is like `encrypt`, except the sender's static DH key is mixed into the key derivation too, like
+/// in the "Auth" mode of HPKE. So whoever decrypts it with `decrypt_authenticated` knows it was
+/// made by someone who holds `my_static_secret`.
+///
+/// Returns: `Ok(ciphertext)` on success. If there is an issue with random scalar generation or
+/// sealing the plaintext, an `Error` is returned.
+pub(crate) fn encrypt_authenticated<R>(
+    cs: &CipherSuite,
+    others_public_key: &DhPublicKey,
+    my_static_secret: &DhPrivateKey,
+    plaintext: Vec<u8>,
+    csprng: &mut R,
+) -> Result<EciesCiphertext, Error>
+where
+    R: CryptoRng,
+{
+    let my_ephemeral_secret = DhPrivateKey::new_from_random(cs.dh_impl, csprng)?;
+    encrypt_authenticated_with_scalar(
+        cs,
+        others_public_key,
+        my_static_secret,
+        plaintext,
+        my_ephemeral_secret,
+    )
+}
+
+/// Performs an authenticated ECIES encryption of a given plaintext under a given DH public key and
+/// a fixed ephemeral scalar. This is the deterministic function underlying
+/// `encrypt_authenticated`.
+///
+/// Returns: `Ok(ciphertext)` on success. If there is an issue with sealing the plaintext, an
+/// `Error::EncryptionError` is returned. If there is an issue with deriving DH keys, an
+/// `Error::DhError` is returned.
+pub(crate) fn encrypt_authenticated_with_scalar(
+    cs: &CipherSuite,
+    others_public_key: &DhPublicKey,
+    my_static_secret: &DhPrivateKey,
+    plaintext: Vec<u8>,
+    my_ephemeral_secret: DhPrivateKey,
+) -> Result<EciesCiphertext, Error> {
+    let my_ephemeral_public_key =
+        DhPublicKey::new_from_private_key(cs.dh_impl, &my_ephemeral_secret);
+    let my_static_public_key = DhPublicKey::new_from_private_key(cs.dh_impl, my_static_secret);
+
+    // One shared secret with the ephemeral key, like in unauthenticated ECIES, and one with the
+    // static key, which only the holder of the static key can compute
+    let ephemeral_shared_secret =
+        cs.dh_impl.diffie_hellman(&my_ephemeral_secret, others_public_key)?;
+    let static_shared_secret = cs.dh_impl.diffie_hellman(my_static_secret, others_public_key)?;
+
+    let (key, nonce) = derive_authenticated_key_nonce(
+        cs,
+        ephemeral_shared_secret.as_bytes(),
+        static_shared_secret.as_bytes(),
+        &my_ephemeral_public_key,
+        others_public_key,
+        &my_static_public_key,
+    );
+    let ciphertext = seal(cs, &key, nonce, plaintext)?;
+
+    Ok(EciesCiphertext {
+        ephemeral_public_key: my_ephemeral_public_key,
+        ciphertext,
+    })
+}
+
+/// Performs an authenticated ECIES decryption of a ciphertext made by `encrypt_authenticated`,
+/// where `senders_public_key` is the static DH public key of whoever we think sent it
+///
+/// Returns: `Ok(plaintext)` on success. Returns an `Error::EncryptionError` if something goes
+/// wrong, including if the ciphertext wasn't made by the holder of `senders_public_key`.
+pub(crate) fn decrypt_authenticated(
+    cs: &CipherSuite,
+    my_secret_key: &DhPrivateKey,
+    senders_public_key: &DhPublicKey,
+    ciphertext: EciesCiphertext,
+) -> Result<Vec<u8>, Error> {
+    let EciesCiphertext {
+        ephemeral_public_key,
+        ciphertext,
+    } = ciphertext;
+    let my_public_key = DhPublicKey::new_from_private_key(cs.dh_impl, my_secret_key);
+
+    let ephemeral_shared_secret =
+        cs.dh_impl.diffie_hellman(my_secret_key, &ephemeral_public_key)?;
+    let static_shared_secret = cs.dh_impl.diffie_hellman(my_secret_key, senders_public_key)?;
+
+    let (key, nonce) = derive_authenticated_key_nonce(
+        cs,
+        ephemeral_shared_secret.as_bytes(),
+        static_shared_secret.as_bytes(),
+        &ephemeral_public_key,
+        &my_public_key,
+        senders_public_key,
+    );
+    open(cs, &key, nonce, ciphertext)
+}
+
+// Appends a zeroed tag to the plaintext and seals it in place
+fn seal(
+    cs: &CipherSuite,
+    key: &AeadKey,
+    nonce: AeadNonce,
+    mut plaintext: Vec<u8>,
+) -> Result<Vec<u8>, Error> {
+    // Make room for the tag and fill it with zeros
+    let tagged_plaintext_size = plaintext
+        .len()
+        .checked_add(cs.aead_impl.tag_size())
+        .expect("plaintext is too large to be encrypted");
+    plaintext.resize(tagged_plaintext_size, 0u8);
+
+    cs.aead_impl.seal(key, nonce, b"", plaintext.as_mut_slice())?;
+    // Rename for clarity
+    let ciphertext = plaintext;
+
+    Ok(ciphertext)
+}
+
+// Opens the ciphertext in place and cuts off the tag
+fn open(
+    cs: &CipherSuite,
+    key: &AeadKey,
+    nonce: AeadNonce,
+    mut ciphertext: Vec<u8>,
+) -> Result<Vec<u8>, Error> {
+    // The length of the subslice open() gives is the length we'll truncate the plaintext to.
+    // Recall this happens because there was a MAC at the end of the ciphertext.
+    let plaintext_len = cs.aead_impl.open(key, nonce, b"", ciphertext.as_mut_slice())?.len();
 
     // Rename for clarity
     let mut plaintext = ciphertext;
@@ -144,28 +269,289 @@ pub(crate) fn decrypt(
 // I think that the Length specified above is supposed to be different for keys and nonces, since
 // it wouldn't make sense otherwise, so I've done that and hope I'm right.
 fn derive_ecies_key_nonce(cs: &CipherSuite, shared_secret_bytes: &[u8]) -> (AeadKey, AeadNonce) {
-    let key_label = EciesLabel::new(b"key", cs.aead_impl.key_size() as u16);
-    let nonce_label = EciesLabel::new(b"nonce", cs.aead_impl.nonce_size() as u16);
-
     // This is the keying information that we will expand
-    let prk = HmacKey::new_from_bytes(&shared_secret_bytes);
+    let prk = HmacKey::new_from_bytes(shared_secret_bytes);
+    expand_key_nonce(cs, &prk, b"key", b"nonce")
+}
+
+/// Derives the key and nonce for authenticated ECIES. Like in HPKE's "Auth" mode, both shared
+/// secrets are extracted together with all three public keys, so the result is bound to who sent
+/// it and who it's for:
+/// ```ignore
+/// prk = HKDF-Extract("", ephemeral_dh || static_dh || ephemeral_pk || recipient_pk || sender_pk)
+/// key = HKDF-Expand(prk, ECIESLabel("auth key"), Length)
+/// nonce = HKDF-Expand(prk, ECIESLabel("auth nonce"), Length)
+/// ```
+/// The labels differ from the unauthenticated ones so that the two modes never share keys.
+fn derive_authenticated_key_nonce(
+    cs: &CipherSuite,
+    ephemeral_shared_secret_bytes: &[u8],
+    static_shared_secret_bytes: &[u8],
+    ephemeral_public_key: &DhPublicKey,
+    recipient_public_key: &DhPublicKey,
+    sender_public_key: &DhPublicKey,
+) -> (AeadKey, AeadNonce) {
+    // The public keys are all fixed-size for a given ciphersuite, so plain concatenation is
+    // unambiguous
+    let ikm = [
+        ephemeral_shared_secret_bytes,
+        static_shared_secret_bytes,
+        ephemeral_public_key.as_bytes(),
+        recipient_public_key.as_bytes(),
+        sender_public_key.as_bytes(),
+    ]
+    .concat();
+    let prk = hkdf::extract(cs.hash_impl, &HmacKey::new_from_bytes(&[]), &ikm);
+    expand_key_nonce(cs, &prk, b"auth key", b"auth nonce")
+}
+
+// Expands the given PRK into an AEAD key and nonce, using the given labels
+fn expand_key_nonce(
+    cs: &CipherSuite,
+    prk: &HmacKey,
+    key_label: &[u8],
+    nonce_label: &[u8],
+) -> (AeadKey, AeadNonce) {
+    let (key, nonce_bytes) = expand_key_nonce_bytes(cs, prk, key_label, nonce_label);
+    let nonce = AeadNonce::new_from_bytes(cs.aead_impl, &nonce_bytes)
+        .expect("couldn't derive AEAD nonce from HKDF");
+
+    (key, nonce)
+}
+
+// Same as expand_key_nonce, but leaves the nonce as bytes, so that it can be used as a base nonce
+fn expand_key_nonce_bytes(
+    cs: &CipherSuite,
+    prk: &HmacKey,
+    key_label: &[u8],
+    nonce_label: &[u8],
+) -> (AeadKey, Vec<u8>) {
+    let key_label = EciesLabel::new(key_label, cs.aead_impl.key_size() as u16);
+    let nonce_label = EciesLabel::new(nonce_label, cs.aead_impl.nonce_size() as u16);
 
     let mut key_buf = vec![0u8; cs.aead_impl.key_size()];
     let mut nonce_buf = vec![0u8; cs.aead_impl.nonce_size()];
 
     // We're gonna used the serialized labels as the `info` parameter to HKDF-Expand. The only way
     // this call fails is because of an `HkdfLabel` serialization error. This can't happen because
-    // the only possible error is if EciesLabel::label is oversized, but it is fixed as b"key" or
-    // b"nonce" above.
-    hkdf::expand(cs.hash_impl, &prk, &key_label, &mut key_buf[..]).unwrap();
-    hkdf::expand(cs.hash_impl, &prk, &nonce_label, &mut nonce_buf[..]).unwrap();
+    // the only possible error is if EciesLabel::label is oversized, but the callers only pass
+    // short fixed labels.
+    hkdf::expand(cs.hash_impl, prk, &key_label, &mut key_buf[..]).unwrap();
+    hkdf::expand(cs.hash_impl, prk, &nonce_label, &mut nonce_buf[..]).unwrap();
 
     let key = AeadKey::new_from_bytes(cs.aead_impl, &key_buf)
         .expect("couldn't derive AEAD key from HKDF");
-    let nonce = AeadNonce::new_from_bytes(cs.aead_impl, &nonce_buf)
-        .expect("couldn't derive AEAD nonce from HKDF");
 
-    (key, nonce)
+    (key, nonce_buf)
+}
+
+//
+// Streaming ECIES
+//
+
+// Draft 4 doesn't encrypt this way either. It's for payloads too big to encrypt in one piece, e.g.,
+// the WelcomeInfo of a huge group, which is why Welcomes over WELCOME_STREAMING_THRESHOLD use it.
+// The construction is STREAM from Hoang, Reyhanitabar, Rogaway, and Vizár's "Online
+// Authenticated-Encryption and its Nonce-Reuse Misuse-Resistance": the key and a base nonce come
+// from the DH shared secret, and every chunk's nonce is the base nonce XORed with the chunk's
+// counter and a flag saying whether it's the last chunk. So chunks can't be reordered, and the
+// stream can't be cut short without the recipient noticing.
+
+/// Encrypts a stream of chunks to a single recipient. Make one with
+/// `EciesStreamSealer::new_from_public_key_bytes` and call `seal_chunk` on each chunk in order,
+/// saying which one is the last.
+pub struct EciesStreamSealer {
+    cs: &'static CipherSuite,
+    key: AeadKey,
+    base_nonce: Vec<u8>,
+    counter: u32,
+    finished: bool,
+}
+
+impl EciesStreamSealer {
+    /// Makes a new sealer that encrypts to `others_public_key` with a random ephemeral key
+    ///
+    /// Returns: `Ok((ephemeral_public_key, sealer))` on success, where `ephemeral_public_key` must
+    /// be sent to the recipient along with the chunks. If there is an issue with random scalar
+    /// generation or deriving DH keys, an `Error` is returned.
+    pub(crate) fn new<R>(
+        cs: &'static CipherSuite,
+        others_public_key: &DhPublicKey,
+        csprng: &mut R,
+    ) -> Result<(DhPublicKey, EciesStreamSealer), Error>
+    where
+        R: CryptoRng,
+    {
+        let my_ephemeral_secret = DhPrivateKey::new_from_random(cs.dh_impl, csprng)?;
+        let my_ephemeral_public_key =
+            DhPublicKey::new_from_private_key(cs.dh_impl, &my_ephemeral_secret);
+        let shared_secret = cs.dh_impl.diffie_hellman(&my_ephemeral_secret, others_public_key)?;
+        let (key, base_nonce) = derive_stream_key_nonce(cs, shared_secret.as_bytes());
+
+        let sealer = EciesStreamSealer {
+            cs,
+            key,
+            base_nonce,
+            counter: 0,
+            finished: false,
+        };
+        Ok((my_ephemeral_public_key, sealer))
+    }
+
+    /// Makes a new sealer that encrypts to the DH public key with the given encoding, in the DH
+    /// scheme of `cs`, with a random ephemeral key
+    ///
+    /// Returns: `Ok((ephemeral_public_key, sealer))` on success, where `ephemeral_public_key` is
+    /// the encoded ephemeral public key, which must be sent to the recipient along with the
+    /// chunks. Returns an `Error::DhError` if `others_public_key` isn't a valid public key.
+    /// Otherwise returns whatever `EciesStreamSealer::new` returns.
+    pub fn new_from_public_key_bytes<R>(
+        cs: &'static CipherSuite,
+        others_public_key: &[u8],
+        csprng: &mut R,
+    ) -> Result<(Vec<u8>, EciesStreamSealer), Error>
+    where
+        R: CryptoRng,
+    {
+        let others_public_key = DhPublicKey::new_from_bytes(cs.dh_impl, others_public_key)?;
+        let (ephemeral_public_key, sealer) =
+            EciesStreamSealer::new(cs, &others_public_key, csprng)?;
+        Ok((ephemeral_public_key.as_bytes().to_vec(), sealer))
+    }
+
+    /// Encrypts the next chunk of the stream. `is_last` MUST be set on the last chunk and only on
+    /// the last chunk. Only this chunk and its ciphertext are in memory at a time.
+    ///
+    /// Returns: `Ok(ciphertext)` on success. Returns an `Error::EncryptionError` if the last
+    /// chunk has already been sealed or there have been 2^32 chunks, or if sealing fails.
+    pub fn seal_chunk(&mut self, chunk: &[u8], is_last: bool) -> Result<Vec<u8>, Error> {
+        if self.finished {
+            return Err(Error::EncryptionError("Stream has already been finished"));
+        }
+        let nonce = stream_chunk_nonce(self.cs, &self.base_nonce, self.counter, is_last)?;
+        let ciphertext = seal(self.cs, &self.key, nonce, chunk.to_vec())?;
+
+        advance_stream(&mut self.counter, &mut self.finished, is_last)?;
+        Ok(ciphertext)
+    }
+}
+
+/// Decrypts a stream of chunks made by an `EciesStreamSealer`. Make one with
+/// `EciesStreamOpener::new_from_private_key_bytes`, call `open_chunk` on each chunk in order, and
+/// call `finish` at the end to make sure the stream wasn't cut short.
+pub struct EciesStreamOpener {
+    cs: &'static CipherSuite,
+    key: AeadKey,
+    base_nonce: Vec<u8>,
+    counter: u32,
+    finished: bool,
+}
+
+impl EciesStreamOpener {
+    /// Makes a new opener for a stream encrypted to `my_secret_key` under the given ephemeral
+    /// public key
+    ///
+    /// Returns: `Ok(opener)` on success. If there is an issue with deriving DH keys, an
+    /// `Error::DhError` is returned.
+    pub(crate) fn new(
+        cs: &'static CipherSuite,
+        my_secret_key: &DhPrivateKey,
+        ephemeral_public_key: &DhPublicKey,
+    ) -> Result<EciesStreamOpener, Error> {
+        let shared_secret = cs.dh_impl.diffie_hellman(my_secret_key, ephemeral_public_key)?;
+        let (key, base_nonce) = derive_stream_key_nonce(cs, shared_secret.as_bytes());
+
+        Ok(EciesStreamOpener {
+            cs,
+            key,
+            base_nonce,
+            counter: 0,
+            finished: false,
+        })
+    }
+
+    /// Makes a new opener for a stream encrypted to the DH private key with the given encoding,
+    /// in the DH scheme of `cs`, under the given encoded ephemeral public key
+    ///
+    /// Returns: `Ok(opener)` on success. Returns an `Error::DhError` if either key is invalid.
+    pub fn new_from_private_key_bytes(
+        cs: &'static CipherSuite,
+        my_secret_key: &[u8],
+        ephemeral_public_key: &[u8],
+    ) -> Result<EciesStreamOpener, Error> {
+        let my_secret_key = DhPrivateKey::new_from_bytes(cs.dh_impl, my_secret_key)?;
+        let ephemeral_public_key = DhPublicKey::new_from_bytes(cs.dh_impl, ephemeral_public_key)?;
+        EciesStreamOpener::new(cs, &my_secret_key, &ephemeral_public_key)
+    }
+
+    /// Decrypts the next chunk of the stream. `is_last` says whether the caller thinks this is
+    /// the last chunk. If it's wrong, decryption fails.
+    ///
+    /// Returns: `Ok(plaintext)` on success. Returns an `Error::EncryptionError` if the last chunk
+    /// has already been opened, if the chunk is out of order or its last-chunk flag is wrong, or
+    /// if decryption fails for any other reason.
+    pub fn open_chunk(&mut self, ciphertext: &[u8], is_last: bool) -> Result<Vec<u8>, Error> {
+        if self.finished {
+            return Err(Error::EncryptionError("Stream has already been finished"));
+        }
+        let nonce = stream_chunk_nonce(self.cs, &self.base_nonce, self.counter, is_last)?;
+        let plaintext = open(self.cs, &self.key, nonce, ciphertext.to_vec())?;
+
+        advance_stream(&mut self.counter, &mut self.finished, is_last)?;
+        Ok(plaintext)
+    }
+
+    /// Checks that the whole stream was opened, i.e., that the last chunk was seen. The chunks
+    /// opened so far MUST NOT be trusted to be the whole plaintext until this succeeds.
+    ///
+    /// Returns: `Ok(())` if the last chunk was opened. Otherwise returns an
+    /// `Error::EncryptionError`.
+    pub fn finish(self) -> Result<(), Error> {
+        if self.finished {
+            Ok(())
+        } else {
+            Err(Error::EncryptionError("Stream was truncated"))
+        }
+    }
+}
+
+// Derives the key and base nonce of a stream. The labels differ from the one-shot ones so that the
+// modes never share keys.
+fn derive_stream_key_nonce(cs: &CipherSuite, shared_secret_bytes: &[u8]) -> (AeadKey, Vec<u8>) {
+    let prk = HmacKey::new_from_bytes(shared_secret_bytes);
+    expand_key_nonce_bytes(cs, &prk, b"stream key", b"stream nonce")
+}
+
+// Computes the nonce of a chunk. The last 5 bytes of the base nonce are XORed with the big-endian
+// counter followed by a byte that's 1 for the last chunk and 0 otherwise.
+fn stream_chunk_nonce(
+    cs: &CipherSuite,
+    base_nonce: &[u8],
+    counter: u32,
+    is_last: bool,
+) -> Result<AeadNonce, Error> {
+    let suffix = [&counter.to_be_bytes()[..], &[is_last as u8]].concat();
+    let mut nonce_bytes = base_nonce.to_vec();
+    let offset = nonce_bytes
+        .len()
+        .checked_sub(suffix.len())
+        .ok_or(Error::EncryptionError("AEAD nonce is too short for streaming"))?;
+    for (b, s) in nonce_bytes[offset..].iter_mut().zip(suffix.iter()) {
+        *b ^= s;
+    }
+
+    AeadNonce::new_from_bytes(cs.aead_impl, &nonce_bytes)
+}
+
+// Moves a stream along after a chunk has been sealed or opened
+fn advance_stream(counter: &mut u32, finished: &mut bool, is_last: bool) -> Result<(), Error> {
+    if is_last {
+        *finished = true;
+    } else {
+        *counter =
+            counter.checked_add(1).ok_or(Error::EncryptionError("Stream has too many chunks"))?;
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -173,7 +559,7 @@ mod test {
     use crate::crypto::{
         ciphersuite::{CipherSuite, X25519_SHA256_AES128GCM},
         dh::{DhPrivateKey, DhPublicKey},
-        ecies::{self, EciesCiphertext},
+        ecies::{self, EciesCiphertext, EciesStreamOpener, EciesStreamSealer},
     };
 
     use quickcheck_macros::quickcheck;
@@ -193,15 +579,142 @@ mod test {
 
             // Now encrypt to Alice
             let ecies_ciphertext: EciesCiphertext =
-                ecies::encrypt(cs, &alice_point, plaintext.clone(), &mut rng)
-                    .expect(&format!("failed to encrypt ECIES plaintext; ciphersuite {}", cs.name));
+                ecies::encrypt(cs, &alice_point, plaintext.clone(), &mut rng).unwrap_or_else(
+                    |_| panic!("failed to encrypt ECIES plaintext; ciphersuite {}", cs.name),
+                );
 
             // Now let Alice decrypt it
             let recovered_plaintext = ecies::decrypt(cs, &alice_scalar, ecies_ciphertext)
-                .expect(&format!("failed to decrypt ECIES ciphertext; ciphersuite {}", cs.name))
+                .unwrap_or_else(|_| {
+                    panic!("failed to decrypt ECIES ciphertext; ciphersuite {}", cs.name)
+                })
                 .to_vec();
 
             assert_eq!(recovered_plaintext, plaintext);
         }
     }
+
+    // Checks that decrypt_authenticated(encrypt_authenticated(m)) == m, and that it fails if the
+    // recipient expects a different sender or the ciphertext is decrypted without authentication
+    #[quickcheck]
+    fn authenticated_ecies_correctness(plaintext: Vec<u8>, rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+
+        for cs in CIPHERSUITES {
+            let alice_scalar = DhPrivateKey::new_from_random(cs.dh_impl, &mut rng).unwrap();
+            let alice_point = DhPublicKey::new_from_private_key(cs.dh_impl, &alice_scalar);
+            let bob_scalar = DhPrivateKey::new_from_random(cs.dh_impl, &mut rng).unwrap();
+            let bob_point = DhPublicKey::new_from_private_key(cs.dh_impl, &bob_scalar);
+            let mallory_scalar = DhPrivateKey::new_from_random(cs.dh_impl, &mut rng).unwrap();
+            let mallory_point = DhPublicKey::new_from_private_key(cs.dh_impl, &mallory_scalar);
+
+            // Bob encrypts to Alice
+            let ciphertext = ecies::encrypt_authenticated(
+                cs,
+                &alice_point,
+                &bob_scalar,
+                plaintext.clone(),
+                &mut rng,
+            )
+            .unwrap();
+
+            // Alice can decrypt it if she expects it from Bob
+            let recovered_plaintext =
+                ecies::decrypt_authenticated(cs, &alice_scalar, &bob_point, ciphertext.clone())
+                    .unwrap();
+            assert_eq!(recovered_plaintext, plaintext);
+
+            // But not if she expects it from Mallory, or doesn't authenticate it at all
+            assert!(ecies::decrypt_authenticated(
+                cs,
+                &alice_scalar,
+                &mallory_point,
+                ciphertext.clone()
+            )
+            .is_err());
+            assert!(ecies::decrypt(cs, &alice_scalar, ciphertext).is_err());
+
+            // And unauthenticated ciphertexts don't pass as authenticated ones
+            let unauthenticated_ciphertext =
+                ecies::encrypt(cs, &alice_point, plaintext.clone(), &mut rng).unwrap();
+            assert!(ecies::decrypt_authenticated(
+                cs,
+                &alice_scalar,
+                &bob_point,
+                unauthenticated_ciphertext
+            )
+            .is_err());
+        }
+    }
+
+    // Checks that a stream opens to the chunks it was sealed from, and that reordered, truncated,
+    // and extended streams are caught
+    #[quickcheck]
+    fn stream_correctness(chunks: Vec<Vec<u8>>, rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        // A stream always has at least one chunk
+        let mut chunks = chunks;
+        if chunks.is_empty() {
+            chunks.push(Vec::new());
+        }
+        let num_chunks = chunks.len();
+
+        let cs = &X25519_SHA256_AES128GCM;
+        let alice_scalar = DhPrivateKey::new_from_random(cs.dh_impl, &mut rng).unwrap();
+        let alice_point = DhPublicKey::new_from_private_key(cs.dh_impl, &alice_scalar);
+
+        let (ephemeral_public_key, mut sealer) =
+            EciesStreamSealer::new(cs, &alice_point, &mut rng).unwrap();
+        let ciphertexts: Vec<Vec<u8>> = chunks
+            .iter()
+            .enumerate()
+            .map(|(i, chunk)| sealer.seal_chunk(chunk, i == num_chunks - 1).unwrap())
+            .collect();
+        // Nothing can be sealed after the last chunk
+        assert!(sealer.seal_chunk(b"more", false).is_err());
+
+        // Alice gets everything back
+        let mut opener = EciesStreamOpener::new(cs, &alice_scalar, &ephemeral_public_key).unwrap();
+        for (i, (ciphertext, chunk)) in ciphertexts.iter().zip(chunks.iter()).enumerate() {
+            let plaintext = opener.open_chunk(ciphertext, i == num_chunks - 1).unwrap();
+            assert_eq!(&plaintext, chunk);
+        }
+        opener.finish().unwrap();
+
+        // Cutting off the last chunk means the one before it doesn't open as the last one, and the
+        // stream doesn't finish without it
+        let mut opener = EciesStreamOpener::new(cs, &alice_scalar, &ephemeral_public_key).unwrap();
+        for ciphertext in ciphertexts[..num_chunks - 1].iter() {
+            opener.open_chunk(ciphertext, false).unwrap();
+        }
+        if num_chunks > 1 {
+            let mut opener =
+                EciesStreamOpener::new(cs, &alice_scalar, &ephemeral_public_key).unwrap();
+            assert!(opener.open_chunk(&ciphertexts[0], true).is_err());
+        }
+        assert!(opener.finish().is_err());
+
+        // Chunks can't be swapped
+        if num_chunks > 1 {
+            let mut opener =
+                EciesStreamOpener::new(cs, &alice_scalar, &ephemeral_public_key).unwrap();
+            assert!(opener.open_chunk(&ciphertexts[1], num_chunks == 2).is_err());
+        }
+
+        // The constructors that take encoded keys, which are what applications use, make the same
+        // streams
+        let (ephemeral_public_key, mut sealer) =
+            EciesStreamSealer::new_from_public_key_bytes(cs, alice_point.as_bytes(), &mut rng)
+                .unwrap();
+        let ciphertext = sealer.seal_chunk(&chunks[0], true).unwrap();
+        let alice_scalar_bytes = alice_scalar.to_bytes();
+        let mut opener = EciesStreamOpener::new_from_private_key_bytes(
+            cs,
+            &alice_scalar_bytes,
+            &ephemeral_public_key,
+        )
+        .unwrap();
+        assert_eq!(opener.open_chunk(&ciphertext, true).unwrap(), chunks[0]);
+        opener.finish().unwrap();
+    }
 }
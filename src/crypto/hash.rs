@@ -1,12 +1,21 @@
-use crate::{error::Error, tls_ser};
+use crate::{
+    crypto::backend::{self, HashAlgorithm},
+    error::Error,
+    tls_ser,
+};
 
 use serde::ser::Serialize;
 
 pub(crate) const SHA256_IMPL: HashFunction = HashFunction {
-    hash_alg: &ring::digest::SHA256,
+    hash_alg: HashAlgorithm::Sha256,
+};
+
+// Nothing uses this yet. It's here for the ciphersuites built on X448 and P-521.
+#[allow(dead_code)]
+pub(crate) const SHA512_IMPL: HashFunction = HashFunction {
+    hash_alg: HashAlgorithm::Sha512,
 };
 
-// This isn't ring::digest::Digest because you can't deserialize those (there's no constructor).
 // TODO: We could be more efficient by making this an ArrayVec internally.
 /// A message digest of a hash function
 #[derive(Clone, Deserialize, Serialize)]
@@ -22,11 +31,17 @@ impl Digest {
     pub(crate) fn new_from_zeros(hash_impl: &HashFunction) -> Digest {
         Digest(vec![0u8; hash_impl.digest_size()])
     }
-}
 
-impl From<ring::digest::Digest> for Digest {
-    fn from(d: ring::digest::Digest) -> Digest {
-        Digest(d.as_ref().to_vec())
+    /// Makes a `Digest` out of the output of the given hash function
+    ///
+    /// Returns: `Ok(digest)` on success. Returns an `Error::ValidationError` if `bytes` isn't
+    /// the size of a digest.
+    pub(crate) fn new_from_bytes(hash_impl: &HashFunction, bytes: &[u8]) -> Result<Digest, Error> {
+        if bytes.len() != hash_impl.digest_size() {
+            Err(Error::ValidationError("Digest is the wrong size"))
+        } else {
+            Ok(Digest(bytes.to_vec()))
+        }
     }
 }
 
@@ -38,7 +53,7 @@ impl subtle::ConstantTimeEq for Digest {
 
 #[derive(Debug)]
 pub(crate) struct HashFunction {
-    pub(crate) hash_alg: &'static ring::digest::Algorithm,
+    pub(crate) hash_alg: HashAlgorithm,
 }
 
 impl HashFunction {
@@ -56,17 +71,17 @@ impl HashFunction {
 
     pub(crate) fn new_context(&self) -> HashContext {
         HashContext {
-            ctx: ring::digest::Context::new(self.hash_alg),
+            ctx: backend::HashContext::new(self.hash_alg),
         }
     }
 
     pub(crate) fn digest_size(&self) -> usize {
-        self.hash_alg.output_len
+        self.hash_alg.output_len()
     }
 }
 
 pub(crate) struct HashContext {
-    ctx: ring::digest::Context,
+    ctx: backend::HashContext,
 }
 
 impl HashContext {
@@ -79,10 +94,10 @@ impl HashContext {
     }
 
     pub(crate) fn feed_bytes(&mut self, bytes: &[u8]) {
-        self.ctx.update(&bytes);
+        self.ctx.update(bytes);
     }
 
     pub(crate) fn finalize(self) -> Digest {
-        self.ctx.finish().into()
+        Digest(self.ctx.finish())
     }
 }
@@ -1,6 +1,14 @@
+//! Defines the HKDF functions that the MLS key schedule is built from. Most of these are internal.
+//! The public part, `extract_prk` and `expand_app_label`, is for applications that want to derive
+//! keys of their own, e.g., for file attachments, under the same labeling rules as MLS.
+
 use crate::{
-    crypto::hash::HashFunction,
-    crypto::hmac::{self, HmacKey},
+    crypto::{
+        backend,
+        ciphersuite::CipherSuite,
+        hash::{Digest, HashFunction},
+        hmac::{self, HmacKey},
+    },
     error::Error,
 };
 
@@ -26,7 +34,8 @@ struct HkdfLabel<'a> {
 /// An implementation of HKDF-Extract. Code mostly copied from `ring::hkdf::extract`.
 pub(crate) fn extract(hash_impl: &HashFunction, salt: &HmacKey, secret: &[u8]) -> HmacKey {
     // We can't just use `ring::hkdf::extract` because it returns a `SigningKey` which we can't get
-    // any key bytes from. So we just reimplement it here. The below comment is copied from ring.
+    // any key bytes from. So we just reimplement it here, which also keeps it the same across
+    // crypto backends. The below comment is copied from ring.
 
     // The spec says that if no salt is provided then a key of `digest_alg.output_len` bytes of
     // zeros is used. But, HMAC keys are already zero-padded to the block length, which is larger
@@ -36,7 +45,7 @@ pub(crate) fn extract(hash_impl: &HashFunction, salt: &HmacKey, secret: &[u8]) -
     HmacKey::new_from_bytes(prk.as_bytes())
 }
 
-/// An implementation of HKDF-Extract. Passes through to `ring::hkdf::expand`.
+/// An implementation of HKDF-Expand. Passes through to the crypto backend.
 pub(crate) fn expand<S: Serialize>(
     hash_impl: &HashFunction,
     salt: &HmacKey,
@@ -45,9 +54,7 @@ pub(crate) fn expand<S: Serialize>(
 ) -> Result<(), Error> {
     let serialized_info = crate::tls_ser::serialize_to_bytes(info)?;
 
-    // Pass to ring
-    let prk = ring::hmac::SigningKey::new(hash_impl.hash_alg, &salt.0);
-    ring::hkdf::expand(&prk, &serialized_info, out_buf);
+    backend::hkdf_expand(hash_impl.hash_alg, salt.as_bytes(), &serialized_info, out_buf);
 
     Ok(())
 }
@@ -73,29 +80,54 @@ pub(crate) fn expand_label(
     context: &[u8],
     out_buf: &mut [u8],
 ) {
-    // The label size is supposed to be at most 255 bytes after being prefixed with "mls10 "
-    assert!(label_info.len() <= 255 - MLS_PREFIX.len());
-    // The output length is also supposed to be representable by a u16
-    assert!(out_buf.len() <= std::u16::MAX as usize);
-
-    // full_label_info_slice = "mls10 " + Label
-    let mut full_label_info = [0u8; 255];
-    full_label_info[0..MLS_PREFIX.len()].copy_from_slice(MLS_PREFIX);
-    full_label_info[MLS_PREFIX.len()..MLS_PREFIX.len() + label_info.len()]
-        .copy_from_slice(label_info);
-    let full_label_info_slice = &full_label_info[0..MLS_PREFIX.len() + label_info.len()];
-
-    // We're gonna used the serialized label as the `info` parameter to HKDF-Expand
-    let label = HkdfLabel {
-        length: out_buf.len() as u16,
-        // Recall the def: opaque label<6..255> = "mls10 " + Label;
-        label: &full_label_info_slice,
-        context,
-    };
+    LabelExpander::new(hash_impl, secret).expand_label(label_info, context, out_buf)
+}
 
-    // Finally, do the HKDF-Expand operation with built-in serialization. This can't fail, since we
-    // check that the label isn't oversized with the assert above.
-    expand(hash_impl, secret, &label, out_buf).unwrap();
+/// A secret that's ready to have `HKDF-Expand-Label` computed over it many times. This is for
+/// deriving lots of values from the same secret, like the write secret of every member of a big
+/// group, since it sets up the HMAC key once instead of once per value.
+pub(crate) struct LabelExpander {
+    prk: backend::HkdfPrk,
+}
+
+impl LabelExpander {
+    pub(crate) fn new(hash_impl: &HashFunction, secret: &HmacKey) -> LabelExpander {
+        LabelExpander {
+            prk: backend::HkdfPrk::new(hash_impl.hash_alg, secret.as_bytes()),
+        }
+    }
+
+    /// Computes `HKDF-Expand-Label` over this secret. This is the same as `expand_label`.
+    ///
+    /// Requires: `label_info.len() <= 255 - MLS_PREFIX.len() = 249
+    ///
+    /// Panics: Iff the above requirement is not met
+    pub(crate) fn expand_label(&self, label_info: &[u8], context: &[u8], out_buf: &mut [u8]) {
+        // The label size is supposed to be at most 255 bytes after being prefixed with "mls10 "
+        assert!(label_info.len() <= 255 - MLS_PREFIX.len());
+        // The output length is also supposed to be representable by a u16
+        assert!(out_buf.len() <= u16::MAX as usize);
+
+        // full_label_info_slice = "mls10 " + Label
+        let mut full_label_info = [0u8; 255];
+        full_label_info[0..MLS_PREFIX.len()].copy_from_slice(MLS_PREFIX);
+        full_label_info[MLS_PREFIX.len()..MLS_PREFIX.len() + label_info.len()]
+            .copy_from_slice(label_info);
+        let full_label_info_slice = &full_label_info[0..MLS_PREFIX.len() + label_info.len()];
+
+        // We're gonna used the serialized label as the `info` parameter to HKDF-Expand
+        let label = HkdfLabel {
+            length: out_buf.len() as u16,
+            // Recall the def: opaque label<6..255> = "mls10 " + Label;
+            label: full_label_info_slice,
+            context,
+        };
+
+        // Finally, do the HKDF-Expand operation. Serializing can't fail, since we check that the
+        // label isn't oversized with the assert above.
+        let serialized_label = crate::tls_ser::serialize_to_bytes(&label).unwrap();
+        self.prk.expand(&serialized_label, out_buf);
+    }
 }
 
 /// This is the `Derive-Secret` function defined in the "Key Schedule" section of the spec. It's
@@ -111,32 +143,130 @@ pub(crate) fn derive_secret<S: Serialize>(
 ) -> Result<HmacKey, Error> {
     // Derive-Secret(Secret, Label, Context) =
     //     HKDF-Expand-Label(Secret, Label, Hash(Context), Hash.length)
-    let key = {
-        let hashed_ctx = hash_impl.hash_serializable(context)?;
-        let mut key_buf = vec![0u8; hash_impl.digest_size()];
-        expand_label(hash_impl, secret, label_info, hashed_ctx.as_bytes(), key_buf.as_mut_slice());
-        HmacKey::new_from_bytes(&key_buf)
-    };
-    Ok(key)
+    let hashed_ctx = hash_impl.hash_serializable(context)?;
+    Ok(derive_secret_from_hashed_context(hash_impl, secret, label_info, &hashed_ctx))
+}
+
+/// Like `derive_secret`, but takes `Hash(Context)` instead of `Context`. This saves serializing
+/// and hashing the context over again when several secrets are derived from the same one.
+pub(crate) fn derive_secret_from_hashed_context(
+    hash_impl: &HashFunction,
+    secret: &HmacKey,
+    label_info: &[u8],
+    hashed_ctx: &Digest,
+) -> HmacKey {
+    let mut key_buf = vec![0u8; hash_impl.digest_size()];
+    expand_label(hash_impl, secret, label_info, hashed_ctx.as_bytes(), key_buf.as_mut_slice());
+    HmacKey::new_from_bytes(&key_buf)
+}
+
+//
+// Everything after this (not including tests) is non-standard
+//
+
+// Separates an application's namespace from its labels. None of the labels MLS itself uses contain
+// this, so application labels can never coincide with protocol labels.
+const APP_LABEL_SEPARATOR: u8 = b':';
+
+/// A pseudorandom key, i.e., the output of HKDF-Extract. This is what `expand_app_label` derives
+/// keys from.
+pub struct Prk(HmacKey);
+
+impl Prk {
+    /// Uses the given bytes as a PRK directly. This is only sound if the bytes are already a
+    /// uniformly random secret at least as long as the hash output. Anything else should go
+    /// through `extract_prk`.
+    pub fn from_bytes(bytes: &[u8]) -> Prk {
+        Prk(HmacKey::new_from_bytes(bytes))
+    }
+}
+
+/// Computes `HKDF-Extract(salt, ikm)` under the hash function of the given ciphersuite
+pub fn extract_prk(cs: &'static CipherSuite, salt: &[u8], ikm: &[u8]) -> Prk {
+    Prk(extract(cs.hash_impl, &HmacKey::new_from_bytes(salt), ikm))
+}
+
+/// Computes `HKDF-Expand-Label(prk, namespace || ":" || label, context, out_len)` under the hash
+/// function of the given ciphersuite, where `HKDF-Expand-Label` is the one from the "Key Schedule"
+/// section of the spec. `namespace` should identify the application, so that different
+/// applications deriving keys from the same secret get different keys. Since no MLS label has a
+/// ':' in it, nothing derived this way can collide with a secret MLS derives itself.
+///
+/// Returns: `Ok(okm)` on success, where `okm` is `out_len` bytes long. Returns an
+/// `Error::ValidationError` if `namespace` is empty or contains a ':', if the full label is longer
+/// than 249 bytes, or if `out_len` is more than HKDF can produce, i.e., 255 times the hash length.
+pub fn expand_app_label(
+    cs: &'static CipherSuite,
+    prk: &Prk,
+    namespace: &[u8],
+    label: &[u8],
+    context: &[u8],
+    out_len: usize,
+) -> Result<Vec<u8>, Error> {
+    if namespace.is_empty() {
+        return Err(Error::ValidationError("Application label namespace cannot be empty"));
+    }
+    if namespace.contains(&APP_LABEL_SEPARATOR) {
+        return Err(Error::ValidationError("Application label namespace cannot contain a ':'"));
+    }
+    // These are the conditions under which expand_label panics
+    if namespace.len() + 1 + label.len() > 255 - MLS_PREFIX.len() {
+        return Err(Error::ValidationError("Application label is too long"));
+    }
+    if out_len > 255 * cs.hash_impl.digest_size() || out_len > u16::MAX as usize {
+        return Err(Error::ValidationError("Requested too much output from HKDF-Expand-Label"));
+    }
+
+    let full_label = [namespace, &[APP_LABEL_SEPARATOR], label].concat();
+    let mut out_buf = vec![0u8; out_len];
+    expand_label(cs.hash_impl, &prk.0, &full_label, context, &mut out_buf);
+
+    Ok(out_buf)
 }
 
 #[cfg(test)]
 mod test {
-    use crate::crypto::{
-        hash::SHA256_IMPL,
-        hkdf,
-        hmac::{self, HmacKey},
+    use crate::{
+        crypto::{ciphersuite::X25519_SHA256_AES128GCM, hash::SHA256_IMPL, hkdf, hmac::HmacKey},
+        error::Error,
     };
 
     use quickcheck_macros::quickcheck;
 
+    // Checks extract and expand against test case 1 of RFC 5869, appendix A. This runs under
+    // whichever crypto backend is selected.
+    #[test]
+    fn hkdf_rfc5869_kat() {
+        let hash_impl = &SHA256_IMPL;
+        let ikm = [0x0bu8; 22];
+        let salt = HmacKey::new_from_bytes(&hex::decode("000102030405060708090a0b0c").unwrap());
+        let info = hex::decode("f0f1f2f3f4f5f6f7f8f9").unwrap();
+
+        let prk = hkdf::extract(hash_impl, &salt, &ikm);
+        assert_eq!(
+            hex::encode(prk.as_bytes()),
+            "077709362c2e32df0ddc3f0dc47bba6390b6c73bb50f9c3122ec844ad7c2b3e5"
+        );
+
+        // A Vec<u8> serializes to its bytes with no length prefix, so this is the raw info string
+        let mut okm = [0u8; 42];
+        hkdf::expand(hash_impl, &prk, &info, &mut okm).unwrap();
+        assert_eq!(
+            hex::encode(&okm[..]),
+            "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865"
+        );
+    }
+
     // Check that our implementation of hkdf::extract matches ring's implementation
+    #[cfg(feature = "ring-backend")]
     #[quickcheck]
     fn hkdf_extract_kat(salt_bytes: Vec<u8>, secret_bytes: Vec<u8>) {
+        use crate::crypto::hmac;
+
         let hash_impl = &SHA256_IMPL;
 
         // Wrap the salt bytes in a signing key
-        let ring_salt = ring::hmac::SigningKey::new(hash_impl.hash_alg, &salt_bytes);
+        let ring_salt = ring::hmac::SigningKey::new(&ring::digest::SHA256, &salt_bytes);
         let my_salt = HmacKey::new_from_bytes(&salt_bytes);
 
         // prk = HKDF-Extract(salt, ikm=secret)
@@ -152,4 +282,32 @@ mod test {
 
         assert_eq!(ring_sig.as_ref(), my_sig.as_bytes());
     }
+
+    // Check that expand_app_label is HKDF-Expand-Label with the namespaced label, that namespaces
+    // separate keys, and that bad arguments are refused rather than panicking
+    #[quickcheck]
+    fn expand_app_label_correctness(secret: Vec<u8>, label: Vec<u8>, context: Vec<u8>) {
+        let cs = &X25519_SHA256_AES128GCM;
+        // Keep the label within the limit
+        let label = &label[..std::cmp::min(label.len(), 200)];
+        let prk = hkdf::extract_prk(cs, b"salt", &secret);
+
+        let okm = hkdf::expand_app_label(cs, &prk, b"attachments", label, &context, 32).unwrap();
+        let mut expected = [0u8; 32];
+        let full_label = [b"attachments:", label].concat();
+        hkdf::expand_label(cs.hash_impl, &prk.0, &full_label, &context, &mut expected);
+        assert_eq!(okm, expected.to_vec());
+
+        let other_okm = hkdf::expand_app_label(cs, &prk, b"calls", label, &context, 32).unwrap();
+        assert_ne!(okm, other_okm);
+
+        for (namespace, out_len) in &[(&b""[..], 32), (&b"a:b"[..], 32), (&b"ok"[..], 256 * 32)] {
+            match hkdf::expand_app_label(cs, &prk, namespace, label, &context, *out_len) {
+                Err(Error::ValidationError(_)) => (),
+                _ => panic!("expand_app_label accepted bad arguments"),
+            }
+        }
+        let long_label = [0u8; 250];
+        assert!(hkdf::expand_app_label(cs, &prk, b"ns", &long_label, &context, 32).is_err());
+    }
 }
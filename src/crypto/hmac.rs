@@ -1,34 +1,37 @@
 use crate::{
-    crypto::{hash::HashFunction, rng::CryptoRng},
+    crypto::{backend, hash::HashFunction, rng::CryptoRng, secret::Secret},
     error::Error,
 };
 
 // TODO: Make these newtypes ArrayVecs
 
-/// An HMAC signing/verification key
-#[derive(Clone, Deserialize, Serialize)]
-#[cfg_attr(test, derive(Debug, Eq, PartialEq))]
-// This is opaque <0..255> because WelcomeInfo::init_secret is
-#[serde(rename = "HmacKey__bound_u8")]
-pub(crate) struct HmacKey(pub(crate) Vec<u8>);
+/// An HMAC signing/verification key. Every secret in the key schedule is one of these, so the bytes
+/// are kept in a `Secret`. Like `Secret`, this can't be serialized.
+#[derive(Clone, Debug)]
+#[cfg_attr(test, derive(Eq, PartialEq))]
+pub(crate) struct HmacKey(pub(crate) Secret);
 
 impl HmacKey {
     pub(crate) fn new_from_bytes(bytes: &[u8]) -> HmacKey {
-        HmacKey(bytes.to_vec())
+        HmacKey(Secret::new_from_bytes(bytes))
     }
 
     pub fn new_from_random<R>(hash_impl: &HashFunction, csprng: &mut R) -> HmacKey
     where
         R: CryptoRng,
     {
-        let mut buf = vec![0u8; hash_impl.digest_size()];
-        csprng.fill_bytes(&mut buf);
-        HmacKey(buf)
+        // Lock the buffer before there's anything secret in it
+        let mut key = HmacKey::new_from_zeros(hash_impl);
+        csprng.fill_bytes(key.0.as_mut_bytes());
+        key
     }
 
     pub(crate) fn new_from_zeros(hash_impl: &HashFunction) -> HmacKey {
-        let buf = vec![0u8; hash_impl.digest_size()];
-        HmacKey(buf)
+        HmacKey(Secret::new_from_zeros(hash_impl.digest_size()))
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
     }
 }
 
@@ -44,12 +47,6 @@ impl Mac {
     }
 }
 
-impl From<ring::hmac::Signature> for Mac {
-    fn from(sig: ring::hmac::Signature) -> Mac {
-        Mac(sig.as_ref().to_vec())
-    }
-}
-
 pub(crate) fn sign(hash_impl: &HashFunction, key: &HmacKey, msg: &[u8]) -> Mac {
     let mut ctx = new_signing_context(hash_impl, key);
     ctx.feed_bytes(msg);
@@ -62,26 +59,19 @@ pub(crate) fn verify(
     msg: &[u8],
     sig: &Mac,
 ) -> Result<(), Error> {
-    let verification_key: ring::hmac::VerificationKey =
-        ring::hmac::VerificationKey::new(hash_impl.hash_alg, &key.0);
-
-    // It's okay to reveal that the MAC is incorrect, because the ring::hmac::verify runs in
+    // It's okay to reveal that the MAC is incorrect, because every backend's hmac_verify runs in
     // constant time
-    ring::hmac::verify(&verification_key, msg, &sig.0)
-        .map_err(|_| Error::SignatureError("MAC verification failed"))
+    backend::hmac_verify(hash_impl.hash_alg, key.as_bytes(), msg, &sig.0)
 }
 
 pub(crate) fn new_signing_context(hash_impl: &HashFunction, key: &HmacKey) -> HmacSigningContext {
-    let signing_key: ring::hmac::SigningKey =
-        ring::hmac::SigningKey::new(hash_impl.hash_alg, &key.0);
-
     HmacSigningContext {
-        ctx: ring::hmac::SigningContext::with_key(&signing_key),
+        ctx: backend::HmacContext::new(hash_impl.hash_alg, key.as_bytes()),
     }
 }
 
 pub(crate) struct HmacSigningContext {
-    ctx: ring::hmac::SigningContext,
+    ctx: backend::HmacContext,
 }
 
 impl HmacSigningContext {
@@ -90,6 +80,6 @@ impl HmacSigningContext {
     }
 
     pub(crate) fn finalize(self) -> Mac {
-        self.ctx.sign().into()
+        Mac(self.ctx.finish())
     }
 }
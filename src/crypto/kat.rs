@@ -0,0 +1,249 @@
+//! Runs the official crypto test vectors against this crate's primitives. The vectors are in
+//! crypto.bin, from https://github.com/mlswg/mls-implementations/tree/master/test_vectors. This is
+//! for checking a build against known answers before trusting it, e.g., as a self-test on a new
+//! platform. Nothing here takes or produces secret values.
+
+use crate::{
+    crypto::{
+        ciphersuite::{CipherSuite, P256_SHA256_AES128GCM, X25519_SHA256_AES128GCM},
+        dh::DhPublicKey,
+        ecies::{self, EciesCiphertext},
+        hkdf,
+        hmac::HmacKey,
+    },
+    error::Error,
+    tls_ser,
+    upcast::{self, CryptoCtx, CryptoUpcast},
+};
+
+use subtle::ConstantTimeEq;
+
+// struct {
+//   opaque hkdf_extract_out<0..255>;
+//   group_state: GroupState,
+//   opaque derive_secret_out<0..255>;
+//   DHPublicKey derive_key_pair_pub;
+//   ECIESCiphertext ecies_out;
+// } CryptoCase;
+//
+// struct {
+//   opaque hkdf_extract_salt<0..255>;
+//   opaque hkdf_extract_ikm<0..255>;
+//   opaque derive_secret_salt<0..255>;
+//   opaque derive_secret_label<0..255>;
+//   opaque derive_secret_context<0..255>;
+//   opaque derive_key_pair_seed<0..255>;
+//   opaque ecies_plaintext<0..255>;
+//
+//   CryptoCase case_p256_p256;
+//   CryptoCase case_x25519_ed25519;
+// } CryptoTestVectors;
+//
+// The CryptoTestVectors struct contains the inputs to cryptographic functions, and the
+// CryptoCase members hold the outputs when using the indicated ciphersuites.  The following
+// functions are tested:
+//
+// * HKDF-Extract
+// * Derive-Secret
+//   * The salt and label arguments are provided
+//   * The State argument should be initialized with the following contents:
+//     * group_id and transcript_hash: The zero-length octet string
+//     * epoch: 0
+//     * roster, tree: Zero-length vectors
+//   * That is, the state should serialize to a sequence of 14 zeros
+// * Derive-Key-Pair
+// * ECIES
+//   * Encryption and decryption is done using the key pair generated in the Derive-Key-Pair
+//     stage.
+//   * The encryption phase is made deterministic by deriving the ephemeral key pair from the
+//     inputs.
+//   * (skE, pkE)  = Derive-Key-Pair(pkR || plaintext), where pkR is the serialization of the
+//     recipient's public key (the body of a DHPublicKey, with no length octets), and plaintext
+//     is the plaintext being encrypted.
+
+/// The outputs of the crypto functions under one ciphersuite
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Debug))]
+pub(crate) struct CryptoCase {
+    #[serde(rename = "hkdf_extract_out__bound_u8")]
+    pub(crate) hkdf_extract_out: Vec<u8>,
+    #[serde(rename = "derive_secret_out__bound_u8")]
+    pub(crate) derive_secret_out: Vec<u8>,
+    pub(crate) derive_key_pair_pub: DhPublicKey,
+    pub(crate) ecies_out: EciesCiphertext,
+}
+
+impl CryptoUpcast for CryptoCase {
+    fn upcast_crypto_values(&mut self, ctx: &CryptoCtx) -> Result<CryptoCtx, Error> {
+        self.derive_key_pair_pub.upcast_crypto_values(ctx)?;
+        self.ecies_out.upcast_crypto_values(ctx)?;
+        Ok(*ctx)
+    }
+}
+
+/// The contents of crypto.bin: the inputs to the crypto functions, and their outputs under each
+/// ciphersuite
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Debug))]
+pub(crate) struct CryptoTestVectors {
+    #[serde(rename = "hkdf_extract_salt__bound_u8")]
+    pub(crate) hkdf_extract_salt: Vec<u8>,
+    #[serde(rename = "hkdf_extract_ikm__bound_u8")]
+    pub(crate) hkdf_extract_ikm: Vec<u8>,
+    #[serde(rename = "derive_secret_salt__bound_u8")]
+    pub(crate) derive_secret_salt: Vec<u8>,
+    #[serde(rename = "derive_secret_label__bound_u8")]
+    pub(crate) derive_secret_label: Vec<u8>,
+    #[serde(rename = "derive_secret_context__bound_u8")]
+    pub(crate) derive_secret_context: Vec<u8>,
+    #[serde(rename = "derive_key_pair_seed__bound_u8")]
+    pub(crate) derive_key_pair_seed: Vec<u8>,
+    #[serde(rename = "ecies_plaintext__bound_u8")]
+    pub(crate) ecies_plaintext: Vec<u8>,
+
+    pub(crate) case_p256_p256: CryptoCase,
+    pub(crate) case_x25519_ed25519: CryptoCase,
+}
+
+impl CryptoUpcast for CryptoTestVectors {
+    // Each case is upcast with its own ciphersuite. Whatever's in ctx is ignored.
+    fn upcast_crypto_values(&mut self, ctx: &CryptoCtx) -> Result<CryptoCtx, Error> {
+        let p256_ctx = ctx.set_cipher_suite(&P256_SHA256_AES128GCM);
+        self.case_p256_p256.upcast_crypto_values(&p256_ctx)?;
+        let x25519_ctx = ctx.set_cipher_suite(&X25519_SHA256_AES128GCM);
+        self.case_x25519_ed25519.upcast_crypto_values(&x25519_ctx)?;
+        Ok(*ctx)
+    }
+}
+
+/// Parses the contents of crypto.bin and checks every known answer in it that this crate can
+/// compute. These are HKDF-Extract and Derive-Secret under both ciphersuites, and Derive-Key-Pair
+/// and ECIES under X25519_SHA256_AES128GCM. P-256 DH isn't implemented yet, so the DH half of the
+/// P-256 case is skipped.
+///
+/// Returns: `Ok(())` if every answer matches. Returns an `Error::ValidationError` naming the first
+/// function whose output doesn't match. Returns an `Error::SerdeError` or `Error::UpcastError` if
+/// the vectors can't be parsed.
+pub fn run_crypto_kat(bytes: &[u8]) -> Result<(), Error> {
+    let test_vec: CryptoTestVectors = upcast::deserialize_and_upcast(bytes, &CryptoCtx::new())?;
+    check_crypto_vectors(&test_vec)
+}
+
+/// Checks every known answer in `test_vec` that this crate can compute. See `run_crypto_kat`.
+pub(crate) fn check_crypto_vectors(test_vec: &CryptoTestVectors) -> Result<(), Error> {
+    check_hash_functions(&P256_SHA256_AES128GCM, test_vec, &test_vec.case_p256_p256)?;
+
+    let cs = &X25519_SHA256_AES128GCM;
+    let case = &test_vec.case_x25519_ed25519;
+    check_hash_functions(cs, test_vec, case)?;
+    check_dh_functions(cs, test_vec, case)
+}
+
+/// Checks HKDF-Extract and Derive-Secret under `cs` against `case`
+fn check_hash_functions(
+    cs: &'static CipherSuite,
+    test_vec: &CryptoTestVectors,
+    case: &CryptoCase,
+) -> Result<(), Error> {
+    // hkdf_extract_out == HKDF-Extract(salt=hkdf_extract_salt, ikm=hkdf_extract_ikm)
+    let salt = HmacKey::new_from_bytes(&test_vec.hkdf_extract_salt);
+    let extract_out = hkdf::extract(cs.hash_impl, &salt, &test_vec.hkdf_extract_ikm);
+    if !bool::from(extract_out.as_bytes().ct_eq(&case.hkdf_extract_out)) {
+        return Err(Error::ValidationError("HKDF-Extract output doesn't match the known answer"));
+    }
+
+    // derive_secret_out == Derive-Secret(
+    //     secret=derive_secret_salt,
+    //     label=derive_secret_label,
+    //     context=derive_secret_context
+    //  )
+    let secret = HmacKey::new_from_bytes(&test_vec.derive_secret_salt);
+    let derive_secret_out = hkdf::derive_secret(
+        cs.hash_impl,
+        &secret,
+        &test_vec.derive_secret_label,
+        &test_vec.derive_secret_context,
+    )?;
+    if !bool::from(derive_secret_out.as_bytes().ct_eq(&case.derive_secret_out)) {
+        return Err(Error::ValidationError("Derive-Secret output doesn't match the known answer"));
+    }
+
+    Ok(())
+}
+
+/// Checks Derive-Key-Pair and ECIES under `cs` against `case`
+fn check_dh_functions(
+    cs: &'static CipherSuite,
+    test_vec: &CryptoTestVectors,
+    case: &CryptoCase,
+) -> Result<(), Error> {
+    // Derive-Key-Pair(derive_key_pair_seed). We only have the answer's public key to compare to.
+    let (recip_public_key, recip_secret_key) =
+        cs.derive_key_pair(&test_vec.derive_key_pair_seed)?;
+    if tls_ser::serialize_to_bytes(&recip_public_key)?
+        != tls_ser::serialize_to_bytes(&case.derive_key_pair_pub)?
+    {
+        return Err(Error::ValidationError(
+            "Derive-Key-Pair output doesn't match the known answer",
+        ));
+    }
+
+    // The known ciphertext has to decrypt to the plaintext under the derived key
+    let plaintext = ecies::decrypt(cs, &recip_secret_key, case.ecies_out.clone())?;
+    if plaintext != test_vec.ecies_plaintext {
+        return Err(Error::ValidationError("ECIES decryption doesn't match the known answer"));
+    }
+
+    // And we have to get the same ciphertext if we use the same ephemeral key as the test creator
+    // (_, skE) = Derive-Key-Pair(pkR || plaintext)
+    let (_, sender_secret_key) = {
+        let key_material =
+            [recip_public_key.as_bytes(), test_vec.ecies_plaintext.as_slice()].concat();
+        cs.derive_key_pair(&key_material)?
+    };
+    let ciphertext = ecies::encrypt_with_scalar(
+        cs,
+        &recip_public_key,
+        test_vec.ecies_plaintext.clone(),
+        sender_secret_key,
+    )?;
+    if tls_ser::serialize_to_bytes(&ciphertext)? != tls_ser::serialize_to_bytes(&case.ecies_out)? {
+        return Err(Error::ValidationError("ECIES encryption doesn't match the known answer"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Tests our code against the official crypto test vector
+    #[test]
+    fn official_crypto_kat() {
+        let bytes = std::fs::read("test_vectors/crypto.bin").unwrap();
+        run_crypto_kat(&bytes).unwrap();
+    }
+
+    // Checks that a wrong answer in any of the checked functions is caught
+    #[test]
+    fn crypto_kat_mismatch() {
+        let bytes = std::fs::read("test_vectors/crypto.bin").unwrap();
+        let corruptions: [fn(&mut CryptoTestVectors); 4] = [
+            |tv| tv.case_p256_p256.hkdf_extract_out[0] ^= 1,
+            |tv| tv.case_x25519_ed25519.derive_secret_out[0] ^= 1,
+            |tv| tv.derive_key_pair_seed.push(0),
+            |tv| tv.ecies_plaintext.push(0),
+        ];
+
+        for corrupt in corruptions.iter() {
+            let mut test_vec: CryptoTestVectors =
+                upcast::deserialize_and_upcast(&bytes, &CryptoCtx::new()).unwrap();
+            corrupt(&mut test_vec);
+            match check_crypto_vectors(&test_vec) {
+                Err(Error::ValidationError(_)) => (),
+                r => panic!("corrupted known answer was accepted: {:?}", r),
+            }
+        }
+    }
+}
@@ -0,0 +1,240 @@
+//! Just enough DER to move keys in and out of this crate in the formats other tools use: PKCS#8
+//! `PrivateKeyInfo` for private keys and X.509 `SubjectPublicKeyInfo` for public keys, both as
+//! specified for curve25519-based algorithms in RFC 8410. Callers turn the `&'static str` errors
+//! here into whatever `Error` variant fits their key type.
+
+/// The object identifier of Ed25519 (1.3.101.112), without its tag and length
+pub(crate) const OID_ED25519: &[u8] = &[0x2b, 0x65, 0x70];
+/// The object identifier of X25519 (1.3.101.110), without its tag and length
+pub(crate) const OID_X25519: &[u8] = &[0x2b, 0x65, 0x6e];
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+// Context-specific tags have the top two bits set to 0b10
+const CLASS_MASK: u8 = 0xc0;
+const CLASS_CONTEXT_SPECIFIC: u8 = 0x80;
+
+/// Appends a DER element with the given tag and contents to `out`
+fn write_tlv(out: &mut Vec<u8>, tag: u8, contents: &[u8]) {
+    out.push(tag);
+    // Nothing we write is anywhere near 2^16 bytes long
+    let len = contents.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else if len <= 0xff {
+        out.extend_from_slice(&[0x81, len as u8]);
+    } else {
+        out.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]);
+    }
+    out.extend_from_slice(contents);
+}
+
+/// Reads one DER element off the front of `input`
+///
+/// Returns: `Ok((tag, contents, rest))` on success. Otherwise returns an error message.
+fn read_tlv(input: &[u8]) -> Result<(u8, &[u8], &[u8]), &'static str> {
+    let (&tag, input) = input.split_first().ok_or("DER element is missing its tag")?;
+    let (&first_len_byte, input) =
+        input.split_first().ok_or("DER element is missing its length")?;
+
+    // Short-form lengths are the byte itself. Long-form lengths say how many bytes follow. DER
+    // requires the shortest encoding, so 1 or 2 bytes is all any key needs.
+    let (len, input) = match first_len_byte {
+        0x00..=0x7f => (first_len_byte as usize, input),
+        0x81 => match input.split_first() {
+            Some((&len, rest)) if len >= 0x80 => (len as usize, rest),
+            _ => return Err("DER length isn't minimally encoded"),
+        },
+        0x82 => match input {
+            [hi, lo, rest @ ..] if *hi != 0 => (((*hi as usize) << 8) | (*lo as usize), rest),
+            _ => return Err("DER length isn't minimally encoded"),
+        },
+        _ => return Err("DER length is unsupported"),
+    };
+
+    if input.len() < len {
+        return Err("DER element is truncated");
+    }
+    let (contents, rest) = input.split_at(len);
+    Ok((tag, contents, rest))
+}
+
+/// Reads one DER element with the given tag off the front of `input`
+///
+/// Returns: `Ok((contents, rest))` on success. Otherwise returns an error message.
+fn expect_tlv(input: &[u8], expected_tag: u8) -> Result<(&[u8], &[u8]), &'static str> {
+    let (tag, contents, rest) = read_tlv(input)?;
+    if tag != expected_tag {
+        return Err("Unexpected DER element");
+    }
+    Ok((contents, rest))
+}
+
+/// Returns the DER encoding of an `AlgorithmIdentifier` with the given OID and no parameters
+fn algorithm_identifier(oid: &[u8]) -> Vec<u8> {
+    let mut encoded_oid = Vec::new();
+    write_tlv(&mut encoded_oid, TAG_OID, oid);
+    let mut out = Vec::new();
+    write_tlv(&mut out, TAG_SEQUENCE, &encoded_oid);
+    out
+}
+
+/// Checks that `input` starts with an `AlgorithmIdentifier` with the given OID and no parameters.
+/// RFC 8410 forbids parameters for these algorithms.
+///
+/// Returns: `Ok(rest)` on success. Otherwise returns an error message.
+fn read_algorithm_identifier<'a>(input: &'a [u8], oid: &[u8]) -> Result<&'a [u8], &'static str> {
+    let (alg_id, rest) = expect_tlv(input, TAG_SEQUENCE)?;
+    let (found_oid, params) = expect_tlv(alg_id, TAG_OID)?;
+    if found_oid != oid {
+        return Err("Key is for a different algorithm");
+    }
+    if !params.is_empty() {
+        return Err("Key's algorithm identifier has parameters");
+    }
+    Ok(rest)
+}
+
+/// Encodes the given private key bytes as a version 1 PKCS#8 `PrivateKeyInfo` for the algorithm
+/// with the given OID
+pub(crate) fn encode_private_key(oid: &[u8], key: &[u8]) -> Vec<u8> {
+    // CurvePrivateKey ::= OCTET STRING, which goes inside the privateKey OCTET STRING
+    let mut curve_private_key = Vec::new();
+    write_tlv(&mut curve_private_key, TAG_OCTET_STRING, key);
+
+    let mut body = Vec::new();
+    // version 0 is v1, which has no public key
+    write_tlv(&mut body, TAG_INTEGER, &[0]);
+    body.extend_from_slice(&algorithm_identifier(oid));
+    write_tlv(&mut body, TAG_OCTET_STRING, &curve_private_key);
+
+    let mut out = Vec::new();
+    write_tlv(&mut out, TAG_SEQUENCE, &body);
+    crate::utils::zeroize(&mut curve_private_key);
+    crate::utils::zeroize(&mut body);
+    out
+}
+
+/// Decodes a PKCS#8 `PrivateKeyInfo` for the algorithm with the given OID. Both version 1 and
+/// version 2 (RFC 5958 `OneAsymmetricKey`) are accepted. Attributes and the public key that can
+/// follow the private key are ignored.
+///
+/// Returns: `Ok(key)` on success, where `key` is the raw private key. Otherwise returns an error
+/// message.
+pub(crate) fn decode_private_key<'a>(oid: &[u8], der: &'a [u8]) -> Result<&'a [u8], &'static str> {
+    let (body, trailing) = expect_tlv(der, TAG_SEQUENCE)?;
+    if !trailing.is_empty() {
+        return Err("Trailing bytes after PrivateKeyInfo");
+    }
+
+    let (version, rest) = expect_tlv(body, TAG_INTEGER)?;
+    if version != [0] && version != [1] {
+        return Err("Unsupported PrivateKeyInfo version");
+    }
+    let rest = read_algorithm_identifier(rest, oid)?;
+    let (curve_private_key, mut rest) = expect_tlv(rest, TAG_OCTET_STRING)?;
+    let (key, trailing) = expect_tlv(curve_private_key, TAG_OCTET_STRING)?;
+    if !trailing.is_empty() {
+        return Err("Trailing bytes after CurvePrivateKey");
+    }
+
+    // Whatever's left has to be the optional [0] attributes and [1] publicKey
+    while !rest.is_empty() {
+        let (tag, _, next) = read_tlv(rest)?;
+        if tag & CLASS_MASK != CLASS_CONTEXT_SPECIFIC {
+            return Err("Unexpected element after PrivateKeyInfo's private key");
+        }
+        rest = next;
+    }
+
+    Ok(key)
+}
+
+/// Encodes the given public key bytes as a `SubjectPublicKeyInfo` for the algorithm with the given
+/// OID
+pub(crate) fn encode_public_key(oid: &[u8], key: &[u8]) -> Vec<u8> {
+    // The leading 0 says there are no unused bits at the end of the BIT STRING
+    let bit_string = [&[0u8][..], key].concat();
+
+    let mut body = algorithm_identifier(oid);
+    write_tlv(&mut body, TAG_BIT_STRING, &bit_string);
+
+    let mut out = Vec::new();
+    write_tlv(&mut out, TAG_SEQUENCE, &body);
+    out
+}
+
+/// Decodes a `SubjectPublicKeyInfo` for the algorithm with the given OID
+///
+/// Returns: `Ok(key)` on success, where `key` is the raw public key. Otherwise returns an error
+/// message.
+pub(crate) fn decode_public_key<'a>(oid: &[u8], der: &'a [u8]) -> Result<&'a [u8], &'static str> {
+    let (body, trailing) = expect_tlv(der, TAG_SEQUENCE)?;
+    if !trailing.is_empty() {
+        return Err("Trailing bytes after SubjectPublicKeyInfo");
+    }
+
+    let rest = read_algorithm_identifier(body, oid)?;
+    let (bit_string, trailing) = expect_tlv(rest, TAG_BIT_STRING)?;
+    if !trailing.is_empty() {
+        return Err("Trailing bytes after SubjectPublicKeyInfo's public key");
+    }
+    match bit_string.split_first() {
+        Some((0, key)) => Ok(key),
+        _ => Err("Public key isn't a whole number of bytes"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Checks our encoding against the examples in RFC 8410, sections 10.1 and 10.3, and checks that
+    // decoding undoes it
+    #[test]
+    fn rfc8410_examples() {
+        let private_key =
+            hex::decode("d4ee72dbf913584ad5b6d8f1f769f8ad3afe7c28cbf1d4fbe097a88f44755842")
+                .unwrap();
+        let private_key_info = hex::decode(
+            "302e020100300506032b657004220420d4ee72dbf913584ad5b6d8f1f769f8ad3afe7c28cbf1d4fbe097a8\
+             8f44755842",
+        )
+        .unwrap();
+        assert_eq!(encode_private_key(OID_ED25519, &private_key), private_key_info);
+        assert_eq!(decode_private_key(OID_ED25519, &private_key_info).unwrap(), &private_key[..]);
+
+        let public_key =
+            hex::decode("19bf44096984cdfe8541bac167dc3b96c85086aa30b6b6cb0c5c38ad703166e1")
+                .unwrap();
+        let public_key_info = hex::decode(
+            "302a300506032b657003210019bf44096984cdfe8541bac167dc3b96c85086aa30b6b6cb0c5c38ad7031\
+             66e1",
+        )
+        .unwrap();
+        assert_eq!(encode_public_key(OID_ED25519, &public_key), public_key_info);
+        assert_eq!(decode_public_key(OID_ED25519, &public_key_info).unwrap(), &public_key[..]);
+
+        // Keys for one algorithm aren't keys for another
+        assert!(decode_private_key(OID_X25519, &private_key_info).is_err());
+        assert!(decode_public_key(OID_X25519, &public_key_info).is_err());
+
+        // Truncated and padded encodings are refused
+        let truncated = &private_key_info[..private_key_info.len() - 1];
+        assert!(decode_private_key(OID_ED25519, truncated).is_err());
+        let padded = [&private_key_info[..], &[0u8][..]].concat();
+        assert!(decode_private_key(OID_ED25519, &padded).is_err());
+
+        // A version 2 key with a public key after the private key is fine. This is what ring
+        // makes. The outer length goes from 0x2e to 0x51 to fit the [1] publicKey.
+        let mut v2 = private_key_info.clone();
+        v2[1] = 0x51;
+        v2[4] = 1;
+        v2.extend_from_slice(&[0x81, 0x21, 0x00]);
+        v2.extend_from_slice(&public_key);
+        assert_eq!(decode_private_key(OID_ED25519, &v2).unwrap(), &private_key[..]);
+    }
+}
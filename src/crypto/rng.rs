@@ -5,3 +5,38 @@ pub trait CryptoRng: rand::RngCore + rand::CryptoRng {}
 
 /// Anything with a cryptographically secure `fill_bytes` method is a `CryptoRng`
 impl<T> CryptoRng for T where T: rand::RngCore + rand::CryptoRng {}
+
+/// A `CryptoRng` that gets its randomness from the platform via the `getrandom` crate. On
+/// wasm32-unknown-unknown this is the browser's `crypto.getRandomValues`, provided the
+/// `getrandom/wasm-bindgen` feature is enabled. This is only available with the `getrandom-rng`
+/// feature.
+#[cfg(feature = "getrandom-rng")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GetrandomRng;
+
+#[cfg(feature = "getrandom-rng")]
+impl rand::RngCore for GetrandomRng {
+    fn next_u32(&mut self) -> u32 {
+        let mut buf = [0u8; 4];
+        self.fill_bytes(&mut buf);
+        u32::from_le_bytes(buf)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut buf = [0u8; 8];
+        self.fill_bytes(&mut buf);
+        u64::from_le_bytes(buf)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        // There's nothing sensible to do if the platform can't give us randomness
+        self.try_fill_bytes(dest).expect("platform randomness source failed")
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        getrandom::getrandom(dest).map_err(rand::Error::new)
+    }
+}
+
+#[cfg(feature = "getrandom-rng")]
+impl rand::CryptoRng for GetrandomRng {}
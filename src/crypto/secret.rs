@@ -0,0 +1,137 @@
+//! Defines `Secret`, the buffer that node secrets, path secrets, and every secret in the key
+//! schedule are kept in. It's wiped on drop, locked into memory under the `mlock` feature (see
+//! `secure_mem`), prints as `[REDACTED; len]`, and can only be compared in constant time.
+//!
+//! `Secret` deliberately doesn't implement `Serialize`, and neither does anything holding one,
+//! `DhPrivateKey`, or `SigSecretKey`. So a struct that gets serialized can't hold private material
+//! unless the field is skipped, and leaving out the `#[serde(skip)]` is a compile error rather than
+//! a key on the wire. The one secret that does get sent, the `init_secret` in an (encrypted)
+//! `WelcomeInfo`, goes through its own wire type, `WelcomeInitSecret`. The secrets in the output of
+//! `MlsClient::save`, which never goes on the wire at all, go through `SavedSecret`.
+
+use crate::crypto::secure_mem;
+
+use subtle::ConstantTimeEq;
+
+/// A secret byte string
+// There's deliberately no PartialEq outside of tests. Use ct_eq instead.
+pub(crate) struct Secret(Vec<u8>);
+
+impl Secret {
+    /// Takes ownership of the given buffer and locks it into memory. Nothing may resize the buffer
+    /// afterwards, since it has to be unlocked from the same place on drop.
+    pub(crate) fn new_from_vec(buf: Vec<u8>) -> Secret {
+        secure_mem::lock_slice(&buf);
+        Secret(buf)
+    }
+
+    /// Copies the given bytes into a new `Secret`
+    pub(crate) fn new_from_bytes(bytes: &[u8]) -> Secret {
+        Secret::new_from_vec(bytes.to_vec())
+    }
+
+    /// Makes a `Secret` of `len` zeros. This is what a secret should be built from if it's going to
+    /// be written in place, so that it's locked before there's anything in it.
+    pub(crate) fn new_from_zeros(len: usize) -> Secret {
+        Secret::new_from_vec(vec![0u8; len])
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub(crate) fn as_mut_bytes(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+// Clones get their own locked buffer
+impl Clone for Secret {
+    fn clone(&self) -> Secret {
+        Secret::new_from_bytes(&self.0)
+    }
+}
+
+// Erasing secrets on drop is what makes old epoch secrets, path secrets, and node secrets
+// unrecoverable once they're gone
+impl Drop for Secret {
+    fn drop(&mut self) {
+        crate::utils::zeroize(&mut self.0);
+        secure_mem::unlock_slice(&self.0);
+    }
+}
+
+// Ensure that the secret value isn't accidentally logged
+impl core::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "[REDACTED; {}]", self.0.len())
+    }
+}
+
+impl ConstantTimeEq for Secret {
+    fn ct_eq(&self, other: &Secret) -> subtle::Choice {
+        self.0.ct_eq(&other.0)
+    }
+}
+
+// Tests compare secrets with assert_eq. This is still constant-time.
+#[cfg(test)]
+impl PartialEq for Secret {
+    fn eq(&self, other: &Secret) -> bool {
+        self.ct_eq(other).into()
+    }
+}
+
+#[cfg(test)]
+impl Eq for Secret {}
+
+/// A secret as it's written by `MlsClient::save`. It's a `Secret` in memory, and an
+/// `opaque<0..255>` when serialized. Nothing else should use this.
+#[derive(Deserialize)]
+#[serde(from = "SavedSecretBytes")]
+pub(crate) struct SavedSecret(pub(crate) Secret);
+
+impl SavedSecret {
+    /// Takes ownership of the given buffer, like `Secret::new_from_vec`
+    pub(crate) fn new_from_vec(buf: Vec<u8>) -> SavedSecret {
+        SavedSecret(Secret::new_from_vec(buf))
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
+// This is what a SavedSecret deserializes as before it's put into a Secret
+#[derive(Deserialize)]
+#[serde(rename = "SavedSecret__bound_u8")]
+struct SavedSecretBytes(Vec<u8>);
+
+impl From<SavedSecretBytes> for SavedSecret {
+    fn from(bytes: SavedSecretBytes) -> SavedSecret {
+        SavedSecret::new_from_vec(bytes.0)
+    }
+}
+
+impl serde::Serialize for SavedSecret {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_newtype_struct("SavedSecret__bound_u8", self.0.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Checks that a Secret doesn't print its contents, and compares by value
+    #[test]
+    fn secret_redaction_and_equality() {
+        let secret = Secret::new_from_bytes(b"hunter2");
+        assert_eq!(format!("{:?}", secret), "[REDACTED; 7]");
+
+        let same = secret.clone();
+        let different = Secret::new_from_bytes(b"hunter3");
+        assert!(bool::from(secret.ct_eq(&same)));
+        assert!(!bool::from(secret.ct_eq(&different)));
+    }
+}
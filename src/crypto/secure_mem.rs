@@ -0,0 +1,186 @@
+//! Keeps secrets out of swap. With the `mlock` feature, the memory holding HMAC keys (which is what
+//! every epoch secret, path secret, and application secret is made of) and DH private keys is
+//! locked into RAM for as long as they're alive, using mlock(2) on Unix and VirtualLock on Windows.
+//! Without the feature, locking does nothing. Either way, these secrets are wiped when they're
+//! dropped.
+//!
+//! Locking is best-effort. The OS caps how much memory a process can lock (see RLIMIT_MEMLOCK), and
+//! when a lock fails, the secret is kept anyway, just unlocked. `lock_failed` says whether that has
+//! ever happened, so deployments that need every secret locked can check it and bail.
+
+use core::ops::{Deref, DerefMut};
+
+use zeroize::Zeroize;
+
+#[cfg(feature = "mlock")]
+mod imp {
+    use std::{
+        collections::BTreeMap,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Mutex,
+        },
+    };
+
+    // Locks are per page, and several secrets can share a page, so a page can only be unlocked once
+    // every secret on it is gone. This maps the address of every locked page to the number of
+    // secrets on it and the guard that unlocks it when dropped. The guard is None if locking
+    // failed.
+    static LOCKED_PAGES: Mutex<BTreeMap<usize, (usize, Option<region::LockGuard>)>> =
+        Mutex::new(BTreeMap::new());
+    static LOCK_FAILED: AtomicBool = AtomicBool::new(false);
+
+    /// Returns the addresses of every page that overlaps `[addr, addr + len)`
+    fn pages(addr: usize, len: usize) -> impl Iterator<Item = usize> {
+        let page_size = region::page::size();
+        let first_page = addr - (addr % page_size);
+        (first_page..addr + len).step_by(page_size)
+    }
+
+    pub(super) fn lock(addr: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        // Nothing in here can panic while holding the lock, so it can't be poisoned
+        let mut locked_pages = LOCKED_PAGES.lock().unwrap();
+        for page in pages(addr, len) {
+            let entry = locked_pages.entry(page).or_insert_with(|| {
+                let guard = region::lock(page as *const u8, region::page::size()).ok();
+                if guard.is_none() {
+                    LOCK_FAILED.store(true, Ordering::Relaxed);
+                }
+                (0, guard)
+            });
+            entry.0 += 1;
+        }
+    }
+
+    pub(super) fn unlock(addr: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        let mut locked_pages = LOCKED_PAGES.lock().unwrap();
+        for page in pages(addr, len) {
+            let last_one = match locked_pages.get_mut(&page) {
+                Some(entry) => {
+                    entry.0 -= 1;
+                    entry.0 == 0
+                }
+                None => false,
+            };
+            // Dropping the guard unlocks the page
+            if last_one {
+                locked_pages.remove(&page);
+            }
+        }
+    }
+
+    pub(super) fn lock_failed() -> bool {
+        LOCK_FAILED.load(Ordering::Relaxed)
+    }
+
+    #[cfg(test)]
+    pub(super) fn is_locked(addr: usize) -> bool {
+        let page_size = region::page::size();
+        LOCKED_PAGES.lock().unwrap().contains_key(&(addr - (addr % page_size)))
+    }
+}
+
+#[cfg(not(feature = "mlock"))]
+mod imp {
+    pub(super) fn lock(_addr: usize, _len: usize) {}
+
+    pub(super) fn unlock(_addr: usize, _len: usize) {}
+
+    pub(super) fn lock_failed() -> bool {
+        false
+    }
+}
+
+/// Returns whether this process has ever failed to lock a secret into memory. This is always
+/// `false` without the `mlock` feature, since nothing is locked at all.
+pub fn lock_failed() -> bool {
+    imp::lock_failed()
+}
+
+/// Locks the memory holding `buf` into RAM. Every call has to be matched by an `unlock_slice` on
+/// the same memory before it's freed.
+pub(crate) fn lock_slice(buf: &[u8]) {
+    imp::lock(buf.as_ptr() as usize, buf.len());
+}
+
+/// Undoes a `lock_slice` on the same memory
+pub(crate) fn unlock_slice(buf: &[u8]) {
+    imp::unlock(buf.as_ptr() as usize, buf.len());
+}
+
+/// A heap-allocated secret that's locked into RAM for as long as it's alive, and wiped when it's
+/// dropped. Note that the value is moved into the box, so whatever it was built from should be
+/// wiped by the caller.
+pub(crate) struct SecretBox<T: Zeroize>(Box<T>);
+
+impl<T: Zeroize> SecretBox<T> {
+    pub(crate) fn new(value: T) -> SecretBox<T> {
+        let boxed = Box::new(value);
+        imp::lock(&*boxed as *const T as usize, core::mem::size_of::<T>());
+        SecretBox(boxed)
+    }
+}
+
+impl<T: Zeroize> Deref for SecretBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: Zeroize> DerefMut for SecretBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+// Clones get their own locked allocation
+impl<T: Zeroize + Clone> Clone for SecretBox<T> {
+    fn clone(&self) -> SecretBox<T> {
+        SecretBox::new((*self.0).clone())
+    }
+}
+
+impl<T: Zeroize> Drop for SecretBox<T> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+        imp::unlock(&*self.0 as *const T as usize, core::mem::size_of::<T>());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Checks that a SecretBox is usable through its Deref, that its clones are independent, and
+    // that, with the mlock feature, its page stays locked exactly as long as something is on it
+    #[test]
+    fn secret_box_lifecycle() {
+        let secret = SecretBox::new([7u8; 32]);
+        let mut copy = secret.clone();
+        copy[0] = 0;
+        assert_eq!(secret[0], 7);
+        assert_eq!(copy[0], 0);
+
+        #[cfg(feature = "mlock")]
+        {
+            let addr = &*secret as *const [u8; 32] as usize;
+            assert!(imp::is_locked(addr));
+            // Other tests lock secrets too, so the page might be shared with something still alive.
+            // Lock a buffer of our own on a fresh page to check unlocking.
+            let page = vec![1u8; 3 * region::page::size()];
+            let mid = &page[region::page::size()..2 * region::page::size()];
+            lock_slice(mid);
+            assert!(imp::is_locked(mid.as_ptr() as usize));
+            unlock_slice(mid);
+            assert!(!imp::is_locked(mid.as_ptr() as usize));
+        }
+    }
+}
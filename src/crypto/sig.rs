@@ -1,16 +1,20 @@
 //! Defines `SignatureScheme` and other related digital signature-related data structures and
 //! algorithms used in MLS
 
-use crate::crypto::rng::CryptoRng;
+use crate::crypto::{pkcs8, rng::CryptoRng};
 use crate::error::Error;
 
-use ed25519_dalek::ed25519::signature::Signature as SigTrait;
 use ed25519_dalek::Verifier;
+use p384::pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey};
 
 /// The canonical instantiation of the ed25519 `SignatureScheme`. Things that use this algorithm
 /// should use `&'static` references to this.
 pub const ED25519_IMPL: SignatureScheme = SignatureScheme(&Ed25519);
 
+/// The canonical instantiation of the ECDSA-over-P384-with-SHA384 `SignatureScheme`. Things that
+/// use this algorithm should use `&'static` references to this.
+pub const ECDSA_P384_IMPL: SignatureScheme = SignatureScheme(&EcdsaP384);
+
 /// A dummy placeholder for the canonical instantiation of the ECDSA-over-P256 `SignatureScheme`
 pub(crate) const ECDSA_P256_IMPL: SignatureScheme = SignatureScheme(&DummyEcdsaP256);
 
@@ -25,6 +29,8 @@ pub struct SigPublicKeyRaw(pub(crate) Vec<u8>);
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum SigPublicKey {
     Ed25519PublicKey(ed25519_dalek::PublicKey),
+    /// An uncompressed SEC1 point that's known to be on the curve
+    EcdsaP384PublicKey(p384::EncodedPoint),
     Raw(SigPublicKeyRaw),
 }
 
@@ -32,6 +38,7 @@ impl SigPublicKey {
     pub fn as_bytes(&self) -> &[u8] {
         match self {
             SigPublicKey::Ed25519PublicKey(p) => p.as_bytes(),
+            SigPublicKey::EcdsaP384PublicKey(p) => p.as_bytes(),
             SigPublicKey::Raw(p) => p.0.as_slice(),
         }
     }
@@ -50,12 +57,33 @@ impl SigPublicKey {
     pub fn new_from_secret_key(ss: &SignatureScheme, secret_key: &SigSecretKey) -> SigPublicKey {
         ss.0.public_key_from_secret_key(secret_key)
     }
+
+    // This just passes through to `SignatureSchemeInterface::public_key_from_spki`
+    /// Creates a public key from a DER-encoded X.509 `SubjectPublicKeyInfo`, as described in
+    /// RFC 8410 for Ed25519 and RFC 5480 for ECDSA
+    ///
+    /// Returns: `Ok(public_key)` on success. If the encoding is malformed, is for a different
+    /// algorithm, or holds an invalid key, returns an `Error::SignatureError`.
+    pub fn new_from_spki(ss: &SignatureScheme, der: &[u8]) -> Result<SigPublicKey, Error> {
+        ss.0.public_key_from_spki(der)
+    }
+
+    // This just passes through to `SignatureSchemeInterface::public_key_to_spki`
+    /// Returns the DER encoding of this public key as an X.509 `SubjectPublicKeyInfo`, as
+    /// described in RFC 8410 for Ed25519 and RFC 5480 for ECDSA
+    ///
+    /// Returns: `Ok(der)` on success. Returns an `Error::SignatureError` if this key isn't a key
+    /// for `ss`.
+    pub fn to_spki(&self, ss: &SignatureScheme) -> Result<Vec<u8>, Error> {
+        ss.0.public_key_to_spki(self)
+    }
 }
 
 /// An enum of possible types for a signature scheme's secret key, depending on the underlying
 /// algorithm
 pub enum SigSecretKey {
     Ed25519SecretKey(ed25519_dalek::SecretKey),
+    EcdsaP384SecretKey(p384::ecdsa::SigningKey),
 }
 
 impl SigSecretKey {
@@ -68,16 +96,56 @@ impl SigSecretKey {
         ss.0.secret_key_from_bytes(bytes)
     }
 
-    // This just passes through to `SignatureSchemeInterface::secret_key_from_random`
     /// Generates a random key pair using the given CSPRNG
     ///
     /// Returns: `Ok(secret_key)` on success. On error, returns `Error::SignatureError` or
     /// `Error::OutOfEntropy`.
+    // The randomness is drawn here rather than in SignatureSchemeInterface, since a trait object
+    // can't have a generic method. This way, the RNG is never a trait object.
     pub fn new_from_random<R>(ss: &SignatureScheme, csprng: &mut R) -> Result<SigSecretKey, Error>
     where
         R: CryptoRng,
     {
-        ss.0.secret_key_from_random(csprng)
+        let mut key_bytes = vec![0u8; ss.0.secret_key_size()];
+        let secret_key = csprng
+            .try_fill_bytes(&mut key_bytes)
+            .map_err(|_| Error::OutOfEntropy)
+            .and_then(|_| ss.0.secret_key_from_bytes(&key_bytes));
+        crate::utils::zeroize(&mut key_bytes);
+
+        secret_key
+    }
+
+    // This just passes through to `SignatureSchemeInterface::secret_key_from_pkcs8`
+    /// Creates a key pair from a DER-encoded PKCS#8 `PrivateKeyInfo`, as described in RFC 8410 for
+    /// Ed25519 and RFC 5915 for ECDSA. This is how keys made by other tools, e.g., `openssl
+    /// genpkey -algorithm ed25519`, get in.
+    ///
+    /// Returns: `Ok(secret_key)` on success. If the encoding is malformed, is for a different
+    /// algorithm, or holds an invalid key, returns an `Error::SignatureError`.
+    pub fn new_from_pkcs8(ss: &SignatureScheme, der: &[u8]) -> Result<SigSecretKey, Error> {
+        ss.0.secret_key_from_pkcs8(der)
+    }
+
+    /// Returns the raw bytes of this secret key. This is the inverse of
+    /// `SigSecretKey::new_from_bytes`. The caller is responsible for zeroing the returned bytes
+    /// once they're done with them.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            SigSecretKey::Ed25519SecretKey(s) => s.as_bytes().to_vec(),
+            SigSecretKey::EcdsaP384SecretKey(s) => s.to_bytes().to_vec(),
+        }
+    }
+
+    // This just passes through to `SignatureSchemeInterface::secret_key_to_pkcs8`
+    /// Returns the DER encoding of this secret key as a PKCS#8 `PrivateKeyInfo`, as described in
+    /// RFC 8410 for Ed25519 and RFC 5915 for ECDSA. The caller is responsible for zeroing the
+    /// returned bytes once they're done with them.
+    ///
+    /// Returns: `Ok(der)` on success. Returns an `Error::SignatureError` if this key isn't a key
+    /// for `ss`.
+    pub fn to_pkcs8(&self, ss: &SignatureScheme) -> Result<Vec<u8>, Error> {
+        ss.0.secret_key_to_pkcs8(self)
     }
 }
 
@@ -89,6 +157,7 @@ impl Clone for SigSecretKey {
                 let inner_clone = ed25519_dalek::SecretKey::from_bytes(s.as_bytes()).unwrap();
                 SigSecretKey::Ed25519SecretKey(inner_clone)
             }
+            SigSecretKey::EcdsaP384SecretKey(s) => SigSecretKey::EcdsaP384SecretKey(s.clone()),
         }
     }
 }
@@ -113,6 +182,7 @@ pub struct SignatureRaw(pub(crate) Vec<u8>);
 #[cfg_attr(test, derive(Debug))]
 pub enum Signature {
     Ed25519Signature(ed25519_dalek::Signature),
+    EcdsaP384Signature(p384::ecdsa::Signature),
     Raw(SignatureRaw),
 }
 
@@ -121,6 +191,8 @@ impl Signature {
     pub(crate) fn as_bytes(&self) -> Vec<u8> {
         match self {
             Signature::Ed25519Signature(s) => s.to_bytes().to_vec(),
+            // ECDSA signatures go over the wire DER-encoded, as in TLS 1.3
+            Signature::EcdsaP384Signature(s) => s.to_der().as_bytes().to_vec(),
             Signature::Raw(s) => s.0.clone(),
         }
     }
@@ -175,6 +247,19 @@ impl SignatureScheme {
     ) -> Result<(), Error> {
         self.0.verify(public_key, msg, sig)
     }
+
+    // This just passes through to `SignatureSchemeInterface::is_public_key`
+    /// Returns whether the given public key is a key for this signature scheme
+    pub(crate) fn is_public_key(&self, public_key: &SigPublicKey) -> bool {
+        self.0.is_public_key(public_key)
+    }
+
+    // This just passes through to `SignatureSchemeInterface::is_key_pair`
+    /// Returns whether the given secret key is a key for this signature scheme and corresponds to
+    /// the given public key
+    pub(crate) fn is_key_pair(&self, secret: &SigSecretKey, public_key: &SigPublicKey) -> bool {
+        self.0.is_key_pair(secret, public_key)
+    }
 }
 
 impl core::fmt::Debug for SignatureScheme {
@@ -192,7 +277,8 @@ impl PartialEq for SignatureScheme {
 impl Eq for SignatureScheme {}
 
 /// A trait representing any signature scheme
-trait SignatureSchemeInterface {
+// This is Sync so that &'static SignatureSchemes, and everything holding them, are Send + Sync
+trait SignatureSchemeInterface: Sync {
     fn name(&self) -> &'static str;
 
     fn signature_from_bytes(&self, bytes: &[u8]) -> Result<Signature, Error>;
@@ -201,16 +287,28 @@ trait SignatureSchemeInterface {
 
     fn public_key_from_secret_key(&self, secret: &SigSecretKey) -> SigPublicKey;
 
+    fn secret_key_size(&self) -> usize;
+
+    // Every uniformly random string of secret_key_size() bytes must make a valid secret key. This
+    // is what SigSecretKey::new_from_random relies on.
     fn secret_key_from_bytes(&self, bytes: &[u8]) -> Result<SigSecretKey, Error>;
 
-    // This has to take a dyn CryptoRng because SignatureSchemeInterface is used as a trait object
-    // inside SignatureScheme. Trait objects can't have associated types, associated constants, or
-    // generic methods.
-    fn secret_key_from_random(&self, csprng: &mut dyn CryptoRng) -> Result<SigSecretKey, Error>;
+    fn public_key_from_spki(&self, der: &[u8]) -> Result<SigPublicKey, Error>;
+
+    fn public_key_to_spki(&self, public_key: &SigPublicKey) -> Result<Vec<u8>, Error>;
+
+    fn secret_key_from_pkcs8(&self, der: &[u8]) -> Result<SigSecretKey, Error>;
+
+    fn secret_key_to_pkcs8(&self, secret: &SigSecretKey) -> Result<Vec<u8>, Error>;
 
     fn sign(&self, secret: &SigSecretKey, msg: &[u8]) -> Signature;
 
     fn verify(&self, public_key: &SigPublicKey, msg: &[u8], sig: &Signature) -> Result<(), Error>;
+
+    // These never panic, unlike the methods above, which assume their inputs are for this scheme
+    fn is_public_key(&self, public_key: &SigPublicKey) -> bool;
+
+    fn is_key_pair(&self, secret: &SigSecretKey, public_key: &SigPublicKey) -> bool;
 }
 
 /// Represents the Ed25519 signature scheme. Notably, it implements `SignatureSchemeInterface`.
@@ -254,6 +352,11 @@ impl SignatureSchemeInterface for Ed25519 {
         SigPublicKey::Ed25519PublicKey(public_key)
     }
 
+    /// Returns the size of a secret key
+    fn secret_key_size(&self) -> usize {
+        ed25519_dalek::SECRET_KEY_LENGTH
+    }
+
     /// Creates a key pair from the provided secret key bytes
     ///
     /// Returns: `Ok(secret_key)` on success. Returns an `Error::SignatureError` iff the number of
@@ -265,16 +368,38 @@ impl SignatureSchemeInterface for Ed25519 {
         }
     }
 
-    /// Generates a random key pair using the given CSPRNG
-    ///
-    /// Returns: `Ok(secret_key)` on success. On error, returns `Error::SignatureError` or
-    /// `Error::OutOfEntropy`.
-    fn secret_key_from_random(&self, csprng: &mut dyn CryptoRng) -> Result<SigSecretKey, Error> {
-        let mut key_bytes = [0u8; 32];
-        csprng.try_fill_bytes(&mut key_bytes).map_err(|_| Error::OutOfEntropy)?;
-        let key = ed25519_dalek::SecretKey::from_bytes(&key_bytes)
-            .map_err(|_| Error::SignatureError("Could not make key from random"))?;
-        Ok(SigSecretKey::Ed25519SecretKey(key))
+    /// Creates a public key from a DER-encoded `SubjectPublicKeyInfo`, as per RFC 8410
+    fn public_key_from_spki(&self, der: &[u8]) -> Result<SigPublicKey, Error> {
+        let bytes =
+            pkcs8::decode_public_key(pkcs8::OID_ED25519, der).map_err(Error::SignatureError)?;
+        self.public_key_from_bytes(bytes)
+    }
+
+    /// Encodes the given public key as a `SubjectPublicKeyInfo`, as per RFC 8410
+    fn public_key_to_spki(&self, public_key: &SigPublicKey) -> Result<Vec<u8>, Error> {
+        match public_key {
+            SigPublicKey::Ed25519PublicKey(p) => {
+                Ok(pkcs8::encode_public_key(pkcs8::OID_ED25519, p.as_bytes()))
+            }
+            _ => Err(Error::SignatureError("Public key isn't an Ed25519 key")),
+        }
+    }
+
+    /// Creates a key pair from a DER-encoded PKCS#8 `PrivateKeyInfo`, as per RFC 8410
+    fn secret_key_from_pkcs8(&self, der: &[u8]) -> Result<SigSecretKey, Error> {
+        let bytes =
+            pkcs8::decode_private_key(pkcs8::OID_ED25519, der).map_err(Error::SignatureError)?;
+        self.secret_key_from_bytes(bytes)
+    }
+
+    /// Encodes the given secret key as a PKCS#8 `PrivateKeyInfo`, as per RFC 8410
+    fn secret_key_to_pkcs8(&self, secret: &SigSecretKey) -> Result<Vec<u8>, Error> {
+        match secret {
+            SigSecretKey::Ed25519SecretKey(s) => {
+                Ok(pkcs8::encode_private_key(pkcs8::OID_ED25519, s.as_bytes()))
+            }
+            _ => Err(Error::SignatureError("Secret key isn't an Ed25519 key")),
+        }
     }
 
     /// Computes a signature of the given message under the given secret key
@@ -286,7 +411,7 @@ impl SignatureSchemeInterface for Ed25519 {
         let public_key: ed25519_dalek::PublicKey = secret.into();
         let expanded_secret: ed25519_dalek::ExpandedSecretKey = secret.into();
 
-        Signature::Ed25519Signature(expanded_secret.sign(&msg, &public_key))
+        Signature::Ed25519Signature(expanded_secret.sign(msg, &public_key))
     }
 
     /// Verifies the signature of the given message under the given public key
@@ -300,7 +425,174 @@ impl SignatureSchemeInterface for Ed25519 {
 
         // Don't worry, it's okay to say "bad signature" for signature schemes, since this
         // function does not depend on any private information, there is nothing to leak.
-        public_key.verify(msg, &sig).map_err(|_| Error::SignatureError("Bad signature"))
+        public_key.verify(msg, sig).map_err(|_| Error::SignatureError("Bad signature"))
+    }
+
+    /// Returns whether the given public key is an Ed25519 public key
+    fn is_public_key(&self, public_key: &SigPublicKey) -> bool {
+        matches!(public_key, SigPublicKey::Ed25519PublicKey(_))
+    }
+
+    /// Returns whether the given secret key is an Ed25519 secret key whose public key is the given
+    /// public key
+    fn is_key_pair(&self, secret: &SigSecretKey, public_key: &SigPublicKey) -> bool {
+        match (secret, public_key) {
+            (SigSecretKey::Ed25519SecretKey(s), SigPublicKey::Ed25519PublicKey(p)) => {
+                ed25519_dalek::PublicKey::from(s) == *p
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Represents ECDSA over the NIST P-384 curve with SHA-384, which is `ecdsa_secp384r1_sha384` in
+/// TLS 1.3. Notably, it implements `SignatureSchemeInterface`.
+pub struct EcdsaP384;
+
+impl SignatureSchemeInterface for EcdsaP384 {
+    /// Returns the signature scheme's name, as per the MLS spec. Here, it is
+    /// `ecdsa_secp384r1_sha384`
+    fn name(&self) -> &'static str {
+        "ecdsa_secp384r1_sha384"
+    }
+
+    /// Creates a signature from the provided DER-encoded `ECDSA-Sig-Value`
+    ///
+    /// Returns: `Ok(signature)` on success. If anything goes wrong, returns an
+    /// `Error::SignatureError`.
+    fn signature_from_bytes(&self, bytes: &[u8]) -> Result<Signature, Error> {
+        match p384::ecdsa::Signature::from_der(bytes) {
+            Ok(sig) => Ok(Signature::EcdsaP384Signature(sig)),
+            Err(_) => Err(Error::SignatureError("Invalid signature bytes")),
+        }
+    }
+
+    /// Creates a public key from the provided uncompressed SEC1 point
+    ///
+    /// Returns: `Ok(public_key)` on success. If the bytes aren't an uncompressed point on the
+    /// curve, returns an `Error::SignatureError`.
+    fn public_key_from_bytes(&self, bytes: &[u8]) -> Result<SigPublicKey, Error> {
+        // TLS 1.3 only allows the uncompressed form. Parsing it as a verifying key makes sure
+        // it's actually on the curve.
+        let encoded_point = p384::EncodedPoint::from_bytes(bytes)
+            .map_err(|_| Error::SignatureError("Invalid public key bytes"))?;
+        if encoded_point.is_compressed() || encoded_point.is_identity() {
+            return Err(Error::SignatureError("P384 ECDSA public key isn't uncompressed"));
+        }
+        p384::ecdsa::VerifyingKey::from_encoded_point(&encoded_point)
+            .map_err(|_| Error::SignatureError("Invalid public key bytes"))?;
+
+        Ok(SigPublicKey::EcdsaP384PublicKey(encoded_point))
+    }
+
+    /// Derives the public key corresponding to the given secret key
+    fn public_key_from_secret_key(&self, secret: &SigSecretKey) -> SigPublicKey {
+        let secret = enum_variant!(secret, SigSecretKey::EcdsaP384SecretKey);
+        SigPublicKey::EcdsaP384PublicKey(secret.verifying_key().to_encoded_point(false))
+    }
+
+    /// Returns the size of a secret key, i.e., the size of a scalar
+    fn secret_key_size(&self) -> usize {
+        48
+    }
+
+    /// Creates a key pair from the provided big-endian scalar
+    ///
+    /// Returns: `Ok(secret_key)` on success. Returns an `Error::SignatureError` if the bytes
+    /// aren't a nonzero scalar less than the order of the curve.
+    // A uniformly random 48-byte string fails this with probability about 2^-190, so it's fine
+    // for SigSecretKey::new_from_random to rely on it
+    fn secret_key_from_bytes(&self, bytes: &[u8]) -> Result<SigSecretKey, Error> {
+        if bytes.len() != self.secret_key_size() {
+            return Err(Error::SignatureError("P384 ECDSA secret key isn't 48 bytes long"));
+        }
+        match p384::ecdsa::SigningKey::from_slice(bytes) {
+            Ok(secret) => Ok(SigSecretKey::EcdsaP384SecretKey(secret)),
+            Err(_) => Err(Error::SignatureError("Invalid secret key")),
+        }
+    }
+
+    /// Creates a public key from a DER-encoded `SubjectPublicKeyInfo`, as per RFC 5480
+    fn public_key_from_spki(&self, der: &[u8]) -> Result<SigPublicKey, Error> {
+        let public_key = p384::ecdsa::VerifyingKey::from_public_key_der(der)
+            .map_err(|_| Error::SignatureError("Invalid P384 SubjectPublicKeyInfo"))?;
+        Ok(SigPublicKey::EcdsaP384PublicKey(public_key.to_encoded_point(false)))
+    }
+
+    /// Encodes the given public key as a `SubjectPublicKeyInfo`, as per RFC 5480
+    fn public_key_to_spki(&self, public_key: &SigPublicKey) -> Result<Vec<u8>, Error> {
+        let encoded_point = match public_key {
+            SigPublicKey::EcdsaP384PublicKey(p) => p,
+            _ => return Err(Error::SignatureError("Public key isn't a P384 ECDSA key")),
+        };
+        p384::ecdsa::VerifyingKey::from_encoded_point(encoded_point)
+            .ok()
+            .and_then(|p| p.to_public_key_der().ok())
+            .map(|der| der.as_bytes().to_vec())
+            .ok_or(Error::SignatureError("Couldn't encode P384 SubjectPublicKeyInfo"))
+    }
+
+    /// Creates a key pair from a DER-encoded PKCS#8 `PrivateKeyInfo`, as per RFC 5915
+    fn secret_key_from_pkcs8(&self, der: &[u8]) -> Result<SigSecretKey, Error> {
+        match p384::ecdsa::SigningKey::from_pkcs8_der(der) {
+            Ok(secret) => Ok(SigSecretKey::EcdsaP384SecretKey(secret)),
+            Err(_) => Err(Error::SignatureError("Invalid P384 PrivateKeyInfo")),
+        }
+    }
+
+    /// Encodes the given secret key as a PKCS#8 `PrivateKeyInfo`, as per RFC 5915
+    fn secret_key_to_pkcs8(&self, secret: &SigSecretKey) -> Result<Vec<u8>, Error> {
+        let secret = match secret {
+            SigSecretKey::EcdsaP384SecretKey(s) => s,
+            _ => return Err(Error::SignatureError("Secret key isn't a P384 ECDSA key")),
+        };
+        // The document zeroes itself when it's dropped. The copy is the caller's to zero.
+        secret
+            .to_pkcs8_der()
+            .map(|der| der.as_bytes().to_vec())
+            .map_err(|_| Error::SignatureError("Couldn't encode P384 PrivateKeyInfo"))
+    }
+
+    /// Computes a signature of the given message under the given secret key. The nonce is derived
+    /// deterministically from the key and message, as per RFC 6979, so no RNG is needed.
+    fn sign(&self, secret: &SigSecretKey, msg: &[u8]) -> Signature {
+        let secret = enum_variant!(secret, SigSecretKey::EcdsaP384SecretKey);
+        let sig: p384::ecdsa::Signature = p384::ecdsa::signature::Signer::sign(secret, msg);
+        Signature::EcdsaP384Signature(sig)
+    }
+
+    /// Verifies the signature of the given message under the given public key
+    ///
+    /// Returns: `Ok(())` iff the signature succeeded. Otherwise, returns an
+    /// `Err(Error::SignatureError)`.
+    fn verify(&self, public_key: &SigPublicKey, msg: &[u8], sig: &Signature) -> Result<(), Error> {
+        let (encoded_point, sig) = match (public_key, sig) {
+            (SigPublicKey::EcdsaP384PublicKey(p), Signature::EcdsaP384Signature(s)) => (p, s),
+            _ => return Err(Error::SignatureError("Key or signature isn't for P384 ECDSA")),
+        };
+        // This can't fail, since EcdsaP384PublicKeys are checked to be on the curve when they're
+        // made. But it doesn't hurt to not panic.
+        let public_key = p384::ecdsa::VerifyingKey::from_encoded_point(encoded_point)
+            .map_err(|_| Error::SignatureError("Invalid public key"))?;
+
+        p384::ecdsa::signature::Verifier::verify(&public_key, msg, sig)
+            .map_err(|_| Error::SignatureError("Bad signature"))
+    }
+
+    /// Returns whether the given public key is a P384 ECDSA public key
+    fn is_public_key(&self, public_key: &SigPublicKey) -> bool {
+        matches!(public_key, SigPublicKey::EcdsaP384PublicKey(_))
+    }
+
+    /// Returns whether the given secret key is a P384 ECDSA secret key whose public key is the
+    /// given public key
+    fn is_key_pair(&self, secret: &SigSecretKey, public_key: &SigPublicKey) -> bool {
+        match (secret, public_key) {
+            (SigSecretKey::EcdsaP384SecretKey(s), SigPublicKey::EcdsaP384PublicKey(p)) => {
+                s.verifying_key().to_encoded_point(false) == *p
+            }
+            _ => false,
+        }
     }
 }
 
@@ -333,11 +625,27 @@ impl SignatureSchemeInterface for DummyEcdsaP256 {
         unimplemented!()
     }
 
+    fn secret_key_size(&self) -> usize {
+        32
+    }
+
     fn secret_key_from_bytes(&self, _bytes: &[u8]) -> Result<SigSecretKey, Error> {
         unimplemented!()
     }
 
-    fn secret_key_from_random(&self, _csprng: &mut dyn CryptoRng) -> Result<SigSecretKey, Error> {
+    fn public_key_from_spki(&self, _der: &[u8]) -> Result<SigPublicKey, Error> {
+        unimplemented!()
+    }
+
+    fn public_key_to_spki(&self, _public_key: &SigPublicKey) -> Result<Vec<u8>, Error> {
+        unimplemented!()
+    }
+
+    fn secret_key_from_pkcs8(&self, _der: &[u8]) -> Result<SigSecretKey, Error> {
+        unimplemented!()
+    }
+
+    fn secret_key_to_pkcs8(&self, _secret: &SigSecretKey) -> Result<Vec<u8>, Error> {
         unimplemented!()
     }
 
@@ -353,6 +661,18 @@ impl SignatureSchemeInterface for DummyEcdsaP256 {
     ) -> Result<(), Error> {
         unimplemented!()
     }
+
+    fn is_public_key(&self, public_key: &SigPublicKey) -> bool {
+        match public_key {
+            SigPublicKey::Raw(p) => p.0.len() == 65,
+            _ => false,
+        }
+    }
+
+    // There are no secret keys for this scheme, so nothing is a key pair
+    fn is_key_pair(&self, _secret: &SigSecretKey, _public_key: &SigPublicKey) -> bool {
+        false
+    }
 }
 
 #[cfg(test)]
@@ -432,4 +752,101 @@ mod test {
         // Make sure the signature we just made is valid
         assert!(ss.verify(&public_key, &msg, &sig).is_ok());
     }
+
+    // Checks that keys survive a trip through PKCS#8 and SubjectPublicKeyInfo, and that the
+    // imported secret key signs the same as the original
+    #[quickcheck]
+    fn ed25519_pkcs8_round_trip(msg: Vec<u8>, rng_seed: u64) {
+        let ss: &'static SignatureScheme = &ED25519_IMPL;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+
+        let secret_key = SigSecretKey::new_from_random(ss, &mut rng).unwrap();
+        let public_key = SigPublicKey::new_from_secret_key(ss, &secret_key);
+
+        let imported_secret_key =
+            SigSecretKey::new_from_pkcs8(ss, &secret_key.to_pkcs8(ss).unwrap()).unwrap();
+        assert_eq!(imported_secret_key.to_bytes(), secret_key.to_bytes());
+        let imported_public_key =
+            SigPublicKey::new_from_spki(ss, &public_key.to_spki(ss).unwrap()).unwrap();
+        assert_eq!(imported_public_key, public_key);
+
+        let sig = ss.sign(&imported_secret_key, &msg);
+        assert!(ss.verify(&imported_public_key, &msg, &sig).is_ok());
+
+        // An X25519 key is not an Ed25519 key
+        let dh_key = crate::crypto::pkcs8::encode_private_key(
+            crate::crypto::pkcs8::OID_X25519,
+            &secret_key.to_bytes(),
+        );
+        match SigSecretKey::new_from_pkcs8(ss, &dh_key) {
+            Err(Error::SignatureError(_)) => (),
+            _ => panic!("imported an X25519 key as an Ed25519 key"),
+        }
+    }
+
+    // Test vector is from https://tools.ietf.org/html/rfc6979#appendix-A.2.6, with the message
+    // "sample" and SHA-384. The signature there is given as (r, s), so this is its DER encoding.
+    #[test]
+    fn ecdsa_p384_kat() {
+        let ss: &'static SignatureScheme = &ECDSA_P384_IMPL;
+
+        let secret = {
+            let bytes = hex::decode(
+                "6b9d3dad2e1b8c1c05b19875b6659f4de23c3b667bf297ba9aa47740787137d896d5724e4c70a825f8\
+                 72c9ea60d2edf5",
+            )
+            .unwrap();
+            SigSecretKey::new_from_bytes(ss, &bytes).unwrap()
+        };
+        // This is 0x04 || Ux || Uy
+        let expected_public = hex::decode(
+            "04ec3a4e415b4e19a4568618029f427fa5da9a8bc4ae92e02e06aae5286b300c64def8f0ea9055866064a2\
+             54515480bc138015d9b72d7d57244ea8ef9ac0c621896708a59367f9dfb9f54ca84b3f1c9db1288b231c3a\
+             e0d4fe7344fd2533264720",
+        )
+        .unwrap();
+        let derived_public = SigPublicKey::new_from_secret_key(ss, &secret);
+        assert_eq!(derived_public.as_bytes(), expected_public.as_slice());
+
+        let expected_sig = hex::decode(
+            "306602310094edbb92a5ecb8aad4736e56c691916b3f88140666ce9fa73d64c4ea95ad133c81a648152e44\
+             acf96e36dd1e80fabe4602310099ef4aeb15f178cea1fe40db2603138f130e740a19624526203b6351d0a3\
+             a94fa329c145786e679e7b82c71a38628ac8",
+        )
+        .unwrap();
+        let derived_sig = ss.sign(&secret, b"sample");
+        assert_eq!(derived_sig.as_bytes(), expected_sig);
+    }
+
+    // Signs a random message with a random P-384 key, then checks that the signature survives the
+    // trip over the wire, and that the keys survive a trip through PKCS#8 and SubjectPublicKeyInfo
+    #[quickcheck]
+    fn ecdsa_p384_correctness(msg: Vec<u8>, rng_seed: u64) {
+        let ss: &'static SignatureScheme = &ECDSA_P384_IMPL;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+
+        let secret_key = SigSecretKey::new_from_random(ss, &mut rng).unwrap();
+        let public_key = SigPublicKey::new_from_secret_key(ss, &secret_key);
+
+        // This is what upcasting does to a signature that came over the wire
+        let sig = ss.sign(&secret_key, &msg);
+        let received_sig = Signature::new_from_bytes(ss, &sig.as_bytes()).unwrap();
+        let received_public_key = SigPublicKey::new_from_bytes(ss, public_key.as_bytes()).unwrap();
+        assert!(ss.verify(&received_public_key, &msg, &received_sig).is_ok());
+
+        // A signature over something else doesn't verify
+        let other_sig = ss.sign(&secret_key, &[msg.as_slice(), b"!"].concat());
+        assert!(ss.verify(&public_key, &msg, &other_sig).is_err());
+
+        let imported_secret_key =
+            SigSecretKey::new_from_pkcs8(ss, &secret_key.to_pkcs8(ss).unwrap()).unwrap();
+        assert_eq!(imported_secret_key.to_bytes(), secret_key.to_bytes());
+        let imported_public_key =
+            SigPublicKey::new_from_spki(ss, &public_key.to_spki(ss).unwrap()).unwrap();
+        assert_eq!(imported_public_key, public_key);
+
+        // Ed25519 keys aren't P-384 keys
+        let ed25519_key = SigSecretKey::new_from_random(&ED25519_IMPL, &mut rng).unwrap();
+        assert!(ed25519_key.to_pkcs8(ss).is_err());
+    }
 }
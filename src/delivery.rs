@@ -0,0 +1,24 @@
+//! Defines `DeliveryService`, which is how a `Session` gets back handshakes it missed. Handshakes
+//! have to be applied in epoch order, so a member that never got one is stuck at that epoch. How
+//! handshakes are stored and sent is up to the application, so this is just the lookup half. See
+//! `Session::handle_handshake_with_recovery` and `MlsClient::receive_with_recovery`.
+
+use crate::{error::Error, handshake::Handshake};
+
+/// A place where a group's past handshakes can be looked up by epoch, e.g., the server that orders
+/// and relays them
+pub trait DeliveryService {
+    /// Fetches the handshakes of the group with ID `group_id` whose prior epochs are in the range
+    /// from `start_epoch` up to, but not including, `end_epoch`. They should be in epoch order,
+    /// one per epoch. The result doesn't have to be checked in any way, since the caller applies
+    /// each one like any other handshake.
+    ///
+    /// Returns: `Ok(handshakes)` on success. Otherwise returns whatever error the implementation
+    /// sees fit, e.g., an `Error::ValidationError` if it no longer has handshakes that old.
+    fn fetch_handshakes(
+        &self,
+        group_id: &[u8],
+        start_epoch: u32,
+        end_epoch: u32,
+    ) -> Result<Vec<Handshake>, Error>;
+}
@@ -0,0 +1,196 @@
+//! Comparing two snapshots of the same group. `diff_group_states` says what changed between them in
+//! terms of members and keys rather than bytes. This is meant for audit tooling, and for tests that
+//! want to check that an operation did exactly what it was supposed to and nothing else.
+
+use crate::{
+    credential::{Credential, RosterIndex},
+    error::Error,
+    group_state::GroupState,
+    tree_math::LeafIndex,
+};
+
+/// The semantic changes between two snapshots of a group. Members are referred to by roster index.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GroupStateDiff {
+    /// How many epochs the later snapshot is ahead of the earlier one. This is negative if the
+    /// "later" snapshot is actually older.
+    pub epoch_delta: i64,
+    /// Members in the later snapshot who weren't in the earlier one, with their credentials
+    pub added_members: Vec<(RosterIndex, Credential)>,
+    /// Members in the earlier snapshot who aren't in the later one, with their credentials
+    pub removed_members: Vec<(RosterIndex, Credential)>,
+    /// Members in both snapshots whose credential changed but whose identity didn't
+    pub updated_credentials: Vec<RosterIndex>,
+    /// Members in both snapshots whose leaf public key changed
+    pub rekeyed_leaves: Vec<RosterIndex>,
+    /// Whether the public part of the ratchet tree changed at all
+    pub tree_hash_changed: bool,
+    /// Whether the transcript hash changed, i.e., whether any operation was applied in between
+    pub transcript_hash_changed: bool,
+}
+
+impl GroupStateDiff {
+    /// Returns whether the two snapshots are the same in every way this diff can see
+    pub fn is_empty(&self) -> bool {
+        self.epoch_delta == 0
+            && self.added_members.is_empty()
+            && self.removed_members.is_empty()
+            && self.updated_credentials.is_empty()
+            && self.rekeyed_leaves.is_empty()
+            && !self.tree_hash_changed
+            && !self.transcript_hash_changed
+    }
+}
+
+/// Compares two snapshots of the same group. They don't have to be from the same member's point of
+/// view, since only public state is compared. A roster entry whose identity changed counts as a
+/// removal followed by an addition at the same roster index.
+///
+/// Returns: `Ok(diff)` on success. Returns an `Error::ValidationError` if the snapshots are of
+/// groups with different IDs or ciphersuites, and an `Error::SerdeError` if a tree can't be
+/// serialized for hashing.
+pub fn diff_group_states(before: &GroupState, after: &GroupState) -> Result<GroupStateDiff, Error> {
+    if before.group_id != after.group_id {
+        return Err(Error::ValidationError("Cannot diff snapshots of different groups"));
+    }
+    if before.cs != after.cs {
+        return Err(Error::ValidationError("Cannot diff snapshots with different ciphersuites"));
+    }
+
+    let mut added_members = Vec::new();
+    let mut removed_members = Vec::new();
+    let mut updated_credentials = Vec::new();
+    let mut rekeyed_leaves = Vec::new();
+
+    // Rosters can grow and shrink, so walk the longer one and treat missing entries as empty
+    let roster_len = core::cmp::max(before.roster.len(), after.roster.len());
+    for i in 0..roster_len {
+        // Roster indices always fit in a u32
+        let roster_index = RosterIndex(i as u32);
+        let old_entry = before.roster.0.get(i).and_then(Option::as_ref);
+        let new_entry = after.roster.0.get(i).and_then(Option::as_ref);
+
+        match (old_entry, new_entry) {
+            (None, None) => (),
+            (None, Some(new_cred)) => added_members.push((roster_index, new_cred.clone())),
+            (Some(old_cred), None) => removed_members.push((roster_index, old_cred.clone())),
+            (Some(old_cred), Some(new_cred)) => {
+                if old_cred.get_identity() != new_cred.get_identity() {
+                    removed_members.push((roster_index, old_cred.clone()));
+                    added_members.push((roster_index, new_cred.clone()));
+                    continue;
+                }
+                if old_cred != new_cred {
+                    updated_credentials.push(roster_index);
+                }
+
+                let leaf_idx = LeafIndex(i).node_index();
+                let old_key = before.tree.get(leaf_idx).and_then(|node| node.get_public_key());
+                let new_key = after.tree.get(leaf_idx).and_then(|node| node.get_public_key());
+                if old_key.map(|k| k.as_bytes()) != new_key.map(|k| k.as_bytes()) {
+                    rekeyed_leaves.push(roster_index);
+                }
+            }
+        }
+    }
+
+    // The tree hash only covers the public part of the tree
+    let old_tree_hash = before.tree.tree_hash(before.cs)?;
+    let new_tree_hash = after.tree.tree_hash(after.cs)?;
+
+    Ok(GroupStateDiff {
+        // Epochs wrap around, so the difference is taken mod 2^32 and read as signed. That's right
+        // as long as the snapshots are less than 2^31 epochs apart.
+        epoch_delta: i64::from(after.epoch.wrapping_sub(before.epoch) as i32),
+        added_members,
+        removed_members,
+        updated_credentials,
+        rekeyed_leaves,
+        tree_hash_changed: old_tree_hash.as_bytes() != new_tree_hash.as_bytes(),
+        transcript_hash_changed: before.transcript_hash.as_bytes()
+            != after.transcript_hash.as_bytes(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        crypto::{
+            ciphersuite::X25519_SHA256_AES128GCM,
+            sig::{SigSecretKey, ED25519_IMPL},
+        },
+        ratchet_tree::PathSecret,
+        test_utils,
+    };
+
+    use quickcheck_macros::quickcheck;
+    use rand::SeedableRng;
+
+    // Does an Update, an Add, and a Remove, and checks that each one's diff says exactly what it
+    // did
+    #[quickcheck]
+    fn diff_correctness(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state, _) = test_utils::random_full_group_state(2, &mut rng);
+        let cs = group_state.cs;
+        let my_roster_index = group_state.roster_index.unwrap();
+
+        // A snapshot has no changes from itself
+        assert!(diff_group_states(&group_state, &group_state).unwrap().is_empty());
+
+        // An Update rekeys our leaf and nothing else about the roster
+        let path_secret = PathSecret::new_from_random(cs, &mut rng);
+        let (_, updated_group_state, _) =
+            group_state.create_and_apply_update_handshake(path_secret, &mut rng).unwrap();
+        let diff = diff_group_states(&group_state, &updated_group_state).unwrap();
+        assert_eq!(diff.epoch_delta, 1);
+        assert!(diff.added_members.is_empty() && diff.removed_members.is_empty());
+        assert!(diff.updated_credentials.is_empty());
+        assert_eq!(diff.rekeyed_leaves, vec![my_roster_index]);
+        assert!(diff.tree_hash_changed && diff.transcript_hash_changed);
+        // And going backwards is the same thing in reverse
+        let reverse_diff = diff_group_states(&updated_group_state, &group_state).unwrap();
+        assert_eq!(reverse_diff.epoch_delta, -1);
+
+        // An Add adds exactly the new member
+        let (new_cred, new_identity_key): (Credential, SigSecretKey) =
+            Credential::new_basic_from_random("newbie".into(), &ED25519_IMPL, &mut rng).unwrap();
+        let init_key = test_utils::make_init_key(
+            &new_identity_key,
+            new_cred.clone(),
+            b"newbie key",
+            &X25519_SHA256_AES128GCM,
+            &mut rng,
+        );
+        let new_roster_index = RosterIndex(group_state.roster.len() as u32);
+        let welcome_info_hash = group_state.welcome_info_hash().unwrap();
+        let (_, added_group_state, _) = group_state
+            .create_and_apply_add_handshake(new_roster_index, init_key, &welcome_info_hash)
+            .unwrap();
+        let diff = diff_group_states(&group_state, &added_group_state).unwrap();
+        assert_eq!(diff.epoch_delta, 1);
+        assert_eq!(diff.added_members, vec![(new_roster_index, new_cred)]);
+        assert!(diff.removed_members.is_empty() && diff.rekeyed_leaves.is_empty());
+
+        // A Remove removes exactly the removed member. Everyone on the removed member's path is
+        // rekeyed, but that's parents, not leaves.
+        let removed_roster_index = test_utils::random_roster_index_with_exceptions(
+            group_state.roster.len(),
+            &[my_roster_index.0 as usize],
+            &mut rng,
+        );
+        let removed_cred = group_state.roster.0[removed_roster_index.0 as usize].clone().unwrap();
+        let path_secret = PathSecret::new_from_random(cs, &mut rng);
+        let (_, removed_group_state, _) = group_state
+            .create_and_apply_remove_handshake(removed_roster_index, path_secret, &mut rng)
+            .unwrap();
+        let diff = diff_group_states(&group_state, &removed_group_state).unwrap();
+        assert_eq!(diff.removed_members, vec![(removed_roster_index, removed_cred)]);
+        assert!(diff.added_members.is_empty() && diff.rekeyed_leaves.is_empty());
+
+        // Snapshots of different groups can't be compared
+        let (other_group_state, _) = test_utils::random_full_group_state(1, &mut rng);
+        assert!(diff_group_states(&group_state, &other_group_state).is_err());
+    }
+}
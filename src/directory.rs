@@ -0,0 +1,243 @@
+//! Defines `UserInitKeyDirectory`, which is how the crate looks up the `UserInitKey` of someone
+//! who's being added by identity. Where `UserInitKey`s are published is up to the application, so
+//! this is just the lookup half. See `Session::add_member_by_identity` and
+//! `SharedGroup::add_member_by_identity`. `InitKeyIdRegistry` is for the publishing half, to keep
+//! each client's `UserInitKey` IDs unique.
+
+use crate::{
+    credential::{Identity, RosterIndex},
+    crypto::dh::DhPublicKey,
+    error::Error,
+    group_state::GroupState,
+    handshake::UserInitKey,
+};
+
+use std::collections::BTreeMap;
+
+/// A place where users' `UserInitKey`s can be looked up by identity, e.g., a key server
+pub trait UserInitKeyDirectory {
+    /// Fetches a `UserInitKey` published by the user with the given identity. The result doesn't
+    /// have to be checked in any way, since the caller does that.
+    ///
+    /// Returns: `Ok(init_key)` on success. Otherwise returns whatever error the implementation sees
+    /// fit, e.g., an `Error::ValidationError` if there's no such user.
+    fn fetch_init_key(&self, identity: &Identity) -> Result<UserInitKey, Error>;
+}
+
+/// Keeps track of the `UserInitKey` IDs each client has used. A client's IDs have to be unique
+/// among its own `UserInitKey`s, since that's how a `Welcome` says which one it's for. IDs are
+/// only compared within one identity, so two clients can use the same ID.
+#[derive(Clone, Debug, Default)]
+pub struct InitKeyIdRegistry {
+    // Maps (identity, user_init_key_id) to the init keys of the UserInitKey seen with that ID.
+    // Seeing the same UserInitKey again is fine. Seeing a different one is not.
+    #[allow(clippy::type_complexity)]
+    seen: BTreeMap<(Vec<u8>, Vec<u8>), Vec<Vec<u8>>>,
+}
+
+impl InitKeyIdRegistry {
+    /// Makes an empty registry
+    pub fn new() -> InitKeyIdRegistry {
+        InitKeyIdRegistry::default()
+    }
+
+    /// Records `init_key` as having been published or used by the client whose credential is in
+    /// it. A directory should call this before accepting a `UserInitKey` for publication, and
+    /// anyone keeping a registry should call it before using one.
+    ///
+    /// Returns: `Ok(())` on success, including when this exact `UserInitKey` was seen before.
+    /// Returns an `Error::DuplicateInitKeyId` if the same client already had a different
+    /// `UserInitKey` with the same ID.
+    pub fn observe(&mut self, init_key: &UserInitKey) -> Result<(), Error> {
+        let identity = init_key.credential.get_identity().0.clone();
+        let user_init_key_id = init_key.user_init_key_id.clone();
+        let init_keys: Vec<Vec<u8>> =
+            init_key.init_keys.iter().map(DhPublicKey::as_bytes).map(<[u8]>::to_vec).collect();
+
+        match self.seen.get(&(identity.clone(), user_init_key_id.clone())) {
+            Some(seen_init_keys) if seen_init_keys != &init_keys => {
+                Err(Error::DuplicateInitKeyId(user_init_key_id))
+            }
+            Some(_) => Ok(()),
+            None => {
+                self.seen.insert((identity, user_init_key_id), init_keys);
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns whether the client with the given identity has a `UserInitKey` with the given ID in
+    /// this registry
+    pub fn contains(&self, identity: &Identity, user_init_key_id: &[u8]) -> bool {
+        self.seen.contains_key(&(identity.0.clone(), user_init_key_id.to_vec()))
+    }
+}
+
+/// Fetches the `UserInitKey` of the user with the given identity and checks that it can be used to
+/// add them to the given group. The key must be validly signed by the credential inside it, the
+/// credential must have the identity we asked for, the key must support the group's ciphersuite
+/// and protocol version, and the user can't already be a member.
+///
+/// Returns: `Ok((roster_index, init_key))` on success, where `roster_index` is the roster index an
+/// `Add` for this user should use. Returns an `Error::ValidationError` if the key is for someone
+/// else or they're already in the group, and an `Error::NoCompatibleInitKey` if the key doesn't
+/// support the group's ciphersuite and protocol version. Otherwise returns whatever the directory
+/// or the `UserInitKey` validity checks return.
+pub(crate) fn fetch_init_key_for_add<D>(
+    directory: &D,
+    identity: &Identity,
+    group_state: &GroupState,
+) -> Result<(RosterIndex, UserInitKey), Error>
+where
+    D: UserInitKeyDirectory + ?Sized,
+{
+    if group_state.find_member(&identity.0).is_some() {
+        return Err(Error::ValidationError("User with this identity is already a group member"));
+    }
+
+    let init_key = directory.fetch_init_key(identity)?;
+
+    // A directory is just somewhere to look things up. It doesn't vouch for anything, so we check
+    // everything the Add would check, plus that the key is actually for who we asked about.
+    init_key.verify_sig()?;
+    init_key.validate()?;
+    if init_key.credential.get_identity() != identity {
+        return Err(Error::ValidationError("Directory returned a UserInitKey for someone else"));
+    }
+    init_key.get_compatible_public_key(group_state.cs, group_state.protocol_version)?;
+
+    let roster_index = group_state.roster.next_add_index()?;
+    Ok((roster_index, init_key))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        credential::Credential,
+        crypto::{
+            ciphersuite::X25519_SHA256_AES128GCM,
+            rng::CryptoRng,
+            sig::{SigSecretKey, ED25519_IMPL},
+        },
+        handshake::{ProtocolVersion, MLS_DUMMY_VERSION},
+        session::Session,
+        test_utils,
+    };
+
+    use quickcheck_macros::quickcheck;
+    use rand::SeedableRng;
+
+    // A directory that's just a list of published keys
+    struct ListDirectory(Vec<(Identity, UserInitKey)>);
+
+    impl UserInitKeyDirectory for ListDirectory {
+        fn fetch_init_key(&self, identity: &Identity) -> Result<UserInitKey, Error> {
+            self.0
+                .iter()
+                .find(|(id, _)| id == identity)
+                .map(|(_, init_key)| init_key.clone())
+                .ok_or(Error::ValidationError("No such user in directory"))
+        }
+    }
+
+    // Makes a credential with the given identity and a UserInitKey for it. The ciphersuite is
+    // always X25519_SHA256_AES128GCM, since it's the only one we can generate keys for.
+    fn make_init_key<R: CryptoRng>(identity: &str, rng: &mut R) -> (UserInitKey, SigSecretKey) {
+        let (cred, identity_key): (Credential, SigSecretKey) =
+            Credential::new_basic_from_random(identity.into(), &ED25519_IMPL, rng).unwrap();
+        let init_key = test_utils::make_init_key(
+            &identity_key,
+            cred,
+            b"key id",
+            &X25519_SHA256_AES128GCM,
+            rng,
+        );
+        (init_key, identity_key)
+    }
+
+    // Adds someone by identity and checks that they can join with the resulting Welcome, and that
+    // bad directory entries are refused
+    #[quickcheck]
+    fn add_by_identity(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        // This group's ciphersuite is X25519_SHA256_AES128GCM
+        let (group_state, _) = test_utils::random_full_group_state(1, &mut rng);
+        let existing_identity =
+            group_state.roster.credential_iter().next().unwrap().get_identity().clone();
+        let mut session = Session::new(group_state, None);
+
+        let (alice_init_key, alice_identity_key) = make_init_key("alice@example.com", &mut rng);
+        let (bob_init_key, _) = make_init_key("bob@example.com", &mut rng);
+        // Carol's key is for a protocol version nobody else speaks
+        let (carol_cred, carol_identity_key) =
+            Credential::new_basic_from_random("carol@example.com".into(), &ED25519_IMPL, &mut rng)
+                .unwrap();
+        let carol_init_key = UserInitKey::new_from_random(
+            &carol_identity_key,
+            b"key id".to_vec(),
+            carol_cred,
+            vec![&X25519_SHA256_AES128GCM],
+            vec![ProtocolVersion(MLS_DUMMY_VERSION.0.wrapping_add(1))],
+            &mut rng,
+        )
+        .unwrap();
+        let directory = ListDirectory(vec![
+            (Identity::from("alice@example.com"), alice_init_key.clone()),
+            // Mallory's entry is really Bob's key
+            (Identity::from("mallory@example.com"), bob_init_key),
+            (Identity::from("carol@example.com"), carol_init_key),
+        ]);
+
+        // Mallory's key is for someone else
+        match session.add_member_by_identity(&directory, &"mallory@example.com".into(), &mut rng) {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("added a member with someone else's UserInitKey"),
+        }
+        // Carol's key doesn't support our protocol version
+        match session.add_member_by_identity(&directory, &"carol@example.com".into(), &mut rng) {
+            Err(Error::NoCompatibleInitKey(_)) => (),
+            _ => panic!("added a member with an incompatible UserInitKey"),
+        }
+        // Dave isn't in the directory at all
+        assert!(session
+            .add_member_by_identity(&directory, &"dave@example.com".into(), &mut rng)
+            .is_err());
+        // And existing members can't be added again
+        assert!(session.add_member_by_identity(&directory, &existing_identity, &mut rng).is_err());
+
+        // Alice can be added, and she ends up where everyone else thinks she is
+        let (welcome, handshake) = session
+            .add_member_by_identity(&directory, &"alice@example.com".into(), &mut rng)
+            .unwrap();
+        let alice_group_state =
+            GroupState::from_welcome(welcome, alice_identity_key, alice_init_key).unwrap();
+        let (alice_group_state, _) = alice_group_state.process_handshake(&handshake).unwrap();
+        assert_serialized_eq!(alice_group_state, *session.group_state());
+    }
+
+    // Checks that a registry refuses a second UserInitKey with an ID that its client already used,
+    // but not the same key twice, or the same ID from another client
+    #[quickcheck]
+    fn init_key_id_registry(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        // These all have the same ID
+        let (alice_init_key, _) = make_init_key("alice@example.com", &mut rng);
+        let (other_alice_init_key, _) = make_init_key("alice@example.com", &mut rng);
+        let (bob_init_key, _) = make_init_key("bob@example.com", &mut rng);
+
+        let mut registry = InitKeyIdRegistry::new();
+        assert!(!registry.contains(&"alice@example.com".into(), b"key id"));
+        registry.observe(&alice_init_key).unwrap();
+        registry.observe(&alice_init_key).unwrap();
+        registry.observe(&bob_init_key).unwrap();
+        assert!(registry.contains(&"alice@example.com".into(), b"key id"));
+        assert!(registry.contains(&"bob@example.com".into(), b"key id"));
+
+        match registry.observe(&other_alice_init_key) {
+            Err(Error::DuplicateInitKeyId(id)) => assert_eq!(id, b"key id"),
+            Err(e) => panic!("duplicate init key ID gave the wrong error: {}", e),
+            Ok(_) => panic!("registry accepted a duplicate init key ID"),
+        }
+    }
+}
@@ -1,5 +1,7 @@
 //! Defines `Error`, which we use to represent anything that goes wrong in this crate
 
+use crate::{credential::RosterIndex, tree_math::NodeIndex};
+
 /// An error type for anything that goes wrong in this crate
 #[derive(Debug)]
 pub enum Error {
@@ -15,18 +17,149 @@ pub enum Error {
     SerdeError(std::io::Error),
     /// For errors encountered during upcasting
     UpcastError(&'static str),
-    /// For errors concerning ratchet tree operations
-    TreeError(&'static str),
+    /// For errors concerning ratchet tree operations. Contains where in the tree it happened.
+    TreeError(TreeErrorContext),
     /// For errors concerning invalid data structures
     ValidationError(&'static str),
     /// For when we need randomness and there's none left
     OutOfEntropy,
     /// For when we've been removed from a group
     IAmRemoved,
+    /// For when this member is still in a group's roster, but its own state for the group can't be
+    /// used anymore, e.g., because its leaf's private key is gone. It has to be re-added. See
+    /// `GroupState::check_own_state`.
+    StateLost,
+    /// For when an application message has already been decrypted
+    ReplayedMessage,
+    /// For when this member has sent as many application messages in this epoch as their
+    /// `GroupConfig` allows. They have to make an `Update` before sending any more.
+    UpdateRequired,
+    /// For when a `UserInitKey` has no init key for the group's ciphersuite and protocol version.
+    /// Contains the names of the ciphersuites the `UserInitKey` does support.
+    NoCompatibleInitKey(Vec<&'static str>),
+    /// For when a client has two different `UserInitKey`s with the same ID. Contains the ID. See
+    /// `InitKeyIdRegistry`.
+    DuplicateInitKeyId(Vec<u8>),
+    /// For when a `Welcome` can't be used to join its group. Says what was wrong with it.
+    InvalidWelcome(WelcomeError),
+    /// For when a `Handshake`'s operation can't have come from its signer, or is for someone who
+    /// isn't there. Says what was wrong with it.
+    InvalidOperation(OperationError),
+    /// For when an `Add` is for someone who's already in the group. Contains the roster index
+    /// they're already at. See `DuplicateIdentityPolicy`.
+    DuplicateMember(RosterIndex),
+}
+
+/// What was wrong with a `Welcome`. See `GroupState::from_welcome`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WelcomeError {
+    /// The `Welcome` is for a `UserInitKey` other than the one given, or the given one isn't one
+    /// we made, since it has no private keys
+    UnknownInitKey,
+    /// The `UserInitKey` has no key for the ciphersuite the `Welcome` says it's encrypted under
+    UnsupportedCipherSuite,
+    /// The `WelcomeInfo`'s transcript hash or init secret isn't the size that the `Welcome`'s
+    /// ciphersuite calls for, so the group isn't using that ciphersuite
+    CipherSuiteMismatch,
+    /// The `WelcomeInfo`'s protocol version isn't the one the `UserInitKey` supports for the
+    /// `Welcome`'s ciphersuite
+    ProtocolVersionMismatch,
+    /// The `WelcomeInfo`'s group ID is empty
+    EmptyGroupId,
+    /// The `WelcomeInfo`'s epoch is at its maximum, so the `Add` that follows it can't be applied
+    EpochExhausted,
+}
+
+impl std::convert::From<WelcomeError> for Error {
+    fn from(err: WelcomeError) -> Error {
+        Error::InvalidWelcome(err)
+    }
+}
+
+/// What was wrong with who a `Handshake`'s operation is from or who it's for. See
+/// `GroupState::process_handshake`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OperationError {
+    /// The signer index is past the end of the roster
+    SignerOutOfBounds,
+    /// The signer index is an empty roster entry, so nobody there could have signed anything
+    SignerNotMember,
+    /// The signer is in the roster, but their leaf in the ratchet tree is blank
+    SignerLeafBlank,
+    /// The `Update` or `CredentialUpdate`'s direct path isn't shaped like the one for the
+    /// signer's leaf, so it's an update of some other leaf
+    PathFromWrongLeaf,
+    /// The `Remove` or `Replace`'s target is past the end of the roster
+    RemoveTargetOutOfBounds,
+    /// The `Remove` or `Replace`'s target is an empty roster entry, so there's nobody to remove
+    RemoveTargetNotMember,
+    /// The `BatchRemove`'s targets are empty, out of order, or repeated, or the targets of a
+    /// `BatchRemove` or `Replace` include the signer
+    RemoveTargetsMalformed,
+    /// The group's `AuthorizationPolicy` doesn't let the signer make this operation
+    NotAuthorized,
+}
+
+impl std::convert::From<OperationError> for Error {
+    fn from(err: OperationError) -> Error {
+        Error::InvalidOperation(err)
+    }
+}
+
+/// What went wrong in a ratchet tree operation, and where. This is meant for debugging, e.g.,
+/// figuring out why another implementation's message doesn't fit our tree.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TreeErrorContext {
+    /// What went wrong
+    pub msg: &'static str,
+    /// The tree operation that failed, e.g., `"decrypt_direct_path_message"`
+    pub operation: &'static str,
+    /// The number of nodes in the tree when the operation failed
+    pub tree_size: usize,
+    /// The nodes involved, each with a short description of its role, e.g., `("copath node", 5)`
+    pub nodes: Vec<(&'static str, NodeIndex)>,
+}
+
+impl TreeErrorContext {
+    /// Makes a context with no nodes in it
+    pub(crate) fn new(
+        msg: &'static str,
+        operation: &'static str,
+        tree_size: usize,
+    ) -> TreeErrorContext {
+        TreeErrorContext {
+            msg,
+            operation,
+            tree_size,
+            nodes: Vec::new(),
+        }
+    }
+
+    /// Adds a node to the context
+    pub(crate) fn with_node(mut self, role: &'static str, idx: NodeIndex) -> TreeErrorContext {
+        self.nodes.push((role, idx));
+        self
+    }
+}
+
+impl std::fmt::Display for TreeErrorContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        write!(f, "{} (in {}, tree size {}", self.msg, self.operation, self.tree_size)?;
+        for (role, idx) in self.nodes.iter() {
+            write!(f, ", {} {}", role, idx.0)?;
+        }
+        f.write_str(")")
+    }
+}
+
+impl std::convert::From<TreeErrorContext> for Error {
+    fn from(ctx: TreeErrorContext) -> Error {
+        Error::TreeError(ctx)
+    }
 }
 
 // The only IO done in molasses is via serde, so this is a natural conversion
-impl<'a> std::convert::From<std::io::Error> for Error {
+impl std::convert::From<std::io::Error> for Error {
     fn from(other: std::io::Error) -> Error {
         crate::error::Error::SerdeError(other)
     }
@@ -38,20 +171,21 @@ impl std::error::Error for Error {}
 // Serde also requires that any Serializer's error type implement std::fmt::Display
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
-        f.write_str(&self.to_string())
+        // to_string goes through Display, so it can't be used here
+        write!(f, "{:?}", self)
     }
 }
 
 // serde requires that any Serializer's error type implement serde::ser::Error
 impl serde::ser::Error for Error {
     fn custom<T: std::fmt::Display>(msg: T) -> Self {
-        Error::SerdeError(std::io::Error::new(std::io::ErrorKind::Other, format!("{}", msg)))
+        Error::SerdeError(std::io::Error::other(format!("{}", msg)))
     }
 }
 
 // serde requires that any Deserializer's error type implement serde::de::Error
 impl serde::de::Error for Error {
     fn custom<T: std::fmt::Display>(msg: T) -> Self {
-        Error::SerdeError(std::io::Error::new(std::io::ErrorKind::Other, format!("{}", msg)))
+        Error::SerdeError(std::io::Error::other(format!("{}", msg)))
     }
 }
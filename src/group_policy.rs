@@ -0,0 +1,483 @@
+//! Defines `GroupPolicy`, a document of a group's rules that's signed by the member who set it and
+//! held by every member as a group extension. It's replaced with a `PolicyUpdate` operation, which
+//! goes into the transcript hash like any other, so every member agrees on which policy is in
+//! force in every epoch. Only the policy's admins can replace it. See
+//! `GroupState::create_and_apply_policy_update_handshake`.
+
+use crate::{
+    authorization::AuthorizationPolicy,
+    credential::{Identity, Roster, RosterIndex},
+    crypto::sig::Signature,
+    error::Error,
+    extensions::{self, ExtensionList, ExtensionType, KnownExtension},
+    group_state::GroupState,
+    tls_ser,
+};
+
+/// Who may add new members under a `GroupPolicy`
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename = "JoinRule__enum_u8")]
+pub enum JoinRule {
+    /// Any member may add new members
+    #[default]
+    Open,
+    /// Only the policy's admins may add new members
+    AdminsOnly,
+}
+
+/// The rules of a group. Every `GroupState` enforces these on the `Handshake`s it makes and
+/// processes, whatever its `AuthorizationPolicy` is.
+// struct {
+//     uint32 version;
+//     Identity admins<0..2^32-1>;
+//     JoinRule join_rule;
+// } GroupPolicy;
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct GroupPolicy {
+    /// A counter that every new policy has to increase
+    pub version: u32,
+    /// The identities of the members who may replace this policy. These are identities rather
+    /// than roster indices so that an admin's rights leave with them, rather than passing to
+    /// whoever is added at their roster index next.
+    #[serde(rename = "admins__bound_u32")]
+    pub admins: Vec<Identity>,
+    /// Who may add new members
+    pub join_rule: JoinRule,
+}
+
+impl GroupPolicy {
+    /// Returns whether `identity` is one of this policy's admins
+    pub fn is_admin(&self, identity: &Identity) -> bool {
+        self.admins.contains(identity)
+    }
+
+    /// Returns whether the member with identity `adder` may add new members to the group
+    pub fn may_add(&self, adder: &Identity) -> bool {
+        self.join_rule == JoinRule::Open || self.is_admin(adder)
+    }
+}
+
+/// A `GroupPolicy`, along with who set it and their signature over it. This is the group
+/// extension every member holds. The signature binds the policy to the group and the epoch it was
+/// set in. It's checked when the `PolicyUpdate` that carries it is applied. Members who join later
+/// take it on trust, along with the rest of their `WelcomeInfo`.
+// struct {
+//     GroupPolicy policy;
+//     uint32 signer_index;
+//     opaque signature<0..2^16-1>;
+// } SignedGroupPolicy;
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SignedGroupPolicy {
+    pub(crate) policy: GroupPolicy,
+    pub(crate) signer_index: RosterIndex,
+    // This is kept as bytes rather than a Signature so that it can be parsed without knowing the
+    // signer's signature scheme
+    #[serde(rename = "signature__bound_u16")]
+    pub(crate) signature: Vec<u8>,
+}
+
+impl KnownExtension for SignedGroupPolicy {
+    const EXTENSION_TYPE: ExtensionType = extensions::SIGNED_GROUP_POLICY;
+}
+
+// struct {
+//     opaque group_id<0..255>;
+//     uint32 prior_epoch;
+//     uint32 signer_index;
+//     GroupPolicy policy;
+// } PolicySignatureContent;
+/// What a `SignedGroupPolicy`'s signature is computed over
+#[derive(Serialize)]
+struct PolicySignatureContent<'a> {
+    #[serde(rename = "group_id__bound_u8")]
+    group_id: &'a [u8],
+    prior_epoch: u32,
+    signer_index: RosterIndex,
+    policy: &'a GroupPolicy,
+}
+
+impl SignedGroupPolicy {
+    /// Signs `policy` as this member of `group_state`, for a `PolicyUpdate` sent in its current
+    /// epoch
+    ///
+    /// Returns: `Ok(signed_policy)` on success. Returns an `Error::ValidationError` if
+    /// `group_state` is preliminary, and an `Error::SerdeError` if the policy can't be serialized.
+    pub(crate) fn new(
+        group_state: &GroupState,
+        policy: GroupPolicy,
+    ) -> Result<SignedGroupPolicy, Error> {
+        let signer_index = group_state.roster_index.ok_or(Error::ValidationError(
+            "Cannot sign a GroupPolicy from a preliminary GroupState",
+        ))?;
+        let content = PolicySignatureContent {
+            group_id: &group_state.group_id,
+            prior_epoch: group_state.epoch,
+            signer_index,
+            policy: &policy,
+        };
+        let serialized_content = tls_ser::serialize_to_bytes(&content)?;
+        let signature = group_state.sign_with_identity_key(&serialized_content)?;
+
+        Ok(SignedGroupPolicy {
+            policy,
+            signer_index,
+            signature: signature.as_bytes(),
+        })
+    }
+
+    /// Returns the policy
+    pub fn get_policy(&self) -> &GroupPolicy {
+        &self.policy
+    }
+
+    /// Returns the roster index of the member who set the policy, as of when they set it
+    pub fn get_signer_index(&self) -> RosterIndex {
+        self.signer_index
+    }
+
+    /// Checks that this policy can replace the one in `extensions`, if there is one, in a
+    /// `PolicyUpdate` sent by the member at `handshake_signer_index` in epoch `prior_epoch` of the
+    /// group with ID `group_id` and roster `roster`. That is, it has to be signed by the sender,
+    /// the sender has to be an admin of the current policy, its version has to be higher than the
+    /// current one's, and its admins all have to be members.
+    ///
+    /// Returns: `Ok(())` on success. Returns an `Error::SignatureError` if the signature doesn't
+    /// verify, and an `Error::ValidationError` if anything else is wrong.
+    pub(crate) fn validate_replacement(
+        &self,
+        extensions: &ExtensionList,
+        roster: &Roster,
+        group_id: &[u8],
+        prior_epoch: u32,
+        handshake_signer_index: RosterIndex,
+    ) -> Result<(), Error> {
+        if self.signer_index != handshake_signer_index {
+            return Err(Error::ValidationError(
+                "GroupPolicy isn't signed by the Handshake's sender",
+            ));
+        }
+        let signer_credential = roster
+            .get(self.signer_index)
+            .ok_or(Error::ValidationError("GroupPolicy's signer isn't a member"))?;
+
+        if let Some(current) = extensions.get::<SignedGroupPolicy>()? {
+            if !current.policy.is_admin(signer_credential.get_identity()) {
+                return Err(Error::ValidationError("Only an admin can replace the GroupPolicy"));
+            }
+            if self.policy.version <= current.policy.version {
+                return Err(Error::ValidationError(
+                    "GroupPolicy's version isn't higher than the current one's",
+                ));
+            }
+        }
+        let is_member =
+            |admin: &Identity| roster.credential_iter().any(|cred| cred.get_identity() == admin);
+        if !self.policy.admins.iter().all(is_member) {
+            return Err(Error::ValidationError("GroupPolicy names an admin who isn't a member"));
+        }
+
+        let content = PolicySignatureContent {
+            group_id,
+            prior_epoch,
+            signer_index: self.signer_index,
+            policy: &self.policy,
+        };
+        let serialized_content = tls_ser::serialize_to_bytes(&content)?;
+        let signature =
+            Signature::new_from_bytes(signer_credential.get_signature_scheme(), &self.signature)?;
+        signer_credential.verify(&serialized_content, &signature)
+    }
+}
+
+/// The `AuthorizationPolicy` that extends the group's `GroupPolicy` to removals. Whenever there's a
+/// policy, only its admins may remove members. A group without a policy allows everything. The
+/// policy's `JoinRule` is enforced by every `GroupState`, so this doesn't need to check it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FollowGroupPolicy;
+
+impl AuthorizationPolicy for FollowGroupPolicy {
+    fn may_remove(
+        &self,
+        group_state: &GroupState,
+        remover: RosterIndex,
+        _removed: RosterIndex,
+    ) -> bool {
+        let remover_identity = match group_state.roster.get(remover) {
+            Some(cred) => cred.get_identity(),
+            None => return false,
+        };
+        match group_state.get_group_policy() {
+            Ok(Some(signed_policy)) => signed_policy.get_policy().is_admin(remover_identity),
+            Ok(None) => true,
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        error::OperationError, group_state::Welcome, ratchet_tree::PathSecret, test_utils,
+    };
+
+    use quickcheck_macros::quickcheck;
+    use rand::SeedableRng;
+    use std::sync::Arc;
+
+    // Returns the identity of the member at the given roster index
+    fn identity_at(group_state: &GroupState, roster_index: u32) -> Identity {
+        group_state.roster.get(RosterIndex(roster_index)).unwrap().get_identity().clone()
+    }
+
+    // Has member 0 set a policy naming themselves admin and checks that everyone agrees on it.
+    // Then checks that a non-admin can't replace it and that the version has to go up.
+    #[quickcheck]
+    fn policy_update_correctness(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state, identity_keys) = test_utils::random_full_group_state(3, &mut rng);
+        let admin_state =
+            test_utils::change_self_index(&group_state, &identity_keys, RosterIndex(0));
+        let member_state =
+            test_utils::change_self_index(&group_state, &identity_keys, RosterIndex(1));
+        assert_eq!(admin_state.get_group_policy().unwrap(), None);
+
+        let policy = GroupPolicy {
+            version: 1,
+            admins: vec![identity_at(&group_state, 0)],
+            join_rule: JoinRule::AdminsOnly,
+        };
+        let (handshake, admin_state, _) =
+            admin_state.create_and_apply_policy_update_handshake(policy.clone()).unwrap();
+        let (member_state, _) = member_state.process_handshake(&handshake).unwrap();
+
+        let admin_view = admin_state.get_group_policy().unwrap().unwrap();
+        let member_view = member_state.get_group_policy().unwrap().unwrap();
+        assert_eq!(admin_view, member_view);
+        assert_eq!(member_view.get_policy(), &policy);
+        assert_eq!(member_view.get_signer_index(), RosterIndex(0));
+        assert_eq!(admin_state.transcript_hash.as_bytes(), member_state.transcript_hash.as_bytes());
+
+        // Member 1 isn't an admin, so nobody accepts a policy from them. Make the policy on a
+        // state that has forgotten the current one, so that it gets past the creator's check.
+        let mut rogue_state = member_state.clone();
+        rogue_state.extensions = ExtensionList::new();
+        let rogue_policy = GroupPolicy {
+            version: 2,
+            admins: vec![identity_at(&group_state, 1)],
+            ..policy.clone()
+        };
+        let (rogue_handshake, _, _) =
+            rogue_state.create_and_apply_policy_update_handshake(rogue_policy.clone()).unwrap();
+        assert!(admin_state.process_handshake(&rogue_handshake).is_err());
+        assert!(member_state.create_and_apply_policy_update_handshake(rogue_policy).is_err());
+
+        // The admin can't reuse a version number
+        assert!(admin_state.create_and_apply_policy_update_handshake(policy.clone()).is_err());
+
+        // The admin can hand the group over to member 1, who can then change the policy
+        let handover = GroupPolicy {
+            version: 2,
+            admins: vec![identity_at(&group_state, 1)],
+            ..policy.clone()
+        };
+        let (handshake, _, _) =
+            admin_state.create_and_apply_policy_update_handshake(handover).unwrap();
+        let (member_state, _) = member_state.process_handshake(&handshake).unwrap();
+        let opened = GroupPolicy {
+            version: 3,
+            admins: vec![identity_at(&group_state, 1)],
+            join_rule: JoinRule::Open,
+        };
+        member_state.create_and_apply_policy_update_handshake(opened).unwrap();
+    }
+
+    // Checks that a signed policy doesn't verify for another group or epoch, or with a bad
+    // signature, and that a policy can't name an admin who isn't in the group
+    #[quickcheck]
+    fn policy_signature_binding(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state, _) = test_utils::random_full_group_state(2, &mut rng);
+        let signer_index = group_state.roster_index.unwrap();
+        let policy = GroupPolicy {
+            version: 1,
+            admins: vec![identity_at(&group_state, signer_index.0)],
+            ..GroupPolicy::default()
+        };
+        let signed_policy = SignedGroupPolicy::new(&group_state, policy).unwrap();
+
+        let validate = |signed_policy: &SignedGroupPolicy, group_id: &[u8], epoch: u32| {
+            signed_policy.validate_replacement(
+                &group_state.extensions,
+                &group_state.roster,
+                group_id,
+                epoch,
+                signer_index,
+            )
+        };
+        validate(&signed_policy, &group_state.group_id, group_state.epoch).unwrap();
+        assert!(validate(&signed_policy, b"some other group", group_state.epoch).is_err());
+        assert!(validate(&signed_policy, &group_state.group_id, group_state.epoch.wrapping_add(1))
+            .is_err());
+
+        let mut tampered = signed_policy.clone();
+        tampered.policy.join_rule = JoinRule::AdminsOnly;
+        assert!(validate(&tampered, &group_state.group_id, group_state.epoch).is_err());
+
+        let absent_admin = GroupPolicy {
+            version: 1,
+            admins: vec![Identity::from("nobody in this group")],
+            ..GroupPolicy::default()
+        };
+        let signed_policy = SignedGroupPolicy::new(&group_state, absent_admin).unwrap();
+        assert!(validate(&signed_policy, &group_state.group_id, group_state.epoch).is_err());
+    }
+
+    // Checks that under JoinRule::AdminsOnly, a member who isn't an admin can't make an Add or a
+    // Replace, and that nobody processes one from them either, whatever their
+    // AuthorizationPolicy is
+    #[quickcheck]
+    fn join_rule_enforced(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state, identity_keys) = test_utils::random_full_group_state(3, &mut rng);
+        let admin_state =
+            test_utils::change_self_index(&group_state, &identity_keys, RosterIndex(0));
+        let member_state =
+            test_utils::change_self_index(&group_state, &identity_keys, RosterIndex(1));
+
+        let policy = GroupPolicy {
+            version: 1,
+            admins: vec![identity_at(&group_state, 0)],
+            join_rule: JoinRule::AdminsOnly,
+        };
+        let (handshake, admin_state, _) =
+            admin_state.create_and_apply_policy_update_handshake(policy).unwrap();
+        let (member_state, _) = member_state.process_handshake(&handshake).unwrap();
+
+        let (init_key, _) =
+            test_utils::random_init_key(b"group policy test", group_state.cs, &mut rng);
+        let new_roster_index = RosterIndex(group_state.roster.len() as u32);
+        let welcome_info_hash = member_state.welcome_info_hash().unwrap();
+        match member_state.create_and_apply_add_handshake(
+            new_roster_index,
+            init_key.clone(),
+            &welcome_info_hash,
+        ) {
+            Err(Error::InvalidOperation(OperationError::NotAuthorized)) => (),
+            _ => panic!("a member who isn't an admin added someone"),
+        }
+        let path_secret = PathSecret::new_from_random(group_state.cs, &mut rng);
+        match member_state.create_and_apply_replace_handshake(
+            RosterIndex(2),
+            init_key.clone(),
+            &welcome_info_hash,
+            path_secret,
+            &mut rng,
+        ) {
+            Err(Error::InvalidOperation(OperationError::NotAuthorized)) => (),
+            _ => panic!("a member who isn't an admin replaced someone"),
+        }
+
+        // An Add made by a member who has forgotten the policy is refused by everyone else
+        let mut rogue_state = member_state.clone();
+        rogue_state.extensions = ExtensionList::new();
+        let rogue_welcome_info_hash = rogue_state.welcome_info_hash().unwrap();
+        let (rogue_add, _, _) = rogue_state
+            .create_and_apply_add_handshake(
+                new_roster_index,
+                init_key.clone(),
+                &rogue_welcome_info_hash,
+            )
+            .unwrap();
+        match admin_state.process_handshake(&rogue_add) {
+            Err(Error::InvalidOperation(OperationError::NotAuthorized)) => (),
+            _ => panic!("an Add from a member who isn't an admin was processed"),
+        }
+
+        let welcome_info_hash = admin_state.welcome_info_hash().unwrap();
+        let (add, _, _) = admin_state
+            .create_and_apply_add_handshake(new_roster_index, init_key, &welcome_info_hash)
+            .unwrap();
+        member_state.process_handshake(&add).unwrap();
+    }
+
+    // Removes the admin, adds someone new at the admin's old roster index, and checks that the
+    // newcomer didn't inherit the admin's rights
+    #[quickcheck]
+    fn admin_rights_leave_with_admin(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state, identity_keys) = test_utils::random_full_group_state(2, &mut rng);
+        let admin_state =
+            test_utils::change_self_index(&group_state, &identity_keys, RosterIndex(0));
+        let member_state =
+            test_utils::change_self_index(&group_state, &identity_keys, RosterIndex(1));
+
+        let policy = GroupPolicy {
+            version: 1,
+            admins: vec![identity_at(&group_state, 0)],
+            join_rule: JoinRule::Open,
+        };
+        let (handshake, _, _) =
+            admin_state.create_and_apply_policy_update_handshake(policy.clone()).unwrap();
+        let (member_state, _) = member_state.process_handshake(&handshake).unwrap();
+
+        let path_secret = PathSecret::new_from_random(group_state.cs, &mut rng);
+        let (_, member_state, _) = member_state
+            .create_and_apply_remove_handshake(RosterIndex(0), path_secret, &mut rng)
+            .unwrap();
+        let (init_key, newcomer_key) =
+            test_utils::random_init_key(b"newcomer", group_state.cs, &mut rng);
+        let (welcome, welcome_info_hash) =
+            Welcome::from_group_state(&member_state, &init_key, &mut rng).unwrap();
+        let (add, member_state, _) = member_state
+            .create_and_apply_add_handshake(RosterIndex(0), init_key.clone(), &welcome_info_hash)
+            .unwrap();
+        let newcomer_state = GroupState::from_welcome(welcome, newcomer_key, init_key).unwrap();
+        let (newcomer_state, _) = newcomer_state.process_handshake(&add).unwrap();
+        assert_eq!(newcomer_state.roster_index, Some(RosterIndex(0)));
+
+        let takeover = GroupPolicy {
+            version: 2,
+            admins: vec![identity_at(&member_state, 0)],
+            ..policy
+        };
+        assert!(newcomer_state.create_and_apply_policy_update_handshake(takeover).is_err());
+    }
+
+    // Checks that FollowGroupPolicy only lets admins remove members
+    #[quickcheck]
+    fn follow_group_policy(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state, identity_keys) = test_utils::random_full_group_state(3, &mut rng);
+        let mut admin_state =
+            test_utils::change_self_index(&group_state, &identity_keys, RosterIndex(0));
+        let mut member_state =
+            test_utils::change_self_index(&group_state, &identity_keys, RosterIndex(1));
+        admin_state.set_authorization_policy(Arc::new(FollowGroupPolicy));
+        member_state.set_authorization_policy(Arc::new(FollowGroupPolicy));
+
+        let policy = GroupPolicy {
+            version: 1,
+            admins: vec![identity_at(&group_state, 0)],
+            join_rule: JoinRule::Open,
+        };
+        let (handshake, admin_state, _) =
+            admin_state.create_and_apply_policy_update_handshake(policy).unwrap();
+        let (member_state, _) = member_state.process_handshake(&handshake).unwrap();
+
+        let path_secret = PathSecret::new_from_random(group_state.cs, &mut rng);
+        match member_state.create_and_apply_remove_handshake(
+            RosterIndex(2),
+            path_secret.clone(),
+            &mut rng,
+        ) {
+            Err(Error::InvalidOperation(OperationError::NotAuthorized)) => (),
+            _ => panic!("a member who isn't an admin removed someone"),
+        }
+
+        let (remove, _, _) = admin_state
+            .create_and_apply_remove_handshake(RosterIndex(2), path_secret, &mut rng)
+            .unwrap();
+        member_state.process_handshake(&remove).unwrap();
+    }
+}
@@ -2,28 +2,42 @@
 //! group operations
 
 use crate::{
-    application::ApplicationKeyChain,
-    credential::{Credential, Roster},
+    application::{ApplicationKeyChain, PaddingScheme},
+    authorization::{AuthorizationPolicy, Role, RosterRoles},
+    config::{DuplicateIdentityPolicy, GroupConfig, PathEncryption},
+    credential::{Credential, MemberIndex, Roster, RosterIndex},
     crypto::{
         ciphersuite::CipherSuite,
-        dh::DhPrivateKey,
-        ecies::{self, EciesCiphertext},
-        hash::Digest,
+        dh::{DhPrivateKey, DhPublicKey},
+        ecies::{self, EciesCiphertext, EciesStreamOpener, EciesStreamSealer},
+        hash::{Digest, HashFunction},
         hkdf,
         hmac::{self, HmacKey},
         rng::CryptoRng,
-        sig::{SigSecretKey, SignatureScheme},
+        secret::{SavedSecret, Secret},
+        sig::{SigPublicKey, SigSecretKey, Signature},
     },
-    error::Error,
+    error::{Error, OperationError, WelcomeError},
+    extensions::ExtensionList,
+    group_policy::{GroupPolicy, SignedGroupPolicy},
     handshake::{
-        GroupAdd, GroupOperation, GroupRemove, GroupUpdate, Handshake, ProtocolVersion, UserInitKey,
+        DirectPathMessage, GroupAdd, GroupBatchRemove, GroupCredentialUpdate, GroupInit,
+        GroupOperation, GroupPolicyUpdate, GroupRemove, GroupReplace, GroupUpdate, Handshake,
+        HandshakeSignatureContent, ProtocolVersion, SavedUserInitKey, UserInitKey,
     },
+    keystore::{IdentityKey, KeyStore},
+    metrics::{self, OperationKind},
+    observer::GroupObserver,
     ratchet_tree::{NodeSecret, PathSecret, RatchetTree, RatchetTreeNode},
     tls_de::TlsDeserializer,
     tls_ser,
-    upcast::{CryptoCtx, CryptoUpcast},
+    tree_math::{LeafIndex, NodeIndex},
+    upcast::{self, CryptoCtx, CryptoUpcast},
 };
 
+use core::convert::TryFrom;
+use std::{borrow::Cow, sync::Arc};
+
 use serde::de::Deserialize;
 use subtle::ConstantTimeEq;
 
@@ -62,27 +76,90 @@ impl From<ConfirmationKey> for HmacKey {
 }
 
 /// This is called the `update_secret` in the MLS key schedule. It's used to derive epoch secrets
-/// in `update_epoch_secrets`.
-pub(crate) struct UpdateSecret(Vec<u8>);
+/// in `derive_epoch_secrets`.
+pub(crate) struct UpdateSecret(pub(crate) Secret);
 
 impl UpdateSecret {
     fn as_bytes(&self) -> &[u8] {
-        &self.0
+        self.0.as_bytes()
     }
 
     // The update secret is all zeros after Add operations
     fn new_from_zeros(num_zeros: usize) -> UpdateSecret {
-        UpdateSecret(vec![0u8; num_zeros])
+        UpdateSecret(Secret::new_from_zeros(num_zeros))
     }
 }
 
-// NodeSecret --> UpdateSecret by rewrapping the underlying vectors
+// NodeSecret --> UpdateSecret trivially
 impl From<NodeSecret> for UpdateSecret {
     fn from(n: NodeSecret) -> UpdateSecret {
         UpdateSecret(n.0)
     }
 }
 
+/// Everything one epoch of the MLS key schedule derives. See `derive_epoch_secrets`.
+pub(crate) struct EpochSecrets {
+    /// The intermediate secret that the rest are derived from. Only tests and the `inspect`
+    /// module look at this.
+    #[cfg_attr(not(feature = "dangerous-debug"), allow(dead_code))]
+    pub(crate) epoch_secret: HmacKey,
+    /// The secret the epoch's `ApplicationKeyChain` is made from
+    pub(crate) application_secret: ApplicationSecret,
+    /// The key that `Handshake` confirmations are computed under
+    pub(crate) confirmation_key: ConfirmationKey,
+    /// The init secret that the next epoch's key schedule starts from
+    pub(crate) init_secret: HmacKey,
+}
+
+/// Runs one epoch of the key schedule, as per the "Key Schedule" section of the spec. The previous
+/// epoch's init secret, the update secret, and the group context that the secrets are bound to are
+/// all passed in, so the output depends on nothing else. In the protocol, the group context is the
+/// `GroupState` of the new epoch. Test vectors supply their own.
+///
+/// Returns: `Ok(epoch_secrets)` on success, and an `Error::SerdeError` if `group_context` can't be
+/// serialized
+pub(crate) fn derive_epoch_secrets<S: serde::Serialize>(
+    hash_impl: &HashFunction,
+    prior_init_secret: &HmacKey,
+    update_secret: &UpdateSecret,
+    group_context: &S,
+) -> Result<EpochSecrets, Error> {
+    // epoch_secret = HKDF-Extract(salt=init_secret_[n-1] (or 0), ikm=update_secret)
+    let epoch_secret = hkdf::extract(hash_impl, prior_init_secret, update_secret.as_bytes());
+
+    // Every secret below is derived from the same context, so only hash it once
+    let hashed_ctx = hash_impl.hash_serializable(group_context)?;
+    let derive_secret = |label: &[u8]| {
+        hkdf::derive_secret_from_hashed_context(hash_impl, &epoch_secret, label, &hashed_ctx)
+    };
+
+    // init_secret_[n] = Derive-Secret(epoch_secret, "init", GroupState_[n])
+    let init_secret = derive_secret(b"init");
+
+    // application_secret = Derive-Secret(epoch_secret, "app", GroupState_[n])
+    let application_secret = derive_secret(b"app");
+
+    // confirmation_key = Derive-Secret(epoch_secret, "confirm", GroupState_[n])
+    let confirmation_key = derive_secret(b"confirm");
+
+    Ok(EpochSecrets {
+        epoch_secret,
+        application_secret: application_secret.into(),
+        confirmation_key: confirmation_key.into(),
+        init_secret,
+    })
+}
+
+// Serializes a shared field of a GroupState as the value it points to. serde only does this for
+// Arcs itself when its rc feature is on.
+fn serialize_shared<T, S>(value: &Arc<T>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: serde::Serialize,
+    S: serde::Serializer,
+{
+    value.as_ref().serialize(serializer)
+}
+
 /// Contains all group state
 #[derive(Clone, Serialize)]
 pub struct GroupState {
@@ -95,9 +172,10 @@ pub struct GroupState {
     #[serde(skip)]
     pub(crate) protocol_version: ProtocolVersion,
 
-    /// This member's long-lived signing key, used to authenticate the sender of a message
+    /// This member's long-lived signing key, used to authenticate the sender of a message. This
+    /// is either held here or in a `KeyStore`.
     #[serde(skip)]
-    pub(crate) identity_key: SigSecretKey,
+    pub(crate) identity_key: IdentityKey,
 
     // opaque group_id<0..255>;
     /// An application-defined identifier for the group
@@ -109,9 +187,11 @@ pub struct GroupState {
 
     // optional<Credential> roster<1..2^32-1>;
     /// Contains credentials for the occupied slots in the tree, including the identity and
-    /// signature public key for the holder of the slot
-    #[serde(rename = "roster__bound_u32")]
-    pub(crate) roster: Roster,
+    /// signature public key for the holder of the slot. Most operations don't touch the roster, so
+    /// it's shared between a `GroupState` and its clones until one of them changes it. See
+    /// `GroupState::roster_mut`.
+    #[serde(rename = "roster__bound_u32", serialize_with = "serialize_shared")]
+    pub(crate) roster: Arc<Roster>,
 
     // optional<PublicKey> tree<1..2^32-1>;
     /// The tree field contains the public keys corresponding to the nodes of the ratchet tree for
@@ -119,14 +199,23 @@ pub struct GroupState {
     pub(crate) tree: RatchetTree,
 
     // opaque transcript_hash<0..255>;
-    /// Contains a running hash of `GroupOperation` messages that led to this state
+    /// Contains a running hash of `GroupOperation` messages that led to this state. This is a hash
+    /// chain, so it stays one digest long no matter how many operations went into it. See
+    /// `update_transcript_hash`.
     pub(crate) transcript_hash: Digest,
 
+    // Extension extensions<0..2^16-1>;
+    /// Group-wide extensions, set by the group's creator and handed to new members in their
+    /// `WelcomeInfo`. This is left out of the serialized state when empty, so that groups without
+    /// extensions hash the same way they always have.
+    #[serde(skip_serializing_if = "ExtensionList::is_empty")]
+    pub(crate) extensions: ExtensionList,
+
     /// The member's position in the roster. This is also known as `signer_index`. It is `None` iff
     /// this `GroupState` is in a preliminary state, i.e., iff it is between a `Welcome` and `Add`
     /// operation.
     #[serde(skip)]
-    pub(crate) roster_index: Option<u32>,
+    pub(crate) roster_index: Option<RosterIndex>,
 
     /// The `UserInitKey` used in the creation of this group from a `Welcome`. This is `Some` iff
     /// this `GroupState` is in a preliminary state, i.e., if it is between a `Welcome` and `Add`
@@ -137,10 +226,80 @@ pub struct GroupState {
     /// The initial secret used to derive `application_secret` and `confirmation_key`
     #[serde(skip)]
     pub(crate) init_secret: HmacKey,
+
+    /// The credentials that members have rotated away from while we've been in the group.
+    /// `UserInitKey`s issued under their identity keys are no longer accepted in an `Add`.
+    #[serde(skip)]
+    pub(crate) retired_credentials: Vec<Credential>,
+
+    /// Maps member identities to roster indices. This is derived from `roster`, and is kept in
+    /// step with it by every operation that fills or empties a roster entry. It's shared the same
+    /// way the roster is. See `GroupState::member_index_mut`.
+    #[serde(skip)]
+    pub(crate) member_index: Arc<MemberIndex>,
+
+    /// The settings this group was made with, plus this member's own policy. See `GroupConfig`.
+    #[serde(skip)]
+    pub(crate) config: GroupConfig,
+
+    /// The `WelcomeInfo`s of the members this member recently added, so their `Welcome`s can be
+    /// made again. See `GroupState::reissue_welcome`.
+    #[serde(skip)]
+    pub(crate) welcome_cache: Vec<CachedWelcome>,
+
+    /// This epoch's `GroupContext`, so that the tree hash in it isn't recomputed every time it's
+    /// needed, along with the version of the tree it was computed from. It's only used while the
+    /// epoch, transcript hash, and tree version all still match, and it's filled in again once
+    /// the new epoch's secrets are derived. See `GroupState::group_context`.
+    #[serde(skip)]
+    pub(crate) cached_context: Option<(u64, GroupContext)>,
+
+    /// The secrets this member derived for the current epoch, kept around to be inspected. See
+    /// the `inspect` module.
+    #[cfg(feature = "dangerous-debug")]
+    #[serde(skip)]
+    pub(crate) recorded_secrets: crate::inspect::RecordedSecrets,
+}
+
+/// The part of a group's state that every member agrees on in a given epoch. Application messages
+/// are encrypted with this as their associated data, and it's covered by `Handshake` and
+/// application message signatures, so none of them can be replayed in another group or epoch.
+// struct {
+//     opaque group_id<0..255>;
+//     uint32 epoch;
+//     opaque tree_hash<0..255>;
+//     opaque transcript_hash<0..255>;
+// } GroupContext;
+#[derive(Clone, Serialize)]
+#[cfg_attr(test, derive(Debug))]
+pub(crate) struct GroupContext {
+    #[serde(rename = "group_id__bound_u8")]
+    pub(crate) group_id: Vec<u8>,
+    pub(crate) epoch: u32,
+    pub(crate) tree_hash: Digest,
+    pub(crate) transcript_hash: Digest,
 }
 
-// TODO: Write the method to create a one-man group from scratch. The spec says that
-// transcript_hash is initialized to all zeros.
+impl GroupContext {
+    /// Makes the `GroupContext` of a group with the given ID, epoch, tree, and transcript hash
+    ///
+    /// Returns: `Ok(group_context)` on success. Returns an `Error::ValidationError` if the tree is
+    /// empty, and an `Error::SerdeError` if it can't be serialized.
+    pub(crate) fn new(
+        cs: &'static CipherSuite,
+        group_id: &[u8],
+        epoch: u32,
+        tree: &RatchetTree,
+        transcript_hash: &Digest,
+    ) -> Result<GroupContext, Error> {
+        Ok(GroupContext {
+            group_id: group_id.to_vec(),
+            epoch,
+            tree_hash: tree.tree_hash(cs)?,
+            transcript_hash: transcript_hash.clone(),
+        })
+    }
+}
 
 impl GroupState {
     /// Creates a new one-person `GroupState` from this member's information and some group
@@ -159,42 +318,158 @@ impl GroupState {
     where
         R: CryptoRng,
     {
+        GroupState::new_singleton_group_with_extensions(
+            cs,
+            protocol_version,
+            identity_key,
+            group_id,
+            my_credential,
+            ExtensionList::new(),
+            csprng,
+        )
+    }
+
+    /// Like `GroupState::new_singleton_group`, but the group starts out with the given group-wide
+    /// extensions
+    ///
+    /// Returns: `Ok(group_state)` on success. Returns an `Error::ValidationError` if `extensions`
+    /// has duplicate types, and some other `Error` if there was an issue creating an ephemeral
+    /// private key.
+    pub fn new_singleton_group_with_extensions<R>(
+        cs: &'static CipherSuite,
+        protocol_version: ProtocolVersion,
+        identity_key: SigSecretKey,
+        group_id: Vec<u8>,
+        my_credential: Credential,
+        extensions: ExtensionList,
+        csprng: &mut R,
+    ) -> Result<GroupState, Error>
+    where
+        R: CryptoRng,
+    {
+        let config =
+            GroupConfig::new(cs).set_protocol_version(protocol_version).set_extensions(extensions);
+        GroupState::new_singleton_group_with_config(
+            identity_key,
+            group_id,
+            my_credential,
+            config,
+            csprng,
+        )
+    }
+
+    /// Like `GroupState::new_singleton_group`, but the group's ciphersuite, protocol version,
+    /// extensions, and this member's policies are all taken from `config`. The config is kept in
+    /// the group state, and is available from `GroupState::get_config`.
+    ///
+    /// Returns: `Ok(group_state)` on success. Returns an `Error::ValidationError` if the configured
+    /// extensions have duplicate types, an `Error::SignatureError` if `identity_key` isn't the key
+    /// of `my_credential`, and some other `Error` if there was an issue creating an ephemeral
+    /// private key.
+    pub fn new_singleton_group_with_config<R>(
+        identity_key: SigSecretKey,
+        group_id: Vec<u8>,
+        my_credential: Credential,
+        config: GroupConfig,
+        csprng: &mut R,
+    ) -> Result<GroupState, Error>
+    where
+        R: CryptoRng,
+    {
+        my_credential.check_identity_key(&identity_key)?;
+        GroupState::new_singleton_group_with_identity_key(
+            IdentityKey::Local(identity_key),
+            group_id,
+            my_credential,
+            config,
+            csprng,
+        )
+    }
+
+    /// Like `GroupState::new_singleton_group_with_config`, but this member's identity key is in
+    /// `keystore` rather than in the group state. Everything this member signs in the group is
+    /// signed by `keystore`. See `KeyStore`.
+    ///
+    /// Returns: `Ok(group_state)` on success. Returns an `Error::ValidationError` if the configured
+    /// extensions have duplicate types, and some other `Error` if there was an issue creating an
+    /// ephemeral private key.
+    pub fn new_singleton_group_with_keystore<R>(
+        keystore: Arc<dyn KeyStore>,
+        group_id: Vec<u8>,
+        my_credential: Credential,
+        config: GroupConfig,
+        csprng: &mut R,
+    ) -> Result<GroupState, Error>
+    where
+        R: CryptoRng,
+    {
+        my_credential.validate()?;
+        GroupState::new_singleton_group_with_identity_key(
+            IdentityKey::Store(keystore),
+            group_id,
+            my_credential,
+            config,
+            csprng,
+        )
+    }
+
+    /// Like `GroupState::new_singleton_group_with_config`, but takes this member's identity key
+    /// wherever it is, and doesn't check it against `my_credential`
+    pub(crate) fn new_singleton_group_with_identity_key<R>(
+        identity_key: IdentityKey,
+        group_id: Vec<u8>,
+        my_credential: Credential,
+        mut config: GroupConfig,
+        csprng: &mut R,
+    ) -> Result<GroupState, Error>
+    where
+        R: CryptoRng,
+    {
+        let cs = config.cs;
+        let extensions = config.group_extensions()?;
+        // A padding scheme might have come in through the extensions, so make the config agree
+        // with what the group will actually do
+        config.padding_scheme = extensions.get::<PaddingScheme>()?.unwrap_or_default();
+
         // Turn the credential into a singleton roster
         let roster = Roster(vec![Some(my_credential)]);
-        let my_roster_index = 0u32;
+        let my_roster_index = RosterIndex(0);
 
         // Make an ephemeral keypair and turn it into a tree
         let my_ephemeral_secret = DhPrivateKey::new_from_random(cs.dh_impl, csprng)?;
         let my_node = RatchetTreeNode::new_from_private_key(cs, my_ephemeral_secret);
-        let tree = RatchetTree {
-            nodes: vec![my_node],
-        };
+        let tree = RatchetTree::new(vec![my_node]);
 
         // Now make the GroupState normally
-        Ok(GroupState::new_from_parts(
+        let mut group_state = GroupState::new_from_parts(
             cs,
-            protocol_version,
+            config.protocol_version,
             identity_key,
             group_id,
             roster,
             my_roster_index,
             tree,
-        ))
+        );
+        group_state.extensions = extensions;
+        group_state.config = config;
+
+        Ok(group_state)
     }
 
     /// Creates a new `GroupState` from its constituent parts
     pub(crate) fn new_from_parts(
         cs: &'static CipherSuite,
         protocol_version: ProtocolVersion,
-        identity_key: SigSecretKey,
+        identity_key: IdentityKey,
         group_id: Vec<u8>,
         roster: Roster,
-        roster_index: u32,
+        roster_index: RosterIndex,
         tree: RatchetTree,
     ) -> GroupState {
         // Transcript hash and init secrets are both zeros to begin with
         let transcript_hash = Digest::new_from_zeros(cs.hash_impl);
         let init_secret = HmacKey::new_from_zeros(cs.hash_impl);
+        let member_index = Arc::new(MemberIndex::from_roster(&roster));
 
         GroupState {
             cs,
@@ -202,12 +477,20 @@ impl GroupState {
             identity_key,
             group_id,
             epoch: 0,
-            roster,
+            roster: Arc::new(roster),
             tree,
             transcript_hash,
+            extensions: ExtensionList::new(),
             roster_index: Some(roster_index),
             initializing_user_init_key: None,
             init_secret,
+            retired_credentials: Vec::new(),
+            member_index,
+            config: GroupConfig::new(cs).set_protocol_version(protocol_version),
+            welcome_cache: Vec::new(),
+            cached_context: None,
+            #[cfg(feature = "dangerous-debug")]
+            recorded_secrets: Default::default(),
         }
     }
 
@@ -215,9 +498,14 @@ impl GroupState {
     /// member's identity key, and the `UserInitKey` used to encrypt the `Welcome` that the
     /// `WelcomeInfo` came from.
     ///
-    /// Returns: A `GroupState` in a "preliminary state", meaning that `roster_index` is `None` and
-    /// `initializing_user_init_key` is `Some`. The only thing to do with a preliminary
-    /// `GroupState` is give it an `Add` operation to add yourself to it.
+    /// Returns: `Ok(group_state)` on success, where `group_state` is in a "preliminary state",
+    /// meaning that `roster_index` is `None` and `initializing_user_init_key` is `Some`. The only
+    /// thing to do with a preliminary `GroupState` is give it the `Add` operation that adds
+    /// yourself to it, preceded by the `Add`s of anyone ahead of you in the same batch (see
+    /// `GroupState::create_and_apply_add_handshakes`). Returns an `Error::TreeError` or
+    /// `Error::ValidationError` if the tree or roster in the `WelcomeInfo` is malformed or they're
+    /// inconsistent with each other, and an `Error::SignatureError` if `my_identity_key` isn't the
+    /// key of the credential in `initializing_user_init_key`.
     // This is different from new_from_parts in that the epoch is not 0, the transcript hash is not
     // 0, the init secret is not 0, and the roster index is None
     pub(crate) fn from_welcome_info(
@@ -225,22 +513,81 @@ impl GroupState {
         w: WelcomeInfo,
         my_identity_key: SigSecretKey,
         initializing_user_init_key: UserInitKey,
-    ) -> GroupState {
-        // Make a new preliminary group (notice how roster is None and initializing_user_init_key
-        // is Some)
-        GroupState {
+    ) -> Result<GroupState, Error> {
+        // Everything we sign in this group has to verify under the credential we're added with
+        initializing_user_init_key.credential.check_identity_key(&my_identity_key)?;
+        GroupState::from_welcome_info_with_identity_key(
+            cs,
+            w,
+            IdentityKey::Local(my_identity_key),
+            initializing_user_init_key,
+        )
+    }
+
+    /// Like `GroupState::from_welcome_info`, but takes this member's identity key wherever it is,
+    /// and doesn't check it against the credential in `initializing_user_init_key`
+    fn from_welcome_info_with_identity_key(
+        cs: &'static CipherSuite,
+        w: WelcomeInfo,
+        my_identity_key: IdentityKey,
+        initializing_user_init_key: UserInitKey,
+    ) -> Result<GroupState, Error> {
+        // Make a new preliminary group (notice how roster_index is None and
+        // initializing_user_init_key is Some)
+        let mut group_state = GroupState::new_from_welcome_info(cs, w, my_identity_key)?;
+        group_state.initializing_user_init_key = Some(initializing_user_init_key);
+        Ok(group_state)
+    }
+
+    /// Makes a `GroupState` out of the given `WelcomeInfo` and identity key, after checking that
+    /// the tree and roster are well-formed and consistent with each other. The result has neither
+    /// a roster index nor an initializing `UserInitKey`. It's up to the caller to set one of them.
+    fn new_from_welcome_info(
+        cs: &'static CipherSuite,
+        w: WelcomeInfo,
+        my_identity_key: IdentityKey,
+    ) -> Result<GroupState, Error> {
+        // Don't take the sender's word for it that the tree is well-formed
+        w.tree.validate_received(w.roster.len())?;
+        w.roster.validate()?;
+        w.extensions.validate()?;
+
+        // A roster entry is filled iff its leaf is. Zipping is fine here, since the above check
+        // ensures that the tree has exactly one leaf per roster entry.
+        for (entry, (_, leaf)) in w.roster.0.iter().zip(w.tree.leaves()) {
+            if entry.is_some() != leaf.is_filled() {
+                return Err(Error::ValidationError(
+                    "WelcomeInfo roster entry and tree leaf disagree on whether a member is there",
+                ));
+            }
+        }
+
+        let member_index = Arc::new(MemberIndex::from_roster(&w.roster));
+        let config = GroupConfig::new(cs)
+            .set_protocol_version(w.protocol_version)
+            .set_padding_scheme(w.extensions.get::<PaddingScheme>()?.unwrap_or_default())
+            .set_extensions(w.extensions.clone());
+        Ok(GroupState {
             cs,
             protocol_version: w.protocol_version,
             identity_key: my_identity_key,
             group_id: w.group_id,
             epoch: w.epoch,
-            roster: w.roster,
-            tree: w.tree,
+            roster: Arc::new(w.roster.into_owned()),
+            tree: w.tree.into_owned(),
             transcript_hash: w.transcript_hash,
+            extensions: w.extensions,
             roster_index: None,
-            initializing_user_init_key: Some(initializing_user_init_key),
-            init_secret: w.init_secret,
-        }
+            initializing_user_init_key: None,
+            init_secret: w.init_secret.0,
+            retired_credentials: Vec::new(),
+            member_index,
+            config,
+            welcome_cache: Vec::new(),
+            cached_context: None,
+            #[cfg(feature = "dangerous-debug")]
+            recorded_secrets: Default::default(),
+        })
     }
 
     /// Creates a new `GroupState` from a `Welcome` message, this member's identity key, and the
@@ -249,6 +596,11 @@ impl GroupState {
     /// Requires: That the `init_key` is the `UserInitKey` that the `Welcome` was encrypted with
     /// (i.e., `init_key.user_init_key_id == self.user_init_key_id`) and `init_key.private_keys`
     /// is not `None`
+    ///
+    /// Returns: `Ok(group_state)` on success, where `group_state` is preliminary, as in
+    /// `GroupState::from_welcome_info`. Returns an `Error::InvalidWelcome` if the `Welcome` isn't
+    /// for `init_key` or the `WelcomeInfo` in it doesn't make sense, and some other `Error` if it
+    /// can't be decrypted or the group it describes is malformed.
     // This is just a convenient wrapper around welcome.into_welcome_info_cipher_suite and
     // GroupState::from_welcome_info
     pub fn from_welcome(
@@ -263,158 +615,663 @@ impl GroupState {
             welcome_info,
             identity_secret_key,
             init_key,
-        );
+        )?;
 
         Ok(group_state)
     }
 
-    /// Creates a `WelcomeInfo` object with all the current state information
-    fn as_welcome_info(&self) -> WelcomeInfo {
+    /// Like `GroupState::from_welcome`, but this member's identity key and the private keys of
+    /// `init_key` are in `keystore`. So `init_key` can be the published copy, without private keys.
+    /// Everything this member signs in the group is signed by `keystore`. See `KeyStore`.
+    ///
+    /// Returns: `Ok(group_state)` on success, where `group_state` is preliminary, as in
+    /// `GroupState::from_welcome`. Returns an `Error::DhError` if the private keys from `keystore`
+    /// can't be decoded. Otherwise returns whatever `KeyStore::init_private_keys` or
+    /// `GroupState::from_welcome` returns.
+    pub fn from_welcome_with_keystore(
+        welcome: Welcome,
+        keystore: Arc<dyn KeyStore>,
+        mut init_key: UserInitKey,
+    ) -> Result<GroupState, Error> {
+        init_key.credential.validate()?;
+        let private_keys = keystore.init_private_keys(&init_key)?;
+        if private_keys.len() != init_key.cipher_suites.len() {
+            return Err(Error::ValidationError(
+                "KeyStore returned the wrong number of init private keys",
+            ));
+        }
+        let private_keys = init_key
+            .cipher_suites
+            .iter()
+            .zip(private_keys.iter())
+            .map(|(cs, der)| DhPrivateKey::new_from_pkcs8(cs.dh_impl, der))
+            .collect::<Result<Vec<_>, Error>>()?;
+        init_key.private_keys = Some(private_keys);
+
+        let (welcome_info, cipher_suite) = welcome.into_welcome_info_cipher_suite(&init_key)?;
+        GroupState::from_welcome_info_with_identity_key(
+            cipher_suite,
+            welcome_info,
+            IdentityKey::Store(keystore),
+            init_key,
+        )
+    }
+
+    /// Creates a `WelcomeInfo` object with all the current state information. It borrows the
+    /// roster and tree from this `GroupState`.
+    pub(crate) fn as_welcome_info(&self) -> WelcomeInfo<'_> {
         WelcomeInfo {
             protocol_version: self.protocol_version,
             group_id: self.group_id.clone(),
             epoch: self.epoch,
-            roster: self.roster.clone(),
-            tree: self.tree.clone(),
+            roster: Cow::Borrowed(self.roster.as_ref()),
+            tree: Cow::Borrowed(&self.tree),
             transcript_hash: self.transcript_hash.clone(),
-            init_secret: self.init_secret.clone(),
+            init_secret: WelcomeInitSecret(self.init_secret.clone()),
+            extensions: self.extensions.clone(),
         }
     }
 
-    /// Returns the signature scheme of this member of the group. This is determined by the
-    /// signature scheme of this member's credential.
-    pub(crate) fn get_signature_scheme(&self) -> &'static SignatureScheme {
-        // We look for our credential first, since this contains our signature scheme. If this is a
-        // preliminary group, i.e., if this group was just created from a WelcomeInfo, then we
-        // don't know our roster index, so we can't get our credential from the roster. In this
-        // case, we look in the initializing UserInitKey for our credential. For any valid
-        // GroupState, precisely one of these has to happen, so this function is always
-        // well-defined.
+    /// Packs up everything this member needs to pick this group up again where it left off, to be
+    /// written out by `MlsClient::save`. Member-local settings and the `WelcomeInfo`s cached for
+    /// `GroupState::reissue_welcome` are left out.
+    ///
+    /// Returns: `Ok(saved_group_state)` on success. Returns an `Error::ValidationError` if this
+    /// member's identity key is in a `KeyStore`, since there's nothing here to save then.
+    pub(crate) fn to_saved(&self) -> Result<SavedGroupState, Error> {
+        let identity_key = match self.identity_key {
+            IdentityKey::Local(ref identity_key) => {
+                SavedSecret::new_from_vec(identity_key.to_bytes())
+            }
+            IdentityKey::Store(_) => {
+                return Err(Error::ValidationError(
+                    "Cannot save a group whose identity key is in a KeyStore",
+                ))
+            }
+        };
+        let initializing_user_init_key = match self.initializing_user_init_key {
+            Some(ref init_key) => Some(init_key.to_saved()?),
+            None => None,
+        };
+        let tree_private_keys = self
+            .tree
+            .nodes()
+            .iter()
+            .enumerate()
+            .filter_map(|(i, node)| match node {
+                RatchetTreeNode::Filled {
+                    private_key: Some(private_key),
+                    ..
+                } => Some(SavedNodeKey {
+                    node_index: i as u32,
+                    private_key: SavedSecret::new_from_vec(private_key.to_bytes()),
+                }),
+                _ => None,
+            })
+            .collect();
+
+        Ok(SavedGroupState {
+            cipher_suite: self.cs,
+            welcome_info: self.as_welcome_info().into_owned(),
+            roster_index: self.roster_index,
+            initializing_user_init_key,
+            identity_key,
+            tree_private_keys,
+            retired_credentials: self.retired_credentials.clone(),
+        })
+    }
 
-        let my_credential = if let Some(roster_idx) = self.roster_index {
-            // My own entry in the roster. This better be in range, otherwise this a very broken
-            // GroupState, and does not merit a nice Error
-            let my_roster_entry: Option<&Credential> = self
+    /// Makes a `GroupState` from what `GroupState::to_saved` packed up. Settings that aren't
+    /// group extensions are the defaults, as they are after a `Welcome`.
+    ///
+    /// Returns: `Ok(group_state)` on success. Returns an `Error::ValidationError` if the saved
+    /// state is inconsistent, e.g., if it has both or neither of a roster index and an
+    /// initializing `UserInitKey`, or a private key doesn't match its public key. Returns an
+    /// `Error::SignatureError` if the identity key doesn't belong to this member's credential, and
+    /// whatever `GroupState::from_welcome_info` returns if the tree or roster are malformed.
+    pub(crate) fn from_saved(saved: SavedGroupState) -> Result<GroupState, Error> {
+        let SavedGroupState {
+            cipher_suite: cs,
+            welcome_info,
+            roster_index,
+            initializing_user_init_key,
+            identity_key,
+            tree_private_keys,
+            retired_credentials,
+        } = saved;
+
+        let digest_size = cs.hash_impl.digest_size();
+        if welcome_info.transcript_hash.as_bytes().len() != digest_size
+            || welcome_info.init_secret.0.as_bytes().len() != digest_size
+        {
+            return Err(Error::ValidationError("Saved group doesn't match its ciphersuite"));
+        }
+
+        // Our credential is in the roster, or, if we haven't been added yet, in the UserInitKey
+        // we're being added with. That's the credential the identity key has to belong to.
+        let initializing_user_init_key = match initializing_user_init_key {
+            Some(init_key) => Some(init_key.into_user_init_key()?),
+            None => None,
+        };
+        let my_credential = match (roster_index, initializing_user_init_key.as_ref()) {
+            (Some(roster_index), None) => welcome_info
                 .roster
-                .0
-                .get(roster_idx as usize)
-                .expect("this member's roster index is out of bounds")
-                .as_ref();
-            // My own credential. This also better exist.
-            my_roster_entry.expect("this member's roster entry is empty")
-        } else {
-            // initializing_user_init_key is Some iff self.roster_index is None
-            let uik = self
-                .initializing_user_init_key
-                .as_ref()
-                .expect("group has no roster index or initializing user init key");
-            &uik.credential
+                .get(roster_index)
+                .ok_or(Error::ValidationError("Saved roster index has no member"))?,
+            (None, Some(init_key)) => &init_key.credential,
+            _ => {
+                return Err(Error::ValidationError(
+                    "Saved group needs either a roster index or an initializing UserInitKey",
+                ))
+            }
         };
+        let ss = my_credential.get_signature_scheme();
+        let identity_key = SigSecretKey::new_from_bytes(ss, identity_key.as_bytes())?;
+        my_credential.check_identity_key(&identity_key)?;
+
+        let mut group_state =
+            GroupState::new_from_welcome_info(cs, welcome_info, IdentityKey::Local(identity_key))?;
+        group_state.roster_index = roster_index;
+        group_state.initializing_user_init_key = initializing_user_init_key;
+
+        for saved_key in tree_private_keys {
+            let node = group_state
+                .tree
+                .get_mut(NodeIndex(saved_key.node_index as usize))
+                .ok_or(Error::ValidationError("Saved private key is for a node out of bounds"))?;
+            let private_key =
+                DhPrivateKey::new_from_bytes(cs.dh_impl, saved_key.private_key.as_bytes())?;
+            match node {
+                RatchetTreeNode::Filled {
+                    ref public_key,
+                    private_key: ref mut slot,
+                } if DhPublicKey::new_from_private_key(cs.dh_impl, &private_key).as_bytes()
+                    == public_key.as_bytes() =>
+                {
+                    *slot = Some(private_key)
+                }
+                _ => {
+                    return Err(Error::ValidationError(
+                        "Saved private key doesn't match its node's public key",
+                    ))
+                }
+            }
+        }
+        group_state.retired_credentials = retired_credentials;
 
-        my_credential.get_signature_scheme()
+        Ok(group_state)
     }
 
-    /// Increments the epoch counter by 1
+    /// Makes a `CachedWelcome` of this group as it is now, for the owner of `init_key`. This is
+    /// called on the state that an `Add` or `Replace` is made from, and the result goes in the
+    /// state it leads to.
     ///
-    /// Returns: An `Error::ValidationError` if the epoch value is at its max
-    fn increment_epoch(&mut self) -> Result<(), Error> {
-        let new_epoch = self
+    /// Returns: `Ok(cached_welcome)` on success, and an `Error::SerdeError` if the `WelcomeInfo`
+    /// can't be serialized
+    fn cached_welcome(&self, init_key: &UserInitKey) -> Result<CachedWelcome, Error> {
+        let serialized_welcome_info = tls_ser::serialize_to_bytes(&self.as_welcome_info())?;
+        let added_epoch = self
             .epoch
             .checked_add(1)
             .ok_or(Error::ValidationError("Cannot increment epoch past its maximum"))?;
-        self.epoch = new_epoch;
 
-        Ok(())
+        Ok(CachedWelcome {
+            added_epoch,
+            init_key: init_key.clone(),
+            serialized_welcome_info: Secret::new_from_vec(serialized_welcome_info),
+        })
     }
 
-    /// Computes and updates the transcript hash, given a new `Handshake` message.
-    ///
-    /// Returns: An `Error::SerdeError` if there was an issue during serialization
-    fn update_transcript_hash(&mut self, operation: &GroupOperation) -> Result<(), Error> {
-        // Compute the new transcript hash
-        // From section 5.7: transcript_hash_[n] = Hash(transcript_hash_[n-1] || operation)
-        self.transcript_hash = {
-            let mut ctx = self.cs.hash_impl.new_context();
-            ctx.feed_bytes(self.transcript_hash.as_bytes());
-            ctx.feed_serializable(&operation)?;
-            ctx.finalize()
-        };
-
-        Ok(())
+    /// Adds `cached_welcome` to the `Welcome` cache, replacing anything cached for the same
+    /// `UserInitKey`
+    fn insert_cached_welcome(&mut self, cached_welcome: CachedWelcome) {
+        let user_init_key_id = &cached_welcome.init_key.user_init_key_id;
+        self.welcome_cache.retain(|cached| &cached.init_key.user_init_key_id != user_init_key_id);
+        self.welcome_cache.push(cached_welcome);
     }
 
-    /// Derives and sets the next generation of Group secrets as per the "Key Schedule" section of
-    /// the spec. Specifically, this sets the init secret of the group, and returns the confirmation
-    /// key and application secret. This is done this way because the latter two values must be used
-    /// immediately in `process_handshake`.
-    fn update_epoch_secrets(
-        &mut self,
-        update_secret: &UpdateSecret,
-    ) -> Result<(ApplicationSecret, ConfirmationKey), Error> {
-        let hash_impl = self.cs.hash_impl;
-
-        // epoch_secret = HKDF-Extract(salt=init_secret_[n-1] (or 0), ikm=update_secret)
-        let ikm = update_secret.as_bytes();
-        let epoch_secret: HmacKey = hkdf::extract(hash_impl, &self.init_secret, ikm);
+    /// Makes the `Welcome` for the owner of the `UserInitKey` with ID `user_init_key_id` again, for
+    /// when they never got the first one. This member has to have made the `Add` or `Replace` that
+    /// added them, at most as many epochs ago as this group's `GroupConfig` retains application
+    /// keys for. The `Welcome` describes the group right before that operation, so the new member
+    /// then processes its `Handshake` and every one after it. For a member added in a batch, that
+    /// skips the `Add`s ahead of theirs, which the original `Welcome` didn't.
+    ///
+    /// Returns: `Ok(welcome)` on success. Returns an `Error::ValidationError` if there's no cached
+    /// `WelcomeInfo` for `user_init_key_id`, e.g., because the group has moved on too far since.
+    pub fn reissue_welcome<R>(
+        &self,
+        user_init_key_id: &[u8],
+        csprng: &mut R,
+    ) -> Result<Welcome, Error>
+    where
+        R: CryptoRng,
+    {
+        let cached = self
+            .welcome_cache
+            .iter()
+            .find(|cached| cached.init_key.user_init_key_id.as_slice() == user_init_key_id)
+            .ok_or(Error::ValidationError("No cached WelcomeInfo for that UserInitKey"))?;
 
-        // Set my new init_secret first. We don't have to worry about this update affecting
-        // subsequent serializations of this GroupState object in the lines below, since
-        // init_secret is not included in the serialized form of a GroupState.
+        Welcome::from_serialized_welcome_info(
+            self.cs,
+            &cached.init_key,
+            self.protocol_version,
+            cached.serialized_welcome_info.as_bytes().to_vec(),
+            csprng,
+        )
+    }
 
-        // init_secret_[n] = Derive-Secret(epoch_secret, "init", GroupState_[n])
-        self.init_secret = hkdf::derive_secret(hash_impl, &epoch_secret, b"init", self)?;
+    /// Returns a `GroupInit` that describes the current state of this group. The tree in it has no
+    /// private keys.
+    pub(crate) fn as_group_init(&self) -> GroupInit {
+        let mut tree = self.tree.clone();
+        tree.erase_all_private_keys();
 
-        // application_secret = Derive-Secret(epoch_secret, "app", GroupState_[n])
-        let application_secret = hkdf::derive_secret(hash_impl, &epoch_secret, b"app", self)?;
+        GroupInit {
+            protocol_version: self.protocol_version,
+            group_id: self.group_id.clone(),
+            cipher_suite: self.cs,
+            roster: (*self.roster).clone(),
+            tree,
+            extensions: self.extensions.clone(),
+        }
+    }
 
-        // confirmation_key = Derive-Secret(epoch_secret, "confirm", GroupState_[n])
-        let confirmation_key = hkdf::derive_secret(hash_impl, &epoch_secret, b"confirm", self)?;
+    /// Returns the group-wide extensions of this group
+    pub fn get_extensions(&self) -> &ExtensionList {
+        &self.extensions
+    }
 
-        Ok((application_secret.into(), confirmation_key.into()))
+    /// Returns the config this group was made with. For a member who joined from a `Welcome`,
+    /// this is the group's ciphersuite, protocol version, and extensions, along with the default
+    /// policies. See `GroupConfig`.
+    pub fn get_config(&self) -> &GroupConfig {
+        &self.config
     }
 
-    /// Converts the index of a roster entry into the index of the corresponding leaf node of the
-    /// ratchet tree
+    /// Returns the scheme this group uses to pad application messages. This is taken from the
+    /// group's `PaddingScheme` extension, and is `PaddingScheme::None` if there is none.
     ///
-    /// Returns: `Ok(n)` on success, where `n` is the corresponding tree index. Returns an
-    /// `Error::ValidationError` if `roster_index` is out of bounds.
-    pub(crate) fn roster_index_to_tree_index(roster_index: u32) -> Result<usize, Error> {
-        // This is easy. The nth leaf node is at position 2n
-        roster_index
-            .checked_mul(2)
-            .map(|n| n as usize)
-            .ok_or(Error::ValidationError("roster/tree size invariant violated"))
+    /// Returns: `Ok(scheme)` on success, and an `Error::SerdeError` if the extension is malformed
+    pub fn get_padding_scheme(&self) -> Result<PaddingScheme, Error> {
+        Ok(self.extensions.get::<PaddingScheme>()?.unwrap_or_default())
     }
 
-    /// Performs an update operation on the `GroupState`, where `new_path_secret` is the node
-    /// secret we will propagate starting at the index `start_idx`. This is the core updating logic
-    /// that is used in `process_incoming_update_op` and `create_and_apply_update_op`.
+    /// Returns whether this group lets an identity be in it more than once. This is taken from
+    /// the group's `DuplicateIdentityPolicy` extension, and is `DuplicateIdentityPolicy::Reject`
+    /// if there is none.
     ///
-    /// Returns: `Ok(update_secret)` on success, where `update_secret` is the update secret
-    /// necessary for generating new epoch secrets
-    fn apply_update(
-        &mut self,
-        new_path_secret: PathSecret,
-        start_idx: usize,
-    ) -> Result<UpdateSecret, Error> {
-        // The main part of doing an update is updating node secrets, private keys, and public keys
-        let root_node_secret =
-            self.tree.propagate_new_path_secret(self.cs, new_path_secret, start_idx)?;
-
-        // "The update secret resulting from this change is the secret for the root node of the
-        // ratchet tree."
-        Ok(UpdateSecret::from(root_node_secret))
+    /// Returns: `Ok(policy)` on success, and an `Error::SerdeError` if the extension is malformed
+    pub fn get_duplicate_identity_policy(&self) -> Result<DuplicateIdentityPolicy, Error> {
+        Ok(self.extensions.get::<DuplicateIdentityPolicy>()?.unwrap_or_default())
     }
 
-    /// Performs and validates an incoming (i.e., one we did not generate) Update operation on the
-    /// `GroupState`, where `sender_tree_idx` is the tree index of the sender of this operation
+    /// Returns how this group encrypts path secrets. This is taken from the group's
+    /// `PathEncryption` extension, and is `PathEncryption::Anonymous` if there is none.
     ///
-    /// Returns: `Ok(update_secret)` on success, where `update_secret` is the update secret
+    /// Returns: `Ok(path_encryption)` on success, and an `Error::SerdeError` if the extension is
+    /// malformed
+    pub fn get_path_encryption(&self) -> Result<PathEncryption, Error> {
+        Ok(self.extensions.get::<PathEncryption>()?.unwrap_or_default())
+    }
+
+    // Returns the key this member authenticates the path secrets they send with, if the group's
+    // PathEncryption calls for it. That's the private key of their leaf at `my_tree_idx`, as it is
+    // before the operation, so this is called on the GroupState the operation is made from.
+    fn path_sender_secret(&self, my_tree_idx: NodeIndex) -> Result<Option<&DhPrivateKey>, Error> {
+        match self.get_path_encryption()? {
+            PathEncryption::Anonymous => Ok(None),
+            PathEncryption::SenderAuthenticated => self
+                .tree
+                .get(my_tree_idx)
+                .and_then(RatchetTreeNode::get_private_key)
+                .map(Some)
+                .ok_or(Error::ValidationError("Don't know the private key of my own leaf")),
+        }
+    }
+
+    // Returns the key that the path secrets sent by the member at `sender_tree_idx` have to be
+    // authenticated with, if the group's PathEncryption calls for it. That's the public key of
+    // their leaf before the operation, so this is called before the path is applied.
+    fn path_sender_public_key(
+        &self,
+        sender_tree_idx: NodeIndex,
+    ) -> Result<Option<DhPublicKey>, Error> {
+        match self.get_path_encryption()? {
+            PathEncryption::Anonymous => Ok(None),
+            PathEncryption::SenderAuthenticated => self
+                .tree
+                .get(sender_tree_idx)
+                .and_then(RatchetTreeNode::get_public_key)
+                .map(|public_key| Some(public_key.clone()))
+                .ok_or(Error::ValidationError("Sender's leaf is blank")),
+        }
+    }
+
+    /// Returns the role of the member at `roster_index`. This is taken from the group's
+    /// `RosterRoles` extension, and is `Role::Member` if there is none.
+    ///
+    /// Returns: `Ok(role)` on success, and an `Error::SerdeError` if the extension is malformed
+    pub fn get_role(&self, roster_index: RosterIndex) -> Result<Role, Error> {
+        let roles = self.extensions.get::<RosterRoles>()?.unwrap_or_default();
+        Ok(roles.get_role(roster_index))
+    }
+
+    /// Returns the group's current `GroupPolicy`, along with who set it, if it has one. See
+    /// `GroupState::create_and_apply_policy_update_handshake`.
+    ///
+    /// Returns: `Ok(Some(signed_policy))` if the group has a policy, and `Ok(None)` if it doesn't.
+    /// Returns an `Error::SerdeError` if the extension is malformed.
+    pub fn get_group_policy(&self) -> Result<Option<SignedGroupPolicy>, Error> {
+        self.extensions.get::<SignedGroupPolicy>()
+    }
+
+    /// Sets the policy on who may make which operations in this group. Members who join from a
+    /// `Welcome` start out with `AllowAll`, so they have to set the group's policy themselves
+    /// before processing anything. See `GroupConfig::set_authorization_policy`.
+    pub fn set_authorization_policy(&mut self, policy: Arc<dyn AuthorizationPolicy>) {
+        self.config.authorization_policy = policy;
+    }
+
+    /// Checks that the group's `GroupPolicy` and `AuthorizationPolicy` let this member make
+    /// `operation`
+    ///
+    /// Returns: `Ok(())` if the operation is allowed. Returns an `Error::ValidationError` if this
+    /// is a preliminary `GroupState`, and an `Error::InvalidOperation` if the operation isn't
+    /// allowed.
+    fn check_own_authorization(&self, operation: &GroupOperation) -> Result<(), Error> {
+        let my_roster_index = self.roster_index.ok_or(Error::ValidationError(
+            "Cannot make an operation from a preliminary GroupState",
+        ))?;
+        self.check_authorization(my_roster_index, operation)
+    }
+
+    /// Checks that the group's `GroupPolicy`, if it has one, and its `AuthorizationPolicy` both let
+    /// the member at `actor` make `operation` in the current state. This is checked before making
+    /// any operation and before applying any `Handshake`.
+    ///
+    /// Returns: `Ok(())` if the operation is allowed. Returns an `Error::InvalidOperation` if it
+    /// isn't, and an `Error::SerdeError` if the group's `GroupPolicy` is malformed.
+    pub(crate) fn check_authorization(
+        &self,
+        actor: RosterIndex,
+        operation: &GroupOperation,
+    ) -> Result<(), Error> {
+        // Adds and Replaces both bring someone new into the group, so the join rule covers both
+        if let GroupOperation::Add(_) | GroupOperation::Replace(_) = operation {
+            if let Some(signed_policy) = self.get_group_policy()? {
+                let may_add = self
+                    .roster
+                    .get(actor)
+                    .is_some_and(|cred| signed_policy.get_policy().may_add(cred.get_identity()));
+                if !may_add {
+                    return Err(OperationError::NotAuthorized.into());
+                }
+            }
+        }
+
+        let policy = self.config.authorization_policy.as_ref();
+        let allowed = match operation {
+            GroupOperation::Add(add) => {
+                policy.may_add(self, actor, add.roster_index, &add.init_key.credential)
+            }
+            GroupOperation::Remove(remove) => {
+                policy.may_remove(self, actor, remove.removed_roster_index)
+            }
+            GroupOperation::BatchRemove(batch_remove) => batch_remove
+                .removed_roster_indices
+                .iter()
+                .all(|removed| policy.may_remove(self, actor, *removed)),
+            GroupOperation::Replace(replace) => {
+                let add = &replace.add;
+                policy.may_remove(self, actor, add.roster_index)
+                    && policy.may_add(self, actor, add.roster_index, &add.init_key.credential)
+            }
+            GroupOperation::Update(_) => policy.may_update(self, actor),
+            GroupOperation::CredentialUpdate(cred_update) => {
+                policy.may_change_credential(self, actor, &cred_update.new_credential)
+            }
+            GroupOperation::PolicyUpdate(policy_update) => {
+                policy.may_change_policy(self, actor, &policy_update.signed_policy.policy)
+            }
+            // Nobody is in the group yet to be allowed or refused anything
+            GroupOperation::Init(_) => true,
+        };
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(OperationError::NotAuthorized.into())
+        }
+    }
+
+    /// Computes the hash of the `WelcomeInfo` describing the current state. This is what an `Add`
+    /// sent in the current epoch must carry in its `welcome_info_hash` field.
+    ///
+    /// Returns: An `Error::SerdeError` if there was an issue during serialization
+    pub(crate) fn welcome_info_hash(&self) -> Result<WelcomeInfoHash, Error> {
+        let digest = self.cs.hash_impl.hash_serializable(&self.as_welcome_info())?;
+        Ok(WelcomeInfoHash::from(digest))
+    }
+
+    /// Returns the signature scheme of this member of the group. This is determined by the
+    /// signature scheme of this member's credential.
+    #[cfg(test)]
+    pub(crate) fn get_signature_scheme(&self) -> &'static crate::crypto::sig::SignatureScheme {
+        self.my_credential().get_signature_scheme()
+    }
+
+    /// Signs `msg` with this member's identity key, wherever it is
+    ///
+    /// Returns: `Ok(signature)` on success. If the key is in a `KeyStore`, returns whatever
+    /// `KeyStore::sign` returns, and an `Error::SignatureError` if its signature doesn't verify.
+    pub(crate) fn sign_with_identity_key(&self, msg: &[u8]) -> Result<Signature, Error> {
+        self.identity_key.sign(self.my_credential(), msg)
+    }
+
+    /// Returns this member's credential
+    fn my_credential(&self) -> &Credential {
+        // We look for our credential first, since this contains our signature scheme. If this is a
+        // preliminary group, i.e., if this group was just created from a WelcomeInfo, then we
+        // don't know our roster index, so we can't get our credential from the roster. In this
+        // case, we look in the initializing UserInitKey for our credential. For any valid
+        // GroupState, precisely one of these has to happen, so this function is always
+        // well-defined.
+
+        if let Some(roster_idx) = self.roster_index {
+            // My own entry in the roster. This better be in range, otherwise this a very broken
+            // GroupState, and does not merit a nice Error
+            let my_roster_entry: Option<&Credential> = self
+                .roster
+                .0
+                .get(roster_idx.0 as usize)
+                .expect("this member's roster index is out of bounds")
+                .as_ref();
+            // My own credential. This also better exist.
+            my_roster_entry.expect("this member's roster entry is empty")
+        } else {
+            // initializing_user_init_key is Some iff self.roster_index is None
+            let uik = self
+                .initializing_user_init_key
+                .as_ref()
+                .expect("group has no roster index or initializing user init key");
+            &uik.credential
+        }
+    }
+
+    /// Returns the roster for modifying. If it's shared with another `GroupState`, this copies it
+    /// first, so the other one is unaffected.
+    pub(crate) fn roster_mut(&mut self) -> &mut Roster {
+        Arc::make_mut(&mut self.roster)
+    }
+
+    /// Returns the member index for modifying. Like `roster_mut`, this copies it first if it's
+    /// shared.
+    fn member_index_mut(&mut self) -> &mut MemberIndex {
+        Arc::make_mut(&mut self.member_index)
+    }
+
+    /// Increments the epoch counter by 1
+    ///
+    /// Returns: An `Error::ValidationError` if the epoch value is at its max
+    fn increment_epoch(&mut self) -> Result<(), Error> {
+        let new_epoch = self
+            .epoch
+            .checked_add(1)
+            .ok_or(Error::ValidationError("Cannot increment epoch past its maximum"))?;
+        self.epoch = new_epoch;
+        // The tree is about to change too
+        self.cached_context = None;
+
+        Ok(())
+    }
+
+    /// Irrecoverably erases the secrets in this `GroupState` that belong to previous epochs. This
+    /// drops the private keys of every tree node that isn't on this member's extended direct path,
+    /// as well as the initializing `UserInitKey` if this member has already been added. Secret
+    /// values zero their memory when dropped, so nothing erased here can be recovered later. This
+    /// is done automatically whenever a `GroupState` advances to a new epoch. Old `GroupState`s
+    /// themselves are erased when they're dropped.
+    ///
+    /// This also drops the cached `WelcomeInfo`s of members added longer ago than the epoch
+    /// retention of this group's `GroupConfig`, since each holds an old init secret. See
+    /// `GroupState::reissue_welcome`.
+    ///
+    /// Other than that, this does nothing to a preliminary `GroupState`, since it needs its
+    /// initializing `UserInitKey` to process the `Add` that adds this member.
+    pub fn erase_old_epochs(&mut self) {
+        let epoch = self.epoch;
+        let epoch_retention = self.config.epoch_retention;
+        self.welcome_cache
+            .retain(|cached| (epoch.saturating_sub(cached.added_epoch) as usize) < epoch_retention);
+
+        let my_tree_idx = match self.roster_index.map(RosterIndex::node_index) {
+            Some(Ok(idx)) => idx,
+            // Preliminary groups keep everything. So do groups with a nonsense roster index, since
+            // we can't tell which keys are ours.
+            _ => return,
+        };
+
+        self.initializing_user_init_key = None;
+        if my_tree_idx.0 < self.tree.size() {
+            self.tree.erase_private_keys_off_path(my_tree_idx);
+        }
+    }
+
+    /// Erases every secret in this `GroupState` that could be used to derive the keys of this epoch
+    /// or any later one. This drops all the private keys in the tree and the initializing
+    /// `UserInitKey`, and zeros the `init_secret`. What's left is still good for checking senders
+    /// against the roster, which is all that decrypting a late application message needs.
+    pub(crate) fn erase_all_secrets(&mut self) {
+        self.tree.erase_all_private_keys();
+        self.initializing_user_init_key = None;
+        self.init_secret = HmacKey::new_from_zeros(self.cs.hash_impl);
+        self.welcome_cache.clear();
+    }
+
+    /// Tells the installed `Metrics` (if any) that this `GroupState` is a new epoch of its group
+    fn report_new_epoch(&self) {
+        metrics::report(|m| {
+            m.epoch_advanced(&self.group_id, self.epoch);
+            m.tree_size(&self.group_id, self.tree.leaf_count());
+        });
+    }
+
+    /// Computes and updates the transcript hash, given a new `Handshake` message.
+    ///
+    /// Returns: An `Error::SerdeError` if there was an issue during serialization
+    fn update_transcript_hash(&mut self, operation: &GroupOperation) -> Result<(), Error> {
+        // Compute the new transcript hash
+        // From section 5.7: transcript_hash_[n] = Hash(transcript_hash_[n-1] || operation)
+        //
+        // This is already incremental: each epoch folds one operation into the previous digest,
+        // and the digest is all we keep. Keeping a single hasher running over the whole history
+        // instead would give Hash(operation_1 || ... || operation_n), which isn't what the spec
+        // says, and members who joined by Welcome couldn't compute it anyway, since a WelcomeInfo
+        // only carries the digest.
+        self.transcript_hash =
+            GroupState::next_transcript_hash(self.cs, &self.transcript_hash, operation)?;
+
+        Ok(())
+    }
+
+    /// Computes the transcript hash that follows `transcript_hash` once `operation` is applied.
+    /// This is what `update_transcript_hash` does, without needing a `GroupState`.
+    ///
+    /// Returns: An `Error::SerdeError` if there was an issue during serialization
+    pub(crate) fn next_transcript_hash(
+        cs: &CipherSuite,
+        transcript_hash: &Digest,
+        operation: &GroupOperation,
+    ) -> Result<Digest, Error> {
+        let mut ctx = cs.hash_impl.new_context();
+        ctx.feed_bytes(transcript_hash.as_bytes());
+        ctx.feed_serializable(operation)?;
+        Ok(ctx.finalize())
+    }
+
+    /// Derives and sets the next generation of Group secrets as per the "Key Schedule" section of
+    /// the spec. Specifically, this sets the init secret of the group, and returns the confirmation
+    /// key and application secret. This is done this way because the latter two values must be used
+    /// immediately in `process_handshake`.
+    pub(crate) fn update_epoch_secrets(
+        &mut self,
+        update_secret: &UpdateSecret,
+    ) -> Result<(ApplicationSecret, ConfirmationKey), Error> {
+        // The group context is this GroupState. Its serialized form doesn't include the init
+        // secret, so it doesn't matter that we're about to change it.
+        let secrets =
+            derive_epoch_secrets(self.cs.hash_impl, &self.init_secret, update_secret, self)?;
+        self.init_secret = secrets.init_secret;
+
+        // This is the last step of every operation, so the tree is final. If it can't be hashed,
+        // nothing is cached, and the error comes up once the context is actually needed.
+        self.refresh_group_context().ok();
+        #[cfg(feature = "dangerous-debug")]
+        self.recorded_secrets.record_epoch(
+            update_secret.as_bytes(),
+            &secrets.epoch_secret,
+            &secrets.application_secret.0,
+            &secrets.confirmation_key.0,
+        );
+
+        Ok((secrets.application_secret, secrets.confirmation_key))
+    }
+
+    /// Performs an update operation on the `GroupState`, where `new_path_secret` is the node
+    /// secret we will propagate starting at the index `start_idx`. This is the core updating logic
+    /// that is used in `process_incoming_update_op` and `create_and_apply_update_op`.
+    ///
+    /// Returns: `Ok(update_secret)` on success, where `update_secret` is the update secret
+    /// necessary for generating new epoch secrets
+    fn apply_update(
+        &mut self,
+        new_path_secret: PathSecret,
+        start_idx: NodeIndex,
+    ) -> Result<UpdateSecret, Error> {
+        #[cfg(feature = "dangerous-debug")]
+        self.recorded_secrets.record_path(self.cs, &self.tree, start_idx, &new_path_secret)?;
+
+        // The main part of doing an update is updating node secrets, private keys, and public keys
+        let root_node_secret =
+            self.tree.propagate_new_path_secret(self.cs, new_path_secret, start_idx)?;
+
+        // "The update secret resulting from this change is the secret for the root node of the
+        // ratchet tree."
+        Ok(UpdateSecret::from(root_node_secret))
+    }
+
+    /// Performs and validates the direct path of an incoming (i.e., one we did not generate)
+    /// Update or CredentialUpdate operation on the `GroupState`, where `sender_tree_idx` is the
+    /// tree index of the sender of this operation
+    ///
+    /// Returns: `Ok(update_secret)` on success, where `update_secret` is the update secret
     /// necessary for generating new epoch secrets.
     fn process_incoming_update_op(
         &mut self,
-        update: &GroupUpdate,
-        sender_tree_idx: usize,
+        path: &DirectPathMessage,
+        sender_tree_idx: NodeIndex,
     ) -> Result<UpdateSecret, Error> {
         // We do three things: compute the new ratchet tree, compute the new transcript hash, and
         // compute the new epoch secrets. We shove all these new values into a delta. To validate
@@ -429,13 +1286,15 @@ impl GroupState {
             let roster_index = self
                 .roster_index
                 .ok_or(Error::ValidationError("Cannot do an Update on a preliminary GroupState"))?;
-            GroupState::roster_index_to_tree_index(roster_index)?
+            roster_index.node_index()?
         };
+        let sender_public_key = self.path_sender_public_key(sender_tree_idx)?;
         let (path_secret, common_ancestor) = self.tree.decrypt_direct_path_message(
             self.cs,
-            &update.path,
+            path,
             sender_tree_idx,
             my_tree_idx,
+            sender_public_key.as_ref(),
         )?;
         let update_secret = self.apply_update(path_secret, common_ancestor)?;
 
@@ -444,7 +1303,7 @@ impl GroupState {
         // performed in apply_update, because this only happens when we're not the ones who created
         // the Update operation.
         let direct_path_public_keys =
-            update.path.node_messages.iter().map(|node_msg| &node_msg.public_key);
+            path.node_messages.iter().map(|node_msg| &node_msg.public_key);
         self.tree.set_public_keys_with_bound(
             sender_tree_idx,
             common_ancestor,
@@ -458,6 +1317,96 @@ impl GroupState {
         Ok(update_secret)
     }
 
+    /// Replaces the credential at `roster_index` with the one in the given CredentialUpdate
+    /// operation, which was sent in the epoch `prior_epoch`. This only touches the roster. The
+    /// direct path is handled by `apply_update` or `process_incoming_update_op`.
+    ///
+    /// Returns: `Ok(())` on success. Returns an `Error::SignatureError` if the new credential's
+    /// signature doesn't verify, and an `Error::ValidationError` if the roster entry is empty or
+    /// the new credential has a different identity than the old one.
+    fn replace_credential(
+        &mut self,
+        cred_update: &GroupCredentialUpdate,
+        roster_index: RosterIndex,
+        prior_epoch: u32,
+    ) -> Result<(), Error> {
+        // Make sure the sender actually holds the key to the credential they're claiming
+        cred_update.verify_credential_sig(&self.group_id, prior_epoch, roster_index)?;
+
+        let old_identity = self
+            .roster
+            .0
+            .get(roster_index.0 as usize)
+            .ok_or(Error::ValidationError("Out of bounds roster index"))?
+            .as_ref()
+            .ok_or(Error::ValidationError("CredentialUpdate sender's roster entry is empty"))?
+            .get_identity();
+
+        // This is for rotating keys and renewing certificates, not for becoming someone else
+        if old_identity != cred_update.new_credential.get_identity() {
+            return Err(Error::ValidationError(
+                "CredentialUpdate cannot change the member's identity",
+            ));
+        }
+
+        // If this is a key rotation, the old key is dead to us now
+        let old_credential =
+            self.roster_mut().replace_at(roster_index, cred_update.new_credential.clone())?;
+        if old_credential.get_public_key() != cred_update.new_credential.get_public_key() {
+            self.retired_credentials.push(old_credential);
+        }
+
+        Ok(())
+    }
+
+    /// Validates an Init operation against this `GroupState`. An Init has to be the first operation
+    /// in its group, so this group must be at epoch 0 with an all-zeros transcript hash, and the
+    /// Init must describe this group exactly. Nothing about the group changes, so this doesn't
+    /// mutate anything.
+    ///
+    /// Returns: `Ok(update_secret)` on success, where `update_secret` is all zeros, just like an
+    /// Add's. Returns an `Error::ValidationError` if the group has already had an operation or if
+    /// the Init describes some other group.
+    fn process_init_op(&self, init: &GroupInit) -> Result<UpdateSecret, Error> {
+        if self.epoch != 0 || self.transcript_hash.as_bytes().iter().any(|&b| b != 0) {
+            return Err(Error::ValidationError("GroupInit isn't the first operation in the group"));
+        }
+
+        // Comparing the encodings checks the public keys of the tree and ignores the private ones
+        let expected = tls_ser::serialize_to_bytes(&self.as_group_init())?;
+        let received = tls_ser::serialize_to_bytes(init)?;
+        if expected != received {
+            return Err(Error::ValidationError("GroupInit doesn't describe this group"));
+        }
+
+        Ok(UpdateSecret::new_from_zeros(self.cs.hash_impl.digest_size()))
+    }
+
+    /// Performs and validates a PolicyUpdate operation sent by the member at `signer_index` in
+    /// epoch `prior_epoch`, replacing this group's `GroupPolicy` extension with the new one
+    ///
+    /// Returns: `Ok(update_secret)` on success, where `update_secret` is all zeros, just like an
+    /// Add's. Returns an `Error::ValidationError` or `Error::SignatureError` if the new policy
+    /// can't replace the current one. See `SignedGroupPolicy::validate_replacement`.
+    fn process_policy_update_op(
+        &mut self,
+        policy_update: &GroupPolicyUpdate,
+        prior_epoch: u32,
+        signer_index: RosterIndex,
+    ) -> Result<UpdateSecret, Error> {
+        let signed_policy = &policy_update.signed_policy;
+        signed_policy.validate_replacement(
+            &self.extensions,
+            &self.roster,
+            &self.group_id,
+            prior_epoch,
+            signer_index,
+        )?;
+        self.extensions.insert(signed_policy)?;
+
+        Ok(UpdateSecret::new_from_zeros(self.cs.hash_impl.digest_size()))
+    }
+
     /// Performs and validates Remove operation on the `GroupState`. This will (necessarily) error
     /// if this member is the one being removed.
     ///
@@ -468,7 +1417,11 @@ impl GroupState {
     // able to process the Remove, whereas the creator of an Update cannot process their own
     // operation (this is because the creator's own path secret is never put into the
     // DirectPathMessage).
-    fn process_remove_op(&mut self, remove: &GroupRemove) -> Result<UpdateSecret, Error> {
+    fn process_remove_op(
+        &mut self,
+        remove: &GroupRemove,
+        sender_tree_idx: NodeIndex,
+    ) -> Result<UpdateSecret, Error> {
         // Find the entropy provided in remove.path that we'll use to update the tree before
         // blanking out the removed node
         let my_tree_idx = {
@@ -477,9 +1430,9 @@ impl GroupState {
             let roster_index = self
                 .roster_index
                 .ok_or(Error::ValidationError("Cannot do a Remove on a preliminary GroupState"))?;
-            GroupState::roster_index_to_tree_index(roster_index)?
+            roster_index.node_index()?
         };
-        let remove_tree_idx = GroupState::roster_index_to_tree_index(remove.removed_roster_index)?;
+        let remove_tree_idx = remove.removed_roster_index.node_index()?;
 
         if my_tree_idx == remove_tree_idx {
             // Oh no, we've been kicked! May as well throw an error now, since the
@@ -488,12 +1441,15 @@ impl GroupState {
             return Err(Error::IAmRemoved);
         }
 
-        // Get the new entropy for the tree
+        // Get the new entropy for the tree. The path starts at the removed member's leaf, but it's
+        // the sender who encrypted it.
+        let sender_public_key = self.path_sender_public_key(sender_tree_idx)?;
         let (new_path_secret, common_ancestor) = self.tree.decrypt_direct_path_message(
             self.cs,
             &remove.path,
             remove_tree_idx,
             my_tree_idx,
+            sender_public_key.as_ref(),
         )?;
 
         // Game plan as per the spec:
@@ -507,6 +1463,13 @@ impl GroupState {
         //   removed leaf
 
         // Update the ratchet tree with the entropy provided in path_secret
+        #[cfg(feature = "dangerous-debug")]
+        self.recorded_secrets.record_path(
+            self.cs,
+            &self.tree,
+            common_ancestor,
+            &new_path_secret,
+        )?;
         let root_node_secret =
             self.tree.propagate_new_path_secret(self.cs, new_path_secret, common_ancestor)?;
         // Update the public keys whose path secret we don't know
@@ -519,19 +1482,21 @@ impl GroupState {
         )?;
 
         // "The update secret resulting from this change is the secret for the root node of the
-        // ratchet tree after the second step". This will be our return value.
+        // ratchet tree after the second step". This will be our return value. The removed member
+        // can't compute it: the new path secrets are only encrypted to the resolutions of their
+        // copath, none of which they hold a key for, and every node they do hold a key for is
+        // either overwritten above or blanked below.
         let update_secret = UpdateSecret::from(root_node_secret);
 
         // Before blank out the direct path of the removed node, check that all the public keys in
         // the message match the ones we derived
         self.tree.validate_direct_path_public_keys(remove_tree_idx, direct_path_public_keys)?;
 
-        // Blank out the roster location
-        self.roster
-            .0
-            .get_mut(remove.removed_roster_index as usize)
-            .map(|cred| *cred = None)
-            .ok_or(Error::ValidationError("Invalid roster index"))?;
+        // Blank out the roster location, and forget who was there
+        let removed_cred = self.roster_mut().remove_at(remove.removed_roster_index)?;
+        if let Some(cred) = removed_cred {
+            self.member_index_mut().remove(cred.get_identity(), remove.removed_roster_index);
+        }
 
         // Try to prune the blanks from the end. Finding yourself in an empty group after a Remove
         // operation should be an impossible state.
@@ -545,7 +1510,7 @@ impl GroupState {
         //     the group (see Error::IAmRemoved conditions in process_handshake and
         //     create_and_apply_remove_op), then it is impossible to have any fewer than 1 group
         //     member. QED
-        self.roster.truncate_to_last_nonblank().expect("Remove resulted in an empty group");
+        self.roster_mut().truncate_to_last_nonblank().expect("Remove resulted in an empty group");
 
         // Blank out the direct path of remove_tree_idx
         self.tree.propagate_blank(remove_tree_idx);
@@ -556,6 +1521,86 @@ impl GroupState {
         Ok(update_secret)
     }
 
+    /// Empties the roster entries at the given indices and blanks their leaves and direct paths,
+    /// without truncating anything. This is the first half of a BatchRemove, both for the member
+    /// who makes it and for everyone who processes it.
+    ///
+    /// Returns: `Ok(())` on success, and an `Error::ValidationError` if an index is past the end
+    /// of the roster
+    fn blank_removed_members(
+        &mut self,
+        removed_roster_indices: &[RosterIndex],
+    ) -> Result<(), Error> {
+        for &removed_roster_index in removed_roster_indices {
+            let removed_tree_idx = removed_roster_index.node_index()?;
+            if let Some(cred) = self.roster_mut().remove_at(removed_roster_index)? {
+                self.member_index_mut().remove(cred.get_identity(), removed_roster_index);
+            }
+            self.tree.propagate_blank(removed_tree_idx);
+        }
+
+        Ok(())
+    }
+
+    /// Performs and validates an incoming BatchRemove operation sent by the member at
+    /// `sender_tree_idx`. The removed members are blanked out first, so that the sender's new path
+    /// is encrypted to and decrypted in a tree that none of them are in anymore.
+    ///
+    /// Returns: `Ok(update_secret)` on success, and an `Error::IAmRemoved` if this member is one of
+    /// the ones being removed. Otherwise returns whatever `process_incoming_update_op` returns.
+    fn process_batch_remove_op(
+        &mut self,
+        batch_remove: &GroupBatchRemove,
+        sender_tree_idx: NodeIndex,
+    ) -> Result<UpdateSecret, Error> {
+        let my_roster_index = self
+            .roster_index
+            .ok_or(Error::ValidationError("Cannot do a BatchRemove on a preliminary GroupState"))?;
+        if batch_remove.removed_roster_indices.contains(&my_roster_index) {
+            return Err(Error::IAmRemoved);
+        }
+
+        self.blank_removed_members(&batch_remove.removed_roster_indices)?;
+        let update_secret = self.process_incoming_update_op(&batch_remove.path, sender_tree_idx)?;
+
+        // Truncate once, now that everyone is gone. The sender isn't removed, so the group can't be
+        // empty. See process_remove_op.
+        self.roster_mut()
+            .truncate_to_last_nonblank()
+            .expect("BatchRemove resulted in an empty group");
+        self.tree.truncate_to_last_nonblank();
+
+        Ok(update_secret)
+    }
+
+    /// Performs and validates an incoming Replace operation sent by the member at
+    /// `sender_tree_idx`. The replaced member is blanked out and the new member is put in their
+    /// place before the sender's path is decrypted, since that's the tree it was encrypted to. The
+    /// new member processes this from the `GroupState` their `Welcome` made, like they would an
+    /// Add.
+    ///
+    /// Returns: `Ok(update_secret)` on success, and an `Error::IAmRemoved` if this member is the
+    /// one being replaced. Otherwise returns whatever `process_add_op` or
+    /// `process_incoming_update_op` returns.
+    fn process_replace_op(
+        &mut self,
+        replace: &GroupReplace,
+        sender_tree_idx: NodeIndex,
+        prior_welcome_info_hash: &WelcomeInfoHash,
+    ) -> Result<UpdateSecret, Error> {
+        let replaced_roster_index = replace.add.roster_index;
+        if self.roster_index == Some(replaced_roster_index) {
+            return Err(Error::IAmRemoved);
+        }
+
+        // Removing first means the new member can have the same identity as the old one, even if
+        // the group doesn't allow duplicate identities
+        self.blank_removed_members(&[replaced_roster_index])?;
+        // An Add's update secret is all zeros, so the path's is the one that counts
+        self.process_add_op(&replace.add, prior_welcome_info_hash)?;
+        self.process_incoming_update_op(&replace.path, sender_tree_idx)
+    }
+
     /// Performs and validates an Add operation on the `GroupState`. Requires a `WelcomeInfo`
     /// representing the `GroupState` before this handshake was received.
     ///
@@ -586,11 +1631,38 @@ impl GroupState {
         // 6. Set the leaf node in the tree at position index to a new node containing the public
         //    key from the UserInitKey in the Add corresponding to the ciphersuite in use
 
+        // The index has to be an empty roster slot or the slot right past the end. We check the
+        // former below, when we actually do the insertion.
         let add_roster_index = add.roster_index;
-        if add_roster_index as usize > self.tree.size() {
+        if add_roster_index.0 as usize > self.roster.len() {
             return Err(Error::ValidationError("Invalid insertion index in Add operation"));
         }
 
+        // Check all the UserInitKeys involved. This comes before anything else looks at the new
+        // member's credential, since until the signature verifies, the credential is only a claim
+        // about who they are. A forged or tampered UserInitKey is rejected here.
+        add.init_key.verify_sig()?;
+        add.init_key.validate()?;
+        self.initializing_user_init_key.as_ref().map(|uik| uik.verify_sig()).transpose()?;
+        self.initializing_user_init_key.as_ref().map(|uik| uik.validate()).transpose()?;
+
+        // Giving someone who's already here a second leaf is only fine if the group allows an
+        // identity to be in it more than once, and even then, not with the same credential. We
+        // check this before the creator makes the Add as well as when it's received, so nobody
+        // ends up with a roster that says the same member is in two places by accident.
+        let new_credential = &add.init_key.credential;
+        let duplicate_identity_policy = self.get_duplicate_identity_policy()?;
+        let duplicate =
+            self.member_index.get_all(new_credential.get_identity().as_bytes()).iter().find(
+                |&&idx| {
+                    duplicate_identity_policy == DuplicateIdentityPolicy::Reject
+                        || self.roster.0[idx.0 as usize].as_ref() == Some(new_credential)
+                },
+            );
+        if let Some(&existing_idx) = duplicate {
+            return Err(Error::DuplicateMember(existing_idx));
+        }
+
         // Constant-time compare the WelcomeInfo hashes (no reason for constant-time other than it
         // feels icky not to do it)
         let hashes_match: bool = prior_welcome_info_hash.ct_eq(&add.welcome_info_hash).into();
@@ -603,102 +1675,106 @@ impl GroupState {
         // self.initializing_user_init_key is non-null.
         let is_preliminary = self.roster_index.is_none();
 
-        // Check all the UserInitKeys involved
-        add.init_key.verify_sig()?;
-        add.init_key.validate()?;
-        self.initializing_user_init_key.as_ref().map(|uik| uik.verify_sig()).transpose()?;
-        self.initializing_user_init_key.as_ref().map(|uik| uik.validate()).transpose()?;
+        // An init key issued under a key its owner has since rotated away from is stale
+        let init_key_public_key = add.init_key.credential.get_public_key();
+        if self.retired_credentials.iter().any(|c| c.get_public_key() == init_key_public_key) {
+            return Err(Error::ValidationError("Add's UserInitKey was issued under a retired key"));
+        }
 
-        // If we just received a WelcomeInfo, we want to use the UserInitKey we created, since it
-        // contains the private key to our ratchet tree node
-        let init_key = if is_preliminary {
+        // A preliminary GroupState is waiting for the Add that adds this member. If several
+        // members were welcomed from the same WelcomeInfo, the Adds for the ones ahead of us in
+        // the batch come first, and we process those like any other member would. We tell the two
+        // apart by the UserInitKey ID.
+        let is_adding_me = if is_preliminary {
             let uik = self.initializing_user_init_key.as_ref().ok_or(Error::ValidationError(
                 "Preliminary GroupState has no initializing UserInitKey",
             ))?;
-            // If it's an initializing key, let's make sure that its ID matches that of the
-            // provided UserInitKey
-            if uik.user_init_key_id != add.init_key.user_init_key_id {
-                return Err(Error::ValidationError(
-                    "Add's UserInitKey and GroupState's initialized UserInitKey differ",
-                ));
-            }
-            uik
+            uik.user_init_key_id == add.init_key.user_init_key_id
         } else {
-            &add.init_key
+            false
         };
 
-        // Is this an appending Add or is it an in-place Add? If in-place, we have to make sure
-        // we're not overwriting any existing members in the group
-        let is_append = add_roster_index as usize == self.roster.len();
-
-        // Update the roster
-        let new_credential = init_key.credential.clone();
-        if is_append {
-            self.roster.0.push(Some(new_credential))
+        // If this Add is for us, we want to use the UserInitKey we created, since it contains the
+        // private key to our ratchet tree node. We unwrap because we checked this is Some above.
+        let init_key = if is_adding_me {
+            self.initializing_user_init_key.as_ref().unwrap()
         } else {
-            // It's an in-place add. Check that we're only overwriting an empty roster entry
-            let entry_to_update = self
-                .roster
-                .0
-                .get_mut(add_roster_index as usize)
-                .ok_or(Error::ValidationError("Out of bounds roster index"))?;
-
-            if entry_to_update.is_some() {
-                return Err(Error::ValidationError("Add tried to overwrite non-null roster entry"));
-            } else {
-                *entry_to_update = Some(new_credential);
-            }
-        }
-
-        // Update the tree. We add a new blank node in the correct position, then set the leaf node
-        // to the appropriate value
-        if is_append {
-            // If we're adding a new node to the end of the tree, we have to make new nodes
-            self.tree.add_leaf_node(RatchetTreeNode::Blank);
-        }
-
-        if is_preliminary {
-            // If we're one being Added, then this index is us
-            self.roster_index = Some(add_roster_index);
-        }
-
-        // Propagate the blank up the tree before we overwrite the new leaf with the new
-        // member's pubkey info
-        let add_tree_index = GroupState::roster_index_to_tree_index(add_roster_index)?;
-        self.tree.propagate_blank(add_tree_index);
+            &add.init_key
+        };
 
         // Now find the node keypair information and make our node in the ratchet tree. The keypair
         // we associate to the new member is the one that corresponds to our current ciphersuite.
-        let public_key = init_key.get_public_key(self.cs)?.ok_or(Error::ValidationError(
-            "UserInitKey has no public keys for group's ciphersuite",
-        ))?;
+        let public_key = init_key.get_compatible_public_key(self.cs, self.protocol_version)?;
         let private_key = init_key.get_private_key(self.cs)?.cloned();
 
-        // The new node we add has the public key we found, and no known secrets
+        // If we're the one being added, the leaf everyone else is about to make for us has to be
+        // the one we can decrypt to. Otherwise we'd silently end up with a different tree.
+        if is_adding_me {
+            let their_public_key =
+                add.init_key.get_compatible_public_key(self.cs, self.protocol_version)?;
+            if their_public_key.as_bytes() != public_key.as_bytes() {
+                return Err(Error::ValidationError(
+                    "Add's UserInitKey has a different public key than our initializing UserInitKey",
+                ));
+            }
+        }
+
+        // The new node we add has the public key we found, and no known secrets
         let new_node = RatchetTreeNode::Filled {
             public_key: public_key.clone(),
             private_key,
         };
 
-        // Check that we're only overwriting a Blank node.
-        let node_to_overwrite = self.tree.get_mut(add_tree_index).unwrap();
-        if node_to_overwrite.is_filled() {
-            return Err(Error::ValidationError("Add tried to overwrite non-blank node"));
-        }
+        // Put the new member in the roster and the tree. Both of these make sure that the index is
+        // either an empty slot or the slot right past the end, so we never overwrite anyone.
+        let credential = init_key.credential.clone();
+        let identity = credential.get_identity().clone();
+        self.roster_mut().add_at(add_roster_index, credential)?;
+        self.member_index_mut().insert(&identity, add_roster_index);
+        self.tree.add_leaf_at(LeafIndex(add_roster_index.0 as usize), new_node)?;
 
-        // Finally, do the overwrite
-        *node_to_overwrite = new_node;
+        if is_adding_me {
+            // If we're one being Added, then this index is us
+            self.roster_index = Some(add_roster_index);
+        }
 
-        // Alright, we're done with the init_key. Make sure that we don't have our initializing
-        // UserInitKey hanging around after this
-        // TODO: Make this erasure secure
-        self.initializing_user_init_key = None;
+        // Alright, we're done with the init_key. Unless we're still waiting on our own Add, make
+        // sure that we don't have our initializing UserInitKey hanging around after this. Its DH
+        // private keys erase themselves on drop.
+        if !is_preliminary || is_adding_me {
+            self.initializing_user_init_key = None;
+        }
 
         // "The update secret resulting from this change is an all-zero octet string of length
         // Hash.length."
         Ok(UpdateSecret::new_from_zeros(self.cs.hash_impl.digest_size()))
     }
 
+    /// Deserializes a `Handshake` sent in this group and upcasts it. The `Handshake`'s signature is
+    /// in its signer's signature scheme, so this looks the signer up in the roster first. This
+    /// parses in the mode given by this group's config.
+    ///
+    /// Returns: `Ok(handshake)` on success. Returns an `Error::ValidationError` if the signer isn't
+    /// in the roster, an `Error::SerdeError` if the bytes can't be deserialized, and some other
+    /// `Error` if they can't be upcast.
+    pub fn deserialize_handshake(&self, bytes: &[u8]) -> Result<Handshake, Error> {
+        let mut cursor = bytes;
+        let mut deserializer =
+            TlsDeserializer::from_reader_with_mode(&mut cursor, self.config.parse_mode);
+        let mut handshake = Handshake::deserialize(&mut deserializer)?;
+        deserializer.finish()?;
+        let signer_credential = self
+            .roster
+            .get(handshake.signer_index)
+            .ok_or(Error::ValidationError("Handshake's signer isn't in the group"))?;
+        let ctx = CryptoCtx::new()
+            .set_cipher_suite(self.cs)
+            .set_signature_scheme(signer_credential.get_signature_scheme());
+        handshake.upcast_crypto_values(&ctx)?;
+
+        Ok(handshake)
+    }
+
     /// Processes the given `Handshake` and, if successful, produces a new `GroupState` and
     /// associated `ApplicationKeyChain` This does not mutate the current `GroupState`. Instead, it
     /// returns the next version of the `GroupState`, where the operation contained by the
@@ -707,8 +1783,52 @@ impl GroupState {
     /// Returns: `Ok((group_state, app_key_chain))` on success, where `group_state` is the
     /// `GroupState` after the given handshake has been applied, and `app_key_chain` is the
     /// `ApplicationKeyChain` belonging to `group_state`. Returns `Error::IAmRemoved` iff this
-    /// member is the subject of a group `Remove` operation. Otherwise, returns some other sort of
-    /// `Error`.
+    /// member is the subject of a validly signed group `Remove` operation. Returns an
+    /// `Error::StateLost` if the `Handshake` can't be processed and this member's own state fails
+    /// `GroupState::check_own_state`, since then the fault is probably ours. Returns an
+    /// `Error::InvalidOperation` if the signer isn't a current member, or the operation doesn't
+    /// fit its signer or its target. Otherwise, returns some other sort of `Error`.
+    pub fn process_handshake(
+        &self,
+        handshake: &Handshake,
+    ) -> Result<(GroupState, ApplicationKeyChain), Error> {
+        self.process_handshake_with_observer(handshake, &mut ())
+    }
+
+    /// Like `GroupState::process_handshake`, but also tells `observer` what the `Handshake` did.
+    /// The observer is only called once the `Handshake` has been fully checked, except for
+    /// `GroupObserver::on_self_removed`, which is called once the `Handshake`'s signature has been
+    /// checked, since there's no way for a removed member to check the rest.
+    ///
+    /// Returns: The same as `GroupState::process_handshake`
+    pub fn process_handshake_with_observer(
+        &self,
+        handshake: &Handshake,
+        observer: &mut dyn GroupObserver,
+    ) -> Result<(GroupState, ApplicationKeyChain), Error> {
+        match self.preview(handshake) {
+            Ok(preview) => Ok(preview.commit_with_observer(observer)),
+            Err(Error::IAmRemoved) => {
+                // preview only says this once it's checked the Remove's signature
+                observer.on_self_removed(&self.group_id, handshake.signer_index);
+                Err(Error::IAmRemoved)
+            }
+            // Only look at our own state once something's gone wrong. Checking it costs a DH
+            // public key derivation, and a healthy member shouldn't pay that on every Handshake.
+            Err(e) => Err(self.check_own_state().err().unwrap_or(e)),
+        }
+    }
+
+    /// Fully checks the given `Handshake` and works out what the group would look like after it,
+    /// without committing to it. This is for applications that want to look at the result before
+    /// accepting it, e.g., to run their own policy checks on the new roster. Nothing is reported
+    /// to metrics or observers until the returned `HandshakePreview` is committed, and dropping it
+    /// instead leaves no trace. Like `GroupState::process_handshake`, this doesn't mutate the
+    /// current `GroupState`, so a `Handshake` that fails partway through can't leave anything
+    /// half-applied.
+    ///
+    /// Returns: `Ok(preview)` on success. Otherwise returns the same errors as
+    /// `GroupState::process_handshake`.
     // According to the spec, this is how we process handshakes:
     // 1. Verify that the prior_epoch field of the Handshake message is equal the epoch field of
     //    the current GroupState object.
@@ -723,19 +1843,23 @@ impl GroupState {
     // 6. Use the confirmation_key for the new group state to compute the confirmation MAC for this
     //    message, as described below, and verify that it is the same as the confirmation field.
     // 7. If the the above checks are successful, consider the updated GroupState object as the
-    //    current state of the group.
-    pub fn process_handshake(
-        &self,
-        handshake: &Handshake,
-    ) -> Result<(GroupState, ApplicationKeyChain), Error> {
+    //    current state of the group. That last step is HandshakePreview::commit.
+    pub fn preview(&self, handshake: &Handshake) -> Result<HandshakePreview, Error> {
+        if handshake.group_id != self.group_id {
+            return Err(Error::ValidationError("Handshake is for a different group"));
+        }
         if handshake.prior_epoch != self.epoch {
             return Err(Error::ValidationError("Handshake's prior epoch isn't the current epoch"));
         }
 
-        let sender_tree_idx = GroupState::roster_index_to_tree_index(handshake.signer_index)?;
-        if sender_tree_idx >= self.tree.size() {
-            return Err(Error::ValidationError("Handshake sender tree index is out of range"));
-        }
+        // Get the sender's public key and preferred signature scheme from the roster, making sure
+        // they're someone who can make this operation in the first place
+        let sender_credential = check_sender_eligibility(&self.roster, &self.tree, handshake)?;
+        self.check_authorization(handshake.signer_index, &handshake.operation)?;
+        let sender_tree_idx = handshake.signer_index.node_index()?;
+
+        // The signature is over the context of the epoch the Handshake was sent in, i.e., this one
+        let prior_context = self.group_context()?;
 
         // Make a preliminary new state and  update its epoch and transcript hash. The state is
         // further mutated in the branches of the match statement below
@@ -743,38 +1867,79 @@ impl GroupState {
         new_state.update_transcript_hash(&handshake.operation)?;
         new_state.increment_epoch()?;
 
-        // Get the sender's public key and preferred signature scheme from the roster. There are
-        // two things that can go wrong here: either the sender index is bad, or the index is good
-        // but the roster entry is empty.
-        let sender_credential = self
-            .roster
-            .0
-            .get(handshake.signer_index as usize)
-            .ok_or(Error::ValidationError("Handshake's signer index is out of bounds"))?
-            .as_ref()
-            .ok_or(Error::ValidationError("Handshake's signer credential is empty"))?;
-        let sender_public_key = sender_credential.get_public_key();
-        let sender_ss = sender_credential.get_signature_scheme();
-
         // Do the handshake operation on the preliminary new state. This returns an update secret
         // that the new epoch secrets are derived from.
         let update_secret = match handshake.operation {
             GroupOperation::Update(ref update) => {
-                new_state.process_incoming_update_op(update, sender_tree_idx)?
+                new_state.process_incoming_update_op(&update.path, sender_tree_idx)?
             }
-            GroupOperation::Remove(ref remove) => new_state.process_remove_op(remove)?,
-            GroupOperation::Add(ref add) => {
-                // Compute the hash of the welcome_info that created this group, which is
-                // just the state of this group
-                let prior_welcome_info_hash = {
-                    let prior_welcome_info = self.as_welcome_info();
-                    let digest = self.cs.hash_impl.hash_serializable(&prior_welcome_info)?;
-                    WelcomeInfoHash::from(digest)
+            GroupOperation::CredentialUpdate(ref cred_update) => {
+                let update_secret =
+                    new_state.process_incoming_update_op(&cred_update.path, sender_tree_idx)?;
+                new_state.replace_credential(
+                    cred_update,
+                    handshake.signer_index,
+                    handshake.prior_epoch,
+                )?;
+                update_secret
+            }
+            GroupOperation::Remove(ref remove) => {
+                if Some(remove.removed_roster_index) == self.roster_index {
+                    // We can't derive the new epoch's secrets, so we can't check the confirmation.
+                    // But we can at least make sure the removal is real before telling anyone.
+                    new_state.verify_handshake_signature(
+                        handshake,
+                        &prior_context,
+                        sender_credential,
+                    )?;
+                    return Err(Error::IAmRemoved);
+                }
+                new_state.process_remove_op(remove, sender_tree_idx)?
+            }
+            GroupOperation::BatchRemove(ref batch_remove) => {
+                let removes_me = match self.roster_index {
+                    Some(idx) => batch_remove.removed_roster_indices.contains(&idx),
+                    None => false,
                 };
+                if removes_me {
+                    // Same as for a Remove
+                    new_state.verify_handshake_signature(
+                        handshake,
+                        &prior_context,
+                        sender_credential,
+                    )?;
+                    return Err(Error::IAmRemoved);
+                }
+                new_state.process_batch_remove_op(batch_remove, sender_tree_idx)?
+            }
+            GroupOperation::Replace(ref replace) => {
+                if self.roster_index == Some(replace.add.roster_index) {
+                    // Same as for a Remove
+                    new_state.verify_handshake_signature(
+                        handshake,
+                        &prior_context,
+                        sender_credential,
+                    )?;
+                    return Err(Error::IAmRemoved);
+                }
+                // Same as for an Add
+                let prior_welcome_info_hash = self.welcome_info_hash()?;
+                new_state.process_replace_op(replace, sender_tree_idx, &prior_welcome_info_hash)?
+            }
+            GroupOperation::Add(ref add) => {
+                // Compute the hash of the welcome_info that the new member should have gotten,
+                // which is just the state of this group. If the adder showed them something else,
+                // this won't match.
+                let prior_welcome_info_hash = self.welcome_info_hash()?;
                 new_state.process_add_op(add, &prior_welcome_info_hash)?
             }
-            // The spec hasn't weighed on group Init yet
-            GroupOperation::Init(_) => unimplemented!(),
+            // This checks the Init against the group as it was before, so it uses self
+            GroupOperation::Init(ref init) => self.process_init_op(init)?,
+            GroupOperation::PolicyUpdate(ref policy_update) => new_state.process_policy_update_op(
+                policy_update,
+                handshake.prior_epoch,
+                handshake.signer_index,
+            )?,
         };
 
         let (app_secret, confirmation_key) = new_state.update_epoch_secrets(&update_secret)?;
@@ -786,11 +1951,9 @@ impl GroupState {
         // Make the state immutable for the rest of this function
         let new_state = new_state;
 
-        // Check the signature. From section 7 of the spec:
-        // signature_data = GroupState.transcript_hash
-        // Handshake.signature = Sign(identity_key, signature_data)
-        let sig_data = new_state.transcript_hash.as_bytes();
-        sender_ss.verify(sender_public_key, sig_data, &handshake.signature)?;
+        // Check the signature. For a CredentialUpdate, this is under the sender's old credential,
+        // since that's what authorizes the change.
+        new_state.verify_handshake_signature(handshake, &prior_context, sender_credential)?;
 
         // Check the MAC. From section 7 of the spec:
         // confirmation_data = GroupState.transcript_hash || Handshake.signature
@@ -805,9 +1968,81 @@ impl GroupState {
             &handshake.confirmation,
         )?;
 
-        // All is well. Make the new application key chain and send it along
-        let app_key_chain = ApplicationKeyChain::from_application_secret(&new_state, app_secret);
-        Ok((new_state, app_key_chain))
+        // All is well. Make the new application key chain. The new state has no use for anything
+        // from the previous epoch.
+        let app_key_chain = ApplicationKeyChain::from_application_secret(&new_state, app_secret)?;
+        let mut new_state = new_state;
+        new_state.erase_old_epochs();
+
+        // Write down what the observers will need to hear about once this is committed
+        let change = match handshake.operation {
+            GroupOperation::Add(ref add) => {
+                MembershipChange::Added(add.roster_index, add.init_key.credential.clone())
+            }
+            GroupOperation::Remove(ref remove) => {
+                let removed_index = remove.removed_roster_index;
+                match self.roster.get(removed_index) {
+                    Some(old_credential) => {
+                        MembershipChange::Removed(vec![(removed_index, old_credential.clone())])
+                    }
+                    None => MembershipChange::Unchanged,
+                }
+            }
+            GroupOperation::BatchRemove(ref batch_remove) => MembershipChange::Removed(
+                batch_remove
+                    .removed_roster_indices
+                    .iter()
+                    .filter_map(|&idx| self.roster.get(idx).map(|cred| (idx, cred.clone())))
+                    .collect(),
+            ),
+            GroupOperation::Replace(ref replace) => {
+                let replaced_index = replace.add.roster_index;
+                match self.roster.get(replaced_index) {
+                    Some(old_credential) => MembershipChange::Replaced(
+                        replaced_index,
+                        old_credential.clone(),
+                        replace.add.init_key.credential.clone(),
+                    ),
+                    None => MembershipChange::Unchanged,
+                }
+            }
+            GroupOperation::CredentialUpdate(ref cred_update) => {
+                MembershipChange::CredentialChanged(
+                    handshake.signer_index,
+                    sender_credential.clone(),
+                    cred_update.new_credential.clone(),
+                )
+            }
+            GroupOperation::Update(_)
+            | GroupOperation::Init(_)
+            | GroupOperation::PolicyUpdate(_) => MembershipChange::Unchanged,
+        };
+
+        Ok(HandshakePreview {
+            group_state: new_state,
+            app_key_chain,
+            op_kind: handshake.operation.kind(),
+            prior_epoch: handshake.prior_epoch,
+            signer_index: handshake.signer_index,
+            change,
+        })
+    }
+
+    /// Checks the signature on `handshake` under `sender_credential`. The signature covers the
+    /// `GroupContext` the `Handshake` was sent in, `prior_context`, as well as the group's history,
+    /// so this must be called on the provisional new state, whose transcript hash already includes
+    /// the `Handshake`'s operation:
+    /// `Handshake.signature = Sign(identity_key, HandshakeSignatureContent)`
+    ///
+    /// Returns: `Ok(())` if the signature verifies. Otherwise returns an `Error::SignatureError`,
+    /// or an `Error::SerdeError` if the signed content can't be serialized.
+    fn verify_handshake_signature(
+        &self,
+        handshake: &Handshake,
+        prior_context: &GroupContext,
+        sender_credential: &Credential,
+    ) -> Result<(), Error> {
+        handshake.verify_sig(sender_credential, prior_context, &self.transcript_hash)
     }
 
     /// Creates and applies a `GroupUpdate` operation with the given path secret information. This
@@ -837,7 +2072,7 @@ impl GroupState {
             let roster_index = new_group_state.roster_index.ok_or(Error::ValidationError(
                 "Cannot make an Update from a preliminary GroupState",
             ))?;
-            GroupState::roster_index_to_tree_index(roster_index)?
+            roster_index.node_index()?
         };
 
         // Do the update and increment the epoch
@@ -849,12 +2084,14 @@ impl GroupState {
             new_group_state.cs,
             my_tree_idx,
             new_path_secret,
+            self.path_sender_secret(my_tree_idx)?,
             csprng,
         )?;
         let update = GroupUpdate {
             path: direct_path_msg,
         };
         let op = GroupOperation::Update(update);
+        self.check_own_authorization(&op)?;
 
         new_group_state.update_transcript_hash(&op)?;
 
@@ -862,7 +2099,79 @@ impl GroupState {
         let (app_secret, confirmation_key) =
             new_group_state.update_epoch_secrets(&update_secret)?;
         let app_key_chain =
-            ApplicationKeyChain::from_application_secret(&new_group_state, app_secret);
+            ApplicationKeyChain::from_application_secret(&new_group_state, app_secret)?;
+
+        Ok((new_group_state, app_key_chain, op, confirmation_key))
+    }
+
+    /// Creates and applies a `GroupCredentialUpdate` operation with the given path secret
+    /// information, replacing this member's credential and identity key with the given ones. This
+    /// method does not mutate this `GroupState`, the operation is rather applied to the returned
+    /// `GroupState`.
+    ///
+    /// Returns: `Ok((group_state, app_key_chain, group_op, confirmation_key))` on success, where
+    /// the values are as in `create_and_apply_update_op`
+    pub(crate) fn create_and_apply_credential_update_op<R>(
+        &self,
+        new_credential: Credential,
+        new_identity_key: SigSecretKey,
+        new_path_secret: PathSecret,
+        csprng: &mut R,
+    ) -> Result<(GroupState, ApplicationKeyChain, GroupOperation, ConfirmationKey), Error>
+    where
+        R: CryptoRng,
+    {
+        // Ugh, a full group state clone, I know
+        let mut new_group_state = self.clone();
+
+        // Safely unwrap the roster index. A preliminary GroupState is one that has just been
+        // initialized with a Welcome message
+        let roster_index = new_group_state.roster_index.ok_or(Error::ValidationError(
+            "Cannot make a CredentialUpdate from a preliminary GroupState",
+        ))?;
+        let my_tree_idx = roster_index.node_index()?;
+
+        // Do the update and increment the epoch
+        let update_secret = new_group_state.apply_update(new_path_secret.clone(), my_tree_idx)?;
+        new_group_state.increment_epoch()?;
+
+        // Now package the update into a GroupCredentialUpdate structure. The credential signature
+        // is bound to the epoch this operation is sent in, i.e., the current one.
+        let direct_path_msg = new_group_state.tree.encrypt_direct_path_secrets(
+            new_group_state.cs,
+            my_tree_idx,
+            new_path_secret,
+            self.path_sender_secret(my_tree_idx)?,
+            csprng,
+        )?;
+        let credential_signature = GroupCredentialUpdate::sign_credential(
+            &self.group_id,
+            self.epoch,
+            roster_index,
+            &new_credential,
+            &new_identity_key,
+        )?;
+        let cred_update = GroupCredentialUpdate {
+            path: direct_path_msg,
+            new_credential,
+            credential_signature,
+        };
+
+        // Swap in the new credential and key. This verifies the signature we just made, so a
+        // credential that doesn't match new_identity_key is caught here rather than by everyone
+        // else in the group.
+        new_group_state.replace_credential(&cred_update, roster_index, self.epoch)?;
+        new_group_state.identity_key = IdentityKey::Local(new_identity_key);
+
+        let op = GroupOperation::CredentialUpdate(cred_update);
+        self.check_own_authorization(&op)?;
+        new_group_state.update_transcript_hash(&op)?;
+
+        // Final modification: update my epoch secrets and make the new ApplicationKeyChain
+        let (app_secret, confirmation_key) =
+            new_group_state.update_epoch_secrets(&update_secret)?;
+        let app_key_chain =
+            ApplicationKeyChain::from_application_secret(&new_group_state, app_secret)?;
 
         Ok((new_group_state, app_key_chain, op, confirmation_key))
     }
@@ -882,10 +2191,19 @@ impl GroupState {
     // create_*_op functions, this should mutate the GroupState too.
     pub(crate) fn create_and_apply_add_op(
         &self,
-        new_roster_index: u32,
+        new_roster_index: RosterIndex,
         init_key: UserInitKey,
         prior_welcome_info_hash: &WelcomeInfoHash,
     ) -> Result<(GroupState, ApplicationKeyChain, GroupOperation, ConfirmationKey), Error> {
+        if let Some(max_group_size) = self.config.max_group_size {
+            if self.get_member_count() >= max_group_size {
+                return Err(Error::ValidationError("Group is already at its maximum size"));
+            }
+        }
+        // Only the adder checks this. Members' clocks can disagree, and a group where some members
+        // accepted an Add and others didn't is no longer a group.
+        init_key.check_lifetime(self.config.get_clock())?;
+
         // Ugh, a full group state clone, I know
         let mut new_group_state = self.clone();
 
@@ -896,15 +2214,20 @@ impl GroupState {
             welcome_info_hash: prior_welcome_info_hash.clone(),
         };
         // Apply the Add, log the operation in the transcript hash, increment the epoch, update
-        // the epoch secrets, and make the new ApplicationKeyChain
-        let update_secret = new_group_state.process_add_op(&add, prior_welcome_info_hash)?;
+        // the epoch secrets, and make the new ApplicationKeyChain. We check the given hash against
+        // our own state, just like everyone else will. This catches Welcomes made from a stale
+        // GroupState.
+        let my_welcome_info_hash = self.welcome_info_hash()?;
+        let update_secret = new_group_state.process_add_op(&add, &my_welcome_info_hash)?;
+        new_group_state.insert_cached_welcome(self.cached_welcome(&add.init_key)?);
         let op = GroupOperation::Add(add);
+        self.check_own_authorization(&op)?;
         new_group_state.update_transcript_hash(&op)?;
         new_group_state.increment_epoch()?;
         let (app_secret, confirmation_key) =
             new_group_state.update_epoch_secrets(&update_secret)?;
         let app_key_chain =
-            ApplicationKeyChain::from_application_secret(&new_group_state, app_secret);
+            ApplicationKeyChain::from_application_secret(&new_group_state, app_secret)?;
 
         Ok((new_group_state, app_key_chain, op, confirmation_key))
     }
@@ -926,7 +2249,7 @@ impl GroupState {
     // create_*_op functions, this should mutate the GroupState too.
     pub(crate) fn create_and_apply_remove_op<R>(
         &self,
-        removed_roster_index: u32,
+        removed_roster_index: RosterIndex,
         new_path_secret: PathSecret,
         csprng: &mut R,
     ) -> Result<(GroupState, ApplicationKeyChain, GroupOperation, ConfirmationKey), Error>
@@ -936,12 +2259,17 @@ impl GroupState {
         // Ugh, a full group state clone, I know
         let mut new_group_state = self.clone();
 
-        let removed_tree_index = GroupState::roster_index_to_tree_index(removed_roster_index)?;
+        let my_tree_idx = self
+            .roster_index
+            .ok_or(Error::ValidationError("Cannot make a Remove from a preliminary GroupState"))?
+            .node_index()?;
+        let removed_tree_index = removed_roster_index.node_index()?;
         // Encrypt the new entropy for the tree
         let direct_path_msg = new_group_state.tree.encrypt_direct_path_secrets(
             new_group_state.cs,
             removed_tree_index,
             new_path_secret,
+            self.path_sender_secret(my_tree_idx)?,
             csprng,
         )?;
 
@@ -953,14 +2281,199 @@ impl GroupState {
 
         // Apply the Remove, log the operation in the transcript hash, increment the epoch, update
         // the epoch secrets, and make the new ApplicationKeyChain
-        let update_secret = new_group_state.process_remove_op(&remove)?;
+        let update_secret = new_group_state.process_remove_op(&remove, my_tree_idx)?;
         let op = GroupOperation::Remove(remove);
+        self.check_own_authorization(&op)?;
+        new_group_state.update_transcript_hash(&op)?;
+        new_group_state.increment_epoch()?;
+        let (app_secret, confirmation_key) =
+            new_group_state.update_epoch_secrets(&update_secret)?;
+        let app_key_chain =
+            ApplicationKeyChain::from_application_secret(&new_group_state, app_secret)?;
+
+        Ok((new_group_state, app_key_chain, op, confirmation_key))
+    }
+
+    /// Creates and applies a `GroupBatchRemove` operation for the members at the given roster
+    /// indices, and introduces a new path secret `new_path_secret` at this member's leaf. This
+    /// method does not mutate this `GroupState`, the operation is rather applied to the returned
+    /// `GroupState`.
+    ///
+    /// Returns: `Ok((group_state, app_key_chain, group_op, confirmation_key))` on success, where
+    /// the values are as in `create_and_apply_remove_op`. Returns an `Error::IAmRemoved` if this
+    /// member is one of the ones being removed, and an `Error::InvalidOperation` if an index isn't
+    /// a current member's or there are no indices at all.
+    pub(crate) fn create_and_apply_batch_remove_op<R>(
+        &self,
+        removed_roster_indices: &[RosterIndex],
+        new_path_secret: PathSecret,
+        csprng: &mut R,
+    ) -> Result<(GroupState, ApplicationKeyChain, GroupOperation, ConfirmationKey), Error>
+    where
+        R: CryptoRng,
+    {
+        let roster_index = self.roster_index.ok_or(Error::ValidationError(
+            "Cannot make a BatchRemove from a preliminary GroupState",
+        ))?;
+        let my_tree_idx = roster_index.node_index()?;
+        if removed_roster_indices.contains(&roster_index) {
+            return Err(Error::IAmRemoved);
+        }
+
+        // The wire format wants the indices in order and without repeats
+        let mut removed_roster_indices: Vec<RosterIndex> = removed_roster_indices.to_vec();
+        removed_roster_indices.sort();
+        removed_roster_indices.dedup();
+        check_remove_targets(&self.roster, roster_index, &removed_roster_indices)?;
+
+        // Ugh, a full group state clone, I know
+        let mut new_group_state = self.clone();
+
+        // Blank out everyone who's leaving, then do an update from our leaf in what's left
+        new_group_state.blank_removed_members(&removed_roster_indices)?;
+        let update_secret = new_group_state.apply_update(new_path_secret.clone(), my_tree_idx)?;
+        new_group_state.increment_epoch()?;
+        let direct_path_msg = new_group_state.tree.encrypt_direct_path_secrets(
+            new_group_state.cs,
+            my_tree_idx,
+            new_path_secret,
+            self.path_sender_secret(my_tree_idx)?,
+            csprng,
+        )?;
+        // Only truncate once the path is encrypted, since the receivers decrypt it before they
+        // truncate. See process_batch_remove_op.
+        new_group_state
+            .roster_mut()
+            .truncate_to_last_nonblank()
+            .expect("BatchRemove emptied the group");
+        new_group_state.tree.truncate_to_last_nonblank();
+
+        let batch_remove = GroupBatchRemove {
+            removed_roster_indices,
+            path: direct_path_msg,
+        };
+        let op = GroupOperation::BatchRemove(batch_remove);
+        self.check_own_authorization(&op)?;
+        new_group_state.update_transcript_hash(&op)?;
+
+        // Final modification: update my epoch secrets and make the new ApplicationKeyChain
+        let (app_secret, confirmation_key) =
+            new_group_state.update_epoch_secrets(&update_secret)?;
+        let app_key_chain =
+            ApplicationKeyChain::from_application_secret(&new_group_state, app_secret)?;
+
+        Ok((new_group_state, app_key_chain, op, confirmation_key))
+    }
+
+    /// Creates and applies a `GroupReplace` operation that puts the owner of `init_key` where the
+    /// member at roster index `replaced_roster_index` is now, and introduces a new path secret
+    /// `new_path_secret` at this member's leaf. This method does not mutate this `GroupState`, the
+    /// operation is rather applied to the returned `GroupState`.
+    ///
+    /// Returns: `Ok((group_state, app_key_chain, group_op, confirmation_key))` on success, where
+    /// the values are as in `create_and_apply_add_op`. Returns an `Error::IAmRemoved` if this
+    /// member is the one being replaced, and an `Error::InvalidOperation` if
+    /// `replaced_roster_index` isn't a current member's. Otherwise returns whatever
+    /// `process_add_op` returns.
+    pub(crate) fn create_and_apply_replace_op<R>(
+        &self,
+        replaced_roster_index: RosterIndex,
+        init_key: UserInitKey,
+        prior_welcome_info_hash: &WelcomeInfoHash,
+        new_path_secret: PathSecret,
+        csprng: &mut R,
+    ) -> Result<(GroupState, ApplicationKeyChain, GroupOperation, ConfirmationKey), Error>
+    where
+        R: CryptoRng,
+    {
+        let roster_index = self
+            .roster_index
+            .ok_or(Error::ValidationError("Cannot make a Replace from a preliminary GroupState"))?;
+        let my_tree_idx = roster_index.node_index()?;
+        if replaced_roster_index == roster_index {
+            return Err(Error::IAmRemoved);
+        }
+        check_remove_targets(&self.roster, roster_index, &[replaced_roster_index])?;
+        // Same as for an Add
+        init_key.check_lifetime(self.config.get_clock())?;
+
+        // Ugh, a full group state clone, I know
+        let mut new_group_state = self.clone();
+
+        // Swap the members, checking the given hash against our own state like everyone else will,
+        // and then do an update from our leaf in the resulting tree
+        let add = GroupAdd {
+            roster_index: replaced_roster_index,
+            init_key,
+            welcome_info_hash: prior_welcome_info_hash.clone(),
+        };
+        let my_welcome_info_hash = self.welcome_info_hash()?;
+        new_group_state.blank_removed_members(&[replaced_roster_index])?;
+        new_group_state.process_add_op(&add, &my_welcome_info_hash)?;
+        new_group_state.insert_cached_welcome(self.cached_welcome(&add.init_key)?);
+        let update_secret = new_group_state.apply_update(new_path_secret.clone(), my_tree_idx)?;
+        new_group_state.increment_epoch()?;
+        let direct_path_msg = new_group_state.tree.encrypt_direct_path_secrets(
+            new_group_state.cs,
+            my_tree_idx,
+            new_path_secret,
+            self.path_sender_secret(my_tree_idx)?,
+            csprng,
+        )?;
+
+        let replace = GroupReplace {
+            add,
+            path: direct_path_msg,
+        };
+        let op = GroupOperation::Replace(replace);
+        self.check_own_authorization(&op)?;
+        new_group_state.update_transcript_hash(&op)?;
+
+        // Final modification: update my epoch secrets and make the new ApplicationKeyChain
+        let (app_secret, confirmation_key) =
+            new_group_state.update_epoch_secrets(&update_secret)?;
+        let app_key_chain =
+            ApplicationKeyChain::from_application_secret(&new_group_state, app_secret)?;
+
+        Ok((new_group_state, app_key_chain, op, confirmation_key))
+    }
+
+    /// Creates and applies a `GroupPolicyUpdate` operation that replaces the group's `GroupPolicy`
+    /// with `policy`, signed by this member. This method does not mutate this `GroupState`, the
+    /// operation is rather applied to the returned `GroupState`.
+    ///
+    /// Returns: `Ok((group_state, app_key_chain, group_op, confirmation_key))` on success, where
+    /// `group_state` is the group state after having applied the operation, `app_key_chain` is
+    /// the resulting application key chain, `group_op` is the raw `GroupOperation` object, and
+    /// `confirmation_key` is the derived confirmation key we'll use to compute the MAC in the
+    /// `Handshake` that will end up containing the `GroupOperation`.
+    pub(crate) fn create_and_apply_policy_update_op(
+        &self,
+        policy: GroupPolicy,
+    ) -> Result<(GroupState, ApplicationKeyChain, GroupOperation, ConfirmationKey), Error> {
+        // Ugh, a full group state clone, I know
+        let mut new_group_state = self.clone();
+
+        // Sign the policy for this epoch and apply it. We check it just like everyone else will.
+        let policy_update = GroupPolicyUpdate {
+            signed_policy: SignedGroupPolicy::new(self, policy)?,
+        };
+        let my_roster_index = self.roster_index.ok_or(Error::ValidationError(
+            "Cannot make a PolicyUpdate from a preliminary GroupState",
+        ))?;
+        let update_secret = new_group_state.process_policy_update_op(
+            &policy_update,
+            self.epoch,
+            my_roster_index,
+        )?;
+        let op = GroupOperation::PolicyUpdate(policy_update);
+        self.check_own_authorization(&op)?;
         new_group_state.update_transcript_hash(&op)?;
         new_group_state.increment_epoch()?;
         let (app_secret, confirmation_key) =
             new_group_state.update_epoch_secrets(&update_secret)?;
         let app_key_chain =
-            ApplicationKeyChain::from_application_secret(&new_group_state, app_secret);
+            ApplicationKeyChain::from_application_secret(&new_group_state, app_secret)?;
 
         Ok((new_group_state, app_key_chain, op, confirmation_key))
     }
@@ -971,16 +2484,77 @@ impl GroupState {
     /// `GroupState`.
     ///
     /// NOTE: This is intended to be called only on objects returned from `create_and_apply_*_op`,
-    /// where `*` is `add` or `update` or `remove`. This makes no sense otherwise.
+    /// where `*` is `add` or `update` or `remove`. This makes no sense otherwise. `prior_context`
+    /// is the `GroupContext` of the state the operation was applied to.
     fn create_handshake(
+        &self,
+        prior_context: &GroupContext,
+        operation: GroupOperation,
+        confirmation_key: ConfirmationKey,
+    ) -> Result<Handshake, Error> {
+        self.create_handshake_signed_by(
+            prior_context,
+            operation,
+            confirmation_key,
+            &self.identity_key,
+            self.my_credential(),
+        )
+    }
+
+    /// Like `create_handshake`, but signs with the given identity key as the given credential
+    /// instead of this member's current ones. This is for CredentialUpdates, which have to be
+    /// signed with the credential that's being replaced.
+    fn create_handshake_signed_by(
+        &self,
+        prior_context: &GroupContext,
+        operation: GroupOperation,
+        confirmation_key: ConfirmationKey,
+        identity_key: &IdentityKey,
+        signer_credential: &Credential,
+    ) -> Result<Handshake, Error> {
+        // signature = Sign(identity_key, HandshakeSignatureContent)
+        let sig_data = self.handshake_to_be_signed(prior_context, &operation)?;
+        let signature = identity_key.sign(signer_credential, &sig_data)?;
+
+        self.finish_handshake(prior_context.epoch, operation, confirmation_key, signature)
+    }
+
+    /// Returns the bytes that a `Handshake` carrying `operation` is signed over, i.e., the
+    /// serialized `HandshakeSignatureContent`. Like `create_handshake`, this is called on the
+    /// state the operation has already been applied to.
+    fn handshake_to_be_signed(
+        &self,
+        prior_context: &GroupContext,
+        operation: &GroupOperation,
+    ) -> Result<Vec<u8>, Error> {
+        // Safely unwrap the roster index. A preliminary GroupState is one that has just been
+        // initialized with a Welcome message
+        let roster_index = self.roster_index.ok_or(Error::ValidationError(
+            "Cannot make a Handshake from a preliminary GroupState",
+        ))?;
+
+        HandshakeSignatureContent {
+            group_context: prior_context,
+            operation,
+            signer_index: roster_index,
+            transcript_hash: &self.transcript_hash,
+        }
+        .to_bytes()
+    }
+
+    /// Packages the given `GroupOperation` and its signature into a `Handshake`, computing the
+    /// confirmation MAC. Like `create_handshake`, this is called on the state the operation has
+    /// already been applied to.
+    fn finish_handshake(
         &self,
         prior_epoch: u32,
         operation: GroupOperation,
         confirmation_key: ConfirmationKey,
+        signature: Signature,
     ) -> Result<Handshake, Error> {
-        // signature = Sign(identity_key, GroupState.transcript_hash)
-        let my_ss = self.get_signature_scheme();
-        let signature = my_ss.sign(&self.identity_key, self.transcript_hash.as_bytes());
+        let roster_index = self.roster_index.ok_or(Error::ValidationError(
+            "Cannot make a Handshake from a preliminary GroupState",
+        ))?;
 
         // Update the epoch secrets and use the resulting key to compute the MAC of the Handshake
 
@@ -994,13 +2568,8 @@ impl GroupState {
             ctx.finalize()
         };
 
-        // Safely unwrap the roster index. A preliminary GroupState is one that has just been
-        // initialized with a Welcome message
-        let roster_index = self.roster_index.ok_or(Error::ValidationError(
-            "Cannot make a Handshake from a preliminary GroupState",
-        ))?;
-
         let handshake = Handshake {
+            group_id: self.group_id.clone(),
             prior_epoch,
             operation,
             signer_index: roster_index,
@@ -1019,11 +2588,183 @@ impl GroupState {
         &self.roster
     }
 
-    /// Creates and applies a `GroupUpdate` operation with the given path secret information. This
-    /// method does not mutate this `GroupState`, the operation is rather applied to the returned
-    /// `GroupState`.
-    ///
-    /// Returns: `Ok((handshake, group_state, app_key_chain))` on success, where `handshake` is the
+    /// Returns this group's ID
+    pub fn get_group_id(&self) -> &[u8] {
+        &self.group_id
+    }
+
+    /// Returns this group's current epoch
+    pub fn get_epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// Returns this group's current transcript hash. See `verify::verify_handshake` for why
+    /// someone outside the group would want this.
+    pub fn get_transcript_hash(&self) -> &[u8] {
+        self.transcript_hash.as_bytes()
+    }
+
+    /// Returns the hash of this group's ratchet tree. This covers every public key in the tree and
+    /// which nodes are blank, so two members with the same tree hash agree on the tree.
+    ///
+    /// Returns: `Ok(tree_hash)` on success. Returns an `Error::SerdeError` if the tree can't be
+    /// serialized.
+    pub fn get_tree_hash(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.group_context()?.tree_hash.as_bytes().to_vec())
+    }
+
+    /// Returns the `GroupContext` of this group's current epoch. This is the cached one if it's
+    /// still current, and is computed from scratch otherwise.
+    ///
+    /// Returns: `Ok(group_context)` on success. Returns an `Error::SerdeError` if the tree can't
+    /// be serialized.
+    pub(crate) fn group_context(&self) -> Result<GroupContext, Error> {
+        match self.cached_context {
+            Some((tree_version, ref context))
+                if tree_version == self.tree.version()
+                    && context.epoch == self.epoch
+                    && context.transcript_hash.as_bytes() == self.transcript_hash.as_bytes() =>
+            {
+                Ok(context.clone())
+            }
+            _ => GroupContext::new(
+                self.cs,
+                &self.group_id,
+                self.epoch,
+                &self.tree,
+                &self.transcript_hash,
+            ),
+        }
+    }
+
+    /// Recomputes the cached `GroupContext`, along with the tree's cached node hashes. A stale one
+    /// is never used, so this is only needed to have the next `GroupState::group_context` call be
+    /// cheap. Since only the node hashes that changed get recomputed, so is this, after the first
+    /// time.
+    ///
+    /// Returns: `Ok(())` on success. Returns an `Error::SerdeError` if the tree can't be
+    /// serialized.
+    pub(crate) fn refresh_group_context(&mut self) -> Result<(), Error> {
+        self.tree.refresh_node_hashes(self.cs)?;
+        let context = self.group_context()?;
+        self.cached_context = Some((self.tree.version(), context));
+        Ok(())
+    }
+
+    /// Returns this member's index in the roster. This is `None` iff this `GroupState` was just
+    /// created from a `Welcome` and hasn't processed the corresponding Add yet.
+    pub fn get_roster_index(&self) -> Option<RosterIndex> {
+        self.roster_index
+    }
+
+    /// Returns the number of members in this group. Unlike `Roster::len`, this does not count
+    /// empty roster entries.
+    pub fn get_member_count(&self) -> usize {
+        self.roster.credential_iter().count()
+    }
+
+    /// Returns the roster index of the member with the given identity, or `None` if there is no
+    /// such member. If several members have the same identity, this returns the lowest of their
+    /// indices. This is a lookup in an index, so it doesn't scan the roster.
+    pub fn find_member(&self, identity: &[u8]) -> Option<RosterIndex> {
+        self.member_index.get(identity)
+    }
+
+    /// Returns the roster index and credential of the member whose leaf is at `leaf_idx` in the
+    /// ratchet tree, or `None` if that leaf is blank or out of bounds
+    pub fn get_member_at_leaf(&self, leaf_idx: LeafIndex) -> Option<(RosterIndex, &Credential)> {
+        // Leaf indices and roster indices are the same thing
+        let roster_index = RosterIndex::try_from(leaf_idx).ok()?;
+        let credential = self.roster.0.get(leaf_idx.0)?.as_ref()?;
+        Some((roster_index, credential))
+    }
+
+    /// Checks that this member's own part of the group's state is intact, i.e., that the roster
+    /// has this member's credential where this `GroupState` says this member is, and that this
+    /// member's leaf has the private key to its public key. A member whose state fails this, e.g.,
+    /// because it was restored from a bad backup, can't decrypt the path secrets sent to it, even
+    /// though everyone else still counts it as a member. The only way back in is for another
+    /// member to re-add it with a `Replace` of its own roster entry. See `MlsClient::readd`.
+    ///
+    /// Returns: `Ok(())` if this member's state is intact or this `GroupState` is preliminary.
+    /// Returns an `Error::StateLost` otherwise.
+    pub fn check_own_state(&self) -> Result<(), Error> {
+        let roster_index = match self.roster_index {
+            Some(roster_index) => roster_index,
+            None => return Ok(()),
+        };
+
+        // If the identity key is in a KeyStore, there's no key to compare here. The KeyStore
+        // checks its own signatures.
+        let credential = self.roster.get(roster_index).ok_or(Error::StateLost)?;
+        if let IdentityKey::Local(ref identity_key) = self.identity_key {
+            credential.check_identity_key(identity_key).map_err(|_| Error::StateLost)?;
+        }
+
+        let leaf_idx = roster_index.node_index().map_err(|_| Error::StateLost)?;
+        let leaf = self.tree.get(leaf_idx).ok_or(Error::StateLost)?;
+        match (leaf.get_public_key(), leaf.get_private_key()) {
+            (Some(public_key), Some(private_key)) => {
+                let derived_public_key =
+                    DhPublicKey::new_from_private_key(self.cs.dh_impl, private_key);
+                if derived_public_key.as_bytes() == public_key.as_bytes() {
+                    Ok(())
+                } else {
+                    Err(Error::StateLost)
+                }
+            }
+            _ => Err(Error::StateLost),
+        }
+    }
+
+    /// Returns the ciphersuite this group uses
+    pub fn get_cipher_suite(&self) -> &'static CipherSuite {
+        self.cs
+    }
+
+    /// Returns the number of leaves in this group's ratchet tree, including blank ones. This is
+    /// always equal to the length of the roster.
+    pub fn get_leaf_count(&self) -> usize {
+        self.tree.leaf_count()
+    }
+
+    /// Renders this group's ratchet tree as ASCII art, marking which nodes are blank, which are
+    /// filled, and which ones this member knows the private key for. This is meant for debugging
+    /// members whose views of the tree have diverged. See `RatchetTree::render_ascii` for the
+    /// format.
+    #[cfg(feature = "tree-render")]
+    pub fn render_tree(&self) -> String {
+        self.tree.render_ascii()
+    }
+
+    /// Returns an iterator over the node indices of the non-blank leaves in this group's ratchet
+    /// tree, in ascending order
+    pub fn occupied_leaf_iter(&self) -> impl Iterator<Item = NodeIndex> + '_ {
+        self.tree.occupied_leaves().map(|(idx, _)| idx)
+    }
+
+    /// Returns an iterator over the current members of this group, skipping empty roster entries.
+    /// Each item is `(roster_index, leaf_node_index, credential)`, where `leaf_node_index` is the
+    /// node index of the member's leaf in the ratchet tree.
+    pub fn member_iter(&self) -> impl Iterator<Item = (RosterIndex, NodeIndex, &Credential)> {
+        self.roster.0.iter().enumerate().filter_map(|(i, entry)| {
+            entry.as_ref().map(|credential| {
+                // The roster and the tree are kept the same size, and tree indices fit in a u32,
+                // so neither of these can fail
+                let roster_index =
+                    RosterIndex(u32::try_from(i).expect("roster/tree size invariant violated"));
+                let leaf_index =
+                    roster_index.node_index().expect("roster/tree size invariant violated");
+                (roster_index, leaf_index, credential)
+            })
+        })
+    }
+
+    /// Creates and applies a `GroupUpdate` operation with the given path secret information. This
+    /// method does not mutate this `GroupState`, the operation is rather applied to the returned
+    /// `GroupState`.
+    ///
+    /// Returns: `Ok((handshake, group_state, app_key_chain))` on success, where `handshake` is the
     /// `Handshake` message representing the specified update operation, `group_state` is the new
     /// group state after the update has been applied, `app_key_chain` is the newly derived
     /// application key schedule object
@@ -1036,14 +2777,141 @@ impl GroupState {
     where
         R: CryptoRng,
     {
-        let (new_group_state, app_key_chain, update_op, conf_key) =
+        let (mut new_group_state, app_key_chain, update_op, conf_key) =
             self.create_and_apply_update_op(new_path_secret, csprng)?;
-        let prior_epoch = self.epoch;
-        let handshake = new_group_state.create_handshake(prior_epoch, update_op, conf_key)?;
+        let prior_context = self.group_context()?;
+        let handshake = new_group_state.create_handshake(&prior_context, update_op, conf_key)?;
+        new_group_state.erase_old_epochs();
+        new_group_state.report_new_epoch();
+
+        Ok((handshake, new_group_state, app_key_chain))
+    }
+
+    /// Creates and applies a `GroupInit` operation, which records this group's protocol version,
+    /// ID, ciphersuite, roster, tree, and extensions in its transcript. This has to be the first
+    /// operation in the group, so it's for the group's creator to make right after
+    /// `GroupState::new_singleton_group`. Every later epoch builds on the Init's transcript hash,
+    /// so anyone who checks the returned `Handshake`, like a server using
+    /// `verify::verify_handshake`, knows the group started the way it claims. This method does not
+    /// mutate this `GroupState`, the operation is rather applied to the returned `GroupState`.
+    ///
+    /// Returns: `Ok((handshake, group_state, app_key_chain))` on success, where `handshake` is the
+    /// `Handshake` message representing the Init, `group_state` is the new group state after the
+    /// Init has been applied, and `app_key_chain` is the newly derived application key schedule
+    /// object. Returns an `Error::ValidationError` if the group has already had an operation.
+    pub fn create_and_apply_init_handshake(
+        &self,
+    ) -> Result<(Handshake, GroupState, ApplicationKeyChain), Error> {
+        // Ugh, a full group state clone, I know
+        let mut new_group_state = self.clone();
+
+        // Make the Init op, log it in the transcript hash, increment the epoch, and derive the new
+        // epoch secrets, just like for any other op. The Init doesn't change anything else.
+        let init = self.as_group_init();
+        let update_secret = self.process_init_op(&init)?;
+        let op = GroupOperation::Init(init);
+        new_group_state.update_transcript_hash(&op)?;
+        new_group_state.increment_epoch()?;
+        let (app_secret, confirmation_key) =
+            new_group_state.update_epoch_secrets(&update_secret)?;
+        let app_key_chain =
+            ApplicationKeyChain::from_application_secret(&new_group_state, app_secret)?;
+
+        let prior_context = self.group_context()?;
+        let handshake = new_group_state.create_handshake(&prior_context, op, confirmation_key)?;
+        new_group_state.erase_old_epochs();
+        new_group_state.report_new_epoch();
 
         Ok((handshake, new_group_state, app_key_chain))
     }
 
+    /// Creates and applies a `GroupCredentialUpdate` operation with the given path secret
+    /// information. This replaces this member's credential with `new_credential`, whose secret key
+    /// is `new_identity_key`. The new credential must have the same identity as the current one.
+    /// This method does not mutate this `GroupState`, the operation is rather applied to the
+    /// returned `GroupState`, which signs with `new_identity_key` from then on. It holds
+    /// `new_identity_key` itself, even if the old key was in a `KeyStore`.
+    ///
+    /// Returns: `Ok((handshake, group_state, app_key_chain))` on success, where `handshake` is the
+    /// `Handshake` message representing the credential update, `group_state` is the new group
+    /// state after the update has been applied, `app_key_chain` is the newly derived application
+    /// key schedule object. Returns an `Error::ValidationError` if the identities differ, and an
+    /// `Error::SignatureError` if `new_identity_key` doesn't match `new_credential`.
+    // This is just a wrapper around self.create_and_apply_credential_update_op and
+    // self.create_handshake_signed_by
+    pub fn create_and_apply_credential_update_handshake<R>(
+        &self,
+        new_credential: Credential,
+        new_identity_key: SigSecretKey,
+        new_path_secret: PathSecret,
+        csprng: &mut R,
+    ) -> Result<(Handshake, GroupState, ApplicationKeyChain), Error>
+    where
+        R: CryptoRng,
+    {
+        let (mut new_group_state, app_key_chain, cred_update_op, conf_key) = self
+            .create_and_apply_credential_update_op(
+                new_credential,
+                new_identity_key,
+                new_path_secret,
+                csprng,
+            )?;
+        let prior_context = self.group_context()?;
+        // The handshake is signed with our old key, since that's the one everyone else knows
+        let handshake = new_group_state.create_handshake_signed_by(
+            &prior_context,
+            cred_update_op,
+            conf_key,
+            &self.identity_key,
+            self.my_credential(),
+        )?;
+        new_group_state.erase_old_epochs();
+        new_group_state.report_new_epoch();
+
+        Ok((handshake, new_group_state, app_key_chain))
+    }
+
+    /// Rotates this member's identity key to `new_identity_key` without having to be re-added to
+    /// the group. The new key must be under the same signature scheme as the current one. This is
+    /// done with a `GroupCredentialUpdate` whose credential is the current one but with the new
+    /// public key, so the new key is bound to the old one by the `Handshake` signature. Once it's
+    /// processed, members reject `Add`s whose `UserInitKey` was issued under the old key.
+    ///
+    /// Returns: `Ok((handshake, group_state, app_key_chain))` on success, where the values are as
+    /// in `create_and_apply_credential_update_handshake`. Returns an `Error::ValidationError` if
+    /// this member's credential is an X.509 credential.
+    // This is just a wrapper around self.create_and_apply_credential_update_handshake
+    pub fn create_and_apply_identity_key_update_handshake<R>(
+        &self,
+        new_identity_key: SigSecretKey,
+        new_path_secret: PathSecret,
+        csprng: &mut R,
+    ) -> Result<(Handshake, GroupState, ApplicationKeyChain), Error>
+    where
+        R: CryptoRng,
+    {
+        let roster_index = self.roster_index.ok_or(Error::ValidationError(
+            "Cannot rotate identity keys in a preliminary GroupState",
+        ))?;
+        let my_credential = self
+            .roster
+            .0
+            .get(roster_index.0 as usize)
+            .and_then(|entry| entry.as_ref())
+            .ok_or(Error::ValidationError("This member's roster entry is empty"))?;
+
+        let ss = my_credential.get_signature_scheme();
+        let new_public_key = SigPublicKey::new_from_secret_key(ss, &new_identity_key);
+        let new_credential = my_credential.with_public_key(new_public_key)?;
+
+        self.create_and_apply_credential_update_handshake(
+            new_credential,
+            new_identity_key,
+            new_path_secret,
+            csprng,
+        )
+    }
+
     /// Creates and applies a `GroupAdd` operation for a member at index `new_roster_index` with
     /// the target `init_key`. This method does not mutate this `GroupState`, the operation is
     /// rather applied to the returned `GroupState`.
@@ -1051,22 +2919,79 @@ impl GroupState {
     /// Returns: `Ok((handshake, group_state, app_key_chain))` on success, where `handshake` is the
     /// `Handshake` message representing the specified add operation, `group_state` is the new
     /// group state after the add has been applied, `app_key_chain` is the newly derived
-    /// application key schedule object
+    /// application key schedule object. Returns an `Error::ValidationError` if
+    /// `prior_welcome_info_hash` isn't the hash of this group's current `WelcomeInfo`, and an
+    /// `Error::DuplicateMember` if the group's `DuplicateIdentityPolicy` doesn't allow the
+    /// `init_key`'s credential to be added.
     // This is just a wrapper around self.create_and_apply_add_op and self.create_handshake
     pub fn create_and_apply_add_handshake(
         &self,
-        new_roster_index: u32,
+        new_roster_index: RosterIndex,
         init_key: UserInitKey,
         prior_welcome_info_hash: &WelcomeInfoHash,
     ) -> Result<(Handshake, GroupState, ApplicationKeyChain), Error> {
-        let (new_group_state, app_key_chain, add_op, conf_key) =
+        let (mut new_group_state, app_key_chain, add_op, conf_key) =
             self.create_and_apply_add_op(new_roster_index, init_key, prior_welcome_info_hash)?;
-        let prior_epoch = self.epoch;
-        let handshake = new_group_state.create_handshake(prior_epoch, add_op, conf_key)?;
+        let prior_context = self.group_context()?;
+        let handshake = new_group_state.create_handshake(&prior_context, add_op, conf_key)?;
+        new_group_state.erase_old_epochs();
+        new_group_state.report_new_epoch();
 
         Ok((handshake, new_group_state, app_key_chain))
     }
 
+    /// Adds several new members at once. Every new member gets a `Welcome` made from the group as
+    /// it is now, and then they're added one `Add` at a time, in the order given. Each goes in the
+    /// first empty roster entry, or at the end if there is none. This method does not mutate this
+    /// `GroupState`, the operations are rather applied to the returned `GroupState`.
+    ///
+    /// Everyone who was in the group processes the `Handshake`s in order, as usual. A new member
+    /// makes a `GroupState` from their `Welcome` and then processes the `Handshake`s in order,
+    /// starting from the first. The ones ahead of their own `Add` bring them up to date.
+    ///
+    /// Returns: `Ok((welcomes, handshakes, group_state, app_key_chain))` on success, where
+    /// `welcomes` is a list of `(user_init_key_id, welcome)` pairs for routing to the new members,
+    /// `handshakes` is the `Add` `Handshake`s in the order they must be processed, and
+    /// `group_state` and `app_key_chain` are the state after all of them have been applied.
+    /// Returns an `Error::ValidationError` if `init_keys` is empty or has two `UserInitKey`s with
+    /// the same ID. Otherwise returns whatever `Welcome::fan_out_from_group_state` or
+    /// `GroupState::create_and_apply_add_handshake` returns.
+    #[allow(clippy::type_complexity)]
+    pub fn create_and_apply_add_handshakes<R>(
+        &self,
+        init_keys: Vec<UserInitKey>,
+        csprng: &mut R,
+    ) -> Result<(Vec<(Vec<u8>, Welcome)>, Vec<Handshake>, GroupState, ApplicationKeyChain), Error>
+    where
+        R: CryptoRng,
+    {
+        if init_keys.is_empty() {
+            return Err(Error::ValidationError("Cannot add an empty batch of members"));
+        }
+        let (welcomes, first_welcome_info_hash) =
+            Welcome::fan_out_from_group_state(self, &init_keys, csprng)?;
+
+        let mut handshakes = Vec::with_capacity(init_keys.len());
+        let mut current: Option<(GroupState, ApplicationKeyChain)> = None;
+        for init_key in init_keys {
+            // Every Add after the first is made on top of the one before it
+            let (group_state, welcome_info_hash) = match current {
+                None => (self, first_welcome_info_hash.clone()),
+                Some((ref gs, _)) => (gs, gs.welcome_info_hash()?),
+            };
+            let new_roster_index = group_state.roster.next_add_index()?;
+
+            let (handshake, new_group_state, app_key_chain) = group_state
+                .create_and_apply_add_handshake(new_roster_index, init_key, &welcome_info_hash)?;
+            handshakes.push(handshake);
+            current = Some((new_group_state, app_key_chain));
+        }
+
+        // init_keys wasn't empty, so there's at least one new state
+        let (group_state, app_key_chain) = current.unwrap();
+        Ok((welcomes, handshakes, group_state, app_key_chain))
+    }
+
     /// Creates and applies a `GroupRemove` operation for a member at roster index
     /// `removed_roster_index` and introduces a new path secret `new_path_secret` at the removed
     /// index. This method does not mutate this `GroupState`, the operation is rather applied to
@@ -1081,36 +3006,635 @@ impl GroupState {
     // This is just a wrapper around self.create_and_apply_remove_op and self.create_handshake
     pub fn create_and_apply_remove_handshake<R>(
         &self,
-        removed_roster_index: u32,
+        removed_roster_index: RosterIndex,
         new_path_secret: PathSecret,
         csprng: &mut R,
     ) -> Result<(Handshake, GroupState, ApplicationKeyChain), Error>
     where
         R: CryptoRng,
     {
-        let (new_group_state, app_key_chain, remove_op, conf_key) =
+        let (mut new_group_state, app_key_chain, remove_op, conf_key) =
             self.create_and_apply_remove_op(removed_roster_index, new_path_secret, csprng)?;
-        let prior_epoch = self.epoch;
-        let handshake = new_group_state.create_handshake(prior_epoch, remove_op, conf_key)?;
+        let prior_context = self.group_context()?;
+        let handshake = new_group_state.create_handshake(&prior_context, remove_op, conf_key)?;
+        new_group_state.erase_old_epochs();
+        new_group_state.report_new_epoch();
 
         Ok((handshake, new_group_state, app_key_chain))
     }
+
+    /// Creates and applies a `GroupBatchRemove` operation for the members at roster indices
+    /// `removed_roster_indices`, all in one epoch. Their leaves and direct paths are blanked, and
+    /// `new_path_secret` is introduced at this member's leaf. The indices can be in any order. This
+    /// method does not mutate this `GroupState`, the operation is rather applied to the returned
+    /// `GroupState`.
+    ///
+    /// Requires: `removed_roster_indices` doesn't contain `self.roster_index`. That is, a member
+    /// cannot remove themselves from the group. An attempt to do so will result in an
+    /// `Error::IAmRemoved`.
+    ///
+    /// Returns: `Ok((handshake, group_state, app_key_chain))` on success, where `handshake` is the
+    /// `Handshake` message representing the removals, `group_state` is the new group state, and
+    /// `app_key_chain` is the newly derived application key schedule object. Returns an
+    /// `Error::InvalidOperation` if `removed_roster_indices` is empty or has an index that isn't a
+    /// current member's.
+    // This is just a wrapper around self.create_and_apply_batch_remove_op and
+    // self.create_handshake
+    pub fn create_and_apply_batch_remove_handshake<R>(
+        &self,
+        removed_roster_indices: &[RosterIndex],
+        new_path_secret: PathSecret,
+        csprng: &mut R,
+    ) -> Result<(Handshake, GroupState, ApplicationKeyChain), Error>
+    where
+        R: CryptoRng,
+    {
+        let (mut new_group_state, app_key_chain, batch_remove_op, conf_key) =
+            self.create_and_apply_batch_remove_op(removed_roster_indices, new_path_secret, csprng)?;
+        let prior_context = self.group_context()?;
+        let handshake =
+            new_group_state.create_handshake(&prior_context, batch_remove_op, conf_key)?;
+        new_group_state.erase_old_epochs();
+        new_group_state.report_new_epoch();
+
+        Ok((handshake, new_group_state, app_key_chain))
+    }
+
+    /// Creates and applies a `GroupReplace` operation that removes the member at roster index
+    /// `replaced_roster_index` and adds the owner of `init_key` in their place, all in one epoch,
+    /// e.g., when a member's device is replaced. `new_path_secret` is introduced at this member's
+    /// leaf, so the replaced member can't follow the group past this point. The new member joins
+    /// with a `Welcome` made from this `GroupState`, whose hash is `prior_welcome_info_hash`, and
+    /// then processes the returned `Handshake`. This method does not mutate this `GroupState`, the
+    /// operation is rather applied to the returned `GroupState`.
+    ///
+    /// Requires: `replaced_roster_index != self.roster_index`. A member cannot replace themselves.
+    /// An attempt to do so will result in an `Error::IAmRemoved`.
+    ///
+    /// Returns: `Ok((handshake, group_state, app_key_chain))` on success, where `handshake` is the
+    /// `Handshake` message representing the replacement, `group_state` is the new group state,
+    /// and `app_key_chain` is the newly derived application key schedule object. Returns an
+    /// `Error::InvalidOperation` if `replaced_roster_index` isn't a current member's. Otherwise
+    /// returns the same errors as `GroupState::create_and_apply_add_handshake`.
+    // This is just a wrapper around self.create_and_apply_replace_op and self.create_handshake
+    pub fn create_and_apply_replace_handshake<R>(
+        &self,
+        replaced_roster_index: RosterIndex,
+        init_key: UserInitKey,
+        prior_welcome_info_hash: &WelcomeInfoHash,
+        new_path_secret: PathSecret,
+        csprng: &mut R,
+    ) -> Result<(Handshake, GroupState, ApplicationKeyChain), Error>
+    where
+        R: CryptoRng,
+    {
+        let (mut new_group_state, app_key_chain, replace_op, conf_key) = self
+            .create_and_apply_replace_op(
+                replaced_roster_index,
+                init_key,
+                prior_welcome_info_hash,
+                new_path_secret,
+                csprng,
+            )?;
+        let prior_context = self.group_context()?;
+        let handshake = new_group_state.create_handshake(&prior_context, replace_op, conf_key)?;
+        new_group_state.erase_old_epochs();
+        new_group_state.report_new_epoch();
+
+        Ok((handshake, new_group_state, app_key_chain))
+    }
+
+    /// Replaces the group's `GroupPolicy` with `policy`. This member signs it, and has to be one
+    /// of the current policy's admins, if there is one. The new policy's version has to be higher
+    /// than the current one's, and its admins all have to be members. This method does not mutate
+    /// this `GroupState`, the operation is rather applied to the returned `GroupState`.
+    ///
+    /// Returns: `Ok((handshake, group_state, app_key_chain))` on success, where `handshake` is the
+    /// `Handshake` message carrying the new policy, `group_state` is the new group state after
+    /// the policy has been replaced, and `app_key_chain` is the newly derived application key
+    /// schedule object. Returns an `Error::ValidationError` if the policy can't replace the
+    /// current one, and an `Error::InvalidOperation` if the group's `AuthorizationPolicy` doesn't
+    /// allow it.
+    // This is just a wrapper around self.create_and_apply_policy_update_op and
+    // self.create_handshake
+    pub fn create_and_apply_policy_update_handshake(
+        &self,
+        policy: GroupPolicy,
+    ) -> Result<(Handshake, GroupState, ApplicationKeyChain), Error> {
+        let (mut new_group_state, app_key_chain, policy_op, conf_key) =
+            self.create_and_apply_policy_update_op(policy)?;
+        let prior_context = self.group_context()?;
+        let handshake = new_group_state.create_handshake(&prior_context, policy_op, conf_key)?;
+        new_group_state.erase_old_epochs();
+        new_group_state.report_new_epoch();
+
+        Ok((handshake, new_group_state, app_key_chain))
+    }
+
+    /// Like `GroupState::create_and_apply_update_handshake`, but leaves the `Handshake` unsigned
+    /// so that the signature can come from somewhere else. See `UnsignedHandshake`.
+    ///
+    /// Returns: `Ok(unsigned_handshake)` on success. Otherwise returns whatever
+    /// `GroupState::create_and_apply_update_handshake` returns.
+    pub fn prepare_update_handshake<R>(
+        &self,
+        new_path_secret: PathSecret,
+        csprng: &mut R,
+    ) -> Result<UnsignedHandshake, Error>
+    where
+        R: CryptoRng,
+    {
+        let (new_group_state, app_key_chain, update_op, conf_key) =
+            self.create_and_apply_update_op(new_path_secret, csprng)?;
+        self.prepare_handshake(new_group_state, app_key_chain, update_op, conf_key)
+    }
+
+    /// Like `GroupState::create_and_apply_add_handshake`, but leaves the `Handshake` unsigned so
+    /// that the signature can come from somewhere else. See `UnsignedHandshake`.
+    ///
+    /// Returns: `Ok(unsigned_handshake)` on success. Otherwise returns whatever
+    /// `GroupState::create_and_apply_add_handshake` returns.
+    pub fn prepare_add_handshake(
+        &self,
+        new_roster_index: RosterIndex,
+        init_key: UserInitKey,
+        prior_welcome_info_hash: &WelcomeInfoHash,
+    ) -> Result<UnsignedHandshake, Error> {
+        let (new_group_state, app_key_chain, add_op, conf_key) =
+            self.create_and_apply_add_op(new_roster_index, init_key, prior_welcome_info_hash)?;
+        self.prepare_handshake(new_group_state, app_key_chain, add_op, conf_key)
+    }
+
+    /// Like `GroupState::create_and_apply_remove_handshake`, but leaves the `Handshake` unsigned
+    /// so that the signature can come from somewhere else. See `UnsignedHandshake`.
+    ///
+    /// Returns: `Ok(unsigned_handshake)` on success. Otherwise returns whatever
+    /// `GroupState::create_and_apply_remove_handshake` returns.
+    pub fn prepare_remove_handshake<R>(
+        &self,
+        removed_roster_index: RosterIndex,
+        new_path_secret: PathSecret,
+        csprng: &mut R,
+    ) -> Result<UnsignedHandshake, Error>
+    where
+        R: CryptoRng,
+    {
+        let (new_group_state, app_key_chain, remove_op, conf_key) =
+            self.create_and_apply_remove_op(removed_roster_index, new_path_secret, csprng)?;
+        self.prepare_handshake(new_group_state, app_key_chain, remove_op, conf_key)
+    }
+
+    // Packages an operation that's been applied to new_group_state into an UnsignedHandshake.
+    // CredentialUpdates and PolicyUpdates aren't offered this way, since they carry signatures of
+    // their own that are made while the operation is built.
+    fn prepare_handshake(
+        &self,
+        new_group_state: GroupState,
+        app_key_chain: ApplicationKeyChain,
+        operation: GroupOperation,
+        confirmation_key: ConfirmationKey,
+    ) -> Result<UnsignedHandshake, Error> {
+        let prior_context = self.group_context()?;
+        let to_be_signed = new_group_state.handshake_to_be_signed(&prior_context, &operation)?;
+        let signer_credential = self
+            .roster_index
+            .and_then(|idx| self.roster.0.get(idx.0 as usize))
+            .and_then(Option::as_ref)
+            .ok_or(Error::ValidationError("Cannot find this member's credential in the roster"))?
+            .clone();
+
+        Ok(UnsignedHandshake {
+            group_state: new_group_state,
+            app_key_chain,
+            prior_epoch: self.epoch,
+            operation,
+            confirmation_key,
+            signer_credential,
+            to_be_signed,
+        })
+    }
+
+    /// Derives the secret that a branch of this group starts its key schedule from. Only someone
+    /// who was a member of this group in this epoch can compute it, which is what lets a branch
+    /// inherit authentication from its parent.
+    // Draft 4 has no notion of branching. This plays the role of the resumption secret in later
+    // drafts:
+    // branch_secret = Derive-Secret(init_secret_[n], "branch", GroupState_[n])
+    // The label keeps it independent of init_secret_[n+1], which is derived from init_secret_[n]
+    // with a different label.
+    fn branch_secret(&self) -> Result<HmacKey, Error> {
+        hkdf::derive_secret(self.cs.hash_impl, &self.init_secret, b"branch", self)
+    }
+
+    /// Makes the initial state of a branch of this group with the given group ID, whose members
+    /// are the members of this group at the given roster indices. The branch's roster and leaves
+    /// are those members' entries, in roster order, and its init secret is this group's
+    /// `branch_secret`. Every member of the branch computes exactly the same state, up to whose
+    /// perspective it's from.
+    ///
+    /// Returns: `Ok(group_state)` on success, where `group_state` is at epoch 0. Returns an
+    /// `Error::ValidationError` if this `GroupState` is preliminary, if `new_group_id` is this
+    /// group's ID, if we're not one of the given members, or if one of the given indices isn't a
+    /// member of this group.
+    fn branch_state(
+        &self,
+        new_group_id: Vec<u8>,
+        member_roster_indices: &[RosterIndex],
+    ) -> Result<GroupState, Error> {
+        let my_roster_index = self
+            .roster_index
+            .ok_or(Error::ValidationError("Cannot branch from a preliminary GroupState"))?;
+        if new_group_id == self.group_id {
+            return Err(Error::ValidationError("A branch cannot have its parent's group ID"));
+        }
+
+        // Everyone has to agree on who goes where, so put the members in roster order regardless
+        // of how they were given to us
+        let mut members = member_roster_indices.to_vec();
+        members.sort_unstable();
+        members.dedup();
+        let my_new_roster_index = members
+            .iter()
+            .position(|&idx| idx == my_roster_index)
+            .ok_or(Error::ValidationError("Cannot make a branch that doesn't include me"))?;
+
+        let mut roster = Roster(Vec::with_capacity(members.len()));
+        let mut tree = RatchetTree::new(Vec::new());
+        for &idx in members.iter() {
+            let cred = self
+                .roster
+                .0
+                .get(idx.0 as usize)
+                .and_then(Option::as_ref)
+                .ok_or(Error::ValidationError("Branch member isn't in the parent group"))?;
+            // The leaf is in the tree, since the roster and tree have the same number of leaves
+            let mut leaf = self
+                .tree
+                .get(idx.node_index()?)
+                .cloned()
+                .ok_or(Error::ValidationError("roster/tree size invariant violated"))?;
+            // The only private key we know in the branch is our own
+            if idx != my_roster_index {
+                leaf.erase_private_key();
+            }
+
+            roster.0.push(Some(cred.clone()));
+            tree.add_leaf_node(leaf);
+        }
+
+        // We can cast to u32 because the branch is no bigger than this group's roster
+        let mut branch = GroupState::new_from_parts(
+            self.cs,
+            self.protocol_version,
+            self.identity_key.clone(),
+            new_group_id,
+            roster,
+            RosterIndex(my_new_roster_index as u32),
+            tree,
+        );
+        branch.extensions = self.extensions.clone();
+        branch.init_secret = self.branch_secret()?;
+
+        Ok(branch)
+    }
+
+    /// Starts a branch of this group: a new group with the given group ID whose members are the
+    /// members of this group at roster indices `member_roster_indices`. The branch's first epoch
+    /// is keyed off of this group's current epoch, so nobody has to be sent a `Welcome`. Instead,
+    /// the returned `Handshake` is an `Update` in the branch that the other branch members
+    /// process with `GroupState::process_branch_handshake`, along with the same list of members.
+    /// Only they can process it, since it takes both a leaf private key and this group's key
+    /// schedule. This method does not mutate this `GroupState`.
+    ///
+    /// The members' leaf keys are carried over from this group, so each of them should do an
+    /// `Update` in the branch soon after joining it.
+    ///
+    /// Requires: This member is one of `member_roster_indices`, and `new_group_id` is not this
+    /// group's ID
+    ///
+    /// Returns: `Ok((handshake, group_state, app_key_chain))` on success, where `group_state` is
+    /// the new branch and `app_key_chain` is its application key schedule object. Returns an
+    /// `Error::ValidationError` if the above conditions aren't met, if this `GroupState` is
+    /// preliminary, or if one of the given indices isn't a member of this group. Otherwise returns
+    /// whatever `GroupState::create_and_apply_update_handshake` returns.
+    pub fn create_and_apply_branch_handshake<R>(
+        &self,
+        new_group_id: Vec<u8>,
+        member_roster_indices: &[RosterIndex],
+        new_path_secret: PathSecret,
+        csprng: &mut R,
+    ) -> Result<(Handshake, GroupState, ApplicationKeyChain), Error>
+    where
+        R: CryptoRng,
+    {
+        let branch = self.branch_state(new_group_id, member_roster_indices)?;
+        branch.create_and_apply_update_handshake(new_path_secret, csprng)
+    }
+
+    /// Joins the branch of this group started by the given `Handshake`, which was made by
+    /// `GroupState::create_and_apply_branch_handshake` with the same `member_roster_indices`. The
+    /// order of the indices doesn't matter. This method does not mutate this `GroupState`.
+    ///
+    /// Returns: `Ok((group_state, app_key_chain))` on success, where `group_state` is the new
+    /// branch and `app_key_chain` is its application key schedule object. Returns an
+    /// `Error::ValidationError` if the `Handshake` isn't an `Update`, if this member isn't in the
+    /// branch, or if the `Handshake` wasn't made from this group's current epoch. Otherwise
+    /// returns whatever `GroupState::process_handshake` returns.
+    pub fn process_branch_handshake(
+        &self,
+        member_roster_indices: &[RosterIndex],
+        handshake: &Handshake,
+    ) -> Result<(GroupState, ApplicationKeyChain), Error> {
+        match handshake.operation {
+            GroupOperation::Update(_) => (),
+            _ => return Err(Error::ValidationError("Branches must start with an Update")),
+        }
+
+        let branch = self.branch_state(handshake.group_id.clone(), member_roster_indices)?;
+        branch.process_handshake(handshake)
+    }
 }
 
-// TODO: Make this COW so we don't have to clone everything in GroupState::as_welcome_info
+/// Checks that the signer of `handshake` is a current member with a filled leaf, and that its
+/// operation makes sense coming from them. An `Update`, `CredentialUpdate`, `BatchRemove`, or
+/// `Replace` has to carry a direct path for the signer's own leaf, and a `Remove`, `BatchRemove`,
+/// or `Replace` has to be for current members. This doesn't check the signature. It only looks at
+/// public state, so `PublicGroupState` uses it too.
+///
+/// Returns: `Ok(sender_credential)` on success, where `sender_credential` is the signer's
+/// credential. Otherwise returns an `Error::InvalidOperation` that says what's wrong.
+pub(crate) fn check_sender_eligibility<'a>(
+    roster: &'a Roster,
+    tree: &RatchetTree,
+    handshake: &Handshake,
+) -> Result<&'a Credential, Error> {
+    let sender_credential = roster
+        .0
+        .get(handshake.signer_index.0 as usize)
+        .ok_or(OperationError::SignerOutOfBounds)?
+        .as_ref()
+        .ok_or(OperationError::SignerNotMember)?;
+
+    // The roster and the tree are the same length, so the leaf exists. Filled roster entries
+    // always have filled leaves, unless someone handed us a bad tree.
+    let sender_tree_idx = handshake.signer_index.node_index()?;
+    match tree.get(sender_tree_idx) {
+        Some(RatchetTreeNode::Filled {
+            ..
+        }) => (),
+        _ => return Err(OperationError::SignerLeafBlank.into()),
+    }
+
+    match handshake.operation {
+        GroupOperation::Update(GroupUpdate {
+            ref path,
+        })
+        | GroupOperation::CredentialUpdate(GroupCredentialUpdate {
+            ref path,
+            ..
+        }) => {
+            let ctx = tree.math_ctx();
+            tree.validate_direct_path_message(&ctx, path, sender_tree_idx)
+                .map_err(|_| OperationError::PathFromWrongLeaf)?;
+        }
+        GroupOperation::Remove(ref remove) => {
+            roster
+                .0
+                .get(remove.removed_roster_index.0 as usize)
+                .ok_or(OperationError::RemoveTargetOutOfBounds)?
+                .as_ref()
+                .ok_or(OperationError::RemoveTargetNotMember)?;
+        }
+        GroupOperation::BatchRemove(ref batch_remove) => {
+            check_remove_targets(
+                roster,
+                handshake.signer_index,
+                &batch_remove.removed_roster_indices,
+            )?;
+            // The path was made after the removed members were blanked, so that's the tree it
+            // has to fit
+            let mut blanked_tree = tree.clone();
+            for removed_roster_index in batch_remove.removed_roster_indices.iter() {
+                blanked_tree.propagate_blank(removed_roster_index.node_index()?);
+            }
+            let ctx = blanked_tree.math_ctx();
+            blanked_tree
+                .validate_direct_path_message(&ctx, &batch_remove.path, sender_tree_idx)
+                .map_err(|_| OperationError::PathFromWrongLeaf)?;
+        }
+        GroupOperation::Replace(ref replace) => {
+            let replaced_roster_index = replace.add.roster_index;
+            check_remove_targets(roster, handshake.signer_index, &[replaced_roster_index])?;
+            // The path was made after the new member took the replaced member's leaf, which blanks
+            // its direct path. Only which nodes are blank matters here, so the old leaf stands in
+            // for the new one.
+            let replaced_tree_idx = replaced_roster_index.node_index()?;
+            let mut replaced_tree = tree.clone();
+            let leaf = replaced_tree
+                .get(replaced_tree_idx)
+                .cloned()
+                .ok_or(OperationError::RemoveTargetOutOfBounds)?;
+            replaced_tree.propagate_blank(replaced_tree_idx);
+            replaced_tree.add_leaf_at(replaced_roster_index.leaf_index()?, leaf)?;
+            let ctx = replaced_tree.math_ctx();
+            replaced_tree
+                .validate_direct_path_message(&ctx, &replace.path, sender_tree_idx)
+                .map_err(|_| OperationError::PathFromWrongLeaf)?;
+        }
+        GroupOperation::Add(_) | GroupOperation::Init(_) | GroupOperation::PolicyUpdate(_) => (),
+    }
+
+    Ok(sender_credential)
+}
+
+/// Checks that the targets of a `BatchRemove` or `Replace` signed by the member at `signer_index`
+/// are all current members, in ascending order without repeats, and don't include the signer
+///
+/// Returns: `Ok(())` on success. Otherwise returns an `Error::InvalidOperation` that says what's
+/// wrong.
+fn check_remove_targets(
+    roster: &Roster,
+    signer_index: RosterIndex,
+    removed_roster_indices: &[RosterIndex],
+) -> Result<(), Error> {
+    if removed_roster_indices.is_empty() || removed_roster_indices.contains(&signer_index) {
+        return Err(OperationError::RemoveTargetsMalformed.into());
+    }
+    if removed_roster_indices.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return Err(OperationError::RemoveTargetsMalformed.into());
+    }
+
+    for removed_roster_index in removed_roster_indices {
+        roster
+            .0
+            .get(removed_roster_index.0 as usize)
+            .ok_or(OperationError::RemoveTargetOutOfBounds)?
+            .as_ref()
+            .ok_or(OperationError::RemoveTargetNotMember)?;
+    }
+
+    Ok(())
+}
+
+/// A `Handshake` that's been fully checked against a `GroupState` but not yet accepted. This is
+/// made by `GroupState::preview`. Look at the would-be new state with `group_state`, then either
+/// `commit` to it or drop the preview to throw it away.
+#[must_use = "a previewed Handshake isn't applied until it's committed"]
+pub struct HandshakePreview {
+    group_state: GroupState,
+    app_key_chain: ApplicationKeyChain,
+    op_kind: OperationKind,
+    prior_epoch: u32,
+    signer_index: RosterIndex,
+    change: MembershipChange,
+}
+
+// What a GroupObserver gets told about a Handshake, besides the epoch change
+enum MembershipChange {
+    Unchanged,
+    Added(RosterIndex, Credential),
+    Removed(Vec<(RosterIndex, Credential)>),
+    Replaced(RosterIndex, Credential, Credential),
+    CredentialChanged(RosterIndex, Credential, Credential),
+}
+
+impl HandshakePreview {
+    /// Returns the `GroupState` that committing this preview would produce
+    pub fn group_state(&self) -> &GroupState {
+        &self.group_state
+    }
+
+    /// Returns the kind of operation the previewed `Handshake` carries
+    pub fn operation_kind(&self) -> OperationKind {
+        self.op_kind
+    }
+
+    /// Returns the roster index of the member who sent the previewed `Handshake`
+    pub fn signer_index(&self) -> RosterIndex {
+        self.signer_index
+    }
+
+    /// Accepts the previewed `Handshake`, reporting it to metrics.
+    ///
+    /// Returns: The same as `GroupState::process_handshake` does on success
+    pub fn commit(self) -> (GroupState, ApplicationKeyChain) {
+        self.commit_with_observer(&mut ())
+    }
+
+    /// Like `HandshakePreview::commit`, but also tells `observer` what the `Handshake` did
+    ///
+    /// Returns: The same as `HandshakePreview::commit`
+    pub fn commit_with_observer(
+        self,
+        observer: &mut dyn GroupObserver,
+    ) -> (GroupState, ApplicationKeyChain) {
+        let group_id = &self.group_state.group_id;
+        metrics::report(|m| m.handshake_processed(group_id, self.op_kind, self.prior_epoch));
+        if let MembershipChange::CredentialChanged(roster_index, _, ref new_credential) =
+            self.change
+        {
+            metrics::report(|m| m.credential_updated(group_id, roster_index, new_credential));
+        }
+        self.group_state.report_new_epoch();
+
+        match self.change {
+            MembershipChange::Added(roster_index, ref credential) => {
+                observer.on_member_added(group_id, roster_index, credential)
+            }
+            MembershipChange::Removed(ref removed) => {
+                for (roster_index, old_credential) in removed {
+                    observer.on_member_removed(group_id, *roster_index, old_credential)
+                }
+            }
+            MembershipChange::Replaced(roster_index, ref old_credential, ref new_credential) => {
+                observer.on_member_removed(group_id, roster_index, old_credential);
+                observer.on_member_added(group_id, roster_index, new_credential);
+            }
+            MembershipChange::CredentialChanged(roster_index, ref old_credential, ref new) => {
+                observer.on_credential_changed(group_id, roster_index, old_credential, new)
+            }
+            MembershipChange::Unchanged => (),
+        }
+        observer.on_epoch_advanced(group_id, self.group_state.epoch);
+
+        (self.group_state, self.app_key_chain)
+    }
+}
+
+/// A `Handshake` whose operation has been made and applied, but that's waiting for its signature.
+/// This is for when the identity key lives somewhere that signs asynchronously, like a signing
+/// service or a secure enclave. Hand the bytes from `UnsignedHandshake::to_be_signed` to the
+/// signer, and give what it returns to `UnsignedHandshake::attach_signature`. Nothing here blocks
+/// in between, so the signer can be awaited however the caller likes. These are made by the
+/// `GroupState::prepare_*_handshake` methods.
+#[must_use = "an UnsignedHandshake does nothing until its signature is attached"]
+pub struct UnsignedHandshake {
+    // The state the operation has been applied to
+    group_state: GroupState,
+    app_key_chain: ApplicationKeyChain,
+    prior_epoch: u32,
+    operation: GroupOperation,
+    confirmation_key: ConfirmationKey,
+    // The credential of the member making the operation, as of the prior epoch
+    signer_credential: Credential,
+    // The serialized HandshakeSignatureContent
+    to_be_signed: Vec<u8>,
+}
+
+impl UnsignedHandshake {
+    /// Returns the bytes that the identity key has to sign
+    pub fn to_be_signed(&self) -> &[u8] {
+        &self.to_be_signed
+    }
+
+    /// Returns the credential whose identity key has to sign this
+    pub fn get_signer_credential(&self) -> &Credential {
+        &self.signer_credential
+    }
+
+    /// Attaches a signature over `UnsignedHandshake::to_be_signed` that was made elsewhere and
+    /// finishes the `Handshake`. The signature has to be in its scheme's wire encoding, i.e., 64
+    /// bytes for Ed25519 and DER for ECDSA. It's checked here, so a misbehaving signer is caught
+    /// before the `Handshake` is sent.
+    ///
+    /// Returns: `Ok((handshake, group_state, app_key_chain))` on success, just like the matching
+    /// `GroupState::create_and_apply_*_handshake` method. Returns an `Error::SignatureError` if the
+    /// signature is malformed or doesn't verify under the signer's credential.
+    pub fn attach_signature(
+        self,
+        signature: &[u8],
+    ) -> Result<(Handshake, GroupState, ApplicationKeyChain), Error> {
+        let ss = self.signer_credential.get_signature_scheme();
+        let signature = Signature::new_from_bytes(ss, signature)?;
+        self.signer_credential.verify(&self.to_be_signed, &signature)?;
+
+        let mut group_state = self.group_state;
+        let handshake = group_state.finish_handshake(
+            self.prior_epoch,
+            self.operation,
+            self.confirmation_key,
+            signature,
+        )?;
+        group_state.erase_old_epochs();
+        group_state.report_new_epoch();
+
+        Ok((handshake, group_state, self.app_key_chain))
+    }
+}
 
 /// Contains everything a new user needs to know to join a group. This is always followed by an
-/// `Add` operation.
+/// `Add` operation. The roster and tree are borrowed when this comes from
+/// `GroupState::as_welcome_info`, so that hashing or serializing it doesn't copy them.
 #[derive(Deserialize, Serialize)]
 #[cfg_attr(test, derive(Debug))]
-pub(crate) struct WelcomeInfo {
+pub(crate) struct WelcomeInfo<'a> {
     // ProtocolVersion version;
     /// The protocol version
     protocol_version: ProtocolVersion,
 
     // opaque group_id<0..255>;
     /// An application-defined identifier for the group
-    #[serde(rename = "group_id__bound_u8")]
+    #[serde(rename = "group_id__nonempty__bound_u8")]
     group_id: Vec<u8>,
 
     /// Represents the current version of the group key
@@ -1120,12 +3644,12 @@ pub(crate) struct WelcomeInfo {
     /// Contains credentials for the occupied slots in the tree, including the identity and
     /// signature public key for the holder of the slot
     #[serde(rename = "roster__bound_u32")]
-    pub(crate) roster: Roster,
+    pub(crate) roster: Cow<'a, Roster>,
 
     // optional<PublicKey> tree<1..2^32-1>;
     /// The tree field contains the public keys corresponding to the nodes of the ratchet tree for
     /// this group. The number of leaves in this tree MUST be equal to the length of `roster`
-    pub(crate) tree: RatchetTree,
+    pub(crate) tree: Cow<'a, RatchetTree>,
 
     // opaque transcript_hash<0..255>;
     /// Contains a running hash of `GroupOperation` messages that led to this state
@@ -1133,7 +3657,126 @@ pub(crate) struct WelcomeInfo {
 
     // opaque init_secret<0..255>;
     /// The initial secret used to derive all the rest
-    init_secret: HmacKey,
+    init_secret: WelcomeInitSecret,
+
+    // Extension extensions<0..2^16-1>;
+    /// The group-wide extensions
+    pub(crate) extensions: ExtensionList,
+}
+
+impl<'a> WelcomeInfo<'a> {
+    /// Returns a copy of this `WelcomeInfo` that owns its roster and tree
+    pub(crate) fn into_owned(self) -> WelcomeInfo<'static> {
+        WelcomeInfo {
+            protocol_version: self.protocol_version,
+            group_id: self.group_id,
+            epoch: self.epoch,
+            roster: Cow::Owned(self.roster.into_owned()),
+            tree: Cow::Owned(self.tree.into_owned()),
+            transcript_hash: self.transcript_hash,
+            init_secret: self.init_secret,
+            extensions: self.extensions,
+        }
+    }
+
+    /// Checks that this `WelcomeInfo` describes a group that uses the given ciphersuite and that a
+    /// new member could join. The tree and roster are checked by `GroupState::from_welcome_info`.
+    ///
+    /// Returns: `Ok(())` on success. Otherwise returns an `Error::InvalidWelcome` saying what's
+    /// wrong.
+    fn validate(&self, cs: &'static CipherSuite) -> Result<(), Error> {
+        if self.group_id.is_empty() {
+            return Err(WelcomeError::EmptyGroupId.into());
+        }
+        // Joining takes an Add, which moves the group to the next epoch
+        if self.epoch == u32::MAX {
+            return Err(WelcomeError::EpochExhausted.into());
+        }
+        // Both of these come out of the group's hash function
+        let digest_size = cs.hash_impl.digest_size();
+        if self.transcript_hash.as_bytes().len() != digest_size
+            || self.init_secret.0.as_bytes().len() != digest_size
+        {
+            return Err(WelcomeError::CipherSuiteMismatch.into());
+        }
+
+        Ok(())
+    }
+}
+
+// struct {
+//     CipherSuite cipher_suite;
+//     WelcomeInfo welcome_info;
+//     optional<uint32> roster_index;
+//     optional<SavedUserInitKey> initializing_user_init_key;
+//     SavedSecret identity_key;
+//     SavedNodeKey tree_private_keys<0..2^32-1>;
+//     Credential retired_credentials<0..2^32-1>;
+// } SavedGroupState;
+/// A `GroupState` as written by `MlsClient::save`. The `WelcomeInfo` has everything every member
+/// knows, and the rest is what only this member knows. See `GroupState::to_saved`.
+#[derive(Deserialize, Serialize)]
+pub(crate) struct SavedGroupState {
+    pub(crate) cipher_suite: &'static CipherSuite,
+    pub(crate) welcome_info: WelcomeInfo<'static>,
+    roster_index: Option<RosterIndex>,
+    pub(crate) initializing_user_init_key: Option<SavedUserInitKey>,
+    identity_key: SavedSecret,
+    #[serde(rename = "tree_private_keys__bound_u32")]
+    tree_private_keys: Vec<SavedNodeKey>,
+    #[serde(rename = "retired_credentials__bound_u32")]
+    pub(crate) retired_credentials: Vec<Credential>,
+}
+
+// struct {
+//     uint32 node_index;
+//     SavedSecret private_key;
+// } SavedNodeKey;
+/// The private key of a node in this member's copy of the tree
+#[derive(Deserialize, Serialize)]
+struct SavedNodeKey {
+    node_index: u32,
+    private_key: SavedSecret,
+}
+
+/// A `WelcomeInfo` that this member made an `Add` or `Replace` from, kept so that the new member's
+/// `Welcome` can be made again. See `GroupState::reissue_welcome`.
+#[derive(Clone)]
+#[cfg_attr(test, derive(Debug))]
+pub(crate) struct CachedWelcome {
+    /// The epoch that the `Add` or `Replace` made. The `WelcomeInfo` describes the one before it.
+    added_epoch: u32,
+
+    /// The `UserInitKey` the new member was added with. This has no private keys.
+    init_key: UserInitKey,
+
+    /// The serialized `WelcomeInfo`. It holds the init secret that the new member's first epoch is
+    /// derived from, so it's kept as a `Secret`.
+    serialized_welcome_info: Secret,
+}
+
+/// The `init_secret` in a `WelcomeInfo`. `HmacKey`s can't be serialized, so that no secret ends up
+/// on the wire by accident. This is the exception: a `WelcomeInfo` is only ever sent encrypted to
+/// the member it welcomes, and they need the secret to join.
+#[derive(Debug, Deserialize)]
+#[serde(from = "WelcomeInitSecretBytes")]
+pub(crate) struct WelcomeInitSecret(pub(crate) HmacKey);
+
+// This is what a WelcomeInitSecret deserializes as before it's put into an HmacKey
+#[derive(Deserialize)]
+#[serde(rename = "WelcomeInitSecret__bound_u8")]
+struct WelcomeInitSecretBytes(Vec<u8>);
+
+impl From<WelcomeInitSecretBytes> for WelcomeInitSecret {
+    fn from(bytes: WelcomeInitSecretBytes) -> WelcomeInitSecret {
+        WelcomeInitSecret(HmacKey(Secret::new_from_vec(bytes.0)))
+    }
+}
+
+impl serde::Serialize for WelcomeInitSecret {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_newtype_struct("WelcomeInitSecret__bound_u8", self.0.as_bytes())
+    }
 }
 
 // This is public-facing
@@ -1142,6 +3785,14 @@ pub(crate) struct WelcomeInfo {
 #[cfg_attr(test, derive(Debug))]
 pub struct WelcomeInfoHash(Digest);
 
+impl WelcomeInfoHash {
+    /// Returns the bytes of the underlying digest
+    #[cfg(feature = "json")]
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
 // Digest --> WelcomeInfoHash trivially
 impl From<Digest> for WelcomeInfoHash {
     fn from(d: Digest) -> WelcomeInfoHash {
@@ -1156,12 +3807,83 @@ impl subtle::ConstantTimeEq for WelcomeInfoHash {
     }
 }
 
+/// A serialized `WelcomeInfo` longer than this many bytes is encrypted in chunks with
+/// `EciesStreamSealer`, instead of in one piece. Shorter ones are encrypted exactly as the spec
+/// says.
+pub const WELCOME_STREAMING_THRESHOLD: usize = 1 << 20;
+
+/// The size in bytes of the plaintext chunks of a streamed `WelcomeInfo`. All but the last one are
+/// exactly this long.
+pub const WELCOME_CHUNK_SIZE: usize = 1 << 16;
+
+// Encrypts a serialized WelcomeInfo to the given public key. Short ones are encrypted in one piece
+// like the spec says, so that their Welcomes are unchanged on the wire. Ones longer than
+// WELCOME_STREAMING_THRESHOLD are sealed in WELCOME_CHUNK_SIZE chunks, and the chunk ciphertexts
+// are concatenated. The receiver tells the two apart by length alone, since no one-shot
+// ciphertext is that long.
+fn encrypt_welcome_info<R>(
+    cs: &'static CipherSuite,
+    public_key: &DhPublicKey,
+    serialized_welcome_info: Vec<u8>,
+    csprng: &mut R,
+) -> Result<EciesCiphertext, Error>
+where
+    R: CryptoRng,
+{
+    if serialized_welcome_info.len() <= WELCOME_STREAMING_THRESHOLD {
+        return ecies::encrypt(cs, public_key, serialized_welcome_info, csprng);
+    }
+
+    let (ephemeral_public_key, mut sealer) = EciesStreamSealer::new(cs, public_key, csprng)?;
+    let tag_size = cs.aead_impl.tag_size();
+    let num_chunks = serialized_welcome_info.len().div_ceil(WELCOME_CHUNK_SIZE);
+    let mut ciphertext = Vec::with_capacity(serialized_welcome_info.len() + num_chunks * tag_size);
+    for (i, chunk) in serialized_welcome_info.chunks(WELCOME_CHUNK_SIZE).enumerate() {
+        let is_last = i + 1 == num_chunks;
+        ciphertext.extend_from_slice(&sealer.seal_chunk(chunk, is_last)?);
+    }
+
+    Ok(EciesCiphertext {
+        ephemeral_public_key,
+        ciphertext,
+    })
+}
+
+// The inverse of encrypt_welcome_info
+fn decrypt_welcome_info(
+    cs: &'static CipherSuite,
+    private_key: &DhPrivateKey,
+    encrypted_welcome_info: EciesCiphertext,
+) -> Result<Vec<u8>, Error> {
+    let tag_size = cs.aead_impl.tag_size();
+    if encrypted_welcome_info.ciphertext.len() <= WELCOME_STREAMING_THRESHOLD + tag_size {
+        return ecies::decrypt(cs, private_key, encrypted_welcome_info);
+    }
+
+    let EciesCiphertext {
+        ephemeral_public_key,
+        ciphertext,
+    } = encrypted_welcome_info;
+    let mut opener = EciesStreamOpener::new(cs, private_key, &ephemeral_public_key)?;
+    let chunk_ciphertext_size = WELCOME_CHUNK_SIZE + tag_size;
+    let num_chunks = ciphertext.len().div_ceil(chunk_ciphertext_size);
+    let mut plaintext = Vec::with_capacity(ciphertext.len() - num_chunks * tag_size);
+    for (i, chunk) in ciphertext.chunks(chunk_ciphertext_size).enumerate() {
+        let is_last = i + 1 == num_chunks;
+        plaintext.extend_from_slice(&opener.open_chunk(chunk, is_last)?);
+    }
+    // The last chunk was flagged, so this can't fail, but it's cheap to be sure
+    opener.finish()?;
+
+    Ok(plaintext)
+}
+
 /// This contains an encrypted `WelcomeInfo` for new group members
 #[derive(Deserialize, Serialize)]
 #[cfg_attr(test, derive(Debug))]
 pub struct Welcome {
     // opaque user_init_key_id<0..255>;
-    #[serde(rename = "user_init_key_id__bound_u8")]
+    #[serde(rename = "user_init_key_id__nonempty__bound_u8")]
     user_init_key_id: Vec<u8>,
     pub(crate) cipher_suite: &'static CipherSuite,
     pub(crate) encrypted_welcome_info: EciesCiphertext,
@@ -1173,20 +3895,40 @@ impl Welcome {
     fn from_welcome_info<R>(
         cs: &'static CipherSuite,
         init_key: &UserInitKey,
-        welcome_info: &WelcomeInfo,
+        welcome_info: &WelcomeInfo,
+        csprng: &mut R,
+    ) -> Result<Welcome, Error>
+    where
+        R: CryptoRng,
+    {
+        let serialized_welcome_info = tls_ser::serialize_to_bytes(welcome_info)?;
+        Welcome::from_serialized_welcome_info(
+            cs,
+            init_key,
+            welcome_info.protocol_version,
+            serialized_welcome_info,
+            csprng,
+        )
+    }
+
+    /// Like `from_welcome_info`, but takes the `WelcomeInfo` already serialized, so that it only
+    /// has to be serialized once when it's going to several new members
+    fn from_serialized_welcome_info<R>(
+        cs: &'static CipherSuite,
+        init_key: &UserInitKey,
+        protocol_version: ProtocolVersion,
+        serialized_welcome_info: Vec<u8>,
         csprng: &mut R,
     ) -> Result<Welcome, Error>
     where
         R: CryptoRng,
     {
         // Get the public key from the supplied UserInitKey corresponding to the given cipher suite
-        let public_key = init_key
-            .get_public_key(cs)?
-            .ok_or(Error::ValidationError("No corresponding public key for given ciphersuite"))?;
+        // and the group's protocol version
+        let public_key = init_key.get_compatible_public_key(cs, protocol_version)?;
 
-        // Serialize and encrypt the WelcomeInfo
-        let serialized_welcome_info = tls_ser::serialize_to_bytes(welcome_info)?;
-        let ciphertext = ecies::encrypt(cs, &public_key, serialized_welcome_info, csprng)?;
+        // Encrypt the WelcomeInfo
+        let ciphertext = encrypt_welcome_info(cs, public_key, serialized_welcome_info, csprng)?;
 
         // All done
         Ok(Welcome {
@@ -1218,63 +3960,111 @@ impl Welcome {
         // Take the hash of the WelcomeInfo. This is necessary if the caller wants to make an Add.
         // The caller can't derive it themselves, because we wrap the WelcomeInfo in a Welcome in
         // the next step.
-        let welcome_info_hash = group_state.cs.hash_impl.hash_serializable(&welcome_info)?;
+        let welcome_info_hash = group_state.welcome_info_hash()?;
 
         // Encrypt it up
-        let welcome = Welcome::from_welcome_info(&group_state.cs, init_key, &welcome_info, csprng)?;
+        let welcome = Welcome::from_welcome_info(group_state.cs, init_key, &welcome_info, csprng)?;
+
+        Ok((welcome, welcome_info_hash))
+    }
+
+    /// Creates a `Welcome` object for each of the target `UserInitKey`s, all from the same snapshot
+    /// of the group's current state. This is what a batch of `Add`s needs. See
+    /// `GroupState::create_and_apply_add_handshakes`.
+    ///
+    /// Returns: `Ok((welcomes, welcome_info_hash))` on success, where `welcomes` is a list of
+    /// `(user_init_key_id, welcome)` pairs in the same order as `init_keys`, and
+    /// `welcome_info_hash` is as in `Welcome::from_group_state`. Returns an
+    /// `Error::ValidationError` if two of the `UserInitKey`s have the same ID, since then the new
+    /// members couldn't tell their `Welcome`s or `Add`s apart. Otherwise returns an error if any
+    /// of the `Welcome`s can't be made.
+    #[allow(clippy::type_complexity)]
+    pub fn fan_out_from_group_state<R>(
+        group_state: &GroupState,
+        init_keys: &[UserInitKey],
+        csprng: &mut R,
+    ) -> Result<(Vec<(Vec<u8>, Welcome)>, WelcomeInfoHash), Error>
+    where
+        R: CryptoRng,
+    {
+        for (i, init_key) in init_keys.iter().enumerate() {
+            let id = &init_key.user_init_key_id;
+            if init_keys[..i].iter().any(|other| &other.user_init_key_id == id) {
+                return Err(Error::ValidationError("Cannot welcome two UserInitKeys with one ID"));
+            }
+        }
 
-        Ok((welcome, welcome_info_hash.into()))
+        // Everyone gets the same WelcomeInfo, so only serialize it once
+        let welcome_info = group_state.as_welcome_info();
+        let welcome_info_hash = group_state.welcome_info_hash()?;
+        let serialized_welcome_info = tls_ser::serialize_to_bytes(&welcome_info)?;
+
+        let welcomes = init_keys
+            .iter()
+            .map(|init_key| {
+                let welcome = Welcome::from_serialized_welcome_info(
+                    group_state.cs,
+                    init_key,
+                    welcome_info.protocol_version,
+                    serialized_welcome_info.clone(),
+                    csprng,
+                )?;
+                Ok((init_key.user_init_key_id.clone(), welcome))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok((welcomes, welcome_info_hash))
     }
 
-    /// Decrypts the `Welcome` with the given `UserInitKey`
+    /// Decrypts the `Welcome` with the given `UserInitKey`, and checks that the `WelcomeInfo`
+    /// inside makes sense for that `UserInitKey` and the `Welcome`'s ciphersuite
     ///
     /// Requires: That the `init_key` is the `UserInitKey` that the `Welcome` was encrypted with
     /// (i.e., `init_key.user_init_key_id == self.user_init_key_id`) and `init_key.private_keys`
     /// is not `None`
     ///
     /// Returns: `Ok((welcome_info, cs))` on success, where `welcome_info` is the decrypted
-    /// `WelcomeInfo` that this `Welcome` contained, and `cs` is this group's cipher suite
+    /// `WelcomeInfo` that this `Welcome` contained, and `cs` is this group's cipher suite. Returns
+    /// an `Error::InvalidWelcome` saying what's wrong if any of the above doesn't hold or the
+    /// `WelcomeInfo` doesn't make sense. Returns some other `Error` if `init_key` is invalid or
+    /// the `WelcomeInfo` can't be decrypted or parsed.
     fn into_welcome_info_cipher_suite(
         self,
         init_key: &UserInitKey,
-    ) -> Result<(WelcomeInfo, &'static CipherSuite), Error> {
+    ) -> Result<(WelcomeInfo<'static>, &'static CipherSuite), Error> {
         // Verify the UserInitKey signature and validate its contents
         init_key.verify_sig()?;
         init_key.validate()?;
-        // Verify that the supplied UserInitKey is the one that the Welcome message references
-        if self.user_init_key_id != init_key.user_init_key_id {
-            return Err(Error::ValidationError("Supplied UserInitKey ID doesn't match Welcome's"));
-        }
-        // Get the ciphersuite and private key we'll use to decrypt the wrapped WelcomeInfo
+        // Verify that the supplied UserInitKey is the one that the Welcome message references,
+        // and that it's one we made. Only its creator has its private keys.
+        let private_keys = match init_key.private_keys {
+            Some(ref private_keys) if self.user_init_key_id == init_key.user_init_key_id => {
+                private_keys
+            }
+            _ => return Err(WelcomeError::UnknownInitKey.into()),
+        };
+        // Find the entry the Welcome was encrypted to. Its private key is what we'll use to decrypt
+        // the wrapped WelcomeInfo, and its version is what the WelcomeInfo must have.
         let cs = self.cipher_suite;
-        let dh_private_key = init_key
-            .get_private_key(cs)?
-            .ok_or(Error::ValidationError("Can't decrypt Welcome without a private key"))?;
+        let entry_idx = init_key.find_entry(cs)?.ok_or(WelcomeError::UnsupportedCipherSuite)?;
+        let dh_private_key = &private_keys[entry_idx];
+        let supported_version = init_key.supported_versions[entry_idx];
 
         // Decrypt the WelcomeInfo, deserialize it, upcast it, and return it
-        let welcome_info_bytes = ecies::decrypt(cs, dh_private_key, self.encrypted_welcome_info)?;
-        let welcome_info = {
-            let mut cursor = welcome_info_bytes.as_slice();
-            let mut deserializer = TlsDeserializer::from_reader(&mut cursor);
-            let mut w = WelcomeInfo::deserialize(&mut deserializer)?;
-
-            // Once it's deserialized, make it nice and typesafe
-            let ctx = CryptoCtx::new().set_cipher_suite(cs);
-            w.upcast_crypto_values(&ctx)?;
-            w
-        };
+        let welcome_info_bytes =
+            decrypt_welcome_info(cs, dh_private_key, self.encrypted_welcome_info)?;
+        let ctx = CryptoCtx::new().set_cipher_suite(cs);
+        let welcome_info: WelcomeInfo<'static> =
+            upcast::deserialize_and_upcast(&welcome_info_bytes, &ctx)?;
 
         // TODO: Figure out if a versioning scheme should accept versions that are less than the
         // requested one.
 
-        // Check that the WelcomeInfo has precisely the supported version. We can unwrap here
-        // because we already found the private key corresponding to this ciphersuite above.
-        let supported_version = init_key.get_supported_version(cs)?.unwrap();
+        // Check that the WelcomeInfo has precisely the supported version
         if welcome_info.protocol_version != supported_version {
-            return Err(Error::ValidationError(
-                "WelcomeInfo's supported protocol version does not match the UserInitKey's",
-            ));
+            return Err(WelcomeError::ProtocolVersionMismatch.into());
         }
+        welcome_info.validate(cs)?;
 
         Ok((welcome_info, cs))
     }
@@ -1288,25 +4078,36 @@ impl Welcome {
 #[cfg(test)]
 mod test {
     use crate::{
-        credential::Roster,
+        config::{DuplicateIdentityPolicy, GroupConfig, PathEncryption},
+        credential::{Credential, MemberIndex, Roster, RosterIndex},
         crypto::{
-            ciphersuite::{CipherSuite, X25519_SHA256_AES128GCM},
+            ciphersuite::{CipherSuite, P256_SHA256_AES128GCM, X25519_SHA256_AES128GCM},
+            dh::DhPrivateKey,
+            ecies,
             hash::Digest,
             hmac::HmacKey,
+            secret::Secret,
             sig::{SigSecretKey, ED25519_IMPL},
         },
-        error::Error,
-        group_state::{GroupState, UpdateSecret, Welcome},
-        handshake::{ProtocolVersion, UserInitKey, MLS_DUMMY_VERSION},
-        ratchet_tree::RatchetTree,
-        test_utils,
-        tls_de::TlsDeserializer,
-        upcast::{CryptoCtx, CryptoUpcast},
+        error::{Error, WelcomeError},
+        extensions::{ExtensionList, ExtensionType},
+        group_state::{
+            derive_epoch_secrets, GroupContext, GroupState, UpdateSecret, Welcome, WelcomeInfo,
+            WelcomeInitSecret, WELCOME_STREAMING_THRESHOLD,
+        },
+        handshake::{GroupAdd, GroupOperation, ProtocolVersion, UserInitKey, MLS_DUMMY_VERSION},
+        keystore::IdentityKey,
+        metrics::OperationKind,
+        ratchet_tree::{PathSecret, RatchetTree, RatchetTreeNode},
+        test_utils, tls_ser,
+        tree_math::{self, LeafIndex, NodeIndex},
+        upcast::{self, CryptoCtx, CryptoUpcast},
+        verify,
     };
 
     use quickcheck_macros::quickcheck;
     use rand::{RngCore, SeedableRng};
-    use serde::de::Deserialize;
+    use std::sync::Arc;
 
     // Checks that
     // GroupState::from_welcome(Welcome::from_welcome_info(group.as_welcome_info())) == group
@@ -1356,6 +4157,1020 @@ mod test {
         assert_serialized_eq!(group_state1, group_state2, "GroupStates disagree after a Welcome");
     }
 
+    // Checks that GroupState::from_welcome_info rejects WelcomeInfos whose trees are malformed or
+    // don't match the roster
+    #[quickcheck]
+    fn welcome_tree_validation(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        // We need at least 2 leaves to have a parent node
+        let (group_state, _) = test_utils::random_full_group_state(2, &mut rng);
+
+        let (init_key, new_identity_key) =
+            test_utils::random_init_key(b"tree_validation", &X25519_SHA256_AES128GCM, &mut rng);
+        // This is what the WelcomeInfo looks like to the new member, i.e., after it's gone over the
+        // wire. Notably, this strips out all the private keys.
+        let received_welcome_info = || -> WelcomeInfo {
+            let bytes = tls_ser::serialize_to_bytes(&group_state.as_welcome_info()).unwrap();
+            let ctx = CryptoCtx::new().set_cipher_suite(group_state.cs);
+            upcast::deserialize_and_upcast(&bytes, &ctx).unwrap()
+        };
+        let join = |w: WelcomeInfo| {
+            GroupState::from_welcome_info(
+                group_state.cs,
+                w,
+                new_identity_key.clone(),
+                init_key.clone(),
+            )
+        };
+
+        // The untouched WelcomeInfo is fine
+        join(received_welcome_info()).unwrap();
+
+        // A tree with a leaf missing
+        let mut welcome_info = received_welcome_info();
+        let num_nodes = welcome_info.tree.size() - 2;
+        welcome_info.tree.to_mut().nodes_mut().truncate(num_nodes);
+        assert!(join(welcome_info).is_err());
+
+        // A blank leaf whose roster entry is filled
+        let mut welcome_info = received_welcome_info();
+        welcome_info.tree.to_mut().nodes_mut()[0] = RatchetTreeNode::Blank;
+        assert!(join(welcome_info).is_err());
+
+        // A filled parent over two blank leaves, even when the roster agrees about the leaves
+        let mut welcome_info = received_welcome_info();
+        welcome_info.tree.to_mut().nodes_mut()[0] = RatchetTreeNode::Blank;
+        welcome_info.tree.to_mut().nodes_mut()[2] = RatchetTreeNode::Blank;
+        welcome_info.roster.to_mut().0[0] = None;
+        welcome_info.roster.to_mut().0[1] = None;
+        assert!(join(welcome_info).is_err());
+
+        // A tree that claims to have private keys
+        assert!(join(group_state.as_welcome_info()).is_err());
+    }
+
+    // Checks that a Welcome is refused, with the right WelcomeError, when it's for the wrong
+    // UserInitKey or ciphersuite, or when the WelcomeInfo inside doesn't make sense
+    #[quickcheck]
+    fn welcome_content_validation(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state, _) = test_utils::random_full_group_state(1, &mut rng);
+        let cs = group_state.cs;
+
+        let (new_credential, new_identity_key) = test_utils::random_basic_credential(&mut rng);
+        let init_key = test_utils::make_init_key(
+            &new_identity_key,
+            new_credential.clone(),
+            b"content_validation",
+            cs,
+            &mut rng,
+        );
+
+        // Encrypts the given WelcomeInfo to init_key, whatever's in it, and tries to join with it
+        let join = |w: WelcomeInfo, rng: &mut rand::rngs::StdRng| {
+            let bytes = tls_ser::serialize_to_bytes(&w).unwrap();
+            let welcome =
+                Welcome::from_serialized_welcome_info(cs, &init_key, MLS_DUMMY_VERSION, bytes, rng)
+                    .unwrap();
+            GroupState::from_welcome(welcome, new_identity_key.clone(), init_key.clone())
+        };
+        let expect = |res: Result<GroupState, Error>, expected: WelcomeError| match res {
+            Err(Error::InvalidWelcome(e)) => assert_eq!(e, expected),
+            Err(e) => panic!("expected {:?}, got {:?}", expected, e),
+            Ok(_) => panic!("expected {:?}, but the Welcome was accepted", expected),
+        };
+
+        // The untouched WelcomeInfo is fine
+        join(group_state.as_welcome_info(), &mut rng).unwrap();
+
+        let mut welcome_info = group_state.as_welcome_info();
+        welcome_info.protocol_version = ProtocolVersion(MLS_DUMMY_VERSION.0.wrapping_add(1));
+        expect(join(welcome_info, &mut rng), WelcomeError::ProtocolVersionMismatch);
+
+        let mut welcome_info = group_state.as_welcome_info();
+        welcome_info.group_id = Vec::new();
+        expect(join(welcome_info, &mut rng), WelcomeError::EmptyGroupId);
+
+        let mut welcome_info = group_state.as_welcome_info();
+        welcome_info.epoch = u32::MAX;
+        expect(join(welcome_info, &mut rng), WelcomeError::EpochExhausted);
+
+        // An init secret that didn't come out of the group's hash function
+        let mut welcome_info = group_state.as_welcome_info();
+        welcome_info.init_secret = WelcomeInitSecret(HmacKey::new_from_bytes(&[0u8; 7]));
+        expect(join(welcome_info, &mut rng), WelcomeError::CipherSuiteMismatch);
+
+        // A Welcome for someone else's UserInitKey
+        let other_init_key = test_utils::make_init_key(
+            &new_identity_key,
+            new_credential,
+            b"someone else",
+            cs,
+            &mut rng,
+        );
+        let (welcome, _) =
+            Welcome::from_group_state(&group_state, &other_init_key, &mut rng).unwrap();
+        let res = GroupState::from_welcome(welcome, new_identity_key.clone(), init_key.clone());
+        expect(res, WelcomeError::UnknownInitKey);
+
+        // A copy of our UserInitKey that came over the wire has no private keys, so it isn't ours
+        let received_init_key: UserInitKey = {
+            let bytes = tls_ser::serialize_to_bytes(&init_key).unwrap();
+            upcast::deserialize_and_upcast(&bytes, &CryptoCtx::new()).unwrap()
+        };
+        let (welcome, _) = Welcome::from_group_state(&group_state, &init_key, &mut rng).unwrap();
+        let res = GroupState::from_welcome(welcome, new_identity_key.clone(), received_init_key);
+        expect(res, WelcomeError::UnknownInitKey);
+
+        // A Welcome that claims a ciphersuite our UserInitKey doesn't have
+        let (mut welcome, _) =
+            Welcome::from_group_state(&group_state, &init_key, &mut rng).unwrap();
+        welcome.cipher_suite = &P256_SHA256_AES128GCM;
+        let res = GroupState::from_welcome(welcome, new_identity_key.clone(), init_key.clone());
+        expect(res, WelcomeError::UnsupportedCipherSuite);
+    }
+
+    // Checks that an Add for someone who's already in the group is refused, both by its creator
+    // and by whoever receives it, unless the group allows duplicate identities and the credential
+    // is a new one
+    #[quickcheck]
+    fn duplicate_member_add(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state, identity_keys) = test_utils::random_full_group_state(2, &mut rng);
+        let cs = group_state.cs;
+        let my_index = group_state.roster_index.unwrap();
+        let existing_index = test_utils::random_roster_index_with_exceptions(
+            group_state.roster.len(),
+            &[my_index.0 as usize],
+            &mut rng,
+        );
+        let existing_credential = group_state.roster.0[existing_index.0 as usize].clone().unwrap();
+        let existing_identity_key = &identity_keys[existing_index.0 as usize];
+        let new_index = RosterIndex(group_state.roster.len() as u32);
+
+        let expect_duplicate = |res: Result<_, Error>| match res {
+            Err(Error::DuplicateMember(idx)) => assert_eq!(idx, existing_index),
+            _ => panic!("added someone who's already in the group"),
+        };
+
+        // The creator refuses to make an Add for an existing member
+        let welcome_info_hash = group_state.welcome_info_hash().unwrap();
+        let init_key = test_utils::make_init_key(
+            existing_identity_key,
+            existing_credential.clone(),
+            b"duplicate",
+            cs,
+            &mut rng,
+        );
+        expect_duplicate(
+            group_state
+                .create_and_apply_add_handshake(new_index, init_key.clone(), &welcome_info_hash)
+                .map(|_| ()),
+        );
+        // And if someone makes one anyway, nobody applies it
+        let add = GroupAdd {
+            roster_index: new_index,
+            init_key,
+            welcome_info_hash: welcome_info_hash.clone(),
+        };
+        expect_duplicate(group_state.clone().process_add_op(&add, &welcome_info_hash).map(|_| ()));
+
+        // The same identity on another device is refused too, by default
+        let (other_device, other_device_key) = Credential::new_basic_from_random(
+            existing_credential.get_identity().clone(),
+            &ED25519_IMPL,
+            &mut rng,
+        )
+        .unwrap();
+        let other_device_init_key =
+            test_utils::make_init_key(&other_device_key, other_device, b"duplicate", cs, &mut rng);
+        expect_duplicate(
+            group_state
+                .create_and_apply_add_handshake(
+                    new_index,
+                    other_device_init_key.clone(),
+                    &welcome_info_hash,
+                )
+                .map(|_| ()),
+        );
+
+        // But a group that allows duplicate identities takes the other device, and still refuses
+        // the same credential twice
+        let mut group_state = group_state;
+        group_state.extensions.insert(&DuplicateIdentityPolicy::Allow).unwrap();
+        let welcome_info_hash = group_state.welcome_info_hash().unwrap();
+        let (_, new_group_state, _) = group_state
+            .create_and_apply_add_handshake(new_index, other_device_init_key, &welcome_info_hash)
+            .unwrap();
+        assert_eq!(
+            new_group_state.member_index.get_all(existing_credential.get_identity().as_bytes()),
+            &[existing_index, new_index]
+        );
+        let init_key = test_utils::make_init_key(
+            existing_identity_key,
+            existing_credential,
+            b"duplicate",
+            cs,
+            &mut rng,
+        );
+        expect_duplicate(
+            group_state
+                .create_and_apply_add_handshake(new_index, init_key, &welcome_info_hash)
+                .map(|_| ()),
+        );
+    }
+
+    // Checks that an Add whose UserInitKey doesn't verify under its own credential is refused,
+    // both by its creator and by whoever receives it
+    #[quickcheck]
+    fn forged_init_key_add(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state, _) = test_utils::random_full_group_state(2, &mut rng);
+        let new_index = RosterIndex(group_state.roster.len() as u32);
+        let welcome_info_hash = group_state.welcome_info_hash().unwrap();
+
+        // Both keys' credentials use the same signature scheme, so that the misattributed one
+        // below looks like what would come off the wire
+        let cs = group_state.cs;
+        let (credential, identity_key) =
+            Credential::new_basic_from_random("forger".into(), &ED25519_IMPL, &mut rng).unwrap();
+        let init_key =
+            test_utils::make_init_key(&identity_key, credential, b"forged", cs, &mut rng);
+        let (credential, identity_key) =
+            Credential::new_basic_from_random("forger".into(), &ED25519_IMPL, &mut rng).unwrap();
+        let other_init_key =
+            test_utils::make_init_key(&identity_key, credential, b"forged", cs, &mut rng);
+
+        // Someone swaps in their own init key, so they could read what's encrypted to the new
+        // member. Or they claim someone else's credential for the key they made.
+        let mut tampered_init_key = init_key.clone();
+        tampered_init_key.init_keys = other_init_key.init_keys.clone();
+        let mut misattributed_init_key = other_init_key;
+        misattributed_init_key.credential = init_key.credential.clone();
+
+        for forged_init_key in [tampered_init_key, misattributed_init_key] {
+            let expect_forgery = |res: Result<_, Error>| match res {
+                Err(Error::SignatureError(_)) => (),
+                Err(e) => panic!("forged UserInitKey gave the wrong error: {}", e),
+                Ok(_) => panic!("added someone with a forged UserInitKey"),
+            };
+
+            // The creator refuses to make the Add
+            expect_forgery(
+                group_state
+                    .create_and_apply_add_handshake(
+                        new_index,
+                        forged_init_key.clone(),
+                        &welcome_info_hash,
+                    )
+                    .map(|_| ()),
+            );
+            // And if someone makes one anyway, nobody applies it
+            let add = GroupAdd {
+                roster_index: new_index,
+                init_key: forged_init_key,
+                welcome_info_hash: welcome_info_hash.clone(),
+            };
+            expect_forgery(
+                group_state.clone().process_add_op(&add, &welcome_info_hash).map(|_| ()),
+            );
+        }
+
+        // The genuine UserInitKey is fine
+        group_state
+            .create_and_apply_add_handshake(new_index, init_key, &welcome_info_hash)
+            .unwrap();
+    }
+
+    // Checks that group extensions make it to new members through a Welcome, that UserInitKey
+    // extensions survive a round trip over the wire, and that duplicate extension types are
+    // rejected when they come in through a WelcomeInfo
+    #[quickcheck]
+    fn welcome_extensions(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (mut group_state1, _) = test_utils::random_full_group_state(1, &mut rng);
+
+        // Give the group an extension the library knows nothing about
+        let group_ext_type = ExtensionType(0xfafa);
+        let mut group_exts = ExtensionList::new();
+        group_exts.insert_raw(group_ext_type, b"group-wide".to_vec());
+        group_state1.extensions = group_exts.clone();
+
+        // Make a UserInitKey with its own extension
+        let uik_ext_type = ExtensionType(0x0a0a);
+        let mut uik_exts = ExtensionList::new();
+        uik_exts.insert_raw(uik_ext_type, b"per-member".to_vec());
+        let (new_credential, new_identity_key) = test_utils::random_basic_credential(&mut rng);
+        let init_key = UserInitKey::new_from_random_with_extensions(
+            &new_identity_key,
+            b"uik-id".to_vec(),
+            new_credential,
+            vec![&X25519_SHA256_AES128GCM],
+            vec![MLS_DUMMY_VERSION],
+            uik_exts,
+            &mut rng,
+        )
+        .unwrap();
+
+        // Send the UserInitKey over the wire. The extension should still be there and still be
+        // covered by the signature.
+        let received_init_key: UserInitKey = {
+            let bytes = tls_ser::serialize_to_bytes(&init_key).unwrap();
+            upcast::deserialize_and_upcast(&bytes, &CryptoCtx::new()).unwrap()
+        };
+        received_init_key.verify_sig().unwrap();
+        assert_eq!(
+            received_init_key.get_extensions().get_raw(uik_ext_type),
+            Some(&b"per-member"[..])
+        );
+
+        // Welcome the new member. They should see the group's extensions.
+        let welcome_info = group_state1.as_welcome_info();
+        let welcome =
+            Welcome::from_welcome_info(group_state1.cs, &init_key, &welcome_info, &mut rng)
+                .unwrap();
+        let group_state2 =
+            GroupState::from_welcome(welcome, new_identity_key.clone(), init_key.clone()).unwrap();
+        assert_eq!(group_state2.get_extensions(), &group_exts);
+        assert_serialized_eq!(group_state1, group_state2, "GroupStates disagree after a Welcome");
+
+        // Now sneak a duplicate extension into the WelcomeInfo. This should be rejected.
+        let mut bad_welcome_info = group_state1.as_welcome_info();
+        let dup = bad_welcome_info.extensions.0[0].clone();
+        bad_welcome_info.extensions.0.push(dup);
+        assert!(GroupState::from_welcome_info(
+            group_state1.cs,
+            bad_welcome_info,
+            new_identity_key,
+            init_key
+        )
+        .is_err());
+    }
+
+    // Checks that the metadata getters agree with the underlying fields
+    #[quickcheck]
+    fn metadata_getters(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (mut group_state, _) = test_utils::random_full_group_state(2, &mut rng);
+
+        assert_eq!(group_state.get_group_id(), group_state.group_id.as_slice());
+        assert_eq!(group_state.get_epoch(), group_state.epoch);
+        assert_eq!(group_state.get_roster_index(), group_state.roster_index);
+        assert_eq!(group_state.get_cipher_suite().name(), group_state.cs.name);
+
+        // The group is full, so the member count is the roster size. Blanking out someone who
+        // isn't us should decrease the count but not the roster size.
+        let roster_len = group_state.roster.len();
+        assert_eq!(group_state.get_member_count(), roster_len);
+        let other_idx = test_utils::random_roster_index_with_exceptions(
+            roster_len,
+            &[group_state.roster_index.unwrap().0 as usize],
+            &mut rng,
+        );
+        group_state.roster_mut().0[other_idx.0 as usize] = None;
+        assert_eq!(group_state.get_member_count(), roster_len - 1);
+        assert_eq!(group_state.get_roster().len(), roster_len);
+
+        // Same goes for the tree. Its leaf count doesn't change when a leaf is blanked.
+        assert_eq!(group_state.get_leaf_count(), roster_len);
+        assert_eq!(group_state.occupied_leaf_iter().count(), roster_len);
+        let other_leaf_idx = other_idx.node_index().unwrap();
+        group_state.tree.nodes_mut()[other_leaf_idx.0] = RatchetTreeNode::Blank;
+        assert_eq!(group_state.get_leaf_count(), roster_len);
+        assert!(group_state.occupied_leaf_iter().all(|idx| idx != other_leaf_idx));
+        assert_eq!(group_state.occupied_leaf_iter().count(), roster_len - 1);
+    }
+
+    // Checks that erase_old_epochs drops every private key off our direct path, drops the
+    // initializing UserInitKey, and keeps the keys we still need
+    #[quickcheck]
+    fn erase_old_epochs_correctness(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (mut group_state, _) = test_utils::random_full_group_state(2, &mut rng);
+
+        // The tree is full and every secret is known, so there's plenty to erase
+        group_state.erase_old_epochs();
+
+        let num_leaves = group_state.tree.leaf_count();
+        let my_leaf_idx = group_state.roster_index.unwrap().node_index().unwrap();
+        for (idx, node) in group_state.tree.nodes().iter().enumerate() {
+            let on_my_path = tree_math::is_ancestor(NodeIndex(idx), my_leaf_idx, num_leaves);
+            assert_eq!(node.get_private_key().is_some(), on_my_path);
+        }
+
+        // Now do an Update. The new state should still have no private keys off our path, and
+        // the state should still be usable
+        let new_path_secret = PathSecret::new_from_random(group_state.cs, &mut rng);
+        let (_, group_state, _) =
+            group_state.create_and_apply_update_handshake(new_path_secret, &mut rng).unwrap();
+        for (idx, node) in group_state.tree.nodes().iter().enumerate() {
+            if !tree_math::is_ancestor(NodeIndex(idx), my_leaf_idx, num_leaves) {
+                assert!(node.get_private_key().is_none());
+            }
+        }
+    }
+
+    // Checks that member_iter skips empty roster entries and points at the right leaves
+    #[quickcheck]
+    fn member_iter_correctness(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (mut group_state, _) = test_utils::random_full_group_state(2, &mut rng);
+
+        // Blank out someone who isn't us
+        let roster_len = group_state.roster.len();
+        let blank_idx = test_utils::random_roster_index_with_exceptions(
+            roster_len,
+            &[group_state.roster_index.unwrap().0 as usize],
+            &mut rng,
+        );
+        group_state.roster_mut().0[blank_idx.0 as usize] = None;
+
+        let members: Vec<_> = group_state.member_iter().collect();
+        assert_eq!(members.len(), roster_len - 1);
+        for (roster_idx, leaf_idx, credential) in members {
+            assert_ne!(roster_idx, blank_idx);
+            // The nth leaf is at tree index 2n
+            assert_eq!(leaf_idx, LeafIndex(roster_idx.0 as usize).node_index());
+            assert!(group_state.tree.get(leaf_idx).is_some());
+            assert_serialized_eq!(
+                credential,
+                group_state.roster.0[roster_idx.0 as usize].as_ref().unwrap()
+            );
+        }
+    }
+
+    // Checks that the transcript hash is chained one operation at a time, as in section 5.7, and
+    // that the sender and receiver of a Handshake agree on it
+    #[quickcheck]
+    fn transcript_hash_chaining(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state1, identity_keys) = test_utils::random_full_group_state(2, &mut rng);
+        let other_idx = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[group_state1.roster_index.unwrap().0 as usize],
+            &mut rng,
+        );
+        let group_state2 = test_utils::change_self_index(&group_state1, &identity_keys, other_idx);
+
+        let new_path_secret = PathSecret::new_from_random(group_state1.cs, &mut rng);
+        let (handshake, new_group_state1, _) =
+            group_state1.create_and_apply_update_handshake(new_path_secret, &mut rng).unwrap();
+        let (new_group_state2, _) = group_state2.process_handshake(&handshake).unwrap();
+
+        // transcript_hash_[n] = Hash(transcript_hash_[n-1] || operation)
+        let expected = {
+            let mut ctx = group_state1.cs.hash_impl.new_context();
+            ctx.feed_bytes(group_state1.transcript_hash.as_bytes());
+            ctx.feed_serializable(&handshake.operation).unwrap();
+            ctx.finalize()
+        };
+        assert_eq!(new_group_state1.transcript_hash.as_bytes(), expected.as_bytes());
+        assert_eq!(new_group_state2.transcript_hash.as_bytes(), expected.as_bytes());
+    }
+
+    // Checks that the cached GroupContext is filled in by operations on both ends, and always
+    // agrees with one computed from scratch
+    #[quickcheck]
+    fn group_context_caching(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state1, identity_keys) = test_utils::random_full_group_state(2, &mut rng);
+        let other_idx = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[group_state1.roster_index.unwrap().0 as usize],
+            &mut rng,
+        );
+        let group_state2 = test_utils::change_self_index(&group_state1, &identity_keys, other_idx);
+
+        let fresh_context = |group_state: &GroupState| {
+            GroupContext::new(
+                group_state.cs,
+                &group_state.group_id,
+                group_state.epoch,
+                &group_state.tree,
+                &group_state.transcript_hash,
+            )
+            .unwrap()
+        };
+
+        let new_path_secret = PathSecret::new_from_random(group_state1.cs, &mut rng);
+        let (handshake, new_group_state1, _) =
+            group_state1.create_and_apply_update_handshake(new_path_secret, &mut rng).unwrap();
+        let (new_group_state2, _) = group_state2.process_handshake(&handshake).unwrap();
+        for group_state in [new_group_state1, new_group_state2].iter() {
+            let (_, cached) = group_state.cached_context.as_ref().expect("context wasn't cached");
+            assert_serialized_eq!(cached, fresh_context(group_state), "Cached context is stale");
+        }
+
+        // A context cached in another epoch isn't used
+        let mut stale = group_state1.clone();
+        stale.refresh_group_context().unwrap();
+        stale.epoch += 1;
+        assert_serialized_eq!(
+            stale.group_context().unwrap(),
+            fresh_context(&stale),
+            "Stale context was used"
+        );
+
+        // Neither is one cached for a different tree, however the tree got changed
+        let mut stale = group_state1.clone();
+        stale.refresh_group_context().unwrap();
+        let old_context = stale.group_context().unwrap();
+        stale.tree.nodes_mut()[0] = RatchetTreeNode::Blank;
+        let new_context = stale.group_context().unwrap();
+        assert_ne!(old_context.tree_hash.as_bytes(), new_context.tree_hash.as_bytes());
+        assert_serialized_eq!(new_context, fresh_context(&stale), "Stale context was used");
+
+        let mut stale = group_state1.clone();
+        stale.refresh_group_context().unwrap();
+        let (other_group_state, _) = test_utils::random_full_group_state(2, &mut rng);
+        stale.tree = other_group_state.tree;
+        assert_serialized_eq!(
+            stale.group_context().unwrap(),
+            fresh_context(&stale),
+            "Stale context was used"
+        );
+    }
+
+    // Checks that in a group whose path secrets are sender-authenticated, Updates and Removes go
+    // through, and that a path only decrypts under the sender's actual leaf key
+    #[quickcheck]
+    fn sender_authenticated_paths(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (mut group_state1, identity_keys) = test_utils::random_full_group_state(3, &mut rng);
+        group_state1.extensions.insert(&PathEncryption::SenderAuthenticated).unwrap();
+        let my_roster_index = group_state1.roster_index.unwrap();
+        let other_idx = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[my_roster_index.0 as usize],
+            &mut rng,
+        );
+        let group_state2 = test_utils::change_self_index(&group_state1, &identity_keys, other_idx);
+        assert_eq!(
+            group_state2.get_path_encryption().unwrap(),
+            PathEncryption::SenderAuthenticated
+        );
+
+        // An Update goes through
+        let path_secret = PathSecret::new_from_random(group_state1.cs, &mut rng);
+        let (update, group_state1, _) =
+            group_state1.create_and_apply_update_handshake(path_secret, &mut rng).unwrap();
+        let (group_state2, _) = group_state2.process_handshake(&update).unwrap();
+        assert_eq!(group_state1.get_tree_hash().unwrap(), group_state2.get_tree_hash().unwrap());
+
+        // So does a Remove of a third member, whose path starts at their leaf
+        let removed_idx = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[my_roster_index.0 as usize, other_idx.0 as usize],
+            &mut rng,
+        );
+        let path_secret = PathSecret::new_from_random(group_state1.cs, &mut rng);
+        let (remove, group_state1, _) = group_state1
+            .create_and_apply_remove_handshake(removed_idx, path_secret, &mut rng)
+            .unwrap();
+        let (group_state2, _) = group_state2.process_handshake(&remove).unwrap();
+        assert_eq!(group_state1.get_tree_hash().unwrap(), group_state2.get_tree_hash().unwrap());
+
+        // If the receiver has a different key for the sender's leaf, the path doesn't decrypt
+        let path_secret = PathSecret::new_from_random(group_state1.cs, &mut rng);
+        let (update, _, _) =
+            group_state1.create_and_apply_update_handshake(path_secret, &mut rng).unwrap();
+        let mut mistaken_state = group_state2.clone();
+        let impostor_key =
+            DhPrivateKey::new_from_random(group_state1.cs.dh_impl, &mut rng).unwrap();
+        *mistaken_state.tree.get_mut(my_roster_index.node_index().unwrap()).unwrap() =
+            RatchetTreeNode::new_from_private_key(group_state1.cs, impostor_key);
+        match mistaken_state.process_handshake(&update) {
+            Err(Error::EncryptionError(_)) => (),
+            _ => panic!("decrypted a path under the wrong sender key"),
+        }
+        group_state2.process_handshake(&update).unwrap();
+    }
+
+    // Check that a previewed Handshake shows the state that processing it would make, that the
+    // original state is untouched, and that committing gives the same result as processing
+    #[quickcheck]
+    fn preview_and_commit(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state1, identity_keys) = test_utils::random_full_group_state(2, &mut rng);
+        let my_roster_index = group_state1.roster_index.unwrap();
+        let other_idx = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[my_roster_index.0 as usize],
+            &mut rng,
+        );
+        let group_state2 = test_utils::change_self_index(&group_state1, &identity_keys, other_idx);
+
+        let new_path_secret = PathSecret::new_from_random(group_state1.cs, &mut rng);
+        let (handshake, new_group_state1, _) =
+            group_state1.create_and_apply_update_handshake(new_path_secret, &mut rng).unwrap();
+
+        let preview = group_state2.preview(&handshake).unwrap();
+        assert_eq!(preview.operation_kind(), OperationKind::Update);
+        assert_eq!(preview.signer_index(), my_roster_index);
+        assert_eq!(preview.group_state().epoch, group_state2.epoch + 1);
+        assert_serialized_eq!(preview.group_state(), new_group_state1, "Preview disagrees");
+
+        // Throwing the preview away leaves nothing behind, so the Handshake can be processed as
+        // usual afterwards
+        drop(preview);
+        let (processed, _) = group_state2.process_handshake(&handshake).unwrap();
+        let (committed, _) = group_state2.preview(&handshake).unwrap().commit();
+        assert_serialized_eq!(processed, committed, "Committed preview disagrees");
+    }
+
+    // Adds a batch of new members at once and checks that every one of them, as well as an existing
+    // member, ends up in the same state as the adder
+    #[quickcheck]
+    fn batch_add_correctness(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state1, identity_keys) = test_utils::random_full_group_state(2, &mut rng);
+        let other_idx = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[group_state1.roster_index.unwrap().0 as usize],
+            &mut rng,
+        );
+        let group_state2 = test_utils::change_self_index(&group_state1, &identity_keys, other_idx);
+
+        // Make a few new members, each with their own UserInitKey ID
+        let num_new_members = 3;
+        let mut new_members = Vec::new();
+        for i in 0..num_new_members {
+            let (init_key, new_identity_key) =
+                test_utils::random_init_key(&[i as u8], &X25519_SHA256_AES128GCM, &mut rng);
+            new_members.push((new_identity_key, init_key));
+        }
+        let init_keys: Vec<UserInitKey> =
+            new_members.iter().map(|(_, init_key)| init_key.clone()).collect();
+
+        // Empty batches and batches with repeated IDs are refused
+        match group_state1.create_and_apply_add_handshakes(Vec::new(), &mut rng) {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("added an empty batch"),
+        }
+        let repeated = vec![init_keys[0].clone(), init_keys[0].clone()];
+        match group_state1.create_and_apply_add_handshakes(repeated, &mut rng) {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("added a batch with a repeated UserInitKey ID"),
+        }
+
+        let (mut welcomes, handshakes, new_group_state1, _) =
+            group_state1.create_and_apply_add_handshakes(init_keys, &mut rng).unwrap();
+        assert_eq!(welcomes.len(), num_new_members);
+        assert_eq!(handshakes.len(), num_new_members);
+
+        // The existing member just processes the Adds in order
+        let mut new_group_state2 = group_state2;
+        for handshake in handshakes.iter() {
+            new_group_state2 = new_group_state2.process_handshake(handshake).unwrap().0;
+        }
+        assert_serialized_eq!(new_group_state1, new_group_state2, "Existing member disagrees");
+
+        // Each new member finds their Welcome by ID and processes every Add, including the ones
+        // for the new members ahead of them
+        for (identity_key, init_key) in new_members {
+            let pos = welcomes.iter().position(|(id, _)| id == &init_key.user_init_key_id).unwrap();
+            let (_, welcome) = welcomes.swap_remove(pos);
+            let mut new_member_state =
+                GroupState::from_welcome(welcome, identity_key, init_key).unwrap();
+            for handshake in handshakes.iter() {
+                new_member_state = new_member_state.process_handshake(handshake).unwrap().0;
+            }
+            assert!(new_member_state.roster_index.is_some());
+            assert_serialized_eq!(new_group_state1, new_member_state, "New member disagrees");
+        }
+    }
+
+    // Adds a member whose Welcome gets lost, and checks that the adder can re-issue it for as long
+    // as the group's epoch retention allows, and that the new member can join from the re-issued
+    // one
+    #[quickcheck]
+    fn welcome_reissue(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (mut group_state1, _) = test_utils::random_full_group_state(1, &mut rng);
+        // Keep one past epoch around, so the Welcome outlives one Update
+        group_state1.config.epoch_retention = 2;
+
+        let (init_key, new_identity_key) =
+            test_utils::random_init_key(b"lost welcome", &X25519_SHA256_AES128GCM, &mut rng);
+
+        // The original Welcome never makes it
+        let (_, welcome_info_hash) =
+            Welcome::from_group_state(&group_state1, &init_key, &mut rng).unwrap();
+        let new_roster_index = RosterIndex(group_state1.roster.len() as u32);
+        let (add, group_state1, _) = group_state1
+            .create_and_apply_add_handshake(new_roster_index, init_key.clone(), &welcome_info_hash)
+            .unwrap();
+
+        // Nothing is cached for a UserInitKey nobody was added with
+        match group_state1.reissue_welcome(b"someone else", &mut rng) {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("re-issued a Welcome for a UserInitKey that was never added"),
+        }
+
+        // The group moves on by one epoch, which the retention allows for
+        let new_path_secret = PathSecret::new_from_random(group_state1.cs, &mut rng);
+        let (update, group_state1, _) =
+            group_state1.create_and_apply_update_handshake(new_path_secret, &mut rng).unwrap();
+
+        // The new member joins from the re-issued Welcome and catches up
+        let welcome = group_state1.reissue_welcome(&init_key.user_init_key_id, &mut rng).unwrap();
+        assert_eq!(welcome.get_user_init_key_id(), init_key.user_init_key_id.as_slice());
+        let group_state2 =
+            GroupState::from_welcome(welcome, new_identity_key, init_key.clone()).unwrap();
+        let (group_state2, _) = group_state2.process_handshake(&add).unwrap();
+        let (group_state2, _) = group_state2.process_handshake(&update).unwrap();
+        assert_serialized_eq!(group_state1, group_state2, "Re-issued Welcome led elsewhere");
+
+        // One more epoch and it's gone
+        let new_path_secret = PathSecret::new_from_random(group_state1.cs, &mut rng);
+        let (_, group_state1, _) =
+            group_state1.create_and_apply_update_handshake(new_path_secret, &mut rng).unwrap();
+        match group_state1.reissue_welcome(&init_key.user_init_key_id, &mut rng) {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("re-issued a Welcome past the group's epoch retention"),
+        }
+    }
+
+    // Makes a group whose WelcomeInfo is over WELCOME_STREAMING_THRESHOLD, and checks that its
+    // Welcome is streamed, and that the new member can join from it and process the Add. Also
+    // checks that small Welcomes are still encrypted in one piece. This encrypts over a megabyte,
+    // so it runs once instead of under quickcheck.
+    #[test]
+    fn streamed_welcome() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0x5eed);
+        let (mut group_state1, _) = test_utils::random_full_group_state(20, &mut rng);
+        let cs = group_state1.cs;
+        let tag_size = cs.aead_impl.tag_size();
+
+        // A small group's Welcome is a plain ECIES ciphertext
+        let (init_key, _) = test_utils::random_init_key(b"small welcome", cs, &mut rng);
+        let (welcome, _) = Welcome::from_group_state(&group_state1, &init_key, &mut rng).unwrap();
+        let private_key = &init_key.private_keys.as_ref().unwrap()[0];
+        assert!(ecies::decrypt(cs, private_key, welcome.encrypted_welcome_info).is_ok());
+
+        // Blow up the WelcomeInfo by padding everyone's identity. The random prefixes keep them
+        // distinct.
+        let identity_len = WELCOME_STREAMING_THRESHOLD / group_state1.roster.len() + 1;
+        for entry in group_state1.roster_mut().0.iter_mut() {
+            match entry {
+                Some(Credential::Basic(cred)) => cred.identity.0.resize(identity_len, 0u8),
+                _ => panic!("random group has a blank or non-basic roster entry"),
+            }
+        }
+        group_state1.member_index = Arc::new(MemberIndex::from_roster(&group_state1.roster));
+
+        // Now the Welcome is streamed, so it's too long to open in one piece
+        let (init_key, new_identity_key) =
+            test_utils::random_init_key(b"big welcome", cs, &mut rng);
+        let (welcome, welcome_info_hash) =
+            Welcome::from_group_state(&group_state1, &init_key, &mut rng).unwrap();
+        let ciphertext_len = welcome.encrypted_welcome_info.ciphertext.len();
+        assert!(ciphertext_len > WELCOME_STREAMING_THRESHOLD + tag_size);
+        let private_key = &init_key.private_keys.as_ref().unwrap()[0];
+        let encrypted_welcome_info = welcome.encrypted_welcome_info.clone();
+        assert!(ecies::decrypt(cs, private_key, encrypted_welcome_info).is_err());
+
+        // It still gets through the wire format, and the new member joins from it
+        let welcome_bytes = tls_ser::serialize_to_bytes(&welcome).unwrap();
+        let welcome: Welcome =
+            upcast::deserialize_and_upcast(&welcome_bytes, &CryptoCtx::new()).unwrap();
+        let new_roster_index = RosterIndex(group_state1.roster.len() as u32);
+        let (add, group_state1, _) = group_state1
+            .create_and_apply_add_handshake(new_roster_index, init_key.clone(), &welcome_info_hash)
+            .unwrap();
+        let group_state2 = GroupState::from_welcome(welcome, new_identity_key, init_key).unwrap();
+        let (group_state2, _) = group_state2.process_handshake(&add).unwrap();
+        assert_serialized_eq!(group_state1, group_state2, "Streamed Welcome led elsewhere");
+    }
+
+    // Branches off a subset of a group and checks that the members of the branch agree on it, and
+    // that people outside of the branch can't join it
+    #[quickcheck]
+    fn branch_correctness(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state1, identity_keys) = test_utils::random_full_group_state(3, &mut rng);
+        let my_idx = group_state1.roster_index.unwrap();
+        let other_idx = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[my_idx.0 as usize],
+            &mut rng,
+        );
+        let outsider_idx = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[my_idx.0 as usize, other_idx.0 as usize],
+            &mut rng,
+        );
+        let group_state2 = test_utils::change_self_index(&group_state1, &identity_keys, other_idx);
+        let outsider_state =
+            test_utils::change_self_index(&group_state1, &identity_keys, outsider_idx);
+
+        // The branch has to have its own ID and include its creator
+        let new_group_id = b"side conversation".to_vec();
+        let new_path_secret = PathSecret::new_from_random(group_state1.cs, &mut rng);
+        match group_state1.create_and_apply_branch_handshake(
+            group_state1.group_id.clone(),
+            &[my_idx, other_idx],
+            new_path_secret.clone(),
+            &mut rng,
+        ) {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("made a branch with its parent's group ID"),
+        }
+        match group_state1.create_and_apply_branch_handshake(
+            new_group_id.clone(),
+            &[other_idx],
+            new_path_secret.clone(),
+            &mut rng,
+        ) {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("made a branch without its creator"),
+        }
+
+        let (handshake, branch1, _) = group_state1
+            .create_and_apply_branch_handshake(
+                new_group_id.clone(),
+                &[my_idx, other_idx],
+                new_path_secret,
+                &mut rng,
+            )
+            .unwrap();
+        assert_eq!(branch1.group_id, new_group_id);
+        assert_eq!(branch1.roster.len(), 2);
+
+        // The other member gets the same branch, regardless of the order of the indices
+        let (branch2, _) =
+            group_state2.process_branch_handshake(&[other_idx, my_idx], &handshake).unwrap();
+        assert_serialized_eq!(branch1, branch2, "Branch members disagree");
+
+        // Someone outside the branch can't get in, even by claiming to be in it
+        assert!(outsider_state.process_branch_handshake(&[my_idx, other_idx], &handshake).is_err());
+        assert!(outsider_state
+            .process_branch_handshake(&[my_idx, other_idx, outsider_idx], &handshake)
+            .is_err());
+
+        // And the branch is keyed off of the parent's key schedule, so knowing everything else
+        // about the parent group isn't enough to join it
+        let mut group_state2_wrong_secret = group_state2.clone();
+        group_state2_wrong_secret.init_secret =
+            HmacKey::new_from_random(group_state2.cs.hash_impl, &mut rng);
+        assert!(group_state2_wrong_secret
+            .process_branch_handshake(&[my_idx, other_idx], &handshake)
+            .is_err());
+    }
+
+    // Checks that find_member and get_member_at_leaf agree with the roster, and that the member
+    // index keeps up with Adds and Removes on both the sending and receiving end
+    #[quickcheck]
+    fn member_lookup_correctness(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state1, identity_keys) = test_utils::random_full_group_state(3, &mut rng);
+
+        // Every member can be found, and nobody else can
+        for (roster_index, _, cred) in group_state1.member_iter() {
+            assert_eq!(group_state1.find_member(&cred.get_identity().0), Some(roster_index));
+            let (leaf_roster_index, leaf_cred) =
+                group_state1.get_member_at_leaf(LeafIndex(roster_index.0 as usize)).unwrap();
+            assert_eq!(leaf_roster_index, roster_index);
+            assert_eq!(leaf_cred, cred);
+        }
+        assert_eq!(group_state1.find_member(b"nobody"), None);
+        assert!(group_state1.get_member_at_leaf(LeafIndex(group_state1.roster.len())).is_none());
+
+        // Remove someone. Both the remover and someone else should forget them.
+        let my_idx = group_state1.roster_index.unwrap().0 as usize;
+        let removed_idx = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[my_idx],
+            &mut rng,
+        );
+        let other_idx = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[my_idx, removed_idx.0 as usize],
+            &mut rng,
+        );
+        let group_state2 = test_utils::change_self_index(&group_state1, &identity_keys, other_idx);
+        let removed_identity =
+            group_state1.roster.0[removed_idx.0 as usize].as_ref().unwrap().get_identity().clone();
+
+        let new_path_secret = PathSecret::new_from_random(group_state1.cs, &mut rng);
+        let (handshake, group_state1, _) = group_state1
+            .create_and_apply_remove_handshake(removed_idx, new_path_secret, &mut rng)
+            .unwrap();
+        let (group_state2, _) = group_state2.process_handshake(&handshake).unwrap();
+        for group_state in &[&group_state1, &group_state2] {
+            assert_eq!(group_state.find_member(&removed_identity.0), None);
+            assert_eq!(*group_state.member_index, MemberIndex::from_roster(&group_state.roster));
+        }
+
+        // Add them back. Both ends should find them again.
+        let (new_credential, new_identity_key) = test_utils::random_basic_credential(&mut rng);
+        let new_identity = new_credential.get_identity().clone();
+        let init_key = test_utils::make_init_key(
+            &new_identity_key,
+            new_credential,
+            b"lookup",
+            &X25519_SHA256_AES128GCM,
+            &mut rng,
+        );
+        let new_roster_index = group_state1.roster.next_add_index().unwrap();
+        let welcome_info_hash = group_state1.welcome_info_hash().unwrap();
+        let (handshake, group_state1, _) = group_state1
+            .create_and_apply_add_handshake(new_roster_index, init_key, &welcome_info_hash)
+            .unwrap();
+        let (group_state2, _) = group_state2.process_handshake(&handshake).unwrap();
+        for group_state in &[&group_state1, &group_state2] {
+            assert_eq!(group_state.find_member(&new_identity.0), Some(new_roster_index));
+            assert_eq!(*group_state.member_index, MemberIndex::from_roster(&group_state.roster));
+        }
+    }
+
+    // Checks that a Handshake that went over the wire can be parsed and processed by another
+    // member, and that one claiming to be from outside the roster can't be parsed at all
+    #[quickcheck]
+    fn handshake_deserialization(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state1, identity_keys) = test_utils::random_full_group_state(2, &mut rng);
+        let my_idx = group_state1.roster_index.unwrap().0 as usize;
+        let other_idx = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[my_idx],
+            &mut rng,
+        );
+        let group_state2 = test_utils::change_self_index(&group_state1, &identity_keys, other_idx);
+
+        let new_path_secret = PathSecret::new_from_random(group_state1.cs, &mut rng);
+        let (mut handshake, group_state1, _) =
+            group_state1.create_and_apply_update_handshake(new_path_secret, &mut rng).unwrap();
+        let bytes = tls_ser::serialize_to_bytes(&handshake).unwrap();
+        let received = group_state2.deserialize_handshake(&bytes).unwrap();
+        let (group_state2, _) = group_state2.process_handshake(&received).unwrap();
+        assert_eq!(
+            group_state1.transcript_hash.as_bytes(),
+            group_state2.transcript_hash.as_bytes()
+        );
+
+        handshake.signer_index = RosterIndex(group_state2.roster.len() as u32);
+        let bytes = tls_ser::serialize_to_bytes(&handshake).unwrap();
+        match group_state2.deserialize_handshake(&bytes) {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("parsed a Handshake from outside the roster"),
+        }
+    }
+
+    // Has a group's creator make its Init, and checks that a copy of the creator's starting state
+    // and a server tracking the transcript hash both accept it, that it can't happen twice, and
+    // that an Init describing some other group is refused
+    #[quickcheck]
+    fn init_correctness(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let cs = &X25519_SHA256_AES128GCM;
+        let (credential, identity_key) = test_utils::random_basic_credential(&mut rng);
+        let group_state = GroupState::new_singleton_group(
+            cs,
+            MLS_DUMMY_VERSION,
+            identity_key,
+            b"init".to_vec(),
+            credential.clone(),
+            &mut rng,
+        )
+        .unwrap();
+
+        let (handshake, new_group_state, _) =
+            group_state.create_and_apply_init_handshake().unwrap();
+        assert_eq!(new_group_state.epoch, 1);
+
+        // The Init goes over the wire and is processed like any other Handshake
+        let bytes = tls_ser::serialize_to_bytes(&handshake).unwrap();
+        let received = group_state.deserialize_handshake(&bytes).unwrap();
+        let (replica, _) = group_state.process_handshake(&received).unwrap();
+        assert_eq!(replica.transcript_hash.as_bytes(), new_group_state.transcript_hash.as_bytes());
+
+        // A server that starts from the all-zeros transcript hash, and knows the tree the group
+        // started with, can check it too
+        let zeros = vec![0u8; cs.hash_impl.digest_size()];
+        let tree_hash = group_state.get_tree_hash().unwrap();
+        let (_, transcript_hash) =
+            verify::verify_handshake(&bytes, cs, &credential, &zeros, &tree_hash).unwrap();
+        assert_eq!(transcript_hash, new_group_state.transcript_hash.as_bytes());
+
+        // An Init is only ever the first operation
+        match new_group_state.create_and_apply_init_handshake() {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("made a second Init"),
+        }
+
+        // An Init has to describe the group exactly
+        let mut received = group_state.deserialize_handshake(&bytes).unwrap();
+        match received.operation {
+            GroupOperation::Init(ref mut init) => init.group_id = b"some other group".to_vec(),
+            _ => unreachable!(),
+        }
+        match group_state.process_handshake(&received) {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("processed an Init for a different group"),
+        }
+    }
+
     // This is all the serializable bits of a GroupState. We have this separate because GroupState
     // is only ever meant to be serialized. The fields in it that are for us and not for
     // serialization require a Default instance in order for GroupState to impl Deserialize. Since
@@ -1382,18 +5197,27 @@ mod test {
     pub(crate) fn group_from_test_group(tgs: TestGroupState) -> GroupState {
         let cs = &X25519_SHA256_AES128GCM;
         let ss = &ED25519_IMPL;
+        let member_index = Arc::new(MemberIndex::from_roster(&tgs.roster));
         GroupState {
             cs,
             protocol_version: MLS_DUMMY_VERSION,
-            identity_key: SigSecretKey::new_from_bytes(ss, &[0u8; 32]).unwrap(),
+            identity_key: IdentityKey::Local(SigSecretKey::new_from_bytes(ss, &[0u8; 32]).unwrap()),
             group_id: tgs.group_id,
             epoch: tgs.epoch,
-            roster: tgs.roster,
+            roster: Arc::new(tgs.roster),
             tree: tgs.tree,
             transcript_hash: tgs.transcript_hash,
-            roster_index: Some(0),
+            extensions: ExtensionList::new(),
+            roster_index: Some(RosterIndex(0)),
             initializing_user_init_key: None,
             init_secret: HmacKey::new_from_zeros(cs.hash_impl),
+            retired_credentials: Vec::new(),
+            member_index,
+            config: GroupConfig::new(cs),
+            welcome_cache: Vec::new(),
+            cached_context: None,
+            #[cfg(feature = "dangerous-debug")]
+            recorded_secrets: Default::default(),
         }
     }
 
@@ -1470,36 +5294,68 @@ mod test {
         case_x25519: KeyScheduleCase,
     }
 
-    // Tests our code against the official key schedule test vector
+    impl CryptoUpcast for KeyScheduleTestVectors {
+        fn upcast_crypto_values(&mut self, ctx: &CryptoCtx) -> Result<CryptoCtx, Error> {
+            self.base_group_state.upcast_crypto_values(ctx)
+        }
+    }
+
+    // Tests our code against the official key schedule test vector, for both ciphersuites. Every
+    // epoch's secrets are derived from nothing but the vector's update secret, the previous
+    // epoch's init secret, and the base GroupState at that epoch, which is the group context.
     #[test]
     fn official_key_schedule_kat() {
-        let mut f = std::fs::File::open("test_vectors/key_schedule.bin").unwrap();
-        let mut deserializer = TlsDeserializer::from_reader(&mut f);
-        let test_vec = KeyScheduleTestVectors::deserialize(&mut deserializer).unwrap();
-        let case1 = test_vec.case_x25519;
-        let mut group_state = group_from_test_group(test_vec.base_group_state);
-
-        // Keep deriving new secrets with respect to the given update secret. Check all the
-        // resulting keys against the test vector.
-        for epoch in case1.epochs.into_iter() {
-            let update_secret = UpdateSecret(epoch.update_secret);
-            let (app_secret, conf_key) = group_state.update_epoch_secrets(&update_secret).unwrap();
-
-            // Wrap all the inputs in HmacKeys so we can compare them to other HmacKeys
-            let epoch_application_secret = HmacKey::new_from_bytes(&epoch.application_secret);
-            let epoch_confirmation_key = HmacKey::new_from_bytes(&epoch.confirmation_key);
-            let epoch_init_secret = HmacKey::new_from_bytes(&epoch.init_secret);
-
-            // We don't save the derived epoch_secret anywhere, since it's just an intermediate
-            // value. We do test all the things derived from it, though. We convert the LHS to
-            // HmacKeys so we can compare them to the RHS.
-            assert_eq!(HmacKey::from(app_secret), epoch_application_secret);
-            assert_eq!(HmacKey::from(conf_key), epoch_confirmation_key);
-            assert_eq!(group_state.init_secret, epoch_init_secret);
-
-            // Increment the state epoch every time we do a key derivation. This is what happens in
-            // the actual protocol.
-            group_state.epoch += 1;
+        let bytes = std::fs::read("test_vectors/key_schedule.bin").unwrap();
+        // The base GroupState's tree has X25519 keys in it. The cases themselves only use the
+        // hash functions of their ciphersuites.
+        let ctx = CryptoCtx::new().set_cipher_suite(&X25519_SHA256_AES128GCM);
+        let test_vec: KeyScheduleTestVectors =
+            upcast::deserialize_and_upcast(&bytes, &ctx).unwrap();
+        let base_group_state = group_from_test_group(test_vec.base_group_state);
+
+        for case in [test_vec.case_p256, test_vec.case_x25519].iter() {
+            assert_eq!(case.epochs.len(), test_vec.n_epochs as usize);
+
+            // The first init secret is all zeros
+            let hash_impl = case.ciphersuite.hash_impl;
+            let mut group_state = base_group_state.clone();
+            group_state.cs = case.ciphersuite;
+            group_state.init_secret = HmacKey::new_from_zeros(hash_impl);
+
+            // Keep deriving new secrets with respect to the given update secret. Check all the
+            // resulting keys against the test vector, both straight out of the key schedule and
+            // as they come out of a GroupState.
+            for epoch in case.epochs.iter() {
+                let update_secret = UpdateSecret(Secret::new_from_bytes(&epoch.update_secret));
+                let secrets = derive_epoch_secrets(
+                    hash_impl,
+                    &group_state.init_secret,
+                    &update_secret,
+                    &group_state,
+                )
+                .unwrap();
+                let (app_secret, conf_key) =
+                    group_state.update_epoch_secrets(&update_secret).unwrap();
+
+                // Wrap all the inputs in HmacKeys so we can compare them to other HmacKeys
+                let epoch_epoch_secret = HmacKey::new_from_bytes(&epoch.epoch_secret);
+                let epoch_application_secret = HmacKey::new_from_bytes(&epoch.application_secret);
+                let epoch_confirmation_key = HmacKey::new_from_bytes(&epoch.confirmation_key);
+                let epoch_init_secret = HmacKey::new_from_bytes(&epoch.init_secret);
+
+                assert_eq!(secrets.epoch_secret, epoch_epoch_secret);
+                assert_eq!(HmacKey::from(secrets.application_secret), epoch_application_secret);
+                assert_eq!(HmacKey::from(secrets.confirmation_key), epoch_confirmation_key);
+                assert_eq!(secrets.init_secret, epoch_init_secret);
+
+                assert_eq!(HmacKey::from(app_secret), epoch_application_secret);
+                assert_eq!(HmacKey::from(conf_key), epoch_confirmation_key);
+                assert_eq!(group_state.init_secret, epoch_init_secret);
+
+                // Increment the state epoch every time we do a key derivation. This is what
+                // happens in the actual protocol.
+                group_state.epoch += 1;
+            }
         }
     }
 }
@@ -0,0 +1,303 @@
+//! Defines `HistoryLog`, an append-only record of the handshakes a `Session` has applied. A
+//! `Session` only keeps one if it's asked to with `Session::set_history_logging`. The log can be
+//! exported with a signature from the member who kept it, for compliance and auditing, and it can
+//! be replayed on top of an older `GroupState` to get back to a later one.
+
+use crate::{
+    application::ApplicationKeyChain,
+    credential::{Credential, RosterIndex},
+    crypto::sig::Signature,
+    error::Error,
+    group_state::GroupState,
+    handshake::Handshake,
+    tls_ser,
+};
+
+/// A handshake that was applied to a group, along with where it left the group
+// struct {
+//     uint32 prior_epoch;
+//     uint32 signer_index;
+//     opaque transcript_hash<0..255>;
+//     opaque handshake<0..2^32-1>;
+// } HistoryEntry;
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct HistoryEntry {
+    /// The epoch the handshake was sent in. Applying it moved the group to the next one.
+    pub prior_epoch: u32,
+    /// The roster index of the member who sent the handshake
+    pub signer_index: RosterIndex,
+    /// The group's transcript hash after the handshake was applied
+    #[serde(rename = "transcript_hash__bound_u8")]
+    pub transcript_hash: Vec<u8>,
+    /// The serialized handshake. This is signed by its sender.
+    #[serde(rename = "handshake__bound_u32")]
+    pub handshake: Vec<u8>,
+}
+
+/// The handshakes applied to a group, oldest first. Entries can only be added to the end, and only
+/// in epoch order.
+#[derive(Clone, Debug)]
+pub struct HistoryLog {
+    group_id: Vec<u8>,
+    entries: Vec<HistoryEntry>,
+}
+
+impl HistoryLog {
+    /// Makes an empty log for the group with the given ID
+    pub(crate) fn new(group_id: Vec<u8>) -> HistoryLog {
+        HistoryLog {
+            group_id,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Returns the ID of the group this log is for
+    pub fn get_group_id(&self) -> &[u8] {
+        &self.group_id
+    }
+
+    /// Returns the entries of this log, oldest first
+    pub fn entries(&self) -> &[HistoryEntry] {
+        &self.entries
+    }
+
+    /// Records that the given handshakes were applied, in order, to `group_state`. This must be
+    /// called before the state they produce replaces `group_state`.
+    ///
+    /// Returns: `Ok(())` on success. Returns an `Error::ValidationError` if a handshake isn't the
+    /// one that follows the last entry. Returns an `Error::SerdeError` if a handshake can't be
+    /// serialized.
+    pub(crate) fn record(
+        &mut self,
+        group_state: &GroupState,
+        handshakes: &[Handshake],
+    ) -> Result<(), Error> {
+        let mut transcript_hash = group_state.transcript_hash.clone();
+        let mut expected_epoch = group_state.epoch;
+        let mut new_entries = Vec::with_capacity(handshakes.len());
+
+        for handshake in handshakes {
+            if handshake.group_id != self.group_id || handshake.prior_epoch != expected_epoch {
+                return Err(Error::ValidationError("Handshake doesn't follow the history log"));
+            }
+            transcript_hash = GroupState::next_transcript_hash(
+                group_state.cs,
+                &transcript_hash,
+                &handshake.operation,
+            )?;
+            new_entries.push(HistoryEntry {
+                prior_epoch: handshake.prior_epoch,
+                signer_index: handshake.signer_index,
+                transcript_hash: transcript_hash.as_bytes().to_vec(),
+                handshake: tls_ser::serialize_to_bytes(handshake)?,
+            });
+            expected_epoch = expected_epoch.wrapping_add(1);
+        }
+
+        // Only append once everything has been checked, so a bad batch leaves the log as it was
+        self.entries.extend(new_entries);
+        Ok(())
+    }
+
+    /// Signs this log with the identity key of the member whose view of the group is
+    /// `group_state`, so that it can be handed to someone else
+    ///
+    /// Returns: `Ok(signed_history)` on success. Returns an `Error::ValidationError` if
+    /// `group_state` is for a different group or is preliminary. Returns an `Error::SerdeError` if
+    /// the log can't be serialized.
+    pub fn export(&self, group_state: &GroupState) -> Result<SignedHistory, Error> {
+        if group_state.group_id != self.group_id {
+            return Err(Error::ValidationError("GroupState is for a different group"));
+        }
+        let signer_index = group_state
+            .roster_index
+            .ok_or(Error::ValidationError("Cannot sign history with a preliminary GroupState"))?;
+
+        let sig_data = history_signature_content(&self.group_id, &self.entries, signer_index)?;
+        let signature = group_state.sign_with_identity_key(&sig_data)?;
+
+        Ok(SignedHistory {
+            group_id: self.group_id.clone(),
+            entries: self.entries.clone(),
+            signer_index,
+            signature: signature.as_bytes(),
+        })
+    }
+}
+
+/// A `HistoryLog` signed by the member who kept it
+// struct {
+//     opaque group_id<0..255>;
+//     HistoryEntry entries<0..2^32-1>;
+//     uint32 signer_index;
+//     opaque signature<0..2^16-1>;
+// } SignedHistory;
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SignedHistory {
+    /// The ID of the group this history is for
+    #[serde(rename = "group_id__bound_u8")]
+    pub group_id: Vec<u8>,
+    /// The entries of the log, oldest first
+    #[serde(rename = "entries__bound_u32")]
+    pub entries: Vec<HistoryEntry>,
+    /// The roster index of the member who signed this
+    pub signer_index: RosterIndex,
+    /// The signature over everything above
+    #[serde(rename = "signature__bound_u16")]
+    pub signature: Vec<u8>,
+}
+
+impl SignedHistory {
+    /// Checks that this history was signed by the holder of `signer_credential`, and that its
+    /// entries are in consecutive epochs. Whether the handshakes themselves are any good is only
+    /// found out by `replay`ing them.
+    ///
+    /// Returns: `Ok(())` on success. Returns an `Error::ValidationError` if the entries skip an
+    /// epoch, and an `Error::SignatureError` if the signature doesn't verify.
+    pub fn verify(&self, signer_credential: &Credential) -> Result<(), Error> {
+        for pair in self.entries.windows(2) {
+            if pair[1].prior_epoch != pair[0].prior_epoch.wrapping_add(1) {
+                return Err(Error::ValidationError("History entries aren't in consecutive epochs"));
+            }
+        }
+
+        let sig_data = history_signature_content(&self.group_id, &self.entries, self.signer_index)?;
+        let ss = signer_credential.get_signature_scheme();
+        let signature = Signature::new_from_bytes(ss, &self.signature)?;
+        signer_credential.verify(&sig_data, &signature)
+    }
+}
+
+// The signed part of a SignedHistory, i.e., everything but the signature
+#[derive(Serialize)]
+struct HistorySignatureContent<'a> {
+    #[serde(rename = "group_id__bound_u8")]
+    group_id: &'a [u8],
+    #[serde(rename = "entries__bound_u32")]
+    entries: &'a [HistoryEntry],
+    signer_index: RosterIndex,
+}
+
+fn history_signature_content(
+    group_id: &[u8],
+    entries: &[HistoryEntry],
+    signer_index: RosterIndex,
+) -> Result<Vec<u8>, Error> {
+    tls_ser::serialize_to_bytes(&HistorySignatureContent {
+        group_id,
+        entries,
+        signer_index,
+    })
+}
+
+/// Re-applies the logged handshakes to `checkpoint`, starting with the one sent in the
+/// checkpoint's epoch. Entries from before the checkpoint are skipped. Every handshake is checked
+/// like `GroupState::process_handshake` checks it, and the resulting transcript hash must match
+/// the logged one. Since nobody can process their own handshakes, the checkpoint must be from a
+/// member who didn't send any of the handshakes being replayed.
+///
+/// Returns: `Ok((group_state, app_key_chain))` on success, where `group_state` is the state after
+/// the last entry, and `app_key_chain` is its application key chain, or `None` if no entries were
+/// replayed. Returns an `Error::ValidationError` if there's a gap between the checkpoint and the
+/// history, or if an entry is missing or its transcript hash doesn't match. Otherwise returns whatever deserializing or processing a handshake returns.
+pub fn replay(
+    checkpoint: &GroupState,
+    entries: &[HistoryEntry],
+) -> Result<(GroupState, Option<ApplicationKeyChain>), Error> {
+    let mut group_state = checkpoint.clone();
+    let mut app_key_chain = None;
+
+    // If no entry was sent in the checkpoint's epoch, the history has to end right where the
+    // checkpoint is. Otherwise there's a gap between them.
+    let start = match entries.iter().position(|entry| entry.prior_epoch == checkpoint.epoch) {
+        Some(start) => start,
+        None => match entries.last() {
+            Some(last) if last.prior_epoch.wrapping_add(1) != checkpoint.epoch => {
+                return Err(Error::ValidationError("History doesn't reach the checkpoint's epoch"))
+            }
+            _ => entries.len(),
+        },
+    };
+    for entry in &entries[start..] {
+        if entry.prior_epoch != group_state.epoch {
+            return Err(Error::ValidationError("History is missing an entry"));
+        }
+
+        let handshake = group_state.deserialize_handshake(&entry.handshake)?;
+        let (new_group_state, new_app_key_chain) = group_state.process_handshake(&handshake)?;
+        if new_group_state.transcript_hash.as_bytes() != entry.transcript_hash.as_slice() {
+            return Err(Error::ValidationError("Replayed transcript hash doesn't match history"));
+        }
+        group_state = new_group_state;
+        app_key_chain = Some(new_app_key_chain);
+    }
+
+    Ok((group_state, app_key_chain))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ratchet_tree::PathSecret, session::Session, test_utils};
+
+    use quickcheck_macros::quickcheck;
+    use rand::SeedableRng;
+
+    // Has one member do some Updates, and checks that both members log the same history, that the
+    // signed export verifies, and that replaying it from the receiver's starting point gets back
+    // to where the receiver ended up
+    #[quickcheck]
+    fn history_correctness(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state1, identity_keys) = test_utils::random_full_group_state(2, &mut rng);
+        let other_index = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[group_state1.roster_index.unwrap().0 as usize],
+            &mut rng,
+        );
+        let group_state2 =
+            test_utils::change_self_index(&group_state1, &identity_keys, other_index);
+        let checkpoint = group_state2.clone();
+
+        let mut session1 = Session::new(group_state1, None);
+        let mut session2 = Session::new(group_state2, None);
+        assert!(session2.history().is_none());
+        session1.set_history_logging(true);
+        session2.set_history_logging(true);
+
+        for _ in 0..3 {
+            let new_path_secret = PathSecret::new_from_random(session1.group_state().cs, &mut rng);
+            let handshake =
+                session1.create_and_apply_update_handshake(new_path_secret, &mut rng).unwrap();
+            session2.handle_handshake(handshake).unwrap();
+        }
+        let history = session2.history().unwrap();
+        assert_eq!(history.entries().len(), 3);
+        assert_eq!(history.entries(), session1.history().unwrap().entries());
+        let last_entry = history.entries().last().unwrap();
+        assert_eq!(
+            last_entry.transcript_hash.as_slice(),
+            session2.group_state().transcript_hash.as_bytes()
+        );
+
+        // The export is signed by member 2 and nobody else
+        let signed = history.export(session2.group_state()).unwrap();
+        let roster = &session2.group_state().roster.0;
+        signed.verify(roster[other_index.0 as usize].as_ref().unwrap()).unwrap();
+        let my_index = session1.group_state().roster_index.unwrap();
+        assert!(signed.verify(roster[my_index.0 as usize].as_ref().unwrap()).is_err());
+        let mut tampered = signed.clone();
+        tampered.entries.pop();
+        assert!(tampered.verify(roster[other_index.0 as usize].as_ref().unwrap()).is_err());
+
+        // Replaying from the checkpoint gets to the same place
+        let (replayed, app_key_chain) = replay(&checkpoint, &signed.entries).unwrap();
+        assert!(app_key_chain.is_some());
+        assert_serialized_eq!(replayed, *session2.group_state(), "Replay went somewhere else");
+
+        // A history with a gap in it can't be replayed
+        let mut gappy_entries = signed.entries.clone();
+        gappy_entries.remove(1);
+        assert!(replay(&checkpoint, &gappy_entries).is_err());
+    }
+}
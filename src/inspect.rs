@@ -0,0 +1,200 @@
+//! Read-only access to the path secrets and epoch secrets a `GroupState` derived, for comparing
+//! this library's key schedule against other implementations. This only exists with the
+//! `dangerous-debug` feature. Anything that can call these functions can read every secret of the
+//! group, so never enable the feature in a build that handles real traffic.
+
+use crate::{
+    crypto::{ciphersuite::CipherSuite, hmac::HmacKey},
+    error::Error,
+    group_state::GroupState,
+    ratchet_tree::{PathSecret, RatchetTree},
+    tree_math::NodeIndex,
+    utils,
+};
+
+/// The secrets a `GroupState` recorded while making its current epoch. This is what the
+/// functions in this module read from.
+#[derive(Clone, Default)]
+pub(crate) struct RecordedSecrets {
+    /// The path secrets of the handshake being applied, which become `path_secrets` once its epoch
+    /// secrets are derived
+    pending_path_secrets: Vec<(NodeIndex, PathSecret)>,
+    /// The path secrets this member learned in the handshake that made the current epoch
+    path_secrets: Vec<(NodeIndex, PathSecret)>,
+    /// The key schedule values of the current epoch, if this member derived them
+    epoch_secrets: Option<RecordedEpochSecrets>,
+}
+
+#[derive(Clone)]
+struct RecordedEpochSecrets {
+    update_secret: HmacKey,
+    epoch_secret: HmacKey,
+    application_secret: HmacKey,
+    confirmation_key: HmacKey,
+}
+
+impl RecordedSecrets {
+    /// Records the path secret of every node from `start_idx` up to the root of `tree`, where
+    /// `path_secret` is the path secret of `start_idx`. This has to be called before the path is
+    /// propagated through `tree`, since that can change its shape.
+    ///
+    /// Returns: `Ok(())` on success, and an `Error::ValidationError` if `path_secret` is the wrong
+    /// length
+    pub(crate) fn record_path(
+        &mut self,
+        cs: &'static CipherSuite,
+        tree: &RatchetTree,
+        start_idx: NodeIndex,
+        path_secret: &PathSecret,
+    ) -> Result<(), Error> {
+        let ctx = tree.math_ctx();
+        let root_idx = ctx.root();
+
+        let mut recorded = Vec::new();
+        let mut current_idx = start_idx;
+        let mut current_path_secret = path_secret.clone();
+        loop {
+            recorded.push((current_idx, current_path_secret.clone()));
+            if current_idx == root_idx {
+                break;
+            }
+            let (_, _, _, parent_path_secret) = utils::derive_node_values(cs, current_path_secret)?;
+            current_idx = ctx.parent(current_idx);
+            current_path_secret = parent_path_secret;
+        }
+
+        self.pending_path_secrets = recorded;
+        Ok(())
+    }
+
+    /// Records the key schedule values of a new epoch. The path secrets recorded since the last
+    /// epoch become this epoch's, so an epoch whose handshake had no path has none.
+    pub(crate) fn record_epoch(
+        &mut self,
+        update_secret: &[u8],
+        epoch_secret: &HmacKey,
+        application_secret: &HmacKey,
+        confirmation_key: &HmacKey,
+    ) {
+        self.path_secrets = core::mem::take(&mut self.pending_path_secrets);
+        self.epoch_secrets = Some(RecordedEpochSecrets {
+            update_secret: HmacKey::new_from_bytes(update_secret),
+            epoch_secret: epoch_secret.clone(),
+            application_secret: application_secret.clone(),
+            confirmation_key: confirmation_key.clone(),
+        });
+    }
+}
+
+/// The key schedule values of one epoch, as derived in `derive_epoch_secrets`
+#[derive(Clone, Copy, Debug)]
+pub struct EpochSecrets<'a> {
+    /// The root node secret of the epoch's handshake, or zeros if it had no path
+    pub update_secret: &'a [u8],
+    /// The intermediate secret the other three are derived from
+    pub epoch_secret: &'a [u8],
+    /// The secret the epoch's `ApplicationKeyChain` is made from
+    pub application_secret: &'a [u8],
+    /// The key the epoch's `Handshake` confirmation is computed under
+    pub confirmation_key: &'a [u8],
+    /// The init secret that the next epoch's key schedule starts from
+    pub init_secret: &'a [u8],
+}
+
+/// Returns the path secrets that `group_state`'s member learned from the handshake that made the
+/// current epoch, as `(node_index, path_secret)` pairs going up the tree from the first node the
+/// member could derive. This is empty if the handshake had no path, like an Add, or if
+/// `group_state` came from a `Welcome` and hasn't processed a handshake yet.
+pub fn path_secrets(group_state: &GroupState) -> Vec<(NodeIndex, &[u8])> {
+    group_state
+        .recorded_secrets
+        .path_secrets
+        .iter()
+        .map(|(idx, path_secret)| (*idx, path_secret.as_bytes()))
+        .collect()
+}
+
+/// Returns the key schedule values of `group_state`'s current epoch
+///
+/// Returns: `Some(epoch_secrets)` if `group_state`'s member derived the current epoch themselves.
+/// Returns `None` if `group_state` was just made, either as a new group or from a `Welcome`, since
+/// then the only secret it has is the init secret. See `init_secret`.
+pub fn epoch_secrets(group_state: &GroupState) -> Option<EpochSecrets<'_>> {
+    group_state.recorded_secrets.epoch_secrets.as_ref().map(|secrets| EpochSecrets {
+        update_secret: secrets.update_secret.as_bytes(),
+        epoch_secret: secrets.epoch_secret.as_bytes(),
+        application_secret: secrets.application_secret.as_bytes(),
+        confirmation_key: secrets.confirmation_key.as_bytes(),
+        init_secret: group_state.init_secret.as_bytes(),
+    })
+}
+
+/// Returns the init secret that `group_state`'s next epoch will be derived from
+pub fn init_secret(group_state: &GroupState) -> &[u8] {
+    group_state.init_secret.as_bytes()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        crypto::{ciphersuite::X25519_SHA256_AES128GCM, hkdf},
+        test_utils,
+    };
+
+    use quickcheck_macros::quickcheck;
+    use rand::SeedableRng;
+
+    // Has one member update and another process it, then checks that both recorded the same
+    // secrets, and that those secrets are the ones the key schedule says they should be
+    #[quickcheck]
+    fn inspect_correctness(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let cs = &X25519_SHA256_AES128GCM;
+
+        let (group_state1, identity_keys) = test_utils::random_full_group_state(2, &mut rng);
+        let my_idx = group_state1.roster_index.unwrap();
+        let other_idx = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[my_idx.0 as usize],
+            &mut rng,
+        );
+        let group_state2 = test_utils::change_self_index(&group_state1, &identity_keys, other_idx);
+        let prior_init_secret = init_secret(&group_state1).to_vec();
+        assert!(epoch_secrets(&group_state1).is_none());
+
+        let path_secret = PathSecret::new_from_random(cs, &mut rng);
+        let (update, group_state1, _) =
+            group_state1.create_and_apply_update_handshake(path_secret, &mut rng).unwrap();
+        let (group_state2, _) = group_state2.process_handshake(&update).unwrap();
+
+        // The updater knows its whole direct path, starting at its own leaf, and the other member
+        // knows the tail of it, starting where the two paths meet
+        let updater_path = path_secrets(&group_state1);
+        let receiver_path = path_secrets(&group_state2);
+        let root_idx = group_state1.tree.math_ctx().root();
+        let my_tree_idx = my_idx.node_index().unwrap();
+        assert_eq!(updater_path.first().unwrap().0, my_tree_idx);
+        assert_eq!(updater_path.last().unwrap().0, root_idx);
+        assert!(!receiver_path.is_empty());
+        assert!(updater_path.ends_with(&receiver_path));
+
+        // Both sides agree on the epoch, and it follows from the recorded values
+        let secrets1 = epoch_secrets(&group_state1).unwrap();
+        let secrets2 = epoch_secrets(&group_state2).unwrap();
+        assert_eq!(secrets1.epoch_secret, secrets2.epoch_secret);
+        assert_eq!(secrets1.init_secret, secrets2.init_secret);
+        let epoch_secret = hkdf::extract(
+            cs.hash_impl,
+            &HmacKey::new_from_bytes(&prior_init_secret),
+            secrets1.update_secret,
+        );
+        assert_eq!(epoch_secret.as_bytes(), secrets1.epoch_secret);
+
+        // The root's path secret gives the update secret
+        let (_, root_path_secret) = updater_path.last().unwrap();
+        let (_, _, root_node_secret, _) =
+            utils::derive_node_values(cs, PathSecret::new_from_bytes(root_path_secret)).unwrap();
+        assert_eq!(root_node_secret.0.as_bytes(), secrets1.update_secret);
+    }
+}
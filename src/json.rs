@@ -0,0 +1,387 @@
+//! Defines human-readable JSON renderings of protocol messages. These are meant for logging and
+//! debugging interop issues, not for the wire. Secret values never make it into the output:
+//! private keys are omitted entirely, and ciphertexts are rendered only by their length.
+//!
+//! This module is only available with the `json` feature enabled.
+
+use crate::{
+    credential::Credential,
+    crypto::ecies::EciesCiphertext,
+    error::Error,
+    extensions::Extension,
+    group_policy::JoinRule,
+    group_state::Welcome,
+    handshake::{DirectPathMessage, GroupOperation, Handshake, UserInitKey},
+};
+
+// The TLS-style Serialize impls on the protocol types are tied to the wire format (see the
+// __bound_u* naming hack in tls_ser), so we can't just hand them to serde_json. Instead, we make a
+// parallel set of "view" structs that borrow from the real thing and serialize nicely.
+
+/// A trait for protocol messages that have a human-readable JSON rendering
+pub trait ToJson {
+    /// Renders this message as pretty-printed JSON with all secret values redacted
+    ///
+    /// Returns: `Ok(json)` on success. If serialization fails, returns an `Error::SerdeError`.
+    fn to_json(&self) -> Result<String, Error>;
+}
+
+/// Serializes the given view struct to a pretty-printed JSON string
+fn render<T: serde::Serialize>(view: &T) -> Result<String, Error> {
+    serde_json::to_string_pretty(view).map_err(|e| Error::SerdeError(e.into()))
+}
+
+#[derive(Serialize)]
+struct EciesCiphertextView {
+    ephemeral_public_key: String,
+    // The payload is always encrypted path secrets or WelcomeInfos. We only show its size.
+    ciphertext_len: usize,
+}
+
+impl<'a> From<&'a EciesCiphertext> for EciesCiphertextView {
+    fn from(ct: &'a EciesCiphertext) -> EciesCiphertextView {
+        EciesCiphertextView {
+            ephemeral_public_key: hex::encode(ct.ephemeral_public_key.as_bytes()),
+            ciphertext_len: ct.ciphertext.len(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DirectPathNodeMessageView {
+    public_key: String,
+    node_secrets: Vec<EciesCiphertextView>,
+}
+
+#[derive(Serialize)]
+struct DirectPathMessageView {
+    node_messages: Vec<DirectPathNodeMessageView>,
+}
+
+impl<'a> From<&'a DirectPathMessage> for DirectPathMessageView {
+    fn from(msg: &'a DirectPathMessage) -> DirectPathMessageView {
+        let node_messages = msg
+            .node_messages
+            .iter()
+            .map(|node_msg| DirectPathNodeMessageView {
+                public_key: hex::encode(node_msg.public_key.as_bytes()),
+                node_secrets: node_msg.node_secrets.iter().map(EciesCiphertextView::from).collect(),
+            })
+            .collect();
+
+        DirectPathMessageView {
+            node_messages,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum CredentialView {
+    Basic {
+        identity: String,
+        signature_scheme: &'static str,
+        public_key: String,
+    },
+    X509 {
+        cert_data_len: usize,
+    },
+}
+
+impl<'a> From<&'a Credential> for CredentialView {
+    fn from(cred: &'a Credential) -> CredentialView {
+        match cred {
+            Credential::Basic(basic) => CredentialView::Basic {
+                identity: hex::encode(basic.identity.as_bytes()),
+                signature_scheme: basic.signature_scheme.name(),
+                public_key: hex::encode(basic.public_key.as_bytes()),
+            },
+            Credential::X509(cert) => CredentialView::X509 {
+                cert_data_len: cert.0.len(),
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ExtensionView {
+    extension_type: u16,
+    extension_data: String,
+}
+
+impl<'a> From<&'a Extension> for ExtensionView {
+    fn from(ext: &'a Extension) -> ExtensionView {
+        ExtensionView {
+            extension_type: ext.get_type().0,
+            extension_data: hex::encode(ext.get_data()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct UserInitKeyView {
+    user_init_key_id: String,
+    supported_versions: Vec<u8>,
+    cipher_suites: Vec<&'static str>,
+    init_keys: Vec<String>,
+    // The private keys themselves are never rendered. We only say whether we're holding them.
+    has_private_keys: bool,
+    credential: CredentialView,
+    extensions: Vec<ExtensionView>,
+    signature: String,
+}
+
+impl<'a> From<&'a UserInitKey> for UserInitKeyView {
+    fn from(uik: &'a UserInitKey) -> UserInitKeyView {
+        UserInitKeyView {
+            user_init_key_id: hex::encode(&uik.user_init_key_id),
+            supported_versions: uik.supported_versions.iter().map(|v| v.0).collect(),
+            cipher_suites: uik.cipher_suites.iter().map(|cs| cs.name).collect(),
+            init_keys: uik.init_keys.iter().map(|k| hex::encode(k.as_bytes())).collect(),
+            has_private_keys: uik.private_keys.is_some(),
+            credential: CredentialView::from(&uik.credential),
+            extensions: uik.extensions.iter().map(ExtensionView::from).collect(),
+            signature: hex::encode(uik.signature.as_bytes()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct WelcomeView {
+    user_init_key_id: String,
+    cipher_suite: &'static str,
+    encrypted_welcome_info: EciesCiphertextView,
+}
+
+impl<'a> From<&'a Welcome> for WelcomeView {
+    fn from(welcome: &'a Welcome) -> WelcomeView {
+        WelcomeView {
+            user_init_key_id: hex::encode(welcome.get_user_init_key_id()),
+            cipher_suite: welcome.cipher_suite.name,
+            encrypted_welcome_info: EciesCiphertextView::from(&welcome.encrypted_welcome_info),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum GroupOperationView {
+    Init {
+        protocol_version: u8,
+        group_id: String,
+        cipher_suite: &'static str,
+        roster: Vec<Option<CredentialView>>,
+        // One public key per node, or null for blank nodes
+        tree: Vec<Option<String>>,
+        extensions: Vec<ExtensionView>,
+    },
+    Add {
+        roster_index: u32,
+        init_key: UserInitKeyView,
+        welcome_info_hash: String,
+    },
+    Update {
+        path: DirectPathMessageView,
+    },
+    Remove {
+        removed_roster_index: u32,
+        path: DirectPathMessageView,
+    },
+    CredentialUpdate {
+        path: DirectPathMessageView,
+        new_credential: CredentialView,
+        credential_signature: String,
+    },
+    PolicyUpdate {
+        version: u32,
+        admins: Vec<String>,
+        join_rule: &'static str,
+        signer_index: u32,
+        signature: String,
+    },
+    BatchRemove {
+        removed_roster_indices: Vec<u32>,
+        path: DirectPathMessageView,
+    },
+    Replace {
+        roster_index: u32,
+        init_key: UserInitKeyView,
+        welcome_info_hash: String,
+        path: DirectPathMessageView,
+    },
+}
+
+impl<'a> From<&'a GroupOperation> for GroupOperationView {
+    fn from(op: &'a GroupOperation) -> GroupOperationView {
+        match op {
+            GroupOperation::Init(init) => GroupOperationView::Init {
+                protocol_version: init.protocol_version.0,
+                group_id: hex::encode(&init.group_id),
+                cipher_suite: init.cipher_suite.name,
+                roster: init
+                    .roster
+                    .0
+                    .iter()
+                    .map(|entry| entry.as_ref().map(CredentialView::from))
+                    .collect(),
+                tree: init
+                    .tree
+                    .nodes()
+                    .iter()
+                    .map(|node| node.get_public_key().map(|pk| hex::encode(pk.as_bytes())))
+                    .collect(),
+                extensions: init.extensions.iter().map(ExtensionView::from).collect(),
+            },
+            GroupOperation::Add(add) => GroupOperationView::Add {
+                roster_index: add.roster_index.0,
+                init_key: UserInitKeyView::from(&add.init_key),
+                welcome_info_hash: hex::encode(add.welcome_info_hash.as_bytes()),
+            },
+            GroupOperation::Update(update) => GroupOperationView::Update {
+                path: DirectPathMessageView::from(&update.path),
+            },
+            GroupOperation::Remove(remove) => GroupOperationView::Remove {
+                removed_roster_index: remove.removed_roster_index.0,
+                path: DirectPathMessageView::from(&remove.path),
+            },
+            GroupOperation::CredentialUpdate(cred_update) => GroupOperationView::CredentialUpdate {
+                path: DirectPathMessageView::from(&cred_update.path),
+                new_credential: CredentialView::from(&cred_update.new_credential),
+                credential_signature: hex::encode(cred_update.credential_signature.as_bytes()),
+            },
+            GroupOperation::PolicyUpdate(policy_update) => {
+                let signed_policy = &policy_update.signed_policy;
+                let policy = signed_policy.get_policy();
+                GroupOperationView::PolicyUpdate {
+                    version: policy.version,
+                    admins: policy
+                        .admins
+                        .iter()
+                        .map(|identity| hex::encode(identity.as_bytes()))
+                        .collect(),
+                    join_rule: match policy.join_rule {
+                        JoinRule::Open => "Open",
+                        JoinRule::AdminsOnly => "AdminsOnly",
+                    },
+                    signer_index: signed_policy.get_signer_index().0,
+                    signature: hex::encode(&signed_policy.signature),
+                }
+            }
+            GroupOperation::BatchRemove(batch_remove) => GroupOperationView::BatchRemove {
+                removed_roster_indices: batch_remove
+                    .removed_roster_indices
+                    .iter()
+                    .map(|idx| idx.0)
+                    .collect(),
+                path: DirectPathMessageView::from(&batch_remove.path),
+            },
+            GroupOperation::Replace(replace) => GroupOperationView::Replace {
+                roster_index: replace.add.roster_index.0,
+                init_key: UserInitKeyView::from(&replace.add.init_key),
+                welcome_info_hash: hex::encode(replace.add.welcome_info_hash.as_bytes()),
+                path: DirectPathMessageView::from(&replace.path),
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct HandshakeView {
+    group_id: String,
+    prior_epoch: u32,
+    operation: GroupOperationView,
+    signer_index: u32,
+    signature: String,
+    confirmation: String,
+}
+
+impl<'a> From<&'a Handshake> for HandshakeView {
+    fn from(handshake: &'a Handshake) -> HandshakeView {
+        HandshakeView {
+            group_id: hex::encode(&handshake.group_id),
+            prior_epoch: handshake.prior_epoch,
+            operation: GroupOperationView::from(&handshake.operation),
+            signer_index: handshake.signer_index.0,
+            signature: hex::encode(handshake.signature.as_bytes()),
+            confirmation: hex::encode(handshake.confirmation.as_bytes()),
+        }
+    }
+}
+
+impl ToJson for Handshake {
+    fn to_json(&self) -> Result<String, Error> {
+        render(&HandshakeView::from(self))
+    }
+}
+
+impl ToJson for Welcome {
+    fn to_json(&self) -> Result<String, Error> {
+        render(&WelcomeView::from(self))
+    }
+}
+
+impl ToJson for UserInitKey {
+    fn to_json(&self) -> Result<String, Error> {
+        render(&UserInitKeyView::from(self))
+    }
+}
+
+impl ToJson for DirectPathMessage {
+    fn to_json(&self) -> Result<String, Error> {
+        render(&DirectPathMessageView::from(self))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        crypto::ciphersuite::X25519_SHA256_AES128GCM, group_state::Welcome,
+        handshake::GroupOperation, json::ToJson, ratchet_tree::PathSecret, test_utils,
+    };
+
+    use quickcheck_macros::quickcheck;
+    use rand::SeedableRng;
+
+    // Checks that every renderable message produces valid JSON, and that none of the ciphertext
+    // payloads leak into the output
+    #[quickcheck]
+    fn json_rendering_redacts_secrets(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state, _) = test_utils::random_full_group_state(2, &mut rng);
+
+        // Make a UserInitKey. We're holding its private keys, so it's a good test of redaction.
+        let (init_key, _) =
+            test_utils::random_init_key(b"json_test", &X25519_SHA256_AES128GCM, &mut rng);
+        let (welcome, _) = Welcome::from_group_state(&group_state, &init_key, &mut rng).unwrap();
+
+        // Make an Update handshake so we have a DirectPathMessage to look at
+        let new_path_secret = PathSecret::new_from_random(group_state.cs, &mut rng);
+        let (handshake, _, _) =
+            group_state.create_and_apply_update_handshake(new_path_secret, &mut rng).unwrap();
+        let path = match handshake.operation {
+            GroupOperation::Update(ref update) => &update.path,
+            _ => unreachable!(),
+        };
+
+        let renderings = [
+            init_key.to_json().unwrap(),
+            welcome.to_json().unwrap(),
+            handshake.to_json().unwrap(),
+            path.to_json().unwrap(),
+        ];
+        for rendering in renderings.iter() {
+            // Every rendering should be valid JSON
+            serde_json::from_str::<serde_json::Value>(rendering).unwrap();
+        }
+
+        // The encrypted WelcomeInfo should only show up as a length
+        let welcome_ct = hex::encode(&welcome.encrypted_welcome_info.ciphertext);
+        assert!(!renderings[1].contains(&welcome_ct));
+        // Same with the encrypted path secrets
+        for node_msg in path.node_messages.iter() {
+            for ct in node_msg.node_secrets.iter() {
+                assert!(!renderings[3].contains(&hex::encode(&ct.ciphertext)));
+            }
+        }
+    }
+}
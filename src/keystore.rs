@@ -0,0 +1,258 @@
+//! Defines `KeyStore`, which holds a user's identity keys and init private keys on behalf of their
+//! groups. A group made with a `KeyStore` doesn't hold its identity key itself. It asks the store
+//! to sign for it, so one identity can back many groups and the key can live wherever the
+//! platform keeps keys. See `GroupState::new_singleton_group_with_keystore` and
+//! `GroupState::from_welcome_with_keystore`.
+
+use crate::{
+    credential::Credential,
+    crypto::sig::{SigSecretKey, Signature},
+    error::Error,
+    handshake::UserInitKey,
+};
+
+use std::sync::Arc;
+
+/// Somewhere a user's secret keys live. Implementors only hand out signatures and init private
+/// keys, never identity keys.
+pub trait KeyStore: Send + Sync + core::fmt::Debug {
+    /// Signs `msg` with the identity key of `credential`. The signature has to be in its scheme's
+    /// wire encoding, i.e., 64 bytes for Ed25519 and DER for ECDSA.
+    ///
+    /// Returns: `Ok(signature)` on success. Otherwise returns whatever error the implementation
+    /// sees fit, e.g., an `Error::SignatureError` if it has no key for `credential`.
+    fn sign(&self, credential: &Credential, msg: &[u8]) -> Result<Vec<u8>, Error>;
+
+    /// Returns the private keys of `init_key`, each as a DER-encoded PKCS#8 `PrivateKeyInfo`, in
+    /// the same order as its ciphersuites. See `UserInitKey::private_keys_to_pkcs8`.
+    ///
+    /// Returns: `Ok(keys)` on success. Otherwise returns whatever error the implementation sees
+    /// fit, e.g., an `Error::ValidationError` if it doesn't know `init_key`.
+    fn init_private_keys(&self, init_key: &UserInitKey) -> Result<Vec<Vec<u8>>, Error>;
+}
+
+/// A `KeyStore` that keeps everything in memory
+#[derive(Clone, Default)]
+pub struct MemoryKeyStore {
+    identity_keys: Vec<(Credential, SigSecretKey)>,
+    // These have their private keys
+    init_keys: Vec<UserInitKey>,
+}
+
+impl core::fmt::Debug for MemoryKeyStore {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        // Say how much is in here, but nothing about what
+        f.debug_struct("MemoryKeyStore")
+            .field("identity_keys", &self.identity_keys.len())
+            .field("init_keys", &self.init_keys.len())
+            .finish()
+    }
+}
+
+impl MemoryKeyStore {
+    /// Makes an empty key store
+    pub fn new() -> MemoryKeyStore {
+        MemoryKeyStore::default()
+    }
+
+    /// Puts the identity key of `credential` in this store
+    ///
+    /// Returns: `Ok(())` on success, and an `Error::SignatureError` if `identity_key` isn't the
+    /// key of `credential`
+    pub fn add_identity_key(
+        &mut self,
+        credential: Credential,
+        identity_key: SigSecretKey,
+    ) -> Result<(), Error> {
+        credential.check_identity_key(&identity_key)?;
+        self.identity_keys.push((credential, identity_key));
+        Ok(())
+    }
+
+    /// Puts the private keys of `init_key` in this store. `init_key` has to be the copy that was
+    /// made by `UserInitKey::new_from_random`, since the published copy has no private keys.
+    ///
+    /// Returns: `Ok(())` on success. Returns an `Error::ValidationError` if `init_key` has no
+    /// private keys, and an `Error::DuplicateInitKeyId` if this store already has a `UserInitKey`
+    /// with the same ID.
+    pub fn add_init_key(&mut self, init_key: UserInitKey) -> Result<(), Error> {
+        if init_key.private_keys.is_none() {
+            return Err(Error::ValidationError("UserInitKey has no private keys to store"));
+        }
+        // Private keys are looked up by ID, so two keys with one ID would be indistinguishable
+        let user_init_key_id = &init_key.user_init_key_id;
+        if self.init_keys.iter().any(|stored| &stored.user_init_key_id == user_init_key_id) {
+            return Err(Error::DuplicateInitKeyId(user_init_key_id.clone()));
+        }
+        self.init_keys.push(init_key);
+        Ok(())
+    }
+}
+
+impl KeyStore for MemoryKeyStore {
+    fn sign(&self, credential: &Credential, msg: &[u8]) -> Result<Vec<u8>, Error> {
+        let (_, identity_key) = self
+            .identity_keys
+            .iter()
+            .find(|(cred, _)| cred == credential)
+            .ok_or(Error::SignatureError("KeyStore has no identity key for this credential"))?;
+        let ss = credential.get_signature_scheme();
+        Ok(ss.sign(identity_key, msg).as_bytes())
+    }
+
+    fn init_private_keys(&self, init_key: &UserInitKey) -> Result<Vec<Vec<u8>>, Error> {
+        self.init_keys
+            .iter()
+            .find(|stored| stored.user_init_key_id == init_key.user_init_key_id)
+            .and_then(UserInitKey::private_keys_to_pkcs8)
+            .ok_or(Error::ValidationError("KeyStore has no private keys for this UserInitKey"))
+    }
+}
+
+/// Where a `GroupState`'s identity key is
+#[derive(Clone)]
+pub(crate) enum IdentityKey {
+    /// The `GroupState` holds the key itself
+    Local(SigSecretKey),
+    /// The key is in a `KeyStore`, which signs on the `GroupState`'s behalf
+    Store(Arc<dyn KeyStore>),
+}
+
+impl IdentityKey {
+    /// Signs `msg` as `credential`, which is the credential this key belongs to
+    ///
+    /// Returns: `Ok(signature)` on success. Returns an `Error::SignatureError` if a `KeyStore`'s
+    /// signature is malformed or doesn't verify under `credential`. Otherwise returns whatever the
+    /// `KeyStore` returns.
+    pub(crate) fn sign(&self, credential: &Credential, msg: &[u8]) -> Result<Signature, Error> {
+        let ss = credential.get_signature_scheme();
+        match self {
+            IdentityKey::Local(identity_key) => Ok(ss.sign(identity_key, msg)),
+            IdentityKey::Store(keystore) => {
+                let signature = Signature::new_from_bytes(ss, &keystore.sign(credential, msg)?)?;
+                // A store that signs with the wrong key would get this member's messages dropped
+                // by everyone else, so catch it here
+                credential.verify(msg, &signature)?;
+                Ok(signature)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        application,
+        config::GroupConfig,
+        credential::RosterIndex,
+        crypto::ciphersuite::X25519_SHA256_AES128GCM,
+        group_state::{GroupState, Welcome},
+        ratchet_tree::PathSecret,
+        test_utils,
+    };
+
+    use quickcheck_macros::quickcheck;
+    use rand::SeedableRng;
+
+    // Has Alice make two groups out of one KeyStore and add Bob to one of them, with Bob's keys in
+    // a KeyStore too. Then checks that everyone can sign and talk, and that a store without the
+    // right key can't sign for anyone.
+    #[quickcheck]
+    fn keystore_correctness(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let cs = &X25519_SHA256_AES128GCM;
+
+        let (alice_credential, alice_identity_key) = test_utils::random_basic_credential(&mut rng);
+        let mut alice_store = MemoryKeyStore::new();
+        alice_store.add_identity_key(alice_credential.clone(), alice_identity_key).unwrap();
+        let alice_store: Arc<dyn KeyStore> = Arc::new(alice_store);
+
+        // Bob keeps his identity key and the private half of his UserInitKey in his store, and
+        // joins with the published half
+        let (bob_credential, bob_identity_key) = test_utils::random_basic_credential(&mut rng);
+        let bob_init_key = test_utils::make_init_key(
+            &bob_identity_key,
+            bob_credential.clone(),
+            b"bob's key",
+            cs,
+            &mut rng,
+        );
+        let mut bob_store = MemoryKeyStore::new();
+        bob_store.add_identity_key(bob_credential, bob_identity_key).unwrap();
+        bob_store.add_init_key(bob_init_key.clone()).unwrap();
+        // Bob can't store another key under the same ID
+        let (duplicate_init_key, _) = test_utils::random_init_key(b"bob's key", cs, &mut rng);
+        match bob_store.add_init_key(duplicate_init_key) {
+            Err(Error::DuplicateInitKeyId(id)) => assert_eq!(id, b"bob's key"),
+            Err(e) => panic!("duplicate init key ID gave the wrong error: {}", e),
+            Ok(_) => panic!("stored two init keys with the same ID"),
+        }
+        let bob_store: Arc<dyn KeyStore> = Arc::new(bob_store);
+        let mut bob_public_init_key = bob_init_key;
+        bob_public_init_key.private_keys = None;
+
+        // One identity backs two groups
+        let alice_state = GroupState::new_singleton_group_with_keystore(
+            alice_store.clone(),
+            b"first group".to_vec(),
+            alice_credential.clone(),
+            GroupConfig::new(cs),
+            &mut rng,
+        )
+        .unwrap();
+        let other_state = GroupState::new_singleton_group_with_keystore(
+            alice_store.clone(),
+            b"second group".to_vec(),
+            alice_credential.clone(),
+            GroupConfig::new(cs),
+            &mut rng,
+        )
+        .unwrap();
+        let path_secret = PathSecret::new_from_random(cs, &mut rng);
+        other_state.create_and_apply_update_handshake(path_secret, &mut rng).unwrap();
+
+        let (welcome, welcome_info_hash) =
+            Welcome::from_group_state(&alice_state, &bob_public_init_key, &mut rng).unwrap();
+        let (add, alice_state, _) = alice_state
+            .create_and_apply_add_handshake(
+                RosterIndex(1),
+                bob_public_init_key.clone(),
+                &welcome_info_hash,
+            )
+            .unwrap();
+        let bob_state =
+            GroupState::from_welcome_with_keystore(welcome, bob_store, bob_public_init_key)
+                .unwrap();
+        let (bob_state, _) = bob_state.process_handshake(&add).unwrap();
+
+        // Bob signs an Update and an application message through his store
+        let path_secret = PathSecret::new_from_random(cs, &mut rng);
+        let (update, bob_state, mut bob_chain) =
+            bob_state.create_and_apply_update_handshake(path_secret, &mut rng).unwrap();
+        let (alice_state, mut alice_chain) = alice_state.process_handshake(&update).unwrap();
+        let app_message = application::encrypt_application_message(
+            b"hi alice".to_vec(),
+            &bob_state,
+            &mut bob_chain,
+        )
+        .unwrap();
+        let decrypted =
+            application::decrypt_application_message(app_message, &alice_state, &mut alice_chain)
+                .unwrap();
+        assert_eq!(decrypted.plaintext, b"hi alice");
+
+        // A store that doesn't have the key can't sign for the credential
+        let empty_store: Arc<dyn KeyStore> = Arc::new(MemoryKeyStore::new());
+        let lost_state = GroupState::new_singleton_group_with_keystore(
+            empty_store,
+            b"third group".to_vec(),
+            alice_credential,
+            GroupConfig::new(cs),
+            &mut rng,
+        )
+        .unwrap();
+        let path_secret = PathSecret::new_from_random(cs, &mut rng);
+        assert!(lost_state.create_and_apply_update_handshake(path_secret, &mut rng).is_err());
+    }
+}
@@ -3,7 +3,7 @@
 
 // This is because ratchet trees use 32-bit indices, which means Vecs need to be able to store up
 // to 2^32 - 1 many elements
-#[cfg(any(target_pointer_width = "16", target_pointer_width = "8"))]
+#[cfg(target_pointer_width = "16")]
 compile_error!("Molasses requires that the architecture's pointer width be at least 32 bits");
 
 // Can't make this work using edition 2018 syntax yet
@@ -19,14 +19,46 @@ mod utils;
 mod test_utils;
 
 pub mod application;
+pub mod archive;
+pub mod authorization;
+#[cfg(feature = "bench")]
+pub mod bench_utils;
+pub mod client;
+pub mod clock;
 mod codec;
+pub mod config;
 pub mod credential;
 pub mod crypto;
+pub mod delivery;
+pub mod diff;
+pub mod directory;
 pub mod error;
+pub mod extensions;
+pub mod group_policy;
 pub mod group_state;
 pub mod handshake;
+pub mod history;
+#[cfg(feature = "dangerous-debug")]
+pub mod inspect;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod keystore;
+pub mod metrics;
+pub mod migration;
+pub mod observer;
+pub mod public_state;
 pub mod ratchet_tree;
+pub mod resync;
+#[cfg(feature = "rfc9420")]
+pub mod rfc9420;
+pub mod session;
+pub mod shared;
+#[cfg(test)]
+mod simulation;
+#[cfg(feature = "gen-test-vectors")]
+pub mod test_vectors;
 pub mod tls_de;
 pub mod tls_ser;
-mod tree_math;
+pub mod tree_math;
 pub mod upcast;
+pub mod verify;
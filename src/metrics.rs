@@ -0,0 +1,196 @@
+//! Defines the `Metrics` trait, through which applications can observe what the protocol is doing
+//! and feed it into their telemetry systems. Install an implementation with `set_metrics`.
+
+use crate::{
+    credential::{Credential, RosterIndex},
+    error::Error,
+};
+
+use std::sync::OnceLock;
+
+/// The kind of operation a `Handshake` carries
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OperationKind {
+    Init,
+    Add,
+    Update,
+    Remove,
+    CredentialUpdate,
+    PolicyUpdate,
+    BatchRemove,
+    Replace,
+}
+
+/// A set of callbacks that get invoked as protocol operations happen. Every method has a no-op
+/// default, so implementors only need to override the ones they care about.
+///
+/// Callbacks are invoked synchronously on whatever thread is doing the operation, so they should
+/// be cheap. None of them are given any secret values.
+pub trait Metrics: Send + Sync {
+    /// Called when a `Handshake` from another member has been successfully processed.
+    /// `prior_epoch` is the epoch the `Handshake` was sent in.
+    fn handshake_processed(&self, _group_id: &[u8], _op: OperationKind, _prior_epoch: u32) {}
+
+    /// Called when a `Handshake` from another member has replaced that member's credential.
+    /// `roster_index` is the member's position in the roster, and `new_credential` is what's
+    /// there now.
+    fn credential_updated(
+        &self,
+        _group_id: &[u8],
+        _roster_index: RosterIndex,
+        _new_credential: &Credential,
+    ) {
+    }
+
+    /// Called whenever a group moves to a new epoch, whether because we processed someone else's
+    /// `Handshake` or because we created and applied our own
+    fn epoch_advanced(&self, _group_id: &[u8], _new_epoch: u32) {}
+
+    /// Called along with `epoch_advanced`. `num_leaves` is the number of leaves in the ratchet
+    /// tree, including blank ones.
+    fn tree_size(&self, _group_id: &[u8], _num_leaves: usize) {}
+
+    /// Called when an application message has been encrypted. `plaintext_len` is the size of the
+    /// message content, and `ciphertext_len` is the size of the encrypted content, i.e., the
+    /// content plus its signature, length prefixes, and AEAD tag.
+    fn message_encrypted(&self, _group_id: &[u8], _plaintext_len: usize, _ciphertext_len: usize) {}
+
+    /// Called when an application message has been successfully decrypted. The lengths are as in
+    /// `message_encrypted`.
+    fn message_decrypted(&self, _group_id: &[u8], _plaintext_len: usize, _ciphertext_len: usize) {}
+}
+
+// The installed Metrics implementation. This is global for the same reason loggers are: threading
+// a handle through every GroupState and key chain would clutter every API for something most
+// callers don't use.
+static METRICS: OnceLock<Box<dyn Metrics>> = OnceLock::new();
+
+/// Installs the given `Metrics` implementation for the rest of the life of the program. This can
+/// only be done once.
+///
+/// Returns: `Ok(())` on success. Returns an `Error::ValidationError` if a `Metrics`
+/// implementation has already been installed.
+pub fn set_metrics(metrics: Box<dyn Metrics>) -> Result<(), Error> {
+    METRICS.set(metrics).map_err(|_| Error::ValidationError("Metrics have already been set"))
+}
+
+/// Runs `f` on the installed `Metrics` implementation, if there is one
+pub(crate) fn report<F: FnOnce(&dyn Metrics)>(f: F) {
+    if let Some(metrics) = METRICS.get() {
+        f(metrics.as_ref());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        application::{decrypt_application_message, encrypt_application_message},
+        metrics::{self, Metrics, OperationKind},
+        ratchet_tree::PathSecret,
+        test_utils,
+    };
+
+    use std::sync::Mutex;
+
+    use quickcheck_macros::quickcheck;
+    use rand::SeedableRng;
+
+    // Everything our Metrics implementation has seen, per group ID. Tests run in parallel and
+    // share the installed Metrics, so each test only looks at its own group.
+    struct Recorder {
+        events: Mutex<Vec<(Vec<u8>, String)>>,
+    }
+
+    impl Recorder {
+        fn record(&self, group_id: &[u8], event: String) {
+            self.events.lock().unwrap().push((group_id.to_vec(), event));
+        }
+
+        fn events_for(&self, group_id: &[u8]) -> Vec<String> {
+            let events = self.events.lock().unwrap();
+            events.iter().filter(|(id, _)| id == group_id).map(|(_, e)| e.clone()).collect()
+        }
+    }
+
+    // The one and only Recorder. It has to be 'static to be installed.
+    static RECORDER: Recorder = Recorder {
+        events: Mutex::new(Vec::new()),
+    };
+
+    // set_metrics wants a 'static owned value. A reference to a static is exactly that.
+    impl Metrics for &'static Recorder {
+        fn handshake_processed(&self, group_id: &[u8], op: OperationKind, prior_epoch: u32) {
+            self.record(group_id, format!("handshake {:?} {}", op, prior_epoch));
+        }
+
+        fn epoch_advanced(&self, group_id: &[u8], new_epoch: u32) {
+            self.record(group_id, format!("epoch {}", new_epoch));
+        }
+
+        fn tree_size(&self, group_id: &[u8], num_leaves: usize) {
+            self.record(group_id, format!("tree {}", num_leaves));
+        }
+
+        fn message_encrypted(&self, group_id: &[u8], plaintext_len: usize, ciphertext_len: usize) {
+            assert!(ciphertext_len > plaintext_len);
+            self.record(group_id, format!("encrypted {}", plaintext_len));
+        }
+
+        fn message_decrypted(&self, group_id: &[u8], plaintext_len: usize, ciphertext_len: usize) {
+            assert!(ciphertext_len > plaintext_len);
+            self.record(group_id, format!("decrypted {}", plaintext_len));
+        }
+    }
+
+    // Does an Update between two members, then sends a message, and checks that every step was
+    // reported exactly once and in order
+    #[quickcheck]
+    fn metrics_are_reported(rng_seed: u64) {
+        // This is the only test that installs metrics, but quickcheck runs it many times
+        let _ = metrics::set_metrics(Box::new(&RECORDER));
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (mut group_state1, identity_keys) = test_utils::random_full_group_state(2, &mut rng);
+        // Group IDs are random, but quickcheck can repeat seeds. Make ours unique to this run.
+        group_state1.group_id.extend_from_slice(&rand::random::<[u8; 16]>());
+
+        let other_index = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[group_state1.roster_index.unwrap().0 as usize],
+            &mut rng,
+        );
+        let group_state2 =
+            test_utils::change_self_index(&group_state1, &identity_keys, other_index);
+        let group_id = group_state1.group_id.clone();
+        let prior_epoch = group_state1.epoch;
+        let num_leaves = group_state1.roster.len();
+
+        // Member 1 updates and member 2 processes the update
+        let new_path_secret = PathSecret::new_from_random(group_state1.cs, &mut rng);
+        let (handshake, group_state1, mut app_key_chain1) =
+            group_state1.create_and_apply_update_handshake(new_path_secret, &mut rng).unwrap();
+        let (group_state2, mut app_key_chain2) =
+            group_state2.process_handshake(&handshake).unwrap();
+
+        // Member 1 sends a message to member 2
+        let app_message =
+            encrypt_application_message(b"hello".to_vec(), &group_state1, &mut app_key_chain1)
+                .unwrap();
+        decrypt_application_message(app_message, &group_state2, &mut app_key_chain2).unwrap();
+
+        let new_epoch = prior_epoch + 1;
+        let expected = vec![
+            // From member 1 applying its own Update
+            format!("epoch {}", new_epoch),
+            format!("tree {}", num_leaves),
+            // From member 2 processing the Update
+            format!("handshake {:?} {}", OperationKind::Update, prior_epoch),
+            format!("epoch {}", new_epoch),
+            format!("tree {}", num_leaves),
+            // From the message
+            String::from("encrypted 5"),
+            String::from("decrypted 5"),
+        ];
+        assert_eq!(RECORDER.events_for(&group_id), expected);
+    }
+}
@@ -0,0 +1,278 @@
+//! Moving a group to a different ciphersuite. A group's ciphersuite is fixed when it's created, so
+//! the only way off of a deprecated one is to make a new group under the new ciphersuite and bring
+//! everyone over. `migrate_group` does that in one go, and marks the new group with a
+//! `PredecessorGroup` extension so that the members being brought over can check where it came
+//! from with `verify_predecessor`.
+
+use crate::{
+    application::ApplicationKeyChain,
+    config::GroupConfig,
+    credential::Identity,
+    crypto::{ciphersuite::CipherSuite, rng::CryptoRng},
+    directory::{self, UserInitKeyDirectory},
+    error::Error,
+    extensions::{self, ExtensionType, KnownExtension},
+    group_state::{GroupState, Welcome},
+    handshake::{Handshake, UserInitKey},
+};
+
+/// A group extension that links a group to the group it replaced. It pins down the old group's
+/// state at the time of the migration, so members can tell that the new group was made from the
+/// group they're actually in.
+// struct {
+//     opaque group_id<0..255>;
+//     uint32 epoch;
+//     opaque transcript_hash<0..255>;
+// } PredecessorGroup;
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PredecessorGroup {
+    /// The ID of the old group
+    #[serde(rename = "group_id__bound_u8")]
+    pub group_id: Vec<u8>,
+    /// The epoch the old group was in when the new group was made
+    pub epoch: u32,
+    /// The old group's transcript hash in that epoch
+    #[serde(rename = "transcript_hash__bound_u8")]
+    pub transcript_hash: Vec<u8>,
+}
+
+impl KnownExtension for PredecessorGroup {
+    const EXTENSION_TYPE: ExtensionType = extensions::PREDECESSOR_GROUP;
+}
+
+/// Everything that comes out of `migrate_group`
+pub struct Migration {
+    /// The new group, from the perspective of the member who made it
+    pub group_state: GroupState,
+    /// The new group's application key schedule object
+    pub app_key_chain: ApplicationKeyChain,
+    /// A `Welcome` for every member who was brought over, paired with the ID of the `UserInitKey`
+    /// it's encrypted to
+    pub welcomes: Vec<(Vec<u8>, Welcome)>,
+    /// The `Add`s that bring everyone into the new group, in the order they must be processed.
+    /// Every member who was brought over processes all of these, starting from the first. See
+    /// `GroupState::create_and_apply_add_handshakes`.
+    pub handshakes: Vec<Handshake>,
+}
+
+/// Re-creates `old_group` under the ciphersuite `new_cs`, with the group ID `new_group_id`. The new
+/// group has the same members, extensions, and protocol version as the old one, plus a
+/// `PredecessorGroup` extension pointing at the old one. This member makes the group, so they keep
+/// their credential and identity key. Everyone else is brought over with a fresh `UserInitKey`
+/// for `new_cs` from `directory`, which is checked like in `Session::add_member_by_identity`.
+/// Members are looked up by identity, so roster entries that share an identity with each other or
+/// with this member are only brought over once.
+///
+/// The old group is left alone. It's up to the application to tell the old group about the new
+/// one and to stop using the old one once everyone has moved.
+///
+/// Returns: `Ok(migration)` on success. Returns an `Error::ValidationError` if `old_group` is
+/// preliminary or has nobody else in it, or if the directory returns someone else's key. Returns
+/// an `Error::NoCompatibleInitKey` if someone's key doesn't support `new_cs`. Otherwise returns
+/// whatever the directory or `GroupState::create_and_apply_add_handshakes` returns.
+pub fn migrate_group<D, R>(
+    old_group: &GroupState,
+    new_cs: &'static CipherSuite,
+    new_group_id: Vec<u8>,
+    directory: &D,
+    csprng: &mut R,
+) -> Result<Migration, Error>
+where
+    D: UserInitKeyDirectory + ?Sized,
+    R: CryptoRng,
+{
+    let my_roster_index = old_group
+        .roster_index
+        .ok_or(Error::ValidationError("Cannot migrate from a preliminary GroupState"))?;
+    // A non-preliminary group always has our own roster entry filled, so this never fails in
+    // practice
+    let my_credential = old_group
+        .roster
+        .0
+        .get(my_roster_index.0 as usize)
+        .and_then(Option::as_ref)
+        .ok_or(Error::ValidationError("Roster has no entry for this member"))?;
+
+    // The new group starts as everything the old group agreed on, plus a link back to it
+    let mut extensions = old_group.extensions.clone();
+    extensions.insert(&PredecessorGroup {
+        group_id: old_group.group_id.clone(),
+        epoch: old_group.epoch,
+        transcript_hash: old_group.transcript_hash.as_bytes().to_vec(),
+    })?;
+    // The identity key goes along wherever it is, so a group backed by a KeyStore stays that way
+    let config = GroupConfig::new(new_cs)
+        .set_protocol_version(old_group.protocol_version)
+        .set_extensions(extensions);
+    let new_group = GroupState::new_singleton_group_with_identity_key(
+        old_group.identity_key.clone(),
+        new_group_id,
+        my_credential.clone(),
+        config,
+        csprng,
+    )?;
+
+    // Fetch a fresh key for everyone else. This is checked against the new group, so that the
+    // keys are for the new ciphersuite.
+    let mut seen_identities: Vec<&Identity> = vec![my_credential.get_identity()];
+    let mut init_keys: Vec<UserInitKey> = Vec::new();
+    for cred in old_group.roster.credential_iter() {
+        let identity = cred.get_identity();
+        if seen_identities.contains(&identity) {
+            continue;
+        }
+        seen_identities.push(identity);
+
+        let (_, init_key) = directory::fetch_init_key_for_add(directory, identity, &new_group)?;
+        init_keys.push(init_key);
+    }
+    if init_keys.is_empty() {
+        return Err(Error::ValidationError("There's nobody else to bring to the new group"));
+    }
+
+    let (welcomes, handshakes, group_state, app_key_chain) =
+        new_group.create_and_apply_add_handshakes(init_keys, csprng)?;
+
+    Ok(Migration {
+        group_state,
+        app_key_chain,
+        welcomes,
+        handshakes,
+    })
+}
+
+/// Checks that `new_group` was made by `migrate_group` from `old_group` in its current epoch. This
+/// is meant to be called by a member who was brought over, right after they've processed the
+/// migration's `Add`s.
+///
+/// Returns: `Ok(())` if `new_group`'s `PredecessorGroup` extension describes `old_group`, and the
+/// member who made `new_group` has the same credential in `old_group`. Returns an
+/// `Error::ValidationError` otherwise, and an `Error::SerdeError` if the extension is malformed.
+pub fn verify_predecessor(new_group: &GroupState, old_group: &GroupState) -> Result<(), Error> {
+    let predecessor = new_group
+        .get_extensions()
+        .get::<PredecessorGroup>()?
+        .ok_or(Error::ValidationError("Group has no PredecessorGroup extension"))?;
+    if predecessor.group_id != old_group.group_id
+        || predecessor.epoch != old_group.epoch
+        || predecessor.transcript_hash != old_group.transcript_hash.as_bytes()
+    {
+        return Err(Error::ValidationError("Group's predecessor is a different group"));
+    }
+
+    // The member who made the new group starts out at roster index 0. They signed the Adds we
+    // just processed, so if they're in the old group, the new group really came from in there.
+    let creator = new_group
+        .roster
+        .0
+        .first()
+        .and_then(Option::as_ref)
+        .ok_or(Error::ValidationError("Group's creator is no longer in it"))?;
+    if !old_group.roster.credential_iter().any(|cred| cred == creator) {
+        return Err(Error::ValidationError("Group's creator isn't in its predecessor"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        credential::Credential,
+        crypto::{
+            ciphersuite::X25519_SHA256_AES128GCM,
+            sig::{SigSecretKey, ED25519_IMPL},
+        },
+        test_utils,
+    };
+
+    use quickcheck_macros::quickcheck;
+    use rand::SeedableRng;
+
+    // A directory that's just a list of published keys
+    struct ListDirectory(Vec<(Identity, UserInitKey)>);
+
+    impl UserInitKeyDirectory for ListDirectory {
+        fn fetch_init_key(&self, identity: &Identity) -> Result<UserInitKey, Error> {
+            self.0
+                .iter()
+                .find(|(id, _)| id == identity)
+                .map(|(_, init_key)| init_key.clone())
+                .ok_or(Error::ValidationError("No such user in directory"))
+        }
+    }
+
+    // Migrates a group and checks that the members who were brought over end up in the same state
+    // as the migrator, and can tell where the new group came from
+    #[quickcheck]
+    fn migration_correctness(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        // X25519_SHA256_AES128GCM is the only ciphersuite we can make keys for, so we "migrate" to
+        // the same one. Nothing in the migration depends on the two being different.
+        let (old_group, _) = test_utils::random_full_group_state(2, &mut rng);
+        let new_cs = &X25519_SHA256_AES128GCM;
+        let my_idx = old_group.roster_index.unwrap();
+
+        // Everyone else publishes a fresh UserInitKey under their old identity. The IDs are
+        // distinct so that the Welcomes can be told apart.
+        let mut published = Vec::new();
+        let mut secrets: Vec<(UserInitKey, SigSecretKey)> = Vec::new();
+        for (i, cred) in old_group.roster.credential_iter().enumerate() {
+            if i as u32 == my_idx.0 {
+                continue;
+            }
+            let identity = cred.get_identity().clone();
+            let (new_cred, new_identity_key): (Credential, SigSecretKey) =
+                Credential::new_basic_from_random(identity.clone(), &ED25519_IMPL, &mut rng)
+                    .unwrap();
+            let init_key = test_utils::make_init_key(
+                &new_identity_key,
+                new_cred,
+                &(i as u32).to_be_bytes(),
+                new_cs,
+                &mut rng,
+            );
+            published.push((identity, init_key.clone()));
+            secrets.push((init_key, new_identity_key));
+        }
+        let directory = ListDirectory(published);
+
+        let new_group_id = b"migrated".to_vec();
+        let Migration {
+            group_state: new_group,
+            welcomes,
+            handshakes,
+            ..
+        } = migrate_group(&old_group, new_cs, new_group_id.clone(), &directory, &mut rng).unwrap();
+        assert_eq!(new_group.get_group_id(), new_group_id.as_slice());
+        assert_eq!(new_group.get_member_count(), old_group.get_member_count());
+        verify_predecessor(&new_group, &old_group).unwrap();
+
+        // Bringing everyone in takes a handshake per member, so only check the first and last
+        // members to be brought over
+        let mut welcomes = welcomes;
+        let checked = if secrets.len() > 1 {
+            vec![secrets.remove(0), secrets.pop().unwrap()]
+        } else {
+            secrets
+        };
+        for (init_key, identity_key) in checked {
+            let pos = welcomes.iter().position(|(id, _)| id == &init_key.user_init_key_id).unwrap();
+            let (_, welcome) = welcomes.swap_remove(pos);
+            let mut joined = GroupState::from_welcome(welcome, identity_key, init_key).unwrap();
+            for handshake in handshakes.iter() {
+                joined = joined.process_handshake(handshake).unwrap().0;
+            }
+            assert_serialized_eq!(new_group, joined, "Migrated member disagrees");
+            verify_predecessor(&joined, &old_group).unwrap();
+        }
+
+        // The link is to the old group in the epoch it was migrated from, and nothing else
+        let mut other_epoch = old_group.clone();
+        other_epoch.epoch = other_epoch.epoch.wrapping_add(1);
+        assert!(verify_predecessor(&new_group, &other_epoch).is_err());
+        let (unrelated_group, _) = test_utils::random_full_group_state(2, &mut rng);
+        assert!(verify_predecessor(&new_group, &unrelated_group).is_err());
+    }
+}
@@ -0,0 +1,231 @@
+//! Defines `GroupObserver`, which is how an application finds out what a `Handshake` did to its
+//! group without diffing rosters itself. See `GroupState::process_handshake_with_observer` and
+//! `Session::set_observer`.
+
+use crate::credential::{Credential, RosterIndex};
+
+/// Something that wants to be told about changes to a group, e.g., a UI. Every method does nothing
+/// by default, so implementors only need to override the ones they care about. Members are
+/// referred to by roster index.
+///
+/// Observers are only told about `Handshake`s from other members. The member who makes a
+/// `Handshake` already knows what it does.
+pub trait GroupObserver: Send {
+    /// Called when `credential` is added to the group at `roster_index`
+    fn on_member_added(
+        &mut self,
+        _group_id: &[u8],
+        _roster_index: RosterIndex,
+        _credential: &Credential,
+    ) {
+    }
+
+    /// Called when the member at `roster_index`, whose credential was `credential`, is removed from
+    /// the group
+    fn on_member_removed(
+        &mut self,
+        _group_id: &[u8],
+        _roster_index: RosterIndex,
+        _credential: &Credential,
+    ) {
+    }
+
+    /// Called when the group moves to `new_epoch`. This happens after every other notification
+    /// about the same `Handshake`.
+    fn on_epoch_advanced(&mut self, _group_id: &[u8], _new_epoch: u32) {}
+
+    /// Called when this member is removed from the group by the member at `removed_by`. No other
+    /// notification follows this one, since a removed member can't follow the group any further.
+    fn on_self_removed(&mut self, _group_id: &[u8], _removed_by: RosterIndex) {}
+
+    /// Called when the member at `roster_index` replaces their credential `old_credential` with
+    /// `new_credential`
+    fn on_credential_changed(
+        &mut self,
+        _group_id: &[u8],
+        _roster_index: RosterIndex,
+        _old_credential: &Credential,
+        _new_credential: &Credential,
+    ) {
+    }
+}
+
+/// The observer that ignores everything. This is what `GroupState::process_handshake` uses.
+impl GroupObserver for () {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        crypto::{
+            ciphersuite::X25519_SHA256_AES128GCM,
+            sig::{SigSecretKey, ED25519_IMPL},
+        },
+        error::Error,
+        ratchet_tree::PathSecret,
+        test_utils,
+    };
+
+    use quickcheck_macros::quickcheck;
+    use rand::SeedableRng;
+
+    // These only ever sit in a test's log, so the size difference doesn't matter
+    #[allow(clippy::large_enum_variant)]
+    #[derive(Debug, PartialEq)]
+    enum Event {
+        Added(RosterIndex, Credential),
+        Removed(RosterIndex, Credential),
+        EpochAdvanced(u32),
+        SelfRemoved(RosterIndex),
+        CredentialChanged(RosterIndex, Credential, Credential),
+    }
+
+    // An observer that writes down everything it's told
+    #[derive(Default)]
+    struct Recorder(Vec<Event>);
+
+    impl GroupObserver for Recorder {
+        fn on_member_added(
+            &mut self,
+            _: &[u8],
+            roster_index: RosterIndex,
+            credential: &Credential,
+        ) {
+            self.0.push(Event::Added(roster_index, credential.clone()));
+        }
+
+        fn on_member_removed(
+            &mut self,
+            _: &[u8],
+            roster_index: RosterIndex,
+            credential: &Credential,
+        ) {
+            self.0.push(Event::Removed(roster_index, credential.clone()));
+        }
+
+        fn on_epoch_advanced(&mut self, _: &[u8], new_epoch: u32) {
+            self.0.push(Event::EpochAdvanced(new_epoch));
+        }
+
+        fn on_self_removed(&mut self, _: &[u8], removed_by: RosterIndex) {
+            self.0.push(Event::SelfRemoved(removed_by));
+        }
+
+        fn on_credential_changed(
+            &mut self,
+            _: &[u8],
+            roster_index: RosterIndex,
+            old_credential: &Credential,
+            new_credential: &Credential,
+        ) {
+            self.0.push(Event::CredentialChanged(
+                roster_index,
+                old_credential.clone(),
+                new_credential.clone(),
+            ));
+        }
+    }
+
+    // Has one member do an Add, a CredentialUpdate, and two Removes, the last of which removes the
+    // observing member, and checks that the observer hears about exactly what happened
+    #[quickcheck]
+    fn observer_correctness(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (sender_state, identity_keys) = test_utils::random_full_group_state(3, &mut rng);
+        let cs = sender_state.cs;
+        let sender_index = sender_state.roster_index.unwrap();
+        let observer_index = test_utils::random_roster_index_with_exceptions(
+            sender_state.roster.len(),
+            &[sender_index.0 as usize],
+            &mut rng,
+        );
+        let observer_state =
+            test_utils::change_self_index(&sender_state, &identity_keys, observer_index);
+        let mut recorder = Recorder::default();
+
+        // Add someone new
+        let (new_cred, new_identity_key): (Credential, SigSecretKey) =
+            Credential::new_basic_from_random("newbie".into(), &ED25519_IMPL, &mut rng).unwrap();
+        let init_key = test_utils::make_init_key(
+            &new_identity_key,
+            new_cred.clone(),
+            b"newbie key",
+            &X25519_SHA256_AES128GCM,
+            &mut rng,
+        );
+        let new_roster_index = RosterIndex(sender_state.roster.len() as u32);
+        let welcome_info_hash = sender_state.welcome_info_hash().unwrap();
+        let (handshake, sender_state, _) = sender_state
+            .create_and_apply_add_handshake(new_roster_index, init_key, &welcome_info_hash)
+            .unwrap();
+        let (observer_state, _) =
+            observer_state.process_handshake_with_observer(&handshake, &mut recorder).unwrap();
+        assert_eq!(
+            recorder.0.drain(..).collect::<Vec<_>>(),
+            vec![
+                Event::Added(new_roster_index, new_cred),
+                Event::EpochAdvanced(sender_state.epoch)
+            ]
+        );
+
+        // Replace the sender's credential with one of the same identity
+        let old_cred = sender_state.roster.0[sender_index.0 as usize].clone().unwrap();
+        let (updated_cred, updated_identity_key): (Credential, SigSecretKey) =
+            Credential::new_basic_from_random(
+                old_cred.get_identity().clone(),
+                &ED25519_IMPL,
+                &mut rng,
+            )
+            .unwrap();
+        let path_secret = PathSecret::new_from_random(cs, &mut rng);
+        let (handshake, sender_state, _) = sender_state
+            .create_and_apply_credential_update_handshake(
+                updated_cred.clone(),
+                updated_identity_key,
+                path_secret,
+                &mut rng,
+            )
+            .unwrap();
+        let (observer_state, _) =
+            observer_state.process_handshake_with_observer(&handshake, &mut recorder).unwrap();
+        assert_eq!(
+            recorder.0.drain(..).collect::<Vec<_>>(),
+            vec![
+                Event::CredentialChanged(sender_index, old_cred, updated_cred),
+                Event::EpochAdvanced(sender_state.epoch)
+            ]
+        );
+
+        // Remove someone who's neither the sender nor the observer
+        let removed_roster_index = test_utils::random_roster_index_with_exceptions(
+            sender_state.roster.len(),
+            &[sender_index.0 as usize, observer_index.0 as usize],
+            &mut rng,
+        );
+        let removed_cred = sender_state.roster.0[removed_roster_index.0 as usize].clone().unwrap();
+        let path_secret = PathSecret::new_from_random(cs, &mut rng);
+        let (handshake, sender_state, _) = sender_state
+            .create_and_apply_remove_handshake(removed_roster_index, path_secret, &mut rng)
+            .unwrap();
+        let (observer_state, _) =
+            observer_state.process_handshake_with_observer(&handshake, &mut recorder).unwrap();
+        assert_eq!(
+            recorder.0.drain(..).collect::<Vec<_>>(),
+            vec![
+                Event::Removed(removed_roster_index, removed_cred),
+                Event::EpochAdvanced(sender_state.epoch)
+            ]
+        );
+
+        // Now remove the observer. They hear about it, and nothing else.
+        let path_secret = PathSecret::new_from_random(cs, &mut rng);
+        let (handshake, _, _) = sender_state
+            .create_and_apply_remove_handshake(observer_index, path_secret, &mut rng)
+            .unwrap();
+        match observer_state.process_handshake_with_observer(&handshake, &mut recorder) {
+            Err(Error::IAmRemoved) => (),
+            _ => panic!("removed member didn't notice"),
+        }
+        assert_eq!(recorder.0, vec![Event::SelfRemoved(sender_index)]);
+    }
+}
@@ -0,0 +1,670 @@
+//! Defines `PublicGroupState`, the part of a group that isn't secret: its roster, the public keys
+//! of its ratchet tree, its epoch, and its transcript hash. This is enough to check that a
+//! `Handshake` is well-formed and properly signed, so a delivery service can hold one and drop
+//! garbage before fanning it out, without ever being able to read the group's messages. It can
+//! also follow the group from one epoch to the next by applying the public half of each
+//! `Handshake`, which makes it a passive observer: an auditor or a delivery service can keep track
+//! of who's in the group and what the tree's public keys are without ever being a member.
+
+use crate::{
+    config::DuplicateIdentityPolicy,
+    credential::{Credential, Roster, RosterIndex},
+    crypto::{ciphersuite::CipherSuite, hash::Digest},
+    error::Error,
+    extensions::ExtensionList,
+    group_state::{check_sender_eligibility, GroupContext, GroupState},
+    handshake::{
+        DirectPathMessage, GroupInit, GroupOperation, Handshake, ProtocolVersion, UserInitKey,
+    },
+    observer::GroupObserver,
+    ratchet_tree::{RatchetTree, RatchetTreeNode},
+    tls_de::{ParseMode, TlsDeserializer},
+    tls_ser,
+    tree_math::{LeafIndex, NodeIndex},
+    upcast::{CryptoCtx, CryptoUpcast},
+};
+
+use serde::de::Deserialize;
+
+/// A view of a group with nothing secret in it. Members make one with
+/// `GroupState::to_public_state`.
+#[derive(Clone)]
+pub struct PublicGroupState {
+    pub(crate) cs: &'static CipherSuite,
+    pub(crate) protocol_version: ProtocolVersion,
+    pub(crate) group_id: Vec<u8>,
+    pub(crate) epoch: u32,
+    pub(crate) roster: Roster,
+    // Every node's private key is erased
+    pub(crate) tree: RatchetTree,
+    pub(crate) transcript_hash: Digest,
+    pub(crate) extensions: ExtensionList,
+}
+
+impl GroupState {
+    /// Returns everything about this group that isn't secret, e.g., to hand to a delivery service
+    /// so it can check `Handshake`s with `PublicGroupState::validate_handshake`
+    pub fn to_public_state(&self) -> PublicGroupState {
+        let mut tree = self.tree.clone();
+        tree.erase_all_private_keys();
+
+        PublicGroupState {
+            cs: self.cs,
+            protocol_version: self.protocol_version,
+            group_id: self.group_id.clone(),
+            epoch: self.epoch,
+            roster: (*self.roster).clone(),
+            tree,
+            transcript_hash: self.transcript_hash.clone(),
+            extensions: self.extensions.clone(),
+        }
+    }
+}
+
+impl PublicGroupState {
+    /// Returns the group's ID
+    pub fn get_group_id(&self) -> &[u8] {
+        &self.group_id
+    }
+
+    /// Returns the epoch that this view is of
+    pub fn get_epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    /// Returns the group's roster
+    pub fn get_roster(&self) -> &Roster {
+        &self.roster
+    }
+
+    /// Returns the group's transcript hash
+    pub fn get_transcript_hash(&self) -> &[u8] {
+        self.transcript_hash.as_bytes()
+    }
+
+    /// Returns the group's ciphersuite
+    pub fn get_cipher_suite(&self) -> &'static CipherSuite {
+        self.cs
+    }
+
+    /// Returns the hash of the group's ratchet tree. This is the tree hash in the group's
+    /// `GroupContext`, which is what a member repairing its tree has to check the new one
+    /// against. See `GroupState::process_tree_sync_response`.
+    ///
+    /// Returns: `Ok(tree_hash)` on success. Returns an `Error::SerdeError` if the tree can't be
+    /// serialized.
+    pub fn get_tree_hash(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.tree.tree_hash(self.cs)?.as_bytes().to_vec())
+    }
+
+    /// Parses a `Handshake` from its wire encoding and checks everything about it that can be
+    /// checked without the group's secrets. It has to be for this group and this epoch, and from a
+    /// current member. Its operation has to make sense: an `Update` or `CredentialUpdate` has to
+    /// carry a direct path from the signer's leaf, a `Remove` has to be for a current member, an
+    /// `Add` has to be for an empty roster slot with a valid `UserInitKey` this group can use, a
+    /// `Replace` has to be for a current member with such a `UserInitKey`, a `CredentialUpdate`
+    /// can't change its sender's identity, and an `Init` has to describe this group exactly.
+    /// Lastly, its signature has to verify. The one thing left unchecked is the confirmation MAC,
+    /// which takes the new epoch's secrets. So passing this doesn't make the `Handshake` valid,
+    /// but failing it means no member would accept it.
+    ///
+    /// This parses in `ParseMode::Strict`, like everything in `verify`.
+    ///
+    /// Returns: `Ok(handshake)` on success. Returns an `Error::ValidationError` if the `Handshake`
+    /// is for another group or epoch or has a malformed operation, an `Error::InvalidOperation`
+    /// if its signer can't make its operation, and an `Error::SignatureError` if a signature
+    /// doesn't verify, and an `Error::DuplicateMember` if it's an `Add` of someone the group won't
+    /// take twice. Returns an `Error::SerdeError` or `Error::UpcastError` if it can't be parsed.
+    pub fn validate_handshake(&self, bytes: &[u8]) -> Result<Handshake, Error> {
+        self.check_handshake(bytes).map(|(handshake, _)| handshake)
+    }
+
+    /// Checks the given `Handshake` with `PublicGroupState::validate_handshake` and, if it passes,
+    /// applies the public half of its operation. This is how a passive observer follows a group.
+    /// Like `GroupState::process_handshake`, this doesn't mutate the current `PublicGroupState`.
+    ///
+    /// Since the confirmation MAC can't be checked without the group's secrets, a `Handshake` that
+    /// the members reject can still get through here. Whoever is following the group this way
+    /// should feed it the same `Handshake`s, in the same order, that the members accept.
+    ///
+    /// Returns: `Ok(public_state)` on success, where `public_state` is the group after the
+    /// `Handshake`. Otherwise returns the same errors as `PublicGroupState::validate_handshake`.
+    pub fn process_handshake(&self, bytes: &[u8]) -> Result<PublicGroupState, Error> {
+        self.process_handshake_with_observer(bytes, &mut ())
+    }
+
+    /// Like `PublicGroupState::process_handshake`, but also tells `observer` what the `Handshake`
+    /// did. There's no member here to be removed, so `GroupObserver::on_self_removed` is never
+    /// called.
+    ///
+    /// Returns: The same as `PublicGroupState::process_handshake`
+    pub fn process_handshake_with_observer(
+        &self,
+        bytes: &[u8],
+        observer: &mut dyn GroupObserver,
+    ) -> Result<PublicGroupState, Error> {
+        let (handshake, transcript_hash) = self.check_handshake(bytes)?;
+        let sender_tree_idx = handshake.signer_index.node_index()?;
+
+        let mut new_state = self.clone();
+        new_state.transcript_hash = transcript_hash;
+        new_state.epoch = self
+            .epoch
+            .checked_add(1)
+            .ok_or(Error::ValidationError("Cannot increment epoch past its maximum"))?;
+
+        // Nothing below can fail on a Handshake that passed the checks above, short of a bug. Even
+        // so, nothing is reported until all of it is done.
+        match handshake.operation {
+            GroupOperation::Update(ref update) => {
+                new_state.set_path_public_keys(&update.path, sender_tree_idx)?;
+            }
+            GroupOperation::CredentialUpdate(ref cred_update) => {
+                new_state.set_path_public_keys(&cred_update.path, sender_tree_idx)?;
+                new_state
+                    .roster
+                    .replace_at(handshake.signer_index, cred_update.new_credential.clone())?;
+            }
+            GroupOperation::Remove(ref remove) => {
+                let remove_tree_idx = remove.removed_roster_index.node_index()?;
+                new_state.set_path_public_keys(&remove.path, remove_tree_idx)?;
+                new_state.roster.remove_at(remove.removed_roster_index)?;
+                new_state.roster.truncate_to_last_nonblank()?;
+                new_state.tree.propagate_blank(remove_tree_idx);
+                new_state.tree.truncate_to_last_nonblank();
+            }
+            GroupOperation::BatchRemove(ref batch_remove) => {
+                // Blank everyone first, since the sender's path was made in the blanked tree
+                for &removed_roster_index in batch_remove.removed_roster_indices.iter() {
+                    new_state.roster.remove_at(removed_roster_index)?;
+                    new_state.tree.propagate_blank(removed_roster_index.node_index()?);
+                }
+                new_state.set_path_public_keys(&batch_remove.path, sender_tree_idx)?;
+                new_state.roster.truncate_to_last_nonblank()?;
+                new_state.tree.truncate_to_last_nonblank();
+            }
+            GroupOperation::Replace(ref replace) => {
+                // Swap the members first, since the sender's path was made after that
+                let add = &replace.add;
+                let public_key =
+                    add.init_key.get_compatible_public_key(self.cs, self.protocol_version)?;
+                let new_node = RatchetTreeNode::Filled {
+                    public_key: public_key.clone(),
+                    private_key: None,
+                };
+                let replaced_roster_index = add.roster_index;
+                new_state.roster.remove_at(replaced_roster_index)?;
+                new_state.tree.propagate_blank(replaced_roster_index.node_index()?);
+                new_state.roster.add_at(replaced_roster_index, add.init_key.credential.clone())?;
+                new_state.tree.add_leaf_at(replaced_roster_index.leaf_index()?, new_node)?;
+                new_state.set_path_public_keys(&replace.path, sender_tree_idx)?;
+            }
+            GroupOperation::Add(ref add) => {
+                let public_key =
+                    add.init_key.get_compatible_public_key(self.cs, self.protocol_version)?;
+                let new_node = RatchetTreeNode::Filled {
+                    public_key: public_key.clone(),
+                    private_key: None,
+                };
+                new_state.roster.add_at(add.roster_index, add.init_key.credential.clone())?;
+                new_state.tree.add_leaf_at(LeafIndex(add.roster_index.0 as usize), new_node)?;
+            }
+            GroupOperation::PolicyUpdate(ref policy_update) => {
+                new_state.extensions.insert(&policy_update.signed_policy)?;
+            }
+            GroupOperation::Init(_) => (),
+        }
+
+        // Tell the observer, in the same order a member's observer would hear it
+        match handshake.operation {
+            GroupOperation::Add(ref add) => {
+                observer.on_member_added(&self.group_id, add.roster_index, &add.init_key.credential)
+            }
+            GroupOperation::Remove(ref remove) => {
+                let removed_index = remove.removed_roster_index;
+                if let Some(old_credential) = self.roster.get(removed_index) {
+                    observer.on_member_removed(&self.group_id, removed_index, old_credential);
+                }
+            }
+            GroupOperation::BatchRemove(ref batch_remove) => {
+                for &removed_index in batch_remove.removed_roster_indices.iter() {
+                    if let Some(old_credential) = self.roster.get(removed_index) {
+                        observer.on_member_removed(&self.group_id, removed_index, old_credential);
+                    }
+                }
+            }
+            GroupOperation::Replace(ref replace) => {
+                let replaced_index = replace.add.roster_index;
+                if let Some(old_credential) = self.roster.get(replaced_index) {
+                    observer.on_member_removed(&self.group_id, replaced_index, old_credential);
+                }
+                let new_credential = &replace.add.init_key.credential;
+                observer.on_member_added(&self.group_id, replaced_index, new_credential);
+            }
+            GroupOperation::CredentialUpdate(ref cred_update) => {
+                let old_credential = self.roster.get(handshake.signer_index).ok_or(
+                    Error::ValidationError("CredentialUpdate sender's roster entry is empty"),
+                )?;
+                observer.on_credential_changed(
+                    &self.group_id,
+                    handshake.signer_index,
+                    old_credential,
+                    &cred_update.new_credential,
+                );
+            }
+            GroupOperation::Update(_)
+            | GroupOperation::Init(_)
+            | GroupOperation::PolicyUpdate(_) => (),
+        }
+        observer.on_epoch_advanced(&self.group_id, new_state.epoch);
+
+        Ok(new_state)
+    }
+
+    // Does everything validate_handshake promises. Also returns the transcript hash the group will
+    // have after the Handshake, since that had to be computed to check the signature.
+    fn check_handshake(&self, bytes: &[u8]) -> Result<(Handshake, Digest), Error> {
+        let handshake = self.deserialize_handshake(bytes)?;
+
+        if handshake.group_id != self.group_id {
+            return Err(Error::ValidationError("Handshake is for a different group"));
+        }
+        if handshake.prior_epoch != self.epoch {
+            return Err(Error::ValidationError("Handshake's prior epoch isn't the current epoch"));
+        }
+
+        let sender_credential = check_sender_eligibility(&self.roster, &self.tree, &handshake)?;
+        self.check_operation(&handshake, sender_credential)?;
+
+        // The signature covers this epoch's context and the transcript hash once the operation is
+        // applied
+        let prior_context = GroupContext::new(
+            self.cs,
+            &self.group_id,
+            self.epoch,
+            &self.tree,
+            &self.transcript_hash,
+        )?;
+        let transcript_hash =
+            GroupState::next_transcript_hash(self.cs, &self.transcript_hash, &handshake.operation)?;
+        handshake.verify_sig(sender_credential, &prior_context, &transcript_hash)?;
+
+        Ok((handshake, transcript_hash))
+    }
+
+    // Overwrites the public keys on the extended direct path of start_idx with the ones in path,
+    // and makes sure there was exactly one for each node
+    fn set_path_public_keys(
+        &mut self,
+        path: &DirectPathMessage,
+        start_idx: NodeIndex,
+    ) -> Result<(), Error> {
+        let public_keys = path.node_messages.iter().map(|node_msg| &node_msg.public_key);
+        // Nothing is past the end of the tree, so this bound sets the whole path
+        let past_the_end = NodeIndex(self.tree.size());
+        self.tree.set_public_keys_with_bound(start_idx, past_the_end, public_keys.clone())?;
+        self.tree.validate_direct_path_public_keys(start_idx, public_keys)
+    }
+
+    // The signature is in the signer's signature scheme, so we have to look them up before we can
+    // upcast. This is the same as GroupState::deserialize_handshake, but always strict.
+    fn deserialize_handshake(&self, bytes: &[u8]) -> Result<Handshake, Error> {
+        let mut cursor = bytes;
+        let mut deserializer =
+            TlsDeserializer::from_reader_with_mode(&mut cursor, ParseMode::Strict);
+        let mut handshake = Handshake::deserialize(&mut deserializer)?;
+        deserializer.finish()?;
+
+        let signer_credential = self
+            .roster
+            .get(handshake.signer_index)
+            .ok_or(Error::ValidationError("Handshake's signer isn't in the group"))?;
+        let ctx = CryptoCtx::new()
+            .set_cipher_suite(self.cs)
+            .set_signature_scheme(signer_credential.get_signature_scheme());
+        handshake.upcast_crypto_values(&ctx)?;
+
+        Ok(handshake)
+    }
+
+    // Checks the UserInitKey of someone being added, and that the group lets them in next to the
+    // current members, not counting the one at replaced_roster_index if there is one
+    fn check_new_member(
+        &self,
+        init_key: &UserInitKey,
+        replaced_roster_index: Option<RosterIndex>,
+    ) -> Result<(), Error> {
+        init_key.verify_sig()?;
+        init_key.validate()?;
+        init_key.get_compatible_public_key(self.cs, self.protocol_version)?;
+
+        // Same as GroupState's check, without the member index to look things up in
+        let new_credential = &init_key.credential;
+        let duplicate_identity_policy =
+            self.extensions.get::<DuplicateIdentityPolicy>()?.unwrap_or_default();
+        let duplicate = self.roster.0.iter().enumerate().find(|(idx, entry)| match entry {
+            _ if replaced_roster_index == Some(RosterIndex(*idx as u32)) => false,
+            Some(cred) if cred.get_identity() == new_credential.get_identity() => {
+                duplicate_identity_policy == DuplicateIdentityPolicy::Reject
+                    || cred == new_credential
+            }
+            _ => false,
+        });
+        if let Some((existing_idx, _)) = duplicate {
+            return Err(Error::DuplicateMember(RosterIndex(existing_idx as u32)));
+        }
+
+        Ok(())
+    }
+
+    // Checks the parts of an operation that check_sender_eligibility doesn't, short of applying it
+    fn check_operation(
+        &self,
+        handshake: &Handshake,
+        sender_credential: &Credential,
+    ) -> Result<(), Error> {
+        match handshake.operation {
+            GroupOperation::Add(ref add) => {
+                let add_roster_index = add.roster_index.0 as usize;
+                let slot_is_free = match self.roster.0.get(add_roster_index) {
+                    Some(entry) => entry.is_none(),
+                    None => add_roster_index == self.roster.len(),
+                };
+                if !slot_is_free {
+                    return Err(Error::ValidationError(
+                        "Add's roster index isn't an empty slot or the end of the roster",
+                    ));
+                }
+                self.check_new_member(&add.init_key, None)?;
+            }
+            // check_sender_eligibility made sure the slot is a current member's
+            GroupOperation::Replace(ref replace) => {
+                let replaced_roster_index = replace.add.roster_index;
+                self.check_new_member(&replace.add.init_key, Some(replaced_roster_index))?;
+            }
+            GroupOperation::CredentialUpdate(ref cred_update) => {
+                cred_update.verify_credential_sig(
+                    &self.group_id,
+                    handshake.prior_epoch,
+                    handshake.signer_index,
+                )?;
+                if sender_credential.get_identity() != cred_update.new_credential.get_identity() {
+                    return Err(Error::ValidationError(
+                        "CredentialUpdate cannot change the member's identity",
+                    ));
+                }
+            }
+            GroupOperation::Init(ref init) => {
+                if self.epoch != 0 || self.transcript_hash.as_bytes().iter().any(|&b| b != 0) {
+                    return Err(Error::ValidationError(
+                        "GroupInit isn't the first operation in the group",
+                    ));
+                }
+                let expected = GroupInit {
+                    protocol_version: self.protocol_version,
+                    group_id: self.group_id.clone(),
+                    cipher_suite: self.cs,
+                    roster: self.roster.clone(),
+                    tree: self.tree.clone(),
+                    extensions: self.extensions.clone(),
+                };
+                if tls_ser::serialize_to_bytes(&expected)? != tls_ser::serialize_to_bytes(init)? {
+                    return Err(Error::ValidationError("GroupInit doesn't describe this group"));
+                }
+            }
+            GroupOperation::PolicyUpdate(ref policy_update) => {
+                policy_update.signed_policy.validate_replacement(
+                    &self.extensions,
+                    &self.roster,
+                    &self.group_id,
+                    handshake.prior_epoch,
+                    handshake.signer_index,
+                )?;
+            }
+            // check_sender_eligibility covers these
+            GroupOperation::Update(_)
+            | GroupOperation::Remove(_)
+            | GroupOperation::BatchRemove(_) => (),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PublicGroupState;
+    use crate::{
+        credential::{Credential, RosterIndex},
+        error::Error,
+        group_state::GroupState,
+        handshake::Handshake,
+        observer::GroupObserver,
+        ratchet_tree::{PathSecret, RatchetTreeNode},
+        test_utils, tls_ser,
+    };
+
+    use core::convert::TryFrom;
+
+    use quickcheck_macros::quickcheck;
+    use rand::SeedableRng;
+
+    // Checks that a public view has no private keys, and that it accepts an honest Update and Add
+    // and rejects them when they're out of place or signed by the wrong member
+    #[quickcheck]
+    fn public_handshake_validation(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state, _) = test_utils::random_full_group_state(2, &mut rng);
+        let public_state = group_state.to_public_state();
+        for node in public_state.tree.nodes().iter() {
+            if let RatchetTreeNode::Filled {
+                private_key,
+                ..
+            } = node
+            {
+                assert!(private_key.is_none());
+            }
+        }
+
+        // An honest Update goes through
+        let new_path_secret = PathSecret::new_from_random(group_state.cs, &mut rng);
+        let (handshake, new_group_state, _) =
+            group_state.create_and_apply_update_handshake(new_path_secret, &mut rng).unwrap();
+        let update_bytes = tls_ser::serialize_to_bytes(&handshake).unwrap();
+        public_state.validate_handshake(&update_bytes).unwrap();
+
+        // It doesn't go through a second time, since the group has moved on
+        let next_public_state = new_group_state.to_public_state();
+        match next_public_state.validate_handshake(&update_bytes) {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("stale Update didn't give an Error::ValidationError"),
+        }
+
+        // An honest Add goes through
+        let (init_key, _) = test_utils::random_init_key(b"public", group_state.cs, &mut rng);
+        let welcome_info_hash = group_state.welcome_info_hash().unwrap();
+        let (mut handshake, _, _) = group_state
+            .create_and_apply_add_handshake(
+                RosterIndex(u32::try_from(group_state.roster.len()).unwrap()),
+                init_key,
+                &welcome_info_hash,
+            )
+            .unwrap();
+        let add_bytes = tls_ser::serialize_to_bytes(&handshake).unwrap();
+        public_state.validate_handshake(&add_bytes).unwrap();
+
+        // Pinning it on another member breaks the signature
+        let other_index = test_utils::random_roster_index_with_exceptions(
+            group_state.roster.len(),
+            &[handshake.signer_index.0 as usize],
+            &mut rng,
+        );
+        handshake.signer_index = other_index;
+        let forged_bytes = tls_ser::serialize_to_bytes(&handshake).unwrap();
+        match public_state.validate_handshake(&forged_bytes) {
+            Err(Error::SignatureError(_)) => (),
+            _ => panic!("misattributed Add didn't give an Error::SignatureError"),
+        }
+    }
+
+    // Counts what a GroupObserver is told
+    #[derive(Default)]
+    struct Tally {
+        added: usize,
+        removed: usize,
+        credentials_changed: usize,
+        last_epoch: Option<u32>,
+    }
+
+    impl GroupObserver for Tally {
+        fn on_member_added(&mut self, _: &[u8], _: RosterIndex, _: &Credential) {
+            self.added += 1;
+        }
+
+        fn on_member_removed(&mut self, _: &[u8], _: RosterIndex, _: &Credential) {
+            self.removed += 1;
+        }
+
+        fn on_epoch_advanced(&mut self, _: &[u8], new_epoch: u32) {
+            self.last_epoch = Some(new_epoch);
+        }
+
+        fn on_credential_changed(
+            &mut self,
+            _: &[u8],
+            _: RosterIndex,
+            _: &Credential,
+            _: &Credential,
+        ) {
+            self.credentials_changed += 1;
+        }
+    }
+
+    // Feeds handshake to public_state and checks that it ends up agreeing with member_state about
+    // everything public
+    fn follow(
+        public_state: &PublicGroupState,
+        handshake: &Handshake,
+        member_state: &GroupState,
+        tally: &mut Tally,
+    ) -> PublicGroupState {
+        let bytes = tls_ser::serialize_to_bytes(handshake).unwrap();
+        let new_public_state = public_state.process_handshake_with_observer(&bytes, tally).unwrap();
+
+        assert_eq!(new_public_state.get_epoch(), member_state.get_epoch());
+        assert_eq!(tally.last_epoch, Some(member_state.get_epoch()));
+        assert_eq!(new_public_state.get_transcript_hash(), member_state.get_transcript_hash());
+        assert_serialized_eq!(new_public_state.roster, *member_state.roster, "rosters disagree");
+        assert_eq!(
+            new_public_state.tree.tree_hash(member_state.cs).unwrap().as_bytes(),
+            member_state.get_tree_hash().unwrap().as_slice()
+        );
+        for node in new_public_state.tree.nodes().iter() {
+            if let RatchetTreeNode::Filled {
+                private_key,
+                ..
+            } = node
+            {
+                assert!(private_key.is_none());
+            }
+        }
+
+        new_public_state
+    }
+
+    // Has a member do an Update, an Add, a CredentialUpdate, a Remove, a Replace, and a
+    // BatchRemove, and checks that a passive observer following along agrees with them on the
+    // roster, the tree's public keys, the epoch, and the transcript hash after every one
+    #[quickcheck]
+    fn passive_observer_correctness(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state, _) = test_utils::random_full_group_state(2, &mut rng);
+        let cs = group_state.cs;
+        let my_roster_index = group_state.roster_index.unwrap();
+        let mut public_state = group_state.to_public_state();
+        let mut tally = Tally::default();
+
+        let new_path_secret = PathSecret::new_from_random(cs, &mut rng);
+        let (handshake, group_state, _) =
+            group_state.create_and_apply_update_handshake(new_path_secret, &mut rng).unwrap();
+        public_state = follow(&public_state, &handshake, &group_state, &mut tally);
+
+        let (new_credential, new_identity_key) = test_utils::random_basic_credential(&mut rng);
+        let init_key =
+            test_utils::make_init_key(&new_identity_key, new_credential, b"passive", cs, &mut rng);
+        let welcome_info_hash = group_state.welcome_info_hash().unwrap();
+        let (handshake, group_state, _) = group_state
+            .create_and_apply_add_handshake(
+                RosterIndex(u32::try_from(group_state.roster.len()).unwrap()),
+                init_key,
+                &welcome_info_hash,
+            )
+            .unwrap();
+        public_state = follow(&public_state, &handshake, &group_state, &mut tally);
+
+        let old_credential = group_state.roster.0[my_roster_index.0 as usize].clone().unwrap();
+        let (new_credential, new_identity_key) = Credential::new_basic_from_random(
+            old_credential.get_identity().clone(),
+            old_credential.get_signature_scheme(),
+            &mut rng,
+        )
+        .unwrap();
+        let new_path_secret = PathSecret::new_from_random(cs, &mut rng);
+        let (handshake, group_state, _) = group_state
+            .create_and_apply_credential_update_handshake(
+                new_credential,
+                new_identity_key,
+                new_path_secret,
+                &mut rng,
+            )
+            .unwrap();
+        public_state = follow(&public_state, &handshake, &group_state, &mut tally);
+
+        let removed_index = test_utils::random_roster_index_with_exceptions(
+            group_state.roster.len(),
+            &[my_roster_index.0 as usize],
+            &mut rng,
+        );
+        let new_path_secret = PathSecret::new_from_random(cs, &mut rng);
+        let (handshake, group_state, _) = group_state
+            .create_and_apply_remove_handshake(removed_index, new_path_secret, &mut rng)
+            .unwrap();
+        public_state = follow(&public_state, &handshake, &group_state, &mut tally);
+
+        // The Remove may have left a hole, so pick someone who's actually there
+        let replaced_index = (0..u32::try_from(group_state.roster.len()).unwrap())
+            .map(RosterIndex)
+            .find(|&idx| idx != my_roster_index && group_state.roster.0[idx.0 as usize].is_some())
+            .unwrap();
+        let (init_key, _) = test_utils::random_init_key(b"replacement", cs, &mut rng);
+        let welcome_info_hash = group_state.welcome_info_hash().unwrap();
+        let new_path_secret = PathSecret::new_from_random(cs, &mut rng);
+        let (handshake, group_state, _) = group_state
+            .create_and_apply_replace_handshake(
+                replaced_index,
+                init_key,
+                &welcome_info_hash,
+                new_path_secret,
+                &mut rng,
+            )
+            .unwrap();
+        public_state = follow(&public_state, &handshake, &group_state, &mut tally);
+
+        let removed_indices: Vec<RosterIndex> = (0..u32::try_from(group_state.roster.len())
+            .unwrap())
+            .map(RosterIndex)
+            .filter(|&idx| idx != my_roster_index && group_state.roster.0[idx.0 as usize].is_some())
+            .collect();
+        let new_path_secret = PathSecret::new_from_random(cs, &mut rng);
+        let (handshake, group_state, _) = group_state
+            .create_and_apply_batch_remove_handshake(&removed_indices, new_path_secret, &mut rng)
+            .unwrap();
+        follow(&public_state, &handshake, &group_state, &mut tally);
+
+        let expected_removed = 2 + removed_indices.len();
+        assert_eq!(
+            (tally.added, tally.removed, tally.credentials_changed),
+            (2, expected_removed, 1)
+        );
+    }
+}
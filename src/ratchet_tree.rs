@@ -5,19 +5,23 @@ use crate::{
         ciphersuite::CipherSuite,
         dh::{DhPrivateKey, DhPublicKey},
         ecies,
+        hash::Digest,
         hmac::HmacKey,
         rng::CryptoRng,
+        secret::Secret,
     },
-    error::Error,
+    error::{Error, TreeErrorContext},
     handshake::{DirectPathMessage, DirectPathNodeMessage},
-    tree_math, utils,
+    tree_math::{self, LeafIndex, NodeIndex, TreeMathContext},
+    utils,
 };
 
-use subtle::ConstantTimeEq;
+use core::sync::atomic::{AtomicU64, Ordering};
+use subtle::{Choice, ConditionallySelectable, ConstantTimeEq};
 
 /// This is called the "node secret" (section 5.2). If `Hash` is the current ciphersuite's hash
 /// algorithm, this MUST have length equal to `Hash.length`.
-pub(crate) struct NodeSecret(pub(crate) Vec<u8>);
+pub(crate) struct NodeSecret(pub(crate) Secret);
 
 /// This is called the "path secret" (section 5.2). If `Hash` is the current ciphersuite's hash
 /// algorithm, this MUST have length equal to `Hash.length`.
@@ -41,9 +45,8 @@ impl PathSecret {
 
     /// Returns the bytes-representation of the path secret. Do not use this method unless you
     /// really really need to.
-    fn as_bytes(&self) -> &[u8] {
-        // Dig into the HMAC key and pull out a slice
-        (self.0).0.as_slice()
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        self.0.as_bytes()
     }
 
     /// Returns the length of the bytes-representation of the path secret
@@ -93,13 +96,8 @@ impl RatchetTreeNode {
     }
 
     /// Returns `true` iff this is the `Filled` variant
-    #[rustfmt::skip]
     pub(crate) fn is_filled(&self) -> bool {
-        if let RatchetTreeNode::Filled { .. } = self {
-            true
-        } else {
-            false
-        }
+        matches!(self, RatchetTreeNode::Filled { .. })
     }
 
     /// Updates the node's public key to the given one. This is the only way to convert a `Blank`
@@ -145,6 +143,21 @@ impl RatchetTreeNode {
         }
     }
 
+    /// Zeroes and drops the private key of this node, if it has one. This does nothing to blank
+    /// nodes.
+    pub(crate) fn erase_private_key(&mut self) {
+        if let RatchetTreeNode::Filled {
+            ref mut private_key,
+            ..
+        } = self
+        {
+            // Zero the key ourselves rather than count on its destructor to
+            if let Some(mut key) = private_key.take() {
+                key.zeroize();
+            }
+        }
+    }
+
     /// Returns `Some(&private_key)` if the node contains a private key. Otherwise returns `None`.
     pub(crate) fn get_private_key(&self) -> Option<&DhPrivateKey> {
         match self {
@@ -157,28 +170,230 @@ impl RatchetTreeNode {
     }
 }
 
+// The inputs to the hash of a node, as in the "Tree Hashes" section of later drafts of the spec.
+// Leaves here don't carry credentials, the roster does, so a leaf's hash only covers its public
+// key. `RatchetTreeNode` serializes the same way as an optional<DHPublicKey>.
+//
+// struct {
+//     uint8 hash_type = 0;
+//     optional<DHPublicKey> public_key;
+// } LeafNodeHashInput;
+//
+// struct {
+//     uint8 hash_type = 1;
+//     optional<DHPublicKey> public_key;
+//     opaque left_hash<0..255>;
+//     opaque right_hash<0..255>;
+// } ParentNodeHashInput;
+//
+// The child hashes are taken as raw bytes, since that's how `NodeHashCache` keeps them. They
+// serialize the same way a `Digest` does.
+
+const LEAF_HASH_TYPE: u8 = 0;
+const PARENT_HASH_TYPE: u8 = 1;
+
+#[derive(Serialize)]
+struct LeafNodeHashInput<'a> {
+    hash_type: u8,
+    public_key: &'a RatchetTreeNode,
+}
+
+#[derive(Serialize)]
+struct ParentNodeHashInput<'a> {
+    hash_type: u8,
+    public_key: &'a RatchetTreeNode,
+    #[serde(rename = "left_hash__bound_u8")]
+    left_hash: &'a [u8],
+    #[serde(rename = "right_hash__bound_u8")]
+    right_hash: &'a [u8],
+}
+
+/// The hashes of a tree's nodes, as of the last `RatchetTree::refresh_node_hashes`. Whenever a
+/// node's public part changes, its hash and those of all its ancestors are dropped, so whatever is
+/// in here is current. An operation only changes a few paths, so in a big tree, this means almost
+/// none of the hashes have to be recomputed. See `RatchetTree::tree_hash`.
+#[derive(Clone, Default)]
+#[cfg_attr(test, derive(Debug))]
+struct NodeHashCache {
+    // The name of the ciphersuite the hashes were computed with, or None if nothing's been
+    // computed. Trees are only ever hashed with their group's ciphersuite, so this is just a check.
+    cs_name: Option<&'static str>,
+    digest_size: usize,
+    // The hash of node i is digests[i * digest_size..(i + 1) * digest_size], if current[i] is set.
+    // They're kept back to back so that cloning a tree doesn't mean one allocation per node.
+    digests: Vec<u8>,
+    current: Vec<bool>,
+}
+
+impl NodeHashCache {
+    /// Returns the cached hash of the node at `idx`, if it was computed with the given ciphersuite
+    /// and is current
+    fn get(&self, cs: &'static CipherSuite, idx: NodeIndex) -> Option<&[u8]> {
+        if self.cs_name == Some(cs.name) && self.current.get(idx.0) == Some(&true) {
+            Some(&self.digests[idx.0 * self.digest_size..(idx.0 + 1) * self.digest_size])
+        } else {
+            None
+        }
+    }
+
+    /// Stores the hash of the node at `idx`
+    fn set(&mut self, idx: NodeIndex, digest: &Digest) {
+        self.digests[idx.0 * self.digest_size..(idx.0 + 1) * self.digest_size]
+            .copy_from_slice(digest.as_bytes());
+        self.current[idx.0] = true;
+    }
+
+    /// Drops the hash of the node at `idx` and those of all its ancestors, since they cover it
+    fn invalidate(&mut self, ctx: &TreeMathContext, idx: NodeIndex) {
+        if self.cs_name.is_none() {
+            return;
+        }
+        let mut idx = idx;
+        loop {
+            self.current[idx.0] = false;
+            if idx == ctx.root() {
+                break;
+            }
+            idx = ctx.parent(idx);
+        }
+    }
+
+    /// Fits the cache to a tree that has grown or shrunk to the size of `ctx`. This drops the
+    /// hashes of the nodes on the right edge of the tree, since those are the only ones whose
+    /// children can change with the size. `ctx` is `None` if the tree is now empty.
+    fn resize(&mut self, ctx: Option<&TreeMathContext>) {
+        if self.cs_name.is_none() {
+            return;
+        }
+        let num_nodes = ctx.map(TreeMathContext::num_nodes).unwrap_or(0);
+        self.digests.resize(num_nodes * self.digest_size, 0);
+        self.current.resize(num_nodes, false);
+        if let Some(ctx) = ctx {
+            // The last node is always a leaf
+            self.invalidate(ctx, NodeIndex(num_nodes - 1));
+        }
+    }
+}
+
+// The source of tree versions. Every version handed out is new, so no two trees that were built or
+// changed separately share one.
+static NEXT_TREE_VERSION: AtomicU64 = AtomicU64::new(0);
+
+fn next_tree_version() -> u64 {
+    NEXT_TREE_VERSION.fetch_add(1, Ordering::Relaxed)
+}
+
 /// A left-balanced binary tree of `RatchetTreeNode`s
 #[derive(Clone, Deserialize, Serialize)]
 #[cfg_attr(test, derive(Debug))]
 pub(crate) struct RatchetTree {
     #[serde(rename = "nodes__bound_u32")]
-    pub(crate) nodes: Vec<RatchetTreeNode>,
+    nodes: Vec<RatchetTreeNode>,
+
+    // Changes every time the public part of the nodes might have. A clone keeps the version of the
+    // tree it was cloned from until one of them changes, so two trees with the same version have
+    // the same public keys in the same places. See `RatchetTree::version`.
+    #[serde(skip, default = "next_tree_version")]
+    version: u64,
+
+    // The node hashes that are still current. See `RatchetTree::tree_hash`.
+    #[serde(skip)]
+    hash_cache: NodeHashCache,
 }
 
 impl RatchetTree {
+    /// Makes a tree with the given nodes
+    pub(crate) fn new(nodes: Vec<RatchetTreeNode>) -> RatchetTree {
+        RatchetTree {
+            nodes,
+            version: next_tree_version(),
+            hash_cache: NodeHashCache::default(),
+        }
+    }
+
+    /// Returns a number that identifies the public contents of this tree. It changes whenever a
+    /// node is modified, other than by erasing private keys, and no other tree has it unless it
+    /// was cloned from this one since then. This is what lets things computed from the public
+    /// keys, like the tree hash, be cached.
+    pub(crate) fn version(&self) -> u64 {
+        self.version
+    }
+
+    // Gives the tree a new version. Everything that can modify the nodes calls this.
+    fn touch(&mut self) {
+        self.version = next_tree_version();
+    }
+
+    /// Returns the nodes of the tree, in array order
+    pub(crate) fn nodes(&self) -> &[RatchetTreeNode] {
+        &self.nodes
+    }
+
+    /// Returns the nodes of the tree for modifying. This changes the tree's version and drops all
+    /// the cached node hashes, whether or not anything is modified.
+    pub(crate) fn nodes_mut(&mut self) -> &mut Vec<RatchetTreeNode> {
+        self.touch();
+        self.hash_cache = NodeHashCache::default();
+        &mut self.nodes
+    }
+
     /// Returns the number of nodes in the tree
     pub(crate) fn size(&self) -> usize {
         self.nodes.len()
     }
 
+    // Starts a TreeError for the given operation on this tree. Callers add the nodes involved.
+    fn error(&self, operation: &'static str, msg: &'static str) -> TreeErrorContext {
+        TreeErrorContext::new(msg, operation, self.size())
+    }
+
     /// Returns the node at the given index
-    pub(crate) fn get(&self, idx: usize) -> Option<&RatchetTreeNode> {
-        self.nodes.get(idx)
+    pub(crate) fn get(&self, idx: NodeIndex) -> Option<&RatchetTreeNode> {
+        self.nodes.get(idx.0)
     }
 
     /// Returns a mutable reference to the node at the given index
-    pub(crate) fn get_mut(&mut self, idx: usize) -> Option<&mut RatchetTreeNode> {
-        self.nodes.get_mut(idx)
+    pub(crate) fn get_mut(&mut self, idx: NodeIndex) -> Option<&mut RatchetTreeNode> {
+        self.touch();
+        if idx.0 < self.size() {
+            let ctx = self.math_ctx();
+            self.hash_cache.invalidate(&ctx, idx);
+        }
+        self.nodes.get_mut(idx.0)
+    }
+
+    /// Returns the number of leaves in the tree, blank or not. An empty tree has no leaves.
+    pub(crate) fn leaf_count(&self) -> usize {
+        if self.nodes.is_empty() {
+            0
+        } else {
+            tree_math::num_leaves_in_tree(self.size())
+        }
+    }
+
+    /// Returns the tree math context for a tree of this size. Methods that do more than one tree
+    /// math operation should make this once and use it throughout.
+    ///
+    /// Panics: when the tree is empty
+    pub(crate) fn math_ctx(&self) -> TreeMathContext {
+        TreeMathContext::new(self.leaf_count())
+    }
+
+    /// Returns an iterator over all the leaves in the tree, blank or not. Each item is
+    /// `(tree_index, node)`, and items are in ascending index order.
+    pub(crate) fn leaves(&self) -> impl DoubleEndedIterator<Item = (NodeIndex, &RatchetTreeNode)> {
+        (0..self.leaf_count()).map(move |i| {
+            let idx = LeafIndex(i).node_index();
+            (idx, &self.nodes[idx.0])
+        })
+    }
+
+    /// Returns an iterator over the non-blank leaves in the tree. Each item is
+    /// `(tree_index, node)`, and items are in ascending index order.
+    pub(crate) fn occupied_leaves(
+        &self,
+    ) -> impl DoubleEndedIterator<Item = (NodeIndex, &RatchetTreeNode)> {
+        self.leaves().filter(|(_, node)| node.is_filled())
     }
 
     // It turns out that appending to the tree in this way preserves the left-balanced property
@@ -196,23 +411,89 @@ impl RatchetTree {
     //                                        A   B   C   D   E
     //                                        0 1 2 3 4 5 6 7 8
     pub(crate) fn add_leaf_node(&mut self, node: RatchetTreeNode) {
+        self.touch();
         if self.nodes.is_empty() {
             self.nodes.push(node);
         } else {
             self.nodes.push(RatchetTreeNode::Blank);
             self.nodes.push(node);
         }
+        let ctx = self.math_ctx();
+        self.hash_cache.resize(Some(&ctx));
     }
 
-    /// Blanks out the direct path of the given node, as well as the root node
-    pub(crate) fn propagate_blank(&mut self, start_idx: usize) {
-        let num_leaves = tree_math::num_leaves_in_tree(self.size());
-        let direct_path = tree_math::node_extended_direct_path(start_idx, num_leaves);
+    /// Puts the given node at the leaf `leaf_idx`, and blanks out everything above it. The leaf
+    /// must either be blank, or be the one right past the last leaf, in which case the tree is
+    /// extended.
+    ///
+    /// Returns: `Ok(())` on success. Returns an `Error::TreeError` if `leaf_idx` is out of bounds
+    /// or is already filled. On error, the tree is untouched.
+    pub(crate) fn add_leaf_at(
+        &mut self,
+        leaf_idx: LeafIndex,
+        node: RatchetTreeNode,
+    ) -> Result<(), Error> {
+        // The leaf right past the last leaf. In an empty tree, that's leaf 0.
+        let extension_idx = LeafIndex(self.leaf_count());
+
+        if leaf_idx > extension_idx {
+            let err = self.error("add_leaf_at", "Leaf index is past the end of the tree");
+            return Err(err.with_node("leaf", leaf_idx.node_index()).into());
+        }
+        let node_idx = leaf_idx.node_index();
+        if leaf_idx == extension_idx {
+            self.add_leaf_node(RatchetTreeNode::Blank);
+        } else if self.nodes[node_idx.0].is_filled() {
+            let err = self.error("add_leaf_at", "Cannot add a leaf on top of a non-blank leaf");
+            return Err(err.with_node("leaf", node_idx).into());
+        }
+
+        // Blank the path above the new leaf, since none of its secrets are known by the new
+        // member. This blanks the leaf too, so we set it afterwards.
+        self.propagate_blank(node_idx);
+        self.nodes[node_idx.0] = node;
+        let ctx = self.math_ctx();
+        self.hash_cache.invalidate(&ctx, node_idx);
+
+        Ok(())
+    }
+
+    /// Blanks out the direct path of the given node, as well as the root node. The private keys of
+    /// the blanked nodes are zeroed first, so nothing of them is left in memory.
+    pub(crate) fn propagate_blank(&mut self, start_idx: NodeIndex) {
+        let ctx = self.math_ctx();
+        let direct_path = ctx.extended_direct_path(start_idx);
+        self.touch();
+        self.hash_cache.invalidate(&ctx, start_idx);
 
         // Blank the extended direct path (direct path + root node)
         for i in direct_path {
             // No need to check index here. By construction, there's no way this is out of bounds
-            self.nodes[i] = RatchetTreeNode::Blank;
+            self.nodes[i.0].erase_private_key();
+            self.nodes[i.0] = RatchetTreeNode::Blank;
+        }
+    }
+
+    /// Erases the private key of every node. The public keys are left as they are.
+    pub(crate) fn erase_all_private_keys(&mut self) {
+        for node in self.nodes.iter_mut() {
+            node.erase_private_key();
+        }
+    }
+
+    /// Erases the private key of every node that isn't on the extended direct path of the given
+    /// leaf. A member only ever needs the private keys of their own leaf and its ancestors, so
+    /// anything else is left over from an old epoch.
+    pub(crate) fn erase_private_keys_off_path(&mut self, leaf_idx: NodeIndex) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let ctx = self.math_ctx();
+        for (idx, node) in self.nodes.iter_mut().enumerate() {
+            if !ctx.is_ancestor(NodeIndex(idx), leaf_idx) {
+                node.erase_private_key();
+            }
         }
     }
 
@@ -223,55 +504,299 @@ impl RatchetTree {
     /// Truncates the tree down to the first non-blank leaf node. If there is all blank, this will
     /// clear the tree.
     pub(crate) fn truncate_to_last_nonblank(&mut self) {
-        let num_leaves = tree_math::num_leaves_in_tree(self.size());
-
         // Look for the last non-blank leaf by iterating backwards through the leaves in the tree
-        let mut last_nonblank_leaf = None;
-        for idx in tree_math::tree_leaves(num_leaves).rev() {
-            if self.nodes[idx].is_filled() {
-                last_nonblank_leaf = Some(idx);
-                break;
-            }
-        }
+        let last_nonblank_leaf = self.occupied_leaves().next_back().map(|(idx, _)| idx);
+        self.touch();
 
         match last_nonblank_leaf {
             // If there are no nonempty entries in the roster, clear it
             None => self.nodes.clear(),
             Some(i) => {
                 // This can't fail, because i is an index
-                let num_elements_to_retain = i + 1;
+                let num_elements_to_retain = i.0 + 1;
                 self.nodes.truncate(num_elements_to_retain)
             }
         }
+        let ctx = if self.nodes.is_empty() {
+            None
+        } else {
+            Some(self.math_ctx())
+        };
+        self.hash_cache.resize(ctx.as_ref());
+    }
+
+    /// Checks the structural invariants of a tree that we received from someone else, e.g., in a
+    /// `WelcomeInfo`. The tree must have exactly `num_leaves` leaves, it must not claim to know any
+    /// private keys, and every filled parent node must have at least one filled leaf below it.
+    ///
+    /// Returns: `Ok(())` on success. Otherwise returns an `Error::TreeError` describing which
+    /// invariant was violated.
+    pub(crate) fn validate_received(&self, num_leaves: usize) -> Result<(), Error> {
+        // Returns whether any leaf under the given node is filled. Errors if the node is a filled
+        // parent with no such leaf.
+        fn check_subtree(
+            tree: &RatchetTree,
+            ctx: &TreeMathContext,
+            idx: NodeIndex,
+        ) -> Result<bool, Error> {
+            if idx.is_leaf() {
+                return Ok(tree.nodes[idx.0].is_filled());
+            }
+
+            let left_idx = tree_math::node_left_child(idx);
+            let right_idx = ctx.right_child(idx);
+            let has_filled_leaf =
+                check_subtree(tree, ctx, left_idx)? | check_subtree(tree, ctx, right_idx)?;
+
+            if tree.nodes[idx.0].is_filled() && !has_filled_leaf {
+                let msg = "Received tree has a filled parent node above only blank leaves";
+                Err(tree.error("validate_received", msg).with_node("parent", idx).into())
+            } else {
+                Ok(has_filled_leaf)
+            }
+        }
+
+        if num_leaves == 0 || num_leaves > tree_math::MAX_LEAVES {
+            let msg = "Received tree has an invalid number of leaves";
+            return Err(self.error("validate_received", msg).into());
+        }
+        let ctx = TreeMathContext::new(num_leaves);
+        if self.size() != ctx.num_nodes() {
+            let msg = "Received tree's size doesn't match its number of leaves";
+            return Err(self.error("validate_received", msg).into());
+        }
+
+        // Nobody can send us private keys. These are never serialized, so if one shows up, this
+        // tree came from somewhere it shouldn't have.
+        if let Some(idx) = self.nodes.iter().position(|node| node.get_private_key().is_some()) {
+            let err = self.error("validate_received", "Received tree claims to know private keys");
+            return Err(err.with_node("node", NodeIndex(idx)).into());
+        }
+
+        check_subtree(self, &ctx, ctx.root())?;
+        Ok(())
+    }
+
+    /// Renders the shape of the tree as ASCII art. Each node is drawn in its own column, in index
+    /// order, on the row for its level, so every parent lands between its children. `_` is a
+    /// blank node, `o` is a filled node whose public key is all we know, and `#` is a filled node
+    /// whose private key we know. The last row is the node indices. For example, here is a tree of
+    /// 3 leaves where we hold leaf 0 and know its parent's secret, and the root is blank:
+    ///
+    /// ```text
+    ///           _
+    ///     #
+    ///  #     o     o
+    ///  0  1  2  3  4
+    /// ```
+    #[cfg(feature = "tree-render")]
+    pub(crate) fn render_ascii(&self) -> String {
+        if self.nodes.is_empty() {
+            return String::from("(empty tree)\n");
+        }
+
+        // Columns have to be wide enough for the biggest index, plus a space
+        let max_idx_width = (self.size() - 1).to_string().len();
+        let col_width = core::cmp::max(3, max_idx_width + 1);
+
+        let root_level = tree_math::node_level(self.math_ctx().root());
+        let mut out = String::new();
+        for level in (0..=root_level).rev() {
+            let mut row = String::new();
+            for (idx, node) in self.nodes.iter().enumerate() {
+                let marker = if tree_math::node_level(NodeIndex(idx)) != level {
+                    " "
+                } else {
+                    match node {
+                        RatchetTreeNode::Blank => "_",
+                        RatchetTreeNode::Filled {
+                            private_key: None,
+                            ..
+                        } => "o",
+                        RatchetTreeNode::Filled {
+                            private_key: Some(_),
+                            ..
+                        } => "#",
+                    }
+                };
+                row.push_str(&format!("{:^width$}", marker, width = col_width));
+            }
+            out.push_str(row.trim_end());
+            out.push('\n');
+        }
+
+        let index_row: String =
+            (0..self.size()).map(|idx| format!("{:^width$}", idx, width = col_width)).collect();
+        out.push_str(index_row.trim_end());
+        out.push('\n');
+
+        out
     }
 
     /// Returns the indices of the resolution of a given node: this an ordered sequence of minimal
     /// set of non-blank nodes that collectively cover (A "covers" B iff A is an ancestor of B) all
     /// non-blank descendants of the given node. The ordering is ascending by node index.
-    pub(crate) fn resolution(&self, idx: usize) -> Vec<usize> {
-        // Helper function that accumulates the resolution recursively
-        fn helper(tree: &RatchetTree, i: usize, acc: &mut Vec<usize>) {
-            if let RatchetTreeNode::Blank = tree.nodes[i] {
-                if tree_math::node_level(i) == 0 {
-                    // The resolution of a blank leaf node is the empty list
+    #[cfg(any(test, feature = "gen-test-vectors"))]
+    pub(crate) fn resolution(&self, idx: NodeIndex) -> Vec<NodeIndex> {
+        self.resolution_iter(&self.math_ctx(), idx).collect()
+    }
+
+    /// Like `resolution`, but uses the given tree math context instead of making a new one, and
+    /// yields the indices lazily instead of collecting them. Callers that only walk the resolution
+    /// once, or stop partway through, should use this.
+    fn resolution_iter(&self, ctx: &TreeMathContext, idx: NodeIndex) -> ResolutionIter<'_> {
+        // Every step down the subtree pops one index and pushes two, so the stack never holds
+        // more than one index per level
+        let mut stack = Vec::with_capacity(tree_math::node_level(idx) + 1);
+        stack.push(idx);
+
+        ResolutionIter {
+            tree: self,
+            ctx: *ctx,
+            stack,
+        }
+    }
+
+    // Hashes the node at `idx`, as described above `LeafNodeHashInput`. `child_hashes` is the
+    // hashes of the node's left and right children if it's a parent, and None if it's a leaf.
+    fn hash_node(
+        &self,
+        cs: &'static CipherSuite,
+        idx: NodeIndex,
+        child_hashes: Option<(&[u8], &[u8])>,
+    ) -> Result<Digest, Error> {
+        let node = &self.nodes[idx.0];
+        match child_hashes {
+            None => cs.hash_impl.hash_serializable(&LeafNodeHashInput {
+                hash_type: LEAF_HASH_TYPE,
+                public_key: node,
+            }),
+            Some((left_hash, right_hash)) => cs.hash_impl.hash_serializable(&ParentNodeHashInput {
+                hash_type: PARENT_HASH_TYPE,
+                public_key: node,
+                left_hash,
+                right_hash,
+            }),
+        }
+    }
+
+    /// Computes the hash of every node in the tree from scratch, as described above
+    /// `LeafNodeHashInput`. The hash of a leaf covers its public key, and the hash of a parent
+    /// covers its public key and the hashes of its children. Blank nodes are hashed too, so the
+    /// hashes also cover which nodes are blank. This is for the test vectors, and for checking the
+    /// cached hashes against.
+    ///
+    /// Returns: `Ok(hashes)` on success, where `hashes[i]` is the hash of the node at index `i`.
+    /// Returns an `Error::SerdeError` if a node can't be serialized.
+    #[cfg(any(test, feature = "gen-test-vectors"))]
+    pub(crate) fn node_hashes(&self, cs: &'static CipherSuite) -> Result<Vec<Digest>, Error> {
+        if self.nodes.is_empty() {
+            return Ok(Vec::new());
+        }
+        let ctx = self.math_ctx();
+        let mut hashes: Vec<Option<Digest>> = vec![None; self.size()];
+
+        // Every child is a level below its parent, so going a level at a time means the children's
+        // hashes are always ready. The nodes at level n are the indices of the form x011...1, with
+        // n trailing ones.
+        for level in 0..=tree_math::node_level(ctx.root()) {
+            let first_idx = (1 << level) - 1;
+            for idx in (first_idx..self.size()).step_by(1 << (level + 1)) {
+                let idx = NodeIndex(idx);
+                let hash = if level == 0 {
+                    self.hash_node(cs, idx, None)?
                 } else {
-                    // The resolution of a blank intermediate node is the result of concatinating
-                    // the resolution of its left child with the resolution of its right child, in
-                    // that order
-                    let num_leaves = tree_math::num_leaves_in_tree(tree.nodes.len());
-                    helper(tree, tree_math::node_left_child(i), acc);
-                    helper(tree, tree_math::node_right_child(i, num_leaves), acc);
+                    let left_idx = tree_math::node_left_child(idx);
+                    let right_idx = ctx.right_child(idx);
+                    let left_hash = hashes[left_idx.0].as_ref().expect("child wasn't hashed");
+                    let right_hash = hashes[right_idx.0].as_ref().expect("child wasn't hashed");
+                    self.hash_node(cs, idx, Some((left_hash.as_bytes(), right_hash.as_bytes())))?
+                };
+                hashes[idx.0] = Some(hash);
+            }
+        }
+
+        Ok(hashes.into_iter().map(|h| h.expect("node wasn't hashed")).collect())
+    }
+
+    /// Brings the cached node hashes up to date, so that `tree_hash` is cheap until the tree next
+    /// changes. Only the hashes that were dropped since the last refresh are recomputed, unless
+    /// the cache was made with another ciphersuite, in which case it starts over.
+    ///
+    /// Returns: `Ok(())` on success. Returns an `Error::SerdeError` if a node can't be serialized.
+    pub(crate) fn refresh_node_hashes(&mut self, cs: &'static CipherSuite) -> Result<(), Error> {
+        if self.hash_cache.cs_name != Some(cs.name) {
+            let digest_size = cs.hash_impl.digest_size();
+            self.hash_cache = NodeHashCache {
+                cs_name: Some(cs.name),
+                digest_size,
+                digests: vec![0u8; self.size() * digest_size],
+                current: vec![false; self.size()],
+            };
+        }
+        if self.nodes.is_empty() {
+            return Ok(());
+        }
+        let ctx = self.math_ctx();
+
+        // Same order as in node_hashes, so the children of every node we rehash are current
+        for level in 0..=tree_math::node_level(ctx.root()) {
+            let first_idx = (1 << level) - 1;
+            for idx in (first_idx..self.size()).step_by(1 << (level + 1)) {
+                let idx = NodeIndex(idx);
+                if self.hash_cache.get(cs, idx).is_some() {
+                    continue;
                 }
-            } else {
-                // The resolution of a non-blank node is a one element list containing the node
-                // itself
-                acc.push(i);
+                let hash = if level == 0 {
+                    self.hash_node(cs, idx, None)?
+                } else {
+                    let left_idx = tree_math::node_left_child(idx);
+                    let right_idx = ctx.right_child(idx);
+                    let left_hash = self.hash_cache.get(cs, left_idx).expect("child wasn't hashed");
+                    let right_hash =
+                        self.hash_cache.get(cs, right_idx).expect("child wasn't hashed");
+                    self.hash_node(cs, idx, Some((left_hash, right_hash)))?
+                };
+                self.hash_cache.set(idx, &hash);
             }
         }
 
-        let mut ret = Vec::new();
-        helper(self, idx, &mut ret);
-        ret
+        Ok(())
+    }
+
+    // Returns the hash of the node at `idx`, from the cache if it's current there, and from the
+    // hashes of its children otherwise
+    fn cached_node_hash(
+        &self,
+        cs: &'static CipherSuite,
+        ctx: &TreeMathContext,
+        idx: NodeIndex,
+    ) -> Result<Digest, Error> {
+        if let Some(hash) = self.hash_cache.get(cs, idx) {
+            return Digest::new_from_bytes(cs.hash_impl, hash);
+        }
+
+        if idx.is_leaf() {
+            self.hash_node(cs, idx, None)
+        } else {
+            let left_hash = self.cached_node_hash(cs, ctx, tree_math::node_left_child(idx))?;
+            let right_hash = self.cached_node_hash(cs, ctx, ctx.right_child(idx))?;
+            self.hash_node(cs, idx, Some((left_hash.as_bytes(), right_hash.as_bytes())))
+        }
+    }
+
+    /// Computes the tree hash, i.e., the hash of the root node. See `node_hashes`. This uses the
+    /// cached node hashes that are still current, so right after `refresh_node_hashes`, it only
+    /// has to hash the nodes that have changed since.
+    ///
+    /// Returns: `Ok(tree_hash)` on success. Returns an `Error::ValidationError` if the tree is
+    /// empty, and an `Error::SerdeError` if a node can't be serialized.
+    pub(crate) fn tree_hash(&self, cs: &'static CipherSuite) -> Result<Digest, Error> {
+        if self.nodes.is_empty() {
+            return Err(Error::ValidationError("Cannot compute the tree hash of an empty tree"));
+        }
+        let ctx = self.math_ctx();
+        self.cached_node_hash(cs, &ctx, ctx.root())
     }
 
     /// Overwrites all the public keys in the extended (including root) direct path of
@@ -281,19 +806,17 @@ impl RatchetTree {
     ///
     /// Returns: `Ok(())` on success. Returns `Error::ValidationError` if the direct path range of
     /// `[start_tree_idx, stop_before_tree_idx)` is longer than the `public_keys` iterator.
-    #[must_use]
     pub(crate) fn set_public_keys_with_bound<'a, I: Iterator<Item = &'a DhPublicKey>>(
         &mut self,
-        start_tree_idx: usize,
-        stop_before_tree_idx: usize,
+        start_tree_idx: NodeIndex,
+        stop_before_tree_idx: NodeIndex,
         mut public_keys: I,
     ) -> Result<(), Error> {
-        let num_leaves = tree_math::num_leaves_in_tree(self.size());
         // Update all the public keys of the nodes in the direct path that are below our common
         // ancestor, i.e., all the ones whose secret we don't know. Note that this step is not
         // performed in apply_update, because this only happens when we're not the ones who created
         // the Update operation.
-        let sender_direct_path = tree_math::node_extended_direct_path(start_tree_idx, num_leaves);
+        let sender_direct_path = self.math_ctx().extended_direct_path(start_tree_idx);
         for path_node_idx in sender_direct_path {
             let pubkey = public_keys.next().ok_or(Error::ValidationError(
                 "Partial direct path is longer than public key iterator",
@@ -319,18 +842,16 @@ impl RatchetTree {
     /// path. Returns some sort of `Error::ValidationError` otherwise.
     pub(crate) fn validate_direct_path_public_keys<'a, I>(
         &self,
-        start_idx: usize,
+        start_idx: NodeIndex,
         mut expected_public_keys: I,
     ) -> Result<(), Error>
     where
         I: Iterator<Item = &'a DhPublicKey>,
     {
-        let num_leaves = tree_math::num_leaves_in_tree(self.size());
-
         // Verify that the pubkeys in the message agree with our newly-derived pubkeys all the way
         // up the tree (including the root node). We go through the iterators in lock-step. If one
         // is longer than the other, that's a problem, and we throw and error.
-        let mut ext_direct_path = tree_math::node_extended_direct_path(start_idx, num_leaves);
+        let mut ext_direct_path = self.math_ctx().extended_direct_path(start_idx);
         loop {
             match (ext_direct_path.next(), expected_public_keys.next()) {
                 (Some(path_node_idx), Some(expected_pubkey)) => {
@@ -368,34 +889,42 @@ impl RatchetTree {
     /// appropriately ratcheted path secret for the rest of the ratchet tree. See section
     /// 5.2 in the spec for details.
     ///
+    /// If `sender_secret` is given, the path secrets are encrypted with authenticated ECIES under
+    /// that key. See `PathEncryption`.
+    ///
     /// Requires: `starting_tree_idx` to be a leaf node. Otherwise, any child of ours would be
     /// unable to decrypt this message.
     pub(crate) fn encrypt_direct_path_secrets<R>(
         &self,
         cs: &'static CipherSuite,
-        starting_tree_idx: usize,
+        starting_tree_idx: NodeIndex,
         starting_path_secret: PathSecret,
+        sender_secret: Option<&DhPrivateKey>,
         csprng: &mut R,
     ) -> Result<DirectPathMessage, Error>
     where
         R: CryptoRng,
     {
         // Check if it's a leaf node
-        if starting_tree_idx % 2 != 0 {
-            return Err(Error::TreeError("Cannot encrypt direct paths of non-leaf nodes"));
+        if !starting_tree_idx.is_leaf() {
+            let msg = "Cannot encrypt direct paths of non-leaf nodes";
+            let err = self.error("encrypt_direct_path_secrets", msg);
+            return Err(err.with_node("starting node", starting_tree_idx).into());
         }
 
-        let num_leaves = tree_math::num_leaves_in_tree(self.size());
-        let direct_path = tree_math::node_direct_path(starting_tree_idx as usize, num_leaves);
+        let ctx = self.math_ctx();
+        let direct_path = ctx.direct_path(starting_tree_idx);
 
-        let mut node_messages = Vec::new();
+        // There's one message per node in the extended direct path, and no path is longer than
+        // the root's level plus one
+        let mut node_messages = Vec::with_capacity(tree_math::node_level(ctx.root()) + 1);
 
         // The first message should be just the starting node's pubkey and no encrypted messages
         let (starting_node_public_key, _, _, mut parent_path_secret) =
             utils::derive_node_values(cs, starting_path_secret)?;
         node_messages.push(DirectPathNodeMessage {
-            public_key: starting_node_public_key.clone(),
-            node_secrets: Vec::with_capacity(0),
+            public_key: starting_node_public_key,
+            node_secrets: Vec::new(),
         });
 
         // Go up the direct path of the starting index
@@ -406,27 +935,33 @@ impl RatchetTree {
                 utils::derive_node_values(cs, parent_path_secret.clone())?;
 
             // Encrypt the path secret at the current node's parent for everyone in the resolution
-            // of the copath node. We can unwrap() here because self.resolution only returns
-            // indices that are actually in the tree.
-            let mut encrypted_path_secrets = Vec::new();
-            let copath_node_idx = tree_math::node_sibling(path_node_idx, num_leaves);
-            for res_node in self.resolution(copath_node_idx).iter().map(|&i| &self.nodes[i]) {
-                // We can unwrap() here because self.resolution only returns indices of nodes
-                // that are non-blank, by definition of "resolution"
-                let others_public_key = res_node.get_public_key().unwrap();
-                // Encrypt the parent's path secret with the resolution node's pubkey
-                let ciphertext = ecies::encrypt(
-                    cs,
-                    others_public_key,
-                    parent_path_secret.as_bytes().to_vec(), // TODO: Make this not copy secrets
-                    csprng,
-                )?;
-                encrypted_path_secrets.push(ciphertext);
-            }
+            // of the copath node. We walk the resolution as we go rather than collecting it first.
+            let copath_node_idx = ctx.sibling(path_node_idx);
+            let encrypted_path_secrets = self
+                .resolution_iter(&ctx, copath_node_idx)
+                .map(|res_node_idx| {
+                    // We can unwrap() here because the resolution only contains indices of nodes
+                    // that are in the tree and non-blank, by definition of "resolution"
+                    let others_public_key = self.nodes[res_node_idx.0].get_public_key().unwrap();
+                    // Encrypt the parent's path secret with the resolution node's pubkey
+                    // TODO: Make this not copy secrets
+                    let plaintext = parent_path_secret.as_bytes().to_vec();
+                    match sender_secret {
+                        Some(sender_secret) => ecies::encrypt_authenticated(
+                            cs,
+                            others_public_key,
+                            sender_secret,
+                            plaintext,
+                            csprng,
+                        ),
+                        None => ecies::encrypt(cs, others_public_key, plaintext, csprng),
+                    }
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
 
             // Push the collection to the message list
             node_messages.push(DirectPathNodeMessage {
-                public_key: parent_public_key.clone(),
+                public_key: parent_public_key,
                 node_secrets: encrypted_path_secrets,
             });
 
@@ -441,59 +976,69 @@ impl RatchetTree {
 
     /// Finds the (unique) ciphertext in the given direct path message that is meant for this
     /// member and decrypts it. `starting_node_idx` is the the index of the starting node of the
-    /// encoded direct path.
+    /// encoded direct path. If `sender_public_key` is given, the ciphertext must have been made by
+    /// `encrypt_direct_path_secrets` with the corresponding `sender_secret`.
     ///
     /// Requires: `starting_tree_idx` cannot be an ancestor of `my_tree_idx`, nor vice-versa. We
     /// cannot decrypt messages that violate this.
     ///
     /// Returns: `Ok((pt, idx))` where `pt` is the `Result` of decrypting the found ciphertext and
-    /// `idx` is the common ancestor of `starting_tree_idx` and `my_tree_idx`. If no decryptable
-    /// ciphertext exists, returns an `Error::TreeError`. If decryption fails, returns an
+    /// `idx` is the common ancestor of `starting_tree_idx` and `my_tree_idx`. If the message
+    /// doesn't have the shape `validate_direct_path_message` expects, or no decryptable ciphertext
+    /// exists, returns an `Error::TreeError`. If decryption fails, returns an
     /// `Error::EncryptionError`.
     pub(crate) fn decrypt_direct_path_message(
         &self,
         cs: &'static CipherSuite,
         direct_path_msg: &DirectPathMessage,
-        starting_tree_idx: usize,
-        my_tree_idx: usize,
-    ) -> Result<(PathSecret, usize), Error> {
-        let num_leaves = tree_math::num_leaves_in_tree(self.size());
+        starting_tree_idx: NodeIndex,
+        my_tree_idx: NodeIndex,
+        sender_public_key: Option<&DhPublicKey>,
+    ) -> Result<(PathSecret, NodeIndex), Error> {
+        // Everything that goes wrong in here involves these two nodes
+        let error = |msg| {
+            self.error("decrypt_direct_path_message", msg)
+                .with_node("starting node", starting_tree_idx)
+                .with_node("receiving node", my_tree_idx)
+        };
 
-        if starting_tree_idx >= self.size() || my_tree_idx >= self.size() {
-            return Err(Error::TreeError("Input index out of range"));
+        if starting_tree_idx.0 >= self.size() || my_tree_idx.0 >= self.size() {
+            return Err(error("Input index out of range").into());
         }
 
-        if tree_math::is_ancestor(starting_tree_idx, my_tree_idx, num_leaves)
-            || tree_math::is_ancestor(my_tree_idx, starting_tree_idx, num_leaves)
+        let ctx = self.math_ctx();
+        if ctx.is_ancestor(starting_tree_idx, my_tree_idx)
+            || ctx.is_ancestor(my_tree_idx, starting_tree_idx)
         {
-            return Err(Error::TreeError("Cannot decrypt messages from ancestors or descendants"));
+            return Err(error("Cannot decrypt messages from ancestors or descendants").into());
         }
+        // Check the shape of the whole message before looking for our ciphertext in it
+        self.validate_direct_path_message(&ctx, direct_path_msg, starting_tree_idx)?;
 
         // This is the intermediate node in the direct path whose secret was encrypted for us.
-        let common_ancestor_idx =
-            tree_math::common_ancestor(starting_tree_idx, my_tree_idx, num_leaves);
+        let common_ancestor_idx = ctx.common_ancestor(starting_tree_idx, my_tree_idx);
 
         // This holds the secret of the intermediate node, encrypted for all the nodes in the
         // resolution of the copath node.
         let node_msg = {
             // To get this value, we have to figure out the correct index into node_message
-            let (pos_in_msg_vec, _) =
-                tree_math::node_extended_direct_path(starting_tree_idx, num_leaves)
-                    .enumerate()
-                    .find(|&(_, dp_idx)| dp_idx == common_ancestor_idx)
-                    .expect("common ancestor somehow did not appear in direct path");
-            direct_path_msg
-                .node_messages
-                .get(pos_in_msg_vec)
-                .ok_or(Error::TreeError("Malformed DirectPathMessage"))?
+            let (pos_in_msg_vec, _) = ctx
+                .extended_direct_path(starting_tree_idx)
+                .enumerate()
+                .find(|&(_, dp_idx)| dp_idx == common_ancestor_idx)
+                .expect("common ancestor somehow did not appear in direct path");
+            direct_path_msg.node_messages.get(pos_in_msg_vec).ok_or_else(|| {
+                error("Malformed DirectPathMessage")
+                    .with_node("common ancestor", common_ancestor_idx)
+            })?
         };
 
         // This is the unique acnestor of the receiver that is in the copath of the sender. This is
         // the one whose resolution is used.
         let copath_ancestor_idx = {
             let left = tree_math::node_left_child(common_ancestor_idx);
-            let right = tree_math::node_right_child(common_ancestor_idx, num_leaves);
-            if tree_math::is_ancestor(left, my_tree_idx, num_leaves) {
+            let right = ctx.right_child(common_ancestor_idx);
+            if ctx.is_ancestor(left, my_tree_idx) {
                 left
             } else {
                 right
@@ -503,32 +1048,107 @@ impl RatchetTree {
         // We're looking for an ancestor in the resolution of this copath node. There is
         // only one such node. Furthermore, we should already know the private key of the
         // node that we find. So our strategy is to look for a node with a private key that
-        // we know, then make sure that it is our ancestor.
-        let resolution = self.resolution(copath_ancestor_idx);
-
-        // Comb the resolution for a node whose private key we know
-        for (pos_in_res, res_node_idx) in resolution.into_iter().enumerate() {
+        // we know, then make sure that it is our ancestor. We walk the entire resolution and pick
+        // out the match with conditional assignments rather than stopping when we find it, so
+        // that how long this takes doesn't say where in the resolution we are.
+        let mut found = Choice::from(0u8);
+        let mut found_pos = 0u64;
+        let mut found_node_idx = 0u64;
+        for (pos_in_res, res_node_idx) in
+            self.resolution_iter(&ctx, copath_ancestor_idx).enumerate()
+        {
             let res_node = self.get(res_node_idx).expect("resolution out of bounds");
-            if res_node.get_private_key().is_some()
-                && tree_math::is_ancestor(res_node_idx, my_tree_idx, num_leaves)
-            {
-                // We found the ancestor in the resolution. Now get the decryption key and
-                // corresponding ciphertext
-                let decryption_key = res_node.get_private_key().unwrap();
-                let ciphertext_for_me = node_msg
-                    .node_secrets
-                    .get(pos_in_res)
-                    .ok_or(Error::TreeError("Malformed DirectPathMessage"))?;
-
-                // Finally, decrypt the thing and return the plaintext and common ancestor
-                let plaintext = ecies::decrypt(cs, decryption_key, ciphertext_for_me.clone())?;
-                let path_secret = PathSecret::new_from_bytes(&plaintext);
-                return Ok((path_secret, common_ancestor_idx));
-            }
+            let knows_key = res_node.get_private_key().is_some() as u8;
+            let is_ancestor = ctx.is_ancestor(res_node_idx, my_tree_idx) as u8;
+            let is_match = Choice::from(knows_key & is_ancestor);
+
+            found_pos.conditional_assign(&(pos_in_res as u64), is_match);
+            found_node_idx.conditional_assign(&(res_node_idx.0 as u64), is_match);
+            found |= is_match;
         }
 
         // With the checks at the beginning of this method, this should never happen
-        Err(Error::TreeError("Cannot find node in resolution with known private key"))
+        if !bool::from(found) {
+            let msg = "Cannot find node in resolution with known private key";
+            return Err(error(msg).with_node("copath node", copath_ancestor_idx).into());
+        }
+        let res_node_idx = NodeIndex(found_node_idx as usize);
+
+        // We found the ancestor in the resolution. Now get the decryption key and corresponding
+        // ciphertext
+        let decryption_key = self
+            .get(res_node_idx)
+            .and_then(RatchetTreeNode::get_private_key)
+            .expect("matched resolution node has no private key");
+        let ciphertext_for_me = node_msg.node_secrets.get(found_pos as usize).ok_or_else(|| {
+            error("Malformed DirectPathMessage")
+                .with_node("copath node", copath_ancestor_idx)
+                .with_node("resolution node", res_node_idx)
+        })?;
+
+        // Finally, decrypt the thing and return the plaintext and common ancestor
+        let ciphertext_for_me = ciphertext_for_me.clone();
+        let plaintext = match sender_public_key {
+            Some(sender_public_key) => ecies::decrypt_authenticated(
+                cs,
+                decryption_key,
+                sender_public_key,
+                ciphertext_for_me,
+            )?,
+            None => ecies::decrypt(cs, decryption_key, ciphertext_for_me)?,
+        };
+        let path_secret = PathSecret::new_from_bytes(&plaintext);
+        Ok((path_secret, common_ancestor_idx))
+    }
+
+    /// Checks that a `DirectPathMessage` starting at `starting_tree_idx` has the shape that
+    /// `encrypt_direct_path_secrets` would give it in this tree. That is, there's one node message
+    /// per node in the extended direct path of `starting_tree_idx`, the first node message has no
+    /// secrets, and every other one has one secret per node in the resolution of the copath node
+    /// it's for.
+    ///
+    /// Requires: `starting_tree_idx` is in the tree
+    ///
+    /// Returns: `Ok(())` if the message has the right shape. Otherwise returns an
+    /// `Error::TreeError` that says what's wrong with it.
+    pub(crate) fn validate_direct_path_message(
+        &self,
+        ctx: &TreeMathContext,
+        direct_path_msg: &DirectPathMessage,
+        starting_tree_idx: NodeIndex,
+    ) -> Result<(), Error> {
+        let error = |msg| {
+            self.error("validate_direct_path_message", msg)
+                .with_node("starting node", starting_tree_idx)
+        };
+
+        let node_messages = &direct_path_msg.node_messages;
+        if node_messages.len() != ctx.extended_direct_path(starting_tree_idx).count() {
+            return Err(
+                error("DirectPathMessage length doesn't match the sender's direct path").into()
+            );
+        }
+
+        // We just checked that there's a first message
+        if !node_messages[0].node_secrets.is_empty() {
+            return Err(error("DirectPathMessage has secrets for the sender's own node").into());
+        }
+
+        // The ith message after the first is the secret of the ith parent up from the sender,
+        // encrypted to the resolution of the ith node's sibling. This is exactly how they were
+        // made in encrypt_direct_path_secrets.
+        for (path_node_idx, node_msg) in
+            ctx.direct_path(starting_tree_idx).zip(node_messages.iter().skip(1))
+        {
+            let copath_node_idx = ctx.sibling(path_node_idx);
+            let resolution_size = self.resolution_iter(ctx, copath_node_idx).count();
+            if node_msg.node_secrets.len() != resolution_size {
+                let msg = "DirectPathMessage secret count doesn't match the copath resolution size";
+                return Err(error(msg).with_node("copath node", copath_node_idx).into());
+            }
+        }
+
+        Ok(())
     }
 
     /// Updates the path secret at the given index and derives the path secrets, node secrets,
@@ -546,10 +1166,10 @@ impl RatchetTree {
         &mut self,
         cs: &'static CipherSuite,
         mut path_secret: PathSecret,
-        start_idx: usize,
+        start_idx: NodeIndex,
     ) -> Result<NodeSecret, Error> {
-        let num_leaves = tree_math::num_leaves_in_tree(self.size());
-        let root_node_idx = tree_math::root_idx(num_leaves);
+        let ctx = self.math_ctx();
+        let root_node_idx = ctx.root();
 
         let mut current_node_idx = start_idx;
 
@@ -574,13 +1194,67 @@ impl RatchetTree {
                 break node_secret;
             } else {
                 // Otherwise, take one step up the tree
-                current_node_idx = tree_math::node_parent(current_node_idx, num_leaves);
+                current_node_idx = ctx.parent(current_node_idx);
                 path_secret = new_path_secret;
             }
         };
 
         Ok(root_node_secret)
     }
+
+    /// Appends a new leaf to the tree, and sets it and its direct path from `path_secret`, the
+    /// same way an Update from the new leaf would. The result depends only on the tree and
+    /// `path_secret`, which is what the tree test vectors need to build their trees.
+    ///
+    /// Requires: `path_secret.len() == cs.hash_alg.output_len`
+    ///
+    /// Panics: If above condition is not satisfied
+    ///
+    /// Returns: `Ok(node_secret)` on success, where `node_secret` is the node secret of the root
+    /// node of the updated ratchet tree.
+    #[cfg(any(test, feature = "gen-test-vectors"))]
+    pub(crate) fn add_leaf_with_path_secret(
+        &mut self,
+        cs: &'static CipherSuite,
+        path_secret: PathSecret,
+    ) -> Result<NodeSecret, Error> {
+        let leaf_idx = LeafIndex(self.leaf_count());
+        self.add_leaf_node(RatchetTreeNode::Blank);
+        self.propagate_new_path_secret(cs, path_secret, leaf_idx.node_index())
+    }
+}
+
+/// An iterator over the resolution of a node, in ascending index order. This walks the node's
+/// subtree depth-first with an explicit stack rather than recursing, and never allocates beyond
+/// that stack. See `RatchetTree::resolution_iter`.
+struct ResolutionIter<'a> {
+    tree: &'a RatchetTree,
+    ctx: TreeMathContext,
+    // The subtrees we have yet to visit. The one on top is the leftmost.
+    stack: Vec<NodeIndex>,
+}
+
+impl<'a> Iterator for ResolutionIter<'a> {
+    type Item = NodeIndex;
+
+    fn next(&mut self) -> Option<NodeIndex> {
+        while let Some(i) = self.stack.pop() {
+            if self.tree.nodes[i.0].is_filled() {
+                // The resolution of a non-blank node is a one element list containing the node
+                // itself
+                return Some(i);
+            } else if !i.is_leaf() {
+                // The resolution of a blank intermediate node is the result of concatinating the
+                // resolution of its left child with the resolution of its right child, in that
+                // order. So the left child goes on top.
+                self.stack.push(self.ctx.right_child(i));
+                self.stack.push(tree_math::node_left_child(i));
+            }
+            // The resolution of a blank leaf node is the empty list
+        }
+
+        None
+    }
 }
 
 #[cfg(test)]
@@ -641,11 +1315,195 @@ mod test {
         cases: Vec<ResolutionCase>,
     }
 
+    // The following test vector is in the format that `test_vectors::tree_test_vectors` generates.
+    // There's no official one for this version of the protocol.
+    //
+    // File: tree.bin
+    //
+    // opaque LeafSecret<0..255>;
+    //
+    // struct {
+    //   optional<DHPublicKey> public_key;
+    //   opaque hash<0..255>;
+    // } TreeNode;
+    //
+    // struct {
+    //   opaque root_secret<0..255>;
+    //   TreeNode nodes<0..2^32-1>;
+    // } TreeCase;
+    //
+    // struct {
+    //   uint32_t n_leaves;
+    //   CipherSuite cipher_suite;
+    //   LeafSecret leaf_secrets<0..2^32-1>;
+    //   TreeCase cases<0..2^32-1>;
+    // } TreeTestVectors;
+    //
+    // These vectors represent a tree built up one leaf at a time, starting from an empty tree.
+    //
+    // * There are n_leaves entries in both leaf_secrets and cases
+    // * Step i appends a leaf to the tree, and then sets the new leaf and its direct path from the
+    //   path secret leaf_secrets[i], the same way an Update from that leaf would
+    // * cases[i] is the tree after step i: the public key and hash of every node, in order, and
+    //   the node secret of the root
+    // * Node hashes are as described above LeafNodeHashInput
+
+    #[derive(Debug, Deserialize)]
+    #[serde(rename = "LeafSecret__bound_u8")]
+    struct LeafSecret(Vec<u8>);
+
+    #[derive(Debug, Deserialize)]
+    struct TreeNode {
+        public_key: RatchetTreeNode,
+        hash: Digest,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct TreeCase {
+        #[serde(rename = "root_secret__bound_u8")]
+        root_secret: Vec<u8>,
+        #[serde(rename = "nodes__bound_u32")]
+        nodes: Vec<TreeNode>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct TreeTestVectors {
+        num_leaves: u32,
+        cipher_suite: &'static CipherSuite,
+        #[serde(rename = "leaf_secrets__bound_u32")]
+        leaf_secrets: Vec<LeafSecret>,
+        #[serde(rename = "cases__bound_u32")]
+        cases: Vec<TreeCase>,
+    }
+
+    // Checks that add_leaf_at extends the tree at the end, fills blank leaves in place, and refuses
+    // everything else
+    #[quickcheck]
+    fn add_leaf_at_placement(num_leaves: u8, rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let num_leaves = core::cmp::max(num_leaves as usize, 2);
+        let cs: &'static CipherSuite = &X25519_SHA256_AES128GCM;
+        let new_node = |rng: &mut rand::rngs::StdRng| {
+            RatchetTreeNode::new_from_private_key(
+                cs,
+                DhPrivateKey::new_from_random(cs.dh_impl, rng).unwrap(),
+            )
+        };
+
+        // Grow a tree one leaf at a time, always at the extension position
+        let mut tree = RatchetTree::new(Vec::new());
+        for i in 0..num_leaves {
+            tree.add_leaf_at(LeafIndex(i), new_node(&mut rng)).unwrap();
+        }
+        assert_eq!(tree.size(), tree_math::num_nodes_in_tree(num_leaves));
+
+        // Fill in all the parents so we can see them get blanked
+        let path_secret = PathSecret::new_from_bytes(&[0u8; 32]);
+        tree.propagate_new_path_secret(cs, path_secret, NodeIndex(0)).unwrap();
+
+        // Filled leaves and anything past the extension position are off limits
+        let leaf_idx = LeafIndex(rng.gen_range(0, num_leaves));
+        assert!(tree.add_leaf_at(leaf_idx, new_node(&mut rng)).is_err());
+        assert!(tree.add_leaf_at(LeafIndex(num_leaves + 1), new_node(&mut rng)).is_err());
+
+        // Blank a leaf out and put something back in its place. Everything above it should be
+        // blank afterwards.
+        let node_idx = leaf_idx.node_index();
+        tree.nodes[node_idx.0] = RatchetTreeNode::Blank;
+        tree.add_leaf_at(leaf_idx, new_node(&mut rng)).unwrap();
+        assert_eq!(tree.size(), tree_math::num_nodes_in_tree(num_leaves));
+        assert!(tree.get(node_idx).unwrap().is_filled());
+        for idx in tree_math::node_extended_direct_path(node_idx, num_leaves).skip(1) {
+            assert!(!tree.get(idx).unwrap().is_filled());
+        }
+    }
+
+    // Checks that the leaf iterators see exactly the even indices, that occupied_leaves skips the
+    // blank ones, and that truncate_to_last_nonblank stops at the last occupied leaf
+    #[quickcheck]
+    fn leaf_iteration(num_leaves: u8, rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let num_leaves = num_leaves as usize;
+        let cs: &'static CipherSuite = &X25519_SHA256_AES128GCM;
+
+        // Make a tree where each leaf is filled with probability 1/2
+        let mut tree = RatchetTree::new(Vec::new());
+        assert_eq!(tree.leaf_count(), 0);
+        let mut expected_occupied = Vec::new();
+        for i in 0..num_leaves {
+            if rng.gen() {
+                let privkey = DhPrivateKey::new_from_random(cs.dh_impl, &mut rng).unwrap();
+                tree.add_leaf_node(RatchetTreeNode::new_from_private_key(cs, privkey));
+                expected_occupied.push(LeafIndex(i).node_index());
+            } else {
+                tree.add_leaf_node(RatchetTreeNode::Blank);
+            }
+        }
+
+        assert_eq!(tree.leaf_count(), num_leaves);
+        let leaf_indices: Vec<NodeIndex> = tree.leaves().map(|(idx, _)| idx).collect();
+        let expected_leaf_indices: Vec<NodeIndex> =
+            (0..num_leaves).map(|i| LeafIndex(i).node_index()).collect();
+        assert_eq!(leaf_indices, expected_leaf_indices);
+        let occupied: Vec<NodeIndex> = tree.occupied_leaves().map(|(idx, _)| idx).collect();
+        assert_eq!(occupied, expected_occupied);
+
+        // Truncating leaves the last occupied leaf at the end of the tree, or clears it if there
+        // are none
+        tree.truncate_to_last_nonblank();
+        match expected_occupied.last() {
+            Some(&last) => assert_eq!(tree.size(), last.0 + 1),
+            None => assert_eq!(tree.leaf_count(), 0),
+        }
+    }
+
+    // Checks render_ascii against a hand-drawn tree
+    #[cfg(feature = "tree-render")]
+    #[test]
+    fn render_ascii_kat() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let cs: &'static CipherSuite = &X25519_SHA256_AES128GCM;
+        let mut known_node = || {
+            let privkey = DhPrivateKey::new_from_random(cs.dh_impl, &mut rng).unwrap();
+            RatchetTreeNode::new_from_private_key(cs, privkey)
+        };
+        // Forget the private key on a node
+        let public_only = |node: RatchetTreeNode| match node {
+            RatchetTreeNode::Filled {
+                public_key,
+                ..
+            } => RatchetTreeNode::Filled {
+                public_key,
+                private_key: None,
+            },
+            RatchetTreeNode::Blank => RatchetTreeNode::Blank,
+        };
+
+        let tree = RatchetTree::new(vec![
+            known_node(),
+            known_node(),
+            public_only(known_node()),
+            RatchetTreeNode::Blank,
+            public_only(known_node()),
+        ]);
+        #[rustfmt::skip]
+        let expected = concat!(
+            "          _\n",
+            "    #\n",
+            " #     o     o\n",
+            " 0  1  2  3  4\n",
+        );
+        assert_eq!(tree.render_ascii(), expected);
+
+        let empty = RatchetTree::new(Vec::new());
+        assert_eq!(empty.render_ascii(), "(empty tree)\n");
+    }
+
     // Test that decrypt_direct_path_message is the inverse of encrypt_direct_path_secrets
     #[quickcheck]
     fn direct_path_message_correctness(num_leaves: u8, rng_seed: u64) {
         // Turns out this test is super slow
-        if num_leaves > 50 || num_leaves < 2 {
+        if !(2..=50).contains(&num_leaves) {
             return;
         }
 
@@ -654,9 +1512,7 @@ mod test {
         let num_nodes = tree_math::num_nodes_in_tree(num_leaves);
 
         // Fill a tree with Blanks
-        let mut tree = RatchetTree {
-            nodes: Vec::new(),
-        };
+        let mut tree = RatchetTree::new(Vec::new());
         for _ in 0..num_leaves {
             tree.add_leaf_node(RatchetTreeNode::Blank);
         }
@@ -665,8 +1521,8 @@ mod test {
         let cs: &'static CipherSuite = &X25519_SHA256_AES128GCM;
         for i in 0..num_leaves {
             // This is the index of a leaf in the tree
-            let tree_idx = 2 * i;
-            let initial_path_secret = PathSecret::new_from_bytes(&vec![i as u8; 32]);
+            let tree_idx = LeafIndex(i).node_index();
+            let initial_path_secret = PathSecret::new_from_bytes(&[i as u8; 32]);
             tree.propagate_new_path_secret(cs, initial_path_secret, tree_idx).unwrap();
         }
 
@@ -675,9 +1531,9 @@ mod test {
         // the decryption function requires it. Also the receiver cannot be an ancestor of the
         // sender, because then it doesn't lie in the copath (and also it would have no need to
         // decrypt the message, since it knows its own secret)
-        let sender_tree_idx = 2 * rng.gen_range(0, num_leaves);
+        let sender_tree_idx = LeafIndex(rng.gen_range(0, num_leaves)).node_index();
         let receiver_tree_idx = loop {
-            let idx = rng.gen_range(0, num_nodes);
+            let idx = NodeIndex(rng.gen_range(0, num_nodes));
             if idx != sender_tree_idx && !tree_math::is_ancestor(idx, sender_tree_idx, num_leaves) {
                 break idx;
             }
@@ -690,13 +1546,69 @@ mod test {
             PathSecret::new_from_bytes(&buf)
         };
         let direct_path_msg = tree
-            .encrypt_direct_path_secrets(cs, sender_tree_idx, sender_path_secret.clone(), &mut rng)
+            .encrypt_direct_path_secrets(
+                cs,
+                sender_tree_idx,
+                sender_path_secret.clone(),
+                None,
+                &mut rng,
+            )
             .expect("failed to encrypt direct path secrets");
         // Decrypt the path secret closest to the receiver
         let (derived_path_secret, common_ancestor_idx) = tree
-            .decrypt_direct_path_message(cs, &direct_path_msg, sender_tree_idx, receiver_tree_idx)
+            .decrypt_direct_path_message(
+                cs,
+                &direct_path_msg,
+                sender_tree_idx,
+                receiver_tree_idx,
+                None,
+            )
             .expect("failed to decrypt direct path secret");
 
+        // Encrypting with the sender's leaf key gives the same path secret, but only to a receiver
+        // who expects that key
+        let sender_node = tree.get(sender_tree_idx).unwrap();
+        let sender_secret = sender_node.get_private_key().unwrap();
+        let sender_public_key = sender_node.get_public_key().unwrap();
+        let authenticated_msg = tree
+            .encrypt_direct_path_secrets(
+                cs,
+                sender_tree_idx,
+                sender_path_secret.clone(),
+                Some(sender_secret),
+                &mut rng,
+            )
+            .unwrap();
+        let (authenticated_path_secret, _) = tree
+            .decrypt_direct_path_message(
+                cs,
+                &authenticated_msg,
+                sender_tree_idx,
+                receiver_tree_idx,
+                Some(sender_public_key),
+            )
+            .unwrap();
+        assert_eq!(authenticated_path_secret.0, derived_path_secret.0);
+        let someone_else = DhPrivateKey::new_from_random(cs.dh_impl, &mut rng).unwrap();
+        let someone_elses_key = DhPublicKey::new_from_private_key(cs.dh_impl, &someone_else);
+        for (msg, expected_key) in [
+            (&authenticated_msg, Some(&someone_elses_key)),
+            (&authenticated_msg, None),
+            (&direct_path_msg, Some(sender_public_key)),
+        ]
+        .iter()
+        {
+            assert!(tree
+                .decrypt_direct_path_message(
+                    cs,
+                    msg,
+                    sender_tree_idx,
+                    receiver_tree_idx,
+                    *expected_key,
+                )
+                .is_err());
+        }
+
         // Make sure it really is the common ancestor
         assert_eq!(
             common_ancestor_idx,
@@ -721,16 +1633,129 @@ mod test {
         assert_eq!(derived_path_secret.0, expected_path_secret.0);
     }
 
+    // Checks that DirectPathMessages with the wrong number of node messages or secrets are caught
+    // before anything is decrypted, and that well-formed ones pass
+    #[quickcheck]
+    fn direct_path_message_structure(num_leaves: u8, rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let num_leaves = core::cmp::max(num_leaves as usize % 50, 2);
+        let cs: &'static CipherSuite = &X25519_SHA256_AES128GCM;
+
+        // Fill every leaf and propagate a path from the first one, so the resolutions vary
+        let mut tree = RatchetTree::new(Vec::new());
+        for _ in 0..num_leaves {
+            let privkey = DhPrivateKey::new_from_random(cs.dh_impl, &mut rng).unwrap();
+            tree.add_leaf_node(RatchetTreeNode::new_from_private_key(cs, privkey));
+        }
+        let path_secret = PathSecret::new_from_bytes(&[0u8; 32]);
+        tree.propagate_new_path_secret(cs, path_secret, NodeIndex(0)).unwrap();
+
+        let ctx = tree.math_ctx();
+        let sender_tree_idx = LeafIndex(rng.gen_range(0, num_leaves)).node_index();
+        // DirectPathMessages aren't Clone, so we make a fresh one for every check
+        let mut make_msg = || {
+            let path_secret = PathSecret::new_from_bytes(&[1u8; 32]);
+            tree.encrypt_direct_path_secrets(cs, sender_tree_idx, path_secret, None, &mut rng)
+                .unwrap()
+        };
+        let msg = make_msg();
+        tree.validate_direct_path_message(&ctx, &msg, sender_tree_idx).unwrap();
+
+        // Dropping the last node message makes it too short
+        let mut short_msg = make_msg();
+        short_msg.node_messages.pop();
+        assert!(tree.validate_direct_path_message(&ctx, &short_msg, sender_tree_idx).is_err());
+
+        // Moving a ciphertext from one node message to another breaks the secret counts
+        let mut shuffled_msg = make_msg();
+        let secret = shuffled_msg.node_messages[1].node_secrets.pop().unwrap();
+        shuffled_msg.node_messages[0].node_secrets.push(secret);
+        assert!(tree.validate_direct_path_message(&ctx, &shuffled_msg, sender_tree_idx).is_err());
+        let mut extra_msg = make_msg();
+        let secret = extra_msg.node_messages[1].node_secrets[0].clone();
+        extra_msg.node_messages[1].node_secrets.push(secret);
+        assert!(tree.validate_direct_path_message(&ctx, &extra_msg, sender_tree_idx).is_err());
+
+        // Decryption refuses it up front, and says which copath node's secrets were off. That's
+        // the sibling of the sender, since we added to the secrets for the sender's parent.
+        let receiver_tree_idx = ctx.sibling(sender_tree_idx);
+        match tree.decrypt_direct_path_message(
+            cs,
+            &extra_msg,
+            sender_tree_idx,
+            receiver_tree_idx,
+            None,
+        ) {
+            Err(Error::TreeError(err)) => {
+                assert_eq!(err.operation, "validate_direct_path_message");
+                assert_eq!(err.tree_size, tree.size());
+                assert_eq!(
+                    err.nodes,
+                    vec![("starting node", sender_tree_idx), ("copath node", receiver_tree_idx)]
+                );
+            }
+            _ => panic!("decrypted a malformed DirectPathMessage"),
+        }
+    }
+
+    // Checks that the resolution of every node in a randomly blanked tree is an ascending list of
+    // filled descendants that covers each filled leaf below it exactly once. The KAT below only
+    // goes up to 7 leaves, and the iterative walk has more room to go wrong on bigger trees.
+    #[quickcheck]
+    fn resolution_covers_filled_leaves(num_leaves: u16, rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let num_leaves = core::cmp::max(num_leaves as usize % 100, 1);
+        let num_nodes = tree_math::num_nodes_in_tree(num_leaves);
+
+        // Every node is filled with probability 1/3, so there are long runs of blanks
+        let nodes = (0..num_nodes)
+            .map(|_| {
+                if rng.gen_range(0, 3) == 0 {
+                    RatchetTreeNode::Filled {
+                        public_key: DhPublicKey::Raw(DhPublicKeyRaw(Vec::new())),
+                        private_key: None,
+                    }
+                } else {
+                    RatchetTreeNode::Blank
+                }
+            })
+            .collect();
+        let tree = RatchetTree::new(nodes);
+
+        let ctx = tree.math_ctx();
+        for idx in (0..num_nodes).map(NodeIndex) {
+            let resolution = tree.resolution(idx);
+            assert!(resolution.windows(2).all(|w| w[0] < w[1]));
+            for &res_idx in resolution.iter() {
+                assert!(tree.get(res_idx).unwrap().is_filled());
+                assert!(ctx.is_ancestor(idx, res_idx));
+            }
+
+            for (leaf_idx, _) in tree.occupied_leaves() {
+                let num_covering = resolution
+                    .iter()
+                    .filter(|&&res_idx| ctx.is_ancestor(res_idx, leaf_idx))
+                    .count();
+                let expected = if ctx.is_ancestor(idx, leaf_idx) {
+                    1
+                } else {
+                    0
+                };
+                assert_eq!(num_covering, expected);
+            }
+        }
+    }
+
     // Tests against the official tree math test vector. See above comment for explanation.
     #[test]
     fn official_resolution_kat() {
         // Helper function
         fn u8_resolution(tree: &RatchetTree, idx: usize) -> Vec<u8> {
-            tree.resolution(idx)
+            tree.resolution(NodeIndex(idx))
                 .into_iter()
-                .map(|i| {
+                .map(|NodeIndex(i)| {
                     // These had better be small indices
-                    if i > core::u8::MAX as usize {
+                    if i > u8::MAX as usize {
                         panic!("resolution node indices are too big to fit into a u8");
                     } else {
                         i as u8
@@ -757,9 +1782,7 @@ mod test {
                 bit_mask <<= 1;
             }
 
-            RatchetTree {
-                nodes,
-            }
+            RatchetTree::new(nodes)
         }
 
         let mut f = std::fs::File::open("test_vectors/resolution.bin").unwrap();
@@ -779,4 +1802,140 @@ mod test {
             }
         }
     }
+
+    // Tests tree construction and tree hashing against the tree test vector. See above comment for
+    // explanation.
+    #[test]
+    fn tree_hash_kat() {
+        let mut f = std::fs::File::open("test_vectors/tree.bin").unwrap();
+        let mut deserializer = TlsDeserializer::from_reader(&mut f);
+        let test_vec = TreeTestVectors::deserialize(&mut deserializer).unwrap();
+        let cs = test_vec.cipher_suite;
+        assert_eq!(test_vec.leaf_secrets.len(), test_vec.num_leaves as usize);
+        assert_eq!(test_vec.cases.len(), test_vec.num_leaves as usize);
+
+        let mut tree = RatchetTree::new(Vec::new());
+        for (leaf_secret, case) in test_vec.leaf_secrets.iter().zip(test_vec.cases.iter()) {
+            let path_secret = PathSecret::new_from_bytes(&leaf_secret.0);
+            let root_secret = tree.add_leaf_with_path_secret(cs, path_secret).unwrap();
+            assert_eq!(root_secret.0.as_bytes(), &case.root_secret[..]);
+
+            // Compare every node's public key and hash
+            let hashes = tree.node_hashes(cs).unwrap();
+            assert_eq!(tree.size(), case.nodes.len());
+            for (idx, expected) in case.nodes.iter().enumerate() {
+                assert_serialized_eq!(tree.nodes[idx], expected.public_key);
+                assert_eq!(hashes[idx].as_bytes(), expected.hash.as_bytes());
+            }
+
+            // The tree hash is the root's hash
+            let root_idx = tree.math_ctx().root();
+            let tree_hash = tree.tree_hash(cs).unwrap();
+            assert_eq!(tree_hash.as_bytes(), case.nodes[root_idx.0].hash.as_bytes());
+        }
+    }
+
+    // Checks that changing any one node of a tree, by blanking it or giving it a new public key,
+    // changes the tree hash
+    #[quickcheck]
+    fn tree_hash_covers_every_node(num_leaves: u8, rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let num_leaves = core::cmp::max(num_leaves as usize, 1);
+        let cs: &'static CipherSuite = &X25519_SHA256_AES128GCM;
+
+        // Make a tree with every leaf filled, then fill the direct path of a random leaf
+        let mut tree = RatchetTree::new(Vec::new());
+        for _ in 0..num_leaves {
+            let privkey = DhPrivateKey::new_from_random(cs.dh_impl, &mut rng).unwrap();
+            tree.add_leaf_node(RatchetTreeNode::new_from_private_key(cs, privkey));
+        }
+        let leaf_idx = LeafIndex(rng.gen_range(0, num_leaves));
+        let path_secret = PathSecret::new_from_random(cs, &mut rng);
+        tree.propagate_new_path_secret(cs, path_secret, leaf_idx.node_index()).unwrap();
+        let tree_hash = tree.tree_hash(cs).unwrap();
+
+        for idx in 0..tree.size() {
+            let mut changed_tree = tree.clone();
+            changed_tree.nodes_mut()[idx] = if tree.nodes[idx].is_filled() {
+                RatchetTreeNode::Blank
+            } else {
+                let privkey = DhPrivateKey::new_from_random(cs.dh_impl, &mut rng).unwrap();
+                RatchetTreeNode::new_from_private_key(cs, privkey)
+            };
+            let changed_tree_hash = changed_tree.tree_hash(cs).unwrap();
+            assert_ne!(tree_hash.as_bytes(), changed_tree_hash.as_bytes());
+        }
+
+        // Private keys aren't covered
+        let mut public_tree = tree.clone();
+        for node in public_tree.nodes.iter_mut() {
+            node.erase_private_key();
+        }
+        assert_eq!(tree_hash.as_bytes(), public_tree.tree_hash(cs).unwrap().as_bytes());
+    }
+
+    // Puts a tree through a random sequence of the changes that operations make, refreshing its
+    // cached node hashes at random points, and checks that the tree hash always matches the one
+    // computed from scratch. Also checks that changing a clone leaves the original's cache alone.
+    #[quickcheck]
+    fn cached_tree_hash_correctness(num_leaves: u8, rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let num_leaves = core::cmp::max(num_leaves as usize, 1);
+        let cs: &'static CipherSuite = &X25519_SHA256_AES128GCM;
+
+        let fresh_tree_hash = |tree: &RatchetTree| {
+            let root_idx = tree.math_ctx().root();
+            tree.node_hashes(cs).unwrap().swap_remove(root_idx.0)
+        };
+
+        let mut tree = RatchetTree::new(Vec::new());
+        for _ in 0..num_leaves {
+            let privkey = DhPrivateKey::new_from_random(cs.dh_impl, &mut rng).unwrap();
+            tree.add_leaf_node(RatchetTreeNode::new_from_private_key(cs, privkey));
+        }
+        tree.refresh_node_hashes(cs).unwrap();
+
+        for _ in 0..20 {
+            let original = tree.clone();
+            let original_tree_hash = fresh_tree_hash(&original);
+
+            let leaf_idx = LeafIndex(rng.gen_range(0, tree.leaf_count())).node_index();
+            match rng.gen_range(0, 5) {
+                0 => {
+                    let path_secret = PathSecret::new_from_random(cs, &mut rng);
+                    tree.propagate_new_path_secret(cs, path_secret, leaf_idx).unwrap();
+                }
+                1 => tree.propagate_blank(leaf_idx),
+                2 => {
+                    // Fill a blank leaf if there is one, and extend the tree otherwise
+                    let blank_leaf = tree.leaves().find(|(_, node)| !node.is_filled());
+                    let leaf_idx = match blank_leaf {
+                        Some((idx, _)) => idx.leaf_index().unwrap(),
+                        None => LeafIndex(tree.leaf_count()),
+                    };
+                    let privkey = DhPrivateKey::new_from_random(cs.dh_impl, &mut rng).unwrap();
+                    let node = RatchetTreeNode::new_from_private_key(cs, privkey);
+                    tree.add_leaf_at(leaf_idx, node).unwrap();
+                }
+                3 => {
+                    let privkey = DhPrivateKey::new_from_random(cs.dh_impl, &mut rng).unwrap();
+                    tree.add_leaf_node(RatchetTreeNode::new_from_private_key(cs, privkey));
+                }
+                _ => {
+                    // Blank the last leaf and cut it off, keeping at least one leaf around
+                    if tree.occupied_leaves().count() > 1 {
+                        let (last_leaf_idx, _) = tree.occupied_leaves().next_back().unwrap();
+                        tree.propagate_blank(last_leaf_idx);
+                        tree.truncate_to_last_nonblank();
+                    }
+                }
+            }
+
+            assert_eq!(tree.tree_hash(cs).unwrap().as_bytes(), fresh_tree_hash(&tree).as_bytes());
+            assert_eq!(original.tree_hash(cs).unwrap().as_bytes(), original_tree_hash.as_bytes());
+            if rng.gen() {
+                tree.refresh_node_hashes(cs).unwrap();
+            }
+        }
+    }
 }
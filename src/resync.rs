@@ -0,0 +1,388 @@
+//! Repairing a member's copy of the ratchet tree. A member whose tree has drifted from everyone
+//! else's, e.g., after restoring a `GroupState` from a bad backup, finds out when its tree hash no
+//! longer matches theirs. Rather than being removed and added back, it can send a
+//! `TreeSyncRequest` to another member and replace its tree with the one in the signed
+//! `TreeSyncResponse` it gets back.
+//!
+//! A single responder isn't trusted to say what the tree is. The requester has to know the tree
+//! hash that the rest of the group has in its `GroupContext`, e.g., from the delivery service's
+//! `PublicGroupState::get_tree_hash`, and the received tree has to hash to exactly that. On top of
+//! that, the response has to be signed by a current member who agrees with the requester about the
+//! group's history, i.e., its epoch and transcript hash. The tree also has to be well-formed, match
+//! the roster, and have the same public key for the requester's own leaf as the requester has.
+//!
+//! Note that this repairs the tree, not the key schedule. A member whose tree drifted because it
+//! processed a `Handshake` differently from everyone else has the wrong epoch secrets too, and can
+//! only get back in by being re-added.
+
+use crate::{
+    credential::RosterIndex,
+    crypto::{hash::Digest, sig::Signature},
+    error::Error,
+    group_state::GroupState,
+    ratchet_tree::RatchetTree,
+    tls_de::TlsDeserializer,
+    tls_ser,
+    upcast::{CryptoCtx, CryptoUpcast},
+};
+
+use serde::de::Deserialize;
+use subtle::ConstantTimeEq;
+
+// struct {
+//     opaque group_id<1..255>;
+//     uint32 epoch;
+//     opaque transcript_hash<0..255>;
+// } TreeSyncRequest;
+/// Asks another member of a group for their copy of the group's ratchet tree. This is made by
+/// `GroupState::create_tree_sync_request` and answered with `GroupState::respond_to_tree_sync`.
+#[derive(Clone, Deserialize, Serialize)]
+#[cfg_attr(test, derive(Debug))]
+pub struct TreeSyncRequest {
+    #[serde(rename = "group_id__nonempty__bound_u8")]
+    pub(crate) group_id: Vec<u8>,
+    pub(crate) epoch: u32,
+    /// The requester's transcript hash. A member only answers if it has the same one.
+    pub(crate) transcript_hash: Digest,
+}
+
+// struct {
+//     opaque group_id<1..255>;
+//     uint32 epoch;
+//     uint32 responder_index;
+//     optional<PublicKey> tree<1..2^32-1>;
+//     opaque signature<1..2^16-1>;
+// } TreeSyncResponse;
+/// A member's copy of a group's ratchet tree, with no private keys in it, sent in reply to a
+/// `TreeSyncRequest`. This is applied with `GroupState::process_tree_sync_response`.
+#[derive(Deserialize, Serialize)]
+#[cfg_attr(test, derive(Debug))]
+pub struct TreeSyncResponse {
+    #[serde(rename = "group_id__nonempty__bound_u8")]
+    pub(crate) group_id: Vec<u8>,
+    pub(crate) epoch: u32,
+    pub(crate) responder_index: RosterIndex,
+    pub(crate) tree: RatchetTree,
+    /// `Sign(identity_key, TreeSyncContent)`
+    pub(crate) signature: Signature,
+}
+
+// struct {
+//     opaque group_id<0..255>;
+//     uint32 epoch;
+//     opaque transcript_hash<0..255>;
+//     uint32 responder_index;
+//     optional<PublicKey> tree<1..2^32-1>;
+// } TreeSyncContent;
+/// What a `TreeSyncResponse`'s signature is computed over. This is the whole response minus the
+/// signature, plus the transcript hash that both members agree on.
+#[derive(Serialize)]
+struct TreeSyncContent<'a> {
+    #[serde(rename = "group_id__bound_u8")]
+    group_id: &'a [u8],
+    epoch: u32,
+    transcript_hash: &'a Digest,
+    responder_index: RosterIndex,
+    tree: &'a RatchetTree,
+}
+
+impl GroupState {
+    /// Makes a `TreeSyncRequest` asking for another member's copy of this group's ratchet tree.
+    /// Send this when the tree hash of this `GroupState` disagrees with another member's.
+    pub fn create_tree_sync_request(&self) -> TreeSyncRequest {
+        TreeSyncRequest {
+            group_id: self.group_id.clone(),
+            epoch: self.epoch,
+            transcript_hash: self.transcript_hash.clone(),
+        }
+    }
+
+    /// Answers the given `TreeSyncRequest` with this member's copy of the ratchet tree, signed
+    /// with this member's identity key. The request has to be for this group in its current
+    /// epoch, from a member with the same transcript hash. Otherwise the requester is behind or
+    /// has a different history, and a tree won't help.
+    ///
+    /// Returns: `Ok(response)` on success. Returns an `Error::ValidationError` if the request is
+    /// for some other group, epoch, or history, or if this `GroupState` is preliminary. Returns
+    /// an `Error::SerdeError` if the signed content can't be serialized.
+    pub fn respond_to_tree_sync(
+        &self,
+        request: &TreeSyncRequest,
+    ) -> Result<TreeSyncResponse, Error> {
+        if request.group_id != self.group_id {
+            return Err(Error::ValidationError("TreeSyncRequest is for a different group"));
+        }
+        if request.epoch != self.epoch {
+            return Err(Error::ValidationError("TreeSyncRequest is for a different epoch"));
+        }
+        let histories_match: bool = request.transcript_hash.ct_eq(&self.transcript_hash).into();
+        if !histories_match {
+            return Err(Error::ValidationError("TreeSyncRequest has a different transcript hash"));
+        }
+        let responder_index = self.roster_index.ok_or(Error::ValidationError(
+            "Cannot answer a TreeSyncRequest from a preliminary GroupState",
+        ))?;
+
+        // Serializing a tree leaves out its private keys, so this is safe to send as it is
+        let mut tree = self.tree.clone();
+        tree.erase_all_private_keys();
+
+        let sig_data = tls_ser::serialize_to_bytes(&TreeSyncContent {
+            group_id: &self.group_id,
+            epoch: self.epoch,
+            transcript_hash: &self.transcript_hash,
+            responder_index,
+            tree: &tree,
+        })?;
+        let signature = self.sign_with_identity_key(&sig_data)?;
+
+        Ok(TreeSyncResponse {
+            group_id: self.group_id.clone(),
+            epoch: self.epoch,
+            responder_index,
+            tree,
+            signature,
+        })
+    }
+
+    /// Deserializes a `TreeSyncResponse` sent in this group and upcasts it. Like
+    /// `GroupState::deserialize_handshake`, this looks the responder up in the roster first, since
+    /// the signature is in their signature scheme.
+    ///
+    /// Returns: `Ok(response)` on success. Returns an `Error::ValidationError` if the responder
+    /// isn't in the roster, an `Error::SerdeError` if the bytes can't be deserialized, and some
+    /// other `Error` if they can't be upcast.
+    pub fn deserialize_tree_sync_response(&self, bytes: &[u8]) -> Result<TreeSyncResponse, Error> {
+        let mut cursor = bytes;
+        let mut deserializer =
+            TlsDeserializer::from_reader_with_mode(&mut cursor, self.config.parse_mode);
+        let mut response = TreeSyncResponse::deserialize(&mut deserializer)?;
+        deserializer.finish()?;
+        let responder_credential = self
+            .roster
+            .0
+            .get(response.responder_index.0 as usize)
+            .and_then(Option::as_ref)
+            .ok_or(Error::ValidationError("TreeSyncResponse's responder isn't in the group"))?;
+        let ctx = CryptoCtx::new()
+            .set_cipher_suite(self.cs)
+            .set_signature_scheme(responder_credential.get_signature_scheme());
+        response.upcast_crypto_values(&ctx)?;
+
+        Ok(response)
+    }
+
+    /// Checks the given `TreeSyncResponse` and, if it passes, replaces this member's ratchet tree
+    /// with the one in it. Private keys are kept for every node whose public key is unchanged, and
+    /// dropped for the rest. Like `GroupState::process_handshake`, this doesn't mutate the current
+    /// `GroupState`.
+    ///
+    /// The tree in the response has to hash to `expected_tree_hash`, which is the tree hash in the
+    /// `GroupContext` of the rest of the group in this epoch. This has to come from somewhere other
+    /// than the responder, e.g., `PublicGroupState::get_tree_hash` on the delivery service.
+    ///
+    /// Returns: `Ok(group_state)` on success, where `group_state` has the repaired tree. Returns
+    /// an `Error::ValidationError` if the response is for some other group or epoch, isn't from
+    /// another current member, or has a tree that doesn't hash to `expected_tree_hash` or doesn't
+    /// match the roster or this member's leaf. Returns an `Error::TreeError` if the tree is
+    /// malformed, and an `Error::SignatureError` if the signature doesn't verify.
+    pub fn process_tree_sync_response(
+        &self,
+        response: &TreeSyncResponse,
+        expected_tree_hash: &[u8],
+    ) -> Result<GroupState, Error> {
+        if response.group_id != self.group_id {
+            return Err(Error::ValidationError("TreeSyncResponse is for a different group"));
+        }
+        if response.epoch != self.epoch {
+            return Err(Error::ValidationError("TreeSyncResponse is for a different epoch"));
+        }
+        let my_roster_index = self
+            .roster_index
+            .ok_or(Error::ValidationError("Cannot repair the tree of a preliminary GroupState"))?;
+        if response.responder_index == my_roster_index {
+            return Err(Error::ValidationError("TreeSyncResponse is from this member"));
+        }
+
+        // The responder signs over the transcript hash, so this only verifies if we agree on the
+        // group's history
+        let responder_credential = self
+            .roster
+            .0
+            .get(response.responder_index.0 as usize)
+            .and_then(Option::as_ref)
+            .ok_or(Error::ValidationError("TreeSyncResponse's responder isn't in the group"))?;
+        let sig_data = tls_ser::serialize_to_bytes(&TreeSyncContent {
+            group_id: &response.group_id,
+            epoch: response.epoch,
+            transcript_hash: &self.transcript_hash,
+            responder_index: response.responder_index,
+            tree: &response.tree,
+        })?;
+        responder_credential.verify(&sig_data, &response.signature)?;
+
+        // Same checks as a tree in a WelcomeInfo gets
+        let new_tree = &response.tree;
+        new_tree.validate_received(self.roster.len())?;
+        for (entry, (_, leaf)) in self.roster.0.iter().zip(new_tree.leaves()) {
+            if entry.is_some() != leaf.is_filled() {
+                return Err(Error::ValidationError(
+                    "TreeSyncResponse roster entry and tree leaf disagree on whether a member is \
+                     there",
+                ));
+            }
+        }
+
+        // The responder signed it, but only the group's tree hash says it's the right tree
+        let new_tree_hash = new_tree.tree_hash(self.cs)?;
+        let hashes_match: bool = new_tree_hash.as_bytes().ct_eq(expected_tree_hash).into();
+        if !hashes_match {
+            return Err(Error::ValidationError(
+                "TreeSyncResponse's tree doesn't have the group's tree hash",
+            ));
+        }
+
+        // Bring over every private key whose public key survived. Our own leaf has to be one of
+        // them, or we couldn't decrypt anything sent to us under the new tree.
+        let my_tree_idx = my_roster_index.node_index()?;
+        let mut repaired_tree = new_tree.clone();
+        for (idx, (old_node, new_node)) in
+            self.tree.nodes().iter().zip(repaired_tree.nodes_mut().iter_mut()).enumerate()
+        {
+            let keys_match = match (old_node.get_public_key(), new_node.get_public_key()) {
+                (Some(old_key), Some(new_key)) => bool::from(old_key.ct_eq(new_key)),
+                _ => false,
+            };
+            match old_node.get_private_key() {
+                Some(private_key) if keys_match => {
+                    new_node.update_private_key(private_key.clone());
+                }
+                _ if idx == my_tree_idx.0 => {
+                    return Err(Error::ValidationError(
+                        "TreeSyncResponse has a different public key for this member's leaf",
+                    ));
+                }
+                _ => (),
+            }
+        }
+
+        let mut new_state = self.clone();
+        new_state.tree = repaired_tree;
+        new_state.refresh_group_context()?;
+        Ok(new_state)
+    }
+}
+
+impl TreeSyncResponse {
+    /// Returns the roster index of the member who sent this response
+    pub fn get_responder_index(&self) -> RosterIndex {
+        self.responder_index
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        crypto::dh::{DhPrivateKey, DhPublicKey},
+        error::Error,
+        ratchet_tree::{PathSecret, RatchetTreeNode},
+        test_utils, tls_ser,
+    };
+
+    use quickcheck_macros::quickcheck;
+    use rand::SeedableRng;
+
+    // Corrupts one member's copy of the tree and checks that a TreeSyncResponse from another
+    // member repairs it without costing them any of their private keys, and that a response with
+    // the wrong tree hash, the wrong leaf for the requester, or a bad signature is rejected
+    #[quickcheck]
+    fn tree_resync_correctness(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state1, identity_keys) = test_utils::random_full_group_state(3, &mut rng);
+        let cs = group_state1.cs;
+        let index1 = group_state1.roster_index.unwrap();
+        let index2 = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[index1.0 as usize],
+            &mut rng,
+        );
+        let group_state2 = test_utils::change_self_index(&group_state1, &identity_keys, index2);
+
+        // Give member 2 a bogus public key for member 1's leaf
+        let mut corrupted = group_state2.clone();
+        let tree_idx1 = index1.node_index().unwrap();
+        let bogus_private_key = DhPrivateKey::new_from_random(cs.dh_impl, &mut rng).unwrap();
+        let bogus_public_key = DhPublicKey::new_from_private_key(cs.dh_impl, &bogus_private_key);
+        corrupted.tree.get_mut(tree_idx1).unwrap().update_public_key(bogus_public_key);
+        let group_tree_hash = group_state1.to_public_state().get_tree_hash().unwrap();
+        assert_ne!(corrupted.get_tree_hash().unwrap(), group_tree_hash);
+
+        // Member 2 asks member 1 for their tree, and the response goes over the wire
+        let request = corrupted.create_tree_sync_request();
+        let response = group_state1.respond_to_tree_sync(&request).unwrap();
+        let response_bytes = tls_ser::serialize_to_bytes(&response).unwrap();
+        let response = corrupted.deserialize_tree_sync_response(&response_bytes).unwrap();
+        assert_eq!(response.get_responder_index(), index1);
+
+        // The repaired tree is what member 2 had before the corruption. Test groups know every
+        // private key, so member 2 keeps all of them but the one under the bogus public key.
+        let repaired = corrupted.process_tree_sync_response(&response, &group_tree_hash).unwrap();
+        assert_eq!(repaired.get_tree_hash().unwrap(), group_state2.get_tree_hash().unwrap());
+        for (idx, (old_node, new_node)) in
+            group_state2.tree.nodes().iter().zip(repaired.tree.nodes().iter()).enumerate()
+        {
+            let should_have_key = old_node.get_private_key().is_some() && idx != tree_idx1.0;
+            assert_eq!(new_node.get_private_key().is_some(), should_have_key);
+        }
+
+        // And it can follow the group again
+        let new_path_secret = PathSecret::new_from_random(cs, &mut rng);
+        let (handshake, _, _) =
+            group_state1.create_and_apply_update_handshake(new_path_secret, &mut rng).unwrap();
+        repaired.process_handshake(&handshake).unwrap();
+
+        // A properly signed tree from a member whose own tree is off isn't taken on their word
+        let mut responder_state = group_state1.clone();
+        responder_state
+            .tree
+            .get_mut(tree_idx1)
+            .unwrap()
+            .update_public_key(DhPublicKey::new_from_private_key(cs.dh_impl, &bogus_private_key));
+        let response = responder_state.respond_to_tree_sync(&request).unwrap();
+        match corrupted.process_tree_sync_response(&response, &group_tree_hash) {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("tree with the wrong tree hash was accepted"),
+        }
+
+        // A tree where member 2's leaf is different is no use to member 2, even if that's the tree
+        // the caller expects
+        let tree_idx2 = index2.node_index().unwrap();
+        let mut responder_state = group_state1.clone();
+        let bogus_private_key = DhPrivateKey::new_from_random(cs.dh_impl, &mut rng).unwrap();
+        responder_state
+            .tree
+            .get_mut(tree_idx2)
+            .unwrap()
+            .update_public_key(DhPublicKey::new_from_private_key(cs.dh_impl, &bogus_private_key));
+        let response = responder_state.respond_to_tree_sync(&request).unwrap();
+        let responder_tree_hash = responder_state.to_public_state().get_tree_hash().unwrap();
+        match corrupted.process_tree_sync_response(&response, &responder_tree_hash) {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("tree with the wrong leaf for the requester was accepted"),
+        }
+
+        // Neither is a tree someone swapped in after it was signed
+        let mut response = group_state1.respond_to_tree_sync(&request).unwrap();
+        if let RatchetTreeNode::Filled {
+            ref mut public_key,
+            ..
+        } = response.tree.nodes_mut()[tree_idx1.0]
+        {
+            *public_key = DhPublicKey::new_from_private_key(cs.dh_impl, &bogus_private_key);
+        }
+        match corrupted.process_tree_sync_response(&response, &group_tree_hash) {
+            Err(Error::SignatureError(_)) => (),
+            _ => panic!("tampered TreeSyncResponse was accepted"),
+        }
+    }
+}
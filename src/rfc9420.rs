@@ -0,0 +1,752 @@
+//! Defines the wire formats of MLS 1.0 (RFC 9420): `KeyPackage`, `LeafNode`, `GroupInfo`,
+//! `Commit`, `PrivateMessage`, and `Welcome`, all wrapped in an `MlsMessage`. This is the first
+//! step of moving molasses from the draft it was written against to the published protocol.
+//!
+//! Only the encodings are here. Group logic still runs on the legacy draft messages in
+//! `handshake` and `group_state`, and nothing in this module signs, verifies, or decrypts. RFC
+//! 9420 also needs HPKE and its own cipher suite and key schedule, which this crate doesn't have
+//! yet. What this module does give you is byte-exact parsing and serialization, so RFC 9420
+//! messages from modern stacks can be recognized, inspected, and passed along, and so the rest of
+//! the migration has types to build on. These are plain data with public fields. A parsed message
+//! is well-formed, not valid.
+//!
+//! Both generations of messages can arrive on the same channel. `WireVersion::detect` tells them
+//! apart by their first bytes, which is the switch between the two paths. `MlsMessage::from_bytes`
+//! only accepts `mls10`, and the legacy deserializers are untouched.
+//!
+//! This module is only available with the `rfc9420` feature enabled.
+
+use crate::{
+    error::Error,
+    tls_de::{ParseMode, TlsDeserializer},
+    tls_ser,
+};
+
+use serde::{
+    de::{Deserialize, Deserializer},
+    ser::{Serialize, Serializer},
+};
+
+/// The `ProtocolVersion` of MLS 1.0
+pub const MLS10: u16 = 0x0001;
+
+/// Which generation of the protocol a message was encoded in
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WireVersion {
+    /// The draft this crate's group logic implements. These start with a one-byte
+    /// `ProtocolVersion`.
+    Legacy,
+    /// MLS 1.0. These are `MlsMessage`s, which start with a two-byte `ProtocolVersion` of `mls10`.
+    Rfc9420,
+}
+
+impl WireVersion {
+    /// Figures out which generation of the protocol the given message was encoded in. This only
+    /// looks at the version prefix. Anything that isn't an RFC 9420 message is assumed to be a
+    /// legacy message, and is left for the legacy deserializers to accept or reject.
+    pub fn detect(bytes: &[u8]) -> WireVersion {
+        if bytes.starts_with(&MLS10.to_be_bytes()) {
+            WireVersion::Rfc9420
+        } else {
+            WireVersion::Legacy
+        }
+    }
+}
+
+/// Stands in for values of RFC 9420 enums that this module can't represent: the `reserved(0)` of
+/// every enum, and message types that aren't implemented yet. There are no values of this type, so
+/// deserializing one is always an error, and an enum variant holding one can't be constructed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Unsupported {}
+
+impl Serialize for Unsupported {
+    fn serialize<S: Serializer>(&self, _serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {}
+    }
+}
+
+impl<'de> Deserialize<'de> for Unsupported {
+    fn deserialize<D: Deserializer<'de>>(_deserializer: D) -> Result<Self, D::Error> {
+        Err(serde::de::Error::custom("reserved or unsupported RFC 9420 enum value"))
+    }
+}
+
+/// Makes a newtype around an opaque byte vector with a varint length prefix
+macro_rules! opaque_newtype {
+    ($(#[$attr:meta])* $name:ident, $wire_name:literal) => {
+        $(#[$attr])*
+        #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+        #[serde(rename = $wire_name)]
+        pub struct $name(pub Vec<u8>);
+    };
+}
+
+opaque_newtype!(
+    /// An HPKE public key
+    // opaque HPKEPublicKey<V>;
+    HpkePublicKey,
+    "HpkePublicKey__bound_varint"
+);
+
+opaque_newtype!(
+    /// A signature public key
+    // opaque SignaturePublicKey<V>;
+    SignaturePublicKey,
+    "SignaturePublicKey__bound_varint"
+);
+
+opaque_newtype!(
+    /// A hash reference to a `KeyPackage` or a `Proposal`
+    // opaque HashReference<V>;
+    HashReference,
+    "HashReference__bound_varint"
+);
+
+/// An HPKE ciphertext
+// struct {
+//     opaque kem_output<V>;
+//     opaque ciphertext<V>;
+// } HPKECiphertext;
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct HpkeCiphertext {
+    #[serde(rename = "kem_output__bound_varint")]
+    pub kem_output: Vec<u8>,
+    #[serde(rename = "ciphertext__bound_varint")]
+    pub ciphertext: Vec<u8>,
+}
+
+/// A single extension. This is the RFC 9420 encoding, which differs from
+/// `extensions::Extension` in its length prefix.
+// struct {
+//     ExtensionType extension_type;
+//     opaque extension_data<V>;
+// } Extension;
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Extension {
+    pub extension_type: u16,
+    #[serde(rename = "extension_data__bound_varint")]
+    pub extension_data: Vec<u8>,
+}
+
+/// A list of certificates, leaf first
+// struct {
+//     opaque cert_data<V>;
+// } Certificate;
+//
+// Certificate certificates<V>;
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename = "CertificateChain__bound_varint")]
+pub struct CertificateChain(pub Vec<Certificate>);
+
+opaque_newtype!(
+    /// A single DER-encoded certificate
+    Certificate,
+    "Certificate__bound_varint"
+);
+
+opaque_newtype!(
+    /// The identity in a basic credential
+    BasicIdentity,
+    "BasicIdentity__bound_varint"
+);
+
+/// A member's credential. The tag is the `CredentialType`.
+// struct {
+//     CredentialType credential_type;
+//     select (Credential.credential_type) {
+//         case basic:
+//             opaque identity<V>;
+//         case x509:
+//             Certificate certificates<V>;
+//     };
+// } Credential;
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename = "Credential__enum_u16")]
+pub enum Credential {
+    Reserved(Unsupported),
+    Basic(BasicIdentity),
+    X509(CertificateChain),
+}
+
+/// The protocol features a client supports
+// struct {
+//     ProtocolVersion versions<V>;
+//     CipherSuite cipher_suites<V>;
+//     ExtensionType extensions<V>;
+//     ProposalType proposals<V>;
+//     CredentialType credentials<V>;
+// } Capabilities;
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Capabilities {
+    #[serde(rename = "versions__bound_varint")]
+    pub versions: Vec<u16>,
+    #[serde(rename = "cipher_suites__bound_varint")]
+    pub cipher_suites: Vec<u16>,
+    #[serde(rename = "extensions__bound_varint")]
+    pub extensions: Vec<u16>,
+    #[serde(rename = "proposals__bound_varint")]
+    pub proposals: Vec<u16>,
+    #[serde(rename = "credentials__bound_varint")]
+    pub credentials: Vec<u16>,
+}
+
+/// The span of time a `KeyPackage` may be used in, in seconds since the UNIX epoch
+// struct {
+//     uint64 not_before;
+//     uint64 not_after;
+// } Lifetime;
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Lifetime {
+    pub not_before: u64,
+    pub not_after: u64,
+}
+
+/// Where a `LeafNode` came from, along with the data that only that source carries. The tag is
+/// the `LeafNodeSource`.
+// enum {
+//     reserved(0),
+//     key_package(1),
+//     update(2),
+//     commit(3),
+//     (255)
+// } LeafNodeSource;
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename = "LeafNodeSource__enum_u8")]
+pub enum LeafNodeSource {
+    Reserved(Unsupported),
+    KeyPackage(Lifetime),
+    Update,
+    Commit(ParentHash),
+}
+
+opaque_newtype!(
+    /// The parent hash of a `LeafNode` sent in a `Commit`
+    ParentHash,
+    "ParentHash__bound_varint"
+);
+
+/// A member's leaf in the ratchet tree
+// struct {
+//     HPKEPublicKey encryption_key;
+//     SignaturePublicKey signature_key;
+//     Credential credential;
+//     Capabilities capabilities;
+//
+//     LeafNodeSource leaf_node_source;
+//     select (LeafNode.leaf_node_source) {
+//         case key_package:
+//             Lifetime lifetime;
+//         case update:
+//             struct{};
+//         case commit:
+//             opaque parent_hash<V>;
+//     };
+//
+//     Extension extensions<V>;
+//     /* SignWithLabel(., "LeafNodeTBS", LeafNodeTBS) */
+//     opaque signature<V>;
+// } LeafNode;
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct LeafNode {
+    pub encryption_key: HpkePublicKey,
+    pub signature_key: SignaturePublicKey,
+    pub credential: Credential,
+    pub capabilities: Capabilities,
+    pub leaf_node_source: LeafNodeSource,
+    #[serde(rename = "extensions__bound_varint")]
+    pub extensions: Vec<Extension>,
+    #[serde(rename = "signature__bound_varint")]
+    pub signature: Vec<u8>,
+}
+
+/// The RFC 9420 counterpart of a `UserInitKey`
+// struct {
+//     ProtocolVersion version;
+//     CipherSuite cipher_suite;
+//     HPKEPublicKey init_key;
+//     LeafNode leaf_node;
+//     Extension extensions<V>;
+//     /* SignWithLabel(., "KeyPackageTBS", KeyPackageTBS) */
+//     opaque signature<V>;
+// } KeyPackage;
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct KeyPackage {
+    pub version: u16,
+    pub cipher_suite: u16,
+    pub init_key: HpkePublicKey,
+    pub leaf_node: LeafNode,
+    #[serde(rename = "extensions__bound_varint")]
+    pub extensions: Vec<Extension>,
+    #[serde(rename = "signature__bound_varint")]
+    pub signature: Vec<u8>,
+}
+
+/// The summary of a group's state that every member agrees on in an epoch
+// struct {
+//     ProtocolVersion version = mls10;
+//     CipherSuite cipher_suite;
+//     opaque group_id<V>;
+//     uint64 epoch;
+//     opaque tree_hash<V>;
+//     opaque confirmed_transcript_hash<V>;
+//     Extension extensions<V>;
+// } GroupContext;
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct GroupContext {
+    pub version: u16,
+    pub cipher_suite: u16,
+    #[serde(rename = "group_id__bound_varint")]
+    pub group_id: Vec<u8>,
+    pub epoch: u64,
+    #[serde(rename = "tree_hash__bound_varint")]
+    pub tree_hash: Vec<u8>,
+    #[serde(rename = "confirmed_transcript_hash__bound_varint")]
+    pub confirmed_transcript_hash: Vec<u8>,
+    #[serde(rename = "extensions__bound_varint")]
+    pub extensions: Vec<Extension>,
+}
+
+/// The information a new member needs about the group they're joining, signed by a member
+// struct {
+//     GroupContext group_context;
+//     Extension extensions<V>;
+//     MAC confirmation_tag;
+//     uint32 signer;
+//     /* SignWithLabel(., "GroupInfoTBS", GroupInfoTBS) */
+//     opaque signature<V>;
+// } GroupInfo;
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct GroupInfo {
+    pub group_context: GroupContext,
+    #[serde(rename = "extensions__bound_varint")]
+    pub extensions: Vec<Extension>,
+    #[serde(rename = "confirmation_tag__bound_varint")]
+    pub confirmation_tag: Vec<u8>,
+    pub signer: u32,
+    #[serde(rename = "signature__bound_varint")]
+    pub signature: Vec<u8>,
+}
+
+/// A proposal to change the group. The tag is the `ProposalType`. Proposal types past `remove`
+/// aren't supported yet.
+// struct {
+//     ProposalType proposal_type;
+//     select (Proposal.proposal_type) {
+//         case add:    Add;
+//         case update: Update;
+//         case remove: Remove;
+//         ...
+//     };
+// } Proposal;
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename = "Proposal__enum_u16")]
+pub enum Proposal {
+    Reserved(Unsupported),
+    // struct {
+    //     KeyPackage key_package;
+    // } Add;
+    Add(KeyPackage),
+    // struct {
+    //     LeafNode leaf_node;
+    // } Update;
+    Update(LeafNode),
+    // struct {
+    //     uint32 removed;
+    // } Remove;
+    Remove(u32),
+}
+
+/// A proposal carried in a `Commit`, either in full or by reference to one sent earlier. The tag
+/// is the `ProposalOrRefType`.
+// struct {
+//     ProposalOrRefType type;
+//     select (ProposalOrRef.type) {
+//         case proposal:  Proposal proposal;
+//         case reference: ProposalRef reference;
+//     };
+// } ProposalOrRef;
+// A Commit only has a handful of these, so the size difference doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename = "ProposalOrRef__enum_u8")]
+pub enum ProposalOrRef {
+    Reserved(Unsupported),
+    Proposal(Proposal),
+    Reference(HashReference),
+}
+
+/// A node's new public key and its path secret, encrypted to each node in its copath child's
+/// resolution
+// struct {
+//     HPKEPublicKey encryption_key;
+//     HPKECiphertext encrypted_path_secret<V>;
+// } UpdatePathNode;
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct UpdatePathNode {
+    pub encryption_key: HpkePublicKey,
+    #[serde(rename = "encrypted_path_secret__bound_varint")]
+    pub encrypted_path_secret: Vec<HpkeCiphertext>,
+}
+
+/// The RFC 9420 counterpart of a `DirectPathMessage`
+// struct {
+//     LeafNode leaf_node;
+//     UpdatePathNode nodes<V>;
+// } UpdatePath;
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct UpdatePath {
+    pub leaf_node: LeafNode,
+    #[serde(rename = "nodes__bound_varint")]
+    pub nodes: Vec<UpdatePathNode>,
+}
+
+/// Applies a set of proposals, and optionally refreshes the committer's path
+// struct {
+//     ProposalOrRef proposals<V>;
+//     optional<UpdatePath> path;
+// } Commit;
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Commit {
+    #[serde(rename = "proposals__bound_varint")]
+    pub proposals: Vec<ProposalOrRef>,
+    pub path: Option<UpdatePath>,
+}
+
+/// What a `PrivateMessage`'s ciphertext decrypts to
+// enum {
+//     reserved(0),
+//     application(1),
+//     proposal(2),
+//     commit(3),
+//     (255)
+// } ContentType;
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename = "ContentType__enum_u8")]
+pub enum ContentType {
+    Reserved(Unsupported),
+    Application,
+    Proposal,
+    Commit,
+}
+
+/// An encrypted and authenticated application message, proposal, or commit
+// struct {
+//     opaque group_id<V>;
+//     uint64 epoch;
+//     ContentType content_type;
+//     opaque authenticated_data<V>;
+//     opaque encrypted_sender_data<V>;
+//     opaque ciphertext<V>;
+// } PrivateMessage;
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PrivateMessage {
+    #[serde(rename = "group_id__bound_varint")]
+    pub group_id: Vec<u8>,
+    pub epoch: u64,
+    pub content_type: ContentType,
+    #[serde(rename = "authenticated_data__bound_varint")]
+    pub authenticated_data: Vec<u8>,
+    #[serde(rename = "encrypted_sender_data__bound_varint")]
+    pub encrypted_sender_data: Vec<u8>,
+    #[serde(rename = "ciphertext__bound_varint")]
+    pub ciphertext: Vec<u8>,
+}
+
+/// The group secrets for one new member, encrypted to their `KeyPackage`'s init key
+// struct {
+//     KeyPackageRef new_member;
+//     HPKECiphertext encrypted_group_secrets;
+// } EncryptedGroupSecrets;
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct EncryptedGroupSecrets {
+    pub new_member: HashReference,
+    pub encrypted_group_secrets: HpkeCiphertext,
+}
+
+/// Invites new members into a group
+// struct {
+//     CipherSuite cipher_suite;
+//     EncryptedGroupSecrets secrets<V>;
+//     opaque encrypted_group_info<V>;
+// } Welcome;
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Welcome {
+    pub cipher_suite: u16,
+    #[serde(rename = "secrets__bound_varint")]
+    pub secrets: Vec<EncryptedGroupSecrets>,
+    #[serde(rename = "encrypted_group_info__bound_varint")]
+    pub encrypted_group_info: Vec<u8>,
+}
+
+/// The body of an `MlsMessage`. The tag is the `WireFormat`. `PublicMessage` isn't supported yet.
+// enum {
+//     reserved(0),
+//     mls_public_message(1),
+//     mls_private_message(2),
+//     mls_welcome(3),
+//     mls_group_info(4),
+//     mls_key_package(5),
+//     (65535)
+// } WireFormat;
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename = "MlsMessageBody__enum_u16")]
+pub enum MlsMessageBody {
+    Reserved(Unsupported),
+    PublicMessage(Unsupported),
+    PrivateMessage(PrivateMessage),
+    Welcome(Welcome),
+    GroupInfo(GroupInfo),
+    KeyPackage(KeyPackage),
+}
+
+/// The envelope every RFC 9420 message is sent in
+// struct {
+//     ProtocolVersion version = mls10;
+//     WireFormat wire_format;
+//     select (MLSMessage.wire_format) {
+//         ...
+//     };
+// } MLSMessage;
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct MlsMessage {
+    pub version: u16,
+    pub body: MlsMessageBody,
+}
+
+impl MlsMessage {
+    /// Wraps the given body in an `mls10` message
+    pub fn new(body: MlsMessageBody) -> MlsMessage {
+        MlsMessage {
+            version: MLS10,
+            body,
+        }
+    }
+
+    /// Serializes this message
+    ///
+    /// Returns: `Ok(bytes)` on success. If a vector is too long for its length prefix, returns an
+    /// `Error::SerdeError`.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        tls_ser::serialize_to_bytes(self)
+    }
+
+    /// Deserializes an `mls10` message in the given mode. The version is checked before the body
+    /// is parsed, since other versions are free to lay out their bodies differently.
+    ///
+    /// Returns: `Ok(message)` on success. Returns an `Error::ValidationError` if the message isn't
+    /// `mls10`, and an `Error::SerdeError` if it can't be deserialized.
+    pub fn from_bytes(bytes: &[u8], mode: ParseMode) -> Result<MlsMessage, Error> {
+        if WireVersion::detect(bytes) != WireVersion::Rfc9420 {
+            return Err(Error::ValidationError("Message isn't an MLS 1.0 message"));
+        }
+
+        let mut cursor = bytes;
+        let mut deserializer = TlsDeserializer::from_reader_with_mode(&mut cursor, mode);
+        let message = MlsMessage::deserialize(&mut deserializer)?;
+        deserializer.finish()?;
+
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::handshake::MLS_DUMMY_VERSION;
+
+    use quickcheck_macros::quickcheck;
+    use rand::{Rng, RngCore, SeedableRng};
+
+    // Makes a random vector of bytes of length at most 80, so that some lengths need a 2-byte
+    // varint and some don't
+    fn random_bytes<R: RngCore>(rng: &mut R) -> Vec<u8> {
+        let mut buf = vec![0u8; rng.gen_range(0, 80)];
+        rng.fill_bytes(&mut buf);
+        buf
+    }
+
+    fn random_leaf_node<R: RngCore>(rng: &mut R, source: LeafNodeSource) -> LeafNode {
+        LeafNode {
+            encryption_key: HpkePublicKey(random_bytes(rng)),
+            signature_key: SignaturePublicKey(random_bytes(rng)),
+            credential: if rng.gen() {
+                Credential::Basic(BasicIdentity(random_bytes(rng)))
+            } else {
+                Credential::X509(CertificateChain(vec![
+                    Certificate(random_bytes(rng)),
+                    Certificate(random_bytes(rng)),
+                ]))
+            },
+            capabilities: Capabilities {
+                versions: vec![MLS10],
+                cipher_suites: vec![0x0001, 0x0003],
+                extensions: Vec::new(),
+                proposals: vec![0x0001, 0x0002, 0x0003],
+                credentials: vec![0x0001],
+            },
+            leaf_node_source: source,
+            extensions: vec![Extension {
+                extension_type: rng.gen(),
+                extension_data: random_bytes(rng),
+            }],
+            signature: random_bytes(rng),
+        }
+    }
+
+    fn random_key_package<R: RngCore>(rng: &mut R) -> KeyPackage {
+        let lifetime = Lifetime {
+            not_before: rng.gen(),
+            not_after: rng.gen(),
+        };
+        KeyPackage {
+            version: MLS10,
+            cipher_suite: 0x0001,
+            init_key: HpkePublicKey(random_bytes(rng)),
+            leaf_node: random_leaf_node(rng, LeafNodeSource::KeyPackage(lifetime)),
+            extensions: Vec::new(),
+            signature: random_bytes(rng),
+        }
+    }
+
+    fn random_ciphertext<R: RngCore>(rng: &mut R) -> HpkeCiphertext {
+        HpkeCiphertext {
+            kem_output: random_bytes(rng),
+            ciphertext: random_bytes(rng),
+        }
+    }
+
+    // Serializes the given message and makes sure it deserializes to the same thing in both modes
+    fn check_round_trip(message: &MlsMessage) {
+        let bytes = message.to_bytes().unwrap();
+        assert_eq!(WireVersion::detect(&bytes), WireVersion::Rfc9420);
+        for &mode in &[ParseMode::Strict, ParseMode::Lenient] {
+            assert_eq!(&MlsMessage::from_bytes(&bytes, mode).unwrap(), message);
+        }
+    }
+
+    // Checks that every supported message type survives a round trip
+    #[quickcheck]
+    fn rfc9420_round_trip(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+
+        let key_package = random_key_package(&mut rng);
+        check_round_trip(&MlsMessage::new(MlsMessageBody::KeyPackage(key_package.clone())));
+
+        let group_info = GroupInfo {
+            group_context: GroupContext {
+                version: MLS10,
+                cipher_suite: 0x0001,
+                group_id: random_bytes(&mut rng),
+                epoch: rng.gen(),
+                tree_hash: random_bytes(&mut rng),
+                confirmed_transcript_hash: random_bytes(&mut rng),
+                extensions: Vec::new(),
+            },
+            extensions: Vec::new(),
+            confirmation_tag: random_bytes(&mut rng),
+            signer: rng.gen(),
+            signature: random_bytes(&mut rng),
+        };
+        check_round_trip(&MlsMessage::new(MlsMessageBody::GroupInfo(group_info)));
+
+        let welcome = Welcome {
+            cipher_suite: 0x0001,
+            secrets: vec![EncryptedGroupSecrets {
+                new_member: HashReference(random_bytes(&mut rng)),
+                encrypted_group_secrets: random_ciphertext(&mut rng),
+            }],
+            encrypted_group_info: random_bytes(&mut rng),
+        };
+        check_round_trip(&MlsMessage::new(MlsMessageBody::Welcome(welcome)));
+
+        let private_message = PrivateMessage {
+            group_id: random_bytes(&mut rng),
+            epoch: rng.gen(),
+            content_type: ContentType::Commit,
+            authenticated_data: random_bytes(&mut rng),
+            encrypted_sender_data: random_bytes(&mut rng),
+            ciphertext: random_bytes(&mut rng),
+        };
+        check_round_trip(&MlsMessage::new(MlsMessageBody::PrivateMessage(private_message)));
+
+        // Commits only travel encrypted or in a PublicMessage, so check them on their own
+        let parent_hash = ParentHash(random_bytes(&mut rng));
+        let commit = Commit {
+            proposals: vec![
+                ProposalOrRef::Proposal(Proposal::Add(key_package)),
+                ProposalOrRef::Proposal(Proposal::Update(random_leaf_node(
+                    &mut rng,
+                    LeafNodeSource::Update,
+                ))),
+                ProposalOrRef::Proposal(Proposal::Remove(rng.gen())),
+                ProposalOrRef::Reference(HashReference(random_bytes(&mut rng))),
+            ],
+            path: Some(UpdatePath {
+                leaf_node: random_leaf_node(&mut rng, LeafNodeSource::Commit(parent_hash)),
+                nodes: vec![UpdatePathNode {
+                    encryption_key: HpkePublicKey(random_bytes(&mut rng)),
+                    encrypted_path_secret: vec![
+                        random_ciphertext(&mut rng),
+                        random_ciphertext(&mut rng),
+                    ],
+                }],
+            }),
+        };
+        let bytes = tls_ser::serialize_to_bytes(&commit).unwrap();
+        let mut cursor = bytes.as_slice();
+        let mut deserializer =
+            TlsDeserializer::from_reader_with_mode(&mut cursor, ParseMode::Strict);
+        assert_eq!(Commit::deserialize(&mut deserializer).unwrap(), commit);
+        deserializer.finish().unwrap();
+    }
+
+    // Checks the byte layout of a small message by hand, and that other versions, reserved tags,
+    // and unsupported wire formats are refused
+    #[test]
+    fn rfc9420_framing() {
+        let message = MlsMessage::new(MlsMessageBody::PrivateMessage(PrivateMessage {
+            group_id: vec![0xaa],
+            epoch: 2,
+            content_type: ContentType::Application,
+            authenticated_data: Vec::new(),
+            encrypted_sender_data: vec![0xbb, 0xcc],
+            ciphertext: vec![0xdd; 64],
+        }));
+        let bytes = message.to_bytes().unwrap();
+        #[rustfmt::skip]
+        let expected_prefix: &[u8] = &[
+            0x00, 0x01,                                      // mls10
+            0x00, 0x02,                                      // mls_private_message
+            0x01, 0xaa,                                      // group_id
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02,  // epoch
+            0x01,                                            // application
+            0x00,                                            // authenticated_data
+            0x02, 0xbb, 0xcc,                                // encrypted_sender_data
+            0x40, 0x40, 0xdd,                                // ciphertext, with a 2-byte varint
+        ];
+        assert_eq!(&bytes[..expected_prefix.len()], expected_prefix);
+        assert_eq!(bytes.len(), expected_prefix.len() + 63);
+
+        // Legacy messages are detected as such, and aren't MLS 1.0 messages
+        let legacy = [MLS_DUMMY_VERSION.0, 0x00, 0x00];
+        assert_eq!(WireVersion::detect(&legacy), WireVersion::Legacy);
+        assert!(MlsMessage::from_bytes(&legacy, ParseMode::Lenient).is_err());
+
+        // Another version is refused even if the rest parses
+        let mut other_version = bytes.clone();
+        other_version[1] = 0x02;
+        assert!(MlsMessage::from_bytes(&other_version, ParseMode::Lenient).is_err());
+
+        // Reserved and unimplemented wire formats are refused
+        for &wire_format in &[0x00, 0x01, 0x06] {
+            let mut other_format = bytes.clone();
+            other_format[3] = wire_format;
+            assert!(MlsMessage::from_bytes(&other_format, ParseMode::Lenient).is_err());
+        }
+
+        // So is a reserved content type
+        let mut reserved_content = bytes.clone();
+        reserved_content[14] = 0x00;
+        assert!(MlsMessage::from_bytes(&reserved_content, ParseMode::Lenient).is_err());
+    }
+}
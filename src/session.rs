@@ -0,0 +1,1909 @@
+//! Defines `Session`, which tracks a single member's ongoing participation in a group. Unlike a
+//! bare `GroupState`, a `Session` copes with the delivery service's quirks, like handshakes that
+//! arrive out of order.
+
+use crate::{
+    application::{self, ApplicationKeyChain, ApplicationMessage, DecryptedMessage},
+    config::{StaleMemberPolicy, UpdatePolicy},
+    credential::{Identity, RosterIndex},
+    crypto::rng::CryptoRng,
+    delivery::DeliveryService,
+    directory::{self, UserInitKeyDirectory},
+    error::Error,
+    group_policy::GroupPolicy,
+    group_state::{GroupState, Welcome},
+    handshake::{Handshake, UserInitKey},
+    history::HistoryLog,
+    observer::GroupObserver,
+    ratchet_tree::PathSecret,
+    tls_ser,
+};
+
+use std::collections::{BTreeMap, VecDeque};
+
+/// The default number of epochs past the current one that a `Session` will buffer handshakes for
+pub const DEFAULT_HANDSHAKE_BUFFER_SIZE: usize = 16;
+
+/// The most handshakes a `Session` will buffer for any one future epoch. Only one of them can be
+/// applied, but nothing about them is checked until their epoch comes, so a forged one can't be
+/// allowed to push out the real one.
+pub const MAX_BUFFERED_HANDSHAKES_PER_EPOCH: usize = 4;
+
+/// The default number of epochs, counting the current one, whose application messages a `Session`
+/// can decrypt. By default, only the current epoch's messages are decryptable.
+pub const DEFAULT_EPOCH_RETENTION: usize = 1;
+
+/// The default number of future-epoch application messages a `Session` will hold onto
+pub const DEFAULT_MESSAGE_BUFFER_SIZE: usize = 16;
+
+/// The default number of epochs a `Session` will fetch from the delivery service to catch up to a
+/// handshake. See `Session::set_max_recovery_gap`.
+pub const DEFAULT_MAX_RECOVERY_GAP: u32 = 1024;
+
+/// What a `Session` does with a future-epoch application message when its message buffer is full.
+/// See `Session::set_message_buffer_overflow`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MessageBufferOverflow {
+    /// The new message is rejected and the buffered ones are kept. This is the default.
+    RejectNew,
+    /// The oldest buffered message is dropped to make room for the new one
+    EvictOldest,
+}
+
+/// A bounded queue of handshakes for epochs we haven't reached yet, keyed by the epoch they were
+/// sent in. Only the `capacity` many epochs right after the current one are kept, with up to
+/// `MAX_BUFFERED_HANDSHAKES_PER_EPOCH` candidates for each, in the order they arrived.
+struct HandshakeBuffer {
+    pending: BTreeMap<u32, Vec<Handshake>>,
+    capacity: usize,
+}
+
+impl HandshakeBuffer {
+    fn new(capacity: usize) -> HandshakeBuffer {
+        HandshakeBuffer {
+            pending: BTreeMap::new(),
+            capacity,
+        }
+    }
+
+    /// Returns whether `epoch` is one of the future epochs this buffer keeps handshakes for, given
+    /// that we're in `current_epoch`
+    fn in_window(&self, current_epoch: u32, epoch: u32) -> bool {
+        epoch > current_epoch && (epoch - current_epoch) as usize <= self.capacity
+    }
+
+    /// Queues a handshake that was sent in a future epoch. Nothing about a handshake is checked
+    /// until its epoch arrives, so it's queued alongside whatever else claims to be from that
+    /// epoch.
+    ///
+    /// Returns: `Ok(())` on success. Returns an `Error::ValidationError` if the handshake's epoch
+    /// is more than `capacity` epochs past `current_epoch`, or if that epoch already has
+    /// `MAX_BUFFERED_HANDSHAKES_PER_EPOCH` handshakes queued.
+    fn insert(&mut self, current_epoch: u32, handshake: Handshake) -> Result<(), Error> {
+        if !self.in_window(current_epoch, handshake.prior_epoch) {
+            return Err(Error::ValidationError("Handshake is too far ahead to be buffered"));
+        }
+
+        let candidates = self.pending.entry(handshake.prior_epoch).or_default();
+        if candidates.len() >= MAX_BUFFERED_HANDSHAKES_PER_EPOCH {
+            return Err(Error::ValidationError("Too many handshakes are buffered for that epoch"));
+        }
+        candidates.push(handshake);
+        Ok(())
+    }
+
+    /// Removes and returns the handshakes sent in the given epoch, in the order they arrived
+    fn take(&mut self, epoch: u32) -> Vec<Handshake> {
+        self.pending.remove(&epoch).unwrap_or_default()
+    }
+
+    /// Returns the number of handshakes queued across all epochs
+    fn len(&self) -> usize {
+        self.pending.values().map(Vec::len).sum()
+    }
+
+    /// Drops every handshake sent before the given epoch. These can never be applied.
+    fn prune_before(&mut self, epoch: u32) {
+        self.pending = self.pending.split_off(&epoch);
+    }
+}
+
+/// A bounded queue of application messages from epochs we haven't reached yet, in the order they
+/// arrived
+struct MessageBuffer {
+    pending: VecDeque<ApplicationMessage>,
+    capacity: usize,
+    overflow: MessageBufferOverflow,
+}
+
+impl MessageBuffer {
+    fn new(capacity: usize) -> MessageBuffer {
+        MessageBuffer {
+            pending: VecDeque::new(),
+            capacity,
+            overflow: MessageBufferOverflow::RejectNew,
+        }
+    }
+
+    /// Queues an application message that was sent in a future epoch
+    ///
+    /// Returns: `Ok(evicted)` on success, where `evicted` is the message that was dropped to make
+    /// room for this one, if any. Returns an `Error::ValidationError` if the buffer is full and
+    /// the overflow policy is `MessageBufferOverflow::RejectNew`, or if the buffer has no room at
+    /// all.
+    fn insert(
+        &mut self,
+        app_message: ApplicationMessage,
+    ) -> Result<Option<ApplicationMessage>, Error> {
+        if self.capacity == 0 {
+            return Err(Error::ValidationError("Application message is from a future epoch"));
+        }
+
+        let mut evicted = None;
+        if self.pending.len() >= self.capacity {
+            match self.overflow {
+                MessageBufferOverflow::RejectNew => {
+                    return Err(Error::ValidationError("Application message buffer is full"))
+                }
+                MessageBufferOverflow::EvictOldest => evicted = self.pending.pop_front(),
+            }
+        }
+
+        self.pending.push_back(app_message);
+        Ok(evicted)
+    }
+
+    /// Removes and returns every message sent in the given epoch or before it, oldest first
+    fn take_up_to(&mut self, epoch: u32) -> VecDeque<ApplicationMessage> {
+        let (ready, waiting) = self.pending.drain(..).partition(|m| m.get_epoch() <= epoch);
+        self.pending = waiting;
+        ready
+    }
+
+    /// Drops the oldest messages until there are few enough to fit the capacity, and returns how
+    /// many were dropped
+    fn shrink_to_capacity(&mut self) -> usize {
+        let num_evicted = self.pending.len().saturating_sub(self.capacity);
+        self.pending.drain(..num_evicted);
+        num_evicted
+    }
+}
+
+/// Returns the roster indices of the members of `old` who aren't at the same place in `new`,
+/// either because they were removed or because someone else took their place
+fn departed_members(old: &GroupState, new: &GroupState) -> Vec<usize> {
+    old.roster
+        .0
+        .iter()
+        .enumerate()
+        .filter_map(|(i, old_entry)| {
+            let old_cred = old_entry.as_ref()?;
+            match new.roster.0.get(i).and_then(Option::as_ref) {
+                Some(new_cred) if new_cred.get_identity() == old_cred.get_identity() => None,
+                _ => Some(i),
+            }
+        })
+        .collect()
+}
+
+/// Returns the roster indices of the members of `new` whose leaf public key isn't the one at their
+/// place in `old`. These are the members who refreshed their leaf on the way from `old` to `new`,
+/// including anyone who was just added.
+fn refreshed_members(old: &GroupState, new: &GroupState) -> Vec<usize> {
+    let old_leaf_keys: Vec<Option<&[u8]>> = old
+        .tree
+        .leaves()
+        .map(|(_, leaf)| leaf.get_public_key().map(|key| key.as_bytes()))
+        .collect();
+    new.tree
+        .leaves()
+        .enumerate()
+        .filter_map(|(i, (_, leaf))| {
+            let new_leaf_key = leaf.get_public_key()?.as_bytes();
+            match old_leaf_keys.get(i) {
+                Some(Some(old_leaf_key)) if *old_leaf_key == new_leaf_key => None,
+                _ => Some(i),
+            }
+        })
+        .collect()
+}
+
+/// A handshake we made that's waiting for the delivery service to echo it back, along with the
+/// state it leads to. See `Session::set_echo_confirmation`.
+struct PendingOwnHandshake {
+    // The handshake as we sent it. The echo has to match this exactly.
+    handshake_bytes: Vec<u8>,
+    handshake: Handshake,
+    group_state: GroupState,
+    app_key_chain: ApplicationKeyChain,
+    // Whether the handshake replaced our leaf secret, i.e., whether it's an Update or a Remove
+    replaces_own_path: bool,
+}
+
+/// What's kept of a past epoch so that its late application messages can still be decrypted. The
+/// group state has had all its secrets erased and the key chain is receive-only. The keys of
+/// members who have since left the group are erased too.
+struct PastEpoch {
+    group_state: GroupState,
+    app_key_chain: ApplicationKeyChain,
+}
+
+/// A member's view of a group over time. This holds the current `GroupState` and
+/// `ApplicationKeyChain`, and applies incoming handshakes in epoch order, buffering any that
+/// arrive early. It also holds the receive keys of up to `epoch_retention - 1` past epochs, oldest
+/// first, and optionally a log of every handshake it's applied.
+pub struct Session {
+    group_state: GroupState,
+    // A fresh group has no application keys until its first handshake
+    app_key_chain: Option<ApplicationKeyChain>,
+    handshake_buffer: HandshakeBuffer,
+    past_epochs: VecDeque<PastEpoch>,
+    epoch_retention: usize,
+    // Only kept if set_history_logging turned it on
+    history: Option<HistoryLog>,
+    // Told about every handshake from another member, if set_observer was called
+    observer: Option<Box<dyn GroupObserver>>,
+    // The epoch in which our leaf secret was last replaced, as far as this session knows
+    last_own_path_epoch: u32,
+    // The epoch in which each roster entry's leaf was last replaced, as far as this session knows.
+    // Empty roster entries have None.
+    last_refresh_epochs: Vec<Option<u32>>,
+    // Whether our own handshakes wait for their echo before they're applied
+    echo_confirmation: bool,
+    // Our handshake that's waiting for its echo. Only used with echo confirmation.
+    pending_own: Option<PendingOwnHandshake>,
+    // The prior epochs and serializations of the handshakes we've applied since the last one from
+    // anyone else, so that their echoes can be recognized. Only used without echo confirmation.
+    sent_handshakes: Vec<(u32, Vec<u8>)>,
+    message_buffer: MessageBuffer,
+    // Whether buffered application messages that are evicted or fail to decrypt are reported in
+    // retried_messages, or just dropped
+    report_message_errors: bool,
+    // What became of the buffered application messages since take_retried_messages was last
+    // called, in the order it happened
+    retried_messages: Vec<Result<DecryptedMessage, Error>>,
+    // The most epochs handle_handshake_with_recovery will fetch to catch up to a handshake
+    max_recovery_gap: u32,
+}
+
+impl Session {
+    /// Makes a `Session` out of the given group state and application key chain, with room for
+    /// `DEFAULT_HANDSHAKE_BUFFER_SIZE` epochs' worth of out-of-order handshakes and
+    /// `DEFAULT_MESSAGE_BUFFER_SIZE` early application messages. The key chain must be the one
+    /// that came out of the handshake that produced `group_state`, or `None` if `group_state` is a
+    /// brand new singleton group. The session keeps as many epochs' keys as the group's
+    /// `GroupConfig` says.
+    pub fn new(group_state: GroupState, app_key_chain: Option<ApplicationKeyChain>) -> Session {
+        Session::with_handshake_buffer_size(
+            group_state,
+            app_key_chain,
+            DEFAULT_HANDSHAKE_BUFFER_SIZE,
+        )
+    }
+
+    /// Like `Session::new`, but buffers out-of-order handshakes for at most `buffer_size` many
+    /// epochs past the current one. Handshakes from further ahead are rejected.
+    pub fn with_handshake_buffer_size(
+        group_state: GroupState,
+        app_key_chain: Option<ApplicationKeyChain>,
+        buffer_size: usize,
+    ) -> Session {
+        let epoch_retention = group_state.config.epoch_retention;
+        let last_own_path_epoch = group_state.epoch;
+        let last_refresh_epochs = group_state
+            .roster
+            .0
+            .iter()
+            .map(|entry| entry.as_ref().map(|_| group_state.epoch))
+            .collect();
+        Session {
+            group_state,
+            app_key_chain,
+            handshake_buffer: HandshakeBuffer::new(buffer_size),
+            past_epochs: VecDeque::new(),
+            epoch_retention,
+            history: None,
+            observer: None,
+            last_own_path_epoch,
+            last_refresh_epochs,
+            echo_confirmation: false,
+            pending_own: None,
+            sent_handshakes: Vec::new(),
+            message_buffer: MessageBuffer::new(DEFAULT_MESSAGE_BUFFER_SIZE),
+            report_message_errors: true,
+            retried_messages: Vec::new(),
+            max_recovery_gap: DEFAULT_MAX_RECOVERY_GAP,
+        }
+    }
+
+    /// Sets how many epochs, counting the current one, this session keeps application keys for.
+    /// Late messages from the last `num_epochs - 1` past epochs can still be decrypted, unless
+    /// their sender has left the group since, but nothing can be encrypted under a past epoch.
+    /// Lowering this immediately erases the keys of every epoch that no longer fits. A value of 0
+    /// is treated as 1, since the current epoch's keys are always kept. This also bounds how long
+    /// the `Welcome`s of members this session added can be re-issued. See
+    /// `GroupState::reissue_welcome`.
+    pub fn set_epoch_retention(&mut self, num_epochs: usize) {
+        self.epoch_retention = core::cmp::max(num_epochs, 1);
+        self.group_state.config.epoch_retention = self.epoch_retention;
+        self.group_state.erase_old_epochs();
+        self.prune_past_epochs();
+    }
+
+    /// Turns the history log on or off. When it's turned on, it starts out empty, and every
+    /// handshake this session applies from then on is appended to it. Turning it off drops it.
+    /// Logging is off by default.
+    pub fn set_history_logging(&mut self, enabled: bool) {
+        if !enabled {
+            self.history = None;
+        } else if self.history.is_none() {
+            self.history = Some(HistoryLog::new(self.group_state.group_id.clone()));
+        }
+    }
+
+    /// Returns the history log, or `None` if logging is off. See `Session::set_history_logging`.
+    pub fn history(&self) -> Option<&HistoryLog> {
+        self.history.as_ref()
+    }
+
+    /// Sets the observer that's told what every handshake handled by `Session::handle_handshake`
+    /// does, replacing any previous one. Passing `None` removes it. Handshakes this session
+    /// creates itself aren't reported. See `GroupState::process_handshake_with_observer`.
+    pub fn set_observer(&mut self, observer: Option<Box<dyn GroupObserver>>) {
+        self.observer = observer;
+    }
+
+    /// Sets whether handshakes this session makes wait for the delivery service to echo them back
+    /// before they're applied. This is off by default, in which case they're applied as soon as
+    /// they're made, and their echoes are recognized and ignored by `Session::handle_handshake`.
+    ///
+    /// With this on, the `create_and_apply_*` methods return the handshake without applying it,
+    /// and only one can be waiting at a time. When its echo arrives, it's applied. If another
+    /// member's handshake for the same epoch arrives first, the delivery service has ordered that
+    /// one first, so ours is dropped and theirs is applied instead. Ours is only dropped once
+    /// theirs has been applied, so a handshake that fails to apply can't cancel it. Turning this
+    /// off drops the waiting handshake, if there is one.
+    pub fn set_echo_confirmation(&mut self, enabled: bool) {
+        self.echo_confirmation = enabled;
+        if !enabled {
+            self.pending_own = None;
+        }
+        self.sent_handshakes.clear();
+    }
+
+    /// Returns whether one of this session's own handshakes is waiting for its echo. See
+    /// `Session::set_echo_confirmation`.
+    pub fn has_pending_handshake(&self) -> bool {
+        self.pending_own.is_some()
+    }
+
+    /// Sets how many application messages from future epochs this session holds onto, dropping the
+    /// oldest ones if there are more than that already. A value of 0 turns buffering off, in which
+    /// case `Session::handle_application_message` rejects such messages outright. Anything can be
+    /// made to look like a message from a future epoch, so this is what keeps a flood of them from
+    /// using up memory. It's `DEFAULT_MESSAGE_BUFFER_SIZE` by default.
+    pub fn set_message_buffer_size(&mut self, buffer_size: usize) {
+        self.message_buffer.capacity = buffer_size;
+        for _ in 0..self.message_buffer.shrink_to_capacity() {
+            self.report_message_error(Error::ValidationError(
+                "Buffered application message was evicted",
+            ));
+        }
+    }
+
+    /// Sets what happens to a future-epoch application message when the message buffer is full.
+    /// It's `MessageBufferOverflow::RejectNew` by default.
+    pub fn set_message_buffer_overflow(&mut self, overflow: MessageBufferOverflow) {
+        self.message_buffer.overflow = overflow;
+    }
+
+    /// Sets whether buffered application messages that are evicted, or that fail to decrypt once
+    /// their epoch arrives, show up as errors in `Session::take_retried_messages`. When this is
+    /// off, they're dropped without a trace. This is on by default.
+    pub fn set_message_error_reporting(&mut self, enabled: bool) {
+        self.report_message_errors = enabled;
+    }
+
+    /// Sets the most epochs `Session::handle_handshake_with_recovery` will fetch from the delivery
+    /// service to catch up to a handshake. The epoch a handshake claims to be from isn't
+    /// authenticated until it's applied, so this is what keeps a forged one from making us ask for
+    /// an arbitrarily large range. It's `DEFAULT_MAX_RECOVERY_GAP` by default.
+    pub fn set_max_recovery_gap(&mut self, num_epochs: u32) {
+        self.max_recovery_gap = num_epochs;
+    }
+
+    /// Returns the number of application messages waiting for their epoch to arrive
+    pub fn num_buffered_messages(&self) -> usize {
+        self.message_buffer.pending.len()
+    }
+
+    /// Returns what became of the buffered application messages since the last call, oldest
+    /// first. Buffered messages are retried as soon as this session reaches their epoch, so this
+    /// is worth calling after every handshake this session applies. Evictions and failed retries
+    /// are only included if `Session::set_message_error_reporting` says so.
+    pub fn take_retried_messages(&mut self) -> Vec<Result<DecryptedMessage, Error>> {
+        core::mem::take(&mut self.retried_messages)
+    }
+
+    // Notes that a buffered application message was lost, if we're reporting that
+    fn report_message_error(&mut self, err: Error) {
+        if self.report_message_errors {
+            self.retried_messages.push(Err(err));
+        }
+    }
+
+    /// Returns the number of past epochs whose late application messages can still be decrypted
+    pub fn num_retained_past_epochs(&self) -> usize {
+        self.past_epochs.len()
+    }
+
+    /// Returns the current state of the group
+    pub fn group_state(&self) -> &GroupState {
+        &self.group_state
+    }
+
+    /// Returns the current epoch's application key chain, or `None` if the group hasn't had its
+    /// first handshake yet
+    pub(crate) fn app_key_chain(&self) -> Option<&ApplicationKeyChain> {
+        self.app_key_chain.as_ref()
+    }
+
+    /// Returns whether this member should refresh their leaf, according to the group's
+    /// `UpdatePolicy`. The count starts over whenever this session makes an Update or a Remove,
+    /// and starts from the session's first epoch. An update is also due once this member has
+    /// reached their `GroupConfig`'s send limit for the current epoch.
+    pub fn is_update_due(&self) -> bool {
+        if self.is_send_limit_reached() {
+            return true;
+        }
+
+        match self.group_state.config.update_policy {
+            UpdatePolicy::Manual => false,
+            UpdatePolicy::EveryNEpochs(n) => {
+                self.group_state.epoch.wrapping_sub(self.last_own_path_epoch) >= n
+            }
+        }
+    }
+
+    /// Returns whether this member has sent as many application messages in the current epoch as
+    /// their `GroupConfig` allows. If so, `Session::encrypt_application_message` returns
+    /// `Error::UpdateRequired` until the group moves to a new epoch.
+    pub fn is_send_limit_reached(&self) -> bool {
+        let max_messages = match self.group_state.config.max_messages_per_epoch {
+            Some(max_messages) => max_messages,
+            None => return false,
+        };
+        match (self.group_state.roster_index, self.app_key_chain.as_ref()) {
+            (Some(roster_idx), Some(app_key_chain)) => app_key_chain
+                .get_generation(roster_idx.0 as usize)
+                .map(|generation| generation >= max_messages)
+                .unwrap_or(false),
+            _ => false,
+        }
+    }
+
+    /// Returns the epoch in which the member at the given roster index last refreshed their leaf,
+    /// as far as this session knows. Members who were already in the group when this session
+    /// started count as having refreshed in its first epoch.
+    ///
+    /// Returns: `Some(epoch)` on success, and `None` if the roster entry is empty or out of bounds
+    pub fn last_refresh_epoch(&self, roster_idx: RosterIndex) -> Option<u32> {
+        self.last_refresh_epochs.get(roster_idx.0 as usize).cloned().flatten()
+    }
+
+    /// Returns the roster indices of the other members who haven't refreshed their leaf in as
+    /// many epochs as the group's `StaleMemberPolicy` allows, in ascending order. This member is
+    /// never included. See `Session::is_update_due` for that.
+    pub fn stale_members(&self) -> Vec<RosterIndex> {
+        let max_epochs = match self.group_state.config.stale_member_policy {
+            StaleMemberPolicy::Ignore => return Vec::new(),
+            StaleMemberPolicy::Flag(n) | StaleMemberPolicy::Remove(n) => n,
+        };
+        let current_epoch = self.group_state.epoch;
+        self.last_refresh_epochs
+            .iter()
+            .enumerate()
+            .filter_map(|(i, last_refresh_epoch)| {
+                let last_refresh_epoch = (*last_refresh_epoch)?;
+                let i = RosterIndex(i as u32);
+                let is_stale = current_epoch.wrapping_sub(last_refresh_epoch) >= max_epochs;
+                if is_stale && Some(i) != self.group_state.roster_index {
+                    Some(i)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Creates and applies a Remove for every member returned by `Session::stale_members`, one
+    /// after the other, each with a fresh random path secret. If nobody is stale, this does
+    /// nothing. This can't be used with echo confirmation if there's more than one stale member.
+    ///
+    /// Returns: `Ok(handshakes)` on success. Returns an `Error::ValidationError` if the group's
+    /// `StaleMemberPolicy` isn't `StaleMemberPolicy::Remove`. Otherwise returns whatever
+    /// `GroupState::create_and_apply_remove_handshake` returns, in which case nobody is removed.
+    pub fn remove_stale_members<R>(&mut self, csprng: &mut R) -> Result<Vec<Handshake>, Error>
+    where
+        R: CryptoRng,
+    {
+        match self.group_state.config.stale_member_policy {
+            StaleMemberPolicy::Remove(_) => (),
+            _ => {
+                return Err(Error::ValidationError(
+                    "The stale member policy doesn't allow removing stale members",
+                ))
+            }
+        }
+
+        let mut handshakes = Vec::new();
+        let mut latest = None;
+        for roster_idx in self.stale_members() {
+            let group_state = match latest {
+                Some((ref group_state, _)) => group_state,
+                None => &self.group_state,
+            };
+            let new_path_secret = PathSecret::new_from_random(group_state.cs, csprng);
+            let (handshake, group_state, app_key_chain) = group_state
+                .create_and_apply_remove_handshake(roster_idx, new_path_secret, csprng)?;
+            handshakes.push(handshake);
+            latest = Some((group_state, app_key_chain));
+        }
+
+        if let Some((group_state, app_key_chain)) = latest {
+            self.handle_own_handshakes(&handshakes, group_state, app_key_chain, true)?;
+        }
+        Ok(handshakes)
+    }
+
+    /// Returns the number of handshakes waiting for an earlier epoch to be processed
+    pub fn num_buffered_handshakes(&self) -> usize {
+        self.handshake_buffer.len()
+    }
+
+    // Logs handshakes that are about to be applied to the current state, if we're keeping a log
+    fn record_history(&mut self, handshakes: &[Handshake]) -> Result<(), Error> {
+        match self.history {
+            Some(ref mut history) => history.record(&self.group_state, handshakes),
+            None => Ok(()),
+        }
+    }
+
+    // Moves us to the next epoch
+    fn advance(&mut self, group_state: GroupState, app_key_chain: ApplicationKeyChain) {
+        let departed = departed_members(&self.group_state, &group_state);
+
+        // Note who refreshed their leaf, and forget about whoever left
+        let refreshed = refreshed_members(&self.group_state, &group_state);
+        self.last_refresh_epochs.resize(group_state.roster.len(), None);
+        for (i, last_refresh_epoch) in self.last_refresh_epochs.iter_mut().enumerate() {
+            if group_state.roster.0[i].is_none() {
+                *last_refresh_epoch = None;
+            } else if refreshed.contains(&i) {
+                *last_refresh_epoch = Some(group_state.epoch);
+            }
+        }
+
+        let mut old_group_state = core::mem::replace(&mut self.group_state, group_state);
+        let old_app_key_chain = self.app_key_chain.replace(app_key_chain);
+
+        // Hang onto the old epoch's receive keys, but nothing that lets us send in it or derive
+        // anything new from it
+        if let Some(mut old_app_key_chain) = old_app_key_chain {
+            old_group_state.erase_all_secrets();
+            old_app_key_chain.make_receive_only();
+            self.past_epochs.push_back(PastEpoch {
+                group_state: old_group_state,
+                app_key_chain: old_app_key_chain,
+            });
+        }
+        self.prune_past_epochs();
+
+        // Whoever just left takes their keys with them. Their late messages from the epochs we're
+        // keeping are no longer decryptable.
+        for past_epoch in self.past_epochs.iter_mut() {
+            for &roster_idx in departed.iter() {
+                past_epoch.app_key_chain.forget_sender(roster_idx);
+            }
+        }
+
+        // Anything queued for an epoch we've now passed is useless
+        self.handshake_buffer.prune_before(self.group_state.epoch);
+
+        // Application messages that were waiting on this epoch can be decrypted now
+        for app_message in self.message_buffer.take_up_to(self.group_state.epoch) {
+            match self.decrypt_application_message(app_message) {
+                Ok(decrypted) => self.retried_messages.push(Ok(decrypted)),
+                Err(e) => self.report_message_error(e),
+            }
+        }
+    }
+
+    // Drops the oldest past epochs until there are few enough to stay within the retention limit.
+    // Their keys erase themselves on drop.
+    fn prune_past_epochs(&mut self) {
+        // The current epoch counts against the limit
+        let max_past_epochs = self.epoch_retention - 1;
+        while self.past_epochs.len() > max_past_epochs {
+            self.past_epochs.pop_front();
+        }
+    }
+
+    /// Processes a `Handshake` from the delivery service. If the `Handshake` is for a future epoch,
+    /// it's buffered until all the handshakes before it have been processed. If it's for the
+    /// current epoch, it's applied, along with any buffered handshakes that directly follow it.
+    /// If it's the echo of one of this session's own handshakes, it's reconciled with what this
+    /// session already did. See `Session::set_echo_confirmation`.
+    ///
+    /// Returns: `Ok(n)` on success, where `n` is the number of handshakes that were applied by this
+    /// call. This is 0 if the handshake was buffered, or if it's the echo of a handshake that was
+    /// already applied. Returns an `Error::ValidationError` if the handshake is from a past epoch,
+    /// or if it can't be buffered. Otherwise returns whatever `GroupState::process_handshake`
+    /// returns for this handshake. Buffered handshakes don't cause errors. Of the ones buffered for
+    /// an epoch, the first to apply wins and the rest are dropped. If none of them apply, they're
+    /// all dropped, and the session stays in that epoch.
+    pub fn handle_handshake(&mut self, handshake: Handshake) -> Result<usize, Error> {
+        let current_epoch = self.group_state.epoch;
+        if handshake.prior_epoch < current_epoch {
+            // This is fine if it's one of ours. We've already applied it.
+            if !self.sent_handshakes.is_empty() {
+                let handshake_bytes = tls_ser::serialize_to_bytes(&handshake)?;
+                let echoed = self.sent_handshakes.iter().position(|(prior_epoch, bytes)| {
+                    *prior_epoch == handshake.prior_epoch && *bytes == handshake_bytes
+                });
+                if let Some(i) = echoed {
+                    self.sent_handshakes.remove(i);
+                    return Ok(0);
+                }
+            }
+            return Err(Error::ValidationError("Handshake is from a past epoch"));
+        } else if handshake.prior_epoch > current_epoch {
+            self.handshake_buffer.insert(current_epoch, handshake)?;
+            return Ok(0);
+        }
+
+        // The handshake is for this epoch. If we're waiting on one of our own, this either is that
+        // one, or it was ordered before ours, in which case ours is dead. Ours stays pending until
+        // the other one is actually applied, since anyone can send something that fails to apply.
+        let is_echo = match self.pending_own {
+            Some(ref pending) => {
+                let handshake_bytes = tls_ser::serialize_to_bytes(&handshake)?;
+                handshake_bytes == pending.handshake_bytes
+            }
+            None => false,
+        };
+        if is_echo {
+            let pending = self.pending_own.take().unwrap();
+            self.apply_own(pending)?;
+        } else {
+            self.apply_other(&handshake)?;
+        }
+
+        // Then apply everything that was waiting on it
+        Ok(1 + self.apply_buffered())
+    }
+
+    // Applies another member's handshake for the current epoch
+    fn apply_other(&mut self, handshake: &Handshake) -> Result<(), Error> {
+        let (group_state, app_key_chain) = match self.observer {
+            Some(ref mut observer) => {
+                self.group_state.process_handshake_with_observer(handshake, observer.as_mut())?
+            }
+            None => self.group_state.process_handshake(handshake)?,
+        };
+        self.record_history(core::slice::from_ref(handshake))?;
+        self.advance(group_state, app_key_chain);
+        // No echo of ours comes after someone else's handshake, and our pending one is dead
+        self.sent_handshakes.clear();
+        self.pending_own = None;
+        Ok(())
+    }
+
+    // Applies the buffered handshakes that follow on from the current epoch, one epoch at a time.
+    // Any of them could be forged, so for each epoch, the first one that applies wins and the rest
+    // are dropped. This stops at the first epoch where none of them apply. Returns the number of
+    // handshakes applied.
+    fn apply_buffered(&mut self) -> usize {
+        let mut num_applied = 0;
+        loop {
+            let candidates = self.handshake_buffer.take(self.group_state.epoch);
+            if !candidates.iter().any(|handshake| self.apply_other(handshake).is_ok()) {
+                return num_applied;
+            }
+            num_applied += 1;
+        }
+    }
+
+    /// Like `Session::handle_handshake`, but a `Handshake` from a future epoch doesn't have to wait
+    /// for the ones before it to show up. Those are fetched from `delivery_service` and applied
+    /// first, in epoch order, and then this one is applied, along with any buffered handshakes
+    /// that directly follow it.
+    ///
+    /// Returns: `Ok(n)` on success, where `n` is the number of handshakes that were applied by this
+    /// call, including the fetched ones. Returns an `Error::ValidationError` if the handshake is
+    /// more than `Session::set_max_recovery_gap` epochs ahead, or if the fetched handshakes don't
+    /// get this session up to the handshake's epoch. Otherwise returns whatever `delivery_service`
+    /// or `Session::handle_handshake` returns. If a fetched handshake fails to apply, the
+    /// handshakes before it remain applied. If the gap can't be filled, the handshake is buffered
+    /// like `Session::handle_handshake` would buffer it, so it's applied once the gap is filled
+    /// some other way.
+    pub fn handle_handshake_with_recovery<D>(
+        &mut self,
+        handshake: Handshake,
+        delivery_service: &D,
+    ) -> Result<usize, Error>
+    where
+        D: DeliveryService + ?Sized,
+    {
+        let mut num_applied = 0;
+        if handshake.prior_epoch > self.group_state.epoch {
+            match self.recover_gap(handshake.prior_epoch, delivery_service) {
+                Ok(n) => num_applied += n,
+                Err(e) => {
+                    // Hang onto it if it fits. Either way, the recovery error is the one that
+                    // matters.
+                    let _ = self.handshake_buffer.insert(self.group_state.epoch, handshake);
+                    return Err(e);
+                }
+            }
+        }
+        num_applied += self.handle_handshake(handshake)?;
+
+        Ok(num_applied)
+    }
+
+    // Fetches and applies the handshakes from the current epoch up to, but not including,
+    // end_epoch. Buffered handshakes in that range are applied along the way, so whatever the
+    // delivery service sends for an epoch we've already passed is skipped.
+    fn recover_gap<D>(&mut self, end_epoch: u32, delivery_service: &D) -> Result<usize, Error>
+    where
+        D: DeliveryService + ?Sized,
+    {
+        let start_epoch = self.group_state.epoch;
+        if end_epoch - start_epoch > self.max_recovery_gap {
+            return Err(Error::ValidationError("Epoch gap is too large to recover"));
+        }
+        let fetched = delivery_service.fetch_handshakes(
+            &self.group_state.group_id,
+            start_epoch,
+            end_epoch,
+        )?;
+
+        let mut num_applied = 0;
+        for handshake in fetched {
+            if self.group_state.epoch >= end_epoch {
+                break;
+            }
+            if handshake.prior_epoch != self.group_state.epoch {
+                continue;
+            }
+            num_applied += self.handle_handshake(handshake)?;
+        }
+
+        if self.group_state.epoch < end_epoch {
+            return Err(Error::ValidationError("Delivery service didn't fill the epoch gap"));
+        }
+        Ok(num_applied)
+    }
+
+    // Applies a handshake we made, now that we know it's been ordered. If that fails, it goes back
+    // to waiting for its echo.
+    fn apply_own(&mut self, pending: PendingOwnHandshake) -> Result<(), Error> {
+        if let Err(e) = self.record_history(core::slice::from_ref(&pending.handshake)) {
+            self.pending_own = Some(pending);
+            return Err(e);
+        }
+        self.advance(pending.group_state, pending.app_key_chain);
+        if pending.replaces_own_path {
+            self.last_own_path_epoch = self.group_state.epoch;
+        }
+        Ok(())
+    }
+
+    // Takes handshakes we just made, which lead to the given state. With echo confirmation, the
+    // state is held until the echo arrives. Otherwise, it's applied right away, and the handshakes
+    // are remembered so that their echoes can be told apart from replays.
+    fn handle_own_handshakes(
+        &mut self,
+        handshakes: &[Handshake],
+        group_state: GroupState,
+        app_key_chain: ApplicationKeyChain,
+        replaces_own_path: bool,
+    ) -> Result<(), Error> {
+        if !self.echo_confirmation {
+            for handshake in handshakes {
+                let handshake_bytes = tls_ser::serialize_to_bytes(handshake)?;
+                self.sent_handshakes.push((handshake.prior_epoch, handshake_bytes));
+            }
+            self.record_history(handshakes)?;
+            self.advance(group_state, app_key_chain);
+            if replaces_own_path {
+                self.last_own_path_epoch = self.group_state.epoch;
+            }
+            return Ok(());
+        }
+
+        if self.pending_own.is_some() {
+            return Err(Error::ValidationError("Another handshake is still waiting for its echo"));
+        }
+        // The first echo would move the group on, but we'd only have the state after the last one
+        let handshake = match handshakes {
+            [handshake] => handshake,
+            _ => {
+                return Err(Error::ValidationError(
+                    "Several handshakes can't wait for their echoes at once",
+                ))
+            }
+        };
+        // Handshakes aren't Clone, so keep a copy off the wire
+        let handshake_bytes = tls_ser::serialize_to_bytes(handshake)?;
+        let handshake = self.group_state.deserialize_handshake(&handshake_bytes)?;
+        self.pending_own = Some(PendingOwnHandshake {
+            handshake_bytes,
+            handshake,
+            group_state,
+            app_key_chain,
+            replaces_own_path,
+        });
+        Ok(())
+    }
+
+    /// Creates and applies the group's Init. See `GroupState::create_and_apply_init_handshake`.
+    ///
+    /// Returns: `Ok(handshake)` on success. Otherwise returns whatever
+    /// `GroupState::create_and_apply_init_handshake` returns.
+    pub fn create_and_apply_init_handshake(&mut self) -> Result<Handshake, Error> {
+        let (handshake, group_state, app_key_chain) =
+            self.group_state.create_and_apply_init_handshake()?;
+        self.handle_own_handshakes(
+            core::slice::from_ref(&handshake),
+            group_state,
+            app_key_chain,
+            false,
+        )?;
+        Ok(handshake)
+    }
+
+    /// Creates and applies an Update. See `GroupState::create_and_apply_update_handshake`.
+    ///
+    /// Returns: `Ok(handshake)` on success. Otherwise returns whatever
+    /// `GroupState::create_and_apply_update_handshake` returns.
+    pub fn create_and_apply_update_handshake<R>(
+        &mut self,
+        new_path_secret: PathSecret,
+        csprng: &mut R,
+    ) -> Result<Handshake, Error>
+    where
+        R: CryptoRng,
+    {
+        let (handshake, group_state, app_key_chain) =
+            self.group_state.create_and_apply_update_handshake(new_path_secret, csprng)?;
+        self.handle_own_handshakes(
+            core::slice::from_ref(&handshake),
+            group_state,
+            app_key_chain,
+            true,
+        )?;
+        Ok(handshake)
+    }
+
+    /// Creates a `Welcome` for the owner of `init_key`, then creates and applies an Add for them.
+    /// See `GroupState::create_and_apply_add_handshake`.
+    ///
+    /// Returns: `Ok((welcome, handshake))` on success. Otherwise returns whatever
+    /// `Welcome::from_group_state` or `GroupState::create_and_apply_add_handshake` returns.
+    pub fn create_and_apply_add_handshake<R>(
+        &mut self,
+        new_roster_index: RosterIndex,
+        init_key: UserInitKey,
+        csprng: &mut R,
+    ) -> Result<(Welcome, Handshake), Error>
+    where
+        R: CryptoRng,
+    {
+        let (welcome, welcome_info_hash) =
+            Welcome::from_group_state(&self.group_state, &init_key, csprng)?;
+        let (handshake, group_state, app_key_chain) = self
+            .group_state
+            .create_and_apply_add_handshake(new_roster_index, init_key, &welcome_info_hash)?;
+        self.handle_own_handshakes(
+            core::slice::from_ref(&handshake),
+            group_state,
+            app_key_chain,
+            false,
+        )?;
+        Ok((welcome, handshake))
+    }
+
+    /// Adds several new members at once. See `GroupState::create_and_apply_add_handshakes`.
+    ///
+    /// Returns: `Ok((welcomes, handshakes))` on success, where `welcomes` pairs each `Welcome` with
+    /// the ID of the `UserInitKey` it's for. Otherwise returns whatever
+    /// `GroupState::create_and_apply_add_handshakes` returns.
+    #[allow(clippy::type_complexity)]
+    pub fn create_and_apply_add_handshakes<R>(
+        &mut self,
+        init_keys: Vec<UserInitKey>,
+        csprng: &mut R,
+    ) -> Result<(Vec<(Vec<u8>, Welcome)>, Vec<Handshake>), Error>
+    where
+        R: CryptoRng,
+    {
+        let (welcomes, handshakes, group_state, app_key_chain) =
+            self.group_state.create_and_apply_add_handshakes(init_keys, csprng)?;
+        self.handle_own_handshakes(&handshakes, group_state, app_key_chain, false)?;
+        Ok((welcomes, handshakes))
+    }
+
+    /// Looks up the `UserInitKey` of the user with the given identity in `directory`, then adds
+    /// them like `Session::create_and_apply_add_handshake` does. They go in the first empty roster
+    /// entry, or at the end if there is none.
+    ///
+    /// Returns: `Ok((welcome, handshake))` on success. Returns an `Error::ValidationError` if the
+    /// user is already a member or the directory returns someone else's key, and an
+    /// `Error::NoCompatibleInitKey` if their key doesn't support this group's ciphersuite.
+    /// Otherwise returns whatever the directory or `Session::create_and_apply_add_handshake`
+    /// returns.
+    pub fn add_member_by_identity<D, R>(
+        &mut self,
+        directory: &D,
+        identity: &Identity,
+        csprng: &mut R,
+    ) -> Result<(Welcome, Handshake), Error>
+    where
+        D: UserInitKeyDirectory + ?Sized,
+        R: CryptoRng,
+    {
+        let (new_roster_index, init_key) =
+            directory::fetch_init_key_for_add(directory, identity, &self.group_state)?;
+        self.create_and_apply_add_handshake(new_roster_index, init_key, csprng)
+    }
+
+    /// Creates and applies a Remove. See `GroupState::create_and_apply_remove_handshake`.
+    ///
+    /// Returns: `Ok(handshake)` on success. Otherwise returns whatever
+    /// `GroupState::create_and_apply_remove_handshake` returns.
+    pub fn create_and_apply_remove_handshake<R>(
+        &mut self,
+        removed_roster_index: RosterIndex,
+        new_path_secret: PathSecret,
+        csprng: &mut R,
+    ) -> Result<Handshake, Error>
+    where
+        R: CryptoRng,
+    {
+        let (handshake, group_state, app_key_chain) = self
+            .group_state
+            .create_and_apply_remove_handshake(removed_roster_index, new_path_secret, csprng)?;
+        self.handle_own_handshakes(
+            core::slice::from_ref(&handshake),
+            group_state,
+            app_key_chain,
+            true,
+        )?;
+        Ok(handshake)
+    }
+
+    /// Creates and applies a BatchRemove. See
+    /// `GroupState::create_and_apply_batch_remove_handshake`.
+    ///
+    /// Returns: `Ok(handshake)` on success. Otherwise returns whatever
+    /// `GroupState::create_and_apply_batch_remove_handshake` returns.
+    pub fn create_and_apply_batch_remove_handshake<R>(
+        &mut self,
+        removed_roster_indices: &[RosterIndex],
+        new_path_secret: PathSecret,
+        csprng: &mut R,
+    ) -> Result<Handshake, Error>
+    where
+        R: CryptoRng,
+    {
+        let (handshake, group_state, app_key_chain) =
+            self.group_state.create_and_apply_batch_remove_handshake(
+                removed_roster_indices,
+                new_path_secret,
+                csprng,
+            )?;
+        self.handle_own_handshakes(
+            core::slice::from_ref(&handshake),
+            group_state,
+            app_key_chain,
+            true,
+        )?;
+        Ok(handshake)
+    }
+
+    /// Creates a `Welcome` for the owner of `init_key`, then creates and applies a Replace that
+    /// puts them where the member at `replaced_roster_index` is now. See
+    /// `GroupState::create_and_apply_replace_handshake`.
+    ///
+    /// Returns: `Ok((welcome, handshake))` on success. Otherwise returns whatever
+    /// `Welcome::from_group_state` or `GroupState::create_and_apply_replace_handshake` returns.
+    pub fn create_and_apply_replace_handshake<R>(
+        &mut self,
+        replaced_roster_index: RosterIndex,
+        init_key: UserInitKey,
+        new_path_secret: PathSecret,
+        csprng: &mut R,
+    ) -> Result<(Welcome, Handshake), Error>
+    where
+        R: CryptoRng,
+    {
+        let (welcome, welcome_info_hash) =
+            Welcome::from_group_state(&self.group_state, &init_key, csprng)?;
+        let (handshake, group_state, app_key_chain) =
+            self.group_state.create_and_apply_replace_handshake(
+                replaced_roster_index,
+                init_key,
+                &welcome_info_hash,
+                new_path_secret,
+                csprng,
+            )?;
+        self.handle_own_handshakes(
+            core::slice::from_ref(&handshake),
+            group_state,
+            app_key_chain,
+            true,
+        )?;
+        Ok((welcome, handshake))
+    }
+
+    /// Creates and applies a PolicyUpdate. See
+    /// `GroupState::create_and_apply_policy_update_handshake`.
+    ///
+    /// Returns: `Ok(handshake)` on success. Otherwise returns whatever
+    /// `GroupState::create_and_apply_policy_update_handshake` returns.
+    pub fn create_and_apply_policy_update_handshake(
+        &mut self,
+        policy: GroupPolicy,
+    ) -> Result<Handshake, Error> {
+        let (handshake, group_state, app_key_chain) =
+            self.group_state.create_and_apply_policy_update_handshake(policy)?;
+        self.handle_own_handshakes(
+            core::slice::from_ref(&handshake),
+            group_state,
+            app_key_chain,
+            false,
+        )?;
+        Ok(handshake)
+    }
+
+    /// Starts a branch of this group with the given members. See
+    /// `GroupState::create_and_apply_branch_handshake`. This session is left as it is.
+    ///
+    /// Returns: `Ok((branch, handshake))` on success, where `branch` is a new `Session` for the
+    /// branch with the same buffering and retention settings as this one. Otherwise returns
+    /// whatever `GroupState::create_and_apply_branch_handshake` returns.
+    pub fn create_branch<R>(
+        &self,
+        new_group_id: Vec<u8>,
+        member_roster_indices: &[RosterIndex],
+        new_path_secret: PathSecret,
+        csprng: &mut R,
+    ) -> Result<(Session, Handshake), Error>
+    where
+        R: CryptoRng,
+    {
+        let (handshake, group_state, app_key_chain) =
+            self.group_state.create_and_apply_branch_handshake(
+                new_group_id,
+                member_roster_indices,
+                new_path_secret,
+                csprng,
+            )?;
+        Ok((self.new_branch_session(group_state, app_key_chain), handshake))
+    }
+
+    /// Joins the branch of this group started by the given `Handshake`. See
+    /// `GroupState::process_branch_handshake`. This session is left as it is.
+    ///
+    /// Returns: `Ok(branch)` on success, where `branch` is a new `Session` for the branch with the
+    /// same buffering and retention settings as this one. Otherwise returns whatever
+    /// `GroupState::process_branch_handshake` returns.
+    pub fn join_branch(
+        &self,
+        member_roster_indices: &[RosterIndex],
+        handshake: &Handshake,
+    ) -> Result<Session, Error> {
+        let (group_state, app_key_chain) =
+            self.group_state.process_branch_handshake(member_roster_indices, handshake)?;
+        Ok(self.new_branch_session(group_state, app_key_chain))
+    }
+
+    // Makes a Session for a branch of this group, with our settings
+    fn new_branch_session(
+        &self,
+        group_state: GroupState,
+        app_key_chain: ApplicationKeyChain,
+    ) -> Session {
+        let mut branch = Session::with_handshake_buffer_size(
+            group_state,
+            Some(app_key_chain),
+            self.handshake_buffer.capacity,
+        );
+        branch.epoch_retention = self.epoch_retention;
+        branch
+    }
+
+    /// Encrypts the given plaintext under the current epoch
+    ///
+    /// Returns: `Ok(app_message)` on success. Returns an `Error::ValidationError` if no handshake
+    /// has happened in this group yet. Otherwise returns whatever
+    /// `application::encrypt_application_message` returns.
+    pub fn encrypt_application_message(
+        &mut self,
+        plaintext: Vec<u8>,
+    ) -> Result<ApplicationMessage, Error> {
+        self.encrypt_application_message_with_aad(plaintext, Vec::new())
+    }
+
+    /// Encrypts the given plaintext under the current epoch, attaching the given authenticated
+    /// data. See `application::encrypt_application_message_with_aad`.
+    ///
+    /// Returns: `Ok(app_message)` on success. Returns an `Error::ValidationError` if no handshake
+    /// has happened in this group yet. Otherwise returns whatever
+    /// `application::encrypt_application_message_with_aad` returns.
+    pub fn encrypt_application_message_with_aad(
+        &mut self,
+        plaintext: Vec<u8>,
+        authenticated_data: Vec<u8>,
+    ) -> Result<ApplicationMessage, Error> {
+        let app_key_chain = self.app_key_chain.as_mut().ok_or(Error::ValidationError(
+            "Group has no application key chain before its first handshake",
+        ))?;
+        application::encrypt_application_message_with_aad(
+            plaintext,
+            authenticated_data,
+            &self.group_state,
+            app_key_chain,
+        )
+    }
+
+    /// Decrypts the given application message under the epoch it was sent in. This is either the
+    /// current epoch or one of the past epochs retained as per `Session::set_epoch_retention`.
+    ///
+    /// Returns: `Ok(decrypted_message)` on success. Returns an `Error::ValidationError` if no
+    /// handshake has happened in this group yet, or if the message is from an epoch whose keys
+    /// aren't retained. Otherwise returns whatever `application::decrypt_application_message`
+    /// returns.
+    pub fn decrypt_application_message(
+        &mut self,
+        app_message: ApplicationMessage,
+    ) -> Result<DecryptedMessage, Error> {
+        let epoch = app_message.get_epoch();
+        if epoch == self.group_state.epoch {
+            let app_key_chain = self.app_key_chain.as_mut().ok_or(Error::ValidationError(
+                "Group has no application key chain before its first handshake",
+            ))?;
+            return application::decrypt_application_message(
+                app_message,
+                &self.group_state,
+                app_key_chain,
+            );
+        }
+
+        let past_epoch = self.past_epochs.iter_mut().find(|p| p.group_state.epoch == epoch).ok_or(
+            Error::ValidationError(
+                "Application message is from an epoch whose keys aren't retained",
+            ),
+        )?;
+        application::decrypt_application_message(
+            app_message,
+            &past_epoch.group_state,
+            &mut past_epoch.app_key_chain,
+        )
+    }
+
+    /// Like `Session::decrypt_application_message`, but an application message from a future
+    /// epoch is buffered instead of rejected. It's retried once this session reaches its epoch,
+    /// and the outcome shows up in `Session::take_retried_messages`. Only messages from the next
+    /// epoch, or from an epoch the handshake buffer reaches, are buffered, since this session
+    /// can't catch up to anything further ahead without dropping them first. See
+    /// `Session::with_handshake_buffer_size`, `Session::set_message_buffer_size` and
+    /// `Session::set_message_buffer_overflow`.
+    ///
+    /// Returns: `Ok(Some(decrypted_message))` if the message was decrypted, and `Ok(None)` if it
+    /// was buffered. Returns an `Error::ValidationError` if it's from a future epoch and can't be
+    /// buffered. Otherwise returns whatever `Session::decrypt_application_message` returns.
+    pub fn handle_application_message(
+        &mut self,
+        app_message: ApplicationMessage,
+    ) -> Result<Option<DecryptedMessage>, Error> {
+        let current_epoch = self.group_state.epoch;
+        let epoch = app_message.get_epoch();
+        if epoch <= current_epoch {
+            return self.decrypt_application_message(app_message).map(Some);
+        }
+        if epoch - current_epoch > 1 && !self.handshake_buffer.in_window(current_epoch, epoch) {
+            return Err(Error::ValidationError(
+                "Application message is too far ahead to be buffered",
+            ));
+        }
+
+        if self.message_buffer.insert(app_message)?.is_some() {
+            self.report_message_error(Error::ValidationError(
+                "Buffered application message was evicted",
+            ));
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        application,
+        config::StaleMemberPolicy,
+        credential::RosterIndex,
+        crypto::rng::CryptoRng,
+        delivery::DeliveryService,
+        error::Error,
+        group_state::GroupState,
+        handshake::Handshake,
+        ratchet_tree::PathSecret,
+        session::{
+            HandshakeBuffer, MessageBufferOverflow, Session, DEFAULT_MESSAGE_BUFFER_SIZE,
+            MAX_BUFFERED_HANDSHAKES_PER_EPOCH,
+        },
+        test_utils, tls_ser,
+        upcast::{self, CryptoCtx},
+    };
+
+    use quickcheck_macros::quickcheck;
+    use rand::{seq::SliceRandom, SeedableRng};
+
+    // Makes a group of at least 2, has one member do `num_updates` many Updates, and returns a
+    // different member's initial state, the Updater's final state, and the Update handshakes
+    fn make_updates<R: rand::Rng + CryptoRng>(
+        num_updates: usize,
+        rng: &mut R,
+    ) -> (GroupState, GroupState, Vec<Handshake>) {
+        let (group_state1, identity_keys) = test_utils::random_full_group_state(2, rng);
+        let other_index = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[group_state1.roster_index.unwrap().0 as usize],
+            rng,
+        );
+        let group_state2 =
+            test_utils::change_self_index(&group_state1, &identity_keys, other_index);
+
+        let mut group_state1 = group_state1;
+        let mut handshakes = Vec::new();
+        for _ in 0..num_updates {
+            let new_path_secret = PathSecret::new_from_random(group_state1.cs, rng);
+            let (handshake, new_group_state, _) =
+                group_state1.create_and_apply_update_handshake(new_path_secret, rng).unwrap();
+            group_state1 = new_group_state;
+            handshakes.push(handshake);
+        }
+
+        (group_state2, group_state1, handshakes)
+    }
+
+    // Makes a pair of sessions for two different members of a group of at least 2
+    fn make_session_pair<R: rand::Rng + CryptoRng>(rng: &mut R) -> (Session, Session) {
+        let (group_state1, identity_keys) = test_utils::random_full_group_state(2, rng);
+        let other_index = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[group_state1.roster_index.unwrap().0 as usize],
+            rng,
+        );
+        let group_state2 =
+            test_utils::change_self_index(&group_state1, &identity_keys, other_index);
+        (Session::new(group_state1, None), Session::new(group_state2, None))
+    }
+
+    // Handshakes aren't Clone, so this is how the delivery service hands the same one to several
+    // members
+    fn copy_handshake(handshake: &Handshake, session: &Session) -> Handshake {
+        let bytes = tls_ser::serialize_to_bytes(handshake).unwrap();
+        session.group_state().deserialize_handshake(&bytes).unwrap()
+    }
+
+    // Checks that the echo of a handshake that was applied when it was made is ignored, and that
+    // nothing else from a past epoch is
+    #[quickcheck]
+    fn own_handshake_echo_ignored(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (mut session1, mut session2) = make_session_pair(&mut rng);
+
+        let path_secret = PathSecret::new_from_random(session1.group_state().cs, &mut rng);
+        let handshake = session1.create_and_apply_update_handshake(path_secret, &mut rng).unwrap();
+        let echo = copy_handshake(&handshake, &session2);
+        let replay = copy_handshake(&handshake, &session2);
+        session2.handle_handshake(handshake).unwrap();
+
+        let epoch = session1.group_state().epoch;
+        assert_eq!(session1.handle_handshake(echo).unwrap(), 0);
+        assert_eq!(session1.group_state().epoch, epoch);
+        assert_serialized_eq!(
+            *session1.group_state(),
+            *session2.group_state(),
+            "Echo changed state"
+        );
+
+        // The echo has been seen, so the same thing again is just a stale handshake
+        match session1.handle_handshake(replay) {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("a handshake's echo was accepted twice"),
+        }
+    }
+
+    // Checks that with echo confirmation, a handshake is applied when its echo arrives, and that a
+    // handshake ordered ahead of it wins
+    #[quickcheck]
+    fn echo_confirmation(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (mut session1, mut session2) = make_session_pair(&mut rng);
+        session1.set_echo_confirmation(true);
+        session2.set_echo_confirmation(true);
+
+        // Nothing happens until the echo arrives, and nothing else can be sent in the meantime
+        let epoch = session1.group_state().epoch;
+        let cs = session1.group_state().cs;
+        let path_secret = PathSecret::new_from_random(cs, &mut rng);
+        let handshake = session1.create_and_apply_update_handshake(path_secret, &mut rng).unwrap();
+        assert!(session1.has_pending_handshake());
+        assert_eq!(session1.group_state().epoch, epoch);
+        let path_secret = PathSecret::new_from_random(cs, &mut rng);
+        assert!(session1.create_and_apply_update_handshake(path_secret, &mut rng).is_err());
+
+        let echo = copy_handshake(&handshake, &session1);
+        assert_eq!(session1.handle_handshake(echo).unwrap(), 1);
+        assert!(!session1.has_pending_handshake());
+        session2.handle_handshake(handshake).unwrap();
+        assert_serialized_eq!(*session1.group_state(), *session2.group_state(), "Echo not applied");
+
+        // Now both members send an Update in the same epoch, and the delivery service orders
+        // member 2's first. Member 1's Update is dropped in favor of it.
+        let path_secret = PathSecret::new_from_random(cs, &mut rng);
+        let handshake1 = session1.create_and_apply_update_handshake(path_secret, &mut rng).unwrap();
+        let path_secret = PathSecret::new_from_random(cs, &mut rng);
+        let handshake2 = session2.create_and_apply_update_handshake(path_secret, &mut rng).unwrap();
+        let echo2 = copy_handshake(&handshake2, &session2);
+
+        assert_eq!(session1.handle_handshake(handshake2).unwrap(), 1);
+        assert!(!session1.has_pending_handshake());
+        assert_eq!(session2.handle_handshake(echo2).unwrap(), 1);
+        assert_serialized_eq!(*session1.group_state(), *session2.group_state(), "Race mishandled");
+
+        // Member 1's Update shows up afterwards, but it's too late for it
+        assert!(session2.handle_handshake(handshake1).is_err());
+    }
+
+    // Checks that a handshake for the current epoch that fails to apply doesn't cancel the
+    // handshake we're waiting on the echo of
+    #[quickcheck]
+    fn echo_confirmation_survives_forgery(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (mut session1, mut session2) = make_session_pair(&mut rng);
+        session1.set_echo_confirmation(true);
+
+        let epoch = session1.group_state().epoch;
+        let cs = session1.group_state().cs;
+        let path_secret = PathSecret::new_from_random(cs, &mut rng);
+        let handshake = session1.create_and_apply_update_handshake(path_secret, &mut rng).unwrap();
+        let echo = copy_handshake(&handshake, &session1);
+
+        // Someone passes off a copy of our Update as member 2's. The signature doesn't check out.
+        let mut forged = copy_handshake(&handshake, &session1);
+        forged.signer_index = session2.group_state().roster_index.unwrap();
+        assert!(session1.handle_handshake(forged).is_err());
+        assert!(session1.has_pending_handshake());
+        assert_eq!(session1.group_state().epoch, epoch);
+
+        // The real echo still gets our Update applied
+        assert_eq!(session1.handle_handshake(echo).unwrap(), 1);
+        assert!(!session1.has_pending_handshake());
+        session2.handle_handshake(handshake).unwrap();
+        assert_serialized_eq!(
+            *session1.group_state(),
+            *session2.group_state(),
+            "Echo not applied after a forgery"
+        );
+    }
+
+    // Delivers handshakes in a random order and checks that the session ends up in the right place
+    #[quickcheck]
+    fn out_of_order_handshakes(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state2, group_state1, mut handshakes) = make_updates(5, &mut rng);
+        handshakes.shuffle(&mut rng);
+
+        let mut session = Session::new(group_state2, None);
+        let mut total_applied = 0;
+        for handshake in handshakes {
+            total_applied += session.handle_handshake(handshake).unwrap();
+        }
+
+        assert_eq!(total_applied, 5);
+        assert_eq!(session.num_buffered_handshakes(), 0);
+        assert_serialized_eq!(*session.group_state(), group_state1, "Session didn't catch up");
+    }
+
+    // A delivery service that keeps every handshake it relayed. They're kept serialized, since
+    // Handshakes aren't Clone, along with a state of the group to deserialize them with.
+    struct LogDeliveryService {
+        group_state: GroupState,
+        log: Vec<Vec<u8>>,
+    }
+
+    impl DeliveryService for LogDeliveryService {
+        fn fetch_handshakes(
+            &self,
+            group_id: &[u8],
+            start_epoch: u32,
+            end_epoch: u32,
+        ) -> Result<Vec<Handshake>, Error> {
+            let mut handshakes = Vec::new();
+            for bytes in self.log.iter() {
+                let handshake = self.group_state.deserialize_handshake(bytes)?;
+                let epoch = handshake.prior_epoch;
+                if handshake.group_id == group_id && start_epoch <= epoch && epoch < end_epoch {
+                    handshakes.push(handshake);
+                }
+            }
+            Ok(handshakes)
+        }
+    }
+
+    // Checks that a session that only gets the last of several handshakes fetches the rest from
+    // the delivery service, and that a gap the delivery service can't fill, or that's too large to
+    // try, is an error that leaves the handshake buffered, unless the missing handshake is already
+    // buffered
+    #[quickcheck]
+    fn gap_recovery(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state2, group_state1, handshakes) = make_updates(5, &mut rng);
+        let start_epoch = group_state2.epoch;
+        let log: Vec<Vec<u8>> =
+            handshakes.iter().map(|h| tls_ser::serialize_to_bytes(h).unwrap()).collect();
+        let full_service = LogDeliveryService {
+            group_state: group_state2.clone(),
+            log: log.clone(),
+        };
+        // This one lost the third handshake
+        let mut partial_log = log;
+        partial_log.remove(2);
+        let partial_service = LogDeliveryService {
+            group_state: group_state2.clone(),
+            log: partial_log,
+        };
+
+        // Everything before the last handshake is fetched and applied first
+        let mut session = Session::new(group_state2.clone(), None);
+        let last = copy_handshake(&handshakes[4], &session);
+        assert_eq!(session.handle_handshake_with_recovery(last, &full_service).unwrap(), 5);
+        assert_serialized_eq!(*session.group_state(), group_state1, "Session didn't recover");
+
+        // A gap that can't be filled stops where the delivery service's handshakes run out
+        let mut session = Session::new(group_state2.clone(), None);
+        let last = copy_handshake(&handshakes[4], &session);
+        match session.handle_handshake_with_recovery(last, &partial_service) {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("unfilled epoch gap wasn't an error"),
+        }
+        assert_eq!(session.group_state().epoch, start_epoch + 2);
+
+        // The last one waits in the buffer until the missing ones show up
+        assert_eq!(session.num_buffered_handshakes(), 1);
+        let third = copy_handshake(&handshakes[2], &session);
+        assert_eq!(session.handle_handshake(third).unwrap(), 1);
+        let fourth = copy_handshake(&handshakes[3], &session);
+        assert_eq!(session.handle_handshake(fourth).unwrap(), 2);
+        assert_serialized_eq!(*session.group_state(), group_state1, "Session didn't recover");
+
+        // A gap that's too large isn't even fetched
+        let mut session = Session::new(group_state2.clone(), None);
+        session.set_max_recovery_gap(3);
+        let last = copy_handshake(&handshakes[4], &session);
+        match session.handle_handshake_with_recovery(last, &full_service) {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("oversized epoch gap wasn't an error"),
+        }
+        assert_eq!(session.group_state().epoch, start_epoch);
+        assert_eq!(session.num_buffered_handshakes(), 1);
+
+        // Unless the session already has the missing one
+        let mut session = Session::new(group_state2, None);
+        let third = copy_handshake(&handshakes[2], &session);
+        assert_eq!(session.handle_handshake(third).unwrap(), 0);
+        let last = copy_handshake(&handshakes[4], &session);
+        assert_eq!(session.handle_handshake_with_recovery(last, &partial_service).unwrap(), 5);
+        assert_eq!(session.num_buffered_handshakes(), 0);
+        assert_serialized_eq!(*session.group_state(), group_state1, "Session didn't recover");
+    }
+
+    // Checks that application messages from a future epoch are buffered and decrypted once their
+    // epoch arrives, and that the buffer's overflow and error reporting settings are respected
+    #[quickcheck]
+    fn message_buffering(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (mut session1, mut session2) = make_session_pair(&mut rng);
+        let cs = session1.group_state().cs;
+
+        // Member 1 moves to the next epoch and talks in it before member 2 has caught up
+        let path_secret = PathSecret::new_from_random(cs, &mut rng);
+        let handshake = session1.create_and_apply_update_handshake(path_secret, &mut rng).unwrap();
+        let app_message = session1.encrypt_application_message(b"early".to_vec()).unwrap();
+        assert!(session2.handle_application_message(app_message).unwrap().is_none());
+        assert_eq!(session2.num_buffered_messages(), 1);
+        assert!(session2.take_retried_messages().is_empty());
+
+        // Catching up decrypts it
+        session2.handle_handshake(handshake).unwrap();
+        assert_eq!(session2.num_buffered_messages(), 0);
+        let retried = session2.take_retried_messages();
+        assert_eq!(retried.len(), 1);
+        assert_eq!(retried[0].as_ref().unwrap().plaintext, b"early");
+
+        // With room for one message, the second is rejected by default, and evicts the first
+        // otherwise
+        session2.set_message_buffer_size(1);
+        let path_secret = PathSecret::new_from_random(cs, &mut rng);
+        let handshake = session1.create_and_apply_update_handshake(path_secret, &mut rng).unwrap();
+        let app_message1 = session1.encrypt_application_message(b"first".to_vec()).unwrap();
+        let app_message2 = session1.encrypt_application_message(b"second".to_vec()).unwrap();
+        assert!(session2.handle_application_message(app_message1).unwrap().is_none());
+        match session2.handle_application_message(app_message2.clone()) {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("application message buffer accepted more than its capacity"),
+        }
+        session2.set_message_buffer_overflow(MessageBufferOverflow::EvictOldest);
+        assert!(session2.handle_application_message(app_message2).unwrap().is_none());
+        let retried = session2.take_retried_messages();
+        assert_eq!(retried.len(), 1);
+        assert!(retried[0].is_err());
+
+        // Without the first message, the second is out of order, which the key chain doesn't allow
+        // by default, so retrying it fails
+        session2.handle_handshake(handshake).unwrap();
+        let retried = session2.take_retried_messages();
+        assert_eq!(retried.len(), 1);
+        assert!(retried[0].is_err());
+
+        // Without error reporting, neither of those is mentioned
+        session2.set_message_error_reporting(false);
+        let path_secret = PathSecret::new_from_random(cs, &mut rng);
+        let handshake = session1.create_and_apply_update_handshake(path_secret, &mut rng).unwrap();
+        let app_message1 = session1.encrypt_application_message(b"third".to_vec()).unwrap();
+        let app_message2 = session1.encrypt_application_message(b"fourth".to_vec()).unwrap();
+        assert!(session2.handle_application_message(app_message1).unwrap().is_none());
+        assert!(session2.handle_application_message(app_message2).unwrap().is_none());
+        session2.handle_handshake(handshake).unwrap();
+        assert!(session2.take_retried_messages().is_empty());
+
+        // With no room at all, future messages are rejected outright
+        session2.set_message_buffer_size(0);
+        let path_secret = PathSecret::new_from_random(cs, &mut rng);
+        session1.create_and_apply_update_handshake(path_secret, &mut rng).unwrap();
+        let app_message = session1.encrypt_application_message(b"fifth".to_vec()).unwrap();
+        assert!(session2.handle_application_message(app_message).is_err());
+        assert_eq!(session2.num_buffered_messages(), 0);
+
+        // With room to spare, a message is still rejected if it's from further ahead than the
+        // handshake buffer reaches
+        session2.set_message_buffer_size(DEFAULT_MESSAGE_BUFFER_SIZE);
+        session2.handshake_buffer = HandshakeBuffer::new(1);
+        let path_secret = PathSecret::new_from_random(cs, &mut rng);
+        session1.create_and_apply_update_handshake(path_secret, &mut rng).unwrap();
+        let app_message = session1.encrypt_application_message(b"sixth".to_vec()).unwrap();
+        match session2.handle_application_message(app_message) {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("application message buffer accepted a message past its window"),
+        }
+        assert_eq!(session2.num_buffered_messages(), 0);
+    }
+
+    // Checks that handshakes past the buffer's window are rejected, that junk buffered for an epoch
+    // doesn't crowd out the real handshake, that junk that fails to apply is dropped without an
+    // error, and that the window moves with the epoch
+    #[quickcheck]
+    fn handshake_buffer_limits(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state2, group_state1, mut handshakes) = make_updates(4, &mut rng);
+
+        // Room for the next two epochs' handshakes
+        let mut session = Session::with_handshake_buffer_size(group_state2, None, 2);
+        let last = handshakes.pop().unwrap();
+        let third = handshakes.pop().unwrap();
+        let second = handshakes.pop().unwrap();
+        let first = handshakes.pop().unwrap();
+
+        // Junk claiming to be from the third epoch gets buffered, and so does the real third
+        // handshake
+        let make_junk = |from: &Handshake, prior_epoch: u32, session: &Session| {
+            let mut junk = copy_handshake(from, session);
+            junk.prior_epoch = prior_epoch;
+            junk
+        };
+        let third_epoch = third.prior_epoch;
+        let junk = make_junk(&second, third_epoch, &session);
+        assert_eq!(session.handle_handshake(junk).unwrap(), 0);
+        assert_eq!(session.handle_handshake(third).unwrap(), 0);
+        assert_eq!(session.num_buffered_handshakes(), 2);
+
+        // Only so much junk fits in one epoch
+        for _ in 2..MAX_BUFFERED_HANDSHAKES_PER_EPOCH {
+            let junk = make_junk(&second, third_epoch, &session);
+            assert_eq!(session.handle_handshake(junk).unwrap(), 0);
+        }
+        let junk = make_junk(&second, third_epoch, &session);
+        match session.handle_handshake(junk) {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("handshake buffer accepted too many handshakes for one epoch"),
+        }
+        assert_eq!(session.num_buffered_handshakes(), MAX_BUFFERED_HANDSHAKES_PER_EPOCH);
+
+        // The fourth one is too far ahead
+        let last_copy = copy_handshake(&last, &session);
+        match session.handle_handshake(last_copy) {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("handshake buffer accepted a handshake past its window"),
+        }
+        assert_eq!(session.num_buffered_handshakes(), MAX_BUFFERED_HANDSHAKES_PER_EPOCH);
+
+        // Junk that's the only thing buffered for the second epoch fails to apply once the first
+        // one is applied. It's dropped, and applying the first one still succeeds.
+        let junk = make_junk(&last, second.prior_epoch, &session);
+        assert_eq!(session.handle_handshake(junk).unwrap(), 0);
+        assert_eq!(session.handle_handshake(first).unwrap(), 1);
+        assert_eq!(session.group_state().epoch, second.prior_epoch);
+        assert_eq!(session.num_buffered_handshakes(), MAX_BUFFERED_HANDSHAKES_PER_EPOCH);
+
+        // That made room for the fourth
+        assert_eq!(session.handle_handshake(last).unwrap(), 0);
+        assert_eq!(session.num_buffered_handshakes(), MAX_BUFFERED_HANDSHAKES_PER_EPOCH + 1);
+
+        // Applying the second one unblocks everything, skipping over the junk
+        assert_eq!(session.handle_handshake(second).unwrap(), 3);
+        assert_eq!(session.num_buffered_handshakes(), 0);
+        assert_serialized_eq!(*session.group_state(), group_state1, "Session didn't catch up");
+    }
+
+    // Checks that late messages from past epochs are decryptable exactly when their epoch is
+    // retained, and that a past epoch can't be sent in
+    #[quickcheck]
+    fn epoch_retention(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state1, identity_keys) = test_utils::random_full_group_state(2, &mut rng);
+        let other_index = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[group_state1.roster_index.unwrap().0 as usize],
+            &mut rng,
+        );
+        let group_state2 =
+            test_utils::change_self_index(&group_state1, &identity_keys, other_index);
+        let mut session1 = Session::new(group_state1, None);
+        let mut session2 = Session::new(group_state2, None);
+
+        // Keep one past epoch around
+        session2.set_epoch_retention(2);
+
+        // Do an Update, send some messages in the new epoch, and then do another Update before the
+        // messages arrive
+        let mut do_update = |session1: &mut Session, session2: &mut Session| {
+            let new_path_secret = PathSecret::new_from_random(session1.group_state().cs, &mut rng);
+            let handshake =
+                session1.create_and_apply_update_handshake(new_path_secret, &mut rng).unwrap();
+            session2.handle_handshake(handshake).unwrap();
+        };
+        do_update(&mut session1, &mut session2);
+        let late_message1 = session1.encrypt_application_message(b"first".to_vec()).unwrap();
+        let late_message2 = session1.encrypt_application_message(b"second".to_vec()).unwrap();
+        do_update(&mut session1, &mut session2);
+        assert_eq!(session2.num_retained_past_epochs(), 1);
+
+        // The previous epoch is retained, so the first message is decryptable
+        let decrypted = session2.decrypt_application_message(late_message1).unwrap();
+        assert_eq!(decrypted.plaintext, b"first");
+
+        // Lowering the retention drops the previous epoch, so the second message isn't
+        session2.set_epoch_retention(1);
+        assert_eq!(session2.num_retained_past_epochs(), 0);
+        match session2.decrypt_application_message(late_message2) {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("decrypted a message from an epoch that isn't retained"),
+        }
+
+        // Past epochs' key chains can only receive
+        assert_eq!(session1.num_retained_past_epochs(), 0);
+        session2.set_epoch_retention(2);
+        do_update(&mut session1, &mut session2);
+        let past_epoch = session2.past_epochs.back_mut().unwrap();
+        assert!(application::encrypt_application_message(
+            b"too late".to_vec(),
+            &past_epoch.group_state,
+            &mut past_epoch.app_key_chain
+        )
+        .is_err());
+    }
+
+    // Checks that a member can't send past their send limit, and that an Update lifts it
+    #[quickcheck]
+    fn send_limit(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (mut group_state1, identity_keys) = test_utils::random_full_group_state(2, &mut rng);
+        let other_index = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[group_state1.roster_index.unwrap().0 as usize],
+            &mut rng,
+        );
+        let group_state2 =
+            test_utils::change_self_index(&group_state1, &identity_keys, other_index);
+        group_state1.config.max_messages_per_epoch = Some(2);
+        let mut session1 = Session::new(group_state1, None);
+        let mut session2 = Session::new(group_state2, None);
+
+        let mut do_update = |session1: &mut Session, session2: &mut Session| {
+            let new_path_secret = PathSecret::new_from_random(session1.group_state().cs, &mut rng);
+            let handshake =
+                session1.create_and_apply_update_handshake(new_path_secret, &mut rng).unwrap();
+            session2.handle_handshake(handshake).unwrap();
+        };
+        do_update(&mut session1, &mut session2);
+
+        // Two messages are fine. The third is refused, and the session says an update is due.
+        for _ in 0..2 {
+            assert!(!session1.is_send_limit_reached());
+            let app_message = session1.encrypt_application_message(b"hi".to_vec()).unwrap();
+            session2.decrypt_application_message(app_message).unwrap();
+        }
+        assert!(session1.is_send_limit_reached());
+        assert!(session1.is_update_due());
+        match session1.encrypt_application_message(b"one too many".to_vec()) {
+            Err(Error::UpdateRequired) => (),
+            _ => panic!("sent a message past the send limit"),
+        }
+
+        // The limit is only on this member. The other member can keep sending.
+        for _ in 0..3 {
+            let app_message = session2.encrypt_application_message(b"hey".to_vec()).unwrap();
+            session1.decrypt_application_message(app_message).unwrap();
+        }
+
+        // After an Update, the count starts over
+        do_update(&mut session1, &mut session2);
+        assert!(!session1.is_send_limit_reached());
+        assert!(!session1.is_update_due());
+        let app_message = session1.encrypt_application_message(b"hi again".to_vec()).unwrap();
+        session2.decrypt_application_message(app_message).unwrap();
+    }
+
+    // Checks that members who don't refresh their leaf get flagged, and removed if the policy says
+    // so
+    #[quickcheck]
+    fn stale_member_policy(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (mut group_state1, identity_keys) = test_utils::random_full_group_state(2, &mut rng);
+        let index1 = group_state1.roster_index.unwrap();
+        let index2 = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[index1.0 as usize],
+            &mut rng,
+        );
+        let group_state2 = test_utils::change_self_index(&group_state1, &identity_keys, index2);
+        group_state1.config.stale_member_policy = StaleMemberPolicy::Flag(2);
+        let start_epoch = group_state1.epoch;
+        let mut session1 = Session::new(group_state1, None);
+        let mut session2 = Session::new(group_state2, None);
+
+        // Member 1 updates. Nobody is stale yet.
+        let new_path_secret = PathSecret::new_from_random(session1.group_state().cs, &mut rng);
+        let handshake =
+            session1.create_and_apply_update_handshake(new_path_secret, &mut rng).unwrap();
+        session2.handle_handshake(handshake).unwrap();
+        assert!(session1.stale_members().is_empty());
+        assert_eq!(session1.last_refresh_epoch(index1), Some(start_epoch + 1));
+        assert_eq!(session1.last_refresh_epoch(index2), Some(start_epoch));
+
+        // Member 2 updates. Now everyone but the two of them has gone 2 epochs without a refresh.
+        let new_path_secret = PathSecret::new_from_random(session2.group_state().cs, &mut rng);
+        let handshake =
+            session2.create_and_apply_update_handshake(new_path_secret, &mut rng).unwrap();
+        session1.handle_handshake(handshake).unwrap();
+        assert_eq!(session1.last_refresh_epoch(index2), Some(start_epoch + 2));
+        let expected_stale: Vec<RosterIndex> = session1
+            .group_state()
+            .roster
+            .0
+            .iter()
+            .enumerate()
+            .filter(|(i, entry)| {
+                entry.is_some() && *i != index1.0 as usize && *i != index2.0 as usize
+            })
+            .map(|(i, _)| RosterIndex(i as u32))
+            .collect();
+        assert_eq!(session1.stale_members(), expected_stale);
+
+        // Flagging doesn't allow removing
+        match session1.remove_stale_members(&mut rng) {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("removed stale members under a policy that only flags them"),
+        }
+
+        // With the policy switched to removal, member 1 removes them. Member 2 follows along.
+        session1.group_state.config.stale_member_policy = StaleMemberPolicy::Remove(2);
+        let handshakes = session1.remove_stale_members(&mut rng).unwrap();
+        assert_eq!(handshakes.len(), expected_stale.len());
+        for handshake in handshakes {
+            session2.handle_handshake(handshake).unwrap();
+        }
+        // The removals took epochs of their own, so member 2 might be stale by now. Nobody else is.
+        assert!(session1.stale_members().iter().all(|&i| i == index2));
+        for &i in expected_stale.iter() {
+            assert_eq!(session1.last_refresh_epoch(i), None);
+        }
+        assert_eq!(session1.group_state().epoch, session2.group_state().epoch);
+    }
+
+    // Has one member remove another, and checks that the remaining member can still decrypt
+    // everyone else's late messages from before the Remove, but not the removed member's
+    #[quickcheck]
+    fn removed_member_keys_forgotten(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state, identity_keys) = test_utils::random_full_group_state(3, &mut rng);
+        let remover_index = group_state.roster_index.unwrap().0 as usize;
+        let other_index = test_utils::random_roster_index_with_exceptions(
+            group_state.roster.len(),
+            &[remover_index],
+            &mut rng,
+        )
+        .0 as usize;
+        let removed_index = test_utils::random_roster_index_with_exceptions(
+            group_state.roster.len(),
+            &[remover_index, other_index],
+            &mut rng,
+        )
+        .0 as usize;
+        let mut sessions: Vec<Session> = [remover_index, other_index, removed_index]
+            .iter()
+            .map(|&idx| {
+                let gs = test_utils::change_self_index(
+                    &group_state,
+                    &identity_keys,
+                    RosterIndex(idx as u32),
+                );
+                let mut session = Session::new(gs, None);
+                session.set_epoch_retention(2);
+                session
+            })
+            .collect();
+
+        // Everyone gets a key chain from an Update, and then the remover and the soon-to-be
+        // removed member each send something that arrives late
+        let path_secret = PathSecret::new_from_random(group_state.cs, &mut rng);
+        let handshake =
+            sessions[0].create_and_apply_update_handshake(path_secret, &mut rng).unwrap();
+        // Handshakes aren't Clone, so the second recipient gets its own copy off the wire
+        let handshake_copy: Handshake = {
+            let signer = group_state.roster.0[remover_index].as_ref().unwrap();
+            let ctx = CryptoCtx::new()
+                .set_cipher_suite(group_state.cs)
+                .set_signature_scheme(signer.get_signature_scheme());
+            let bytes = tls_ser::serialize_to_bytes(&handshake).unwrap();
+            upcast::deserialize_and_upcast(&bytes, &ctx).unwrap()
+        };
+        sessions[1].handle_handshake(handshake).unwrap();
+        sessions[2].handle_handshake(handshake_copy).unwrap();
+        let late_from_remover = sessions[0].encrypt_application_message(b"stay".to_vec()).unwrap();
+        let late_from_removed = sessions[2].encrypt_application_message(b"bye".to_vec()).unwrap();
+
+        let path_secret = PathSecret::new_from_random(group_state.cs, &mut rng);
+        let handshake = sessions[0]
+            .create_and_apply_remove_handshake(
+                RosterIndex(removed_index as u32),
+                path_secret,
+                &mut rng,
+            )
+            .unwrap();
+        sessions[1].handle_handshake(handshake).unwrap();
+
+        let decrypted = sessions[1].decrypt_application_message(late_from_remover).unwrap();
+        assert_eq!(decrypted.plaintext, b"stay");
+        match sessions[1].decrypt_application_message(late_from_removed) {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("decrypted a late message from a removed member"),
+        }
+    }
+}
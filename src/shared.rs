@@ -0,0 +1,415 @@
+//! Defines `SharedGroup`, a thread-safe handle to a group. This lets a multithreaded client
+//! encrypt and decrypt application messages from several threads while handshakes are applied from
+//! another.
+
+use crate::{
+    application::{self, ApplicationKeyChain, ApplicationMessage, DecryptedMessage},
+    credential::{Identity, RosterIndex},
+    crypto::rng::CryptoRng,
+    directory::{self, UserInitKeyDirectory},
+    error::Error,
+    group_state::{GroupState, Welcome},
+    handshake::{Handshake, UserInitKey},
+    ratchet_tree::PathSecret,
+};
+
+use std::sync::{Arc, Mutex, RwLock};
+
+// GroupState and ApplicationKeyChain have to be Send + Sync for SharedGroup to be of any use. If
+// this stops compiling, something non-thread-safe made its way into one of them.
+const _: fn() = || {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<GroupState>();
+    assert_send_sync::<ApplicationKeyChain>();
+};
+
+/// A group state along with the application key chain for that epoch
+struct Epoch {
+    // This is behind an Arc so that handshakes can be processed without holding any locks
+    group_state: Arc<GroupState>,
+    // Encryption and decryption ratchet the key chain forward, so this needs its own lock. This is
+    // None iff no handshake has happened yet in this group.
+    app_key_chain: Mutex<Option<ApplicationKeyChain>>,
+}
+
+/// A handle to a group that can be shared between threads. Application messages can be encrypted
+/// and decrypted concurrently with each other and with the processing of handshakes. Every
+/// handshake moves the group to a new epoch atomically, so a message is always encrypted with the
+/// key chain belonging to the `GroupState` it's checked against.
+///
+/// Handshakes are processed on a snapshot of the current state, without blocking message
+/// encryption. If two handshakes are applied concurrently, only the first succeeds. The second
+/// gets an `Error::ValidationError` and can be retried.
+pub struct SharedGroup {
+    epoch: RwLock<Epoch>,
+}
+
+impl SharedGroup {
+    /// Wraps the given group state and application key chain. The key chain must be the one that
+    /// came out of the handshake that produced `group_state`, or `None` if `group_state` is a
+    /// brand new singleton group.
+    pub fn new(group_state: GroupState, app_key_chain: Option<ApplicationKeyChain>) -> SharedGroup {
+        SharedGroup {
+            epoch: RwLock::new(Epoch {
+                group_state: Arc::new(group_state),
+                app_key_chain: Mutex::new(app_key_chain),
+            }),
+        }
+    }
+
+    /// Returns the current `GroupState`. This is a snapshot, so it won't reflect any handshakes
+    /// applied after this call.
+    pub fn group_state(&self) -> Arc<GroupState> {
+        Arc::clone(&self.epoch.read().expect("SharedGroup lock poisoned").group_state)
+    }
+
+    /// Encrypts the given plaintext under the current epoch. This can be called from several
+    /// threads at once.
+    ///
+    /// Returns: `Ok(app_message)` on success. Returns an `Error::ValidationError` if no handshake
+    /// has happened in this group yet. Otherwise returns whatever
+    /// `application::encrypt_application_message` returns.
+    pub fn encrypt_application_message(
+        &self,
+        plaintext: Vec<u8>,
+    ) -> Result<ApplicationMessage, Error> {
+        self.encrypt_application_message_with_aad(plaintext, Vec::new())
+    }
+
+    /// Encrypts the given plaintext under the current epoch, attaching the given authenticated
+    /// data. This can be called from several threads at once.
+    ///
+    /// Returns: `Ok(app_message)` on success. Returns an `Error::ValidationError` if no handshake
+    /// has happened in this group yet. Otherwise returns whatever
+    /// `application::encrypt_application_message_with_aad` returns.
+    pub fn encrypt_application_message_with_aad(
+        &self,
+        plaintext: Vec<u8>,
+        authenticated_data: Vec<u8>,
+    ) -> Result<ApplicationMessage, Error> {
+        let epoch = self.epoch.read().expect("SharedGroup lock poisoned");
+        let mut app_key_chain = epoch.app_key_chain.lock().expect("SharedGroup lock poisoned");
+        let app_key_chain = app_key_chain.as_mut().ok_or(Error::ValidationError(
+            "Group has no application key chain before its first handshake",
+        ))?;
+
+        application::encrypt_application_message_with_aad(
+            plaintext,
+            authenticated_data,
+            &epoch.group_state,
+            app_key_chain,
+        )
+    }
+
+    /// Decrypts the given application message under the current epoch. This can be called from
+    /// several threads at once.
+    ///
+    /// Returns: `Ok(decrypted_message)` on success. Returns an `Error::ValidationError` if no
+    /// handshake has happened in this group yet. Otherwise returns whatever
+    /// `application::decrypt_application_message` returns.
+    pub fn decrypt_application_message(
+        &self,
+        app_message: ApplicationMessage,
+    ) -> Result<DecryptedMessage, Error> {
+        let epoch = self.epoch.read().expect("SharedGroup lock poisoned");
+        let mut app_key_chain = epoch.app_key_chain.lock().expect("SharedGroup lock poisoned");
+        let app_key_chain = app_key_chain.as_mut().ok_or(Error::ValidationError(
+            "Group has no application key chain before its first handshake",
+        ))?;
+
+        application::decrypt_application_message(app_message, &epoch.group_state, app_key_chain)
+    }
+
+    /// Runs `f` on a snapshot of the current group state and, if the group hasn't moved on in the
+    /// meantime, installs the resulting group state and key chain as the new epoch
+    ///
+    /// Returns: `Ok(t)` on success, where `t` is the rest of the output of `f`. Returns an
+    /// `Error::ValidationError` if another operation was applied while `f` was running. Otherwise
+    /// returns whatever `f` returns.
+    fn advance<F, T>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&GroupState) -> Result<(GroupState, ApplicationKeyChain, T), Error>,
+    {
+        // Do the expensive part without holding any locks
+        let old_group_state = self.group_state();
+        let (new_group_state, new_app_key_chain, t) = f(&old_group_state)?;
+
+        // Now swap in the new epoch, but only if nobody beat us to it
+        let mut epoch = self.epoch.write().expect("SharedGroup lock poisoned");
+        if !Arc::ptr_eq(&epoch.group_state, &old_group_state) {
+            return Err(Error::ValidationError(
+                "Group state changed while this operation was being applied",
+            ));
+        }
+        *epoch = Epoch {
+            group_state: Arc::new(new_group_state),
+            app_key_chain: Mutex::new(Some(new_app_key_chain)),
+        };
+
+        Ok(t)
+    }
+
+    /// Processes the given `Handshake` and moves the group to the next epoch. See
+    /// `GroupState::process_handshake` for details.
+    ///
+    /// Returns: `Ok(())` on success. Returns an `Error::ValidationError` if another operation was
+    /// applied concurrently. Otherwise returns whatever `GroupState::process_handshake` returns.
+    pub fn process_handshake(&self, handshake: &Handshake) -> Result<(), Error> {
+        self.advance(|group_state| {
+            let (new_group_state, app_key_chain) = group_state.process_handshake(handshake)?;
+            Ok((new_group_state, app_key_chain, ()))
+        })
+    }
+
+    /// Creates and applies an Update. See `GroupState::create_and_apply_update_handshake` for
+    /// details.
+    ///
+    /// Returns: `Ok(handshake)` on success. Returns an `Error::ValidationError` if another
+    /// operation was applied concurrently. Otherwise returns whatever
+    /// `GroupState::create_and_apply_update_handshake` returns.
+    pub fn create_and_apply_update_handshake<R>(
+        &self,
+        new_path_secret: PathSecret,
+        csprng: &mut R,
+    ) -> Result<Handshake, Error>
+    where
+        R: CryptoRng,
+    {
+        self.advance(|group_state| {
+            let (handshake, new_group_state, app_key_chain) =
+                group_state.create_and_apply_update_handshake(new_path_secret, csprng)?;
+            Ok((new_group_state, app_key_chain, handshake))
+        })
+    }
+
+    /// Creates a `Welcome` for the owner of `init_key`, then creates and applies an Add for them.
+    /// Unlike `GroupState::create_and_apply_add_handshake`, this makes the `Welcome` itself, since
+    /// the `Welcome` has to describe exactly the group state that the Add is applied to.
+    ///
+    /// Returns: `Ok((welcome, handshake))` on success. Returns an `Error::ValidationError` if
+    /// another operation was applied concurrently. Otherwise returns whatever
+    /// `Welcome::from_group_state` or `GroupState::create_and_apply_add_handshake` returns.
+    pub fn create_and_apply_add_handshake<R>(
+        &self,
+        new_roster_index: RosterIndex,
+        init_key: UserInitKey,
+        csprng: &mut R,
+    ) -> Result<(Welcome, Handshake), Error>
+    where
+        R: CryptoRng,
+    {
+        self.advance(|group_state| {
+            let (welcome, welcome_info_hash) =
+                Welcome::from_group_state(group_state, &init_key, csprng)?;
+            let (handshake, new_group_state, app_key_chain) = group_state
+                .create_and_apply_add_handshake(new_roster_index, init_key, &welcome_info_hash)?;
+            Ok((new_group_state, app_key_chain, (welcome, handshake)))
+        })
+    }
+
+    /// Adds several new members at once. See `GroupState::create_and_apply_add_handshakes`.
+    ///
+    /// Returns: `Ok((welcomes, handshakes))` on success, where `welcomes` pairs each `Welcome` with
+    /// the ID of the `UserInitKey` it's for. Returns an `Error::ValidationError` if another
+    /// operation was applied concurrently. Otherwise returns whatever
+    /// `GroupState::create_and_apply_add_handshakes` returns.
+    #[allow(clippy::type_complexity)]
+    pub fn create_and_apply_add_handshakes<R>(
+        &self,
+        init_keys: Vec<UserInitKey>,
+        csprng: &mut R,
+    ) -> Result<(Vec<(Vec<u8>, Welcome)>, Vec<Handshake>), Error>
+    where
+        R: CryptoRng,
+    {
+        self.advance(|group_state| {
+            let (welcomes, handshakes, new_group_state, app_key_chain) =
+                group_state.create_and_apply_add_handshakes(init_keys, csprng)?;
+            Ok((new_group_state, app_key_chain, (welcomes, handshakes)))
+        })
+    }
+
+    /// Looks up the `UserInitKey` of the user with the given identity in `directory`, then adds
+    /// them like `SharedGroup::create_and_apply_add_handshake` does. They go in the first empty
+    /// roster entry, or at the end if there is none. No locks are held during the lookup.
+    ///
+    /// Returns: `Ok((welcome, handshake))` on success. Returns an `Error::ValidationError` if the
+    /// user is already a member, the directory returns someone else's key, or another operation
+    /// was applied concurrently. Returns an `Error::NoCompatibleInitKey` if their key doesn't
+    /// support this group's ciphersuite. Otherwise returns whatever the directory,
+    /// `Welcome::from_group_state`, or `GroupState::create_and_apply_add_handshake` returns.
+    pub fn add_member_by_identity<D, R>(
+        &self,
+        directory: &D,
+        identity: &Identity,
+        csprng: &mut R,
+    ) -> Result<(Welcome, Handshake), Error>
+    where
+        D: UserInitKeyDirectory + ?Sized,
+        R: CryptoRng,
+    {
+        self.advance(|group_state| {
+            let (new_roster_index, init_key) =
+                directory::fetch_init_key_for_add(directory, identity, group_state)?;
+            let (welcome, welcome_info_hash) =
+                Welcome::from_group_state(group_state, &init_key, csprng)?;
+            let (handshake, new_group_state, app_key_chain) = group_state
+                .create_and_apply_add_handshake(new_roster_index, init_key, &welcome_info_hash)?;
+            Ok((new_group_state, app_key_chain, (welcome, handshake)))
+        })
+    }
+
+    /// Creates and applies a Remove. See `GroupState::create_and_apply_remove_handshake` for
+    /// details.
+    ///
+    /// Returns: `Ok(handshake)` on success. Returns an `Error::ValidationError` if another
+    /// operation was applied concurrently. Otherwise returns whatever
+    /// `GroupState::create_and_apply_remove_handshake` returns.
+    pub fn create_and_apply_remove_handshake<R>(
+        &self,
+        removed_roster_index: RosterIndex,
+        new_path_secret: PathSecret,
+        csprng: &mut R,
+    ) -> Result<Handshake, Error>
+    where
+        R: CryptoRng,
+    {
+        self.advance(|group_state| {
+            let (handshake, new_group_state, app_key_chain) = group_state
+                .create_and_apply_remove_handshake(removed_roster_index, new_path_secret, csprng)?;
+            Ok((new_group_state, app_key_chain, handshake))
+        })
+    }
+
+    /// Creates and applies a BatchRemove. See
+    /// `GroupState::create_and_apply_batch_remove_handshake` for details.
+    ///
+    /// Returns: `Ok(handshake)` on success. Returns an `Error::ValidationError` if another
+    /// operation was applied concurrently. Otherwise returns whatever
+    /// `GroupState::create_and_apply_batch_remove_handshake` returns.
+    pub fn create_and_apply_batch_remove_handshake<R>(
+        &self,
+        removed_roster_indices: &[RosterIndex],
+        new_path_secret: PathSecret,
+        csprng: &mut R,
+    ) -> Result<Handshake, Error>
+    where
+        R: CryptoRng,
+    {
+        self.advance(|group_state| {
+            let (handshake, new_group_state, app_key_chain) = group_state
+                .create_and_apply_batch_remove_handshake(
+                    removed_roster_indices,
+                    new_path_secret,
+                    csprng,
+                )?;
+            Ok((new_group_state, app_key_chain, handshake))
+        })
+    }
+
+    /// Creates a `Welcome` for the owner of `init_key`, then creates and applies a Replace that
+    /// puts them where the member at `replaced_roster_index` is now. Like
+    /// `SharedGroup::create_and_apply_add_handshake`, this makes the `Welcome` itself. See
+    /// `GroupState::create_and_apply_replace_handshake` for details.
+    ///
+    /// Returns: `Ok((welcome, handshake))` on success. Returns an `Error::ValidationError` if
+    /// another operation was applied concurrently. Otherwise returns whatever
+    /// `Welcome::from_group_state` or `GroupState::create_and_apply_replace_handshake` returns.
+    pub fn create_and_apply_replace_handshake<R>(
+        &self,
+        replaced_roster_index: RosterIndex,
+        init_key: UserInitKey,
+        new_path_secret: PathSecret,
+        csprng: &mut R,
+    ) -> Result<(Welcome, Handshake), Error>
+    where
+        R: CryptoRng,
+    {
+        self.advance(|group_state| {
+            let (welcome, welcome_info_hash) =
+                Welcome::from_group_state(group_state, &init_key, csprng)?;
+            let (handshake, new_group_state, app_key_chain) = group_state
+                .create_and_apply_replace_handshake(
+                    replaced_roster_index,
+                    init_key,
+                    &welcome_info_hash,
+                    new_path_secret,
+                    csprng,
+                )?;
+            Ok((new_group_state, app_key_chain, (welcome, handshake)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        application::encrypt_application_message, ratchet_tree::PathSecret, shared::SharedGroup,
+        test_utils,
+    };
+
+    use quickcheck_macros::quickcheck;
+    use rand::SeedableRng;
+
+    // Encrypts from several threads while another thread applies a stream of Updates, then checks
+    // that the shared group ends up where the Updater did
+    #[quickcheck]
+    fn concurrent_encrypt_and_process(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (group_state1, identity_keys) = test_utils::random_full_group_state(2, &mut rng);
+
+        // Member 2 will be wrapped in a SharedGroup. Member 1 sends it Updates.
+        let new_index = test_utils::random_roster_index_with_exceptions(
+            group_state1.roster.len(),
+            &[group_state1.roster_index.unwrap().0 as usize],
+            &mut rng,
+        );
+        let group_state2 = test_utils::change_self_index(&group_state1, &identity_keys, new_index);
+        let shared = SharedGroup::new(group_state2, None);
+
+        // Make a bunch of Updates ahead of time
+        let mut group_state1 = group_state1;
+        let mut app_key_chain1 = None;
+        let mut handshakes = Vec::new();
+        for _ in 0..5 {
+            let new_path_secret = PathSecret::new_from_random(group_state1.cs, &mut rng);
+            let (handshake, new_group_state, app_key_chain) =
+                group_state1.create_and_apply_update_handshake(new_path_secret, &mut rng).unwrap();
+            group_state1 = new_group_state;
+            app_key_chain1 = Some(app_key_chain);
+            handshakes.push(handshake);
+        }
+
+        // Make sure there's a key chain to encrypt with before the threads start
+        shared.process_handshake(&handshakes.remove(0)).unwrap();
+
+        crossbeam::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|_| {
+                    for _ in 0..10 {
+                        shared.encrypt_application_message(b"hello".to_vec()).unwrap();
+                    }
+                });
+            }
+            s.spawn(|_| {
+                for handshake in handshakes.iter() {
+                    shared.process_handshake(handshake).unwrap();
+                }
+            });
+        })
+        .unwrap();
+
+        // Both members should be in the same place now
+        assert_serialized_eq!(*shared.group_state(), group_state1, "SharedGroup didn't keep up");
+
+        // And the shared group should be able to read what member 1 sends in this epoch
+        let app_message = encrypt_application_message(
+            b"bye".to_vec(),
+            &group_state1,
+            app_key_chain1.as_mut().unwrap(),
+        )
+        .unwrap();
+        let decrypted = shared.decrypt_application_message(app_message).unwrap();
+        assert_eq!(decrypted.plaintext, b"bye");
+    }
+}
@@ -0,0 +1,487 @@
+//! A deterministic multi-party simulation of a group. This spins up a handful of in-memory
+//! members, connects them with a simulated delivery service, and drives a random sequence of
+//! Adds, Updates, Removes, and application messages from a seeded RNG. After every step, it checks
+//! that all the members agree on the state of the group.
+
+use crate::{
+    application::{self, ApplicationKeyChain, ApplicationMessage},
+    credential::{Credential, RosterIndex},
+    crypto::{ciphersuite::X25519_SHA256_AES128GCM, sig::SigSecretKey},
+    error::Error,
+    group_state::{GroupState, Welcome},
+    handshake::{Handshake, UserInitKey, MLS_DUMMY_VERSION},
+    ratchet_tree::PathSecret,
+    test_utils,
+};
+
+use core::convert::TryFrom;
+use std::collections::VecDeque;
+
+use rand::{Rng, RngCore, SeedableRng};
+
+// Keep groups small so that a single run doesn't take forever
+const MAX_GROUP_SIZE: usize = 8;
+
+/// A single participant in the simulation
+struct Member {
+    group_state: GroupState,
+    // This is only None for the creator of the group, before anything has happened in the group
+    app_key_chain: Option<ApplicationKeyChain>,
+}
+
+/// Something that can be sent over the delivery service
+// Most messages are handshakes anyway, so boxing them wouldn't save anything
+#[allow(clippy::large_enum_variant)]
+enum Message {
+    Handshake(Handshake),
+    Application(ApplicationMessage),
+}
+
+/// A simulated delivery service. Everything sent is broadcast to every current member except the
+/// sender, in the order in which it was sent.
+#[derive(Default)]
+struct DeliveryService {
+    // Pairs of (sender roster index, message)
+    queue: VecDeque<(u32, Message)>,
+}
+
+// This is a free function so that we can borrow a member and the RNG at the same time
+fn get_member(members: &[Option<Member>], roster_index: u32) -> &Member {
+    members[roster_index as usize].as_ref().expect("no member at this roster index")
+}
+
+/// The kinds of steps a simulation can take
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Op {
+    Add,
+    Update,
+    Remove,
+    Message,
+}
+
+/// A group of in-memory members, all driven by a single seeded RNG
+pub(crate) struct Simulation {
+    rng: rand::rngs::StdRng,
+    // Indexed by roster index. A None means the roster slot is empty.
+    members: Vec<Option<Member>>,
+    delivery_service: DeliveryService,
+    // Every step taken so far. This is printed when members disagree, to help with reproducing.
+    history: Vec<Op>,
+}
+
+impl Simulation {
+    /// Makes a new simulation consisting of a single member, whose randomness is entirely
+    /// determined by `rng_seed`
+    pub(crate) fn new(rng_seed: u64) -> Simulation {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+
+        let (credential, identity_key) = test_utils::random_basic_credential(&mut rng);
+        let group_id = {
+            let mut buf = [0u8; 16];
+            rng.fill_bytes(&mut buf);
+            buf.to_vec()
+        };
+        let group_state = GroupState::new_singleton_group(
+            &X25519_SHA256_AES128GCM,
+            MLS_DUMMY_VERSION,
+            identity_key,
+            group_id,
+            credential,
+            &mut rng,
+        )
+        .unwrap();
+
+        let creator = Member {
+            group_state,
+            app_key_chain: None,
+        };
+
+        Simulation {
+            rng,
+            members: vec![Some(creator)],
+            delivery_service: DeliveryService::default(),
+            history: Vec::new(),
+        }
+    }
+
+    /// Returns the roster indices of all the current members
+    fn member_indices(&self) -> Vec<u32> {
+        self.members
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| m.is_some())
+            .map(|(i, _)| u32::try_from(i).unwrap())
+            .collect()
+    }
+
+    /// Picks a random current member
+    fn random_member_index(&mut self) -> u32 {
+        let indices = self.member_indices();
+        indices[self.rng.gen_range(0, indices.len())]
+    }
+
+    fn member(&self, roster_index: u32) -> &Member {
+        get_member(&self.members, roster_index)
+    }
+
+    fn member_mut(&mut self, roster_index: u32) -> &mut Member {
+        self.members[roster_index as usize].as_mut().expect("no member at this roster index")
+    }
+
+    /// Picks a random operation that makes sense for the current group
+    fn random_op(&mut self) -> Op {
+        let num_members = self.member_indices().len();
+        // Nobody has an application key chain until the first handshake. Also, there's nobody to
+        // send anything to in a group of one.
+        if num_members == 1 {
+            return Op::Add;
+        }
+
+        let mut choices = vec![Op::Update, Op::Remove, Op::Message];
+        if num_members < MAX_GROUP_SIZE {
+            choices.push(Op::Add);
+        }
+        choices[self.rng.gen_range(0, choices.len())]
+    }
+
+    /// Takes a single random step and checks that everyone still agrees afterwards
+    pub(crate) fn step(&mut self) {
+        let op = self.random_op();
+        self.history.push(op);
+
+        match op {
+            Op::Add => self.do_add(),
+            Op::Update => self.do_update(),
+            Op::Remove => self.do_remove(),
+            Op::Message => self.do_message(),
+        }
+
+        self.assert_converged();
+    }
+
+    /// Takes `num_steps` many random steps, checking for agreement after each one
+    pub(crate) fn run(&mut self, num_steps: usize) {
+        for _ in 0..num_steps {
+            self.step();
+        }
+    }
+
+    /// A random member adds a brand new member, either into an empty roster slot or at the end of
+    /// the roster
+    fn do_add(&mut self) {
+        let adder_idx = self.random_member_index();
+
+        // The new member can go in any empty slot, or they can be appended
+        let new_roster_index = {
+            let mut slots: Vec<u32> = self
+                .members
+                .iter()
+                .enumerate()
+                .filter(|(_, m)| m.is_none())
+                .map(|(i, _)| u32::try_from(i).unwrap())
+                .collect();
+            slots.push(u32::try_from(self.members.len()).unwrap());
+            slots[self.rng.gen_range(0, slots.len())]
+        };
+
+        // Make the new member's credential and UserInitKey
+        let (credential, identity_key) = test_utils::random_basic_credential(&mut self.rng);
+        let init_key = self.random_init_key(&identity_key, credential);
+
+        // The adder makes the Welcome from their pre-Add state, then makes the Add
+        let (welcome, add_handshake, new_state, app_key_chain) = {
+            let adder_state = &get_member(&self.members, adder_idx).group_state;
+            let (welcome, welcome_info_hash) =
+                Welcome::from_group_state(adder_state, &init_key, &mut self.rng).unwrap();
+            let (handshake, new_state, app_key_chain) = adder_state
+                .create_and_apply_add_handshake(
+                    RosterIndex(new_roster_index),
+                    init_key.clone(),
+                    &welcome_info_hash,
+                )
+                .unwrap();
+            (welcome, handshake, new_state, app_key_chain)
+        };
+        self.install_state(adder_idx, new_state, app_key_chain);
+
+        // The new member starts from the Welcome, then processes the Add that put them in
+        let preliminary_state = GroupState::from_welcome(welcome, identity_key, init_key).unwrap();
+        let (group_state, app_key_chain) =
+            preliminary_state.process_handshake(&add_handshake).unwrap();
+        let new_member = Member {
+            group_state,
+            app_key_chain: Some(app_key_chain),
+        };
+
+        // Everyone who's already in the group processes the Add. We do this before the new member
+        // shows up so they don't receive their own Add twice.
+        self.send(adder_idx, Message::Handshake(add_handshake));
+        self.deliver_all();
+
+        let slot = new_roster_index as usize;
+        if slot == self.members.len() {
+            self.members.push(Some(new_member));
+        } else {
+            self.members[slot] = Some(new_member);
+        }
+    }
+
+    /// A random member updates their path secret
+    fn do_update(&mut self) {
+        let updater_idx = self.random_member_index();
+
+        let (handshake, new_state, app_key_chain) = {
+            let updater_state = &get_member(&self.members, updater_idx).group_state;
+            let path_secret = PathSecret::new_from_random(updater_state.cs, &mut self.rng);
+            updater_state.create_and_apply_update_handshake(path_secret, &mut self.rng).unwrap()
+        };
+        self.install_state(updater_idx, new_state, app_key_chain);
+
+        self.send(updater_idx, Message::Handshake(handshake));
+        self.deliver_all();
+    }
+
+    /// A random member removes some other random member
+    fn do_remove(&mut self) {
+        let remover_idx = self.random_member_index();
+        // Members can't remove themselves
+        let removed_idx = loop {
+            let idx = self.random_member_index();
+            if idx != remover_idx {
+                break idx;
+            }
+        };
+
+        let (handshake, new_state, app_key_chain) = {
+            let remover_state = &get_member(&self.members, remover_idx).group_state;
+            let path_secret = PathSecret::new_from_random(remover_state.cs, &mut self.rng);
+            remover_state
+                .create_and_apply_remove_handshake(
+                    RosterIndex(removed_idx),
+                    path_secret,
+                    &mut self.rng,
+                )
+                .unwrap()
+        };
+        self.install_state(remover_idx, new_state, app_key_chain);
+
+        // The removed member gets the Remove too, and drops out when they see it
+        self.send(remover_idx, Message::Handshake(handshake));
+        self.deliver_all();
+        assert!(self.members[removed_idx as usize].is_none(), "removed member is still around");
+
+        // The roster gets truncated to the last non-empty slot, so do the same to our members
+        while let Some(None) = self.members.last() {
+            self.members.pop();
+        }
+    }
+
+    /// A random member sends a random application message to everyone else
+    fn do_message(&mut self) {
+        let sender_idx = self.random_member_index();
+
+        let plaintext = {
+            let len = self.rng.gen_range(0, 256);
+            let mut buf = vec![0u8; len];
+            self.rng.fill_bytes(&mut buf);
+            buf
+        };
+
+        let app_message = {
+            let sender = self.member_mut(sender_idx);
+            let app_key_chain = sender.app_key_chain.as_mut().expect("sender has no key chain");
+            application::encrypt_application_message(
+                plaintext.clone(),
+                &sender.group_state,
+                app_key_chain,
+            )
+            .unwrap()
+        };
+
+        // Check that everyone got exactly what was sent
+        let received = self.send_and_collect_plaintexts(sender_idx, app_message);
+        for (receiver_idx, decrypted) in received {
+            assert_eq!(
+                decrypted, plaintext,
+                "member {} decrypted the wrong plaintext from {}",
+                receiver_idx, sender_idx
+            );
+        }
+    }
+
+    /// Makes a fresh `UserInitKey` with a random ID for the given identity
+    fn random_init_key(
+        &mut self,
+        identity_key: &SigSecretKey,
+        credential: Credential,
+    ) -> UserInitKey {
+        let user_init_key_id = {
+            let mut buf = [0u8; 16];
+            self.rng.fill_bytes(&mut buf);
+            buf.to_vec()
+        };
+        UserInitKey::new_from_random(
+            identity_key,
+            user_init_key_id,
+            credential,
+            vec![&X25519_SHA256_AES128GCM],
+            vec![MLS_DUMMY_VERSION],
+            &mut self.rng,
+        )
+        .unwrap()
+    }
+
+    /// Replaces the given member's state with a new one
+    fn install_state(
+        &mut self,
+        roster_index: u32,
+        group_state: GroupState,
+        app_key_chain: ApplicationKeyChain,
+    ) {
+        let member = self.member_mut(roster_index);
+        member.group_state = group_state;
+        member.app_key_chain = Some(app_key_chain);
+    }
+
+    /// Puts a message on the delivery service
+    fn send(&mut self, sender_idx: u32, msg: Message) {
+        self.delivery_service.queue.push_back((sender_idx, msg));
+    }
+
+    /// Delivers every queued handshake to every member but its sender. Members who find out that
+    /// they've been removed are dropped from the simulation.
+    fn deliver_all(&mut self) {
+        while let Some((sender_idx, msg)) = self.delivery_service.queue.pop_front() {
+            let handshake = match msg {
+                Message::Handshake(hs) => hs,
+                Message::Application(_) => {
+                    panic!("application messages are delivered by send_and_collect_plaintexts")
+                }
+            };
+
+            for receiver_idx in self.member_indices() {
+                if receiver_idx == sender_idx {
+                    continue;
+                }
+
+                let res = self.member(receiver_idx).group_state.process_handshake(&handshake);
+                match res {
+                    Ok((group_state, app_key_chain)) => {
+                        self.install_state(receiver_idx, group_state, app_key_chain)
+                    }
+                    Err(Error::IAmRemoved) => self.members[receiver_idx as usize] = None,
+                    Err(e) => panic!(
+                        "member {} failed to process handshake from {}: {:?}",
+                        receiver_idx, sender_idx, e
+                    ),
+                }
+            }
+        }
+    }
+
+    /// Delivers an application message to every member but its sender. Returns a list of
+    /// `(receiver roster index, decrypted plaintext)` pairs.
+    fn send_and_collect_plaintexts(
+        &mut self,
+        sender_idx: u32,
+        app_message: ApplicationMessage,
+    ) -> Vec<(u32, Vec<u8>)> {
+        self.send(sender_idx, Message::Application(app_message));
+
+        let mut plaintexts = Vec::new();
+        while let Some((sender_idx, msg)) = self.delivery_service.queue.pop_front() {
+            let app_message = match msg {
+                Message::Application(am) => am,
+                Message::Handshake(_) => panic!("handshakes are delivered by deliver_all"),
+            };
+
+            for receiver_idx in self.member_indices() {
+                if receiver_idx == sender_idx {
+                    continue;
+                }
+
+                let receiver = self.member_mut(receiver_idx);
+                let app_key_chain =
+                    receiver.app_key_chain.as_mut().expect("receiver has no key chain");
+                let decrypted = application::decrypt_application_message(
+                    app_message.clone(),
+                    &receiver.group_state,
+                    app_key_chain,
+                )
+                .unwrap();
+                plaintexts.push((receiver_idx, decrypted.plaintext));
+            }
+        }
+
+        plaintexts
+    }
+
+    /// Panics unless every member has the same view of the group, i.e., the same group ID, epoch,
+    /// roster, tree, transcript hash, and epoch secrets
+    pub(crate) fn assert_converged(&self) {
+        let history = &self.history;
+
+        let mut members = self.members.iter().flatten();
+        let first = members.next().expect("simulated group is empty");
+
+        for (i, member) in self.members.iter().enumerate() {
+            // Everyone should know where they are in the roster
+            if let Some(member) = member {
+                assert_eq!(
+                    member.group_state.roster_index,
+                    Some(RosterIndex(u32::try_from(i).unwrap()))
+                );
+                assert!(member.group_state.roster.0[i].is_some());
+            }
+        }
+
+        for member in members {
+            assert_serialized_eq!(
+                first.group_state,
+                member.group_state,
+                "GroupStates diverged. History: {:?}",
+                history
+            );
+            assert_eq!(
+                first.group_state.init_secret, member.group_state.init_secret,
+                "Epoch secrets diverged. History: {:?}",
+                history
+            );
+        }
+
+        // Our view of who's in the group should match the roster
+        assert_eq!(first.group_state.roster.len(), self.members.len());
+    }
+
+    /// Returns the serialized group state of the lowest-indexed member, for comparing runs
+    fn serialized_state(&self) -> Vec<u8> {
+        let member = self.members.iter().flatten().next().expect("simulated group is empty");
+        crate::tls_ser::serialize_to_bytes(&member.group_state).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Simulation;
+
+    use quickcheck_macros::quickcheck;
+
+    // Runs a random sequence of operations and checks that all members agree after every step
+    #[quickcheck]
+    fn simulation_converges(rng_seed: u64) {
+        let mut sim = Simulation::new(rng_seed);
+        sim.run(30);
+    }
+
+    // Two simulations with the same seed should end up in exactly the same place. This is what
+    // makes failures reproducible.
+    #[quickcheck]
+    fn simulation_is_deterministic(rng_seed: u64) {
+        let mut sim1 = Simulation::new(rng_seed);
+        let mut sim2 = Simulation::new(rng_seed);
+        sim1.run(10);
+        sim2.run(10);
+
+        assert_eq!(sim1.serialized_state(), sim2.serialized_state());
+    }
+}
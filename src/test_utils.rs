@@ -1,14 +1,19 @@
 use crate::{
-    credential::{self, BasicCredential, Credential, Roster},
+    config::GroupConfig,
+    credential::{self, BasicCredential, Credential, MemberIndex, Roster, RosterIndex},
     crypto::{
+        aead::AES128GCM_IMPL,
         ciphersuite::{CipherSuite, X25519_SHA256_AES128GCM},
-        hash::Digest,
+        dh::X25519_IMPL,
+        hash::{Digest, SHA512_IMPL},
         hmac::HmacKey,
         rng::CryptoRng,
-        sig::{SigPublicKey, SigSecretKey, SignatureScheme, ED25519_IMPL},
+        sig::{SigPublicKey, SigSecretKey, SignatureScheme, ECDSA_P384_IMPL, ED25519_IMPL},
     },
+    extensions::ExtensionList,
     group_state::GroupState,
-    handshake::MLS_DUMMY_VERSION,
+    handshake::{UserInitKey, MLS_DUMMY_VERSION},
+    keystore::IdentityKey,
     ratchet_tree::{PathSecret, RatchetTree, RatchetTreeNode},
     tree_math,
 };
@@ -16,6 +21,7 @@ use crate::{
 use core::convert::TryFrom;
 
 use rand::seq::SliceRandom;
+use std::sync::Arc;
 
 macro_rules! assert_serialized_eq {
     ($left:expr, $right:expr $(,$fmt:tt)*) => {
@@ -27,19 +33,29 @@ macro_rules! assert_serialized_eq {
     };
 }
 
+// None of the registered ciphersuites that use SHA-512 have DH we implement, so this stands in for
+// them in tests. It has no ID, so it panics if it's ever serialized. GroupStates and Handshakes
+// don't serialize their ciphersuite, so whole groups can run under it.
+pub(crate) const X25519_SHA512_AES128GCM: CipherSuite = CipherSuite {
+    name: "X25519_SHA512_AES128GCM",
+    dh_impl: &X25519_IMPL,
+    aead_impl: &AES128GCM_IMPL,
+    hash_impl: &SHA512_IMPL,
+};
+
 // Generates a random roster index within the given bounds, and guarantees that the output is not in
 // `forbidden_indices`
 pub(crate) fn random_roster_index_with_exceptions<R: rand::Rng>(
     roster_size: usize,
     forbidden_indices: &[usize],
     rng: &mut R,
-) -> u32 {
+) -> RosterIndex {
     loop {
         let idx = rng.gen_range(0, roster_size);
         if forbidden_indices.contains(&idx) {
             continue;
         } else {
-            return u32::try_from(idx).unwrap();
+            return RosterIndex(u32::try_from(idx).unwrap());
         }
     }
 }
@@ -76,21 +92,17 @@ fn random_tree<R: rand::Rng + CryptoRng>(
 ) -> RatchetTree {
     // Make a tree of Blanks, then fill it with private keys
     let num_nodes = tree_math::num_nodes_in_tree(num_leaves);
-    let mut tree = RatchetTree {
-        nodes: vec![RatchetTreeNode::Blank; num_nodes],
-    };
+    let mut tree = RatchetTree::new(vec![RatchetTreeNode::Blank; num_nodes]);
 
-    // In a random order, fill the tree
-    // We cannot say the word "leaf index" because that means something else
-    let indices_of_leaves = (0..num_leaves).map(|i| i.checked_mul(2).unwrap());
-    for idx in indices_of_leaves {
+    // Fill the tree from every leaf in turn
+    for leaf_idx in (0..num_leaves).map(tree_math::LeafIndex) {
         // Random path secret used to derive all private keys up the tree
         let path_secret = {
             let mut buf = [0u8; 32];
             rng.fill_bytes(&mut buf);
             PathSecret::new_from_bytes(&buf)
         };
-        tree.propagate_new_path_secret(cs, path_secret, idx)
+        tree.propagate_new_path_secret(cs, path_secret, leaf_idx.node_index())
             .expect("couldn't propagate random secrets in a random tree");
     }
 
@@ -106,14 +118,14 @@ pub(crate) fn random_full_group_state<R: rand::Rng + CryptoRng>(
 ) -> (GroupState, Vec<SigSecretKey>) {
     // TODO: Expand the number of available ciphersuites once more are available
     let cipher_suites = &[X25519_SHA256_AES128GCM];
-    let sig_schemes = &[ED25519_IMPL];
+    let sig_schemes = &[ED25519_IMPL, ECDSA_P384_IMPL];
 
     let cs = cipher_suites.choose(rng).unwrap();
     let ss = sig_schemes.choose(rng).unwrap();
 
     // Group size and position in group are random
     let group_size: u32 = rng.gen_range(min_size, 50);
-    let my_roster_idx: u32 = rng.gen_range(0, group_size);
+    let my_roster_idx = RosterIndex(rng.gen_range(0, group_size));
 
     // Make a full roster (no empty slots) of random creds and store the identity keys
     let mut roster = Roster(Vec::new());
@@ -123,7 +135,7 @@ pub(crate) fn random_full_group_state<R: rand::Rng + CryptoRng>(
         roster.0.push(Some(cred));
         identity_keys.push(secret);
     }
-    let my_identity_key = identity_keys[my_roster_idx as usize].clone();
+    let my_identity_key = identity_keys[my_roster_idx.0 as usize].clone();
 
     // Make a full tree with all secrets known
     let tree = random_tree(rng, cs, group_size as usize);
@@ -138,19 +150,28 @@ pub(crate) fn random_full_group_state<R: rand::Rng + CryptoRng>(
     // Make a random init_secret and a zero transcript_hash
     let init_secret = HmacKey::new_from_random(cs.hash_impl, rng);
     let transcript_hash = Digest::new_from_zeros(cs.hash_impl);
+    let member_index = Arc::new(MemberIndex::from_roster(&roster));
 
     let group_state = GroupState {
-        cs: cs,
+        cs,
         protocol_version: MLS_DUMMY_VERSION,
-        identity_key: my_identity_key,
+        identity_key: IdentityKey::Local(my_identity_key),
         group_id: group_id.to_vec(),
         epoch: rng.gen(),
-        roster: roster,
-        tree: tree,
-        transcript_hash: transcript_hash,
+        roster: Arc::new(roster),
+        tree,
+        transcript_hash,
+        extensions: ExtensionList::new(),
         roster_index: Some(my_roster_idx),
         initializing_user_init_key: None,
-        init_secret: init_secret,
+        init_secret,
+        retired_credentials: Vec::new(),
+        member_index,
+        config: GroupConfig::new(cs),
+        welcome_cache: Vec::new(),
+        cached_context: None,
+        #[cfg(feature = "dangerous-debug")]
+        recorded_secrets: Default::default(),
     };
 
     (group_state, identity_keys)
@@ -168,7 +189,7 @@ pub(crate) fn random_basic_credential<R: rand::Rng + CryptoRng>(
     };
 
     // TODO: Expand the number of available ciphersuites once more are available
-    let signature_schemes = [&ED25519_IMPL];
+    let signature_schemes = [&ED25519_IMPL, &ECDSA_P384_IMPL];
     let ss = *signature_schemes.choose(rng).unwrap();
 
     // Generate a random keypair
@@ -184,19 +205,51 @@ pub(crate) fn random_basic_credential<R: rand::Rng + CryptoRng>(
     (cred, identity_key)
 }
 
+// Makes a UserInitKey with the given ID for the given credential, offering only the given
+// ciphersuite and MLS_DUMMY_VERSION. `identity_key` must be the credential's secret key.
+pub(crate) fn make_init_key<R: rand::Rng + CryptoRng>(
+    identity_key: &SigSecretKey,
+    credential: Credential,
+    id: &[u8],
+    cs: &'static CipherSuite,
+    rng: &mut R,
+) -> UserInitKey {
+    UserInitKey::new_from_random(
+        identity_key,
+        id.to_vec(),
+        credential,
+        vec![cs],
+        vec![MLS_DUMMY_VERSION],
+        rng,
+    )
+    .unwrap()
+}
+
+// Makes a random credential and a UserInitKey for it with the given ID, like `make_init_key`.
+// Returns the key and the credential's secret key.
+pub(crate) fn random_init_key<R: rand::Rng + CryptoRng>(
+    id: &[u8],
+    cs: &'static CipherSuite,
+    rng: &mut R,
+) -> (UserInitKey, SigSecretKey) {
+    let (credential, identity_key) = random_basic_credential(rng);
+    let init_key = make_init_key(&identity_key, credential, id, cs, rng);
+    (init_key, identity_key)
+}
+
 // Returns a new GroupState where the roster index is changed to the given `new_index` and the
 // identity key is changed to correspond to that roster index. Requires that the secret keys in
 // `identity_keys` correspond to the public keys in the given group's roster
 pub(crate) fn change_self_index(
     group_state: &GroupState,
-    identity_keys: &Vec<SigSecretKey>,
-    new_index: u32,
+    identity_keys: &[SigSecretKey],
+    new_index: RosterIndex,
 ) -> GroupState {
-    assert!(new_index as usize <= group_state.roster.len());
+    assert!(new_index.0 as usize <= group_state.roster.len());
 
     let mut new_group_state = group_state.clone();
     new_group_state.roster_index = Some(new_index);
-    new_group_state.identity_key = identity_keys[new_index as usize].clone();
+    new_group_state.identity_key = IdentityKey::Local(identity_keys[new_index.0 as usize].clone());
 
     new_group_state
 }
@@ -0,0 +1,680 @@
+//! Generates test vectors in the formats used by the official MLS implementations repo:
+//! https://github.com/mlswg/mls-implementations/tree/master/test_vectors
+//!
+//! The formats mirror the ones we parse in our own KAT tests (see the comments above
+//! `official_resolution_kat`, `official_key_schedule_kat`, and `official_message_parsing_kat`).
+//! Every message that goes into a vector is first run through a serialize → deserialize → upcast →
+//! serialize round trip, so generating vectors doubles as a consistency check between our encoder
+//! and decoder.
+//!
+//! This module is only available with the `gen-test-vectors` feature enabled. See the
+//! `gen-test-vectors` binary for a command-line wrapper.
+
+use crate::{
+    credential::{BasicCredential, Credential, Identity, Roster, RosterIndex},
+    crypto::{
+        ciphersuite::{CipherSuite, P256_SHA256_AES128GCM, X25519_SHA256_AES128GCM},
+        dh::{DhPrivateKey, DhPublicKey, DhPublicKeyRaw},
+        hash::Digest,
+        hmac::HmacKey,
+        rng::CryptoRng,
+        secret::Secret,
+        sig::{SigPublicKey, SigSecretKey, SignatureScheme, ECDSA_P256_IMPL, ED25519_IMPL},
+    },
+    error::Error,
+    group_state::{derive_epoch_secrets, GroupState, UpdateSecret, Welcome},
+    handshake::{UserInitKey, MLS_DUMMY_VERSION},
+    keystore::IdentityKey,
+    ratchet_tree::{PathSecret, RatchetTree, RatchetTreeNode},
+    tls_de::TlsDeserializer,
+    tls_ser,
+    tree_math::{self, NodeIndex},
+    upcast::{CryptoCtx, CryptoUpcast},
+};
+
+use serde::{de::Deserialize, ser::Serialize};
+
+//
+// Resolution
+//
+
+#[derive(Serialize)]
+#[serde(rename = "Resolution__bound_u8")]
+struct Resolution(Vec<u8>);
+
+#[derive(Serialize)]
+#[serde(rename = "ResolutionCase__bound_u16")]
+struct ResolutionCase(Vec<Resolution>);
+
+#[derive(Serialize)]
+struct ResolutionTestVectors {
+    num_leaves: u32,
+    #[serde(rename = "cases__bound_u32")]
+    cases: Vec<ResolutionCase>,
+}
+
+/// Generates the contents of `resolution.bin`. This contains the resolution of every node in every
+/// blank/filled configuration of a tree with `num_leaves` leaves. Case `t` is the tree whose `n`-th
+/// node is filled iff the `n`-th bit of `t` is set.
+///
+/// Requires: `1 <= num_leaves <= 8`. Otherwise the number of cases gets out of hand, and the node
+/// indices stop fitting in a `u8`.
+///
+/// Returns: `Ok(bytes)` on success. If the above condition is not met, returns an
+/// `Error::ValidationError`.
+pub fn resolution_test_vectors(num_leaves: u32) -> Result<Vec<u8>, Error> {
+    if num_leaves == 0 || num_leaves > 8 {
+        return Err(Error::ValidationError("Resolution vectors need between 1 and 8 leaves"));
+    }
+    let num_nodes = tree_math::num_nodes_in_tree(num_leaves as usize);
+
+    let mut cases = Vec::new();
+    for encoded_tree in 0..(1usize << num_nodes) {
+        // Make the tree whose filled nodes correspond to the set bits of encoded_tree. The public
+        // keys are never looked at, so empty ones will do.
+        let nodes = (0..num_nodes)
+            .map(|i| {
+                if encoded_tree & (1 << i) == 0 {
+                    RatchetTreeNode::Blank
+                } else {
+                    RatchetTreeNode::Filled {
+                        public_key: DhPublicKey::Raw(DhPublicKeyRaw(Vec::new())),
+                        private_key: None,
+                    }
+                }
+            })
+            .collect();
+        let tree = RatchetTree::new(nodes);
+
+        // Record the resolution of every node. The indices are all < 15, so the casts are fine.
+        let resolutions = (0..num_nodes)
+            .map(|i| {
+                let resolution = tree.resolution(NodeIndex(i));
+                Resolution(resolution.into_iter().map(|NodeIndex(j)| j as u8).collect())
+            })
+            .collect();
+        cases.push(ResolutionCase(resolutions));
+    }
+
+    let vectors = ResolutionTestVectors {
+        num_leaves,
+        cases,
+    };
+    tls_ser::serialize_to_bytes(&vectors)
+}
+
+//
+// Key schedule
+//
+
+#[derive(Serialize)]
+struct KeyScheduleEpoch {
+    #[serde(rename = "update_secret__bound_u8")]
+    update_secret: Vec<u8>,
+    #[serde(rename = "epoch_secret__bound_u8")]
+    epoch_secret: Vec<u8>,
+    #[serde(rename = "application_secret__bound_u8")]
+    application_secret: Vec<u8>,
+    #[serde(rename = "confirmation_key__bound_u8")]
+    confirmation_key: Vec<u8>,
+    #[serde(rename = "init_secret__bound_u8")]
+    init_secret: Vec<u8>,
+}
+
+#[derive(Serialize)]
+struct KeyScheduleCase {
+    cipher_suite: &'static CipherSuite,
+    #[serde(rename = "epochs__bound_u16")]
+    epochs: Vec<KeyScheduleEpoch>,
+}
+
+#[derive(Serialize)]
+struct KeyScheduleTestVectors<'a> {
+    n_epochs: u32,
+    // Nobody knows what this is for. The official vectors have it, so we have it too.
+    garbage: u32,
+    // Only the public parts of a GroupState get serialized, which is exactly what we want
+    base_group_state: &'a GroupState,
+    case_p256: KeyScheduleCase,
+    case_x25519: KeyScheduleCase,
+}
+
+/// Generates the contents of `key_schedule.bin`. This contains `n_epochs` many rounds of the key
+/// schedule, starting from a random base `GroupState` of `group_size` members, for each of the
+/// P-256 and X25519 ciphersuites.
+///
+/// Requires: `1 <= n_epochs <= 2^16 - 1` and `group_size >= 1`
+///
+/// Returns: `Ok(bytes)` on success. If the above condition is not met, returns an
+/// `Error::ValidationError`.
+pub fn key_schedule_test_vectors<R>(
+    n_epochs: u32,
+    group_size: u32,
+    csprng: &mut R,
+) -> Result<Vec<u8>, Error>
+where
+    R: CryptoRng,
+{
+    if n_epochs == 0 || n_epochs > u16::MAX as u32 {
+        return Err(Error::ValidationError(
+            "Key schedule vectors need between 1 and 2^16-1 epochs",
+        ));
+    }
+
+    let base_group_state = random_group_state(group_size, csprng)?;
+
+    // The key schedule only ever touches the hash function of a ciphersuite. Since P-256 shares
+    // its hash function with X25519, we can run the P-256 case even though we don't have P-256 DH
+    // yet. The same base GroupState is used for both cases.
+    let case_p256 = key_schedule_case(&P256_SHA256_AES128GCM, &base_group_state, n_epochs, csprng)?;
+    let case_x25519 =
+        key_schedule_case(&X25519_SHA256_AES128GCM, &base_group_state, n_epochs, csprng)?;
+
+    let vectors = KeyScheduleTestVectors {
+        n_epochs,
+        garbage: 0,
+        base_group_state: &base_group_state,
+        case_p256,
+        case_x25519,
+    };
+    tls_ser::serialize_to_bytes(&vectors)
+}
+
+// Runs the key schedule on a copy of the base GroupState n_epochs times with random update
+// secrets, recording every intermediate value
+fn key_schedule_case<R: CryptoRng>(
+    cs: &'static CipherSuite,
+    base_group_state: &GroupState,
+    n_epochs: u32,
+    csprng: &mut R,
+) -> Result<KeyScheduleCase, Error> {
+    let mut group_state = base_group_state.clone();
+    group_state.cs = cs;
+    group_state.init_secret = HmacKey::new_from_zeros(cs.hash_impl);
+
+    let mut epochs = Vec::new();
+    for _ in 0..n_epochs {
+        let update_secret = random_bytes(cs.hash_impl.digest_size(), csprng);
+
+        // The group context is the GroupState itself, just like in the real protocol
+        let secrets = derive_epoch_secrets(
+            cs.hash_impl,
+            &group_state.init_secret,
+            &UpdateSecret(Secret::new_from_bytes(&update_secret)),
+            &group_state,
+        )?;
+        group_state.init_secret = secrets.init_secret.clone();
+
+        epochs.push(KeyScheduleEpoch {
+            update_secret,
+            epoch_secret: secrets.epoch_secret.as_bytes().to_vec(),
+            application_secret: HmacKey::from(secrets.application_secret).as_bytes().to_vec(),
+            confirmation_key: HmacKey::from(secrets.confirmation_key).as_bytes().to_vec(),
+            init_secret: secrets.init_secret.as_bytes().to_vec(),
+        });
+
+        // The epoch is incremented after the key schedule runs, just like in the real protocol
+        group_state.epoch += 1;
+    }
+
+    Ok(KeyScheduleCase {
+        cipher_suite: cs,
+        epochs,
+    })
+}
+
+// Makes a GroupState with group_size members, all with random credentials and leaf keys. The
+// roster index of this member is 0.
+fn random_group_state<R: CryptoRng>(group_size: u32, csprng: &mut R) -> Result<GroupState, Error> {
+    if group_size == 0 {
+        return Err(Error::ValidationError("Cannot make an empty group"));
+    }
+    let cs = &X25519_SHA256_AES128GCM;
+    let ss = &ED25519_IMPL;
+
+    let mut roster = Roster(Vec::new());
+    let mut tree = RatchetTree::new(Vec::new());
+    let mut identity_keys = Vec::new();
+    for _ in 0..group_size {
+        let identity = random_bytes(16, csprng);
+        let (credential, identity_key) = basic_credential(identity, ss, csprng)?;
+        roster.0.push(Some(credential));
+        identity_keys.push(identity_key);
+
+        let leaf_secret = DhPrivateKey::new_from_random(cs.dh_impl, csprng)?;
+        tree.add_leaf_node(RatchetTreeNode::new_from_private_key(cs, leaf_secret));
+    }
+
+    let group_id = random_bytes(16, csprng);
+    let mut group_state = GroupState::new_from_parts(
+        cs,
+        MLS_DUMMY_VERSION,
+        IdentityKey::Local(identity_keys.swap_remove(0)),
+        group_id,
+        roster,
+        RosterIndex(0),
+        tree,
+    );
+    // Give the group some history so the vectors aren't all zeros
+    group_state.epoch = csprng.next_u32();
+    group_state.transcript_hash = cs.hash_impl.hash_bytes(&random_bytes(32, csprng));
+
+    Ok(group_state)
+}
+
+//
+// Messages
+//
+
+// Each message is preceded by its serialized length, so that implementations that don't support
+// a given ciphersuite can skip over its case
+#[derive(Serialize)]
+struct MessagesCase {
+    cipher_suite: &'static CipherSuite,
+    signature_scheme: &'static SignatureScheme,
+    #[serde(rename = "user_init_key__bound_u32")]
+    user_init_key: Vec<u8>,
+    #[serde(rename = "welcome_info__bound_u32")]
+    welcome_info: Vec<u8>,
+    #[serde(rename = "welcome__bound_u32")]
+    welcome: Vec<u8>,
+    #[serde(rename = "add__bound_u32")]
+    add: Vec<u8>,
+    #[serde(rename = "update__bound_u32")]
+    update: Vec<u8>,
+    #[serde(rename = "remove__bound_u32")]
+    remove: Vec<u8>,
+}
+
+#[derive(Serialize)]
+struct MessagesTestVectors {
+    epoch: u32,
+    signer_index: u32,
+    removed: u32,
+    #[serde(rename = "user_id__bound_u8")]
+    user_id: Vec<u8>,
+    #[serde(rename = "group_id__bound_u8")]
+    group_id: Vec<u8>,
+    #[serde(rename = "uik_id__bound_u8")]
+    uik_id: Vec<u8>,
+    #[serde(rename = "dh_seed__bound_u8")]
+    dh_seed: Vec<u8>,
+    #[serde(rename = "sig_seed__bound_u8")]
+    sig_seed: Vec<u8>,
+    #[serde(rename = "random__bound_u8")]
+    random: Vec<u8>,
+    uik_all_scheme: &'static SignatureScheme,
+    #[serde(rename = "user_init_key_all__bound_u32")]
+    user_init_key_all: Vec<u8>,
+
+    case_p256_p256: MessagesCase,
+    case_x25519_ed25519: MessagesCase,
+}
+
+// The inputs that every message in the vectors is built from
+struct MessagesParams {
+    epoch: u32,
+    user_id: Vec<u8>,
+    group_id: Vec<u8>,
+    uik_id: Vec<u8>,
+    dh_seed: Vec<u8>,
+    sig_seed: Vec<u8>,
+}
+
+/// Generates the contents of `messages.bin`. This contains a `UserInitKey`, `WelcomeInfo`,
+/// `Welcome`, and `Add`, `Update`, and `Remove` `Handshake`s for each ciphersuite. Unlike the
+/// official vectors, all the signatures and confirmation MACs here are valid.
+///
+/// We don't implement P-256 yet, so the `case_p256_p256` entry only has its ciphersuite and
+/// signature scheme filled in. All of its messages are empty. Likewise, `user_init_key_all` only
+/// offers the ciphersuites we support.
+///
+/// Returns: `Ok(bytes)` on success. If any message fails to survive a serialization round trip,
+/// returns an `Error::ValidationError`.
+pub fn messages_test_vectors<R>(csprng: &mut R) -> Result<Vec<u8>, Error>
+where
+    R: CryptoRng,
+{
+    // Nothing interesting happens at the signer and removed indices. Alice makes a group, adds Bob
+    // at index 1, updates, then removes Bob.
+    let signer_index = 0u32;
+    let removed = 1u32;
+
+    let params = MessagesParams {
+        epoch: csprng.next_u32(),
+        user_id: random_bytes(16, csprng),
+        group_id: random_bytes(16, csprng),
+        uik_id: random_bytes(16, csprng),
+        dh_seed: random_bytes(32, csprng),
+        sig_seed: random_bytes(32, csprng),
+    };
+    // This is recorded for completeness. It is not used to derive anything.
+    let random = random_bytes(32, csprng);
+
+    // The all-ciphersuite UserInitKey is signed with the same identity key as everything else
+    let uik_all_scheme = &ED25519_IMPL;
+    let user_init_key_all = {
+        let identity_key = SigSecretKey::new_from_bytes(uik_all_scheme, &params.sig_seed)?;
+        let public_key = SigPublicKey::new_from_secret_key(uik_all_scheme, &identity_key);
+        let credential = Credential::Basic(BasicCredential::new(
+            Identity::from_bytes(params.user_id.clone()),
+            uik_all_scheme,
+            public_key,
+        ));
+        let cipher_suites = vec![&X25519_SHA256_AES128GCM];
+        let supported_versions = vec![MLS_DUMMY_VERSION; cipher_suites.len()];
+        let uik = UserInitKey::new_from_random(
+            &identity_key,
+            params.uik_id.clone(),
+            credential,
+            cipher_suites,
+            supported_versions,
+            csprng,
+        )?;
+
+        let ctx = CryptoCtx::new().set_signature_scheme(uik_all_scheme);
+        roundtrip(&uik, &ctx)?
+    };
+
+    let case_p256_p256 = MessagesCase {
+        cipher_suite: &P256_SHA256_AES128GCM,
+        signature_scheme: &ECDSA_P256_IMPL,
+        user_init_key: Vec::new(),
+        welcome_info: Vec::new(),
+        welcome: Vec::new(),
+        add: Vec::new(),
+        update: Vec::new(),
+        remove: Vec::new(),
+    };
+    let case_x25519_ed25519 =
+        messages_case(&X25519_SHA256_AES128GCM, &ED25519_IMPL, &params, removed, csprng)?;
+
+    let vectors = MessagesTestVectors {
+        epoch: params.epoch,
+        signer_index,
+        removed,
+        user_id: params.user_id,
+        group_id: params.group_id,
+        uik_id: params.uik_id,
+        dh_seed: params.dh_seed,
+        sig_seed: params.sig_seed,
+        random,
+        uik_all_scheme,
+        user_init_key_all,
+        case_p256_p256,
+        case_x25519_ed25519,
+    };
+    tls_ser::serialize_to_bytes(&vectors)
+}
+
+// Makes every message in a MessagesCase by running through an actual Add, Update, and Remove.
+// The sig_seed is used as the identity key of both members, and the dh_seed is used as the path
+// secret for the Update and Remove.
+fn messages_case<R: CryptoRng>(
+    cs: &'static CipherSuite,
+    ss: &'static SignatureScheme,
+    params: &MessagesParams,
+    removed: u32,
+    csprng: &mut R,
+) -> Result<MessagesCase, Error> {
+    let ctx = CryptoCtx::new().set_cipher_suite(cs).set_signature_scheme(ss);
+
+    let (alice_credential, alice_identity_key) =
+        basic_credential_from_seed(b"alice".to_vec(), ss, &params.sig_seed)?;
+    let (bob_credential, bob_identity_key) =
+        basic_credential_from_seed(params.user_id.clone(), ss, &params.sig_seed)?;
+
+    // Alice starts a group at the given epoch
+    let mut group_state = GroupState::new_singleton_group(
+        cs,
+        MLS_DUMMY_VERSION,
+        alice_identity_key,
+        params.group_id.clone(),
+        alice_credential,
+        csprng,
+    )?;
+    group_state.epoch = params.epoch;
+
+    // Bob introduces himself
+    let init_key = UserInitKey::new_from_random(
+        &bob_identity_key,
+        params.uik_id.clone(),
+        bob_credential,
+        vec![cs],
+        vec![MLS_DUMMY_VERSION],
+        csprng,
+    )?;
+
+    // Alice welcomes and adds Bob
+    let welcome_info = group_state.as_welcome_info();
+    let (welcome, welcome_info_hash) = Welcome::from_group_state(&group_state, &init_key, csprng)?;
+    let (add, group_state, _) = group_state.create_and_apply_add_handshake(
+        RosterIndex(removed),
+        init_key.clone(),
+        &welcome_info_hash,
+    )?;
+
+    // Alice updates, then removes Bob
+    let path_secret = PathSecret::new_from_bytes(&params.dh_seed);
+    let (update, group_state, _) =
+        group_state.create_and_apply_update_handshake(path_secret.clone(), csprng)?;
+    let (remove, _, _) =
+        group_state.create_and_apply_remove_handshake(RosterIndex(removed), path_secret, csprng)?;
+
+    Ok(MessagesCase {
+        cipher_suite: cs,
+        signature_scheme: ss,
+        user_init_key: roundtrip(&init_key, &ctx)?,
+        welcome_info: roundtrip(&welcome_info, &ctx)?,
+        welcome: roundtrip(&welcome, &ctx)?,
+        add: roundtrip(&add, &ctx)?,
+        update: roundtrip(&update, &ctx)?,
+        remove: roundtrip(&remove, &ctx)?,
+    })
+}
+
+//
+// Tree
+//
+
+#[derive(Serialize)]
+#[serde(rename = "LeafSecret__bound_u8")]
+struct LeafSecret(Vec<u8>);
+
+#[derive(Serialize)]
+struct TreeNode {
+    // A RatchetTreeNode serializes as an optional<DHPublicKey>
+    public_key: RatchetTreeNode,
+    hash: Digest,
+}
+
+#[derive(Serialize)]
+struct TreeCase {
+    #[serde(rename = "root_secret__bound_u8")]
+    root_secret: Vec<u8>,
+    #[serde(rename = "nodes__bound_u32")]
+    nodes: Vec<TreeNode>,
+}
+
+#[derive(Serialize)]
+struct TreeTestVectors {
+    num_leaves: u32,
+    cipher_suite: &'static CipherSuite,
+    #[serde(rename = "leaf_secrets__bound_u32")]
+    leaf_secrets: Vec<LeafSecret>,
+    #[serde(rename = "cases__bound_u32")]
+    cases: Vec<TreeCase>,
+}
+
+/// Generates the contents of `tree.bin`. This starts from an empty tree and adds `num_leaves`
+/// leaves one at a time, each with a random leaf secret that it then uses to set its direct path.
+/// Case `i` is the tree after the `i`-th leaf is added: every node's public key and hash, and the
+/// root's node secret. Only X25519 is covered, since we don't have P-256 DH yet.
+///
+/// Requires: `num_leaves >= 1`
+///
+/// Returns: `Ok(bytes)` on success. If the above condition is not met, returns an
+/// `Error::ValidationError`.
+pub fn tree_test_vectors<R>(num_leaves: u32, csprng: &mut R) -> Result<Vec<u8>, Error>
+where
+    R: CryptoRng,
+{
+    if num_leaves == 0 {
+        return Err(Error::ValidationError("Tree vectors need at least 1 leaf"));
+    }
+    let cs = &X25519_SHA256_AES128GCM;
+
+    let mut tree = RatchetTree::new(Vec::new());
+    let mut leaf_secrets = Vec::new();
+    let mut cases = Vec::new();
+    for _ in 0..num_leaves {
+        let leaf_secret = random_bytes(cs.hash_impl.digest_size(), csprng);
+        let root_secret =
+            tree.add_leaf_with_path_secret(cs, PathSecret::new_from_bytes(&leaf_secret))?;
+
+        // Private keys don't get serialized, so cloning the nodes is enough to leave them out
+        let nodes = tree
+            .nodes()
+            .iter()
+            .cloned()
+            .zip(tree.node_hashes(cs)?)
+            .map(|(public_key, hash)| TreeNode {
+                public_key,
+                hash,
+            })
+            .collect();
+        cases.push(TreeCase {
+            root_secret: root_secret.0.as_bytes().to_vec(),
+            nodes,
+        });
+        leaf_secrets.push(LeafSecret(leaf_secret));
+    }
+
+    let vectors = TreeTestVectors {
+        num_leaves,
+        cipher_suite: cs,
+        leaf_secrets,
+        cases,
+    };
+    tls_ser::serialize_to_bytes(&vectors)
+}
+
+//
+// Helpers
+//
+
+/// Serializes the given value, then deserializes, upcasts, and reserializes it
+///
+/// Returns: `Ok(bytes)` on success, where `bytes` is the serialized form of `value`. If the
+/// reserialized bytes differ from the original ones, returns an `Error::ValidationError`. If
+/// something goes wrong in (de)serialization or upcasting, returns that `Error`.
+fn roundtrip<T>(value: &T, ctx: &CryptoCtx) -> Result<Vec<u8>, Error>
+where
+    T: Serialize + for<'de> Deserialize<'de> + CryptoUpcast,
+{
+    let bytes = tls_ser::serialize_to_bytes(value)?;
+
+    let mut cursor = bytes.as_slice();
+    let mut deserializer = TlsDeserializer::from_reader(&mut cursor);
+    let mut decoded = T::deserialize(&mut deserializer)?;
+    decoded.upcast_crypto_values(ctx)?;
+
+    // The deserializer should have consumed every byte
+    if !cursor.is_empty() {
+        return Err(Error::ValidationError("Deserialization left trailing bytes"));
+    }
+
+    if tls_ser::serialize_to_bytes(&decoded)? != bytes {
+        return Err(Error::ValidationError("Message changed after a serialization round trip"));
+    }
+
+    Ok(bytes)
+}
+
+// Makes a credential with the given identity and a fresh identity key
+fn basic_credential<R: CryptoRng>(
+    identity: Vec<u8>,
+    ss: &'static SignatureScheme,
+    csprng: &mut R,
+) -> Result<(Credential, SigSecretKey), Error> {
+    let identity_key = SigSecretKey::new_from_random(ss, csprng)?;
+    let public_key = SigPublicKey::new_from_secret_key(ss, &identity_key);
+    let credential =
+        Credential::Basic(BasicCredential::new(Identity::from_bytes(identity), ss, public_key));
+
+    Ok((credential, identity_key))
+}
+
+// Makes a credential with the given identity and an identity key derived from the given seed
+fn basic_credential_from_seed(
+    identity: Vec<u8>,
+    ss: &'static SignatureScheme,
+    seed: &[u8],
+) -> Result<(Credential, SigSecretKey), Error> {
+    let identity_key = SigSecretKey::new_from_bytes(ss, seed)?;
+    let public_key = SigPublicKey::new_from_secret_key(ss, &identity_key);
+    let credential =
+        Credential::Basic(BasicCredential::new(Identity::from_bytes(identity), ss, public_key));
+
+    Ok((credential, identity_key))
+}
+
+// Returns a vector of len random bytes
+fn random_bytes<R: CryptoRng>(len: usize, csprng: &mut R) -> Vec<u8> {
+    let mut buf = vec![0u8; len];
+    csprng.fill_bytes(&mut buf);
+    buf
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        test_vectors::{
+            key_schedule_test_vectors, messages_test_vectors, resolution_test_vectors,
+            tree_test_vectors,
+        },
+        tls_de::TlsDeserializer,
+    };
+
+    use rand::SeedableRng;
+    use serde::de::Deserialize;
+
+    // Every case in the resolution vectors should have an entry for every node
+    #[test]
+    fn resolution_vector_shape() {
+        #[derive(Deserialize)]
+        #[serde(rename = "Resolution__bound_u8")]
+        struct Resolution(#[allow(dead_code)] Vec<u8>);
+        #[derive(Deserialize)]
+        #[serde(rename = "ResolutionCase__bound_u16")]
+        struct ResolutionCase(Vec<Resolution>);
+        #[derive(Deserialize)]
+        struct ResolutionTestVectors {
+            num_leaves: u32,
+            #[serde(rename = "cases__bound_u32")]
+            cases: Vec<ResolutionCase>,
+        }
+
+        let bytes = resolution_test_vectors(3).unwrap();
+        let mut cursor = bytes.as_slice();
+        let mut deserializer = TlsDeserializer::from_reader(&mut cursor);
+        let test_vec = ResolutionTestVectors::deserialize(&mut deserializer).unwrap();
+
+        assert_eq!(test_vec.num_leaves, 3);
+        // 3 leaves means 5 nodes, which means 2^5 configurations
+        assert_eq!(test_vec.cases.len(), 32);
+        for case in test_vec.cases.iter() {
+            assert_eq!(case.0.len(), 5);
+        }
+    }
+
+    // Make sure the generators don't error. The message generator does its own round-trip checks
+    // internally, so this also checks encoder/decoder consistency.
+    #[test]
+    fn generators_succeed() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        key_schedule_test_vectors(10, 5, &mut rng).unwrap();
+        messages_test_vectors(&mut rng).unwrap();
+        tree_test_vectors(5, &mut rng).unwrap();
+    }
+}
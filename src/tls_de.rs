@@ -1,4 +1,11 @@
 //! An MLS deserializer. This is the same wire format as TLS.
+//!
+//! How picky the deserializer is depends on its `ParseMode`. Anything that can't be parsed at all
+//! is an error in either mode. This includes unknown enum discriminants, since there's nowhere to
+//! keep a value we can't interpret, and length-bounded fields whose contents don't fill their
+//! length tag exactly. Strict mode additionally rejects encodings that parse fine but that an
+//! up-to-date, honest peer would never send. Lenient mode lets those through and ignores what it
+//! doesn't understand, the way `ExtensionList` keeps unknown extensions around untouched.
 
 use crate::error::Error;
 
@@ -7,9 +14,6 @@ use std::io::Read;
 use byteorder::{BigEndian, ReadBytesExt};
 use serde::de::{Deserializer, IntoDeserializer, Visitor};
 
-// TODO: Make this parser more conservative in what it accepts. Currently, it will happily return
-// incomplete vectors (i.e., it'll read a length, get to the end of a buffer that's too short, and
-// then return what it has instead of blocking or erroring).
 // TODO: Consider the blocking behavior of this deserializer. Can we provide non-blocking options?
 
 /// Makes an `error::Error::SerdeError(std::io::Error)` given some formattable input
@@ -17,6 +21,19 @@ fn make_custom_error<T: core::fmt::Display>(msg: T) -> Error {
     <Error as serde::de::Error>::custom(msg)
 }
 
+/// How strictly a `TlsDeserializer` treats input that's well-formed but unexpected
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ParseMode {
+    /// Rejects empty values in fields whose name contains `__nonempty`, like group IDs and
+    /// `UserInitKey` IDs, and rejects bytes left over after the top-level value. This is meant for
+    /// servers, which have no reason to store or forward anything unusual.
+    Strict,
+    /// Accepts empty IDs and ignores anything after the top-level value. This is meant for
+    /// clients, which have to keep talking to peers running newer versions of the protocol.
+    #[default]
+    Lenient,
+}
+
 /// Given a reader and the name of a field or unit struct, find the length of the upcoming data.
 /// This only makes sense for variable-length data types. So for example if we were parsing the `v`
 /// field of
@@ -37,21 +54,24 @@ fn make_custom_error<T: core::fmt::Display>(msg: T) -> Error {
 /// struct Foo(Vec<u8>);
 /// ```
 /// we would have `field == "Foo__bound_u8` and look for a single byte representing the length of
-/// the contained vector.
-fn get_field_len<R>(field: &'static str, reader: &mut R) -> Result<Option<u64>, Error>
+/// the contained vector. A `__nonempty` before the bound, as in `"Foo__nonempty__bound_u8"`,
+/// doesn't change the length tag. It only tells a strict deserializer to refuse a length of 0.
+fn get_field_len<R>(field: &'static str, de: &mut TlsDeserializer<R>) -> Result<Option<u64>, Error>
 where
     R: std::io::Read,
 {
     let res = if field.ends_with("__bound_u8") {
-        Some(reader.read_u8()?.into())
+        Some(de.read_u8()?.into())
     } else if field.ends_with("__bound_u16") {
-        Some(reader.read_u16::<BigEndian>()?.into())
+        Some(de.read_u16()?.into())
     } else if field.ends_with("__bound_u24") {
-        Some(reader.read_u24::<BigEndian>()?.into())
+        Some(de.read_u24()?.into())
     } else if field.ends_with("__bound_u32") {
-        Some(reader.read_u32::<BigEndian>()?.into())
+        Some(de.read_u32()?.into())
     } else if field.ends_with("__bound_u64") {
-        Some(reader.read_u64::<BigEndian>()?)
+        Some(de.read_u64()?)
+    } else if field.ends_with("__bound_varint") {
+        Some(de.read_varint()?)
     } else {
         None
     };
@@ -64,14 +84,166 @@ where
 /// prefix".
 pub struct TlsDeserializer<'a, R: std::io::Read> {
     reader: &'a mut R,
+    /// The number of bytes left to read, if this deserializer is reading a length-bounded field.
+    /// This is `None` for the top-level deserializer, which reads until the reader is exhausted.
+    remaining: Option<u64>,
+    /// How picky to be. Sub-deserializers for length-bounded fields inherit this.
+    mode: ParseMode,
 }
 
 impl<'a, R: std::io::Read> TlsDeserializer<'a, R> {
-    /// Makes a new `TlsDeserializer` from the given byte reader
-    pub fn from_reader(reader: &'a mut R) -> TlsDeserializer<R> {
+    /// Makes a new lenient `TlsDeserializer` from the given byte reader
+    pub fn from_reader(reader: &'a mut R) -> TlsDeserializer<'a, R> {
+        TlsDeserializer::from_reader_with_mode(reader, ParseMode::Lenient)
+    }
+
+    /// Makes a new `TlsDeserializer` from the given byte reader that parses in the given mode
+    pub fn from_reader_with_mode(reader: &'a mut R, mode: ParseMode) -> TlsDeserializer<'a, R> {
         TlsDeserializer {
             reader,
+            remaining: None,
+            mode,
+        }
+    }
+
+    /// Makes a `TlsDeserializer` that must read exactly `len` bytes from the given reader
+    fn bounded(reader: &'a mut R, len: u64, mode: ParseMode) -> TlsDeserializer<'a, R> {
+        TlsDeserializer {
+            reader,
+            remaining: Some(len),
+            mode,
+        }
+    }
+
+    /// Returns the mode this deserializer parses in
+    pub fn mode(&self) -> ParseMode {
+        self.mode
+    }
+
+    /// Finishes deserializing a top-level value. In strict mode, this checks that the reader has
+    /// nothing left in it. In lenient mode, whatever's left is ignored.
+    ///
+    /// Returns: `Ok(())` on success. Returns an `Error::SerdeError` if this is strict and the
+    /// reader isn't exhausted, or if reading from it fails.
+    pub fn finish(&mut self) -> Result<(), Error> {
+        if self.mode == ParseMode::Lenient {
+            return Ok(());
+        }
+
+        let mut leftover = [0u8; 1];
+        loop {
+            match self.reader.read(&mut leftover) {
+                Ok(0) => return Ok(()),
+                Ok(_) => return Err(make_custom_error("trailing bytes after the top-level value")),
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::SerdeError(e)),
+            }
+        }
+    }
+
+    /// Records that `n` bytes were successfully read
+    fn consume(&mut self, n: u64) {
+        if let Some(ref mut remaining) = self.remaining {
+            // The reader of a bounded deserializer is a Take of the bound's size, so this can't
+            // underflow. Saturate anyway to be safe.
+            *remaining = remaining.saturating_sub(n);
+        }
+    }
+
+    fn read_u8(&mut self) -> Result<u8, Error> {
+        let val = self.reader.read_u8()?;
+        self.consume(1);
+        Ok(val)
+    }
+
+    fn read_u16(&mut self) -> Result<u16, Error> {
+        let val = self.reader.read_u16::<BigEndian>()?;
+        self.consume(2);
+        Ok(val)
+    }
+
+    fn read_u24(&mut self) -> Result<u32, Error> {
+        let val = self.reader.read_u24::<BigEndian>()?;
+        self.consume(3);
+        Ok(val)
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Error> {
+        let val = self.reader.read_u32::<BigEndian>()?;
+        self.consume(4);
+        Ok(val)
+    }
+
+    fn read_u64(&mut self) -> Result<u64, Error> {
+        let val = self.reader.read_u64::<BigEndian>()?;
+        self.consume(8);
+        Ok(val)
+    }
+
+    /// Reads an RFC 9420 variable-length integer. The top two bits of the first byte give the
+    /// size of the integer: 1, 2, or 4 bytes. MLS doesn't allow the 8-byte form, and requires
+    /// every integer to be encoded in as few bytes as possible.
+    ///
+    /// Returns: `Ok(n)` on success. If the first byte starts with `0b11`, if the encoding isn't
+    /// minimal, or if the reader runs out of bytes, returns an `Error::SerdeError`.
+    fn read_varint(&mut self) -> Result<u64, Error> {
+        let first = self.read_u8()?;
+        let (len, min) = match first >> 6 {
+            0 => return Ok(first.into()),
+            1 => (u64::from(first & 0x3f) << 8 | u64::from(self.read_u8()?), 1 << 6),
+            2 => (u64::from(first & 0x3f) << 24 | u64::from(self.read_u24()?), 1 << 14),
+            _ => return Err(make_custom_error("8-byte variable-length integers aren't allowed")),
+        };
+
+        if len < min {
+            Err(make_custom_error("variable-length integer isn't minimally encoded"))
+        } else {
+            Ok(len)
+        }
+    }
+
+    /// Deserializes a value of exactly `len` bytes using the given seed. This is how all
+    /// length-prefixed fields are read. `field` is the name of the field or newtype struct being
+    /// read.
+    ///
+    /// Returns: `Ok(val)` on success. If `len` exceeds the number of bytes left in the enclosing
+    /// field, if the reader runs out of bytes before `len` are read, if the seed doesn't use up
+    /// all `len` bytes, or if this is strict and `len` is 0 for a `__nonempty` field, returns an
+    /// `Error::SerdeError`.
+    fn deserialize_bounded<'de, T>(
+        &mut self,
+        field: &'static str,
+        len: u64,
+        seed: T,
+    ) -> Result<T::Value, Error>
+    where
+        T: serde::de::DeserializeSeed<'de>,
+    {
+        if self.mode == ParseMode::Strict && len == 0 && field.contains("__nonempty") {
+            return Err(make_custom_error(format_args!("{} is empty", field)));
+        }
+
+        // Don't let a length tag claim more bytes than its enclosing field has
+        if let Some(remaining) = self.remaining {
+            if len > remaining {
+                return Err(make_custom_error("length tag exceeds the enclosing field's length"));
+            }
+        }
+
+        // Make a sub-reader that only reads the number of bytes specified by the length tag. Then
+        // deserialize the contents normally. It will finish when it runs out of things to read.
+        // This is guaranteed by the logic in TlsVecSeq.
+        let mut sub_reader = (&mut *self.reader).take(len);
+        let mut sub_deserializer = TlsDeserializer::bounded(&mut sub_reader, len, self.mode);
+        let val = seed.deserialize(&mut sub_deserializer)?;
+
+        // A well-formed field is consumed exactly
+        if sub_deserializer.remaining != Some(0) {
+            return Err(make_custom_error("length-bounded field has trailing bytes"));
         }
+        self.consume(len);
+
+        Ok(val)
     }
 }
 
@@ -87,7 +259,7 @@ impl<'de, 'a, 'b, R: std::io::Read> Deserializer<'de> for &'b mut TlsDeserialize
     where
         V: Visitor<'de>,
     {
-        visitor.visit_u8(self.reader.read_u8()?)
+        visitor.visit_u8(self.read_u8()?)
     }
 
     /// Hint that the `Deserialize` type is expecting a `u16` value.
@@ -95,7 +267,7 @@ impl<'de, 'a, 'b, R: std::io::Read> Deserializer<'de> for &'b mut TlsDeserialize
     where
         V: Visitor<'de>,
     {
-        visitor.visit_u16(self.reader.read_u16::<BigEndian>()?)
+        visitor.visit_u16(self.read_u16()?)
     }
 
     /// Hint that the `Deserialize` type is expecting a `u32` value.
@@ -103,7 +275,7 @@ impl<'de, 'a, 'b, R: std::io::Read> Deserializer<'de> for &'b mut TlsDeserialize
     where
         V: Visitor<'de>,
     {
-        visitor.visit_u32(self.reader.read_u32::<BigEndian>()?)
+        visitor.visit_u32(self.read_u32()?)
     }
 
     /// Hint that the `Deserialize` type is expecting a `u64` value.
@@ -111,7 +283,7 @@ impl<'de, 'a, 'b, R: std::io::Read> Deserializer<'de> for &'b mut TlsDeserialize
     where
         V: Visitor<'de>,
     {
-        visitor.visit_u64(self.reader.read_u64::<BigEndian>()?)
+        visitor.visit_u64(self.read_u64()?)
     }
 
     /// Hint that the `Deserialize` type is expecting an `Option` value. This reads a single byte
@@ -138,18 +310,11 @@ impl<'de, 'a, 'b, R: std::io::Read> Deserializer<'de> for &'b mut TlsDeserialize
     {
         // If the inner type is variable-length, this will return the length of the inner type in
         // bytes
-        let field_len = get_field_len(name, &mut self.reader)?;
+        let field_len = get_field_len(name, self)?;
 
-        // Make a sub-reader that only reads the number of bytes specified by the length tag. Then
-        // deserialize the contents normally. It will finish when it runs out of things to read.
-        // This is guaranteed by the logic in TlsVecSeq.
+        // If it's variable-length, deserialize the contents in a sub-buffer of exactly that length
         if let Some(len) = field_len {
-            // Make a new deserializer with a sub-buffer
-            let mut sub_reader = self.reader.take(len);
-            let mut sub_deserializer = TlsDeserializer::from_reader(&mut sub_reader);
-
-            // Deserialize the contents normally
-            visitor.visit_newtype_struct(&mut sub_deserializer)
+            self.deserialize_bounded(name, len, NewtypeSeed(visitor))
         } else {
             // Otherwise, if the inner type is not variable-length, deserialize the contents
             // normally
@@ -179,9 +344,12 @@ impl<'de, 'a, 'b, R: std::io::Read> Deserializer<'de> for &'b mut TlsDeserialize
         if name.ends_with("__enum_u8") {
             let s = TlsEnumU8::new(self);
             visitor.visit_enum(s)
+        } else if name.ends_with("__enum_u16") {
+            let s = TlsEnumU16::new(self);
+            visitor.visit_enum(s)
         } else {
             Err(make_custom_error(format_args!(
-                "don't know how to deserialize non-__enum_u8 enums: {}",
+                "don't know how to deserialize enums that aren't __enum_u8 or __enum_u16: {}",
                 name
             )))
         }
@@ -218,56 +386,57 @@ impl<'de, 'a, 'b, R: std::io::Read> Deserializer<'de> for &'b mut TlsDeserialize
     }
 
     //
-    // Unimplemented stuff
+    // Unsupported stuff. None of the MLS types use these, so anything that asks for them is
+    // malformed as far as we're concerned.
     //
 
     fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
-        unimplemented!()
+        Err(make_custom_error("TlsDeserializer does not support this type"))
     }
     fn deserialize_bool<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
-        unimplemented!()
+        Err(make_custom_error("TlsDeserializer does not support this type"))
     }
     fn deserialize_i8<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
-        unimplemented!()
+        Err(make_custom_error("TlsDeserializer does not support this type"))
     }
     fn deserialize_i16<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
-        unimplemented!()
+        Err(make_custom_error("TlsDeserializer does not support this type"))
     }
     fn deserialize_i32<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
-        unimplemented!()
+        Err(make_custom_error("TlsDeserializer does not support this type"))
     }
     fn deserialize_i64<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
-        unimplemented!()
+        Err(make_custom_error("TlsDeserializer does not support this type"))
     }
     fn deserialize_i128<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
-        unimplemented!()
+        Err(make_custom_error("TlsDeserializer does not support this type"))
     }
     fn deserialize_u128<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
-        unimplemented!()
+        Err(make_custom_error("TlsDeserializer does not support this type"))
     }
     fn deserialize_f32<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
-        unimplemented!()
+        Err(make_custom_error("TlsDeserializer does not support this type"))
     }
     fn deserialize_f64<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
-        unimplemented!()
+        Err(make_custom_error("TlsDeserializer does not support this type"))
     }
     fn deserialize_char<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
-        unimplemented!()
+        Err(make_custom_error("TlsDeserializer does not support this type"))
     }
     fn deserialize_str<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
-        unimplemented!()
+        Err(make_custom_error("TlsDeserializer does not support this type"))
     }
     fn deserialize_string<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
-        unimplemented!()
+        Err(make_custom_error("TlsDeserializer does not support this type"))
     }
     fn deserialize_bytes<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
-        unimplemented!()
+        Err(make_custom_error("TlsDeserializer does not support this type"))
     }
     fn deserialize_byte_buf<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
-        unimplemented!()
+        Err(make_custom_error("TlsDeserializer does not support this type"))
     }
     fn deserialize_unit<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
-        unimplemented!()
+        Err(make_custom_error("TlsDeserializer does not support this type"))
     }
     fn deserialize_unit_struct<V>(
         self,
@@ -277,7 +446,7 @@ impl<'de, 'a, 'b, R: std::io::Read> Deserializer<'de> for &'b mut TlsDeserialize
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        Err(make_custom_error("TlsDeserializer does not support this type"))
     }
     fn deserialize_tuple_struct<V>(
         self,
@@ -288,19 +457,34 @@ impl<'de, 'a, 'b, R: std::io::Read> Deserializer<'de> for &'b mut TlsDeserialize
     where
         V: Visitor<'de>,
     {
-        unimplemented!()
+        Err(make_custom_error("TlsDeserializer does not support this type"))
     }
     fn deserialize_map<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
-        unimplemented!()
+        Err(make_custom_error("TlsDeserializer does not support this type"))
     }
     fn deserialize_identifier<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
-        unimplemented!()
+        Err(make_custom_error("TlsDeserializer does not support this type"))
     }
     fn deserialize_ignored_any<V: Visitor<'de>>(
         self,
         _visitor: V,
     ) -> Result<V::Value, Self::Error> {
-        unimplemented!()
+        Err(make_custom_error("TlsDeserializer does not support this type"))
+    }
+}
+
+/// Wraps a `Visitor` that expects a newtype struct so that it can be handed to
+/// `TlsDeserializer::deserialize_bounded` like any other `DeserializeSeed`
+struct NewtypeSeed<V>(V);
+
+impl<'de, V: Visitor<'de>> serde::de::DeserializeSeed<'de> for NewtypeSeed<V> {
+    type Value = V::Value;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<V::Value, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        self.0.visit_newtype_struct(deserializer)
     }
 }
 
@@ -379,44 +563,32 @@ impl<'de, 'a, 'b, R: std::io::Read> serde::de::SeqAccess<'de> for TlsStructSeq<'
     where
         T: serde::de::DeserializeSeed<'de>,
     {
-        // This function will not be called more times than there are fields in the struct. If it
-        // is, we will panic
-        let field =
-            self.fields.get(self.field_idx).expect("in unknown field while deserializing a struct");
+        // This function should not be called more times than there are fields in the struct
+        let field = match self.fields.get(self.field_idx) {
+            Some(f) => f,
+            None => return Err(make_custom_error("in unknown field while deserializing a struct")),
+        };
         self.field_idx += 1;
 
         // If this is a variable-length field, read off the length
-        let field_len = get_field_len(field, &mut self.de.reader)?;
+        let field_len = get_field_len(field, self.de)?;
 
-        // As in TlsDeserializer::deserialize_newtype_struct, make a sub-reader that only reads the
-        // number of bytes specified by the length tag. Then deserialize the contents normally. It
-        // will finish when it runs out of things to read. This is guaranteed by the logic in
-        // TlsVecSeq.
+        // As in TlsDeserializer::deserialize_newtype_struct, deserialize variable-length fields in
+        // a sub-buffer of exactly the specified length
         if let Some(len) = field_len {
-            // Make a sub-buffer to read from
-            let mut sub_reader = self.de.reader.take(len);
-            let mut sub_deserializer = TlsDeserializer::from_reader(&mut sub_reader);
-
-            // Deserialize from it normally
-            seed.deserialize(&mut sub_deserializer).map(Some)
+            self.de.deserialize_bounded(field, len, seed).map(Some)
         } else {
             // If no length is specified, do the natural thing
             seed.deserialize(&mut *self.de).map(Some)
         }
-
-        // We can't wrap errors like below. Recall that the sequence deserializer will stop
-        // deserializing once it hits an io::ErrorKind::UnexpectedEof. If `res` above is one of
-        // those, then changing it to an ErrorKind::Custom will make the sequence deserializer
-        // incorrectly think that an error occurred.
-        // TL;DR be better
-        //
-        //res.map_err(|e| make_custom_error(format!("Error reading field {}\n{}", field, e)))
     }
 }
 
 /// This deals with the logic of deserializing sequences (mostly `Vec`s). The logic is simple: keep
-/// deserializing items until you run out of buffer space. The reader that this is given is limited
-/// to the total number of bytes we're supposed to read, so there's no fear of overrun.
+/// deserializing items until you run out of buffer space. If the sequence is inside a
+/// length-bounded field, the sequence ends precisely when the bound is used up, and running out of
+/// bytes in the middle of an item is an error. Otherwise, the sequence ends at the end of the
+/// reader.
 struct TlsVecSeq<'a, 'b, R: std::io::Read> {
     de: &'a mut TlsDeserializer<'b, R>,
 }
@@ -438,7 +610,17 @@ impl<'de, 'a, 'b, R: std::io::Read> serde::de::SeqAccess<'de> for TlsVecSeq<'a,
     where
         T: serde::de::DeserializeSeed<'de>,
     {
-        // Try to deserialize the next item
+        // If we know how many bytes are left, we know exactly when the list ends. Any error after
+        // this point, including running out of bytes, means the list is malformed.
+        if let Some(remaining) = self.de.remaining {
+            if remaining == 0 {
+                return Ok(None);
+            } else {
+                return seed.deserialize(&mut *self.de).map(Some);
+            }
+        }
+
+        // Otherwise, try to deserialize the next item
         match seed.deserialize(&mut *self.de) {
             // If it's all good, return it
             Ok(a) => Ok(Some(a)),
@@ -452,8 +634,8 @@ impl<'de, 'a, 'b, R: std::io::Read> serde::de::SeqAccess<'de> for TlsVecSeq<'a,
                     Err(Error::SerdeError(io_err))
                 }
             }
-            // We can't receive a non-serde error from a deserialize method
-            _ => unreachable!(),
+            // Any other error gets passed along
+            Err(e) => Err(e),
         }
     }
 }
@@ -491,6 +673,37 @@ impl<'de, 'a, 'b, R: std::io::Read> serde::de::EnumAccess<'de> for TlsEnumU8<'a,
     }
 }
 
+/// This deals with the logic of deserializing enums with variant indices of size u16. Only the
+/// width of the tag differs from `TlsEnumU8`, so the contents are read by a `TlsEnumU8`.
+struct TlsEnumU16<'a, 'b, R: std::io::Read> {
+    de: &'a mut TlsDeserializer<'b, R>,
+}
+
+impl<'a, 'b, R: std::io::Read> TlsEnumU16<'a, 'b, R> {
+    /// Makes a new `TlsEnumU16` object from the given deserializer
+    fn new(de: &'a mut TlsDeserializer<'b, R>) -> TlsEnumU16<'a, 'b, R> {
+        TlsEnumU16 {
+            de,
+        }
+    }
+}
+
+impl<'de, 'a, 'b, R: std::io::Read> serde::de::EnumAccess<'de> for TlsEnumU16<'a, 'b, R> {
+    type Error = Error;
+    type Variant = TlsEnumU8<'a, 'b, R>;
+
+    /// Deserializes an enum variant
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self::Variant), Error>
+    where
+        V: serde::de::DeserializeSeed<'de>,
+    {
+        let idx: u16 = serde::de::Deserialize::deserialize(&mut *self.de)?;
+        let variant_de: serde::de::value::U16Deserializer<Error> = idx.into_deserializer();
+        let val = seed.deserialize(variant_de)?;
+        Ok((val, TlsEnumU8::new(self.de)))
+    }
+}
+
 impl<'de, 'a, 'b, R> serde::de::VariantAccess<'de> for TlsEnumU8<'a, 'b, R>
 where
     R: std::io::Read,
@@ -534,10 +747,48 @@ where
 mod test {
     use super::*;
     // Use the test vectors from the serialization code
-    use crate::tls_ser::test::{make_biff, Biff, BIFF_BYTES};
+    use crate::{
+        group_state::Welcome,
+        handshake::{Handshake, UserInitKey},
+        tls_ser::test::{make_biff, Biff, BIFF_BYTES},
+    };
 
+    use quickcheck_macros::quickcheck;
     use serde::de::Deserialize;
 
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Named {
+        #[serde(rename = "id__nonempty__bound_u8")]
+        id: Vec<u8>,
+        tag: u8,
+    }
+
+    // Deserializes a Named from the given bytes in the given mode, and finishes the deserializer
+    fn deserialize_named(mut bytes: &[u8], mode: ParseMode) -> Result<Named, Error> {
+        let mut deserializer = TlsDeserializer::from_reader_with_mode(&mut bytes, mode);
+        let named = Named::deserialize(&mut deserializer)?;
+        deserializer.finish()?;
+        Ok(named)
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    #[serde(rename = "Inner__bound_u8")]
+    struct Inner(u16);
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Outer {
+        #[serde(rename = "v__bound_u8")]
+        v: Vec<u16>,
+        #[serde(rename = "w__bound_u8")]
+        w: Vec<Inner>,
+    }
+
+    // Deserializes an Outer from the given bytes
+    fn deserialize_outer(mut bytes: &[u8]) -> Result<Outer, Error> {
+        let mut deserializer = TlsDeserializer::from_reader(&mut bytes);
+        Outer::deserialize(&mut deserializer)
+    }
+
     // Make a byte sequence by hand whose Biff-deserialization we know, then test that it is what
     // we expect. This uses some stupidly named structs.
     #[test]
@@ -549,4 +800,110 @@ mod test {
 
         assert_eq!(deserialized_biff, expected_biff);
     }
+
+    // Make sure that length-bounded fields are read exactly, and that malformed ones are errors
+    #[test]
+    fn bounded_field_strictness() {
+        // Well-formed: v = [1, 2], w = [Inner(3)]
+        let outer = deserialize_outer(&[0x04, 0x00, 0x01, 0x00, 0x02, 0x03, 0x02, 0x00, 0x03]);
+        assert_eq!(
+            outer.unwrap(),
+            Outer {
+                v: vec![1, 2],
+                w: vec![Inner(3)],
+            }
+        );
+
+        // The length of v is odd, so its last element is cut off
+        assert!(deserialize_outer(&[0x03, 0x00, 0x01, 0x00, 0x00]).is_err());
+        // The buffer ends before v does
+        assert!(deserialize_outer(&[0x04, 0x00, 0x01]).is_err());
+        // Inner has 3 bytes of room but only uses 2
+        assert!(deserialize_outer(&[0x00, 0x04, 0x03, 0x00, 0x03, 0xff]).is_err());
+        // Inner claims more bytes than w has
+        assert!(deserialize_outer(&[0x00, 0x03, 0x05, 0x00, 0x03, 0x00, 0x00, 0x00]).is_err());
+    }
+
+    // Checks that strict mode refuses empty IDs and trailing bytes, that lenient mode lets both
+    // through, and that neither accepts a truncated encoding
+    #[test]
+    fn parse_modes() {
+        use ParseMode::{Lenient, Strict};
+
+        let honest = [0x02, 0xaa, 0xbb, 0x07];
+        let expected = Named {
+            id: vec![0xaa, 0xbb],
+            tag: 7,
+        };
+        assert_eq!(deserialize_named(&honest, Strict).unwrap(), expected);
+        assert_eq!(deserialize_named(&honest, Lenient).unwrap(), expected);
+
+        let empty_id = [0x00, 0x07];
+        assert!(deserialize_named(&empty_id, Strict).is_err());
+        assert_eq!(deserialize_named(&empty_id, Lenient).unwrap().id, Vec::<u8>::new());
+
+        let trailing = [0x02, 0xaa, 0xbb, 0x07, 0xff];
+        assert!(deserialize_named(&trailing, Strict).is_err());
+        assert_eq!(deserialize_named(&trailing, Lenient).unwrap(), expected);
+
+        let truncated = [0x02, 0xaa, 0xbb];
+        assert!(deserialize_named(&truncated, Strict).is_err());
+        assert!(deserialize_named(&truncated, Lenient).is_err());
+    }
+
+    // Reads a single varint from the given bytes
+    fn read_varint(mut bytes: &[u8]) -> Result<u64, Error> {
+        TlsDeserializer::from_reader(&mut bytes).read_varint()
+    }
+
+    // Checks varint decoding against the examples in RFC 9000 §A.1, minus the 8-byte one that MLS
+    // forbids, and checks that non-minimal encodings are refused
+    #[test]
+    fn varint_kat() {
+        assert_eq!(read_varint(&[0x25]).unwrap(), 37);
+        assert_eq!(read_varint(&[0x7b, 0xbd]).unwrap(), 15293);
+        assert_eq!(read_varint(&[0x9d, 0x7f, 0x3e, 0x7d]).unwrap(), 494878333);
+
+        // 37 in 2 bytes, and 63 in 4
+        assert!(read_varint(&[0x40, 0x25]).is_err());
+        assert!(read_varint(&[0x80, 0x00, 0x00, 0x3f]).is_err());
+        // The 8-byte encoding of 151288809941952652
+        assert!(read_varint(&[0xc2, 0x19, 0x7c, 0x5e, 0xff, 0x14, 0xe8, 0x8c]).is_err());
+        // Cut off
+        assert!(read_varint(&[0x9d, 0x7f]).is_err());
+    }
+
+    // Makes sure u16 enum tags and varint-bounded variant contents are read correctly
+    #[test]
+    fn u16_enum_with_varint_bound() {
+        #[derive(Debug, Deserialize, PartialEq)]
+        #[serde(rename = "Tagged__enum_u16")]
+        enum Tagged {
+            Short(u8),
+            Long(Bytes),
+        }
+        #[derive(Debug, Deserialize, PartialEq)]
+        #[serde(rename = "Bytes__bound_varint")]
+        struct Bytes(Vec<u8>);
+
+        let deserialize =
+            |mut bytes: &[u8]| Tagged::deserialize(&mut TlsDeserializer::from_reader(&mut bytes));
+        assert_eq!(deserialize(&[0x00, 0x00, 0x07]).unwrap(), Tagged::Short(7));
+        assert_eq!(
+            deserialize(&[0x00, 0x01, 0x02, 0xaa, 0xbb]).unwrap(),
+            Tagged::Long(Bytes(vec![0xaa, 0xbb]))
+        );
+        // No variant 2
+        assert!(deserialize(&[0x00, 0x02, 0x07]).is_err());
+        // A u8 tag isn't enough
+        assert!(deserialize(&[0x01, 0x02, 0xaa, 0xbb]).is_err());
+    }
+
+    // Arbitrary bytes should never make the deserializer panic. We don't care what the result is.
+    #[quickcheck]
+    fn arbitrary_bytes_dont_panic(bytes: Vec<u8>) {
+        let _ = Handshake::deserialize(&mut TlsDeserializer::from_reader(&mut bytes.as_slice()));
+        let _ = Welcome::deserialize(&mut TlsDeserializer::from_reader(&mut bytes.as_slice()));
+        let _ = UserInitKey::deserialize(&mut TlsDeserializer::from_reader(&mut bytes.as_slice()));
+    }
 }
@@ -4,156 +4,95 @@ use crate::error::Error;
 
 use std::convert::TryFrom;
 
-use byteorder::{BigEndian, WriteBytesExt};
-use doc_comment::doc_comment;
-use serde::ser::{Serialize, SerializeSeq, Serializer};
+use serde::ser::{Serialize, Serializer};
 
 /// Uses `TlsSerializer` to serialize the input to a vector of bytes
 pub(crate) fn serialize_to_bytes<T: Serialize>(value: &T) -> Result<Vec<u8>, Error> {
     let mut serializer = TlsSerializer::new();
     value.serialize(&mut serializer)?;
-    Ok(serializer.buf.into_inner())
+    Ok(serializer.buf)
 }
 
-// This macro gives us a way of serializing things with Tls notation like <1..2^16-1>. Here's how
-// it works: we're given some serializable value: &T and we want to encode it so that we can
-// specify its length in bytes as a prefix. So we first write 0 to the serialization buffer (that's
-// TlsSerializer.buf), then serialize the whole value out. Once it's serialized, we now know how
-// many bytes its serialization takes, so we seek back to the prefix location, and put that in as
-// the length. One downside of this: we have to serialize the whole thing before we can reject it
-// as too long. But this is nice and simple and I don't think it'll backfire unless the local
-// member is actively trying to take up a ton of memory.
-macro_rules! serialize_with_bound {
-    ($t:ty, $ti:ident, $fn_name:ident, $write_fn:ident, $endianness:ty ) => {
-        doc_comment! {
-            concat!(
-                "Serializes an object with a length in bytes that must be representable by `",
-                stringify!($t),
-                "`",
-            ),
-            pub(crate) fn $fn_name<'a, T: Serialize + ?Sized>(
-                value: &T,
-                serializer: &mut &'a mut TlsSerializer,
-            ) -> Result<
-                <&'a mut TlsSerializer as Serializer>::Ok,
-                <&'a mut TlsSerializer as Serializer>::Error,
-            > {
-                use std::convert::TryFrom;
-
-                // Starting position
-                let len_pos = serializer.buf.position();
-                // Write a dummy zero here, then serialize everything we get, then rewrite the
-                // correct length in the position of the dummy zero.
-                serializer.buf.$write_fn::<$endianness>(0)?;
-                value.serialize(&mut **serializer)?;
-                // End position - start position - size of length tag = length of serialized output
-                // The length is a u64 because an std::Cursor can only seek up to 2^64-1 bytes
-                let len: u64 = {
-                    let ty_size = u64::try_from(std::mem::size_of::<$t>())
-                        .expect(&format!("Type {} is too big to be serialized", stringify!(t)));
-                    serializer.buf.position() - len_pos - ty_size
-                };
-
-                if len > u64::from(std::$ti::MAX) {
-                    let err = <Error as serde::ser::Error>::custom(
-                        format_args!(
-                            "tried to serialize a {}-bounded object that was too long",
-                            stringify!($t)
-                        )
-                    );
-                    return Err(err)
-                }
-
-                // If we haven't errored out yet, we're within the bound
-                let len: $t = len as $t;
-                // Save the position at the end of the buffer, seek to the length tag, write, the
-                // length, then seek back to the end.
-                let curr_pos = serializer.buf.position();
-                serializer.buf.set_position(len_pos);
-                serializer.buf.$write_fn::<$endianness>(len)?;
-                serializer.buf.set_position(curr_pos);
-
-                Ok(())
-            }
-        }
-    };
-}
-
-serialize_with_bound!(u16, u16, serialize_with_bound_u16, write_u16, BigEndian);
-serialize_with_bound!(u32, u32, serialize_with_bound_u32, write_u32, BigEndian);
-serialize_with_bound!(u64, u64, serialize_with_bound_u64, write_u64, BigEndian);
-
-// We need some custom instances for u8 and u24, since byteorder's write_u8 isn't parametric over
-// any type, and u24 is not a type.
-
-/// Serializes an object with a length in bytes that must be representable by `u8`
-pub(crate) fn serialize_with_bound_u8<'a, T: Serialize + ?Sized>(
+// This is how we serialize things with TLS notation like <1..2^16-1>. We're given some
+// serializable value: &T and we want to encode it so that its length in bytes is its prefix. We
+// don't know that length until the value is serialized, and computing it up front means walking the
+// value once per level of nesting, which gets expensive for big trees full of bounded vectors. So
+// instead we reserve `prefix_len` zero bytes in the buffer, serialize the value right after them,
+// and then overwrite the zeros with the number of bytes written since. Nothing is serialized more
+// than once, and nested bounded values each patch their own prefix. The downside is that we
+// have to serialize the whole thing before we can reject it as too long. But I don't think that'll
+// backfire unless the local member is actively trying to take up a ton of memory.
+/// Serializes an object with a length in bytes that must be representable in `prefix_len` bytes,
+/// big-endian, and prefixes it with that length
+fn serialize_with_bound<'a, T: Serialize + ?Sized>(
+    prefix_len: usize,
     value: &T,
     serializer: &mut &'a mut TlsSerializer,
 ) -> Result<<&'a mut TlsSerializer as Serializer>::Ok, <&'a mut TlsSerializer as Serializer>::Error>
 {
-    // Starting position
-    let len_pos = serializer.buf.position();
-    // Write a dummy zero here, then serialize everything we get, then rewrite the correct
-    // length in the position of the dummy zero.
-    serializer.buf.write_u8(0)?;
+    // Reserve the prefix, then serialize everything we get right after it
+    let len_pos = serializer.buf.len();
+    serializer.buf.resize(len_pos + prefix_len, 0u8);
     value.serialize(&mut **serializer)?;
     // End position - start position - size of length tag = length of serialized output
-    let len: u64 = serializer.buf.position() - len_pos - 1;
-
-    if len > std::u8::MAX.into() {
-        let err = <Error as serde::ser::Error>::custom(
-            "tried to serialize a u8-bounded object that was too long",
-        );
+    let len = serializer.buf.len() - len_pos - prefix_len;
+
+    // A prefix_len of 8 can hold any usize, so the shift only happens when it could overflow
+    if prefix_len < 8 && (len as u64) >> (8 * prefix_len) != 0 {
+        let err = <Error as serde::ser::Error>::custom(format_args!(
+            "tried to serialize a {}-byte-bounded object that was too long",
+            prefix_len
+        ));
         return Err(err);
     }
 
-    // If we haven't errored out yet, we're within the bound
-    let len: u8 = len as u8;
-    // Save the position at the end of the buffer, seek to the length tag, write, the
-    // length, then seek back to the end.
-    let curr_pos = serializer.buf.position();
-    serializer.buf.set_position(len_pos);
-    serializer.buf.write_u8(len)?;
-    serializer.buf.set_position(curr_pos);
+    // If we haven't errored out yet, we're within the bound. Patch the length into the prefix.
+    let len_bytes = (len as u64).to_be_bytes();
+    serializer.buf[len_pos..len_pos + prefix_len].copy_from_slice(&len_bytes[8 - prefix_len..]);
 
     Ok(())
 }
 
-/// Serializes an object with a length in bytes that must be representable by `u24` (i.e. 3 bytes)
-pub(crate) fn serialize_with_bound_u24<'a, T: Serialize + ?Sized>(
+// RFC 9420 prefixes vectors with a QUIC-style variable-length integer (RFC 9000 §16) instead of a
+// fixed-width one. The top two bits of the first byte say whether the length takes 1, 2, or 4
+// bytes, so we can't reserve the prefix ahead of time. Instead we serialize the value, then
+// splice the prefix in front of it. That's one extra copy of the value per varint-bounded level of
+// nesting, which is fine for the message sizes MLS deals in.
+/// Serializes an object and prefixes it with its length in bytes, encoded as a minimal-size MLS
+/// variable-length integer. The 8-byte form isn't valid in MLS, so lengths of 2^30 or more are an
+/// error.
+fn serialize_with_varint_bound<'a, T: Serialize + ?Sized>(
     value: &T,
     serializer: &mut &'a mut TlsSerializer,
 ) -> Result<<&'a mut TlsSerializer as Serializer>::Ok, <&'a mut TlsSerializer as Serializer>::Error>
 {
-    // Starting position
-    let len_pos = serializer.buf.position();
-    // Write a dummy zero here, then serialize everything we get, then rewrite the correct
-    // length in the position of the dummy zero.
-    serializer.buf.write_u24::<BigEndian>(0)?;
+    let len_pos = serializer.buf.len();
     value.serialize(&mut **serializer)?;
-    // End position - start position - size of length tag = length of serialized output
-    let len: u64 = serializer.buf.position() - len_pos - 3;
-
-    if len >= (1u64 << 24) {
-        let err = <Error as serde::ser::Error>::custom(
-            "tried to serialize a u24-bounded object that was too long",
-        );
-        return Err(err);
-    }
+    let len = serializer.buf.len() - len_pos;
 
-    // If we haven't errored out yet, we're within the bound
-    let len: u32 = len as u32;
-    // Save the position at the end of the buffer, seek to the length tag, write, the
-    // length, then seek back to the end.
-    let curr_pos = serializer.buf.position();
-    serializer.buf.set_position(len_pos);
-    serializer.buf.write_u24::<BigEndian>(len)?;
-    serializer.buf.set_position(curr_pos);
+    let prefix = encode_varint(len as u64)?;
+    serializer.buf.splice(len_pos..len_pos, prefix);
 
     Ok(())
 }
 
+/// Encodes the given length as a minimal-size MLS variable-length integer
+///
+/// Returns: `Ok(bytes)` on success. If `len` is 2^30 or more, returns an `Error::SerdeError`.
+pub(crate) fn encode_varint(len: u64) -> Result<Vec<u8>, Error> {
+    if len < 1 << 6 {
+        Ok(vec![len as u8])
+    } else if len < 1 << 14 {
+        Ok((0x4000 | len as u16).to_be_bytes().to_vec())
+    } else if len < 1 << 30 {
+        Ok((0x8000_0000 | len as u32).to_be_bytes().to_vec())
+    } else {
+        Err(<Error as serde::ser::Error>::custom(
+            "tried to serialize a varint-bounded object that was too long",
+        ))
+    }
+}
+
 /// Tries to match the suffix fo the given field with a `__bound_u*` and calls the appropriate
 /// deserialization function. If no such suffix is found, this just calls `deserialize`.
 pub(crate) fn serialize_with_optional_bound<'a, T>(
@@ -165,15 +104,17 @@ where
     T: Serialize + ?Sized,
 {
     if field.ends_with("__bound_u8") {
-        serialize_with_bound_u8(value, serializer)
+        serialize_with_bound(1, value, serializer)
     } else if field.ends_with("__bound_u16") {
-        serialize_with_bound_u16(value, serializer)
+        serialize_with_bound(2, value, serializer)
     } else if field.ends_with("__bound_u24") {
-        serialize_with_bound_u24(value, serializer)
+        serialize_with_bound(3, value, serializer)
     } else if field.ends_with("__bound_u32") {
-        serialize_with_bound_u32(value, serializer)
+        serialize_with_bound(4, value, serializer)
     } else if field.ends_with("__bound_u64") {
-        serialize_with_bound_u64(value, serializer)
+        serialize_with_bound(8, value, serializer)
+    } else if field.ends_with("__bound_varint") {
+        serialize_with_varint_bound(value, serializer)
     } else {
         value.serialize(&mut **serializer)
     }
@@ -183,20 +124,34 @@ where
 /// format, but it seems as though the idea is "concat everything, and specify length in the
 /// prefix". The output of this is verified against known serializations.
 pub struct TlsSerializer {
-    buf: std::io::Cursor<Vec<u8>>,
+    buf: Vec<u8>,
+}
+
+impl Default for TlsSerializer {
+    fn default() -> TlsSerializer {
+        TlsSerializer::new()
+    }
 }
 
 impl TlsSerializer {
     /// Makes a new empty `TlsSerializer` object
     pub fn new() -> TlsSerializer {
         TlsSerializer {
-            buf: std::io::Cursor::new(Vec::new()),
+            buf: Vec::new(),
+        }
+    }
+
+    /// Makes a new empty `TlsSerializer` object whose buffer can hold `capacity` bytes before it
+    /// has to grow. This is worth it when the size of the output is roughly known ahead of time.
+    pub fn with_capacity(capacity: usize) -> TlsSerializer {
+        TlsSerializer {
+            buf: Vec::with_capacity(capacity),
         }
     }
 
     /// Returns this objects internal buffer
     pub fn into_vec(self) -> Vec<u8> {
-        self.buf.into_inner()
+        self.buf
     }
 }
 
@@ -204,7 +159,7 @@ impl TlsSerializer {
 // for us, we don't actually need that much functionality out of our serializer. So we're going to
 // leave most things unimplemented, and then implement them if we ever end up needing them.
 
-impl<'a> Serializer for &'a mut TlsSerializer {
+impl Serializer for &mut TlsSerializer {
     type Ok = ();
     type Error = crate::error::Error;
 
@@ -221,22 +176,22 @@ impl<'a> Serializer for &'a mut TlsSerializer {
     //
 
     fn serialize_u8(self, v: u8) -> Result<Self::Ok, Self::Error> {
-        self.buf.write_u8(v)?;
+        self.buf.push(v);
         Ok(())
     }
 
     fn serialize_u16(self, v: u16) -> Result<Self::Ok, Self::Error> {
-        self.buf.write_u16::<BigEndian>(v)?;
+        self.buf.extend_from_slice(&v.to_be_bytes());
         Ok(())
     }
 
     fn serialize_u32(self, v: u32) -> Result<Self::Ok, Self::Error> {
-        self.buf.write_u32::<BigEndian>(v)?;
+        self.buf.extend_from_slice(&v.to_be_bytes());
         Ok(())
     }
 
     fn serialize_u64(self, v: u64) -> Result<Self::Ok, Self::Error> {
-        self.buf.write_u64::<BigEndian>(v)?;
+        self.buf.extend_from_slice(&v.to_be_bytes());
         Ok(())
     }
 
@@ -260,7 +215,8 @@ impl<'a> Serializer for &'a mut TlsSerializer {
 
     /// Serializes a newtype struct. This is a bit of a hack: if the name of the struct ends with
     /// `__bound_uX` where X = 8, 16, 24, 32, or 64, then we prefix the serialized inner type with
-    /// its length in bytes. This length tag will be the width of the specified X.
+    /// its length in bytes. This length tag will be the width of the specified X. A
+    /// `__bound_varint` suffix prefixes it with an RFC 9420 variable-length integer instead.
     fn serialize_newtype_struct<T>(
         mut self,
         name: &'static str,
@@ -272,13 +228,10 @@ impl<'a> Serializer for &'a mut TlsSerializer {
         serialize_with_optional_bound(name, value, &mut self)
     }
 
-    /// This just forwards to `serialize_seq`
+    /// Bytes are written as-is, same as a sequence of `u8`s would be
     fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, Self::Error> {
-        let mut seq = self.serialize_seq(Some(v.len()))?;
-        for b in v {
-            seq.serialize_element(b)?;
-        }
-        seq.end()
+        self.buf.extend_from_slice(v);
+        Ok(())
     }
 
     /// `TlsSerializer` is also a `SerializeSeq` (see impl below)
@@ -306,9 +259,12 @@ impl<'a> Serializer for &'a mut TlsSerializer {
             // Make sure the variant index isn't out of our range
             let byte = u8::try_from(variant_index).expect("enum variant index out of bounds");
             self.serialize_u8(byte)
+        } else if name.ends_with("__enum_u16") {
+            let tag = u16::try_from(variant_index).expect("enum variant index out of bounds");
+            self.serialize_u16(tag)
         } else {
             let err = <Error as serde::ser::Error>::custom(
-                "don't know how to serialize a non-__enum_u8 enum",
+                "don't know how to serialize an enum that isn't __enum_u8 or __enum_u16",
             );
             Err(err)
         }
@@ -407,7 +363,7 @@ impl<'a> Serializer for &'a mut TlsSerializer {
 }
 
 /// Serializes slices, vecs, etc.
-impl<'a> serde::ser::SerializeSeq for &'a mut TlsSerializer {
+impl serde::ser::SerializeSeq for &mut TlsSerializer {
     type Ok = ();
     type Error = Error;
 
@@ -426,7 +382,7 @@ impl<'a> serde::ser::SerializeSeq for &'a mut TlsSerializer {
 }
 
 /// Serializes structs. This does the same thing as `TlsSerializer as SerializeSeq`
-impl<'a> serde::ser::SerializeStruct for &'a mut TlsSerializer {
+impl serde::ser::SerializeStruct for &mut TlsSerializer {
     type Ok = ();
     type Error = crate::error::Error;
 
@@ -445,13 +401,13 @@ impl<'a> serde::ser::SerializeStruct for &'a mut TlsSerializer {
 }
 
 /// Serializes tuples. this does the same thing as `TlsSerializer as SerializeSeq`
-impl<'a> serde::ser::SerializeTuple for &'a mut TlsSerializer {
+impl serde::ser::SerializeTuple for &mut TlsSerializer {
     type Ok = ();
     type Error = Error;
 
-    fn serialize_element<T: ?Sized>(&mut self, value: &T) -> Result<(), Self::Error>
+    fn serialize_element<T>(&mut self, value: &T) -> Result<(), Self::Error>
     where
-        T: Serialize,
+        T: ?Sized + Serialize,
     {
         value.serialize(&mut **self)
     }
@@ -464,7 +420,7 @@ impl<'a> serde::ser::SerializeTuple for &'a mut TlsSerializer {
 // More unimplemented stuff
 //
 
-impl<'a> serde::ser::SerializeTupleStruct for &'a mut TlsSerializer {
+impl serde::ser::SerializeTupleStruct for &mut TlsSerializer {
     type Ok = ();
     type Error = crate::error::Error;
 
@@ -479,7 +435,7 @@ impl<'a> serde::ser::SerializeTupleStruct for &'a mut TlsSerializer {
     }
 }
 
-impl<'a> serde::ser::SerializeTupleVariant for &'a mut TlsSerializer {
+impl serde::ser::SerializeTupleVariant for &mut TlsSerializer {
     type Ok = ();
     type Error = crate::error::Error;
 
@@ -494,7 +450,7 @@ impl<'a> serde::ser::SerializeTupleVariant for &'a mut TlsSerializer {
     }
 }
 
-impl<'a> serde::ser::SerializeMap for &'a mut TlsSerializer {
+impl serde::ser::SerializeMap for &mut TlsSerializer {
     type Ok = ();
     type Error = crate::error::Error;
 
@@ -515,7 +471,7 @@ impl<'a> serde::ser::SerializeMap for &'a mut TlsSerializer {
     }
 }
 
-impl<'a> serde::ser::SerializeStructVariant for &'a mut TlsSerializer {
+impl serde::ser::SerializeStructVariant for &mut TlsSerializer {
     type Ok = ();
     type Error = crate::error::Error;
 
@@ -579,7 +535,7 @@ pub(crate) mod test {
 
     // This represents the known Biff data structure that's returned by make_biff()
     #[rustfmt::skip]
-    pub(crate) const BIFF_BYTES: &'static [u8] = &[
+    pub(crate) const BIFF_BYTES: &[u8] = &[
         0x01, 0x00, 0x00, 0x00,          // u32
         0x0a, 0x0b, 0x0c,                // [u8; 3]
         0xff,                            // u8
@@ -641,4 +597,71 @@ pub(crate) mod test {
 
         assert_eq!(serialized.as_slice(), expected_bytes);
     }
+
+    #[derive(Serialize)]
+    struct Tiny {
+        #[serde(rename = "v__bound_u8")]
+        v: Vec<u8>,
+    }
+
+    #[derive(Serialize)]
+    struct Nested {
+        #[serde(rename = "inner__bound_u24")]
+        inner: Vec<Tiny>,
+    }
+
+    // Checks that length prefixes are patched in correctly when bounded values are nested, and
+    // that a value that doesn't fit in its bound is an error
+    #[test]
+    fn bound_prefixes() {
+        let nested = Nested {
+            inner: vec![
+                Tiny {
+                    v: vec![0xaa; 255],
+                },
+                Tiny {
+                    v: Vec::new(),
+                },
+            ],
+        };
+        let serialized = serialize_to_bytes(&nested).unwrap();
+        // 3 bytes of outer prefix, then 1 + 255 bytes, then 1 + 0 bytes
+        assert_eq!(&serialized[..5], &[0x00, 0x01, 0x01, 0xff, 0xaa]);
+        assert_eq!(serialized.len(), 3 + 256 + 1);
+        assert_eq!(serialized.last(), Some(&0x00));
+
+        let too_long = Tiny {
+            v: vec![0xaa; 256],
+        };
+        assert!(serialize_to_bytes(&too_long).is_err());
+    }
+
+    #[derive(Serialize)]
+    struct Varying {
+        #[serde(rename = "v__bound_varint")]
+        v: Vec<u8>,
+        #[serde(rename = "inner__bound_varint")]
+        inner: Vec<Tiny>,
+    }
+
+    // Checks varint length prefixes against the examples in RFC 9000 §A.1, and that lengths too
+    // big for a 4-byte varint are refused
+    #[test]
+    fn varint_prefixes() {
+        assert_eq!(encode_varint(37).unwrap(), vec![0x25]);
+        assert_eq!(encode_varint(15293).unwrap(), vec![0x7b, 0xbd]);
+        assert_eq!(encode_varint(494878333).unwrap(), vec![0x9d, 0x7f, 0x3e, 0x7d]);
+        assert!(encode_varint(1 << 30).is_err());
+
+        // 64 is the first length that needs 2 bytes. The inner list is 1 + 2 bytes long.
+        let varying = Varying {
+            v: vec![0xaa; 64],
+            inner: vec![Tiny {
+                v: vec![0xbb, 0xcc],
+            }],
+        };
+        let serialized = serialize_to_bytes(&varying).unwrap();
+        assert_eq!(&serialized[..3], &[0x40, 0x40, 0xaa]);
+        assert_eq!(&serialized[66..], &[0x03, 0x02, 0xbb, 0xcc]);
+    }
 }
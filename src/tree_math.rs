@@ -1,12 +1,59 @@
-//! This module defines all the tree operations we'll need to use when working with left-balanced
-//! binary trees. For more info, see section 5.1 of the MLS spec.
-
-// TODO: Use a type alias for the index type, and switch out usize for u32
+//! Index arithmetic for the left-balanced binary trees that ratchet trees are built on. For more
+//! info, see section 5.1 of the MLS spec.
+//!
+//! A tree with `n` leaves is stored as an array of `2n - 1` nodes, with the leaves at the even
+//! positions and the parent nodes at the odd ones. So there are two index spaces: a `LeafIndex`
+//! counts leaves from the left, which makes it the same thing as a member's roster index, and a
+//! `NodeIndex` is a position in the node array. These are distinct types so that one can't be
+//! passed where the other is expected. Convert between them with `LeafIndex::node_index` and
+//! `NodeIndex::leaf_index`.
+//!
+//! Every function here that takes a `num_leaves` recomputes the size and root of the tree on every
+//! call. Code that does many operations on a tree of a fixed size should use a `TreeMathContext`.
 
 // Suppose usize is u64. If there are k := 2^(63)+1 leaves, then there are a total of 2(k-1) + 1 =
 // 2(2^(63))+1 = 2^(64)+1 nodes in the tree, which is outside the representable range. So our upper
 // bound is 2^(63) leaves, which gives a tree with 2^(64)-1 nodes.
-pub(crate) const MAX_LEAVES: usize = (std::usize::MAX >> 1) + 1;
+/// The maximum number of leaves a tree can have
+pub const MAX_LEAVES: usize = (usize::MAX >> 1) + 1;
+
+/// The index of a leaf, counting only leaves, from the left. The leftmost leaf is leaf 0. A
+/// member's leaf index is the same as their roster index.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct LeafIndex(pub usize);
+
+/// The index of a node in the array representation of a tree. Leaves and parent nodes both have
+/// node indices.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct NodeIndex(pub usize);
+
+impl LeafIndex {
+    /// Returns the node index of this leaf
+    ///
+    /// Panics: when `self.0 >= MAX_LEAVES`, since no tree has a leaf there
+    pub fn node_index(self) -> NodeIndex {
+        assert!(self.0 < MAX_LEAVES);
+        // Leaves are at the even positions
+        NodeIndex(2 * self.0)
+    }
+}
+
+impl NodeIndex {
+    /// Returns whether this node is a leaf
+    pub fn is_leaf(self) -> bool {
+        node_level(self) == 0
+    }
+
+    /// Returns `Some(leaf_idx)` if this node is a leaf, where `leaf_idx` is its leaf index.
+    /// Otherwise returns `None`.
+    pub fn leaf_index(self) -> Option<LeafIndex> {
+        if self.is_leaf() {
+            Some(LeafIndex(self.0 / 2))
+        } else {
+            None
+        }
+    }
+}
 
 /// Returns `Some(floor(log2(x))` when `x != 0`, and `None` otherwise
 fn log2(x: usize) -> Option<usize> {
@@ -18,16 +65,16 @@ fn log2(x: usize) -> Option<usize> {
 /// Computes the level of a given node in a binary left-balanced tree. Leaves are level 0, their
 /// parents are level 1, etc. If a node's children are at different level, then its level is the
 /// max level of its children plus one.
-pub(crate) fn node_level(idx: usize) -> usize {
+pub fn node_level(idx: NodeIndex) -> usize {
     // The level of idx is equal to the number of trialing 1s in its binary representation.
     // Equivalently, this is just the number of trailing zeros of (NOT idx)
-    (!idx).trailing_zeros() as usize
+    (!idx.0).trailing_zeros() as usize
 }
 
 /// Computes the number of nodes needed to represent a tree with `num_leaves` many leaves
 ///
 /// Panics: when `num_leaves == 0` or `num_leaves > MAX_LEAVES`
-pub(crate) fn num_nodes_in_tree(num_leaves: usize) -> usize {
+pub fn num_nodes_in_tree(num_leaves: usize) -> usize {
     assert!(num_leaves > 0 && num_leaves <= MAX_LEAVES);
     2 * (num_leaves - 1) + 1
 }
@@ -36,7 +83,7 @@ pub(crate) fn num_nodes_in_tree(num_leaves: usize) -> usize {
 ///
 /// Panics: when `num_nodes` is odd, since all left-balanced binary trees have an odd number of
 /// nodes
-pub(crate) fn num_leaves_in_tree(num_nodes: usize) -> usize {
+pub fn num_leaves_in_tree(num_nodes: usize) -> usize {
     assert!(num_nodes % 2 == 1);
     // Inverting the formula for num_nodes_in_tree, we get num_leaves = (num_nodes-1)/2 + 1
     ((num_nodes - 1) >> 1) + 1
@@ -45,17 +92,17 @@ pub(crate) fn num_leaves_in_tree(num_nodes: usize) -> usize {
 /// Computes the index of the root node of a tree with `num_leaves` many leaves
 ///
 /// Panics: when `num_leaves == 0` or `num_leaves > MAX_LEAVES`
-pub(crate) fn root_idx(num_leaves: usize) -> usize {
+pub fn root_idx(num_leaves: usize) -> NodeIndex {
     assert!(num_leaves > 0 && num_leaves <= MAX_LEAVES);
     // Root nodes are always index 2^n - 1 where n is the smallest number such that the size of the
     // tree is less than the next power of 2, i.e., 2^(n+1).
     let n = num_nodes_in_tree(num_leaves);
-    (1 << log2(n).unwrap()) - 1
+    NodeIndex((1 << log2(n).unwrap()) - 1)
 }
 
 /// Computes the index of the left child of a given node. This does not depend on the size of the
 /// tree. The child of a leaf is itself.
-pub(crate) fn node_left_child(idx: usize) -> usize {
+pub fn node_left_child(idx: NodeIndex) -> NodeIndex {
     let lvl = node_level(idx);
     // The child of a leaf is itself
     if lvl == 0 {
@@ -64,89 +111,24 @@ pub(crate) fn node_left_child(idx: usize) -> usize {
         // Being on the n-th level (index 0) means your index is of the form xyz..01111...1 where
         // x,y,z are arbitrary, and there are n-many ones at the end. Stepping to the left is
         // equivalent to clearing the highest trailing 1.
-        idx ^ (0x01 << (lvl - 1))
+        NodeIndex(idx.0 ^ (0x01 << (lvl - 1)))
     }
 }
 
-/// Computes the index of the left child of the given node. The child of a leaf is itself.
+/// Computes the index of the right child of the given node. The child of a leaf is itself.
 ///
 /// Panics: when `num_leaves == 0` or `num_leaves > MAX_LEAVES` or
 /// `idx >= num_nodes_in_tree(num_leaves)`
-pub(crate) fn node_right_child(idx: usize, num_leaves: usize) -> usize {
-    assert!(num_leaves > 0 && num_leaves <= MAX_LEAVES);
-    assert!(idx < num_nodes_in_tree(num_leaves));
-
-    let lvl = node_level(idx);
-    // The child of a leaf is itself
-    if lvl == 0 {
-        idx
-    } else {
-        // Being on the n-th level (index 0) means your index is of the form xyz..01111...1 where
-        // x,y,z are arbitrary, and there are n-many ones at the end. Stepping to the right is
-        // equivalent to setting the rightmost 0 to a 1 and the highest trailing 1 to a 0. However,
-        // this node might not exist (e.g., in a tree of 3 leaves, the right child of the root node
-        // (idx 3) is the node with idx 4, not 5, since the rightmost tree isn't full). So we start
-        // at the conjectured node and move left until we are within the bounds of the tree. This
-        // is guaranteed to terminate, because if it didn't, there couldn't be any nodes with index
-        // higher than the parent, which violates the invariant that every non-leaf node has two
-        // children.
-        let mut r = idx ^ (0x03 << (lvl - 1));
-        let idx_threshold = num_nodes_in_tree(num_leaves);
-        while r >= idx_threshold {
-            r = node_left_child(r);
-        }
-
-        r
-    }
+pub fn node_right_child(idx: NodeIndex, num_leaves: usize) -> NodeIndex {
+    TreeMathContext::new(num_leaves).right_child(idx)
 }
 
 /// Computes the index of the parent of a given node. The parent of the root is the root.
 ///
 /// Panics: when `num_leaves == 0` or `num_leaves > MAX_LEAVES` or
 /// `idx >= num_nodes_in_tree(num_leaves)`
-pub(crate) fn node_parent(idx: usize, num_leaves: usize) -> usize {
-    // The immediate parent of a node. May be beyond the right edge of the tree. This means weird
-    // overflowing behavior when i == usize::MAX. However, this case is caught by the check below
-    // that idx == root_idx(num_leaves). We hit the overflowing case iff idx is usize::MAX, which
-    // is of the form 2^n - 1 for some n, which means that it's the root of a completely full tree
-    // or it's the root of a subtree with more than `MAX_LEAVES` elements. The former case is
-    // handled by the first if-statement below, and the latter is handled by the assert below.
-    fn parent_step(i: usize) -> usize {
-        // Recall that the children of xyz...0111...1 are xyz...0011...1 and xyz...1011...1 Working
-        // backwards, this means that the parent of something that ends with 0011...1 or
-        // 1011...1 is 0111...1. So if i is the index of the least significant 0, we must clear the
-        // (i+1)-th bit and set the i-th bit.
-        // This might be off the edge of the tree, since if, say, we have a tree on 3 leaves, the
-        // rightmost leaf is idx 4, whose parent according to this algorithm would be idx 5, which
-        // doesn't exist.
-        let lvl = node_level(i);
-        let bit_to_clear = i & (0x01 << (lvl + 1));
-        let bit_to_set = 0x01 << lvl;
-
-        (i | bit_to_set) ^ bit_to_clear
-    }
-
-    assert!(num_leaves > 0 && num_leaves <= MAX_LEAVES);
-    assert!(idx < num_nodes_in_tree(num_leaves));
-
-    if idx == root_idx(num_leaves) {
-        idx
-    } else {
-        // First assume we're in a full tree. This means we're assuming the direct path of this
-        // node is maximally long.
-        let mut p = parent_step(idx);
-        let idx_threshold = num_nodes_in_tree(num_leaves);
-        // This must terminate, since stepping up will eventually land us at the root node of the
-        // tree, and parent_step increases the level at every step. The algorithm is correct, since
-        // the direct path of the node of index i ocurring in a non-full subtree is a subpath of
-        // the node of index i ocurring in a full subtree. Since they share an ancestor, we'll
-        // eventually reach it if we start from the bottom and work our way up.
-        while p >= idx_threshold {
-            p = parent_step(p);
-        }
-
-        p
-    }
+pub fn node_parent(idx: NodeIndex, num_leaves: usize) -> NodeIndex {
+    TreeMathContext::new(num_leaves).parent(idx)
 }
 
 /// Finds the minmal common ancestor of the given nodes. Here, minimal means having the smallest
@@ -154,73 +136,25 @@ pub(crate) fn node_parent(idx: usize, num_leaves: usize) -> usize {
 ///
 /// Panics: when `num_leaves == 0` or `num_leaves > MAX_LEAVES` or `idx1 >=
 /// num_nodes_in_tree(num_leaves)` or `idx2 >= num_nodes_in_tree(num_leaves)`
-pub(crate) fn common_ancestor(idx1: usize, idx2: usize, num_leaves: usize) -> usize {
-    // We will compute the direct paths of both and find the first location where they begin to
-    // agree. If they never agree, then their common ancestor is the root node
-
-    // We have to allocate because our implementation of node_direct_path isn't reversible as-is
-    let idx1_dp: Vec<usize> = node_direct_path(idx1, num_leaves).collect();
-    let idx2_dp: Vec<usize> = node_direct_path(idx2, num_leaves).collect();
-
-    // We iterate backwards through the direct paths and stop after we find the first place where
-    // they disagree
-    let mut common_ancestor = root_idx(num_leaves);
-    for (&a, &b) in idx1_dp.iter().rev().zip(idx2_dp.iter().rev()) {
-        if a == b {
-            common_ancestor = a;
-        } else {
-            break;
-        }
-    }
-
-    common_ancestor
+pub fn common_ancestor(idx1: NodeIndex, idx2: NodeIndex, num_leaves: usize) -> NodeIndex {
+    TreeMathContext::new(num_leaves).common_ancestor(idx1, idx2)
 }
 
 /// Returns whether the node at index `a` is an ancestor of the node at index `b`. By convention,
 /// we say that `a` is its own ancestor.
 ///
-/// Panics: when `num_leaves == 0` or `num_leaves > MAX_LEAVES` or `idx1 >=
-/// num_nodes_in_tree(num_leaves)` or `idx2 >= num_nodes_in_tree(num_leaves)`
-pub(crate) fn is_ancestor(a: usize, b: usize, num_leaves: usize) -> bool {
-    let mut curr_idx = b;
-    let root = root_idx(num_leaves);
-
-    // Try to find a along the direct path of b by iteratively moving up the tree. Note that this
-    // doesn't check the root node
-    while curr_idx != root {
-        if curr_idx == a {
-            return true;
-        }
-        curr_idx = node_parent(curr_idx, num_leaves);
-    }
-
-    // If a is the root, then it's everybody's ancestor. Otherwise, we couldn't find a in b's
-    // direct path, so it's not an ancestor
-    a == root
+/// Panics: when `num_leaves == 0` or `num_leaves > MAX_LEAVES` or `a >=
+/// num_nodes_in_tree(num_leaves)` or `b >= num_nodes_in_tree(num_leaves)`
+pub fn is_ancestor(a: NodeIndex, b: NodeIndex, num_leaves: usize) -> bool {
+    TreeMathContext::new(num_leaves).is_ancestor(a, b)
 }
 
 /// Computes the index of the sibling of a given node. The sibling of the root is the root.
 ///
 /// Panics: when `num_leaves == 0` or `num_leaves > MAX_LEAVES` or
 /// `idx >= num_nodes_in_tree(num_leaves)`
-pub(crate) fn node_sibling(idx: usize, num_leaves: usize) -> usize {
-    assert!(num_leaves > 0 && num_leaves <= MAX_LEAVES);
-    assert!(idx < num_nodes_in_tree(num_leaves));
-
-    // Recall that the left and right children of xyz...0111...1 are xyz...0011...1 and
-    // xyz...1011...1, respectively. The former is less than the initial index, and the latter is
-    // greater. So left is smaller, right is greater.
-    let parent = node_parent(idx, num_leaves);
-    if idx < parent {
-        // We were on the left child, so return the right
-        node_right_child(parent, num_leaves)
-    } else if idx > parent {
-        // We were on the right child, so return the left
-        node_left_child(parent)
-    } else {
-        // We're at the root, so return the root
-        parent
-    }
+pub fn node_sibling(idx: NodeIndex, num_leaves: usize) -> NodeIndex {
+    TreeMathContext::new(num_leaves).sibling(idx)
 }
 
 /// Returns an iterator for the path up the tree `i_1, i_2, ..., i_n` where `i_1` is the the given
@@ -228,17 +162,11 @@ pub(crate) fn node_sibling(idx: usize, num_leaves: usize) -> usize {
 ///
 /// Panics: when `num_leaves == 0` or `num_leaves > MAX_LEAVES` or
 /// `start_idx >= num_nodes_in_tree(num_leaves)`
-pub(crate) fn node_direct_path(start_idx: usize, num_leaves: usize) -> impl Iterator<Item = usize> {
-    assert!(num_leaves > 0 && num_leaves <= MAX_LEAVES);
-    assert!(start_idx < num_nodes_in_tree(num_leaves));
-
-    // Start the direct path on the the given node. Since we loop inside DirectPathIter until
-    // parent == root, this will be an empty iterator if we're the root node (since the parent of
-    // the root is the root)
-    DirectPathIter {
-        num_leaves,
-        successive_parent: start_idx,
-    }
+pub fn node_direct_path(
+    start_idx: NodeIndex,
+    num_leaves: usize,
+) -> impl Iterator<Item = NodeIndex> {
+    TreeMathContext::new(num_leaves).direct_path(start_idx)
 }
 
 /// Returns an iterator for the path up the tree `i_1, i_2, ..., i_n` where `i_1` is the the given
@@ -248,28 +176,213 @@ pub(crate) fn node_direct_path(start_idx: usize, num_leaves: usize) -> impl Iter
 ///
 /// Panics: when `num_leaves == 0` or `num_leaves > MAX_LEAVES` or
 /// `start_idx >= num_nodes_in_tree(num_leaves)`
-pub(crate) fn node_extended_direct_path(
-    start_idx: usize,
+pub fn node_extended_direct_path(
+    start_idx: NodeIndex,
     num_leaves: usize,
-) -> impl Iterator<Item = usize> {
-    let root = std::iter::once(root_idx(num_leaves));
-    node_direct_path(start_idx, num_leaves).chain(root)
+) -> impl Iterator<Item = NodeIndex> {
+    TreeMathContext::new(num_leaves).extended_direct_path(start_idx)
+}
+
+/// The size-dependent facts about a tree, computed once. Every function above that takes a
+/// `num_leaves` has to recompute the tree's node count and root index (and assert their bounds)
+/// on every call, and walking a path calls them once per step. Code that does many operations on
+/// a tree of a fixed size should make one of these and use its methods instead.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct TreeMathContext {
+    num_nodes: usize,
+    root: NodeIndex,
+}
+
+impl TreeMathContext {
+    /// Makes a context for a tree with `num_leaves` many leaves
+    ///
+    /// Panics: when `num_leaves == 0` or `num_leaves > MAX_LEAVES`
+    pub fn new(num_leaves: usize) -> TreeMathContext {
+        TreeMathContext {
+            num_nodes: num_nodes_in_tree(num_leaves),
+            root: root_idx(num_leaves),
+        }
+    }
+
+    /// Returns the number of nodes in the tree
+    pub fn num_nodes(&self) -> usize {
+        self.num_nodes
+    }
+
+    /// Returns the index of the root node of the tree
+    pub fn root(&self) -> NodeIndex {
+        self.root
+    }
+
+    /// Computes the index of the right child of the given node. The child of a leaf is itself.
+    ///
+    /// Panics: when `idx >= self.num_nodes()`
+    pub fn right_child(&self, idx: NodeIndex) -> NodeIndex {
+        assert!(idx.0 < self.num_nodes);
+
+        let lvl = node_level(idx);
+        // The child of a leaf is itself
+        if lvl == 0 {
+            idx
+        } else {
+            // Being on the n-th level (index 0) means your index is of the form xyz..01111...1
+            // where x,y,z are arbitrary, and there are n-many ones at the end. Stepping to the
+            // right is equivalent to setting the rightmost 0 to a 1 and the highest trailing 1 to
+            // a 0. However, this node might not exist (e.g., in a tree of 3 leaves, the right
+            // child of the root node (idx 3) is the node with idx 4, not 5, since the rightmost
+            // tree isn't full). So we start at the conjectured node and move left until we are
+            // within the bounds of the tree. This is guaranteed to terminate, because if it
+            // didn't, there couldn't be any nodes with index higher than the parent, which
+            // violates the invariant that every non-leaf node has two children.
+            let mut r = NodeIndex(idx.0 ^ (0x03 << (lvl - 1)));
+            while r.0 >= self.num_nodes {
+                r = node_left_child(r);
+            }
+
+            r
+        }
+    }
+
+    /// Computes the index of the parent of a given node. The parent of the root is the root.
+    ///
+    /// Panics: when `idx >= self.num_nodes()`
+    pub fn parent(&self, idx: NodeIndex) -> NodeIndex {
+        // The immediate parent of a node. May be beyond the right edge of the tree. This means
+        // weird overflowing behavior when i == usize::MAX. However, this case is caught by the
+        // check below that idx == self.root. We hit the overflowing case iff idx is usize::MAX,
+        // which is of the form 2^n - 1 for some n, which means that it's the root of a completely
+        // full tree or it's the root of a subtree with more than `MAX_LEAVES` elements. The former
+        // case is handled by the first if-statement below, and the latter is handled by the
+        // assert below.
+        fn parent_step(i: NodeIndex) -> NodeIndex {
+            // Recall that the children of xyz...0111...1 are xyz...0011...1 and xyz...1011...1
+            // Working backwards, this means that the parent of something that ends with 0011...1
+            // or 1011...1 is 0111...1. So if i is the index of the least significant 0, we must
+            // clear the (i+1)-th bit and set the i-th bit.
+            // This might be off the edge of the tree, since if, say, we have a tree on 3 leaves,
+            // the rightmost leaf is idx 4, whose parent according to this algorithm would be idx
+            // 5, which doesn't exist.
+            let lvl = node_level(i);
+            let bit_to_clear = i.0 & (0x01 << (lvl + 1));
+            let bit_to_set = 0x01 << lvl;
+
+            NodeIndex((i.0 | bit_to_set) ^ bit_to_clear)
+        }
+
+        assert!(idx.0 < self.num_nodes);
+
+        if idx == self.root {
+            idx
+        } else {
+            // First assume we're in a full tree. This means we're assuming the direct path of this
+            // node is maximally long.
+            let mut p = parent_step(idx);
+            // This must terminate, since stepping up will eventually land us at the root node of
+            // the tree, and parent_step increases the level at every step. The algorithm is
+            // correct, since the direct path of the node of index i ocurring in a non-full subtree
+            // is a subpath of the node of index i ocurring in a full subtree. Since they share an
+            // ancestor, we'll eventually reach it if we start from the bottom and work our way up.
+            while p.0 >= self.num_nodes {
+                p = parent_step(p);
+            }
+
+            p
+        }
+    }
+
+    /// Computes the index of the sibling of a given node. The sibling of the root is the root.
+    ///
+    /// Panics: when `idx >= self.num_nodes()`
+    pub fn sibling(&self, idx: NodeIndex) -> NodeIndex {
+        // Recall that the left and right children of xyz...0111...1 are xyz...0011...1 and
+        // xyz...1011...1, respectively. The former is less than the initial index, and the latter
+        // is greater. So left is smaller, right is greater.
+        let parent = self.parent(idx);
+        if idx < parent {
+            // We were on the left child, so return the right
+            self.right_child(parent)
+        } else if idx > parent {
+            // We were on the right child, so return the left
+            node_left_child(parent)
+        } else {
+            // We're at the root, so return the root
+            parent
+        }
+    }
+
+    /// Returns whether the node at index `a` is an ancestor of the node at index `b`. By
+    /// convention, we say that `a` is its own ancestor.
+    ///
+    /// Panics: when `a >= self.num_nodes()` or `b >= self.num_nodes()`
+    pub fn is_ancestor(&self, a: NodeIndex, b: NodeIndex) -> bool {
+        assert!(a.0 < self.num_nodes && b.0 < self.num_nodes);
+
+        // In a full tree, the subtree under a node at level n is exactly the indices that are
+        // within 2^n - 1 of it. A left-balanced tree is a full tree with its right edge cut off,
+        // and cutting doesn't move any nodes, so this also holds here for all the indices that
+        // exist. a can't be usize::MAX, since it's in the tree, so its level is less than the
+        // number of bits in a usize and this can't overflow.
+        let radius = (1usize << node_level(a)) - 1;
+        let distance = core::cmp::max(a.0, b.0) - core::cmp::min(a.0, b.0);
+        distance <= radius
+    }
+
+    /// Finds the minmal common ancestor of the given nodes. Here, minimal means having the
+    /// smallest node level. By convention, we say that the common ancestor of `a` and `a` is `a`.
+    ///
+    /// Panics: when `idx1 >= self.num_nodes()` or `idx2 >= self.num_nodes()`
+    pub fn common_ancestor(&self, idx1: NodeIndex, idx2: NodeIndex) -> NodeIndex {
+        assert!(idx2.0 < self.num_nodes);
+
+        // Walk up from idx1 until we hit something above idx2. This always terminates, since the
+        // root is above everything.
+        let mut ancestor = idx1;
+        while !self.is_ancestor(ancestor, idx2) {
+            ancestor = self.parent(ancestor);
+        }
+
+        ancestor
+    }
+
+    /// Returns an iterator for the path up the tree `i_1, i_2, ..., i_n` where `i_1` is the the
+    /// given starting node and `i_n` is a child of the root node.
+    ///
+    /// Panics: when `start_idx >= self.num_nodes()`
+    pub fn direct_path(&self, start_idx: NodeIndex) -> impl Iterator<Item = NodeIndex> {
+        assert!(start_idx.0 < self.num_nodes);
+
+        // Start the direct path on the the given node. Since we loop inside DirectPathIter until
+        // parent == root, this will be an empty iterator if we're the root node (since the parent
+        // of the root is the root)
+        DirectPathIter {
+            ctx: *self,
+            successive_parent: start_idx,
+        }
+    }
+
+    /// Returns an iterator for the path up the tree `i_1, i_2, ..., i_n` where `i_1` is the the
+    /// given starting node and `i_n` is the root node. See `node_extended_direct_path`.
+    ///
+    /// Panics: when `start_idx >= self.num_nodes()`
+    pub fn extended_direct_path(&self, start_idx: NodeIndex) -> impl Iterator<Item = NodeIndex> {
+        self.direct_path(start_idx).chain(std::iter::once(self.root))
+    }
 }
 
 /// An iterator for direct paths
 struct DirectPathIter {
-    num_leaves: usize,
-    successive_parent: usize,
+    ctx: TreeMathContext,
+    successive_parent: NodeIndex,
 }
 
 impl Iterator for DirectPathIter {
-    type Item = usize;
+    type Item = NodeIndex;
 
-    fn next(&mut self) -> Option<usize> {
+    fn next(&mut self) -> Option<NodeIndex> {
         // If we're not at the root, return where we are, then move up one level
-        if self.successive_parent != root_idx(self.num_leaves) {
+        if self.successive_parent != self.ctx.root {
             let ret = self.successive_parent;
-            self.successive_parent = node_parent(self.successive_parent, self.num_leaves);
+            self.successive_parent = self.ctx.parent(self.successive_parent);
 
             Some(ret)
         } else {
@@ -278,16 +391,6 @@ impl Iterator for DirectPathIter {
     }
 }
 
-/// Returns a list of indices for leaf nodes in a tree of given size. The list is in ascending
-/// index order.
-///
-/// Panics: when `num_leaves == 0` or `num_leaves > MAX_LEAVES`
-pub(crate) fn tree_leaves(num_leaves: usize) -> impl DoubleEndedIterator<Item = usize> {
-    assert!(num_leaves > 0 && num_leaves <= MAX_LEAVES);
-    // The leaves are just all the even indices
-    (0..num_leaves).map(|i| 2 * i)
-}
-
 #[cfg(test)]
 mod test {
     use super::*;
@@ -310,7 +413,7 @@ mod test {
         assert_eq!(log2(255), Some(7));
 
         // Check log2(x/2) == log2(x/4) + 1 where x == 2^n for the biggest possible n for usize
-        let bigboi = std::usize::MAX;
+        let bigboi = usize::MAX;
         assert_eq!(log2((bigboi >> 1) + 1), log2((bigboi >> 2) + 1).map(|i| i + 1));
     }
 
@@ -321,7 +424,7 @@ mod test {
         assert_eq!(num_nodes_in_tree(5), 9);
 
         // For explanation, see comments by definition of MAX_LEAVES
-        assert_eq!(num_nodes_in_tree(MAX_LEAVES), std::usize::MAX);
+        assert_eq!(num_nodes_in_tree(MAX_LEAVES), usize::MAX);
     }
 
     #[test]
@@ -331,14 +434,14 @@ mod test {
         assert_eq!(num_leaves_in_tree(9), 5);
 
         // For explanation, see comments by definition of MAX_LEAVES
-        assert_eq!(num_leaves_in_tree(std::usize::MAX), MAX_LEAVES);
+        assert_eq!(num_leaves_in_tree(usize::MAX), MAX_LEAVES);
     }
 
     // num_leaves_in_tree and num_nodes_in_tree are inverses of each other
     #[quickcheck]
     fn counting_correctness(num_nodes: usize) -> TestResult {
         // num_leaves_in_tree only works on odd inputs, so throw out the even ones
-        if num_nodes % 2 == 0 {
+        if num_nodes.is_multiple_of(2) {
             return TestResult::discard();
         }
 
@@ -358,9 +461,9 @@ mod test {
         let num_nodes = num_nodes_in_tree(num_leaves);
 
         // This is our starting node
-        let me: usize = {
+        let me = {
             let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
-            rng.gen_range(0, num_nodes)
+            NodeIndex(rng.gen_range(0, num_nodes))
         };
         let my_sibling = node_sibling(me, num_leaves);
         let my_parent = node_parent(my_sibling, num_leaves);
@@ -414,9 +517,9 @@ mod test {
         let num_nodes = num_nodes_in_tree(num_leaves);
 
         // The two nodes we want to test. This test is for cases where idx1 != idx2
-        let idx1 = rng.gen_range(0, num_nodes);
+        let idx1 = NodeIndex(rng.gen_range(0, num_nodes));
         let idx2 = loop {
-            let i = rng.gen_range(0, num_nodes);
+            let i = NodeIndex(rng.gen_range(0, num_nodes));
             if i != idx1 {
                 break i;
             }
@@ -431,18 +534,42 @@ mod test {
         assert!(!(is_ancestor(right, idx1, num_leaves) && is_ancestor(right, idx2, num_leaves)));
     }
 
+    // Checks that TreeMathContext's shortcuts for ancestry agree with walking up the direct path
+    #[quickcheck]
+    fn context_ancestry_matches_paths(num_leaves: usize, rng_seed: u64) {
+        // Keep the tree small enough that we can afford to walk paths
+        if num_leaves == 0 || num_leaves > (1 << 20) {
+            return;
+        }
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let ctx = TreeMathContext::new(num_leaves);
+        assert_eq!(ctx.num_nodes(), num_nodes_in_tree(num_leaves));
+        assert_eq!(ctx.root(), root_idx(num_leaves));
+
+        let a = NodeIndex(rng.gen_range(0, ctx.num_nodes()));
+        let b = NodeIndex(rng.gen_range(0, ctx.num_nodes()));
+
+        // a is an ancestor of b iff it's on b's extended direct path. Also, b is always on its own
+        // path, so the common ancestor of a and b is the first thing on b's path that's above a
+        let b_path: Vec<NodeIndex> = ctx.extended_direct_path(b).collect();
+        assert_eq!(ctx.is_ancestor(a, b), b_path.contains(&a));
+        let expected_ancestor = *b_path.iter().find(|&&x| ctx.is_ancestor(x, a)).unwrap();
+        assert_eq!(ctx.common_ancestor(a, b), expected_ancestor);
+    }
+
     // Tests that common_ancestor(a, b, num_leaves) always equals common_ancestor(b, a, num_leaves)
     #[quickcheck]
     fn ancestry_symmetry(a: usize, b: usize, c: usize) {
         // Make the setup idx1 <= idx2 <= num_leaves
         let mut indices = [a, b, c];
         indices.sort();
-        let idx1 = indices[0];
-        let idx2 = indices[1];
+        let idx1 = NodeIndex(indices[0]);
+        let idx2 = NodeIndex(indices[1]);
         let num_leaves = indices[2];
 
         // idx2 has to index into the tree, and num_leaves can't be too big
-        if idx2 == num_leaves || num_leaves >= MAX_LEAVES {
+        if idx2.0 == num_leaves || num_leaves >= MAX_LEAVES {
             return;
         }
 
@@ -466,15 +593,33 @@ mod test {
     // See above tree for a diagram
     #[test]
     fn node_level_simple_kat() {
-        assert_eq!(node_level(0), 0);
-        assert_eq!(node_level(1), 1);
-        assert_eq!(node_level(2), 0);
-        assert_eq!(node_level(3), 2);
-        assert_eq!(node_level(4), 0);
-        assert_eq!(node_level(5), 1);
-        assert_eq!(node_level(6), 0);
-        assert_eq!(node_level(7), 3);
-        assert_eq!(node_level(8), 0);
+        assert_eq!(node_level(NodeIndex(0)), 0);
+        assert_eq!(node_level(NodeIndex(1)), 1);
+        assert_eq!(node_level(NodeIndex(2)), 0);
+        assert_eq!(node_level(NodeIndex(3)), 2);
+        assert_eq!(node_level(NodeIndex(4)), 0);
+        assert_eq!(node_level(NodeIndex(5)), 1);
+        assert_eq!(node_level(NodeIndex(6)), 0);
+        assert_eq!(node_level(NodeIndex(7)), 3);
+        assert_eq!(node_level(NodeIndex(8)), 0);
+    }
+
+    // Converting a leaf index to a node index and back is the identity, and parent nodes have no
+    // leaf index
+    #[quickcheck]
+    fn index_conversion(leaf_idx: usize) {
+        if leaf_idx >= MAX_LEAVES {
+            return;
+        }
+
+        let leaf_idx = LeafIndex(leaf_idx);
+        let node_idx = leaf_idx.node_index();
+        assert!(node_idx.is_leaf());
+        assert_eq!(node_idx.leaf_index(), Some(leaf_idx));
+
+        let parent_idx = NodeIndex(node_idx.0 + 1);
+        assert!(!parent_idx.is_leaf());
+        assert_eq!(parent_idx.leaf_index(), None);
     }
 
     // See above tree for a diagram
@@ -483,7 +628,7 @@ mod test {
         // Convenience function
         fn direct_path_vec(start_idx: usize) -> Vec<usize> {
             let num_leaves = 5;
-            node_direct_path(start_idx, num_leaves).collect::<Vec<usize>>()
+            node_direct_path(NodeIndex(start_idx), num_leaves).map(|idx| idx.0).collect()
         }
 
         assert_eq!(direct_path_vec(0), vec![0, 1, 3]);
@@ -493,7 +638,7 @@ mod test {
         assert_eq!(direct_path_vec(4), vec![4, 5, 3]);
         assert_eq!(direct_path_vec(5), vec![5, 3]);
         assert_eq!(direct_path_vec(6), vec![6, 5, 3]);
-        assert_eq!(direct_path_vec(7), vec![]);
+        assert_eq!(direct_path_vec(7), Vec::<usize>::new());
         assert_eq!(direct_path_vec(8), vec![8]);
     }
 
@@ -503,50 +648,50 @@ mod test {
         let num_leaves = 5;
 
         // Test parent relations
-        assert_eq!(node_parent(0, num_leaves), 1);
-        assert_eq!(node_parent(2, num_leaves), 1);
-        assert_eq!(node_parent(4, num_leaves), 5);
-        assert_eq!(node_parent(6, num_leaves), 5);
-        assert_eq!(node_parent(1, num_leaves), 3);
-        assert_eq!(node_parent(5, num_leaves), 3);
-        assert_eq!(node_parent(3, num_leaves), 7);
-        assert_eq!(node_parent(8, num_leaves), 7);
-        assert_eq!(node_parent(7, num_leaves), 7);
+        assert_eq!(node_parent(NodeIndex(0), num_leaves), NodeIndex(1));
+        assert_eq!(node_parent(NodeIndex(2), num_leaves), NodeIndex(1));
+        assert_eq!(node_parent(NodeIndex(4), num_leaves), NodeIndex(5));
+        assert_eq!(node_parent(NodeIndex(6), num_leaves), NodeIndex(5));
+        assert_eq!(node_parent(NodeIndex(1), num_leaves), NodeIndex(3));
+        assert_eq!(node_parent(NodeIndex(5), num_leaves), NodeIndex(3));
+        assert_eq!(node_parent(NodeIndex(3), num_leaves), NodeIndex(7));
+        assert_eq!(node_parent(NodeIndex(8), num_leaves), NodeIndex(7));
+        assert_eq!(node_parent(NodeIndex(7), num_leaves), NodeIndex(7));
 
         // Test leaf child relations
-        assert_eq!(node_left_child(0), 0);
-        assert_eq!(node_right_child(0, num_leaves), 0);
-        assert_eq!(node_left_child(2), 2);
-        assert_eq!(node_right_child(2, num_leaves), 2);
-        assert_eq!(node_left_child(4), 4);
-        assert_eq!(node_right_child(4, num_leaves), 4);
-        assert_eq!(node_left_child(6), 6);
-        assert_eq!(node_right_child(6, num_leaves), 6);
-        assert_eq!(node_left_child(8), 8);
-        assert_eq!(node_right_child(8, num_leaves), 8);
+        assert_eq!(node_left_child(NodeIndex(0)), NodeIndex(0));
+        assert_eq!(node_right_child(NodeIndex(0), num_leaves), NodeIndex(0));
+        assert_eq!(node_left_child(NodeIndex(2)), NodeIndex(2));
+        assert_eq!(node_right_child(NodeIndex(2), num_leaves), NodeIndex(2));
+        assert_eq!(node_left_child(NodeIndex(4)), NodeIndex(4));
+        assert_eq!(node_right_child(NodeIndex(4), num_leaves), NodeIndex(4));
+        assert_eq!(node_left_child(NodeIndex(6)), NodeIndex(6));
+        assert_eq!(node_right_child(NodeIndex(6), num_leaves), NodeIndex(6));
+        assert_eq!(node_left_child(NodeIndex(8)), NodeIndex(8));
+        assert_eq!(node_right_child(NodeIndex(8), num_leaves), NodeIndex(8));
 
         // Test the non-leaf left relations
-        assert_eq!(node_left_child(7), 3);
-        assert_eq!(node_left_child(3), 1);
-        assert_eq!(node_left_child(1), 0);
-        assert_eq!(node_left_child(5), 4);
+        assert_eq!(node_left_child(NodeIndex(7)), NodeIndex(3));
+        assert_eq!(node_left_child(NodeIndex(3)), NodeIndex(1));
+        assert_eq!(node_left_child(NodeIndex(1)), NodeIndex(0));
+        assert_eq!(node_left_child(NodeIndex(5)), NodeIndex(4));
 
         // Test the non-leaf right relations
-        assert_eq!(node_right_child(7, num_leaves), 8);
-        assert_eq!(node_right_child(3, num_leaves), 5);
-        assert_eq!(node_right_child(1, num_leaves), 2);
-        assert_eq!(node_right_child(5, num_leaves), 6);
+        assert_eq!(node_right_child(NodeIndex(7), num_leaves), NodeIndex(8));
+        assert_eq!(node_right_child(NodeIndex(3), num_leaves), NodeIndex(5));
+        assert_eq!(node_right_child(NodeIndex(1), num_leaves), NodeIndex(2));
+        assert_eq!(node_right_child(NodeIndex(5), num_leaves), NodeIndex(6));
 
         // Test sibling relations
-        assert_eq!(node_sibling(0, num_leaves), 2);
-        assert_eq!(node_sibling(2, num_leaves), 0);
-        assert_eq!(node_sibling(4, num_leaves), 6);
-        assert_eq!(node_sibling(6, num_leaves), 4);
-        assert_eq!(node_sibling(1, num_leaves), 5);
-        assert_eq!(node_sibling(5, num_leaves), 1);
-        assert_eq!(node_sibling(8, num_leaves), 3);
-        assert_eq!(node_sibling(3, num_leaves), 8);
-        assert_eq!(node_sibling(7, num_leaves), 7);
+        assert_eq!(node_sibling(NodeIndex(0), num_leaves), NodeIndex(2));
+        assert_eq!(node_sibling(NodeIndex(2), num_leaves), NodeIndex(0));
+        assert_eq!(node_sibling(NodeIndex(4), num_leaves), NodeIndex(6));
+        assert_eq!(node_sibling(NodeIndex(6), num_leaves), NodeIndex(4));
+        assert_eq!(node_sibling(NodeIndex(1), num_leaves), NodeIndex(5));
+        assert_eq!(node_sibling(NodeIndex(5), num_leaves), NodeIndex(1));
+        assert_eq!(node_sibling(NodeIndex(8), num_leaves), NodeIndex(3));
+        assert_eq!(node_sibling(NodeIndex(3), num_leaves), NodeIndex(8));
+        assert_eq!(node_sibling(NodeIndex(7), num_leaves), NodeIndex(7));
     }
 
     // See above tree for diagram
@@ -557,63 +702,63 @@ mod test {
         // If common_ancestor(a, b, num_leaves) was tested, there's no need to test
         // common_ancestor(b, a, num_leaves), since symmetry was already tested above
 
-        assert_eq!(common_ancestor(0, 0, num_leaves), 0);
-        assert_eq!(common_ancestor(0, 1, num_leaves), 1);
-        assert_eq!(common_ancestor(0, 2, num_leaves), 1);
-        assert_eq!(common_ancestor(0, 3, num_leaves), 3);
-        assert_eq!(common_ancestor(0, 4, num_leaves), 3);
-        assert_eq!(common_ancestor(0, 5, num_leaves), 3);
-        assert_eq!(common_ancestor(0, 6, num_leaves), 3);
-        assert_eq!(common_ancestor(0, 7, num_leaves), 7);
-        assert_eq!(common_ancestor(0, 8, num_leaves), 7);
-
-        assert_eq!(common_ancestor(1, 1, num_leaves), 1);
-        assert_eq!(common_ancestor(1, 2, num_leaves), 1);
-        assert_eq!(common_ancestor(1, 3, num_leaves), 3);
-        assert_eq!(common_ancestor(1, 4, num_leaves), 3);
-        assert_eq!(common_ancestor(1, 5, num_leaves), 3);
-        assert_eq!(common_ancestor(1, 6, num_leaves), 3);
-        assert_eq!(common_ancestor(1, 7, num_leaves), 7);
-        assert_eq!(common_ancestor(1, 8, num_leaves), 7);
-
-        assert_eq!(common_ancestor(2, 2, num_leaves), 2);
-        assert_eq!(common_ancestor(2, 3, num_leaves), 3);
-        assert_eq!(common_ancestor(2, 4, num_leaves), 3);
-        assert_eq!(common_ancestor(2, 5, num_leaves), 3);
-        assert_eq!(common_ancestor(2, 6, num_leaves), 3);
-        assert_eq!(common_ancestor(2, 7, num_leaves), 7);
-        assert_eq!(common_ancestor(2, 8, num_leaves), 7);
-
-        assert_eq!(common_ancestor(3, 3, num_leaves), 3);
-        assert_eq!(common_ancestor(3, 4, num_leaves), 3);
-        assert_eq!(common_ancestor(3, 5, num_leaves), 3);
-        assert_eq!(common_ancestor(3, 6, num_leaves), 3);
-        assert_eq!(common_ancestor(3, 7, num_leaves), 7);
-        assert_eq!(common_ancestor(3, 8, num_leaves), 7);
-
-        assert_eq!(common_ancestor(4, 4, num_leaves), 4);
-        assert_eq!(common_ancestor(4, 5, num_leaves), 5);
-        assert_eq!(common_ancestor(4, 6, num_leaves), 5);
-        assert_eq!(common_ancestor(4, 7, num_leaves), 7);
-        assert_eq!(common_ancestor(4, 8, num_leaves), 7);
-
-        assert_eq!(common_ancestor(5, 5, num_leaves), 5);
-        assert_eq!(common_ancestor(5, 6, num_leaves), 5);
-        assert_eq!(common_ancestor(5, 7, num_leaves), 7);
-        assert_eq!(common_ancestor(5, 8, num_leaves), 7);
-
-        assert_eq!(common_ancestor(6, 6, num_leaves), 6);
-        assert_eq!(common_ancestor(6, 7, num_leaves), 7);
-        assert_eq!(common_ancestor(6, 8, num_leaves), 7);
-
-        assert_eq!(common_ancestor(7, 7, num_leaves), 7);
-        assert_eq!(common_ancestor(7, 8, num_leaves), 7);
-
-        assert_eq!(common_ancestor(8, 8, num_leaves), 8);
+        assert_eq!(common_ancestor(NodeIndex(0), NodeIndex(0), num_leaves), NodeIndex(0));
+        assert_eq!(common_ancestor(NodeIndex(0), NodeIndex(1), num_leaves), NodeIndex(1));
+        assert_eq!(common_ancestor(NodeIndex(0), NodeIndex(2), num_leaves), NodeIndex(1));
+        assert_eq!(common_ancestor(NodeIndex(0), NodeIndex(3), num_leaves), NodeIndex(3));
+        assert_eq!(common_ancestor(NodeIndex(0), NodeIndex(4), num_leaves), NodeIndex(3));
+        assert_eq!(common_ancestor(NodeIndex(0), NodeIndex(5), num_leaves), NodeIndex(3));
+        assert_eq!(common_ancestor(NodeIndex(0), NodeIndex(6), num_leaves), NodeIndex(3));
+        assert_eq!(common_ancestor(NodeIndex(0), NodeIndex(7), num_leaves), NodeIndex(7));
+        assert_eq!(common_ancestor(NodeIndex(0), NodeIndex(8), num_leaves), NodeIndex(7));
+
+        assert_eq!(common_ancestor(NodeIndex(1), NodeIndex(1), num_leaves), NodeIndex(1));
+        assert_eq!(common_ancestor(NodeIndex(1), NodeIndex(2), num_leaves), NodeIndex(1));
+        assert_eq!(common_ancestor(NodeIndex(1), NodeIndex(3), num_leaves), NodeIndex(3));
+        assert_eq!(common_ancestor(NodeIndex(1), NodeIndex(4), num_leaves), NodeIndex(3));
+        assert_eq!(common_ancestor(NodeIndex(1), NodeIndex(5), num_leaves), NodeIndex(3));
+        assert_eq!(common_ancestor(NodeIndex(1), NodeIndex(6), num_leaves), NodeIndex(3));
+        assert_eq!(common_ancestor(NodeIndex(1), NodeIndex(7), num_leaves), NodeIndex(7));
+        assert_eq!(common_ancestor(NodeIndex(1), NodeIndex(8), num_leaves), NodeIndex(7));
+
+        assert_eq!(common_ancestor(NodeIndex(2), NodeIndex(2), num_leaves), NodeIndex(2));
+        assert_eq!(common_ancestor(NodeIndex(2), NodeIndex(3), num_leaves), NodeIndex(3));
+        assert_eq!(common_ancestor(NodeIndex(2), NodeIndex(4), num_leaves), NodeIndex(3));
+        assert_eq!(common_ancestor(NodeIndex(2), NodeIndex(5), num_leaves), NodeIndex(3));
+        assert_eq!(common_ancestor(NodeIndex(2), NodeIndex(6), num_leaves), NodeIndex(3));
+        assert_eq!(common_ancestor(NodeIndex(2), NodeIndex(7), num_leaves), NodeIndex(7));
+        assert_eq!(common_ancestor(NodeIndex(2), NodeIndex(8), num_leaves), NodeIndex(7));
+
+        assert_eq!(common_ancestor(NodeIndex(3), NodeIndex(3), num_leaves), NodeIndex(3));
+        assert_eq!(common_ancestor(NodeIndex(3), NodeIndex(4), num_leaves), NodeIndex(3));
+        assert_eq!(common_ancestor(NodeIndex(3), NodeIndex(5), num_leaves), NodeIndex(3));
+        assert_eq!(common_ancestor(NodeIndex(3), NodeIndex(6), num_leaves), NodeIndex(3));
+        assert_eq!(common_ancestor(NodeIndex(3), NodeIndex(7), num_leaves), NodeIndex(7));
+        assert_eq!(common_ancestor(NodeIndex(3), NodeIndex(8), num_leaves), NodeIndex(7));
+
+        assert_eq!(common_ancestor(NodeIndex(4), NodeIndex(4), num_leaves), NodeIndex(4));
+        assert_eq!(common_ancestor(NodeIndex(4), NodeIndex(5), num_leaves), NodeIndex(5));
+        assert_eq!(common_ancestor(NodeIndex(4), NodeIndex(6), num_leaves), NodeIndex(5));
+        assert_eq!(common_ancestor(NodeIndex(4), NodeIndex(7), num_leaves), NodeIndex(7));
+        assert_eq!(common_ancestor(NodeIndex(4), NodeIndex(8), num_leaves), NodeIndex(7));
+
+        assert_eq!(common_ancestor(NodeIndex(5), NodeIndex(5), num_leaves), NodeIndex(5));
+        assert_eq!(common_ancestor(NodeIndex(5), NodeIndex(6), num_leaves), NodeIndex(5));
+        assert_eq!(common_ancestor(NodeIndex(5), NodeIndex(7), num_leaves), NodeIndex(7));
+        assert_eq!(common_ancestor(NodeIndex(5), NodeIndex(8), num_leaves), NodeIndex(7));
+
+        assert_eq!(common_ancestor(NodeIndex(6), NodeIndex(6), num_leaves), NodeIndex(6));
+        assert_eq!(common_ancestor(NodeIndex(6), NodeIndex(7), num_leaves), NodeIndex(7));
+        assert_eq!(common_ancestor(NodeIndex(6), NodeIndex(8), num_leaves), NodeIndex(7));
+
+        assert_eq!(common_ancestor(NodeIndex(7), NodeIndex(7), num_leaves), NodeIndex(7));
+        assert_eq!(common_ancestor(NodeIndex(7), NodeIndex(8), num_leaves), NodeIndex(7));
+
+        assert_eq!(common_ancestor(NodeIndex(8), NodeIndex(8), num_leaves), NodeIndex(8));
 
         // Regression tests
-        assert!(is_ancestor(11, 12, 7));
-        assert_eq!(common_ancestor(12, 10, 7), 11);
+        assert!(is_ancestor(NodeIndex(11), NodeIndex(12), 7));
+        assert_eq!(common_ancestor(NodeIndex(12), NodeIndex(10), 7), NodeIndex(11));
     }
 
     // TODO: Add Panic tests
@@ -673,13 +818,15 @@ mod test {
         let num_parent_ops = test_vec.parent.len();
         let num_sibling_ops = test_vec.sibling.len();
 
-        let root: Vec<u32> = (1..=num_root_ops).map(|i| root_idx(i) as u32).collect();
-        let left: Vec<u32> = (0..num_left_ops).map(|i| node_left_child(i) as u32).collect();
+        let root: Vec<u32> = (1..=num_root_ops).map(|i| root_idx(i).0 as u32).collect();
+        let left: Vec<u32> =
+            (0..num_left_ops).map(|i| node_left_child(NodeIndex(i)).0 as u32).collect();
         let right: Vec<u32> =
-            (0..num_right_ops).map(|i| node_right_child(i, size) as u32).collect();
-        let parent: Vec<u32> = (0..num_parent_ops).map(|i| node_parent(i, size) as u32).collect();
+            (0..num_right_ops).map(|i| node_right_child(NodeIndex(i), size).0 as u32).collect();
+        let parent: Vec<u32> =
+            (0..num_parent_ops).map(|i| node_parent(NodeIndex(i), size).0 as u32).collect();
         let sibling: Vec<u32> =
-            (0..num_sibling_ops).map(|i| node_sibling(i, size) as u32).collect();
+            (0..num_sibling_ops).map(|i| node_sibling(NodeIndex(i), size).0 as u32).collect();
 
         assert_eq!(root, test_vec.root);
         assert_eq!(left, test_vec.left);
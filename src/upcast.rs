@@ -12,6 +12,11 @@
 //! struct to properly interpret the bytes in other structs it contains. This requires at least a
 //! little bit of custom logic, so we opt to implement this manually for all the types that need
 //! it.
+//!
+//! A value that's been deserialized but not upcast is only half-parsed, and using it will panic or
+//! fail in confusing ways. So nothing should ever do the two stages separately. Parse with
+//! `deserialize_and_upcast`, or with `GroupState::deserialize_handshake` for `Handshake`s, whose
+//! signature scheme depends on who sent them.
 
 use crate::{
     credential::{self, Credential},
@@ -22,8 +27,11 @@ use crate::{
     },
     error::Error,
     ratchet_tree,
+    tls_de::{ParseMode, TlsDeserializer},
 };
 
+use serde::de::Deserialize;
+
 /// The context necessary for a `CryptoUpcast`. This specifies the ambient ciphersuite and
 /// signature scheme.
 #[derive(Clone, Copy)]
@@ -34,6 +42,12 @@ pub struct CryptoCtx {
 
 // TODO: Figure out when to check for coherence in ciphersuites
 
+impl Default for CryptoCtx {
+    fn default() -> CryptoCtx {
+        CryptoCtx::new()
+    }
+}
+
 impl CryptoCtx {
     /// Makes a new empty `CryptoCtx`
     pub fn new() -> CryptoCtx {
@@ -66,10 +80,44 @@ impl CryptoCtx {
 /// of `UserInitKey::signature` and `UserInitKey::credential::signature_scheme`). We need a way to
 /// propogate that information, so we send it back up to the caller.
 pub trait CryptoUpcast {
-    #[must_use]
     fn upcast_crypto_values(&mut self, ctx: &CryptoCtx) -> Result<CryptoCtx, Error>;
 }
 
+/// Deserializes a `T` from the given bytes and upcasts it with respect to `ctx`, all in one go, so
+/// the half-parsed value never escapes. This parses leniently, so anything after the encoding of
+/// the `T` is ignored.
+///
+/// Returns: `Ok(val)` on success. Returns an `Error::SerdeError` if the bytes can't be
+/// deserialized, and whatever `T::upcast_crypto_values` returns if they can't be upcast.
+pub fn deserialize_and_upcast<T>(bytes: &[u8], ctx: &CryptoCtx) -> Result<T, Error>
+where
+    T: for<'de> Deserialize<'de> + CryptoUpcast,
+{
+    deserialize_and_upcast_with_mode(bytes, ctx, ParseMode::Lenient)
+}
+
+/// Does the same thing as `deserialize_and_upcast`, but parses in the given mode. In strict mode,
+/// anything after the encoding of the `T` is an error.
+///
+/// Returns: `Ok(val)` on success. Returns an `Error::SerdeError` if the bytes can't be
+/// deserialized in the given mode, and whatever `T::upcast_crypto_values` returns if they can't be
+/// upcast.
+pub fn deserialize_and_upcast_with_mode<T>(
+    bytes: &[u8],
+    ctx: &CryptoCtx,
+    mode: ParseMode,
+) -> Result<T, Error>
+where
+    T: for<'de> Deserialize<'de> + CryptoUpcast,
+{
+    let mut cursor = bytes;
+    let mut deserializer = TlsDeserializer::from_reader_with_mode(&mut cursor, mode);
+    let mut val = T::deserialize(&mut deserializer)?;
+    deserializer.finish()?;
+    val.upcast_crypto_values(ctx)?;
+    Ok(val)
+}
+
 impl CryptoUpcast for DhPublicKey {
     fn upcast_crypto_values(&mut self, ctx: &CryptoCtx) -> Result<CryptoCtx, Error> {
         let raw = enum_variant!(self, DhPublicKey::Raw);
@@ -167,7 +215,7 @@ impl CryptoUpcast for ratchet_tree::RatchetTreeNode {
 
 impl CryptoUpcast for ratchet_tree::RatchetTree {
     fn upcast_crypto_values(&mut self, ctx: &CryptoCtx) -> Result<CryptoCtx, Error> {
-        self.nodes.upcast_crypto_values(ctx)
+        self.nodes_mut().upcast_crypto_values(ctx)
     }
 }
 
@@ -182,15 +230,15 @@ impl CryptoUpcast for Credential {
     fn upcast_crypto_values(&mut self, ctx: &CryptoCtx) -> Result<CryptoCtx, Error> {
         match self {
             Credential::Basic(b) => b.upcast_crypto_values(ctx),
-            _ => unimplemented!("Cannot do X.509 upcasting yet"),
+            Credential::X509(_) => Err(Error::UpcastError("Cannot do X.509 upcasting yet")),
         }
     }
 }
 
-impl CryptoUpcast for crate::group_state::WelcomeInfo {
+impl<'a> CryptoUpcast for crate::group_state::WelcomeInfo<'a> {
     fn upcast_crypto_values(&mut self, ctx: &CryptoCtx) -> Result<CryptoCtx, Error> {
-        self.roster.upcast_crypto_values(ctx)?;
-        self.tree.upcast_crypto_values(ctx)?;
+        self.roster.to_mut().upcast_crypto_values(ctx)?;
+        self.tree.to_mut().upcast_crypto_values(ctx)?;
         // No change in context
         Ok(*ctx)
     }
@@ -205,6 +253,10 @@ impl CryptoUpcast for crate::group_state::Welcome {
 
 impl CryptoUpcast for crate::handshake::UserInitKey {
     fn upcast_crypto_values(&mut self, ctx: &CryptoCtx) -> Result<CryptoCtx, Error> {
+        // The zips below silently drop entries if the vectors aren't all the same length. Catch
+        // that here, when the UserInitKey has just been parsed.
+        self.validate()?;
+
         // Try to upcast the private keys if they're around
         if let Some(ref mut private_keys) = self.private_keys {
             // Each ciphersuite corresponds to a keypair. Upcast both of these with respect to that
@@ -259,7 +311,9 @@ impl CryptoUpcast for crate::handshake::DirectPathMessage {
 
 impl CryptoUpcast for crate::handshake::GroupInit {
     fn upcast_crypto_values(&mut self, ctx: &CryptoCtx) -> Result<CryptoCtx, Error> {
-        // GroupInit is empty; this is a no-op
+        self.roster.upcast_crypto_values(ctx)?;
+        self.tree.upcast_crypto_values(ctx)?;
+        // No change in context
         Ok(*ctx)
     }
 }
@@ -276,12 +330,39 @@ impl CryptoUpcast for crate::handshake::GroupUpdate {
     }
 }
 
+impl CryptoUpcast for crate::handshake::GroupCredentialUpdate {
+    fn upcast_crypto_values(&mut self, ctx: &CryptoCtx) -> Result<CryptoCtx, Error> {
+        self.path.upcast_crypto_values(ctx)?;
+        // Use the new credential's signature scheme to upcast the signature
+        let new_ctx = self.new_credential.upcast_crypto_values(ctx)?;
+        self.credential_signature.upcast_crypto_values(&new_ctx)?;
+
+        // No change to context
+        Ok(*ctx)
+    }
+}
+
 impl CryptoUpcast for crate::handshake::GroupRemove {
     fn upcast_crypto_values(&mut self, ctx: &CryptoCtx) -> Result<CryptoCtx, Error> {
         self.path.upcast_crypto_values(ctx)
     }
 }
 
+impl CryptoUpcast for crate::handshake::GroupBatchRemove {
+    fn upcast_crypto_values(&mut self, ctx: &CryptoCtx) -> Result<CryptoCtx, Error> {
+        self.path.upcast_crypto_values(ctx)
+    }
+}
+
+impl CryptoUpcast for crate::handshake::GroupReplace {
+    fn upcast_crypto_values(&mut self, ctx: &CryptoCtx) -> Result<CryptoCtx, Error> {
+        self.add.upcast_crypto_values(ctx)?;
+        self.path.upcast_crypto_values(ctx)?;
+        // No change to context
+        Ok(*ctx)
+    }
+}
+
 impl CryptoUpcast for crate::handshake::GroupOperation {
     fn upcast_crypto_values(&mut self, ctx: &CryptoCtx) -> Result<CryptoCtx, Error> {
         use crate::handshake::GroupOperation::*;
@@ -290,6 +371,11 @@ impl CryptoUpcast for crate::handshake::GroupOperation {
             Add(add) => add.upcast_crypto_values(ctx),
             Update(update) => update.upcast_crypto_values(ctx),
             Remove(remove) => remove.upcast_crypto_values(ctx),
+            CredentialUpdate(cred_update) => cred_update.upcast_crypto_values(ctx),
+            // The policy's signature is kept as bytes, so there's nothing to upcast
+            PolicyUpdate(_) => Ok(*ctx),
+            BatchRemove(batch_remove) => batch_remove.upcast_crypto_values(ctx),
+            Replace(replace) => replace.upcast_crypto_values(ctx),
         }
     }
 }
@@ -303,6 +389,61 @@ impl CryptoUpcast for crate::handshake::Handshake {
     }
 }
 
+impl CryptoUpcast for crate::resync::TreeSyncResponse {
+    fn upcast_crypto_values(&mut self, ctx: &CryptoCtx) -> Result<CryptoCtx, Error> {
+        self.tree.upcast_crypto_values(ctx)?;
+        self.signature.upcast_crypto_values(ctx)?;
+        // No change to context
+        Ok(*ctx)
+    }
+}
+
+impl CryptoUpcast for crate::archive::GroupArchive {
+    fn upcast_crypto_values(&mut self, ctx: &CryptoCtx) -> Result<CryptoCtx, Error> {
+        self.roster.upcast_crypto_values(ctx)
+    }
+}
+
+impl CryptoUpcast for crate::archive::SealedGroupArchive {
+    fn upcast_crypto_values(&mut self, ctx: &CryptoCtx) -> Result<CryptoCtx, Error> {
+        let new_ctx = ctx.set_cipher_suite(self.cipher_suite);
+        self.encrypted_archive.upcast_crypto_values(&new_ctx)
+    }
+}
+
+impl CryptoUpcast for crate::handshake::SavedUserInitKey {
+    fn upcast_crypto_values(&mut self, ctx: &CryptoCtx) -> Result<CryptoCtx, Error> {
+        self.init_key.upcast_crypto_values(ctx)
+    }
+}
+
+impl CryptoUpcast for crate::group_state::SavedGroupState {
+    fn upcast_crypto_values(&mut self, ctx: &CryptoCtx) -> Result<CryptoCtx, Error> {
+        let new_ctx = ctx.set_cipher_suite(self.cipher_suite);
+        self.welcome_info.upcast_crypto_values(&new_ctx)?;
+        self.initializing_user_init_key.upcast_crypto_values(&new_ctx)?;
+        self.retired_credentials.upcast_crypto_values(&new_ctx)?;
+        // No change in context
+        Ok(*ctx)
+    }
+}
+
+impl CryptoUpcast for crate::client::SavedGroup {
+    fn upcast_crypto_values(&mut self, ctx: &CryptoCtx) -> Result<CryptoCtx, Error> {
+        self.group_state.upcast_crypto_values(ctx)
+    }
+}
+
+impl CryptoUpcast for crate::client::SavedClient {
+    fn upcast_crypto_values(&mut self, ctx: &CryptoCtx) -> Result<CryptoCtx, Error> {
+        self.credential.upcast_crypto_values(ctx)?;
+        self.init_keys.upcast_crypto_values(ctx)?;
+        self.groups.upcast_crypto_values(ctx)?;
+        // No change in context
+        Ok(*ctx)
+    }
+}
+
 impl CryptoUpcast for crate::application::ApplicationMessage {
     fn upcast_crypto_values(&mut self, ctx: &CryptoCtx) -> Result<CryptoCtx, Error> {
         // No-op
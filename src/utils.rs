@@ -4,6 +4,7 @@ use crate::{
         dh::{DhPrivateKey, DhPublicKey},
         hkdf,
         hmac::HmacKey,
+        secret::Secret,
     },
     error::Error,
     ratchet_tree::{NodeSecret, PathSecret},
@@ -29,6 +30,13 @@ macro_rules! enum_variant {
     };
 }
 
+/// Overwrites the given buffer with zeros. This is for erasing secrets before their memory is
+/// freed. The zeroize crate makes sure the compiler can't optimize the writes away, even though the
+/// buffer is never read afterwards.
+pub(crate) fn zeroize(buf: &mut [u8]) {
+    zeroize::Zeroize::zeroize(buf);
+}
+
 /// Given a path secret, derives all node-specific values as well as the subsequent path secret.
 ///
 /// Requires: `path_secret.len() == cs.hash_impl.digest_size()`
@@ -48,18 +56,17 @@ pub(crate) fn derive_node_values(
     let prk: HmacKey = path_secret.into();
 
     // node_secret[n] = HKDF-Expand-Label(path_secret[n], "node", "", Hash.Length)
-    let mut node_secret_buf = vec![0u8; digest_size];
-    hkdf::expand_label(cs.hash_impl, &prk, b"node", b"", &mut node_secret_buf);
+    let mut node_secret = NodeSecret(Secret::new_from_zeros(digest_size));
+    hkdf::expand_label(cs.hash_impl, &prk, b"node", b"", node_secret.0.as_mut_bytes());
 
     // path_secret[n] = HKDF-Expand-Label(path_secret[n-1], "path", "", Hash.Length)
-    let mut path_secret_buf = vec![0u8; digest_size];
-    hkdf::expand_label(cs.hash_impl, &prk, b"path", b"", &mut path_secret_buf);
+    let mut path_secret_buf = Secret::new_from_zeros(digest_size);
+    hkdf::expand_label(cs.hash_impl, &prk, b"path", b"", path_secret_buf.as_mut_bytes());
 
     // Derive the private and public keys and assign them to the node
-    let (node_public_key, node_private_key) = cs.derive_key_pair(&node_secret_buf)?;
+    let (node_public_key, node_private_key) = cs.derive_key_pair(node_secret.0.as_bytes())?;
 
-    // Wrap the new values and return them
-    let node_secret = NodeSecret(node_secret_buf);
-    let new_path_secret = PathSecret::new_from_bytes(&path_secret_buf);
+    // Wrap the new path secret and return everything. The copy's original is wiped on drop.
+    let new_path_secret = PathSecret::new_from_bytes(path_secret_buf.as_bytes());
     Ok((node_public_key, node_private_key, node_secret, new_path_secret))
 }
@@ -0,0 +1,191 @@
+//! Defines helpers for checking the signatures on `UserInitKey`s and `Handshake`s without being a
+//! member of any group. These are for server-side components, like a directory or a delivery
+//! service, that want to drop garbage before storing it or fanning it out. Passing these checks
+//! doesn't make a message valid. Only a member can check that a `Handshake` applies cleanly.
+//! A server that holds a `PublicGroupState` can check a good deal more, see
+//! `PublicGroupState::validate_handshake`.
+//!
+//! Everything here parses in `ParseMode::Strict`. A server has no reason to pass along anything
+//! that an up-to-date, honest client wouldn't send.
+
+use crate::{
+    credential::Credential,
+    crypto::{ciphersuite::CipherSuite, hash::Digest},
+    error::Error,
+    group_state::{GroupContext, GroupState},
+    handshake::{Handshake, UserInitKey},
+    tls_de::ParseMode,
+    upcast::{self, CryptoCtx},
+};
+
+/// Parses a `UserInitKey` from its wire encoding and checks that it's well-formed, that it was
+/// issued for `credential`, and that it's signed by `credential`'s identity key
+///
+/// Returns: `Ok(user_init_key)` on success. Returns an `Error::ValidationError` if the
+/// `UserInitKey` is malformed or is for a different credential, and an `Error::SignatureError` if
+/// its signature doesn't verify. Returns an `Error::SerdeError` or `Error::UpcastError` if it
+/// can't be parsed.
+pub fn verify_user_init_key(bytes: &[u8], credential: &Credential) -> Result<UserInitKey, Error> {
+    // This validates the UserInitKey and picks up the signature scheme from its credential
+    let init_key: UserInitKey =
+        upcast::deserialize_and_upcast_with_mode(bytes, &CryptoCtx::new(), ParseMode::Strict)?;
+
+    if init_key.credential != *credential {
+        return Err(Error::ValidationError("UserInitKey was issued for a different credential"));
+    }
+    init_key.verify_sig()?;
+
+    Ok(init_key)
+}
+
+/// Parses a `Handshake` from its wire encoding and checks that it's signed by `signer_credential`.
+/// `cs` is the group's ciphersuite.
+///
+/// A `Handshake`'s signature covers the group's transcript hash after its operation is applied,
+/// so checking it requires the transcript hash from before, `prior_transcript_hash`. A new group
+/// starts with an all-zeros transcript hash as long as `cs`'s digest, and every `Handshake`,
+/// starting with its Init if it has one, moves it along. So a server that sees every `Handshake`
+/// in a group can keep track of it by passing in what the previous call returned. A member can
+/// also hand it out with `GroupState::get_transcript_hash`.
+///
+/// The signature also covers the hash of the group's tree in the epoch the `Handshake` was sent
+/// in, `prior_tree_hash`. There's no way to follow that without the tree, so a server has to get
+/// it from a member with `GroupState::get_tree_hash`, or keep a `PublicGroupState` instead.
+///
+/// Returns: `Ok((handshake, transcript_hash))` on success, where `transcript_hash` is the group's
+/// transcript hash once `handshake` is applied. Returns an `Error::SignatureError` if the
+/// signature doesn't verify, and an `Error::ValidationError` if `prior_transcript_hash` or
+/// `prior_tree_hash` is the wrong size. Returns an `Error::SerdeError` or `Error::UpcastError` if
+/// the `Handshake` can't be parsed.
+pub fn verify_handshake(
+    bytes: &[u8],
+    cs: &'static CipherSuite,
+    signer_credential: &Credential,
+    prior_transcript_hash: &[u8],
+    prior_tree_hash: &[u8],
+) -> Result<(Handshake, Vec<u8>), Error> {
+    let prior_transcript_hash = Digest::new_from_bytes(cs.hash_impl, prior_transcript_hash)?;
+    let prior_tree_hash = Digest::new_from_bytes(cs.hash_impl, prior_tree_hash)?;
+
+    // The signature is in the signer's signature scheme. Everything else is in the group's
+    // ciphersuite.
+    let ctx = CryptoCtx::new()
+        .set_cipher_suite(cs)
+        .set_signature_scheme(signer_credential.get_signature_scheme());
+    let handshake: Handshake =
+        upcast::deserialize_and_upcast_with_mode(bytes, &ctx, ParseMode::Strict)?;
+
+    // The Handshake's framing is taken at its word here. verify_sig checks that it's what was
+    // signed.
+    let prior_context = GroupContext {
+        group_id: handshake.group_id.clone(),
+        epoch: handshake.prior_epoch,
+        tree_hash: prior_tree_hash,
+        transcript_hash: prior_transcript_hash.clone(),
+    };
+    let transcript_hash =
+        GroupState::next_transcript_hash(cs, &prior_transcript_hash, &handshake.operation)?;
+    handshake.verify_sig(signer_credential, &prior_context, &transcript_hash)?;
+
+    Ok((handshake, transcript_hash.as_bytes().to_vec()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        crypto::ciphersuite::X25519_SHA256_AES128GCM, ratchet_tree::PathSecret, test_utils, tls_ser,
+    };
+
+    use quickcheck_macros::quickcheck;
+    use rand::SeedableRng;
+
+    // Checks that an honest UserInitKey verifies under its own credential and nobody else's, and
+    // that a tampered one doesn't verify at all
+    #[quickcheck]
+    fn user_init_key_detached_verification(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+
+        let (credential, identity_key) = test_utils::random_basic_credential(&mut rng);
+        let init_key = test_utils::make_init_key(
+            &identity_key,
+            credential.clone(),
+            b"detached",
+            &X25519_SHA256_AES128GCM,
+            &mut rng,
+        );
+        let bytes = tls_ser::serialize_to_bytes(&init_key).unwrap();
+
+        let verified = verify_user_init_key(&bytes, &credential).unwrap();
+        assert_eq!(verified.user_init_key_id, b"detached");
+
+        let (other_credential, _) = test_utils::random_basic_credential(&mut rng);
+        match verify_user_init_key(&bytes, &other_credential) {
+            Err(Error::ValidationError(_)) => (),
+            _ => panic!("UserInitKey verified for someone else's credential"),
+        }
+
+        // The UserInitKey ID is the first thing after its length byte. Changing it breaks the
+        // signature.
+        let mut tampered = bytes.clone();
+        tampered[1] ^= 1;
+        match verify_user_init_key(&tampered, &credential) {
+            Err(Error::SignatureError(_)) => (),
+            _ => panic!("tampered UserInitKey verified"),
+        }
+
+        // Servers parse strictly, so padding an honest UserInitKey gets it dropped
+        let padded = [&bytes[..], &[0u8][..]].concat();
+        match verify_user_init_key(&padded, &credential) {
+            Err(Error::SerdeError(_)) => (),
+            _ => panic!("padded UserInitKey verified"),
+        }
+    }
+
+    // Has one member make a couple of Updates, and checks that someone outside the group can
+    // verify them by tracking the transcript hash, and can't when the signer or tree is wrong
+    #[quickcheck]
+    fn handshake_detached_verification(rng_seed: u64) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(rng_seed);
+        let (mut group_state, _) = test_utils::random_full_group_state(2, &mut rng);
+        let cs = group_state.cs;
+        let signer_index = group_state.roster_index.unwrap();
+        let signer_credential = group_state.roster.0[signer_index.0 as usize].clone().unwrap();
+        let other_index = test_utils::random_roster_index_with_exceptions(
+            group_state.roster.len(),
+            &[signer_index.0 as usize],
+            &mut rng,
+        );
+        let other_credential = group_state.roster.0[other_index.0 as usize].clone().unwrap();
+
+        let mut transcript_hash = group_state.get_transcript_hash().to_vec();
+        for _ in 0..2 {
+            let tree_hash = group_state.get_tree_hash().unwrap();
+            let path_secret = PathSecret::new_from_random(cs, &mut rng);
+            let (handshake, new_group_state, _) =
+                group_state.create_and_apply_update_handshake(path_secret, &mut rng).unwrap();
+            let bytes = tls_ser::serialize_to_bytes(&handshake).unwrap();
+
+            match verify_handshake(&bytes, cs, &other_credential, &transcript_hash, &tree_hash) {
+                Err(Error::SignatureError(_)) => (),
+                _ => panic!("Handshake verified under the wrong credential"),
+            }
+
+            let new_tree_hash = new_group_state.get_tree_hash().unwrap();
+            match verify_handshake(&bytes, cs, &signer_credential, &transcript_hash, &new_tree_hash)
+            {
+                Err(Error::SignatureError(_)) => (),
+                _ => panic!("Handshake verified against the wrong tree"),
+            }
+
+            let (verified, new_transcript_hash) =
+                verify_handshake(&bytes, cs, &signer_credential, &transcript_hash, &tree_hash)
+                    .unwrap();
+            assert_eq!(verified.get_signer_index(), signer_index);
+            assert_eq!(new_transcript_hash, new_group_state.get_transcript_hash());
+
+            group_state = new_group_state;
+            transcript_hash = new_transcript_hash;
+        }
+    }
+}
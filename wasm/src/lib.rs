@@ -0,0 +1,285 @@
+// WebAssembly bindings for molasses. These wrap the group lifecycle (creating a group, joining
+// one, Adds, Updates, Removes, processing handshakes, and application messages) in a
+// wasm-bindgen-friendly API so that browser-based clients can run MLS locally.
+//
+// All protocol messages cross the JS boundary as TLS-serialized Uint8Arrays. Errors are thrown as
+// strings. Randomness comes from the browser's crypto.getRandomValues via GetrandomRng.
+//
+// To build, do
+//
+//     wasm-pack build wasm
+//
+// or, without wasm-pack,
+//
+//     cargo build --manifest-path wasm/Cargo.toml --target wasm32-unknown-unknown
+
+use molasses::{
+    application::{
+        decrypt_application_message, encrypt_application_message, ApplicationKeyChain,
+        ApplicationMessage,
+    },
+    credential::{BasicCredential, Credential, Identity, RosterIndex},
+    crypto::{
+        ciphersuite::{CipherSuite, X25519_SHA256_AES128GCM},
+        rng::GetrandomRng,
+        sig::{SigPublicKey, SigSecretKey, SignatureScheme, ED25519_IMPL},
+    },
+    error::Error,
+    group_state::{GroupState, Welcome},
+    handshake::{Handshake, UserInitKey, MLS_DUMMY_VERSION},
+    ratchet_tree::PathSecret,
+    tls_ser::TlsSerializer,
+    upcast::{self, CryptoCtx, CryptoUpcast},
+};
+
+use serde::{de::Deserialize, ser::Serialize};
+use wasm_bindgen::prelude::*;
+
+// We don't do any ciphersuite negotiation. These are the only ones we fully support anyway.
+const CIPHER_SUITE: &'static CipherSuite = &X25519_SHA256_AES128GCM;
+const SIG_SCHEME: &'static SignatureScheme = &ED25519_IMPL;
+
+// molasses::Error's Display impl isn't usable, so we go through Debug
+fn mls_err(e: Error) -> JsValue {
+    JsValue::from_str(&format!("{:?}", e))
+}
+
+fn serialize<T: Serialize>(value: &T) -> Result<Vec<u8>, JsValue> {
+    let mut serializer = TlsSerializer::new();
+    value.serialize(&mut serializer).map_err(mls_err)?;
+    Ok(serializer.into_vec())
+}
+
+fn deserialize<T>(bytes: &[u8]) -> Result<T, JsValue>
+where
+    T: for<'de> Deserialize<'de> + CryptoUpcast,
+{
+    let ctx = CryptoCtx::new().set_cipher_suite(CIPHER_SUITE).set_signature_scheme(SIG_SCHEME);
+    upcast::deserialize_and_upcast(bytes, &ctx).map_err(mls_err)
+}
+
+// Makes a new identity key and a credential for it
+fn new_credential(identity: &[u8]) -> Result<(Credential, SigSecretKey), JsValue> {
+    let identity_key =
+        SigSecretKey::new_from_random(SIG_SCHEME, &mut GetrandomRng).map_err(mls_err)?;
+    let public_key = SigPublicKey::new_from_secret_key(SIG_SCHEME, &identity_key);
+    let credential = Credential::Basic(BasicCredential::new(
+        Identity::from_bytes(identity.to_vec()),
+        SIG_SCHEME,
+        public_key,
+    ));
+
+    Ok((credential, identity_key))
+}
+
+// This is a free function rather than a method so that it only borrows the key chain. Otherwise we
+// couldn't use the group state while holding onto the key chain.
+fn get_app_key_chain(
+    app_key_chain: &mut Option<ApplicationKeyChain>,
+) -> Result<&mut ApplicationKeyChain, JsValue> {
+    app_key_chain
+        .as_mut()
+        .ok_or_else(|| JsValue::from_str("no application keys before the first handshake"))
+}
+
+/// A published `UserInitKey` along with the secrets needed to join a group with it
+#[wasm_bindgen]
+pub struct PendingJoin {
+    init_key: UserInitKey,
+    identity_key: SigSecretKey,
+}
+
+#[wasm_bindgen]
+impl PendingJoin {
+    /// Makes a fresh identity and a `UserInitKey` for it. `init_key_id` must be unique among this
+    /// client's `UserInitKey`s.
+    #[wasm_bindgen(constructor)]
+    pub fn new(identity: &[u8], init_key_id: &[u8]) -> Result<PendingJoin, JsValue> {
+        let (credential, identity_key) = new_credential(identity)?;
+        let init_key = UserInitKey::new_from_random(
+            &identity_key,
+            init_key_id.to_vec(),
+            credential,
+            vec![CIPHER_SUITE],
+            vec![MLS_DUMMY_VERSION],
+            &mut GetrandomRng,
+        )
+        .map_err(mls_err)?;
+
+        Ok(PendingJoin {
+            init_key,
+            identity_key,
+        })
+    }
+
+    /// Returns the serialized `UserInitKey`. This is what gets sent to the member doing the Add.
+    #[wasm_bindgen(js_name = userInitKey)]
+    pub fn user_init_key(&self) -> Result<Vec<u8>, JsValue> {
+        serialize(&self.init_key)
+    }
+
+    /// Joins a group, given the `Welcome` and the Add `Handshake` that put us in it. This consumes
+    /// the `PendingJoin`.
+    pub fn join(self, welcome: &[u8], add: &[u8]) -> Result<Group, JsValue> {
+        let welcome: Welcome = deserialize(welcome)?;
+        let add: Handshake = deserialize(add)?;
+
+        let preliminary_group =
+            GroupState::from_welcome(welcome, self.identity_key, self.init_key).map_err(mls_err)?;
+        let (group_state, app_key_chain) =
+            preliminary_group.process_handshake(&add).map_err(mls_err)?;
+
+        Ok(Group {
+            group_state,
+            app_key_chain: Some(app_key_chain),
+        })
+    }
+}
+
+/// The output of an Add: a `Welcome` for the new member and a `Handshake` for everyone
+#[wasm_bindgen]
+pub struct AddMessages {
+    welcome: Vec<u8>,
+    handshake: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl AddMessages {
+    /// Returns the serialized `Welcome`. This goes only to the new member.
+    #[wasm_bindgen(getter)]
+    pub fn welcome(&self) -> Vec<u8> {
+        self.welcome.clone()
+    }
+
+    /// Returns the serialized Add `Handshake`. This goes to the whole group, new member included.
+    #[wasm_bindgen(getter)]
+    pub fn handshake(&self) -> Vec<u8> {
+        self.handshake.clone()
+    }
+}
+
+/// This member's view of a group. Unlike `GroupState`, this is mutable: every operation moves the
+/// group to its next state in place.
+#[wasm_bindgen]
+pub struct Group {
+    group_state: GroupState,
+    // A fresh group has no application keys until its first handshake
+    app_key_chain: Option<ApplicationKeyChain>,
+}
+
+#[wasm_bindgen]
+impl Group {
+    /// Makes a new group whose only member is us
+    #[wasm_bindgen(constructor)]
+    pub fn new(group_id: &[u8], identity: &[u8]) -> Result<Group, JsValue> {
+        let (credential, identity_key) = new_credential(identity)?;
+        let group_state = GroupState::new_singleton_group(
+            CIPHER_SUITE,
+            MLS_DUMMY_VERSION,
+            identity_key,
+            group_id.to_vec(),
+            credential,
+            &mut GetrandomRng,
+        )
+        .map_err(mls_err)?;
+
+        Ok(Group {
+            group_state,
+            app_key_chain: None,
+        })
+    }
+
+    /// Returns the number of slots in the roster, including empty ones
+    #[wasm_bindgen(js_name = rosterSize)]
+    pub fn roster_size(&self) -> usize {
+        self.group_state.get_roster().len()
+    }
+
+    // Moves us to the next state of the group
+    fn advance(&mut self, group_state: GroupState, app_key_chain: ApplicationKeyChain) {
+        self.group_state = group_state;
+        self.app_key_chain = Some(app_key_chain);
+    }
+
+    /// Adds the owner of the given serialized `UserInitKey` to the end of the roster
+    pub fn add(&mut self, user_init_key: &[u8]) -> Result<AddMessages, JsValue> {
+        let init_key: UserInitKey = deserialize(user_init_key)?;
+
+        let new_roster_index = RosterIndex(self.group_state.get_roster().len() as u32);
+        let (welcome, welcome_info_hash) =
+            Welcome::from_group_state(&self.group_state, &init_key, &mut GetrandomRng)
+                .map_err(mls_err)?;
+        let (handshake, group_state, app_key_chain) = self
+            .group_state
+            .create_and_apply_add_handshake(new_roster_index, init_key, &welcome_info_hash)
+            .map_err(mls_err)?;
+
+        let messages = AddMessages {
+            welcome: serialize(&welcome)?,
+            handshake: serialize(&handshake)?,
+        };
+        self.advance(group_state, app_key_chain);
+        Ok(messages)
+    }
+
+    /// Refreshes our path secret. Returns the serialized Update `Handshake`.
+    pub fn update(&mut self) -> Result<Vec<u8>, JsValue> {
+        let path_secret = PathSecret::new_from_random(CIPHER_SUITE, &mut GetrandomRng);
+        let (handshake, group_state, app_key_chain) = self
+            .group_state
+            .create_and_apply_update_handshake(path_secret, &mut GetrandomRng)
+            .map_err(mls_err)?;
+
+        let serialized = serialize(&handshake)?;
+        self.advance(group_state, app_key_chain);
+        Ok(serialized)
+    }
+
+    /// Removes the member at the given roster index. Returns the serialized Remove `Handshake`.
+    pub fn remove(&mut self, roster_index: u32) -> Result<Vec<u8>, JsValue> {
+        let path_secret = PathSecret::new_from_random(CIPHER_SUITE, &mut GetrandomRng);
+        let (handshake, group_state, app_key_chain) = self
+            .group_state
+            .create_and_apply_remove_handshake(
+                RosterIndex(roster_index),
+                path_secret,
+                &mut GetrandomRng,
+            )
+            .map_err(mls_err)?;
+
+        let serialized = serialize(&handshake)?;
+        self.advance(group_state, app_key_chain);
+        Ok(serialized)
+    }
+
+    /// Processes a serialized `Handshake` from another member. If this throws `IAmRemoved`, then
+    /// this `Group` should be thrown away.
+    pub fn handle(&mut self, handshake: &[u8]) -> Result<(), JsValue> {
+        let handshake: Handshake = deserialize(handshake)?;
+        let (group_state, app_key_chain) =
+            self.group_state.process_handshake(&handshake).map_err(mls_err)?;
+
+        self.advance(group_state, app_key_chain);
+        Ok(())
+    }
+
+    /// Encrypts a message to the group. Returns the serialized `ApplicationMessage`.
+    pub fn protect(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, JsValue> {
+        let app_key_chain = get_app_key_chain(&mut self.app_key_chain)?;
+        let app_message =
+            encrypt_application_message(plaintext.to_vec(), &self.group_state, app_key_chain)
+                .map_err(mls_err)?;
+
+        serialize(&app_message)
+    }
+
+    /// Decrypts a serialized `ApplicationMessage` from another member
+    pub fn unprotect(&mut self, app_message: &[u8]) -> Result<Vec<u8>, JsValue> {
+        let app_message: ApplicationMessage = deserialize(app_message)?;
+        let app_key_chain = get_app_key_chain(&mut self.app_key_chain)?;
+
+        decrypt_application_message(app_message, &self.group_state, app_key_chain)
+            .map(|decrypted| decrypted.plaintext)
+            .map_err(mls_err)
+    }
+}